@@ -0,0 +1,56 @@
+use std::ffi::OsStr;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use cryptomator::{
+    crypto::{siv_ctrmac, siv_gcm, Cryptor, FileCryptor},
+    MasterKey,
+};
+
+fn cryptors(key: &MasterKey) -> [(&'static str, Cryptor<'_>); 2] {
+    [
+        ("SIV_CTRMAC", siv_ctrmac::Cryptor::new(key).into()),
+        ("SIV_GCM", siv_gcm::Cryptor::new(key).into()),
+    ]
+}
+
+fn chunks(c: &mut Criterion) {
+    let key = MasterKey::random().unwrap();
+    let mut group = c.benchmark_group("chunks");
+    for (combo, cryptor) in cryptors(&key) {
+        let header = cryptor.new_header().unwrap();
+        let chunk = vec![7; cryptor.max_chunk_len()];
+        let encrypted_chunk = cryptor.encrypt_chunk(&chunk, &header, 0).unwrap();
+        group.throughput(Throughput::Bytes(chunk.len() as u64));
+
+        group.bench_function(BenchmarkId::new("encrypt_chunk", combo), |b| {
+            b.iter(|| cryptor.encrypt_chunk(&chunk, &header, 0).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("decrypt_chunk", combo), |b| {
+            b.iter(|| cryptor.decrypt_chunk(&encrypted_chunk, &header, 0).unwrap())
+        });
+    }
+}
+
+fn names(c: &mut Criterion) {
+    let key = MasterKey::random().unwrap();
+    let dir_id = "68fdafca-2315-4840-87bc-19c48baf897f";
+    let mut group = c.benchmark_group("names");
+    for (combo, cryptor) in cryptors(&key) {
+        for len in [16, 255] {
+            let name = "n".repeat(len);
+            let encrypted_name = cryptor.encrypt_name(OsStr::new(&name), dir_id).unwrap();
+
+            group.bench_function(
+                BenchmarkId::new(format!("encrypt_name/{combo}"), len),
+                |b| b.iter(|| cryptor.encrypt_name(OsStr::new(&name), dir_id).unwrap()),
+            );
+            group.bench_function(
+                BenchmarkId::new(format!("decrypt_name/{combo}"), len),
+                |b| b.iter(|| cryptor.decrypt_name(&encrypted_name, dir_id).unwrap()),
+            );
+        }
+    }
+}
+
+criterion_group!(benches, chunks, names);
+criterion_main!(benches);
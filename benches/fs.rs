@@ -0,0 +1,106 @@
+use std::{
+    hint::black_box,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use cryptomator::{
+    fs::{EncryptedFile, EncryptedFileSystem},
+    storage::OpenOptions,
+    testing::{self, VaultShape},
+    Vault,
+};
+
+const FILE_LEN: usize = 4 * 1024 * 1024;
+const REQUEST_SIZES: [usize; 4] = [4 * 1024, 32 * 1024, 128 * 1024, 1024 * 1024];
+
+fn open_file<'v>(vault: &'v Vault, shape: &VaultShape, write: bool) -> EncryptedFile<'v> {
+    let fs = EncryptedFileSystem::new(vault);
+    let mut options = OpenOptions::new();
+    options.read(true).write(write);
+    fs.open_ciphertext(
+        fs.ciphertext_path(shape.file_path("/", 0)).unwrap(),
+        options,
+    )
+    .unwrap()
+}
+
+fn file_io(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let shape = VaultShape {
+        files_per_dir: 1,
+        file_len: FILE_LEN,
+        ..Default::default()
+    };
+    let vault = testing::create_vault(dir.path().join("vault"), &shape).unwrap();
+
+    let mut group = c.benchmark_group("file_io");
+    group.throughput(Throughput::Bytes(FILE_LEN as u64));
+    for size in REQUEST_SIZES {
+        let mut buf = vec![0; size];
+
+        let mut file = open_file(&vault, &shape, false);
+        group.bench_function(BenchmarkId::new("sequential_read", size), |b| {
+            b.iter(|| {
+                file.seek(SeekFrom::Start(0)).unwrap();
+                while file.read(&mut buf).unwrap() > 0 {}
+            })
+        });
+
+        let mut file = open_file(&vault, &shape, true);
+        group.bench_function(BenchmarkId::new("sequential_write", size), |b| {
+            b.iter(|| {
+                file.seek(SeekFrom::Start(0)).unwrap();
+                for _ in 0..FILE_LEN / size {
+                    file.write_all(&buf).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+
+    // Offsets spread over the file without lining up with chunk boundaries
+    let offsets: Vec<u64> = (0..64_u64)
+        .map(|n| n * 7919 * 97 % (FILE_LEN as u64 - 4096))
+        .collect();
+    let mut group = c.benchmark_group("file_io");
+    group.throughput(Throughput::Bytes(offsets.len() as u64 * 4096));
+    let mut file = open_file(&vault, &shape, false);
+    let mut buf = vec![0; 4096];
+    group.bench_function("random_read_at", |b| {
+        b.iter(|| {
+            for &offset in &offsets {
+                file.seek(SeekFrom::Start(offset)).unwrap();
+                file.read_exact(&mut buf).unwrap();
+            }
+        })
+    });
+}
+
+fn listing(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut group = c.benchmark_group("listing");
+    group.sample_size(10);
+    for name_len in [16, 200] {
+        let shape = VaultShape {
+            files_per_dir: 10_000,
+            file_len: 0,
+            name_len,
+            ..Default::default()
+        };
+        let vault =
+            testing::create_vault(dir.path().join(format!("vault-{name_len}")), &shape).unwrap();
+
+        group.throughput(Throughput::Elements(shape.files_per_dir as u64));
+        group.bench_function(BenchmarkId::new("10k_entries", name_len), |b| {
+            b.iter(|| {
+                // A new file system each time, so nothing is cached between runs
+                let fs = EncryptedFileSystem::new(&vault);
+                black_box(fs.walk("/").max_depth(1).count())
+            })
+        });
+    }
+}
+
+criterion_group!(benches, file_io, listing);
+criterion_main!(benches);
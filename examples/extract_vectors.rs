@@ -0,0 +1,21 @@
+//! Read test vectors from a vault written by another implementation of the vault format, such as
+//! the official desktop app, for checking our cryptors against.
+//!
+//! cargo run --example extract_vectors --features test-vectors -- <vault config> <password> <output file>
+
+use std::env;
+
+use color_eyre::eyre::eyre;
+use cryptomator::{testing::vectors::TestVectors, Result, Vault};
+
+fn main() -> Result<()> {
+    let [config_path, password, output_path] =
+        <[_; 3]>::try_from(env::args_os().skip(1).collect::<Vec<_>>())
+            .map_err(|_| eyre!("usage: extract_vectors <vault config> <password> <output file>"))?;
+    let password = password
+        .into_string()
+        .map_err(|_| eyre!("password is not valid UTF-8"))?;
+
+    let vault = Vault::open(config_path, password)?;
+    TestVectors::from_vault(&vault)?.save(output_path)
+}
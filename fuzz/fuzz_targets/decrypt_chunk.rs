@@ -0,0 +1,28 @@
+#![no_main]
+
+use cryptomator::crypto::FileCryptor;
+use cryptomator_fuzz::cryptors;
+use libfuzzer_sys::fuzz_target;
+
+// Input: a byte picking the cipher combo, a big-endian chunk number, an encrypted file header,
+// then an encrypted chunk
+fuzz_target!(|data: &[u8]| {
+    let Some((&combo, rest)) = data.split_first() else {
+        return;
+    };
+    let Some((chunk_number, rest)) = rest.split_first_chunk() else {
+        return;
+    };
+    let cryptor = cryptors()[combo as usize % 2];
+    let Some((header, chunk)) = rest.split_at_checked(cryptor.encrypted_header_len()) else {
+        return;
+    };
+    let Ok(header) = cryptor.decrypt_header(header) else {
+        return;
+    };
+
+    if let Ok(cleartext) = cryptor.decrypt_chunk(chunk, &header, u64::from_be_bytes(*chunk_number))
+    {
+        assert!(!cleartext.is_empty() && cleartext.len() <= cryptor.max_chunk_len());
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use cryptomator::crypto::FileCryptor;
+use cryptomator_fuzz::cryptors;
+use libfuzzer_sys::fuzz_target;
+
+// Input: an encrypted file header, which is tried with both cipher combos
+fuzz_target!(|data: &[u8]| {
+    for cryptor in cryptors() {
+        // A header that decrypts is authentic, so it must encrypt back to the same bytes
+        if let Ok(header) = cryptor.decrypt_header(data) {
+            assert_eq!(cryptor.encrypt_header(&header).unwrap(), data);
+        }
+    }
+});
@@ -0,0 +1,28 @@
+#![no_main]
+
+use std::ffi::OsStr;
+
+use cryptomator::crypto::{self, DirId, FileCryptor};
+use cryptomator_fuzz::cryptors;
+use libfuzzer_sys::fuzz_target;
+
+// Input: the ID of the parent directory and a ciphertext file name, separated by a newline. The
+// ID is empty for the root directory.
+fuzz_target!(|data: &[u8]| {
+    let Ok(data) = std::str::from_utf8(data) else {
+        return;
+    };
+    let (dir_id, file_name) = data.split_once('\n').unwrap_or(("", data));
+    let parent_dir_id = match dir_id {
+        "" => Some(DirId::root()),
+        dir_id => DirId::parse(dir_id).ok(),
+    };
+
+    crypto::is_encrypted_name(OsStr::new(file_name));
+    for cryptor in cryptors() {
+        let _ = cryptor.decrypt_name(file_name, dir_id);
+        if let Some(parent_dir_id) = &parent_dir_id {
+            let _ = cryptor.decrypt_filename(file_name, parent_dir_id);
+        }
+    }
+});
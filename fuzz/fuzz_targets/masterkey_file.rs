@@ -0,0 +1,16 @@
+#![no_main]
+
+use cryptomator::{MasterKey, WrappedKey};
+use cryptomator_fuzz::kek;
+use libfuzzer_sys::fuzz_target;
+
+// Input: the contents of a masterkey.cryptomator file, whose keys are unwrapped with the key of
+// the SIV_GCM fixture vault
+fuzz_target!(|data: &[u8]| {
+    let Ok(json) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(wrapped_key) = WrappedKey::from_json(json) {
+        let _ = MasterKey::from_wrapped(&wrapped_key, kek());
+    }
+});
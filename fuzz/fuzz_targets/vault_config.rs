@@ -0,0 +1,18 @@
+#![no_main]
+
+use cryptomator::VaultConfig;
+use cryptomator_fuzz::vaults;
+use libfuzzer_sys::fuzz_target;
+
+// Input: the contents of a vault.cryptomator file, which is verified with the master keys of the
+// fixture vaults
+fuzz_target!(|data: &[u8]| {
+    let Ok(jwt) = std::str::from_utf8(data) else {
+        return;
+    };
+    for vault in vaults() {
+        if let Ok(config) = VaultConfig::decode(jwt, &vault.master_key().unwrap()) {
+            assert_eq!(config.claims.format, 8);
+        }
+    }
+});
@@ -0,0 +1,47 @@
+#!/bin/sh
+# Fill corpus/ with seed inputs for each fuzz target, taken from the fixture vaults.
+set -eu
+
+cd "$(dirname "$0")"
+fixtures=../tests/fixtures
+
+# Write the standard input to a file named after its hash in the corpus of the target $1.
+seed() {
+    mkdir -p "corpus/$1"
+    tmp=$(mktemp)
+    cat > "$tmp"
+    mv "$tmp" "corpus/$1/$(sha1sum "$tmp" | cut -d ' ' -f 1)"
+}
+
+# The cipher combo byte, header length, and maximum encrypted chunk length of each vault
+for vault in "0 vault_v8_siv_ctrmac 88 32816" "1 vault_v8_siv_gcm 68 32796"; do
+    set -- $vault
+    combo=$1 dir=$fixtures/$2 header_len=$3 chunk_len=$4
+
+    find "$dir/d" -type f -name '*.c9r' ! -name dir.c9r | while read -r file; do
+        head -c "$header_len" "$file" | seed decrypt_header
+        # The first chunk, if there is one
+        if [ "$(wc -c < "$file")" -gt "$header_len" ]; then
+            {
+                printf "\\$(printf %03o "$combo")"
+                head -c 8 /dev/zero
+                head -c "$((header_len + chunk_len))" "$file"
+            } | seed decrypt_chunk
+        fi
+    done
+
+    # Only the root directory's ID is known without decrypting, and its dirid.c9r is just a header
+    find "$dir/d" -name dirid.c9r -size "${header_len}c" | while read -r file; do
+        for name in "$(dirname "$file")"/*.c9[rs]; do
+            [ "$(basename "$name")" = dirid.c9r ] && continue
+            printf '\n%s' "$(basename "$name")" | seed decrypt_name
+        done
+    done
+done
+
+for file in "$fixtures"/*/vault.cryptomator; do
+    seed vault_config < "$file"
+done
+for file in "$fixtures"/*/masterkey.cryptomator; do
+    seed masterkey_file < "$file"
+done
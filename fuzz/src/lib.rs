@@ -0,0 +1,34 @@
+//! Setup shared by the fuzz targets, which decrypt with the keys of the fixture vaults so that
+//! inputs derived from the fixtures get past authentication.
+
+use std::sync::OnceLock;
+
+use aes_kw::KekAes256;
+use cryptomator::{crypto::Cryptor, util, Vault, WrappedKey};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/fixtures");
+const PASSWORD: &str = "password";
+
+/// The SIV_CTRMAC and SIV_GCM fixture vaults, opened once since deriving their keys is slow.
+pub fn vaults() -> &'static [Vault; 2] {
+    static VAULTS: OnceLock<[Vault; 2]> = OnceLock::new();
+    VAULTS.get_or_init(|| {
+        ["vault_v8_siv_ctrmac", "vault_v8_siv_gcm"]
+            .map(|name| Vault::open(format!("{FIXTURES}/{name}"), PASSWORD).unwrap())
+    })
+}
+
+/// Cryptors for both cipher combos.
+pub fn cryptors() -> [Cryptor<'static>; 2] {
+    vaults().each_ref().map(Vault::cryptor)
+}
+
+/// The key that wraps the master key of the SIV_GCM fixture vault.
+pub fn kek() -> &'static KekAes256 {
+    static KEK: OnceLock<KekAes256> = OnceLock::new();
+    KEK.get_or_init(|| {
+        let path = format!("{FIXTURES}/vault_v8_siv_gcm/masterkey.cryptomator");
+        let wrapped_key = WrappedKey::from_file(path).unwrap();
+        util::derive_kek(&PASSWORD.into(), wrapped_key.params(), wrapped_key.salt()).unwrap()
+    })
+}
@@ -0,0 +1,137 @@
+#ifndef CRYPTOMATOR_H
+#define CRYPTOMATOR_H
+
+/* Generated with cbindgen from src/fs/ffi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The kind of a directory entry.
+ */
+typedef enum CmFileKind {
+  CM_FILE_KIND_FILE,
+  CM_FILE_KIND_DIRECTORY,
+  CM_FILE_KIND_SYMLINK,
+} CmFileKind;
+
+/**
+ * An iterator over the entries of a cleartext directory.
+ */
+typedef struct CmDirIter CmDirIter;
+
+/**
+ * An open cleartext file.
+ */
+typedef struct CmFile CmFile;
+
+/**
+ * An unlocked vault.
+ */
+typedef struct CmVault CmVault;
+
+/**
+ * Unlock the vault with the config file `config_path` using `password`. Returns `NULL` on
+ * failure. The vault must be freed with [`cm_vault_free`].
+ *
+ * # Safety
+ *
+ * `config_path` and `password` must be null or valid NUL-terminated strings.
+ */
+CmVault *cm_vault_open(const char *config_path, const char *password);
+
+/**
+ * Lock and free a vault, wiping its keys from memory. Does nothing if `vault` is `NULL`.
+ *
+ * # Safety
+ *
+ * `vault` must be null or returned by [`cm_vault_open`], and must not be used afterwards. Files
+ * opened from it must be closed first.
+ */
+void cm_vault_free(CmVault *vault);
+
+/**
+ * Open the cleartext file `cleartext_path`, an absolute path within the vault. `flags` are
+ * `open(2)` flags: the access mode, plus `O_CREAT`, `O_EXCL`, `O_TRUNC`, and `O_APPEND`. Returns
+ * `NULL` on failure. The file must be closed with [`cm_file_close`].
+ *
+ * # Safety
+ *
+ * `vault` must be null or a live vault, which must outlive the file. `cleartext_path` must be
+ * null or a valid NUL-terminated string.
+ */
+CmFile *cm_file_open(CmVault *vault, const char *cleartext_path, int flags);
+
+/**
+ * Read up to `len` bytes at `offset` into `buf`. Returns the number of bytes read, which is only
+ * less than `len` at the end of the file, or -1 on failure.
+ *
+ * # Safety
+ *
+ * `file` must be null or a live file that isn't used by another thread during the call. `buf`
+ * must be null or valid for writes of `len` bytes.
+ */
+int64_t cm_file_read_at(CmFile *file, uint8_t *buf, size_t len, uint64_t offset);
+
+/**
+ * Write `len` bytes from `buf` at `offset`, or at the end of the file if it was opened with
+ * `O_APPEND`. A gap left by writing past the end of the file reads as zeros. Returns the number
+ * of bytes written, which is always `len`, or -1 on failure.
+ *
+ * # Safety
+ *
+ * `file` must be null or a live file that isn't used by another thread during the call. `buf`
+ * must be null or valid for reads of `len` bytes.
+ */
+int64_t cm_file_write_at(CmFile *file, const uint8_t *buf, size_t len, uint64_t offset);
+
+/**
+ * Close a file. Does nothing if `file` is `NULL`.
+ *
+ * # Safety
+ *
+ * `file` must be null or returned by [`cm_file_open`], and must not be used afterwards.
+ */
+void cm_file_close(CmFile *file);
+
+/**
+ * List the cleartext directory `cleartext_path`, an absolute path within the vault. Returns
+ * `NULL` on failure. The iterator must be freed with [`cm_dir_free`].
+ *
+ * # Safety
+ *
+ * `vault` must be null or a live vault. `cleartext_path` must be null or a valid NUL-terminated
+ * string.
+ */
+CmDirIter *cm_dir_list(CmVault *vault, const char *cleartext_path);
+
+/**
+ * Return the name of the next entry, storing its kind in `kind` unless that is `NULL`. Returns
+ * `NULL` once there are no more entries. The name is valid until the next call with the same
+ * iterator.
+ *
+ * # Safety
+ *
+ * `iter` must be null or a live iterator. `kind` must be null or valid for writes.
+ */
+const char *cm_dir_next(CmDirIter *iter, CmFileKind *kind);
+
+/**
+ * Free a directory iterator. Does nothing if `iter` is `NULL`.
+ *
+ * # Safety
+ *
+ * `iter` must be null or returned by [`cm_dir_list`], and must not be used afterwards.
+ */
+void cm_dir_free(CmDirIter *iter);
+
+/**
+ * Describe the last error on the calling thread, or return `NULL` if nothing has failed yet.
+ * The message is valid until the next failing call on the same thread.
+ */
+const char *cm_last_error(void);
+
+#endif /* CRYPTOMATOR_H */
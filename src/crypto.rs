@@ -1,6 +1,9 @@
-use std::{ffi::OsStr, path::PathBuf};
+use std::{ffi::OsStr, fmt::Display, path::PathBuf};
 
+use base64ct::{Base64Url, Encoding};
+use color_eyre::eyre::bail;
 use rand_core::{OsRng, RngCore};
+use uuid::Uuid;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
 use crate::{key::SUBKEY_LEN, Result};
@@ -9,6 +12,10 @@ pub mod siv_ctrmac;
 pub mod siv_gcm;
 
 const HEADER_RESERVED_LEN: usize = 8;
+// Length of the AES-SIV tag prepended to every encrypted name
+const SIV_TAG_LEN: usize = 16;
+// Length of the SHA-1 hash used for shortened names
+const SHORTENED_HASH_LEN: usize = 20;
 
 // TODO: If future cryptors require different header fields, you'll need to generalize
 #[derive(Debug, PartialEq, Eq, Clone, Zeroize, ZeroizeOnDrop)]
@@ -31,6 +38,22 @@ impl FileHeader {
         Ok(Self { nonce, payload })
     }
 
+    // Test vectors spell out headers byte by byte.
+    #[cfg(feature = "test-vectors")]
+    pub(crate) fn from_parts(nonce: Vec<u8>, payload: Vec<u8>) -> Self {
+        Self { nonce, payload }
+    }
+
+    #[cfg(feature = "test-vectors")]
+    pub(crate) fn nonce(&self) -> &[u8] {
+        &self.nonce
+    }
+
+    #[cfg(feature = "test-vectors")]
+    pub(crate) fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
     fn content_key(&self) -> [u8; SUBKEY_LEN] {
         // TODO: This will fail if payload len is too small
         debug_assert_eq!(self.payload.len() - HEADER_RESERVED_LEN, SUBKEY_LEN);
@@ -38,7 +61,53 @@ impl FileHeader {
     }
 }
 
+/// A directory ID, as stored in `dir.c9r` files. The root directory has an empty ID.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DirId(String);
+
+impl DirId {
+    /// Directory IDs longer than this are rejected as corrupt.
+    pub const MAX_LEN: usize = 36;
+
+    /// Generate a fresh directory ID for a new directory.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// The ID of the vault's root directory.
+    pub fn root() -> Self {
+        Self(String::new())
+    }
+
+    /// Parse the contents of a `dir.c9r` file. Cryptomator generates UUID strings, but any
+    /// non-empty string of at most [`DirId::MAX_LEN`] bytes is accepted.
+    pub fn parse(dir_id: &str) -> Result<Self> {
+        if dir_id.is_empty() || dir_id.len() > Self::MAX_LEN {
+            bail!("invalid directory ID length: {}", dir_id.len());
+        }
+
+        Ok(Self(dir_id.to_string()))
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for DirId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 // TODO: This trait is kind of goofy (e.g. functions taking &self but returning constants)
+/// Operations shared by all cryptor types. This trait is object safe, so code that needs to handle
+/// any cipher combo can hold a `&dyn FileCryptor` or `Box<dyn FileCryptor>`.
 pub trait FileCryptor {
     fn encrypted_header_len(&self) -> usize;
 
@@ -46,47 +115,105 @@ pub trait FileCryptor {
 
     fn max_encrypted_chunk_len(&self) -> usize;
 
+    /// The number of bytes that encrypting a chunk adds to it, like its nonce and tag.
+    fn chunk_overhead(&self) -> usize {
+        self.max_encrypted_chunk_len() - self.max_chunk_len()
+    }
+
     fn new_header(&self) -> Result<FileHeader>;
 
     fn encrypt_header(&self, header: &FileHeader) -> Result<Vec<u8>>;
 
-    fn decrypt_header(&self, encrypted_header: impl AsRef<[u8]>) -> Result<FileHeader>;
+    fn decrypt_header(&self, encrypted_header: &[u8]) -> Result<FileHeader>;
 
     fn encrypt_chunk(
         &self,
-        chunk: impl AsRef<[u8]>,
+        chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>>;
 
     fn decrypt_chunk(
         &self,
-        encrypted_chunk: impl AsRef<[u8]>,
+        encrypted_chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>>;
 
-    fn hash_dir_id(&self, dir_id: impl AsRef<str>) -> Result<PathBuf>;
+    fn hash_dir_id(&self, dir_id: &DirId) -> Result<PathBuf>;
 
-    fn encrypt_name(
-        &self,
-        name: impl AsRef<OsStr>,
-        parent_dir_id: impl AsRef<str>,
-    ) -> Result<String>;
+    fn encrypt_name(&self, name: &OsStr, parent_dir_id: &str) -> Result<String>;
 
-    fn decrypt_name(
-        &self,
-        encrypted_name: impl AsRef<str>,
-        parent_dir_id: impl AsRef<str>,
-    ) -> Result<String>;
+    fn decrypt_name(&self, encrypted_name: &str, parent_dir_id: &str) -> Result<String>;
+
+    /// Encrypt a cleartext name into a ciphertext file name, including the `.c9r` extension.
+    fn encrypt_filename(&self, name: &OsStr, parent_dir_id: &DirId) -> Result<String> {
+        Ok(self.encrypt_name(name, parent_dir_id.as_str())? + ".c9r")
+    }
+
+    /// Decrypt a ciphertext file name ending in `.c9r` into its cleartext name. Any other
+    /// extension is rejected.
+    fn decrypt_filename(&self, file_name: &str, parent_dir_id: &DirId) -> Result<String> {
+        match file_name.strip_suffix(".c9r") {
+            Some(encrypted_name) => self.decrypt_name(encrypted_name, parent_dir_id.as_str()),
+            None => bail!("unexpected ciphertext file name: {file_name}"),
+        }
+    }
+}
+
+/// Check whether a ciphertext directory entry looks like an encrypted name, i.e. either a `.c9r`
+/// name containing base64url-encoded AES-SIV output, or a `.c9s` shortened name. This does not
+/// decrypt anything, so it's cheap enough to use when scanning directories.
+pub fn is_encrypted_name(file_name: &OsStr) -> bool {
+    let Some(file_name) = file_name.to_str() else {
+        return false;
+    };
+
+    if let Some(encrypted_name) = file_name.strip_suffix(".c9r") {
+        Base64Url::decode_vec(encrypted_name).is_ok_and(|bytes| bytes.len() >= SIV_TAG_LEN)
+    } else if let Some(hash) = file_name.strip_suffix(".c9s") {
+        Base64Url::decode_vec(hash).is_ok_and(|bytes| bytes.len() == SHORTENED_HASH_LEN)
+    } else {
+        false
+    }
 }
 
+/// A cryptor for any supported cipher combo.
 #[derive(Debug, Clone, Copy)]
 pub enum Cryptor<'k> {
     SivCtrMac(siv_ctrmac::Cryptor<'k>),
     SivGcm(siv_gcm::Cryptor<'k>),
 }
 
+impl<'k> From<siv_ctrmac::Cryptor<'k>> for Cryptor<'k> {
+    fn from(cryptor: siv_ctrmac::Cryptor<'k>) -> Self {
+        Cryptor::SivCtrMac(cryptor)
+    }
+}
+
+impl<'k> From<siv_gcm::Cryptor<'k>> for Cryptor<'k> {
+    fn from(cryptor: siv_gcm::Cryptor<'k>) -> Self {
+        Cryptor::SivGcm(cryptor)
+    }
+}
+
+impl Cryptor<'_> {
+    // Deterministic, for test vectors. Everything else encrypts with a random nonce.
+    #[cfg(feature = "test-vectors")]
+    pub(crate) fn encrypt_chunk_with_nonce(
+        &self,
+        nonce: &[u8],
+        chunk: &[u8],
+        header: &FileHeader,
+        chunk_number: u64,
+    ) -> Result<Vec<u8>> {
+        match self {
+            Cryptor::SivCtrMac(c) => c.encrypt_chunk_with_nonce(nonce, chunk, header, chunk_number),
+            Cryptor::SivGcm(c) => c.encrypt_chunk_with_nonce(nonce, chunk, header, chunk_number),
+        }
+    }
+}
+
 impl<'k> FileCryptor for Cryptor<'k> {
     fn encrypted_header_len(&self) -> usize {
         match self {
@@ -123,7 +250,7 @@ impl<'k> FileCryptor for Cryptor<'k> {
         }
     }
 
-    fn decrypt_header(&self, encrypted_header: impl AsRef<[u8]>) -> Result<FileHeader> {
+    fn decrypt_header(&self, encrypted_header: &[u8]) -> Result<FileHeader> {
         match self {
             Cryptor::SivCtrMac(c) => c.decrypt_header(encrypted_header),
             Cryptor::SivGcm(c) => c.decrypt_header(encrypted_header),
@@ -132,9 +259,9 @@ impl<'k> FileCryptor for Cryptor<'k> {
 
     fn encrypt_chunk(
         &self,
-        chunk: impl AsRef<[u8]>,
+        chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>> {
         match self {
             Cryptor::SivCtrMac(c) => c.encrypt_chunk(chunk, header, chunk_number),
@@ -144,9 +271,9 @@ impl<'k> FileCryptor for Cryptor<'k> {
 
     fn decrypt_chunk(
         &self,
-        encrypted_chunk: impl AsRef<[u8]>,
+        encrypted_chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>> {
         match self {
             Cryptor::SivCtrMac(c) => c.decrypt_chunk(encrypted_chunk, header, chunk_number),
@@ -154,32 +281,103 @@ impl<'k> FileCryptor for Cryptor<'k> {
         }
     }
 
-    fn hash_dir_id(&self, dir_id: impl AsRef<str>) -> Result<PathBuf> {
+    fn hash_dir_id(&self, dir_id: &DirId) -> Result<PathBuf> {
         match self {
             Cryptor::SivCtrMac(c) => c.hash_dir_id(dir_id),
             Cryptor::SivGcm(c) => c.hash_dir_id(dir_id),
         }
     }
 
-    fn encrypt_name(
-        &self,
-        name: impl AsRef<OsStr>,
-        parent_dir_id: impl AsRef<str>,
-    ) -> Result<String> {
+    fn encrypt_name(&self, name: &OsStr, parent_dir_id: &str) -> Result<String> {
         match self {
             Cryptor::SivCtrMac(c) => c.encrypt_name(name, parent_dir_id),
             Cryptor::SivGcm(c) => c.encrypt_name(name, parent_dir_id),
         }
     }
 
-    fn decrypt_name(
-        &self,
-        encrypted_name: impl AsRef<str>,
-        parent_dir_id: impl AsRef<str>,
-    ) -> Result<String> {
+    fn decrypt_name(&self, encrypted_name: &str, parent_dir_id: &str) -> Result<String> {
         match self {
             Cryptor::SivCtrMac(c) => c.decrypt_name(encrypted_name, parent_dir_id),
             Cryptor::SivGcm(c) => c.decrypt_name(encrypted_name, parent_dir_id),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use zeroize::Zeroizing;
+
+    use crate::MasterKey;
+
+    use super::*;
+
+    #[test]
+    fn dyn_cryptor_test() {
+        let key = MasterKey::from_raw(Zeroizing::new([7_u8; SUBKEY_LEN * 2])).unwrap();
+        let cryptors: Vec<Box<dyn FileCryptor>> = vec![
+            Box::new(siv_ctrmac::Cryptor::new(&key)),
+            Box::new(siv_gcm::Cryptor::new(&key)),
+            Box::new(Cryptor::from(siv_gcm::Cryptor::new(&key))),
+        ];
+
+        for cryptor in cryptors {
+            let header = cryptor.new_header().unwrap();
+            let encrypted_header = cryptor.encrypt_header(&header).unwrap();
+            assert_eq!(encrypted_header.len(), cryptor.encrypted_header_len());
+            assert_eq!(cryptor.decrypt_header(&encrypted_header).unwrap(), header);
+
+            let ciphertext = cryptor.encrypt_chunk(b"some data", &header, 3).unwrap();
+            assert_eq!(
+                cryptor.decrypt_chunk(&ciphertext, &header, 3).unwrap(),
+                b"some data"
+            );
+
+            let name = cryptor.encrypt_name(OsStr::new("name.txt"), "dir").unwrap();
+            assert_eq!(cryptor.decrypt_name(&name, "dir").unwrap(), "name.txt");
+        }
+    }
+
+    #[test]
+    fn filename_test() {
+        let key = MasterKey::from_raw(Zeroizing::new([7_u8; SUBKEY_LEN * 2])).unwrap();
+        let cryptor = Cryptor::from(siv_gcm::Cryptor::new(&key));
+        let dir_id = DirId::new();
+
+        let file_name = cryptor
+            .encrypt_filename(OsStr::new("name.txt"), &dir_id)
+            .unwrap();
+        assert!(file_name.ends_with(".c9r"));
+        assert!(is_encrypted_name(OsStr::new(&file_name)));
+        assert_eq!(
+            cryptor.decrypt_filename(&file_name, &dir_id).unwrap(),
+            "name.txt"
+        );
+
+        // Missing or unexpected extensions are rejected rather than decrypted into garbage
+        let encrypted_name = file_name.strip_suffix(".c9r").unwrap();
+        assert!(cryptor.decrypt_filename(encrypted_name, &dir_id).is_err());
+        assert!(cryptor
+            .decrypt_filename(&format!("{encrypted_name}.c9s"), &dir_id)
+            .is_err());
+
+        assert!(is_encrypted_name(OsStr::new(
+            "kR7qduQKc3duqmSGzHpXYTZQI80=.c9s"
+        )));
+        assert!(!is_encrypted_name(OsStr::new("dirid.c9r")));
+        assert!(!is_encrypted_name(OsStr::new(encrypted_name)));
+        assert!(!is_encrypted_name(OsStr::new("desktop.ini")));
+    }
+
+    #[test]
+    fn dir_id_test() {
+        assert!(DirId::root().is_root());
+        assert_eq!(DirId::new().as_str().len(), DirId::MAX_LEN);
+        assert_ne!(DirId::new(), DirId::new());
+
+        let dir_id = DirId::parse("68fdafca-2315-4840-87bc-19c48baf897f").unwrap();
+        assert_eq!(dir_id.to_string(), "68fdafca-2315-4840-87bc-19c48baf897f");
+        assert!(DirId::parse("short-but-fine").is_ok());
+        assert!(DirId::parse("").is_err());
+        assert!(DirId::parse(&"x".repeat(DirId::MAX_LEN + 1)).is_err());
+    }
+}
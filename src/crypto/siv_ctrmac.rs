@@ -14,9 +14,12 @@ use rand_core::{self, OsRng, RngCore};
 use sha1::{Digest, Sha1};
 use sha2::Sha256;
 
-use crate::{key::SUBKEY_LEN, util, MasterKey, Result};
+use crate::{
+    key::{KeyRef, SharedKey, SUBKEY_LEN},
+    util, MasterKey, Result,
+};
 
-use super::{FileCryptor, FileHeader, HEADER_RESERVED_LEN};
+use super::{DirId, FileCryptor, FileHeader, HEADER_RESERVED_LEN};
 
 // General constants
 const NONCE_LEN: usize = 16;
@@ -28,17 +31,47 @@ const PAYLOAD_LEN: usize = HEADER_RESERVED_LEN + CONTENT_KEY_LEN;
 const ENCRYPTED_HEADER_LEN: usize = NONCE_LEN + PAYLOAD_LEN + MAC_LEN;
 
 // File content constants
-const MAX_CHUNK_LEN: usize = 32 * 1024;
-const MAX_ENCRYPTED_CHUNK_LEN: usize = NONCE_LEN + MAX_CHUNK_LEN + MAC_LEN;
+const CHUNK_LEN: usize = 32 * 1024;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Cryptor<'k> {
-    key: &'k MasterKey,
+    key: KeyRef<'k>,
+    chunk_len: usize,
 }
 
 impl<'k> Cryptor<'k> {
     pub fn new(key: &'k MasterKey) -> Self {
-        Self { key }
+        Self {
+            key: KeyRef::Borrowed(key),
+            chunk_len: CHUNK_LEN,
+        }
+    }
+
+    /// A cryptor for the key of a vault, which fails with [`VaultLocked`](crate::VaultLocked)
+    /// once the vault is locked.
+    pub(crate) fn shared(key: &'k SharedKey) -> Self {
+        Self {
+            key: KeyRef::Shared(key),
+            chunk_len: CHUNK_LEN,
+        }
+    }
+
+    /// A cryptor that splits file contents into chunks of `chunk_len` bytes, rather than the
+    /// 32 KiB of the vault format.
+    ///
+    /// This is experimental and not interoperable: files encrypted this way can only be read with
+    /// the same chunk length, which is recorded nowhere, and Cryptomator can't read them at all.
+    /// Names, directory IDs, and headers are encrypted as usual.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_len` is 0.
+    pub fn with_chunk_len(key: &'k MasterKey, chunk_len: usize) -> Self {
+        assert!(chunk_len > 0, "chunk length must not be 0");
+        Self {
+            key: KeyRef::Borrowed(key),
+            chunk_len,
+        }
     }
 
     fn aes_ctr(&self, message: &[u8], key: &[u8; SUBKEY_LEN], nonce: &[u8]) -> Result<Vec<u8>> {
@@ -52,10 +85,11 @@ impl<'k> Cryptor<'k> {
 
         // AES-SIV takes both the encryption key and mac key, but in reverse order
         // TODO: Use slice flatten() method when stabilized
+        let master_key = self.key.get()?;
         let mut key = [0_u8; SUBKEY_LEN * 2];
         let (left, right) = key.split_at_mut(SUBKEY_LEN);
-        left.copy_from_slice(self.key.mac_key());
-        right.copy_from_slice(self.key.enc_key());
+        left.copy_from_slice(master_key.mac_key());
+        right.copy_from_slice(master_key.enc_key());
 
         Ok(Aes256Siv::new(&key.into()).encrypt(associated_data, plaintext)?)
     }
@@ -65,16 +99,17 @@ impl<'k> Cryptor<'k> {
 
         // AES-SIV takes both the encryption key and mac key, but in reverse order
         // TODO: Use slice flatten() method when stabilized
+        let master_key = self.key.get()?;
         let mut key = [0_u8; SUBKEY_LEN * 2];
         let (left, right) = key.split_at_mut(SUBKEY_LEN);
-        left.copy_from_slice(self.key.mac_key());
-        right.copy_from_slice(self.key.enc_key());
+        left.copy_from_slice(master_key.mac_key());
+        right.copy_from_slice(master_key.enc_key());
 
         Ok(Aes256Siv::new(&key.into()).decrypt(associated_data, ciphertext)?)
     }
 
-    fn chunk_hmac(&self, data: &[u8], header: &FileHeader, chunk_number: usize) -> Vec<u8> {
-        Hmac::<Sha256>::new_from_slice(self.key.mac_key())
+    fn chunk_hmac(&self, data: &[u8], header: &FileHeader, chunk_number: u64) -> Result<Vec<u8>> {
+        Ok(Hmac::<Sha256>::new_from_slice(self.key.get()?.mac_key())
             // Ok to unwrap, HMAC can take keys of any size
             .unwrap()
             .chain_update(&header.nonce)
@@ -82,22 +117,27 @@ impl<'k> Cryptor<'k> {
             .chain_update(data)
             .finalize()
             .into_bytes()
-            .to_vec()
+            .to_vec())
     }
 
-    fn encrypt_chunk_with_nonce(
+    // Deterministic, for test vectors. Everything else encrypts with a random nonce.
+    pub(crate) fn encrypt_chunk_with_nonce(
         &self,
         nonce: &[u8],
         chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>> {
+        if nonce.len() != NONCE_LEN {
+            bail!("invalid chunk nonce length: {}", nonce.len());
+        }
+
         let mut buffer = Vec::with_capacity(NONCE_LEN + chunk.len() + MAC_LEN);
         buffer.extend(nonce);
         buffer.extend(self.aes_ctr(chunk, &header.content_key(), nonce)?);
-        buffer.extend(self.chunk_hmac(&buffer, header, chunk_number));
+        buffer.extend(self.chunk_hmac(&buffer, header, chunk_number)?);
 
-        debug_assert!(buffer.len() <= MAX_ENCRYPTED_CHUNK_LEN);
+        debug_assert!(buffer.len() <= self.max_encrypted_chunk_len());
 
         Ok(buffer)
     }
@@ -109,11 +149,11 @@ impl<'k> FileCryptor for Cryptor<'k> {
     }
 
     fn max_chunk_len(&self) -> usize {
-        MAX_CHUNK_LEN
+        self.chunk_len
     }
 
     fn max_encrypted_chunk_len(&self) -> usize {
-        MAX_ENCRYPTED_CHUNK_LEN
+        NONCE_LEN + self.chunk_len + MAC_LEN
     }
 
     fn new_header(&self) -> Result<FileHeader> {
@@ -123,14 +163,13 @@ impl<'k> FileCryptor for Cryptor<'k> {
     fn encrypt_header(&self, header: &FileHeader) -> Result<Vec<u8>> {
         let mut buffer = Vec::with_capacity(ENCRYPTED_HEADER_LEN);
         buffer.extend(&header.nonce);
-        buffer.extend(self.aes_ctr(&header.payload, self.key.enc_key(), &header.nonce)?);
-        buffer.extend(util::hmac(&buffer, self.key));
+        buffer.extend(self.aes_ctr(&header.payload, self.key.get()?.enc_key(), &header.nonce)?);
+        buffer.extend(util::hmac(&buffer, &*self.key.get()?));
         debug_assert_eq!(buffer.len(), ENCRYPTED_HEADER_LEN);
         Ok(buffer)
     }
 
-    fn decrypt_header(&self, encrypted_header: impl AsRef<[u8]>) -> Result<FileHeader> {
-        let encrypted_header = encrypted_header.as_ref();
+    fn decrypt_header(&self, encrypted_header: &[u8]) -> Result<FileHeader> {
         if encrypted_header.len() != ENCRYPTED_HEADER_LEN {
             bail!("invalid header length: {}", encrypted_header.len());
         }
@@ -139,7 +178,10 @@ impl<'k> FileCryptor for Cryptor<'k> {
         let expected_mac = encrypted_header[NONCE_LEN + PAYLOAD_LEN..].to_vec();
 
         // First, verify the HMAC
-        let actual_mac = util::hmac(&encrypted_header[..NONCE_LEN + PAYLOAD_LEN], self.key);
+        let actual_mac = util::hmac(
+            &encrypted_header[..NONCE_LEN + PAYLOAD_LEN],
+            &*self.key.get()?,
+        );
         if actual_mac != expected_mac {
             bail!("failed to verify header MAC");
         }
@@ -147,19 +189,18 @@ impl<'k> FileCryptor for Cryptor<'k> {
         // Next, decrypt the payload
         let nonce = encrypted_header[..NONCE_LEN].to_vec();
         let encrypted_payload = &encrypted_header[NONCE_LEN..NONCE_LEN + PAYLOAD_LEN];
-        let payload = self.aes_ctr(encrypted_payload, self.key.enc_key(), &nonce)?;
+        let payload = self.aes_ctr(encrypted_payload, self.key.get()?.enc_key(), &nonce)?;
 
         Ok(FileHeader { nonce, payload })
     }
 
     fn encrypt_chunk(
         &self,
-        chunk: impl AsRef<[u8]>,
+        chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>> {
-        let chunk = chunk.as_ref();
-        if chunk.is_empty() || chunk.len() > MAX_CHUNK_LEN {
+        if chunk.is_empty() || chunk.len() > self.chunk_len {
             bail!("invalid cleartext chunk length: {}", chunk.len());
         }
 
@@ -170,13 +211,12 @@ impl<'k> FileCryptor for Cryptor<'k> {
 
     fn decrypt_chunk(
         &self,
-        encrypted_chunk: impl AsRef<[u8]>,
+        encrypted_chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>> {
-        let encrypted_chunk = encrypted_chunk.as_ref();
         if encrypted_chunk.len() <= NONCE_LEN + MAC_LEN
-            || encrypted_chunk.len() > MAX_ENCRYPTED_CHUNK_LEN
+            || encrypted_chunk.len() > self.max_encrypted_chunk_len()
         {
             bail!("invalid ciphertext chunk length: {}", encrypted_chunk.len());
         }
@@ -184,7 +224,7 @@ impl<'k> FileCryptor for Cryptor<'k> {
         // First, verify the HMAC
         let (nonce_and_chunk, expected_mac) =
             encrypted_chunk.split_at(encrypted_chunk.len() - MAC_LEN);
-        let actual_mac = self.chunk_hmac(nonce_and_chunk, header, chunk_number);
+        let actual_mac = self.chunk_hmac(nonce_and_chunk, header, chunk_number)?;
         if actual_mac != expected_mac {
             bail!("failed to verify chunk MAC");
         }
@@ -197,8 +237,8 @@ impl<'k> FileCryptor for Cryptor<'k> {
         self.aes_ctr(chunk, &header.content_key(), &nonce)
     }
 
-    fn hash_dir_id(&self, dir_id: impl AsRef<str>) -> Result<PathBuf> {
-        let ciphertext = self.aes_siv_encrypt(dir_id.as_ref().as_bytes(), &[])?;
+    fn hash_dir_id(&self, dir_id: &DirId) -> Result<PathBuf> {
+        let ciphertext = self.aes_siv_encrypt(dir_id.as_str().as_bytes(), &[])?;
         let hash = Sha1::new().chain_update(ciphertext).finalize();
         let base32 = Base32Upper::encode_string(&hash);
         let (first, second) = base32.split_at(2);
@@ -207,27 +247,19 @@ impl<'k> FileCryptor for Cryptor<'k> {
 
     // TODO: "The cleartext name of a file gets encoded using UTF-8 in Normalization Form C to get
     // a unique binary representation." https://github.com/unicode-rs/unicode-normalization
-    fn encrypt_name(
-        &self,
-        name: impl AsRef<OsStr>,
-        parent_dir_id: impl AsRef<str>,
-    ) -> Result<String> {
+    fn encrypt_name(&self, name: &OsStr, parent_dir_id: &str) -> Result<String> {
         Ok(Base64Url::encode_string(&self.aes_siv_encrypt(
             // TODO: Is it okay to use lossy UTF-8 conversion?
-            name.as_ref().to_string_lossy().as_bytes(),
-            &[parent_dir_id.as_ref().as_bytes()],
+            name.to_string_lossy().as_bytes(),
+            &[parent_dir_id.as_bytes()],
         )?))
     }
 
-    fn decrypt_name(
-        &self,
-        encrypted_name: impl AsRef<str>,
-        parent_dir_id: impl AsRef<str>,
-    ) -> Result<String> {
+    fn decrypt_name(&self, encrypted_name: &str, parent_dir_id: &str) -> Result<String> {
         // TODO: Can we assume the decrypted bytes are valid UTF-8?
         Ok(String::from_utf8(self.aes_siv_decrypt(
-            &Base64Url::decode_vec(encrypted_name.as_ref())?,
-            &[parent_dir_id.as_ref().as_bytes()],
+            &Base64Url::decode_vec(encrypted_name)?,
+            &[parent_dir_id.as_bytes()],
         )?)?)
     }
 }
@@ -235,13 +267,13 @@ impl<'k> FileCryptor for Cryptor<'k> {
 #[cfg(test)]
 mod tests {
     use base64ct::Base64;
+    use zeroize::Zeroizing;
 
     use super::*;
 
     #[test]
     fn file_chunk_test() {
-        // Safe, this is for test purposes only
-        let key = unsafe { MasterKey::from_bytes([13_u8; SUBKEY_LEN * 2]) };
+        let key = MasterKey::from_raw(Zeroizing::new([13_u8; SUBKEY_LEN * 2])).unwrap();
         let cryptor = Cryptor::new(&key);
         let header = FileHeader {
             nonce: vec![19; NONCE_LEN],
@@ -261,4 +293,70 @@ mod tests {
             chunk
         );
     }
+
+    #[test]
+    fn large_chunk_number_test() {
+        let key = MasterKey::from_raw(Zeroizing::new([13_u8; SUBKEY_LEN * 2])).unwrap();
+        let cryptor = Cryptor::new(&key);
+        let header = FileHeader {
+            nonce: vec![19; NONCE_LEN],
+            payload: vec![23; PAYLOAD_LEN],
+        };
+        let chunk = b"the quick brown fox jumps over the lazy dog".to_vec();
+
+        // Chunk numbers are always authenticated as 8-byte big-endian integers, even on 32-bit
+        // targets, so chunks past the 4 GiB mark must not collide with earlier chunks
+        let chunk_number = (1 << 32) + 2;
+        let ciphertext = cryptor
+            .encrypt_chunk_with_nonce(&[0; NONCE_LEN], &chunk, &header, chunk_number)
+            .unwrap();
+        assert_eq!(
+            Base64::encode_string(&ciphertext),
+            "AAAAAAAAAAAAAAAAAAAAAPEq/PjcykUIlDRazM36igCN1QKikATEKglKUEDWiEkMGujfnzOMHOLK+h0PyM6piSDMseY9M362Dfx/+Ar3/s2j/EYQQxxKG1aUww=="
+        );
+
+        // Cross-check the MAC against one computed by hand
+        let (nonce_and_chunk, mac) = ciphertext.split_at(ciphertext.len() - MAC_LEN);
+        let expected_mac = Hmac::<Sha256>::new_from_slice(key.mac_key())
+            .unwrap()
+            .chain_update([19; NONCE_LEN])
+            .chain_update([0, 0, 0, 1, 0, 0, 0, 2])
+            .chain_update(nonce_and_chunk)
+            .finalize()
+            .into_bytes();
+        assert_eq!(mac, expected_mac.as_slice());
+        assert_eq!(
+            cryptor
+                .decrypt_chunk(&ciphertext, &header, chunk_number)
+                .unwrap(),
+            chunk
+        );
+        assert!(cryptor.decrypt_chunk(&ciphertext, &header, 2).is_err());
+    }
+
+    #[test]
+    fn custom_chunk_len_test() {
+        let key = MasterKey::from_raw(Zeroizing::new([13_u8; SUBKEY_LEN * 2])).unwrap();
+        let cryptor = Cryptor::with_chunk_len(&key, 64 * 1024);
+        let header = cryptor.new_header().unwrap();
+        assert_eq!(cryptor.max_chunk_len(), 64 * 1024);
+        assert_eq!(
+            cryptor.max_encrypted_chunk_len(),
+            NONCE_LEN + 64 * 1024 + MAC_LEN
+        );
+
+        // Chunks longer than the default are only accepted by a cryptor with the same chunk length
+        let chunk = vec![7; 64 * 1024];
+        let ciphertext = cryptor.encrypt_chunk(&chunk, &header, 0).unwrap();
+        assert_eq!(
+            cryptor.decrypt_chunk(&ciphertext, &header, 0).unwrap(),
+            chunk
+        );
+        assert!(Cryptor::new(&key)
+            .decrypt_chunk(&ciphertext, &header, 0)
+            .is_err());
+        assert!(cryptor
+            .encrypt_chunk(&[7; 64 * 1024 + 1], &header, 0)
+            .is_err());
+    }
 }
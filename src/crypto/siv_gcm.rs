@@ -8,9 +8,12 @@ use color_eyre::eyre::bail;
 use rand_core::{OsRng, RngCore};
 use sha1::{Digest, Sha1};
 
-use crate::{key::SUBKEY_LEN, MasterKey, Result};
+use crate::{
+    key::{KeyRef, SharedKey, SUBKEY_LEN},
+    MasterKey, Result,
+};
 
-use super::{FileCryptor, FileHeader, HEADER_RESERVED_LEN};
+use super::{DirId, FileCryptor, FileHeader, HEADER_RESERVED_LEN};
 
 // General constants
 const NONCE_LEN: usize = 12;
@@ -22,17 +25,47 @@ const PAYLOAD_LEN: usize = HEADER_RESERVED_LEN + CONTENT_KEY_LEN;
 const ENCRYPTED_HEADER_LEN: usize = NONCE_LEN + PAYLOAD_LEN + TAG_LEN;
 
 // File content constants
-const MAX_CHUNK_LEN: usize = 32 * 1024;
-const MAX_ENCRYPTED_CHUNK_LEN: usize = NONCE_LEN + MAX_CHUNK_LEN + TAG_LEN;
+const CHUNK_LEN: usize = 32 * 1024;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Cryptor<'k> {
-    key: &'k MasterKey,
+    key: KeyRef<'k>,
+    chunk_len: usize,
 }
 
 impl<'k> Cryptor<'k> {
     pub fn new(key: &'k MasterKey) -> Self {
-        Self { key }
+        Self {
+            key: KeyRef::Borrowed(key),
+            chunk_len: CHUNK_LEN,
+        }
+    }
+
+    /// A cryptor for the key of a vault, which fails with [`VaultLocked`](crate::VaultLocked)
+    /// once the vault is locked.
+    pub(crate) fn shared(key: &'k SharedKey) -> Self {
+        Self {
+            key: KeyRef::Shared(key),
+            chunk_len: CHUNK_LEN,
+        }
+    }
+
+    /// A cryptor that splits file contents into chunks of `chunk_len` bytes, rather than the
+    /// 32 KiB of the vault format.
+    ///
+    /// This is experimental and not interoperable: files encrypted this way can only be read with
+    /// the same chunk length, which is recorded nowhere, and Cryptomator can't read them at all.
+    /// Names, directory IDs, and headers are encrypted as usual.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_len` is 0.
+    pub fn with_chunk_len(key: &'k MasterKey, chunk_len: usize) -> Self {
+        assert!(chunk_len > 0, "chunk length must not be 0");
+        Self {
+            key: KeyRef::Borrowed(key),
+            chunk_len,
+        }
     }
 
     fn aes_gcm_encrypt(
@@ -80,10 +113,11 @@ impl<'k> Cryptor<'k> {
 
         // AES-SIV takes both the encryption key and mac key, but in reverse order
         // TODO: Use slice flatten() method when stabilized
+        let master_key = self.key.get()?;
         let mut key = [0_u8; SUBKEY_LEN * 2];
         let (left, right) = key.split_at_mut(SUBKEY_LEN);
-        left.copy_from_slice(self.key.mac_key());
-        right.copy_from_slice(self.key.enc_key());
+        left.copy_from_slice(master_key.mac_key());
+        right.copy_from_slice(master_key.enc_key());
 
         Ok(Aes256Siv::new(&key.into()).encrypt(associated_data, plaintext)?)
     }
@@ -93,21 +127,29 @@ impl<'k> Cryptor<'k> {
 
         // AES-SIV takes both the encryption key and mac key, but in reverse order
         // TODO: Use slice flatten() method when stabilized
+        let master_key = self.key.get()?;
         let mut key = [0_u8; SUBKEY_LEN * 2];
         let (left, right) = key.split_at_mut(SUBKEY_LEN);
-        left.copy_from_slice(self.key.mac_key());
-        right.copy_from_slice(self.key.enc_key());
+        left.copy_from_slice(master_key.mac_key());
+        right.copy_from_slice(master_key.enc_key());
 
         Ok(Aes256Siv::new(&key.into()).decrypt(associated_data, ciphertext)?)
     }
 
-    fn encrypt_chunk_with_nonce(
+    // Deterministic, for test vectors. Everything else encrypts with a random nonce.
+    pub(crate) fn encrypt_chunk_with_nonce(
         &self,
-        nonce: &[u8; NONCE_LEN],
+        nonce: &[u8],
         chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>> {
+        if nonce.len() != NONCE_LEN {
+            bail!("invalid chunk nonce length: {}", nonce.len());
+        }
+
+        // Chunks are encrypted with the content key, but not once the vault is locked
+        self.key.get()?;
         let mut buffer = Vec::with_capacity(NONCE_LEN + chunk.len() + TAG_LEN);
 
         let mut associated_data = chunk_number.to_be_bytes().to_vec();
@@ -119,7 +161,7 @@ impl<'k> Cryptor<'k> {
         buffer.extend(ciphertext);
         buffer.extend(tag);
 
-        debug_assert!(buffer.len() <= MAX_ENCRYPTED_CHUNK_LEN);
+        debug_assert!(buffer.len() <= self.max_encrypted_chunk_len());
 
         Ok(buffer)
     }
@@ -131,11 +173,11 @@ impl<'k> FileCryptor for Cryptor<'k> {
     }
 
     fn max_chunk_len(&self) -> usize {
-        MAX_CHUNK_LEN
+        self.chunk_len
     }
 
     fn max_encrypted_chunk_len(&self) -> usize {
-        MAX_ENCRYPTED_CHUNK_LEN
+        NONCE_LEN + self.chunk_len + TAG_LEN
     }
 
     fn new_header(&self) -> Result<FileHeader> {
@@ -144,8 +186,12 @@ impl<'k> FileCryptor for Cryptor<'k> {
 
     fn encrypt_header(&self, header: &FileHeader) -> Result<Vec<u8>> {
         let mut buffer = Vec::with_capacity(ENCRYPTED_HEADER_LEN);
-        let (ciphertext, tag) =
-            self.aes_gcm_encrypt(&header.payload, self.key.enc_key(), &header.nonce, &[])?;
+        let (ciphertext, tag) = self.aes_gcm_encrypt(
+            &header.payload,
+            self.key.get()?.enc_key(),
+            &header.nonce,
+            &[],
+        )?;
 
         buffer.extend(&header.nonce);
         buffer.extend(ciphertext);
@@ -156,8 +202,7 @@ impl<'k> FileCryptor for Cryptor<'k> {
         Ok(buffer)
     }
 
-    fn decrypt_header(&self, encrypted_header: impl AsRef<[u8]>) -> Result<FileHeader> {
-        let encrypted_header = encrypted_header.as_ref();
+    fn decrypt_header(&self, encrypted_header: &[u8]) -> Result<FileHeader> {
         if encrypted_header.len() != ENCRYPTED_HEADER_LEN {
             bail!("invalid header length: {}", encrypted_header.len());
         }
@@ -167,20 +212,24 @@ impl<'k> FileCryptor for Cryptor<'k> {
         let encrypted_payload = encrypted_header[NONCE_LEN..NONCE_LEN + PAYLOAD_LEN].to_vec();
         let tag = encrypted_header[NONCE_LEN + PAYLOAD_LEN..].to_vec();
 
-        let payload =
-            self.aes_gcm_decrypt(&encrypted_payload, self.key.enc_key(), &nonce, &[], &tag)?;
+        let payload = self.aes_gcm_decrypt(
+            &encrypted_payload,
+            self.key.get()?.enc_key(),
+            &nonce,
+            &[],
+            &tag,
+        )?;
 
         Ok(FileHeader { nonce, payload })
     }
 
     fn encrypt_chunk(
         &self,
-        chunk: impl AsRef<[u8]>,
+        chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>> {
-        let chunk = chunk.as_ref();
-        if chunk.is_empty() || chunk.len() > MAX_CHUNK_LEN {
+        if chunk.is_empty() || chunk.len() > self.chunk_len {
             bail!("invalid cleartext chunk length: {}", chunk.len());
         }
 
@@ -191,17 +240,18 @@ impl<'k> FileCryptor for Cryptor<'k> {
 
     fn decrypt_chunk(
         &self,
-        encrypted_chunk: impl AsRef<[u8]>,
+        encrypted_chunk: &[u8],
         header: &FileHeader,
-        chunk_number: usize,
+        chunk_number: u64,
     ) -> Result<Vec<u8>> {
-        let encrypted_chunk = encrypted_chunk.as_ref();
         if encrypted_chunk.len() <= NONCE_LEN + TAG_LEN
-            || encrypted_chunk.len() > MAX_ENCRYPTED_CHUNK_LEN
+            || encrypted_chunk.len() > self.max_encrypted_chunk_len()
         {
             bail!("invalid ciphertext chunk length: {}", encrypted_chunk.len());
         }
 
+        // Chunks are decrypted with the content key, but not once the vault is locked
+        self.key.get()?;
         let (nonce_and_chunk, tag) = encrypted_chunk.split_at(encrypted_chunk.len() - TAG_LEN);
         let (nonce, chunk) = nonce_and_chunk.split_at(NONCE_LEN);
         // Ok to convert to sized arrays - we know the lengths at this point
@@ -214,8 +264,8 @@ impl<'k> FileCryptor for Cryptor<'k> {
         self.aes_gcm_decrypt(chunk, &header.content_key(), &nonce, &associated_data, &tag)
     }
 
-    fn hash_dir_id(&self, dir_id: impl AsRef<str>) -> Result<PathBuf> {
-        let ciphertext = self.aes_siv_encrypt(dir_id.as_ref().as_bytes(), &[])?;
+    fn hash_dir_id(&self, dir_id: &DirId) -> Result<PathBuf> {
+        let ciphertext = self.aes_siv_encrypt(dir_id.as_str().as_bytes(), &[])?;
         let hash = Sha1::new().chain_update(ciphertext).finalize();
         let base32 = Base32Upper::encode_string(&hash);
         let (first, second) = base32.split_at(2);
@@ -224,27 +274,19 @@ impl<'k> FileCryptor for Cryptor<'k> {
 
     // TODO: "The cleartext name of a file gets encoded using UTF-8 in Normalization Form C to get
     // a unique binary representation." https://github.com/unicode-rs/unicode-normalization
-    fn encrypt_name(
-        &self,
-        name: impl AsRef<OsStr>,
-        parent_dir_id: impl AsRef<str>,
-    ) -> Result<String> {
+    fn encrypt_name(&self, name: &OsStr, parent_dir_id: &str) -> Result<String> {
         Ok(Base64Url::encode_string(&self.aes_siv_encrypt(
             // TODO: Is it okay to use lossy UTF-8 conversion?
-            name.as_ref().to_string_lossy().as_bytes(),
-            &[parent_dir_id.as_ref().as_bytes()],
+            name.to_string_lossy().as_bytes(),
+            &[parent_dir_id.as_bytes()],
         )?))
     }
 
-    fn decrypt_name(
-        &self,
-        encrypted_name: impl AsRef<str>,
-        parent_dir_id: impl AsRef<str>,
-    ) -> Result<String> {
+    fn decrypt_name(&self, encrypted_name: &str, parent_dir_id: &str) -> Result<String> {
         // TODO: Can we assume the decrypted bytes are valid UTF-8?
         Ok(String::from_utf8(self.aes_siv_decrypt(
-            &Base64Url::decode_vec(encrypted_name.as_ref())?,
-            &[parent_dir_id.as_ref().as_bytes()],
+            &Base64Url::decode_vec(encrypted_name)?,
+            &[parent_dir_id.as_bytes()],
         )?)?)
     }
 }
@@ -252,13 +294,13 @@ impl<'k> FileCryptor for Cryptor<'k> {
 #[cfg(test)]
 mod tests {
     use base64ct::Base64;
+    use zeroize::Zeroizing;
 
     use super::*;
 
     #[test]
     fn file_chunk_test() {
-        // Safe, this is for test purposes only
-        let key = unsafe { MasterKey::from_bytes([13_u8; SUBKEY_LEN * 2]) };
+        let key = MasterKey::from_raw(Zeroizing::new([13_u8; SUBKEY_LEN * 2])).unwrap();
         let cryptor = Cryptor::new(&key);
         let header = FileHeader {
             nonce: vec![19; NONCE_LEN],
@@ -278,4 +320,59 @@ mod tests {
             chunk
         );
     }
+
+    #[test]
+    fn large_chunk_number_test() {
+        let key = MasterKey::from_raw(Zeroizing::new([13_u8; SUBKEY_LEN * 2])).unwrap();
+        let cryptor = Cryptor::new(&key);
+        let header = FileHeader {
+            nonce: vec![19; NONCE_LEN],
+            payload: vec![23; PAYLOAD_LEN],
+        };
+        let chunk = b"the quick brown fox jumps over the lazy dog".to_vec();
+
+        // Chunk numbers are always authenticated as 8-byte big-endian integers, even on 32-bit
+        // targets, so chunks past the 4 GiB mark must not collide with earlier chunks
+        let chunk_number = (1 << 32) + 2;
+        let ciphertext = cryptor
+            .encrypt_chunk_with_nonce(&[0; NONCE_LEN], &chunk, &header, chunk_number)
+            .unwrap();
+        assert_eq!(
+            Base64::encode_string(&ciphertext),
+            "AAAAAAAAAAAAAAAABuWa0yODDKHFtRizEcmdCC+Lj4yIt17WEiaw4kNyO3sLHx+6HNwklpgcijcFhTGKoPMk6hf0Lr8WKNU="
+        );
+        assert_eq!(
+            cryptor
+                .decrypt_chunk(&ciphertext, &header, chunk_number)
+                .unwrap(),
+            chunk
+        );
+        assert!(cryptor.decrypt_chunk(&ciphertext, &header, 2).is_err());
+    }
+
+    #[test]
+    fn custom_chunk_len_test() {
+        let key = MasterKey::from_raw(Zeroizing::new([13_u8; SUBKEY_LEN * 2])).unwrap();
+        let cryptor = Cryptor::with_chunk_len(&key, 64 * 1024);
+        let header = cryptor.new_header().unwrap();
+        assert_eq!(cryptor.max_chunk_len(), 64 * 1024);
+        assert_eq!(
+            cryptor.max_encrypted_chunk_len(),
+            NONCE_LEN + 64 * 1024 + TAG_LEN
+        );
+
+        // Chunks longer than the default are only accepted by a cryptor with the same chunk length
+        let chunk = vec![7; 64 * 1024];
+        let ciphertext = cryptor.encrypt_chunk(&chunk, &header, 0).unwrap();
+        assert_eq!(
+            cryptor.decrypt_chunk(&ciphertext, &header, 0).unwrap(),
+            chunk
+        );
+        assert!(Cryptor::new(&key)
+            .decrypt_chunk(&ciphertext, &header, 0)
+            .is_err());
+        assert!(cryptor
+            .encrypt_chunk(&[7; 64 * 1024 + 1], &header, 0)
+            .is_err());
+    }
 }
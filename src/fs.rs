@@ -1,485 +1,1501 @@
 use std::{
-    collections::BTreeMap,
-    ffi::OsStr,
-    fmt::Debug,
-    fs::{self, File, FileTimes, Metadata, OpenOptions, Permissions},
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt::{self, Debug, Display},
+    fs::Permissions,
+    hash::{DefaultHasher, Hash, Hasher},
     io::{self, Read, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::PermissionsExt,
+    },
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::{crypto::FileCryptor, util, Result, Vault};
+use crate::{
+    crypto::{DirId, FileCryptor},
+    storage::{FileTimes, Metadata, OpenOptions, ThrottleHandle, VaultStorage},
+    util, Result, Vault, VaultLocked,
+};
 
+mod archive;
+#[cfg(feature = "tokio")]
+mod async_fs;
+mod conflicts;
+mod context;
+mod copy;
+mod dir_cache_file;
+mod dir_tree;
 mod encrypted_file;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod fingerprint;
+mod freeze;
 pub mod fuse;
+mod glob;
+mod header_cache;
+mod import;
+#[cfg(feature = "nfs")]
+pub mod nfs;
+mod observer;
+mod orphans;
+mod paths;
+mod read_dir;
+mod reencrypt;
+mod stats;
 mod translator;
-
-use color_eyre::eyre::bail;
+mod usage;
+mod vault_lock;
+mod walk;
+#[cfg(feature = "webdav")]
+pub mod webdav;
+
+pub use archive::{ExportOptions, ExportReport};
+#[cfg(feature = "tokio")]
+pub use async_fs::{AsyncEncryptedFile, AsyncEncryptedFileSystem};
+use color_eyre::eyre::{bail, eyre, WrapErr};
+pub use context::ErrorContext;
+use context::WithContext;
+pub use copy::CopyReport;
 pub use encrypted_file::EncryptedFile;
+pub use fingerprint::{DigestAlgorithm, Fingerprint};
+use freeze::{Freeze, WriteGuard};
+pub use freeze::{FreezeGuard, WhileFrozen};
+pub use glob::Glob;
+use header_cache::HeaderCache;
+pub use import::{ImportOptions, ImportReport};
+pub use observer::{CacheKind, CountingObserver, Observer};
+pub use orphans::OrphanDir;
+pub use read_dir::ReadDir;
+pub use reencrypt::ReencryptReport;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+pub use stats::{StatsOptions, VaultStats};
+use tracing::{field, instrument, Span};
 use translator::Translator;
+pub use usage::{DuOptions, DuReport};
 use uuid::Uuid;
+use vault_lock::VaultLock;
+pub use walk::{Walk, WalkEntry, WalkOrder};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum FileKind {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FileKind {
     File,
     Directory,
     Symlink,
 }
 
-#[derive(Debug)]
-struct DirEntry {
-    kind: FileKind,
-    size: u64,
-    metadata: Metadata,
+/// Information about a cleartext file, directory, or symlink. It holds no handle to the storage,
+/// so it can be built from any metadata and sent elsewhere, like in a listing over the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DirEntry {
+    pub kind: FileKind,
+    /// The cleartext size of a file or symlink target, or the size of a ciphertext directory.
+    pub size: u64,
+    /// Metadata of the underlying ciphertext file or directory.
+    pub metadata: Metadata,
+    /// When the ciphertext was last modified, or failing that, when its metadata changed or it
+    /// was created. Only storage that keeps no timestamps at all reports the epoch.
+    pub modified: SystemTime,
+    /// When the ciphertext was last accessed, or [`DirEntry::modified`] if that's unknown.
+    pub accessed: SystemTime,
+    /// When the metadata of the ciphertext last changed, like `st_ctime`, or
+    /// [`DirEntry::modified`] if that's unknown.
+    pub changed: SystemTime,
+    /// When the ciphertext was created, or [`DirEntry::modified`] if the storage doesn't keep
+    /// birth times. On Linux, this is read with `statx` where the file system supports it.
+    pub created: SystemTime,
+}
+
+impl DirEntry {
+    /// An entry with timestamps taken from `metadata`, falling back as described for each field.
+    pub fn new(kind: FileKind, size: u64, metadata: Metadata) -> Self {
+        let modified = metadata
+            .modified
+            .or(metadata.changed)
+            .or(metadata.created)
+            .unwrap_or(UNIX_EPOCH);
+
+        Self {
+            kind,
+            size,
+            accessed: metadata.accessed.unwrap_or(modified),
+            changed: metadata.changed.unwrap_or(modified),
+            created: metadata.created.unwrap_or(modified),
+            modified,
+            metadata,
+        }
+    }
+
+    /// Like [`DirEntry::new`], for the metadata of a file or directory on local disk.
+    pub fn from_metadata(kind: FileKind, size: u64, metadata: std::fs::Metadata) -> Self {
+        Self::new(kind, size, metadata.into())
+    }
+}
+
+/// A ciphertext entry that was left out of a directory listing because it couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{}: {reason}", .ciphertext_name.to_string_lossy())]
+pub struct EntryError {
+    pub ciphertext_name: OsString,
+    pub reason: EntryErrorReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum EntryErrorReason {
+    /// The entry is a copy made by a sync client for conflicting changes, such as
+    /// `<name> (conflicted copy).c9r`, of the entry with the ciphertext name `original_name`, and
+    /// its cleartext name couldn't be recovered.
+    #[error("sync conflict copy of {original_name}")]
+    SyncConflict { original_name: String },
+    /// The entry's name couldn't be decrypted, or it isn't a valid file, directory, or symlink.
+    #[error("{0}")]
+    Invalid(String),
 }
 
-#[derive(Debug, Copy, Clone)]
+/// The reasons a cleartext entry can't be looked up or listed.
+#[derive(Debug, thiserror::Error)]
+pub enum FsError {
+    #[error("no such file or directory")]
+    NotFound,
+    /// A component of the path is a file or symlink, rather than a directory.
+    #[error("not a directory")]
+    NotADirectory,
+    /// A path with a `.`, `..`, or empty component or a NUL byte, or a name that isn't a single
+    /// plain path component. Paths are never resolved against anything outside the vault.
+    #[error("invalid path")]
+    InvalidPath,
+    #[error("failed to decrypt the name of {}", .ciphertext_name.to_string_lossy())]
+    NameDecryption {
+        ciphertext_name: OsString,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The ciphertext file at `path` is locked by another handle, such as another process using
+    /// the vault. Trying again later may succeed.
+    #[error("{} is locked by another process", .path.display())]
+    Locked { path: PathBuf },
+    /// The file system was made [read-only](EncryptedFileSystem::read_only).
+    #[error("read-only file system")]
+    ReadOnly,
+    /// Another file system has [locked](EncryptedFileSystem::acquire_lock) the vault. `pid` and
+    /// `since` tell which process took the lock and when, if it's still running.
+    #[error("the vault is in use by {}", match .pid {
+        Some(pid) => format!("process {pid}"),
+        None => "another process".to_string(),
+    })]
+    VaultInUse {
+        pid: Option<u32>,
+        since: Option<SystemTime>,
+    },
+    /// The file system is [frozen](EncryptedFileSystem::freeze) and set to fail changes while it
+    /// is. Trying again later may succeed.
+    #[error("the file system is frozen")]
+    Frozen,
+    /// The vault was [locked](Vault::lock), so its master key is gone.
+    #[error("the vault is locked")]
+    VaultLocked,
+    #[error(transparent)]
+    Io(io::Error),
+    /// Anything else, such as a corrupt dir.c9r or an entry that's neither a file, directory,
+    /// nor symlink.
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// I/O errors that wrap an [`FsError`], like those [converted](#impl-From<FsError>-for-Error)
+/// from one, are turned back into it.
+impl From<io::Error> for FsError {
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<FsError>()) {
+            // Ok to unwrap, we just checked what's inside
+            return *err.into_inner().unwrap().downcast().unwrap();
+        }
+
+        Self::Io(err)
+    }
+}
+
+impl From<color_eyre::Report> for FsError {
+    fn from(report: color_eyre::Report) -> Self {
+        if let Some(err) = report.downcast_ref::<io::Error>().and_then(wrapped_error) {
+            return err;
+        }
+
+        if report.is::<VaultLocked>() {
+            return Self::VaultLocked;
+        }
+
+        // Lookups that fail part of the way through may have already said why, in which case the
+        // context of the operation is moved over so it isn't lost
+        let context = report.downcast_ref::<ErrorContext>().cloned();
+        let report = match (report.downcast::<FsError>(), context) {
+            (Ok(err), Some(context)) if err.context().is_none() => {
+                return err.with_context(context)
+            }
+            (Ok(err), _) => return err,
+            (Err(report), _) => report,
+        };
+
+        // I/O errors keep their kind, with the whole report as the source
+        match report.downcast_ref::<io::Error>().map(io::Error::kind) {
+            Some(kind) => Self::Io(io::Error::new(kind, report)),
+            None => Self::Other(report.into()),
+        }
+    }
+}
+
+/// The error is kept as the source, so converting it back gives the same [`FsError`].
+impl From<FsError> for io::Error {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+impl FsError {
+    /// The kind of I/O error this is reported as, e.g. when it's converted into an
+    /// [`io::Error`].
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::NotFound => io::ErrorKind::NotFound,
+            Self::NotADirectory => io::ErrorKind::NotADirectory,
+            Self::InvalidPath => io::ErrorKind::InvalidInput,
+            Self::NameDecryption { .. } => io::ErrorKind::InvalidData,
+            Self::Locked { .. } | Self::Frozen => io::ErrorKind::WouldBlock,
+            Self::ReadOnly => io::ErrorKind::ReadOnlyFilesystem,
+            Self::VaultInUse { .. } => io::ErrorKind::ResourceBusy,
+            Self::VaultLocked => io::ErrorKind::PermissionDenied,
+            Self::Io(err) => err.kind(),
+            Self::Other(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+// The FsError an I/O error in a report was converted from, such as the ciphertext file being
+// locked by another handle or the file system being frozen, if it can be copied out.
+fn wrapped_error(err: &io::Error) -> Option<FsError> {
+    Some(match err.get_ref()?.downcast_ref::<FsError>()? {
+        FsError::NotFound => FsError::NotFound,
+        FsError::NotADirectory => FsError::NotADirectory,
+        FsError::InvalidPath => FsError::InvalidPath,
+        FsError::Locked { path } => FsError::Locked { path: path.clone() },
+        FsError::ReadOnly => FsError::ReadOnly,
+        FsError::VaultInUse { pid, since } => FsError::VaultInUse {
+            pid: *pid,
+            since: *since,
+        },
+        FsError::Frozen => FsError::Frozen,
+        FsError::VaultLocked => FsError::VaultLocked,
+        _ => return None,
+    })
+}
+
+/// How carefully an [`EncryptedFileSystem`] makes sure that changes survive a crash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Don't sync directories after changing them, so a crash may undo recent changes. New
+    /// entries still never appear half-written.
+    Fast,
+    /// Sync new entries and the directories they're added to or removed from.
+    #[default]
+    Safe,
+}
+
+#[derive(Debug, Clone)]
 pub struct EncryptedFileSystem<'v> {
     vault: &'v Vault,
+    durability: Durability,
+    repair_conflicts: bool,
+    read_only: bool,
+    trace_paths: bool,
+    observer: Option<Arc<dyn Observer>>,
+    throttle: Option<Arc<ThrottleHandle>>,
+    freeze: Arc<Freeze>,
+    while_frozen: WhileFrozen,
     translator: Translator<'v>,
+    header_cache: Arc<HeaderCache>,
+    dir_cache_file: Option<PathBuf>,
+    // Hashed directory paths (relative to `d`) to cleartext directory paths, built on demand
+    dir_paths: Arc<Mutex<HashMap<PathBuf, PathBuf>>>,
+    dir_locks: Arc<DirLocks>,
+    vault_lock: Option<Arc<VaultLock>>,
+}
+
+struct Traced<'a> {
+    value: &'a OsStr,
+    visible: bool,
+}
+
+impl Display for Traced<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.visible {
+            true => write!(f, "{}", self.value.to_string_lossy()),
+            false => f.write_str("<redacted>"),
+        }
+    }
+}
+
+/// Locks that serialize changes to the contents of directories. Directories share a fixed number
+/// of locks, picked by hashing their IDs.
+#[derive(Debug)]
+struct DirLocks([Mutex<()>; DirLocks::COUNT]);
+
+impl DirLocks {
+    const COUNT: usize = 64;
+
+    /// Lock the directories with the given IDs. Locks are always taken in the same order, so
+    /// concurrent callers can't deadlock.
+    fn lock(&self, dir_ids: &[&DirId]) -> Vec<MutexGuard<'_, ()>> {
+        let mut indices = dir_ids
+            .iter()
+            .map(|dir_id| {
+                let mut hasher = DefaultHasher::new();
+                dir_id.hash(&mut hasher);
+                hasher.finish() as usize % Self::COUNT
+            })
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices.dedup();
+
+        // The locks don't protect any data, so a panic while holding one doesn't matter
+        indices
+            .into_iter()
+            .map(|i| self.0[i].lock().unwrap_or_else(PoisonError::into_inner))
+            .collect()
+    }
+}
+
+impl Default for DirLocks {
+    fn default() -> Self {
+        Self(std::array::from_fn(|_| Mutex::new(())))
+    }
 }
 
 impl<'v> EncryptedFileSystem<'v> {
+    /// The default number of directory IDs kept in memory.
+    pub const DEFAULT_DIR_CACHE_CAPACITY: usize = 1024;
+
+    /// The longest symlink target in bytes, the same limit as the desktop app's.
+    pub const MAX_SYMLINK_TARGET_LEN: usize = 32_767;
+
     pub fn new(vault: &'v Vault) -> Self {
         Self {
             vault,
-            translator: Translator::new(vault),
+            durability: Default::default(),
+            repair_conflicts: false,
+            read_only: false,
+            trace_paths: false,
+            observer: None,
+            throttle: None,
+            freeze: Default::default(),
+            while_frozen: Default::default(),
+            translator: Translator::new(vault, Self::DEFAULT_DIR_CACHE_CAPACITY),
+            header_cache: Default::default(),
+            dir_cache_file: None,
+            dir_paths: Default::default(),
+            dir_locks: Default::default(),
+            vault_lock: None,
+        }
+    }
+
+    /// Keep up to `capacity` directory IDs in memory, so resolving a path doesn't need to read
+    /// the dir.c9r of every directory along the way. A capacity of 0 disables the cache.
+    pub fn dir_cache_capacity(mut self, capacity: usize) -> Self {
+        self.translator = Translator::new(self.vault, capacity);
+        self.translator.set_observer(self.observer.clone());
+        self.load_dir_cache();
+        self
+    }
+
+    /// Keep up to `capacity` decrypted file headers in memory, so opening the same file again
+    /// doesn't have to read and decrypt its header. A header is only used while its ciphertext
+    /// file has the same device, inode, and modification time. Headers are zeroized when they're
+    /// dropped from the cache. The cache is disabled by default.
+    pub fn header_cache_capacity(mut self, capacity: usize) -> Self {
+        self.header_cache = Arc::new(HeaderCache::new(capacity));
+        self
+    }
+
+    /// Keep directory IDs in an encrypted file at `path`, outside the vault, so they don't all
+    /// have to be read again the next time the vault is used. The file is read now and written by
+    /// [`save_dir_cache`](Self::save_dir_cache). Each directory in it is checked when it's first
+    /// looked up, and read again if its dir.c9r has changed. A file that can't be read, such as
+    /// one for another vault, is ignored and later replaced.
+    pub fn dir_cache_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.dir_cache_file = Some(path.into());
+        self.load_dir_cache();
+        self
+    }
+
+    /// Write the cached directory IDs to the file set with
+    /// [`dir_cache_file`](Self::dir_cache_file), if any.
+    pub fn save_dir_cache(&self) -> Result<()> {
+        match &self.dir_cache_file {
+            Some(path) => dir_cache_file::save(self.vault, path, &self.translator.persisted()),
+            None => Ok(()),
+        }
+    }
+
+    // Load the directories in the persistent cache file, if there is one.
+    fn load_dir_cache(&self) {
+        let Some(path) = &self.dir_cache_file else {
+            return;
+        };
+        match dir_cache_file::load(self.vault, path) {
+            Ok(dirs) => self.translator.load_persisted(dirs),
+            Err(err)
+                if err
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|err| err.kind() == io::ErrorKind::NotFound) => {}
+            Err(err) => {
+                tracing::warn!(path = %path.display(), "ignoring directory cache file: {err}");
+            }
+        }
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Copies made by sync clients for conflicting changes, such as `<name> (conflicted
+    /// copy).c9r`, can only be opened once their ciphertext is renamed to a valid name. With this
+    /// set, they're renamed as they're listed and show up under names like `report (Conflict
+    /// 1).txt`, and conflict copies of directory entries that point to the same directory are
+    /// removed. Otherwise, or while the file system is read-only, they're left out of listings.
+    pub fn repair_conflicts(mut self, repair_conflicts: bool) -> Self {
+        self.repair_conflicts = repair_conflicts;
+        self
+    }
+
+    /// Refuse to change anything in the vault. Creating, removing, or renaming entries, changing
+    /// their permissions or times, and opening files for writing all fail with
+    /// [`FsError::ReadOnly`] before the storage is touched, and conflict copies are never
+    /// repaired.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Lock the vault, so no other file system can change it while this one is in use, which
+    /// could leave its directories in a mess. Read-only file systems share the lock with each
+    /// other, so this has to come after [`read_only`](Self::read_only). The lock is released once
+    /// this and all its clones are dropped.
+    ///
+    /// Fails with [`FsError::VaultInUse`] if the vault is already locked, unless `force` is set,
+    /// in which case only a warning is logged. Lock files left behind by processes that died are
+    /// taken over.
+    pub fn acquire_lock(mut self, force: bool) -> Result<Self, FsError> {
+        match VaultLock::acquire(self.storage(), self.vault.path(), !self.read_only) {
+            Ok(lock) => self.vault_lock = Some(Arc::new(lock)),
+            Err(err @ FsError::VaultInUse { .. }) if force => {
+                tracing::warn!("using the vault anyway: {err}");
+            }
+            Err(err) => return Err(err),
         }
+
+        Ok(self)
     }
 
-    fn root_dir(&self) -> PathBuf {
-        self.vault
+    /// Include cleartext paths and names in tracing spans and events. They're redacted by
+    /// default, since they're part of what the vault protects.
+    pub fn trace_paths(mut self, trace_paths: bool) -> Self {
+        self.trace_paths = trace_paths;
+        self
+    }
+
+    /// Report chunk encryption and cache lookups to `observer`, which is also used by a
+    /// [`FuseFileSystem`](fuse::FuseFileSystem) built on this file system. Nothing is reported by
+    /// default.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.translator.set_observer(Some(observer.clone()));
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Read and write the contents of files no faster than `throttle` allows. The limits can be
+    /// changed through the handle while files are open. Contents aren't throttled by default.
+    pub fn throttle(mut self, throttle: Arc<ThrottleHandle>) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Set what changes do while the file system is [frozen](Self::freeze), which is to block by
+    /// default.
+    pub fn while_frozen(mut self, while_frozen: WhileFrozen) -> Self {
+        self.while_frozen = while_frozen;
+        self
+    }
+
+    // Call `f` with the observer, if there is one.
+    fn observe(&self, f: impl FnOnce(&dyn Observer)) {
+        if let Some(observer) = &self.observer {
+            f(&**observer);
+        }
+    }
+
+    /// [Lock](Vault::lock) the vault, zeroizing its master key along with the content keys of
+    /// cached file headers. Everything that needs a key fails with [`FsError::VaultLocked`] from
+    /// then on, including files that are still open.
+    pub fn lock_vault(&self) {
+        self.header_cache.clear();
+        self.vault.lock();
+    }
+
+    /// Forget any cached information about `cleartext_path` and everything below it. This is only
+    /// needed if the vault is changed by something other than this file system.
+    pub fn invalidate(&self, cleartext_path: impl AsRef<Path>) {
+        self.translator.invalidate(cleartext_path);
+    }
+
+    // A cleartext path or name to include in traces, redacted unless `trace_paths` is set.
+    fn traced<'a>(&self, value: &'a (impl AsRef<OsStr> + ?Sized)) -> Traced<'a> {
+        Traced {
+            value: value.as_ref(),
+            visible: self.trace_paths,
+        }
+    }
+
+    fn storage(&self) -> &'v dyn VaultStorage {
+        self.vault.storage()
+    }
+
+    fn root_dir(&self) -> Result<PathBuf> {
+        Ok(self
+            .vault
             .path()
             .join("d")
-            .join(self.vault.cryptor().hash_dir_id("").unwrap())
+            .join(self.vault.cryptor().hash_dir_id(&DirId::root())?))
     }
 
-    fn dir_entry(&self, cleartext_path: impl AsRef<Path>) -> Result<DirEntry> {
-        // TOOD: Handle case with no parent
-        let parent_dir_id = self
-            .translator
-            .get_dir_id(cleartext_path.as_ref().parent().unwrap())?;
-        let ciphertext_path = self
-            .translator
-            .get_ciphertext_path(&cleartext_path, parent_dir_id)?;
+    /// Get the entry for the cleartext file, directory, or symlink at `cleartext_path`. `/` and
+    /// the empty path refer to the root directory, like with [`root_entry`](Self::root_entry).
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %self.traced(cleartext_path.as_ref()), ciphertext_path = field::Empty)
+    )]
+    pub fn dir_entry(&self, cleartext_path: impl AsRef<Path>) -> Result<DirEntry, FsError> {
+        let cleartext_path = cleartext_path.as_ref();
+        self.with_context("look up", cleartext_path, || {
+            check_path(cleartext_path)?;
+            let Some(parent) = cleartext_path.parent() else {
+                return self.root_entry();
+            };
+            let parent_dir_id = self.translator.get_dir_id(parent)?;
+            let ciphertext_path = self
+                .translator
+                .get_ciphertext_path(cleartext_path, &parent_dir_id)?;
+            record_ciphertext_path(&ciphertext_path);
+
+            match self.ciphertext_dir_entry(ciphertext_path) {
+                // Paths through a file are translated as if the file were its parent directory, so
+                // check whether that's why nothing was found
+                Err(FsError::NotFound) if parent.parent().is_some() => {
+                    match self.dir_entry(parent)? {
+                        entry if entry.kind == FileKind::Directory => Err(FsError::NotFound),
+                        _ => Err(FsError::NotADirectory),
+                    }
+                }
+                result => result,
+            }
+        })
+    }
+
+    /// Get the entry for the root directory of the vault, which is also what the paths `/` and
+    /// the empty path refer to. Its metadata is that of the root's hashed directory.
+    pub fn root_entry(&self) -> Result<DirEntry, FsError> {
+        let metadata = self.storage().metadata(&self.root_dir()?)?;
+        Ok(DirEntry::new(FileKind::Directory, metadata.len(), metadata))
+    }
+
+    /// Get the entry for a full-length or shortened ciphertext path inside a hashed directory.
+    fn ciphertext_dir_entry(&self, ciphertext_path: impl AsRef<Path>) -> Result<DirEntry, FsError> {
+        let ciphertext_path = ciphertext_path.as_ref();
+        let storage = self.storage();
 
         // File, full-length name
-        if ciphertext_path.is_file() {
-            let meta = ciphertext_path.metadata()?;
+        if storage.is_file(ciphertext_path) {
+            let meta = storage.metadata(ciphertext_path)?;
             let size = util::get_cleartext_size(self.vault.cryptor(), meta.len());
-            return Ok(DirEntry {
-                kind: FileKind::File,
-                size,
-                metadata: meta,
-            });
+            return Ok(DirEntry::new(FileKind::File, size, meta));
         }
 
         // File, shortened name
-        if ciphertext_path.is_dir() && ciphertext_path.join("contents.c9r").is_file() {
-            let meta = ciphertext_path.join("contents.c9r").metadata()?;
+        if storage.is_file(&ciphertext_path.join("contents.c9r")) {
+            let meta = storage.metadata(&ciphertext_path.join("contents.c9r"))?;
             let size = util::get_cleartext_size(self.vault.cryptor(), meta.len());
-            return Ok(DirEntry {
-                kind: FileKind::File,
-                size,
-                metadata: meta,
-            });
+            return Ok(DirEntry::new(FileKind::File, size, meta));
         }
 
         // Directory, either full-length or shortened name
-        if ciphertext_path.is_dir() && ciphertext_path.join("dir.c9r").is_file() {
-            let dir_id = self.translator.get_dir_id(&cleartext_path)?;
-            let hashed_dir_id = self.vault.cryptor().hash_dir_id(dir_id).unwrap();
-            let meta = self.vault.path().join("d").join(hashed_dir_id).metadata()?;
-            return Ok(DirEntry {
-                kind: FileKind::Directory,
-                size: meta.len(),
-                metadata: meta,
-            });
+        if storage.is_file(&ciphertext_path.join("dir.c9r")) {
+            let dir_id = read_dir_id(storage, ciphertext_path)?;
+            let hashed_dir_id = self.vault.cryptor().hash_dir_id(&dir_id)?;
+            let meta = storage.metadata(&self.vault.path().join("d").join(hashed_dir_id))?;
+            return Ok(DirEntry::new(FileKind::Directory, meta.len(), meta));
         }
 
-        // Symlink, either full-length or shortened name
-        if ciphertext_path.is_dir() && ciphertext_path.join("symlink.c9r").is_file() {
-            let meta = ciphertext_path.join("symlink.c9r").metadata()?;
+        // Symlink, either full-length or shortened name. The cleartext size is the length of the
+        // target in bytes, so it's known without decrypting it.
+        if storage.is_file(&ciphertext_path.join("symlink.c9r")) {
+            let meta = storage.metadata(&ciphertext_path.join("symlink.c9r"))?;
             let size = util::get_cleartext_size(self.vault.cryptor(), meta.len());
-            return Ok(DirEntry {
-                kind: FileKind::Symlink,
-                size,
-                metadata: meta,
-            });
+            return Ok(DirEntry::new(FileKind::Symlink, size, meta));
         }
 
-        bail!("invalid file type");
+        if !storage.exists(ciphertext_path) {
+            return Err(FsError::NotFound);
+        }
+        Err(eyre!("invalid file type").into())
     }
 
-    fn dir_entries(&self, cleartext_dir: impl AsRef<Path>) -> Result<BTreeMap<PathBuf, DirEntry>> {
-        let dir_id = self.translator.get_dir_id(&cleartext_dir)?;
-        let hashed_dir_id = self.vault.cryptor().hash_dir_id(&dir_id).unwrap();
-        let hashed_dir_path = self.vault.path().join("d").join(hashed_dir_id);
-        let ciphertext_entries = hashed_dir_path
-            .read_dir()?
-            .collect::<io::Result<Vec<_>>>()?;
+    /// List a cleartext directory, failing if any entry can't be read.
+    pub fn dir_entries(
+        &self,
+        cleartext_dir: impl AsRef<Path>,
+    ) -> Result<BTreeMap<PathBuf, DirEntry>, FsError> {
+        Ok(self.list_dir(cleartext_dir.as_ref(), true)?.0)
+    }
 
-        let mut cleartext_entries: BTreeMap<PathBuf, DirEntry> = Default::default();
-        for entry in ciphertext_entries {
-            if entry.file_name() == "dirid.c9r" {
-                continue;
-            }
+    /// List a cleartext directory, skipping entries that can't be read and returning them
+    /// separately.
+    pub fn dir_entries_lossy(
+        &self,
+        cleartext_dir: impl AsRef<Path>,
+    ) -> Result<(BTreeMap<PathBuf, DirEntry>, Vec<EntryError>), FsError> {
+        self.list_dir(cleartext_dir.as_ref(), false)
+    }
 
-            let cleartext_name = self.translator.get_cleartext_name(entry.path(), &dir_id)?;
-            let cleartext_path = cleartext_dir.as_ref().join(&cleartext_name);
-            let entry = self.dir_entry(&cleartext_path)?;
-            cleartext_entries.insert(cleartext_path, entry);
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %self.traced(cleartext_dir), ciphertext_path = field::Empty)
+    )]
+    fn list_dir(
+        &self,
+        cleartext_dir: &Path,
+        strict: bool,
+    ) -> Result<(BTreeMap<PathBuf, DirEntry>, Vec<EntryError>), FsError> {
+        check_path(cleartext_dir)?;
+        let (dir_id, hashed_dir_path) = self.translator.get_dir_id_and_path(cleartext_dir)?;
+        record_ciphertext_path(&hashed_dir_path);
+        // Entries that are being created or removed may be incomplete
+        let _guard = self.dir_locks.lock(&[&dir_id]);
+        let mut read_dir = self.read_dir_locked(cleartext_dir, &dir_id, &hashed_dir_path)?;
+
+        let mut entries = BTreeMap::new();
+        let mut errors = Vec::new();
+        while let Some(result) = read_dir.next_listed() {
+            match result {
+                Ok((cleartext_path, entry)) => {
+                    entries.insert(cleartext_path, entry);
+                }
+                Err((err @ FsError::NameDecryption { .. }, _)) if strict => return Err(err),
+                Err((err, error)) if strict => {
+                    let report = color_eyre::Report::new(err).wrap_err(error);
+                    return Err(FsError::Other(report.into()));
+                }
+                Err((_, error)) => errors.push(error),
+            }
         }
 
-        Ok(cleartext_entries)
+        errors.sort_by(|a, b| a.ciphertext_name.cmp(&b.ciphertext_name));
+        Ok((entries, errors))
     }
 
-    fn link_target(&self, cleartext_path: impl AsRef<Path> + Debug) -> Result<PathBuf> {
-        let dir_id = self.translator.get_dir_id(&cleartext_path)?;
-        let ciphertext_path = self
-            .translator
-            .get_ciphertext_path(&cleartext_path, dir_id)?
-            .join("symlink.c9r");
+    /// Read the target of the cleartext symlink at `cleartext_path`. Fails with
+    /// [`io::ErrorKind::InvalidData`] if the entry there isn't a symlink.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %self.traced(cleartext_path.as_ref()), ciphertext_path = field::Empty)
+    )]
+    pub fn link_target(
+        &self,
+        cleartext_path: impl AsRef<Path> + Debug,
+    ) -> Result<PathBuf, FsError> {
+        let cleartext_path = cleartext_path.as_ref();
+        self.with_context("read the target of", cleartext_path, || {
+            check_path(cleartext_path)?;
+            let dir_id = self.translator.get_dir_id(cleartext_path)?;
+            let ciphertext_path = self
+                .translator
+                .get_ciphertext_path(cleartext_path, &dir_id)?
+                .join("symlink.c9r");
+            record_ciphertext_path(&ciphertext_path);
 
-        if ciphertext_path.is_file() {
-            let mut decrypted = String::new();
-            let mut options = OpenOptions::new();
-            options.read(true);
-            EncryptedFile::open(self.vault.cryptor(), ciphertext_path, options)?
-                .read_to_string(&mut decrypted)?;
+            if self.storage().is_file(&ciphertext_path) {
+                return Ok(self.read_symlink(&ciphertext_path)?);
+            }
+
+            // Fail the same way as other lookups if there's nothing here at all
+            self.dir_entry(cleartext_path)?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "not a link").into())
+        })
+    }
 
-            return Ok(decrypted.into());
+    // Read the target stored in a symlink.c9r. Targets are arbitrary bytes, not necessarily UTF-8.
+    fn read_symlink(&self, symlink_path: &Path) -> Result<PathBuf> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut target = Vec::new();
+        EncryptedFile::open_in(self.storage(), self.vault.cryptor(), symlink_path, options)?
+            .take(Self::MAX_SYMLINK_TARGET_LEN as u64 + 1)
+            .read_to_end(&mut target)?;
+        if target.len() > Self::MAX_SYMLINK_TARGET_LEN {
+            bail!("symlink target is too long: {}", symlink_path.display());
         }
 
-        Err(io::Error::new(io::ErrorKind::InvalidData, "not a link").into())
+        Ok(OsString::from_vec(target).into())
     }
 
-    fn open_file(
+    /// Open the cleartext file at `cleartext_path`, with options that mean the same as for
+    /// [`std::fs::OpenOptions`]. A file that's created gets a header right away and the
+    /// permissions in `options.mode`, without applying the umask. Truncating keeps the header.
+    /// The file is appended to if either `append` or `options.append` is set.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %self.traced(cleartext_path.as_ref()), ciphertext_path = field::Empty)
+    )]
+    pub fn open_file(
         &self,
         cleartext_path: impl AsRef<Path>,
         options: OpenOptions,
         append: bool,
     ) -> Result<EncryptedFile<'v>> {
-        let dir_id = self.translator.get_dir_id(&cleartext_path)?;
-        let mut ciphertext_path = self
-            .translator
-            .get_ciphertext_path(cleartext_path, dir_id)?;
+        let cleartext_path = cleartext_path.as_ref();
+        self.with_context("open", cleartext_path, || {
+            check_path(cleartext_path)?;
+            let append = append || options.append;
+            let _write = match append || writes(&options) {
+                true => Some(self.check_writable()?),
+                false => None,
+            };
+            check_open_options(&options, append)?;
+            let dir_id = self.translator.get_dir_id(cleartext_path)?;
+            let mut ciphertext_path = self
+                .translator
+                .get_ciphertext_path(cleartext_path, &dir_id)?;
 
-        if ciphertext_path.join("contents.c9r").is_file() {
-            ciphertext_path = ciphertext_path.join("contents.c9r");
-        }
+            // A new file gets a header right away, so it's never seen without one
+            let exists = self.storage().exists(&ciphertext_path);
+            if exists && options.create_new {
+                return Err(FsError::Io(io::ErrorKind::AlreadyExists.into()).into());
+            }
+            if !exists && (options.create || options.create_new) {
+                let (Some(parent), Some(name)) =
+                    (cleartext_path.parent(), cleartext_path.file_name())
+                else {
+                    return Err(FsError::InvalidPath.into());
+                };
+                match self.create_file(parent, name, Permissions::from_mode(options.mode)) {
+                    Ok(_) => {}
+                    // Someone else created it first, which is fine unless it had to be new
+                    Err(err) if is_already_exists(&err) && !options.create_new => {}
+                    Err(err) => return Err(err),
+                }
+            }
 
-        let mut file = EncryptedFile::open(self.vault.cryptor(), ciphertext_path, options)?;
-        file.set_append(append);
+            if self
+                .storage()
+                .is_file(&ciphertext_path.join("contents.c9r"))
+            {
+                ciphertext_path = ciphertext_path.join("contents.c9r");
+            }
+            record_ciphertext_path(&ciphertext_path);
+
+            let mut file = match self.header_cache.is_enabled() {
+                true => EncryptedFile::open_cached_in(
+                    self.storage(),
+                    self.vault.cryptor(),
+                    &ciphertext_path,
+                    ciphertext_options(&options, append),
+                    &self.header_cache,
+                    self.observer.as_deref(),
+                )?,
+                false => EncryptedFile::open_in(
+                    self.storage(),
+                    self.vault.cryptor(),
+                    ciphertext_path,
+                    ciphertext_options(&options, append),
+                )?,
+            };
+            // Only the contents go, so the file keeps its header
+            if options.truncate && exists {
+                file.truncate()?;
+            }
+            file.set_append(append);
+            file.set_observer(self.observer.clone());
+            file.set_freeze(self.freeze.clone(), self.while_frozen);
+            if let Some(throttle) = &self.throttle {
+                file.set_throttle(throttle.clone());
+            }
 
-        Ok(file)
+            Ok(file)
+        })
     }
 
-    fn rename_file(
+    /// Rename an entry of `kind`, given the directory IDs of its old and new parents, and drop
+    /// the cached IDs of a directory at either path.
+    fn rename_entry(
         &self,
-        old_parent: impl AsRef<Path>,
+        (old_parent, old_dir_id): (&Path, &DirId),
         old_name: &OsStr,
-        new_parent: impl AsRef<Path>,
+        (new_parent, new_dir_id): (&Path, &DirId),
         new_name: &OsStr,
+        kind: FileKind,
     ) -> Result<()> {
-        let old_dir_id = self.translator.get_dir_id(&old_parent)?;
-        let old_ciphertext_path = self
-            .translator
-            .get_ciphertext_path(old_parent.as_ref().join(old_name), old_dir_id)?;
-        let new_dir_id = self.translator.get_dir_id(&new_parent)?;
-        let new_ciphertext_path = self
+        let old_path = old_parent.join(old_name);
+        let old_ciphertext_path = self.translator.get_ciphertext_path(&old_path, old_dir_id)?;
+        let new_path = new_parent.join(new_name);
+        let new_ciphertext_path = self.translator.get_ciphertext_path(&new_path, new_dir_id)?;
+        let full_name = self
             .translator
-            .get_ciphertext_path(new_parent.as_ref().join(new_name), &new_dir_id)?;
-
-        // These are probably fine to unwrap since get_ciphertext_path always gives a c9r/c9s
-        // extension
-        match (
-            old_ciphertext_path.extension().unwrap().to_str().unwrap(),
-            new_ciphertext_path.extension().unwrap().to_str().unwrap(),
-        ) {
-            ("c9r", "c9r") => {
-                fs::rename(&old_ciphertext_path, new_ciphertext_path)?;
-            }
-            ("c9r", "c9s") => {
-                let new_ciphertext_name = self
-                    .translator
-                    .get_full_ciphertext_name(new_parent.as_ref().join(new_name), new_dir_id)?;
-                fs::create_dir_all(&new_ciphertext_path)?;
-                fs::write(new_ciphertext_path.join("name.c9s"), new_ciphertext_name)?;
-                fs::rename(
-                    &old_ciphertext_path,
-                    new_ciphertext_path.join("contents.c9r"),
-                )?;
-            }
-            ("c9s", "c9r") => {
-                fs::rename(
-                    old_ciphertext_path.join("contents.c9r"),
-                    new_ciphertext_path,
-                )?;
-                fs::remove_dir_all(old_ciphertext_path)?;
-            }
-            ("c9s", "c9s") => {
-                let new_ciphertext_name = self
-                    .translator
-                    .get_full_ciphertext_name(new_parent.as_ref().join(new_name), new_dir_id)?;
-                fs::create_dir_all(&new_ciphertext_path)?;
-                fs::write(new_ciphertext_path.join("name.c9s"), new_ciphertext_name)?;
-                fs::rename(
-                    old_ciphertext_path.join("contents.c9r"),
-                    new_ciphertext_path.join("contents.c9r"),
-                )?;
-                fs::remove_dir_all(old_ciphertext_path)?;
-            }
-            _ => unreachable!(),
+            .get_full_ciphertext_name(new_name, new_dir_id)?;
+
+        self.move_entry(&old_ciphertext_path, &new_ciphertext_path, &full_name, kind)?;
+        if kind == FileKind::Directory {
+            self.translator.invalidate(old_path);
+            self.translator.invalidate(new_path);
         }
 
         Ok(())
     }
 
-    fn rename_dir(
+    /// Move a ciphertext entry to a new full-length or shortened ciphertext path, where
+    /// `full_name` is the full ciphertext name for the new path.
+    fn move_entry(
         &self,
-        old_parent: impl AsRef<Path>,
-        old_name: &OsStr,
-        new_parent: impl AsRef<Path>,
-        new_name: &OsStr,
+        old_ciphertext_path: &Path,
+        new_ciphertext_path: &Path,
+        full_name: &str,
+        kind: FileKind,
     ) -> Result<()> {
-        let old_dir_id = self.translator.get_dir_id(&old_parent)?;
-        let old_ciphertext_path = self
-            .translator
-            .get_ciphertext_path(old_parent.as_ref().join(old_name), old_dir_id)?;
-        let new_dir_id = self.translator.get_dir_id(&new_parent)?;
-        let new_ciphertext_path = self
-            .translator
-            .get_ciphertext_path(new_parent.as_ref().join(new_name), &new_dir_id)?;
-
-        // These are probably fine to unwrap since get_ciphertext_path always gives a c9r/c9s
-        // extension
-        match (
-            old_ciphertext_path.extension().unwrap().to_str().unwrap(),
-            new_ciphertext_path.extension().unwrap().to_str().unwrap(),
-        ) {
-            ("c9r", "c9r") => {
-                fs::rename(&old_ciphertext_path, new_ciphertext_path)?;
-            }
-            ("c9s", "c9r") => {
-                fs::create_dir_all(&new_ciphertext_path)?;
-                fs::rename(
-                    old_ciphertext_path.join("dir.c9r"),
-                    new_ciphertext_path.join("dir.c9r"),
-                )?;
-                fs::remove_dir_all(old_ciphertext_path)?;
-            }
-            (_, "c9s") => {
-                let new_ciphertext_name = self
-                    .translator
-                    .get_full_ciphertext_name(new_parent.as_ref().join(new_name), new_dir_id)?;
-                fs::create_dir_all(&new_ciphertext_path)?;
-                fs::write(new_ciphertext_path.join("name.c9s"), new_ciphertext_name)?;
-                fs::rename(
-                    old_ciphertext_path.join("dir.c9r"),
-                    new_ciphertext_path.join("dir.c9r"),
-                )?;
-                let _ = fs::remove_dir_all(old_ciphertext_path);
-            }
-            _ => unreachable!(),
+        // Ok to unwrap, ciphertext paths are always inside a hashed directory
+        let old_hashed_dir_path = old_ciphertext_path.parent().unwrap();
+        let new_hashed_dir_path = new_ciphertext_path.parent().unwrap();
+
+        // Without shortened names on either side, the entry can be moved as a whole
+        let (old_shortened, new_shortened) = (
+            is_shortened(old_ciphertext_path),
+            is_shortened(new_ciphertext_path),
+        );
+        let storage = self.storage();
+        if !old_shortened && !new_shortened {
+            storage.rename(old_ciphertext_path, new_ciphertext_path)?;
+            self.sync_dir(new_hashed_dir_path)?;
+            return self.sync_dir(old_hashed_dir_path);
         }
 
-        Ok(())
+        let old_payload = payload_path(old_ciphertext_path, kind);
+        if storage.exists(new_ciphertext_path) {
+            // The existing entry already has the right name.c9s, so only the payload is replaced
+            storage.rename(&old_payload, &payload_path(new_ciphertext_path, kind))?;
+            self.sync_dir(new_ciphertext_path)?;
+        } else {
+            // Link the payload into the new entry, so it's never missing from both places
+            self.create_entry(new_ciphertext_path, full_name, kind, |path| {
+                if storage.hard_link(&old_payload, path).is_err() {
+                    storage.copy(&old_payload, path)?;
+                }
+                Ok(())
+            })?;
+        }
+
+        if storage.is_dir(old_ciphertext_path) {
+            storage.remove_dir_all(old_ciphertext_path)?;
+        } else {
+            storage.remove_file(old_ciphertext_path)?;
+        }
+        self.sync_dir(old_hashed_dir_path)
     }
 
-    fn rename_link(
+    /// Move the entry `old_name` in the cleartext directory `old_parent` to `new_name` in
+    /// `new_parent`, replacing any entry already there.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            old_parent = %self.traced(old_parent.as_ref()),
+            old_name = %self.traced(old_name),
+            new_parent = %self.traced(new_parent.as_ref()),
+            new_name = %self.traced(new_name),
+        )
+    )]
+    pub fn rename(
         &self,
         old_parent: impl AsRef<Path>,
         old_name: &OsStr,
         new_parent: impl AsRef<Path>,
         new_name: &OsStr,
     ) -> Result<()> {
-        let old_dir_id = self.translator.get_dir_id(&old_parent)?;
-        let old_ciphertext_path = self
-            .translator
-            .get_ciphertext_path(old_parent.as_ref().join(old_name), old_dir_id)?;
-        let new_dir_id = self.translator.get_dir_id(&new_parent)?;
-        let new_ciphertext_path = self
-            .translator
-            .get_ciphertext_path(new_parent.as_ref().join(new_name), &new_dir_id)?;
-
-        // These are probably fine to unwrap since get_ciphertext_path always gives a c9r/c9s
-        // extension
-        match (
-            old_ciphertext_path.extension().unwrap().to_str().unwrap(),
-            new_ciphertext_path.extension().unwrap().to_str().unwrap(),
-        ) {
-            ("c9r", "c9r") => {
-                fs::rename(&old_ciphertext_path, new_ciphertext_path)?;
-            }
-            ("c9s", "c9r") => {
-                fs::create_dir_all(&new_ciphertext_path)?;
-                fs::rename(
-                    old_ciphertext_path.join("symlink.c9r"),
-                    new_ciphertext_path.join("symlink.c9r"),
-                )?;
-                fs::remove_dir_all(old_ciphertext_path)?;
-            }
-            (_, "c9s") => {
-                let new_ciphertext_name = self
-                    .translator
-                    .get_full_ciphertext_name(new_parent.as_ref().join(new_name), new_dir_id)?;
-                fs::create_dir_all(&new_ciphertext_path)?;
-                fs::write(new_ciphertext_path.join("name.c9s"), new_ciphertext_name)?;
-                fs::rename(
-                    old_ciphertext_path.join("symlink.c9r"),
-                    new_ciphertext_path.join("symlink.c9r"),
-                )?;
-                let _ = fs::remove_dir_all(old_ciphertext_path);
+        self.with_context("rename", &old_parent.as_ref().join(old_name), || {
+            check_path(old_parent.as_ref())?;
+            check_name(old_name)?;
+            check_path(new_parent.as_ref())?;
+            check_name(new_name)?;
+            let _write = self.check_writable()?;
+            // Shortened entries are moved by removing the old entry, which would delete the new one
+            if old_parent.as_ref().join(old_name) == new_parent.as_ref().join(new_name) {
+                return Ok(());
+            }
+
+            let (old_parent, new_parent) = (old_parent.as_ref(), new_parent.as_ref());
+            let old_parent_dir_id = self.translator.get_dir_id(old_parent)?;
+            let new_parent_dir_id = self.translator.get_dir_id(new_parent)?;
+            let _guard = self
+                .dir_locks
+                .lock(&[&old_parent_dir_id, &new_parent_dir_id]);
+
+            let kind = self.dir_entry(old_parent.join(old_name))?.kind;
+            self.rename_entry(
+                (old_parent, &old_parent_dir_id),
+                old_name,
+                (new_parent, &new_parent_dir_id),
+                new_name,
+                kind,
+            )?;
+            self.touch_dir(&old_parent_dir_id);
+            if new_parent_dir_id != old_parent_dir_id {
+                self.touch_dir(&new_parent_dir_id);
             }
-            _ => unreachable!(),
+
+            Ok(())
+        })
+    }
+
+    /// Rename entries within the cleartext directory `parent`, where each item of `renames` is an
+    /// old and a new name. This is quicker than renaming them one at a time, as the directory is
+    /// only looked up and locked once.
+    ///
+    /// Renames are carried out in whatever order lets an entry take the place of another one
+    /// that's being renamed too, so `a` → `b` and `b` → `c` keeps both entries. As with
+    /// [`rename`](Self::rename), any other entry in the way is replaced. Returns the result of
+    /// each item of `renames`. An item fails without changing anything if its old or new name
+    /// already appears in an earlier item, if it's part of a cycle like `a` → `b` and `b` → `a`,
+    /// or if the entry in its way failed to be renamed.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(parent = %self.traced(parent.as_ref()), count = renames.len())
+    )]
+    pub fn rename_batch(
+        &self,
+        parent: impl AsRef<Path>,
+        renames: &[(OsString, OsString)],
+    ) -> Vec<Result<()>> {
+        let parent = parent.as_ref();
+        let (_write, parent_dir_id) = match check_path(parent)
+            .and_then(|()| self.check_writable())
+            .map_err(Into::into)
+            .and_then(|write| -> Result<_> {
+                Ok((write, self.translator.get_dir_id_and_path(parent)?.0))
+            }) {
+            Ok(started) => started,
+            Err(err) => {
+                let err = FsError::from(err);
+                return renames.iter().map(|_| Err(batch_error(&err))).collect();
+            }
+        };
+
+        // Check the whole batch up front, and find which item renames each old name
+        let mut results: Vec<Option<Result<()>>> = renames.iter().map(|_| None).collect();
+        let mut sources = HashMap::new();
+        let mut targets = HashSet::new();
+        for (i, (old_name, new_name)) in renames.iter().enumerate() {
+            let invalid =
+                |message| Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+            results[i] = if let Err(err) = check_name(old_name).and_then(|()| check_name(new_name))
+            {
+                Some(Err(err.into()))
+            } else if sources.contains_key(old_name) {
+                Some(invalid("the entry is already renamed earlier in the batch"))
+            } else if targets.contains(new_name) {
+                Some(invalid("another entry is already renamed to this name"))
+            } else {
+                sources.insert(old_name, i);
+                targets.insert(new_name);
+                None
+            };
         }
 
-        Ok(())
+        let _guard = self.dir_locks.lock(&[&parent_dir_id]);
+        let rename = |old_name: &OsStr, new_name: &OsStr| -> Result<()> {
+            let kind = self.dir_entry(parent.join(old_name))?.kind;
+            let parent = (parent, &parent_dir_id);
+            self.rename_entry(parent, old_name, parent, new_name, kind)
+        };
+        let mut pending: Vec<_> = (0..renames.len())
+            .filter(|&i| results[i].is_none())
+            .collect();
+        let mut renamed = false;
+        loop {
+            let mut progress = false;
+            for &i in &pending {
+                let (old_name, new_name) = &renames[i];
+                // An entry being renamed itself has to get out of the way first
+                results[i] = match sources.get(new_name) {
+                    Some(&j) if j != i && results[j].is_none() => continue,
+                    Some(&j) if j != i && results[j].as_ref().is_some_and(Result::is_err) => {
+                        Some(Err(eyre!(
+                            "the entry in the way of the new name failed to be renamed"
+                        )))
+                    }
+                    _ if old_name == new_name => Some(Ok(())),
+                    _ => {
+                        renamed = true;
+                        Some(rename(old_name, new_name))
+                    }
+                };
+                progress = true;
+            }
+
+            pending.retain(|&i| results[i].is_none());
+            if !progress {
+                break;
+            }
+        }
+        if renamed {
+            self.touch_dir(&parent_dir_id);
+        }
+
+        // Whatever is left waits on itself
+        let message = "the entry is part of a cycle of renames";
+        results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    Err(io::Error::new(io::ErrorKind::InvalidInput, message).into())
+                })
+            })
+            .collect()
     }
 
-    fn rename(
+    /// Create a new entry at `ciphertext_path` in a way that it's never seen half-written. The
+    /// entry is put together under a temporary name and then renamed into place. `write` is called
+    /// with the temporary location of the file that holds the entry's data, which is the entry
+    /// itself for files with full-length names, and otherwise a file in the entry's directory.
+    fn create_entry(
         &self,
-        old_parent: impl AsRef<Path>,
-        old_name: &OsStr,
-        new_parent: impl AsRef<Path>,
-        new_name: &OsStr,
+        ciphertext_path: &Path,
+        full_name: &str,
+        kind: FileKind,
+        write: impl FnOnce(&Path) -> Result<()>,
     ) -> Result<()> {
-        let old_entry = self.dir_entry(old_parent.as_ref().join(old_name))?;
-        match old_entry.kind {
-            FileKind::File => self.rename_file(old_parent, old_name, new_parent, new_name),
-            FileKind::Directory => self.rename_dir(old_parent, old_name, new_parent, new_name),
-            FileKind::Symlink => self.rename_link(old_parent, old_name, new_parent, new_name),
+        let storage = self.storage();
+        if storage.exists(ciphertext_path) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+
+        // Ok to unwrap, ciphertext paths are always inside a hashed directory
+        let hashed_dir_path = ciphertext_path.parent().unwrap();
+        let temp_path = hashed_dir_path.join(format!(".{}.tmp", Uuid::new_v4().simple()));
+        // Ok to unwrap, the payload is either the entry itself or a file inside it
+        let payload = payload_path(ciphertext_path, kind);
+        let in_dir = payload != ciphertext_path;
+        let temp_payload = if in_dir {
+            temp_path.join(payload.file_name().unwrap())
+        } else {
+            temp_path.clone()
+        };
+
+        let result = (|| {
+            if in_dir {
+                storage.create_dir(&temp_path)?;
+                if is_shortened(ciphertext_path) {
+                    storage.write(&temp_path.join("name.c9s"), full_name.as_bytes())?;
+                }
+            }
+            write(&temp_payload)?;
+            if in_dir {
+                self.sync_dir(&temp_path)?;
+            }
+
+            fault_point("create_entry")?;
+            storage.rename(&temp_path, ciphertext_path)?;
+            self.sync_dir(hashed_dir_path)
+        })();
+
+        if result.is_err() {
+            let _ = storage
+                .remove_dir_all(&temp_path)
+                .or_else(|_| storage.remove_file(&temp_path));
+        }
+        result
+    }
+
+    // Start a change, failing if the file system is read-only, before anything is changed. The
+    // change keeps the file system from being frozen until the guard is dropped.
+    fn check_writable(&self) -> Result<WriteGuard, FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+
+        self.begin_write()
+    }
+
+    // Sync a directory after changing its entries, if the file system is set up to do so.
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        if self.durability == Durability::Safe {
+            self.storage().sync_dir(path)?;
+        }
+
+        Ok(())
+    }
+
+    // Bump the modification time of the hashed directory for `dir_id`, which is what directory
+    // times are read from, after changing its entries. Storages usually do this by themselves, but
+    // not when only the payload inside a shortened entry is replaced, so it's done explicitly.
+    fn touch_dir(&self, dir_id: &DirId) {
+        let result = self.hashed_dir_path(dir_id).and_then(|path| {
+            let times = FileTimes::new().set_modified(SystemTime::now());
+            Ok(self.storage().set_times(&path, times)?)
+        });
+        if let Err(err) = result {
+            tracing::warn!("failed to update directory modification time: {err}");
         }
     }
 
-    fn mknod(
+    /// Create an empty file named `name` in the cleartext directory `parent`, with a header and
+    /// `permissions`. Fails with [`io::ErrorKind::AlreadyExists`] if the name is taken.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            parent = %self.traced(parent.as_ref()),
+            name = %self.traced(name),
+            ciphertext_path = field::Empty,
+        )
+    )]
+    pub fn mknod(
         &self,
         parent: impl AsRef<Path> + Debug,
         name: &OsStr,
         permissions: Permissions,
     ) -> Result<DirEntry> {
-        let parent_dir_id = self.translator.get_dir_id(&parent)?;
-        let mut ciphertext_path = self
-            .translator
-            .get_ciphertext_path(parent.as_ref().join(name), &parent_dir_id)?;
+        self.with_context("create", &parent.as_ref().join(name), || {
+            check_path(parent.as_ref())?;
+            check_name(name)?;
+            let _write = self.check_writable()?;
+            self.create_file(parent.as_ref(), name, permissions)
+        })
+    }
 
-        if ciphertext_path.extension().unwrap().to_str().unwrap() == "c9s" {
-            fs::create_dir_all(&ciphertext_path)?;
-            let full_name = self
-                .translator
-                .get_full_ciphertext_name(parent.as_ref().join(name), parent_dir_id)?;
-            fs::write(ciphertext_path.join("name.c9s"), full_name)?;
-            ciphertext_path = ciphertext_path.join("contents.c9r");
-        }
+    // Create an empty file named `name` in `parent`, once the change has been started.
+    fn create_file(
+        &self,
+        parent: &Path,
+        name: &OsStr,
+        permissions: Permissions,
+    ) -> Result<DirEntry> {
+        let parent_dir_id = self.translator.get_dir_id(parent)?;
+        let _guard = self.dir_locks.lock(&[&parent_dir_id]);
+        let ciphertext_path = self
+            .translator
+            .get_ciphertext_path(parent.join(name), &parent_dir_id)?;
+        record_ciphertext_path(&ciphertext_path);
+        let full_name = self
+            .translator
+            .get_full_ciphertext_name(name, &parent_dir_id)?;
 
-        let file = EncryptedFile::create_new(self.vault.cryptor(), &ciphertext_path)?;
-        fs::set_permissions(ciphertext_path, permissions)?;
+        // New files are synced with their header, so they never end up empty after a crash
+        self.create_entry(&ciphertext_path, &full_name, FileKind::File, |path| {
+            EncryptedFile::create_new_in(self.storage(), self.vault.cryptor(), path)?;
+            Ok(self.storage().set_permissions(path, permissions)?)
+        })?;
+        self.touch_dir(&parent_dir_id);
 
-        Ok(DirEntry {
-            kind: FileKind::File,
-            size: 0,
-            metadata: file.metadata()?,
-        })
+        Ok(self.ciphertext_dir_entry(ciphertext_path)?)
     }
 
-    fn mkdir(
+    /// Create the entry for a directory named `name` in `parent`, which points to the hashed
+    /// directory for `dir_id`.
+    fn create_dir_entry(
         &self,
         parent: impl AsRef<Path>,
         name: &OsStr,
-        permissions: Permissions,
-    ) -> Result<DirEntry> {
+        dir_id: &DirId,
+    ) -> Result<PathBuf> {
         let parent_dir_id = self.translator.get_dir_id(&parent)?;
         let ciphertext_path = self
             .translator
             .get_ciphertext_path(parent.as_ref().join(name), &parent_dir_id)?;
+        let full_name = self
+            .translator
+            .get_full_ciphertext_name(name, &parent_dir_id)?;
 
-        fs::create_dir_all(&ciphertext_path)?;
-        let dir_id = Uuid::new_v4().to_string();
-        fs::write(ciphertext_path.join("dir.c9r"), &dir_id)?;
+        self.create_entry(&ciphertext_path, &full_name, FileKind::Directory, |path| {
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            let file = self.storage().open(path, &options)?;
+            file.write_at(dir_id.as_str().as_bytes(), 0)?;
+            if self.durability == Durability::Safe {
+                file.sync_all()?;
+            }
+            Ok(())
+        })?;
 
-        if ciphertext_path.extension().unwrap().to_str().unwrap() == "c9s" {
-            let full_name = self
-                .translator
-                .get_full_ciphertext_name(parent.as_ref().join(name), parent_dir_id)?;
-            fs::write(ciphertext_path.join("name.c9s"), full_name)?;
-        }
+        Ok(ciphertext_path)
+    }
 
-        let hashed_dir_id = self.vault.cryptor().hash_dir_id(dir_id).unwrap();
-        let hashed_dir_path = self.vault.path().join("d").join(hashed_dir_id);
-        fs::create_dir_all(&hashed_dir_path)?;
-        fs::set_permissions(&hashed_dir_path, permissions)?;
-        // TODO: Write encrypted dirid.c9r under hashed dir path
+    /// Create a directory named `name` in the cleartext directory `parent`, with `permissions`.
+    /// Fails with [`io::ErrorKind::AlreadyExists`] if the name is taken.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            parent = %self.traced(parent.as_ref()),
+            name = %self.traced(name),
+            ciphertext_path = field::Empty,
+        )
+    )]
+    pub fn mkdir(
+        &self,
+        parent: impl AsRef<Path>,
+        name: &OsStr,
+        permissions: Permissions,
+    ) -> Result<DirEntry> {
+        self.with_context("create directory", &parent.as_ref().join(name), || {
+            check_path(parent.as_ref())?;
+            check_name(name)?;
+            let _write = self.check_writable()?;
+            let parent_dir_id = self.translator.get_dir_id(&parent)?;
+            let _guard = self.dir_locks.lock(&[&parent_dir_id]);
+
+            // The hashed directory is created first, so the new entry is never seen without it
+            let dir_id = DirId::new();
+            let hashed_dir_id = self.vault.cryptor().hash_dir_id(&dir_id)?;
+            let hashed_dir_path = self.vault.path().join("d").join(hashed_dir_id);
+            record_ciphertext_path(&hashed_dir_path);
+            let storage = self.storage();
+            storage.create_dir_all(&hashed_dir_path)?;
+
+            // Keep an encrypted backup of the directory ID, so the directory can be recovered if its
+            // dir.c9r is lost. This has to happen before the permissions are set, which may not allow
+            // writing.
+            let mut backup = EncryptedFile::create_new_in(
+                storage,
+                self.vault.cryptor(),
+                hashed_dir_path.join("dirid.c9r"),
+            )?;
+            backup.write_all(dir_id.as_str().as_bytes())?;
+            if self.durability == Durability::Safe {
+                backup.sync_all()?;
+            }
+            drop(backup);
+
+            // If the process dies before the entry is created, the hashed directory is left as an
+            // empty orphan, but otherwise it's cleaned up
+            if let Err(err) = self.create_dir_entry(parent, name, &dir_id) {
+                let _ = storage.remove_dir_all(&hashed_dir_path);
+                // Ok to unwrap, hashed directories are always inside a prefix directory
+                let _ = storage.remove_dir(hashed_dir_path.parent().unwrap());
+                return Err(err);
+            }
+            self.touch_dir(&parent_dir_id);
+            storage.set_permissions(&hashed_dir_path, permissions)?;
 
-        let meta = hashed_dir_path.metadata()?;
-        Ok(DirEntry {
-            kind: FileKind::Directory,
-            size: meta.len(),
-            metadata: meta,
+            let meta = storage.metadata(&hashed_dir_path)?;
+            Ok(DirEntry::new(FileKind::Directory, meta.len(), meta))
         })
     }
 
-    fn symlink(
+    /// Create a symlink named `link_name` in the cleartext directory `parent`, pointing to
+    /// `target`. The target is stored as is, without checking what it points to.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            parent = %self.traced(parent.as_ref()),
+            name = %self.traced(link_name),
+            ciphertext_path = field::Empty,
+        )
+    )]
+    pub fn symlink(
         &self,
         parent: impl AsRef<Path>,
         link_name: &OsStr,
         target: impl AsRef<Path>,
     ) -> Result<DirEntry> {
-        let parent_dir_id = self.translator.get_dir_id(&parent)?;
-        let ciphertext_path = self
-            .translator
-            .get_ciphertext_path(parent.as_ref().join(link_name), &parent_dir_id)?;
-
-        fs::create_dir_all(&ciphertext_path)?;
+        self.with_context("create symlink", &parent.as_ref().join(link_name), || {
+            check_path(parent.as_ref())?;
+            check_name(link_name)?;
+            let _write = self.check_writable()?;
+            let target = target.as_ref().as_os_str().as_bytes();
+            if target.is_empty() {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidInput, "empty symlink target").into(),
+                );
+            }
+            if target.len() > Self::MAX_SYMLINK_TARGET_LEN {
+                let message = "symlink target is too long";
+                return Err(io::Error::new(io::ErrorKind::InvalidFilename, message).into());
+            }
 
-        if ciphertext_path.extension().unwrap().to_str().unwrap() == "c9s" {
+            let parent_dir_id = self.translator.get_dir_id(&parent)?;
+            let _guard = self.dir_locks.lock(&[&parent_dir_id]);
+            let ciphertext_path = self
+                .translator
+                .get_ciphertext_path(parent.as_ref().join(link_name), &parent_dir_id)?;
+            record_ciphertext_path(&ciphertext_path);
             let full_name = self
                 .translator
-                .get_full_ciphertext_name(parent.as_ref().join(link_name), parent_dir_id)?;
-            fs::write(ciphertext_path.join("name.c9s"), full_name)?;
-        }
-
-        let mut symlink =
-            EncryptedFile::create_new(self.vault.cryptor(), ciphertext_path.join("symlink.c9r"))?;
-        symlink.write_all(target.as_ref().as_os_str().as_encoded_bytes())?;
-        symlink.flush()?;
+                .get_full_ciphertext_name(link_name, &parent_dir_id)?;
+
+            self.create_entry(&ciphertext_path, &full_name, FileKind::Symlink, |path| {
+                let mut symlink =
+                    EncryptedFile::create_new_in(self.storage(), self.vault.cryptor(), path)?;
+                symlink.write_all(target)?;
+                symlink.flush()?;
+                if self.durability == Durability::Safe {
+                    symlink.sync_all()?;
+                }
+                Ok(())
+            })?;
+            self.touch_dir(&parent_dir_id);
 
-        Ok(DirEntry {
-            kind: FileKind::Symlink,
-            size: symlink.len()?,
-            metadata: symlink.metadata()?,
+            Ok(self.ciphertext_dir_entry(ciphertext_path)?)
         })
     }
 
-    fn unlink(&self, parent: impl AsRef<Path>, name: &OsStr) -> Result<()> {
-        let parent_dir_id = self.translator.get_dir_id(&parent)?;
-        let ciphertext_path = self
-            .translator
-            .get_ciphertext_path(parent.as_ref().join(name), parent_dir_id)?;
+    /// Remove the file or symlink `name` from the cleartext directory `parent`.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            parent = %self.traced(parent.as_ref()),
+            name = %self.traced(name),
+            ciphertext_path = field::Empty,
+        )
+    )]
+    pub fn unlink(&self, parent: impl AsRef<Path>, name: &OsStr) -> Result<()> {
+        self.with_context("remove", &parent.as_ref().join(name), || {
+            check_path(parent.as_ref())?;
+            check_name(name)?;
+            let _write = self.check_writable()?;
+            let parent_dir_id = self.translator.get_dir_id(&parent)?;
+            let _guard = self.dir_locks.lock(&[&parent_dir_id]);
+            let ciphertext_path = self
+                .translator
+                .get_ciphertext_path(parent.as_ref().join(name), &parent_dir_id)?;
+            record_ciphertext_path(&ciphertext_path);
 
-        if ciphertext_path.is_file() {
-            Ok(fs::remove_file(ciphertext_path)?)
-        } else {
-            Ok(fs::remove_dir_all(&ciphertext_path)?)
-        }
+            if self.storage().is_file(&ciphertext_path) {
+                self.storage().remove_file(&ciphertext_path)?;
+            } else {
+                self.storage().remove_dir_all(&ciphertext_path)?;
+            }
+            self.touch_dir(&parent_dir_id);
+
+            Ok(())
+        })
     }
 
-    fn rmdir(&self, parent: impl AsRef<Path>, name: &OsStr) -> Result<()> {
-        let dir_id = self.translator.get_dir_id(parent.as_ref().join(name))?;
-        let hashed_dir_id = self.vault.cryptor().hash_dir_id(dir_id).unwrap();
-        fs::remove_dir_all(self.vault.path().join("d").join(hashed_dir_id))?;
+    /// Remove the empty directory `name` from the cleartext directory `parent`. Fails with
+    /// [`io::ErrorKind::DirectoryNotEmpty`] if it still has entries.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            parent = %self.traced(parent.as_ref()),
+            name = %self.traced(name),
+            ciphertext_path = field::Empty,
+        )
+    )]
+    pub fn rmdir(&self, parent: impl AsRef<Path>, name: &OsStr) -> Result<()> {
+        self.with_context("remove directory", &parent.as_ref().join(name), || {
+            check_path(parent.as_ref())?;
+            check_name(name)?;
+            let _write = self.check_writable()?;
+            let parent_dir_id = self.translator.get_dir_id(&parent)?;
+            let ciphertext_path = self
+                .translator
+                .get_ciphertext_path(parent.as_ref().join(name), &parent_dir_id)?;
+            record_ciphertext_path(&ciphertext_path);
+            let storage = self.storage();
+            let dir_id = read_dir_id(storage, &ciphertext_path)?;
+            let _guard = self.dir_locks.lock(&[&parent_dir_id, &dir_id]);
+            let hashed_dir_path = self.hashed_dir_path(&dir_id)?;
+
+            // Like the desktop app, remove the hashed directory before the dir.c9r pointing to it, so
+            // an interrupted rmdir leaves an empty directory behind rather than an orphan
+            match storage.list(&hashed_dir_path) {
+                Ok(entries) => {
+                    for entry in entries {
+                        if entry != "dirid.c9r" {
+                            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty).into());
+                        }
+                    }
+
+                    match storage.remove_file(&hashed_dir_path.join("dirid.c9r")) {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                        _ => {}
+                    }
+                    storage.remove_dir(&hashed_dir_path)?;
+
+                    // The two-level parent is shared with other directories, so it's only removed
+                    // once nothing else uses it
+                    if let Some(prefix_dir) = hashed_dir_path.parent() {
+                        let _ = storage.remove_dir(prefix_dir);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
 
-        let parent_dir_id = self.translator.get_dir_id(&parent)?;
-        let ciphertext_path = self
-            .translator
-            .get_ciphertext_path(parent.as_ref().join(name), parent_dir_id)?;
-        Ok(fs::remove_dir_all(ciphertext_path)?)
+            self.translator.invalidate(parent.as_ref().join(name));
+            storage.remove_dir_all(&ciphertext_path)?;
+            self.touch_dir(&parent_dir_id);
+
+            Ok(())
+        })
     }
 
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %self.traced(cleartext_path.as_ref()), ciphertext_path = field::Empty)
+    )]
     fn set_permissions(
         &self,
         cleartext_path: impl AsRef<Path>,
         permissions: Permissions,
     ) -> Result<()> {
+        let cleartext_path = cleartext_path.as_ref();
+        self.with_context("set the permissions of", cleartext_path, || {
+            let _write = self.check_writable()?;
+            let path = self.metadata_path(cleartext_path)?;
+            record_ciphertext_path(&path);
+            Ok(self.storage().set_permissions(&path, permissions)?)
+        })
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %self.traced(cleartext_path.as_ref()), ciphertext_path = field::Empty)
+    )]
+    fn set_times(&self, cleartext_path: impl AsRef<Path>, times: FileTimes) -> Result<()> {
+        let cleartext_path = cleartext_path.as_ref();
+        self.with_context("set the times of", cleartext_path, || {
+            let _write = self.check_writable()?;
+            let path = self.metadata_path(cleartext_path)?;
+            record_ciphertext_path(&path);
+            Ok(self.storage().set_times(&path, times)?)
+        })
+    }
+
+    // The ciphertext file or directory that holds the permissions and times of an entry.
+    fn metadata_path(&self, cleartext_path: impl AsRef<Path>) -> Result<PathBuf> {
         let entry = self.dir_entry(&cleartext_path)?;
 
         match entry.kind {
@@ -489,18 +1505,21 @@ impl<'v> EncryptedFileSystem<'v> {
                     .get_dir_id(cleartext_path.as_ref().parent().unwrap())?;
                 let mut ciphertext_path = self
                     .translator
-                    .get_ciphertext_path(&cleartext_path, parent_dir_id)?;
+                    .get_ciphertext_path(&cleartext_path, &parent_dir_id)?;
 
-                if ciphertext_path.is_dir() && ciphertext_path.join("contents.c9r").is_file() {
+                if self
+                    .storage()
+                    .is_file(&ciphertext_path.join("contents.c9r"))
+                {
                     ciphertext_path = ciphertext_path.join("contents.c9r");
                 }
 
-                fs::set_permissions(ciphertext_path, permissions)?;
+                Ok(ciphertext_path)
             }
             FileKind::Directory => {
                 let dir_id = self.translator.get_dir_id(&cleartext_path)?;
-                let hashed_dir_id = self.vault.cryptor().hash_dir_id(dir_id).unwrap();
-                fs::set_permissions(self.vault.path().join("d").join(hashed_dir_id), permissions)?;
+                let hashed_dir_id = self.vault.cryptor().hash_dir_id(&dir_id)?;
+                Ok(self.vault.path().join("d").join(hashed_dir_id))
             }
             FileKind::Symlink => {
                 let parent_dir_id = self
@@ -508,51 +1527,1616 @@ impl<'v> EncryptedFileSystem<'v> {
                     .get_dir_id(cleartext_path.as_ref().parent().unwrap())?;
                 let ciphertext_path = self
                     .translator
-                    .get_ciphertext_path(&cleartext_path, parent_dir_id)?;
-                fs::set_permissions(ciphertext_path.join("symlink.c9r"), permissions)?;
+                    .get_ciphertext_path(&cleartext_path, &parent_dir_id)?;
+                Ok(ciphertext_path.join("symlink.c9r"))
             }
         }
+    }
+
+    /// Copy the permissions and access and modification times in `metadata` to an entry.
+    fn copy_metadata(&self, cleartext_path: impl AsRef<Path>, metadata: &Metadata) -> Result<()> {
+        self.set_permissions(&cleartext_path, metadata.permissions())?;
+
+        let times = FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?);
+        self.set_times(cleartext_path, times)
+    }
+}
+
+// Make sure a cleartext path only names entries inside the vault. It may be absolute or
+// relative, but both are taken to start at the root of the vault.
+fn check_path(cleartext_path: &Path) -> Result<(), FsError> {
+    let bytes = cleartext_path.as_os_str().as_bytes();
+    let relative = bytes.strip_prefix(b"/").unwrap_or(bytes);
+    if relative.is_empty() {
+        return Ok(());
+    }
+
+    relative
+        .split(|&b| b == b'/')
+        .try_for_each(|name| check_name(OsStr::from_bytes(name)))
+}
+
+// Make sure `name` is a single plain path component.
+fn check_name(name: &OsStr) -> Result<(), FsError> {
+    match name.as_bytes() {
+        b"" | b"." | b".." => Err(FsError::InvalidPath),
+        bytes if bytes.contains(&b'/') || bytes.contains(&0) => Err(FsError::InvalidPath),
+        _ => Ok(()),
+    }
+}
+
+// A copy of an error that applies to every item of a batch, which keeps what it means to callers
+// that turn it back into an FsError.
+fn batch_error(err: &FsError) -> color_eyre::Report {
+    match err {
+        FsError::NotFound => FsError::NotFound.into(),
+        FsError::NotADirectory => FsError::NotADirectory.into(),
+        FsError::InvalidPath => FsError::InvalidPath.into(),
+        FsError::Locked { path } => FsError::Locked { path: path.clone() }.into(),
+        FsError::ReadOnly => FsError::ReadOnly.into(),
+        FsError::Io(err) => io::Error::new(err.kind(), err.to_string()).into(),
+        err => eyre!("{err}"),
+    }
+}
+
+// Whether opening a file with `options` may change it.
+fn writes(options: &OpenOptions) -> bool {
+    options.write || options.append || options.truncate || options.create || options.create_new
+}
 
+// Check that options to open a cleartext file with make sense together, the same way as
+// std::fs::OpenOptions. `append` is set if the file is opened for appending either way.
+fn check_open_options(options: &OpenOptions, append: bool) -> io::Result<()> {
+    let invalid = |message| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    let writes = options.write || append;
+    if !options.read && !writes {
+        invalid("the file must be opened for reading, writing, or appending")
+    } else if !writes && (options.truncate || options.create || options.create_new) {
+        invalid("creating or truncating a file requires opening it for writing")
+    } else if append && options.truncate && !options.create_new {
+        invalid("a file can't be both truncated and appended to")
+    } else {
         Ok(())
     }
+}
 
-    fn set_times(&self, cleartext_path: impl AsRef<Path>, times: FileTimes) -> Result<()> {
-        let entry = self.dir_entry(&cleartext_path)?;
+// The options to open the ciphertext of a file with. It's always read, for the header and the
+// chunks that are partly overwritten. Creating and truncating are done separately, since they'd
+// leave the file without a header, and appending moves to the end before each write instead,
+// since chunks are rewritten in place.
+fn ciphertext_options(options: &OpenOptions, append: bool) -> OpenOptions {
+    let mut ciphertext_options = options.clone();
+    ciphertext_options
+        .read(true)
+        .write(options.write || append)
+        .append(false)
+        .truncate(false)
+        .create(false)
+        .create_new(false);
+    ciphertext_options
+}
 
-        match entry.kind {
-            FileKind::File => {
-                let parent_dir_id = self
-                    .translator
-                    .get_dir_id(cleartext_path.as_ref().parent().unwrap())?;
-                let mut ciphertext_path = self
-                    .translator
-                    .get_ciphertext_path(&cleartext_path, parent_dir_id)?;
+// Whether creating an entry failed because something with its name already exists.
+fn is_already_exists(err: &color_eyre::Report) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::AlreadyExists)
+}
 
-                if ciphertext_path.is_dir() && ciphertext_path.join("contents.c9r").is_file() {
-                    ciphertext_path = ciphertext_path.join("contents.c9r");
+// Add the ciphertext path an operation works on to its span.
+fn record_ciphertext_path(ciphertext_path: &Path) {
+    Span::current().record("ciphertext_path", field::display(ciphertext_path.display()));
+    context::record(ciphertext_path);
+}
+
+/// Whether a ciphertext entry has a shortened name.
+fn is_shortened(ciphertext_path: &Path) -> bool {
+    ciphertext_path
+        .extension()
+        .is_some_and(|extension| extension == "c9s")
+}
+
+/// The file holding the data of a ciphertext entry: the entry itself for files with full-length
+/// names, and otherwise the `contents.c9r`, `dir.c9r`, or `symlink.c9r` inside it.
+fn payload_path(ciphertext_path: &Path, kind: FileKind) -> PathBuf {
+    match kind {
+        FileKind::File if !is_shortened(ciphertext_path) => ciphertext_path.to_path_buf(),
+        FileKind::File => ciphertext_path.join("contents.c9r"),
+        FileKind::Directory => ciphertext_path.join("dir.c9r"),
+        FileKind::Symlink => ciphertext_path.join("symlink.c9r"),
+    }
+}
+
+/// If a ciphertext name looks like a sync client's copy of a conflicting entry, e.g.
+/// `<name> (conflicted copy).c9r` or `<name> (1).c9s`, give the name of the original entry.
+pub(crate) fn sync_conflict_original(file_name: &str) -> Option<String> {
+    let (stem, extension) = file_name.rsplit_once('.')?;
+    if extension != "c9r" && extension != "c9s" {
+        return None;
+    }
+
+    // Encrypted names and hashes are padded base64url, so they come in blocks of 4 characters,
+    // and sync clients add a suffix like " (1)" or ".sync-conflict-..."
+    let (encoded, suffix) = stem.split_at(
+        stem.find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '=')))?,
+    );
+    if encoded.is_empty() || encoded.len() % 4 != 0 || !suffix.starts_with([' ', '.']) {
+        return None;
+    }
+
+    Some(format!("{encoded}.{extension}"))
+}
+
+/// Whether a name in a hashed directory belongs to a temporary entry that's still being created,
+/// or was left behind by a crash.
+fn is_temp_name(file_name: &OsStr) -> bool {
+    let file_name = file_name.as_encoded_bytes();
+    file_name.starts_with(b".") && file_name.ends_with(b".tmp")
+}
+
+// Fail at a named point in a multi-step operation, as if the process had crashed there.
+#[cfg(test)]
+fn fault_point(name: &str) -> Result<()> {
+    FAULT_POINT.with(|fault_point| match fault_point.take() {
+        Some(fault) if fault == name => Err(eyre!("injected fault at {name}")),
+        fault => {
+            fault_point.set(fault);
+            Ok(())
+        }
+    })
+}
+
+#[cfg(not(test))]
+fn fault_point(_name: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    static FAULT_POINT: std::cell::Cell<Option<&'static str>> = const { std::cell::Cell::new(None) };
+}
+
+/// Read the directory ID from the dir.c9r file of a ciphertext directory entry.
+fn read_dir_id(storage: &dyn VaultStorage, ciphertext_path: &Path) -> Result<DirId> {
+    let dir_id = storage.read_to_string(&ciphertext_path.join("dir.c9r"))?;
+    DirId::parse(&dir_id)
+        .wrap_err_with(|| format!("corrupt dir.c9r in {}", ciphertext_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Seek, SeekFrom},
+        ops::ControlFlow,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        time::{Duration, Instant},
+    };
+
+    use base64ct::{Base64Url, Encoding};
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::{storage::Throttle, testing::copy_dir};
+
+    #[test]
+    fn shortened_name_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let test_dir = Path::new("/test_dir");
+        let dir_id = fs.translator.get_dir_id(test_dir).unwrap();
+
+        // Moving the desktop app's shortened entries away and back must recreate them exactly
+        let shortened = fs
+            .dir_entries(test_dir)
+            .unwrap()
+            .into_keys()
+            .filter_map(|path| {
+                let ciphertext_path = fs.ciphertext_path(&path).unwrap();
+                (ciphertext_path.extension().unwrap() == "c9s").then_some((path, ciphertext_path))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(shortened.len(), 3);
+        for (path, ciphertext_path) in shortened {
+            let name = path.file_name().unwrap();
+            let name_c9s = fs::read(ciphertext_path.join("name.c9s")).unwrap();
+            fs.rename(test_dir, name, test_dir, OsStr::new("short"))
+                .unwrap();
+            assert!(!ciphertext_path.exists());
+            assert_eq!(
+                fs.ciphertext_path(test_dir.join("short"))
+                    .unwrap()
+                    .extension()
+                    .unwrap(),
+                "c9r"
+            );
+
+            fs.rename(test_dir, OsStr::new("short"), test_dir, name)
+                .unwrap();
+            assert_eq!(
+                fs::read(ciphertext_path.join("name.c9s")).unwrap(),
+                name_c9s
+            );
+            fs.rename(test_dir, name, test_dir, name).unwrap();
+            assert!(ciphertext_path.join("name.c9s").is_file());
+        }
+
+        // New entries with long names are shortened the same way
+        let long_name = "x".repeat(200);
+        let expected_name = vault
+            .cryptor()
+            .encrypt_filename(OsStr::new(&long_name), &dir_id)
+            .unwrap();
+        let expected_path = dir
+            .path()
+            .join("d/RT/C3KT7DD5C3X6QE32X4IL6PM6WHHNB5")
+            .join(Base64Url::encode_string(&Sha1::digest(&expected_name)) + ".c9s");
+        let permissions = Permissions::from_mode(0o755);
+        for (kind, inner) in [
+            (FileKind::File, "contents.c9r"),
+            (FileKind::Directory, "dir.c9r"),
+            (FileKind::Symlink, "symlink.c9r"),
+        ] {
+            let long_name = OsStr::new(&long_name);
+            match kind {
+                FileKind::File => fs.mknod(test_dir, long_name, permissions.clone()).map(drop),
+                FileKind::Directory => fs.mkdir(test_dir, long_name, permissions.clone()).map(drop),
+                FileKind::Symlink => fs.symlink(test_dir, long_name, "target").map(drop),
+            }
+            .unwrap();
+            assert_eq!(
+                fs::read_to_string(expected_path.join("name.c9s")).unwrap(),
+                expected_name
+            );
+            assert!(expected_path.join(inner).is_file());
+            assert_eq!(fs.dir_entry(test_dir.join(long_name)).unwrap().kind, kind);
+
+            match kind {
+                FileKind::Directory => fs.rmdir(test_dir, long_name),
+                _ => fs.unlink(test_dir, long_name),
+            }
+            .unwrap();
+        }
+
+        let report = vault.check(Default::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn dir_id_backup_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let read_backup = |cleartext_dir: &str| {
+            let dir_id = fs.translator.get_dir_id(cleartext_dir).unwrap();
+            let hashed_dir_path = fs.hashed_dir_path(&dir_id).unwrap();
+            let mut options = OpenOptions::new();
+            options.read(true);
+            let mut backup = String::new();
+            EncryptedFile::open(vault.cryptor(), hashed_dir_path.join("dirid.c9r"), options)
+                .unwrap()
+                .read_to_string(&mut backup)
+                .unwrap();
+            let size = hashed_dir_path.join("dirid.c9r").metadata().unwrap().len();
+            (dir_id, backup, size)
+        };
+
+        // New backups look just like the ones written by the desktop app
+        let (dir_id, backup, fixture_size) = read_backup("/test_dir");
+        assert_eq!(backup, dir_id.as_str());
+        fs.mkdir("/", OsStr::new("new_dir"), Permissions::from_mode(0o555))
+            .unwrap();
+        let (dir_id, backup, size) = read_backup("/new_dir");
+        assert_eq!(backup, dir_id.as_str());
+        assert_eq!(size, fixture_size);
+
+        // Renaming doesn't change the directory ID, so the backup stays valid
+        fs.rename("/", OsStr::new("new_dir"), "/test_dir", OsStr::new("moved"))
+            .unwrap();
+        assert_eq!(read_backup("/test_dir/moved").1, dir_id.as_str());
+        let report = vault.check(Default::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+
+        let hashed_dir_path = fs.hashed_dir_path(&dir_id).unwrap();
+        fs::set_permissions(&hashed_dir_path, Permissions::from_mode(0o755)).unwrap();
+        fs.rmdir("/test_dir", OsStr::new("moved")).unwrap();
+        assert!(!hashed_dir_path.exists());
+    }
+
+    #[test]
+    fn rmdir_test() {
+        fn list(dir: &Path, paths: &mut Vec<PathBuf>) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    list(&path, paths);
                 }
+                paths.push(path);
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let mut before = Vec::new();
+        list(&dir.path().join("d"), &mut before);
+        before.sort();
+
+        let permissions = Permissions::from_mode(0o755);
+        for _ in 0..5 {
+            fs.mkdir("/", OsStr::new("a"), permissions.clone()).unwrap();
+            fs.mkdir("/a", OsStr::new("b"), permissions.clone())
+                .unwrap();
+            fs.mknod("/a/b", OsStr::new("file"), permissions.clone())
+                .unwrap();
+
+            // Directories with contents are left alone
+            let err = fs.rmdir("/a", OsStr::new("b")).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<io::Error>().unwrap().kind(),
+                io::ErrorKind::DirectoryNotEmpty
+            );
+            assert_eq!(fs.dir_entries("/a/b").unwrap().len(), 1);
+
+            fs.unlink("/a/b", OsStr::new("file")).unwrap();
+            fs.rmdir("/a", OsStr::new("b")).unwrap();
+            fs.rmdir("/", OsStr::new("a")).unwrap();
+        }
 
-                let file = File::open(ciphertext_path)?;
-                file.set_times(times)?;
+        let mut after = Vec::new();
+        list(&dir.path().join("d"), &mut after);
+        after.sort();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn interrupted_create_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = crate::VaultCreateOptions {
+            kdf_params: crate::KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let permissions = Permissions::from_mode(0o755);
+        let long_name = "x".repeat(200);
+
+        for durability in [Durability::Fast, Durability::Safe] {
+            let fs = EncryptedFileSystem::new(&vault).durability(durability);
+            fs.mkdir("/", OsStr::new("test_dir"), permissions.clone())
+                .unwrap();
+            let names = [OsStr::new("short"), OsStr::new(&long_name)];
+
+            type Attempt<'a> = &'a dyn Fn(&OsStr) -> Result<()>;
+            let attempts: [Attempt; 3] = [
+                &|name| fs.mknod("/test_dir", name, permissions.clone()).map(drop),
+                &|name| fs.mkdir("/test_dir", name, permissions.clone()).map(drop),
+                &|name| fs.symlink("/test_dir", name, "target").map(drop),
+            ];
+            for (attempt, name) in attempts.iter().zip(names.iter().cycle()) {
+                FAULT_POINT.set(Some("create_entry"));
+                assert!(attempt(name).is_err());
+                assert!(fs.dir_entries("/test_dir").unwrap().is_empty());
+                assert!(fs.walk("/test_dir").next().is_none());
+
+                attempt(name).unwrap();
+                if fs
+                    .dir_entry(Path::new("/test_dir").join(name))
+                    .unwrap()
+                    .kind
+                    == FileKind::Directory
+                {
+                    fs.rmdir("/test_dir", name).unwrap();
+                } else {
+                    fs.unlink("/test_dir", name).unwrap();
+                }
             }
-            FileKind::Directory => {
-                // let dir_id = self.translator.get_dir_id(&cleartext_path)?;
-                // let hashed_dir_id = self.vault.cryptor().hash_dir_id(dir_id).unwrap();
-                bail!("not yet implemented");
+
+            // Renaming between full-length and shortened names keeps the contents
+            fs.mknod("/test_dir", names[0], permissions.clone())
+                .unwrap();
+            let mut options = OpenOptions::new();
+            options.write(true).read(true);
+            let mut file = fs.open_file("/test_dir/short", options, false).unwrap();
+            file.write_all(b"contents").unwrap();
+            file.flush().unwrap();
+            for (from, to) in [(names[0], names[1]), (names[1], names[0])] {
+                fs.rename("/test_dir", from, "/test_dir", to).unwrap();
+                let mut options = OpenOptions::new();
+                options.read(true);
+                let mut contents = String::new();
+                fs.open_file(Path::new("/test_dir").join(to), options, false)
+                    .unwrap()
+                    .read_to_string(&mut contents)
+                    .unwrap();
+                assert_eq!(contents, "contents");
+                assert_eq!(fs.dir_entries("/test_dir").unwrap().len(), 1);
             }
-            FileKind::Symlink => {
-                let parent_dir_id = self
-                    .translator
-                    .get_dir_id(cleartext_path.as_ref().parent().unwrap())?;
-                let ciphertext_path = self
-                    .translator
-                    .get_ciphertext_path(&cleartext_path, parent_dir_id)?;
 
-                let file = File::open(ciphertext_path.join("symlink.c9r"))?;
-                file.set_times(times)?;
+            fs.unlink("/test_dir", names[0]).unwrap();
+            fs.rmdir("/", OsStr::new("test_dir")).unwrap();
+        }
+
+        let report = vault.check(Default::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn dir_entries_lossy_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let (entries, errors) = fs.dir_entries_lossy("/").unwrap();
+        assert!(errors.is_empty());
+
+        // A conflict copy of an entry that doesn't exist, and a file renamed by hand
+        let file = fs.ciphertext_path("/test_file.txt").unwrap();
+        let conflict_name = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA (conflicted copy 2024-05-01).c9r";
+        fs::copy(&file, file.with_file_name(conflict_name)).unwrap();
+        fs::copy(&file, file.with_file_name("renamed.txt.c9r")).unwrap();
+
+        let (lossy_entries, errors) = fs.dir_entries_lossy("/").unwrap();
+        assert_eq!(
+            lossy_entries.keys().collect::<Vec<_>>(),
+            entries.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0],
+            EntryError {
+                ciphertext_name: conflict_name.into(),
+                reason: EntryErrorReason::SyncConflict {
+                    original_name: "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA.c9r".into()
+                }
+            }
+        );
+        assert_eq!(errors[1].ciphertext_name, "renamed.txt.c9r");
+        assert!(matches!(errors[1].reason, EntryErrorReason::Invalid(_)));
+
+        // Strict listings fail at the first entry that can't be read, in ciphertext order
+        let err = fs.dir_entries("/").unwrap_err();
+        assert!(err.to_string().starts_with(conflict_name), "{err:?}");
+        fs::remove_file(file.with_file_name(conflict_name)).unwrap();
+        let err = fs.dir_entries("/").unwrap_err();
+        assert!(
+            matches!(err, FsError::NameDecryption { ref ciphertext_name, .. } if ciphertext_name == "renamed.txt.c9r"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn sync_conflict_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_conflicts", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let long_name = "test_name_too_long".to_string() + &"_name_too_long".repeat(9);
+        let conflicts = [
+            ("/test_file (Conflict 1).txt", "/test_file.txt".to_string()),
+            (
+                "/test_dir/test_file_2 (Conflict 1).txt",
+                "/test_dir/test_file_2.txt".to_string(),
+            ),
+            (
+                &format!("/test_dir/{long_name} (Conflict 1).txt"),
+                format!("/test_dir/{long_name}.txt"),
+            ),
+        ];
+        let read = |fs: &EncryptedFileSystem, path: &str| -> Result<String> {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            let mut contents = String::new();
+            fs.open_file(path, options, false)?
+                .read_to_string(&mut contents)?;
+            Ok(contents)
+        };
+
+        // Conflict copies can't be opened until they're renamed, so they're left out
+        for fs in [
+            EncryptedFileSystem::new(&vault),
+            EncryptedFileSystem::new(&vault)
+                .repair_conflicts(true)
+                .read_only(true),
+        ] {
+            let mut entries = fs.dir_entries("/").unwrap();
+            assert_eq!(entries.len(), 4);
+            entries.extend(fs.dir_entries("/test_dir").unwrap());
+            assert_eq!(entries.len(), 8);
+            for (conflict, _) in &conflicts {
+                assert!(!entries.contains_key(Path::new(conflict)), "{conflict}");
+                assert!(read(&fs, conflict).is_err());
             }
         }
 
-        Ok(())
+        // Once renamed, they're listed under new names. The copy of the directory entry points
+        // to the same directory, so it's removed.
+        let fs = EncryptedFileSystem::new(&vault).repair_conflicts(true);
+        let mut entries = fs.dir_entries("/").unwrap();
+        assert_eq!(entries.len(), 5);
+        entries.extend(fs.dir_entries("/test_dir").unwrap());
+        assert_eq!(entries.len(), 11);
+        for (conflict, original) in &conflicts {
+            assert!(entries.contains_key(Path::new(conflict)), "{conflict}");
+            assert_eq!(read(&fs, conflict).unwrap(), read(&fs, original).unwrap());
+        }
+
+        // Repaired entries are found without repairing again
+        let fs = EncryptedFileSystem::new(&vault);
+        for (conflict, _) in &conflicts {
+            read(&fs, conflict).unwrap();
+        }
+        let report = vault.check(Default::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn sync_conflict_name_test() {
+        for (name, original) in [
+            ("AbCd (1).c9r", Some("AbCd.c9r")),
+            ("AbCdEf== (conflicted copy).c9s", Some("AbCdEf==.c9s")),
+            ("AbCd-_Ef (Conflict 2).c9r", Some("AbCd-_Ef.c9r")),
+            (
+                "AbCdEf==.sync-conflict-20240501-120000-ABCDEFG.c9r",
+                Some("AbCdEf==.c9r"),
+            ),
+            ("AbCd.c9r", None),
+            ("renamed.txt.c9r", None),
+            ("AbCd (1).txt", None),
+            ("A (1).c9r", None),
+        ] {
+            assert_eq!(sync_conflict_original(name).as_deref(), original, "{name}");
+        }
+    }
+
+    #[test]
+    fn concurrent_changes_test() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<EncryptedFileSystem>();
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = crate::VaultCreateOptions {
+            kdf_params: crate::KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let permissions = Permissions::from_mode(0o755);
+        fs.mkdir("/", OsStr::new("shared"), permissions.clone())
+            .unwrap();
+
+        let threads = 8;
+        let iterations = 20;
+        std::thread::scope(|scope| {
+            for thread in 0..threads {
+                let (fs, permissions) = (&fs, permissions.clone());
+                scope.spawn(move || {
+                    for i in 0..iterations {
+                        // Some names are long enough to be shortened
+                        let padding = if i % 4 == 0 {
+                            "x".repeat(200)
+                        } else {
+                            "".into()
+                        };
+                        let file = format!("file-{thread}-{i}{padding}");
+                        let dir = format!("dir-{thread}-{i}{padding}");
+
+                        fs.mknod("/shared", OsStr::new(&file), permissions.clone())
+                            .unwrap();
+                        fs.mkdir("/shared", OsStr::new(&dir), permissions.clone())
+                            .unwrap();
+                        fs.dir_entries("/shared").unwrap();
+                        fs.rmdir("/shared", OsStr::new(&dir)).unwrap();
+                        if i % 2 == 1 {
+                            fs.unlink("/shared", OsStr::new(&file)).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        let entries = fs.dir_entries("/shared").unwrap();
+        assert_eq!(entries.len(), threads * iterations / 2);
+        assert!(entries.values().all(|entry| entry.kind == FileKind::File));
+        assert!(fs.find_orphans().unwrap().is_empty());
+        let report = vault.check(Default::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn memory_storage_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = crate::VaultCreateOptions {
+            kdf_params: crate::KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options)
+            .unwrap()
+            .with_storage(crate::storage::MemoryStorage::new());
+        let storage = vault.storage();
+        let root_dir = vault
+            .path()
+            .join("d")
+            .join(vault.cryptor().hash_dir_id(&DirId::root()).unwrap());
+        storage.create_dir_all(&root_dir).unwrap();
+        EncryptedFile::create_new_in(storage, vault.cryptor(), root_dir.join("dirid.c9r")).unwrap();
+
+        let fs = EncryptedFileSystem::new(&vault);
+        let permissions = Permissions::from_mode(0o755);
+        let long_name = "x".repeat(200);
+        fs.mkdir("/", OsStr::new("dir"), permissions.clone())
+            .unwrap();
+        fs.mknod("/dir", OsStr::new(&long_name), permissions.clone())
+            .unwrap();
+        fs.symlink("/", OsStr::new("link"), "dir").unwrap();
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let mut file = fs
+            .open_file(Path::new("/dir").join(&long_name), options, false)
+            .unwrap();
+        file.write_all(b"in memory").unwrap();
+        file.flush().unwrap();
+        drop(file);
+
+        fs.rename("/dir", OsStr::new(&long_name), "/", OsStr::new("file.txt"))
+            .unwrap();
+        let entries = fs.dir_entries("/").unwrap();
+        assert_eq!(
+            entries.keys().collect::<Vec<_>>(),
+            ["/dir", "/file.txt", "/link"]
+        );
+        assert_eq!(entries[Path::new("/file.txt")].size, 9);
+        assert_eq!(fs.link_target("/link").unwrap(), Path::new("dir"));
+
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = String::new();
+        fs.open_file("/file.txt", options, false)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "in memory");
+
+        // Nothing is written to the root directory the vault created on disk
+        assert_eq!(fs::read_dir(&root_dir).unwrap().count(), 1);
+        fs.unlink("/", OsStr::new("file.txt")).unwrap();
+        fs.rmdir("/", OsStr::new("dir")).unwrap();
+        assert_eq!(fs.dir_entries("/").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tracing_spans_test() {
+        #[derive(Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Output {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let shape = crate::testing::VaultShape {
+            files_per_dir: 0,
+            ..Default::default()
+        };
+        let vault = crate::testing::create_vault(dir.path(), &shape).unwrap();
+
+        // Run some operations, returning everything that was traced
+        let trace = |trace_paths: bool| {
+            let output = Output::default();
+            let writer = output.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(move || writer.clone())
+                .with_max_level(tracing::Level::DEBUG)
+                .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+                .with_ansi(false)
+                .finish();
+
+            let fs = EncryptedFileSystem::new(&vault).trace_paths(trace_paths);
+            tracing::subscriber::with_default(subscriber, || {
+                let permissions = Permissions::from_mode(0o644);
+                fs.mknod("/", OsStr::new("secret.txt"), permissions)
+                    .unwrap();
+                fs.rename("/", OsStr::new("secret.txt"), "/", OsStr::new("moved.txt"))
+                    .unwrap();
+                assert!(fs.dir_entry("/secret.txt").is_err());
+                fs.unlink("/", OsStr::new("moved.txt")).unwrap();
+            });
+
+            let output = output.0.lock().unwrap().clone();
+            String::from_utf8(output).unwrap()
+        };
+
+        let redacted = trace(false);
+        for span in ["mknod", "rename", "dir_entry", "unlink"] {
+            assert!(redacted.contains(&format!("{span}{{")), "{redacted}");
+        }
+        assert!(redacted.contains("name=<redacted>"), "{redacted}");
+        assert!(redacted.contains("ciphertext_path="), "{redacted}");
+        assert!(!redacted.contains("secret.txt"), "{redacted}");
+        assert!(!redacted.contains("moved.txt"), "{redacted}");
+
+        let visible = trace(true);
+        assert!(visible.contains("name=secret.txt"), "{visible}");
+        assert!(visible.contains("new_name=moved.txt"), "{visible}");
+        assert!(!visible.contains("<redacted>"), "{visible}");
+    }
+
+    #[test]
+    fn observer_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = crate::testing::VaultShape {
+            files_per_dir: 1,
+            dirs_per_dir: 1,
+            depth: 1,
+            file_len: 40_000,
+            ..Default::default()
+        };
+        let vault = crate::testing::create_vault(dir.path(), &shape).unwrap();
+        let observer = Arc::new(CountingObserver::new());
+        let fs = EncryptedFileSystem::new(&vault).observer(observer.clone());
+        let path = shape.file_path(shape.dir_path("/", 0), 0);
+
+        // Looking up a path fills the directory ID cache, which the next lookup hits
+        fs.dir_entry(&path).unwrap();
+        let (hits, misses) = observer.cache(CacheKind::DirId);
+        assert!(misses > 0);
+        fs.dir_entry(&path).unwrap();
+        assert!(observer.cache(CacheKind::DirId).0 > hits);
+        assert_eq!(observer.cache(CacheKind::DirId).1, misses);
+
+        // Reading decrypts both chunks
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = vec![0; shape.file_len];
+        fs.open_file(&path, options.clone(), false)
+            .unwrap()
+            .read_exact(&mut contents)
+            .unwrap();
+        assert_eq!(contents, shape.file_contents(0));
+        assert_eq!(observer.decrypted(), (2, 40_000));
+        assert_eq!(observer.encrypted(), (0, 0));
+
+        // Overwriting part of a chunk decrypts it first
+        options.write(true);
+        fs.open_file(&path, options, false)
+            .unwrap()
+            .write_all(b"hello")
+            .unwrap();
+        assert_eq!(observer.decrypted(), (3, 40_000 + 32 * 1024));
+        assert_eq!(observer.encrypted(), (1, 32 * 1024));
+
+        // Translating a ciphertext path goes through the directory path index
+        let ciphertext_path = fs.ciphertext_path(&path).unwrap();
+        assert_eq!(fs.cleartext_path(&ciphertext_path).unwrap(), path);
+        assert_eq!(fs.cleartext_path(&ciphertext_path).unwrap(), path);
+        assert_eq!(observer.cache(CacheKind::DirPath), (1, 1));
+
+        let text = observer.to_string();
+        assert!(
+            text.contains("cryptomator_chunks_decrypted_total 3\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("cryptomator_cache_misses_total{cache=\"dir_path\"} 1\n"),
+            "{text}"
+        );
+    }
+
+    #[test]
+    fn fs_error_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let permissions = Permissions::from_mode(0o755);
+        fs.mkdir("/", OsStr::new("dir"), permissions.clone())
+            .unwrap();
+        fs.mkdir("/", OsStr::new("gone"), permissions).unwrap();
+
+        assert!(matches!(fs.dir_entry("/missing"), Err(FsError::NotFound)));
+        assert!(matches!(
+            fs.dir_entry("/missing/file"),
+            Err(FsError::NotFound)
+        ));
+        assert!(matches!(fs.dir_entries("/missing"), Err(FsError::NotFound)));
+        assert!(matches!(fs.link_target("/missing"), Err(FsError::NotFound)));
+
+        assert!(matches!(
+            fs.dir_entry("/test_file.txt/file"),
+            Err(FsError::NotADirectory)
+        ));
+        assert!(matches!(
+            fs.dir_entry("/test_file.txt/dir/file"),
+            Err(FsError::NotADirectory)
+        ));
+        assert!(matches!(
+            fs.dir_entries("/test_file.txt"),
+            Err(FsError::NotADirectory)
+        ));
+
+        // An entry whose name wasn't encrypted with this directory's ID
+        let (_, hashed_dir) = fs.translator.get_dir_id_and_path("/dir").unwrap();
+        fs::copy(
+            fs.ciphertext_path("/test_file.txt").unwrap(),
+            hashed_dir.join("bogus.c9r"),
+        )
+        .unwrap();
+        match fs.dir_entries("/dir") {
+            Err(FsError::NameDecryption {
+                ciphertext_name, ..
+            }) => assert_eq!(ciphertext_name, "bogus.c9r"),
+            result => panic!("{result:?}"),
+        }
+
+        // A directory whose hashed directory was deleted
+        let (_, hashed_dir) = fs.translator.get_dir_id_and_path("/gone").unwrap();
+        fs::remove_dir_all(hashed_dir).unwrap();
+        assert!(matches!(fs.dir_entries("/gone"), Err(FsError::Io(_))));
+
+        // A directory with a corrupt dir.c9r
+        let dir_file = fs.ciphertext_path("/dir").unwrap().join("dir.c9r");
+        fs::write(dir_file, "").unwrap();
+        fs.invalidate("/dir");
+        assert!(matches!(fs.dir_entry("/dir"), Err(FsError::Other(_))));
+    }
+
+    #[test]
+    fn invalid_path_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let invalid_path =
+            |result: Result<_>| matches!(result.unwrap_err().downcast(), Ok(FsError::InvalidPath));
+
+        for path in [
+            "a/./../../b",
+            "../../etc/passwd",
+            "/test_dir/../test_file.txt",
+            "/test_dir/.",
+            "/test_dir//test_file_2.txt",
+            "/test_dir/",
+            "/test\0file.txt",
+            "..",
+            "/..",
+            "/test_dir/..",
+        ] {
+            let path = Path::new(path);
+            assert!(
+                matches!(fs.dir_entry(path), Err(FsError::InvalidPath)),
+                "{path:?}"
+            );
+            assert!(matches!(fs.dir_entries(path), Err(FsError::InvalidPath)));
+            assert!(matches!(fs.link_target(path), Err(FsError::InvalidPath)));
+            assert!(invalid_path(
+                fs.open_file(path, OpenOptions::new(), false).map(drop)
+            ));
+            let name = OsStr::new("new");
+            assert!(invalid_path(
+                fs.mkdir(path, name, Permissions::from_mode(0o755))
+                    .map(drop)
+            ));
+            assert!(invalid_path(fs.unlink(path, name)));
+
+            assert!(invalid_path(fs.ciphertext_path(path).map(drop)));
+            assert!(matches!(
+                fs.read_dir(path).map(drop),
+                Err(FsError::InvalidPath)
+            ));
+            assert!(invalid_path(fs.walk(path).next().unwrap().map(drop)));
+            assert!(invalid_path(fs.glob("*", path).next().unwrap().map(drop)));
+            assert!(invalid_path(
+                fs.disk_usage(path, Default::default()).map(drop)
+            ));
+            assert!(invalid_path(fs.file_fingerprint(path).map(drop)));
+            assert!(invalid_path(
+                fs.copy_file("/test_file.txt", path, false).map(drop)
+            ));
+            assert!(invalid_path(fs.copy_file(path, "/copy", false).map(drop)));
+            assert!(invalid_path(
+                fs.copy_dir_recursive("/test_dir", path, |_| ControlFlow::Continue(()))
+                    .map(drop)
+            ));
+            assert!(invalid_path(fs.move_dir(path, "/moved")));
+            assert!(invalid_path(
+                fs.import_dir(dir.path(), path, Default::default())
+                    .map(drop)
+            ));
+            assert!(invalid_path(
+                vault
+                    .export_tar(io::sink(), path, &Default::default())
+                    .map(drop)
+            ));
+        }
+
+        for name in ["", ".", "..", "a/b", "/b", "a\0b"] {
+            let name = OsStr::new(name);
+            let permissions = Permissions::from_mode(0o644);
+            assert!(
+                invalid_path(fs.mknod("/", name, permissions).map(drop)),
+                "{name:?}"
+            );
+            assert!(invalid_path(fs.symlink("/", name, "target").map(drop)));
+            assert!(invalid_path(fs.rmdir("/", name)));
+            let file = OsStr::new("test_file.txt");
+            assert!(invalid_path(fs.rename("/", file, "/", name)));
+            assert!(invalid_path(fs.rename("/", name, "/test_dir", file)));
+        }
+        assert!(fs.dir_entry("/test_file.txt").is_ok());
+
+        // Absolute and relative paths both start at the root of the vault
+        for path in ["/test_dir/test_file_2.txt", "test_dir/test_file_2.txt"] {
+            assert_eq!(fs.dir_entry(path).unwrap().kind, FileKind::File);
+        }
+        assert!(fs.dir_entries("").is_ok());
+    }
+
+    #[test]
+    fn symlink_target_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+
+        // Created by the desktop app
+        assert_eq!(
+            fs.link_target("/test_link").unwrap(),
+            Path::new("test_dir/test_file_2.txt")
+        );
+
+        // However they're looked up, symlinks are as large as their targets, like on other file
+        // systems
+        let check_links = |fs: &EncryptedFileSystem| {
+            let links = fs
+                .walk("/")
+                .map(Result::unwrap)
+                .filter(|entry| entry.entry.kind == FileKind::Symlink)
+                .collect::<Vec<_>>();
+            assert!(!links.is_empty());
+            for link in links {
+                let len = fs.link_target(&link.path).unwrap().as_os_str().len() as u64;
+                assert_eq!(link.entry.size, len);
+                assert_eq!(fs.dir_entry(&link.path).unwrap().size, len);
+            }
+        };
+        check_links(&fs);
+        for fixture in ["vault_v8_siv_ctrmac", "vault_v8_long_names"] {
+            let config_path = Path::new("tests/fixtures")
+                .join(fixture)
+                .join("vault.cryptomator");
+            check_links(&EncryptedFileSystem::new(
+                &Vault::open(config_path, "password").unwrap(),
+            ));
+        }
+
+        let long_target = "long/".repeat(1024);
+        let invalid_utf8 = OsStr::from_bytes(b"target-\xff\xfe");
+        let longest = "x".repeat(EncryptedFileSystem::MAX_SYMLINK_TARGET_LEN);
+        for (name, target) in [
+            ("multibyte", OsStr::new("ünïcødé/目标/🔗")),
+            ("long", OsStr::new(&long_target)),
+            ("invalid_utf8", invalid_utf8),
+            ("longest", OsStr::new(&longest)),
+        ] {
+            let entry = fs.symlink("/", OsStr::new(name), target).unwrap();
+            assert_eq!(entry.size, target.len() as u64);
+            let path = Path::new("/").join(name);
+            assert_eq!(fs.link_target(&path).unwrap(), target);
+            assert_eq!(fs.dir_entry(&path).unwrap().size, target.len() as u64);
+            assert_eq!(
+                fs.dir_entries("/").unwrap()[&path].size,
+                target.len() as u64
+            );
+        }
+
+        let err = fs.symlink("/", OsStr::new("empty"), "").unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let too_long = "x".repeat(EncryptedFileSystem::MAX_SYMLINK_TARGET_LEN + 1);
+        let err = fs
+            .symlink("/", OsStr::new("too_long"), too_long)
+            .unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidFilename);
+        assert!(fs.dir_entry("/empty").is_err());
+        assert!(fs.dir_entry("/too_long").is_err());
+
+        // Targets written by something else are only read up to the limit
+        let symlink_path = fs.ciphertext_path("/longest").unwrap().join("symlink.c9r");
+        fs::remove_file(&symlink_path).unwrap();
+        EncryptedFile::create_new(vault.cryptor(), &symlink_path)
+            .unwrap()
+            .write_all(&[b'x'; 40_000])
+            .unwrap();
+        assert!(matches!(fs.link_target("/longest"), Err(FsError::Other(_))));
+    }
+
+    #[test]
+    fn locked_file_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = crate::testing::VaultShape {
+            files_per_dir: 1,
+            ..Default::default()
+        };
+        let vault = crate::testing::create_vault(dir.path(), &shape).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let path = shape.file_path("/", 0);
+        let ciphertext_path = fs.ciphertext_path(&path).unwrap();
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let mut file = fs.open_file(&path, options.clone(), false).unwrap();
+
+        // Another handle, like one in another process, holds the lock
+        let other = vault.storage().open(&ciphertext_path, &options).unwrap();
+        other.try_lock_exclusive().unwrap();
+        let err = file.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        match FsError::from(err) {
+            FsError::Locked { path } => assert_eq!(path, ciphertext_path),
+            err => panic!("{err:?}"),
+        }
+        let result = fs.open_file(&path, options.clone(), false);
+        assert!(matches!(
+            result.map_err(FsError::from),
+            Err(FsError::Locked { .. })
+        ));
+
+        // Other errors aren't mistaken for it
+        let err = io::Error::new(io::ErrorKind::WouldBlock, "busy");
+        assert!(matches!(FsError::from(err), FsError::Io(_)));
+
+        other.unlock().unwrap();
+        assert_eq!(file.read(&mut [0; 16]).unwrap(), 16);
+    }
+
+    #[test]
+    fn io_error_test() {
+        let errors = [
+            (FsError::NotFound, io::ErrorKind::NotFound),
+            (FsError::NotADirectory, io::ErrorKind::NotADirectory),
+            (FsError::InvalidPath, io::ErrorKind::InvalidInput),
+            (
+                FsError::NameDecryption {
+                    ciphertext_name: "name.c9r".into(),
+                    source: "bad tag".into(),
+                },
+                io::ErrorKind::InvalidData,
+            ),
+            (
+                FsError::Locked {
+                    path: "file.c9r".into(),
+                },
+                io::ErrorKind::WouldBlock,
+            ),
+            (FsError::ReadOnly, io::ErrorKind::ReadOnlyFilesystem),
+            (
+                FsError::VaultInUse {
+                    pid: Some(1),
+                    since: None,
+                },
+                io::ErrorKind::ResourceBusy,
+            ),
+            (FsError::Frozen, io::ErrorKind::WouldBlock),
+            (
+                FsError::Io(io::ErrorKind::PermissionDenied.into()),
+                io::ErrorKind::PermissionDenied,
+            ),
+            (FsError::Other("bad dir.c9r".into()), io::ErrorKind::Other),
+        ];
+
+        // Every variant keeps its kind, and comes back the same
+        for (err, kind) in errors {
+            let message = err.to_string();
+            let variant = std::mem::discriminant(&err);
+            assert_eq!(err.kind(), kind);
+            let io_err = io::Error::from(err);
+            assert_eq!(io_err.kind(), kind);
+            assert_eq!(io_err.to_string(), message);
+            let err = FsError::from(io_err);
+            assert_eq!(std::mem::discriminant(&err), variant);
+            assert_eq!(err.to_string(), message);
+
+            // Even from inside a report
+            let report = color_eyre::Report::new(io::Error::from(err)).wrap_err("outer");
+            assert_eq!(FsError::from(report).kind(), kind);
+        }
+
+        // Reports of I/O errors keep their kind and the whole chain
+        let report = color_eyre::Report::new(io::Error::from(io::ErrorKind::NotFound))
+            .wrap_err("failed to read dir.c9r");
+        let io_err = io::Error::from(FsError::from(report));
+        assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(io_err.to_string(), "failed to read dir.c9r");
+        assert_eq!(
+            std::error::Error::source(&io_err).unwrap().to_string(),
+            io::Error::from(io::ErrorKind::NotFound).to_string()
+        );
+    }
+
+    #[test]
+    fn timestamps_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+
+        // Whatever the file system supports, nothing looks like it's from 1970
+        let entries = fs.dir_entries("/").unwrap();
+        assert!(!entries.is_empty());
+        for (path, entry) in entries {
+            for time in [entry.modified, entry.accessed, entry.changed, entry.created] {
+                assert!(time > UNIX_EPOCH, "{}", path.display());
+            }
+        }
+
+        // Missing timestamps fall back to the modification time, or failing that, another one
+        let mut metadata = Metadata::new(false, 0o644);
+        metadata.accessed = None;
+        metadata.created = None;
+        let (modified, changed) = (metadata.modified.unwrap(), metadata.changed.unwrap());
+        let entry = DirEntry::new(FileKind::File, 0, metadata.clone());
+        assert_eq!((entry.accessed, entry.created), (modified, modified));
+        assert_eq!(entry.changed, changed);
+        metadata.modified = None;
+        assert_eq!(DirEntry::new(FileKind::File, 0, metadata).modified, changed);
+    }
+
+    #[test]
+    fn root_entry_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+
+        // The root is the hashed directory of the root directory ID, however it's named
+        let root_dir = fs::metadata(fs.root_dir().unwrap()).unwrap();
+        for entry in [
+            fs.root_entry().unwrap(),
+            fs.dir_entry("/").unwrap(),
+            fs.dir_entry("").unwrap(),
+        ] {
+            assert_eq!(entry.kind, FileKind::Directory);
+            assert_eq!(entry.size, root_dir.len());
+            assert_eq!(entry.modified, root_dir.modified().unwrap());
+            assert_eq!(entry.metadata.ino, root_dir.ino());
+        }
+
+        // Directories are located by their hashed ID, which takes the key
+        fs.lock_vault();
+        assert!(matches!(fs.root_entry(), Err(FsError::VaultLocked)));
+        assert!(matches!(fs.dir_entry("/"), Err(FsError::VaultLocked)));
+        assert!(matches!(
+            fs.dir_entry("/test_dir"),
+            Err(FsError::VaultLocked)
+        ));
+        let permissions = Permissions::from_mode(0o755);
+        let err = fs.mkdir("/", OsStr::new("new"), permissions).unwrap_err();
+        assert!(matches!(FsError::from(err), FsError::VaultLocked));
+
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        fs::remove_dir_all(fs.root_dir().unwrap()).unwrap();
+        assert!(matches!(fs.root_entry(), Err(FsError::Io(_))));
+    }
+
+    #[test]
+    fn parent_mtime_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let permissions = Permissions::from_mode(0o755);
+        fs.mkdir("/", OsStr::new("a"), permissions.clone()).unwrap();
+        fs.mkdir("/", OsStr::new("b"), permissions.clone()).unwrap();
+
+        // Each change to a directory's entries should bump its time from the distant past
+        let past = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let reset = |path: &str| {
+            let times = FileTimes::new().set_modified(past);
+            fs.set_times(path, times).unwrap();
+        };
+        let assert_bumped = |path: &str| {
+            let entry = fs.dir_entry(path).unwrap();
+            assert!(entry.modified > past, "{path}");
+        };
+
+        let long_name = OsString::from("x".repeat(200));
+        reset("/a");
+        fs.mknod("/a", &long_name, permissions.clone()).unwrap();
+        fs.mknod("/a", OsStr::new("file"), permissions.clone())
+            .unwrap();
+        assert_bumped("/a");
+
+        reset("/a");
+        fs.mkdir("/a", OsStr::new("dir"), permissions.clone())
+            .unwrap();
+        assert_bumped("/a");
+
+        reset("/a");
+        fs.symlink("/a", OsStr::new("link"), "file").unwrap();
+        assert_bumped("/a");
+
+        reset("/a");
+        fs.unlink("/a", OsStr::new("link")).unwrap();
+        assert_bumped("/a");
+
+        reset("/a");
+        fs.rmdir("/a", OsStr::new("dir")).unwrap();
+        assert_bumped("/a");
+
+        // Replacing a shortened entry only swaps the payload inside it, which the storage
+        // doesn't see as a change to the directory
+        fs.mknod("/b", &long_name, permissions.clone()).unwrap();
+        reset("/a");
+        reset("/b");
+        fs.rename("/a", &long_name, "/b", &long_name).unwrap();
+        assert_bumped("/a");
+        assert_bumped("/b");
+
+        reset("/a");
+        fs.rename("/a", OsStr::new("file"), "/a", OsStr::new("renamed"))
+            .unwrap();
+        assert_bumped("/a");
+    }
+
+    #[test]
+    fn rename_batch_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let permissions = Permissions::from_mode(0o755);
+        fs.mkdir("/", OsStr::new("batch"), permissions.clone())
+            .unwrap();
+        let long_name = "x".repeat(200);
+        for name in ["a", "x", "y", "q", &long_name] {
+            fs.mknod("/batch", OsStr::new(name), permissions.clone())
+                .unwrap();
+        }
+        fs.mkdir("/batch", OsStr::new("b"), permissions.clone())
+            .unwrap();
+
+        let renames = [
+            ("a", "b"),
+            ("b", "c"),
+            ("x", "y"),
+            ("y", "x"),
+            ("missing", "m"),
+            ("q", "missing"),
+            (&long_name, "long"),
+            ("a", "dup"),
+            ("..", "up"),
+            ("c", "c"),
+        ]
+        .map(|(old, new)| (OsString::from(old), OsString::from(new)));
+        let results = fs.rename_batch("/batch", &renames);
+        let errors: Vec<_> = results
+            .into_iter()
+            .map(|result| result.err().map(FsError::from))
+            .collect();
+        assert!(errors[0].is_none() && errors[1].is_none() && errors[6].is_none());
+        let invalid = |err: &Option<FsError>| matches!(err, Some(FsError::Io(err)) if err.kind() == io::ErrorKind::InvalidInput);
+        assert!(invalid(&errors[2]) && invalid(&errors[3]));
+        assert!(matches!(errors[4], Some(FsError::NotFound)));
+        assert!(matches!(&errors[5], Some(FsError::Other(_))));
+        assert!(invalid(&errors[7]));
+        assert!(matches!(errors[8], Some(FsError::InvalidPath)));
+        assert!(invalid(&errors[9]));
+
+        // Only the renames that went through changed anything
+        let kinds: Vec<_> = fs
+            .dir_entries("/batch")
+            .unwrap()
+            .into_iter()
+            .map(|(path, entry)| (path.file_name().unwrap().to_owned(), entry.kind))
+            .collect();
+        let expected = [
+            ("b", FileKind::File),
+            ("c", FileKind::Directory),
+            ("long", FileKind::File),
+            ("q", FileKind::File),
+            ("x", FileKind::File),
+            ("y", FileKind::File),
+        ]
+        .map(|(name, kind)| (OsString::from(name), kind));
+        assert_eq!(kinds, expected);
+        assert!(fs.dir_entries("/batch/c").unwrap().is_empty());
+
+        // A directory that can't be looked up fails every item in the same way
+        let results = fs.rename_batch("/missing", &renames[..2]);
+        assert!(results
+            .into_iter()
+            .all(|result| matches!(FsError::from(result.unwrap_err()), FsError::NotFound)));
+    }
+
+    #[test]
+    fn read_only_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let fs = EncryptedFileSystem::new(&vault).read_only(true);
+
+        fn snapshot(path: &Path, times: &mut Vec<(PathBuf, SystemTime, u64)>) {
+            let metadata = fs::symlink_metadata(path).unwrap();
+            times.push((path.into(), metadata.modified().unwrap(), metadata.len()));
+            if metadata.is_dir() {
+                for entry in fs::read_dir(path).unwrap() {
+                    snapshot(&entry.unwrap().path(), times);
+                }
+            }
+        }
+        fn assert_read_only<T>(result: Result<T>) {
+            let err = result.err().map(FsError::from);
+            assert!(matches!(err, Some(FsError::ReadOnly)), "{err:?}");
+        }
+        let mut before = Vec::new();
+        snapshot(&dir.path().join("d"), &mut before);
+
+        let name = OsStr::new("new");
+        let permissions = Permissions::from_mode(0o777);
+        let mut read_options = OpenOptions::new();
+        read_options.read(true);
+        let mut write_options = OpenOptions::new();
+        write_options.read(true).write(true);
+        for entry in fs.walk("/") {
+            let entry = entry.unwrap();
+            let (parent, old_name) = (
+                entry.path.parent().unwrap(),
+                entry.path.file_name().unwrap(),
+            );
+
+            // Reading still works, but nothing can be changed
+            match entry.entry.kind {
+                FileKind::File => {
+                    let mut file = fs
+                        .open_file(&entry.path, read_options.clone(), false)
+                        .unwrap();
+                    io::copy(&mut file, &mut io::sink()).unwrap();
+                    assert!(file.write_all(b"data").is_err());
+                    assert_read_only(fs.open_file(&entry.path, write_options.clone(), false));
+                    assert_read_only(fs.open_file(&entry.path, read_options.clone(), true));
+                    assert_read_only(
+                        fs.open_ciphertext(&entry.ciphertext_path, write_options.clone()),
+                    );
+                    assert_read_only(fs.unlink(parent, old_name));
+                }
+                FileKind::Directory => {
+                    fs.read_dir(&entry.path).unwrap();
+                    assert_read_only(fs.mknod(&entry.path, name, permissions.clone()));
+                    assert_read_only(fs.mkdir(&entry.path, name, permissions.clone()));
+                    assert_read_only(fs.symlink(&entry.path, name, "target"));
+                    assert_read_only(fs.rmdir(parent, old_name));
+                }
+                FileKind::Symlink => {
+                    fs.link_target(&entry.path).unwrap();
+                    assert_read_only(fs.unlink(parent, old_name));
+                }
+            }
+            assert_read_only(fs.rename(parent, old_name, "/", name));
+            assert_read_only(fs.set_permissions(&entry.path, permissions.clone()));
+            assert_read_only(fs.set_times(&entry.path, FileTimes::new().set_modified(UNIX_EPOCH)));
+            let renames = [(old_name.to_owned(), name.to_owned())];
+            assert_read_only(fs.rename_batch(parent, &renames).remove(0));
+        }
+
+        let mut after = Vec::new();
+        snapshot(&dir.path().join("d"), &mut after);
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn open_options_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = crate::VaultCreateOptions {
+            kdf_params: crate::KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path().join("vault"), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let plain_dir = dir.path().join("plain");
+        fs::create_dir(&plain_dir).unwrap();
+
+        // The kind of I/O error an operation failed with, if any
+        let kind = |report: &color_eyre::Report| match report.downcast_ref::<FsError>() {
+            Some(FsError::Io(err)) => Some(err.kind()),
+            Some(_) => None,
+            None => report.downcast_ref::<io::Error>().map(io::Error::kind),
+        };
+        let read = |path: &Path| {
+            let mut contents = Vec::new();
+            let mut options = OpenOptions::new();
+            options.read(true);
+            fs.open_file(path, options, false)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            contents
+        };
+
+        // Every combination of options works like it does on a plain file, whether or not the
+        // file exists, including for shortened names
+        for i in 0..128_u32 {
+            let flag = |bit: u32| i & (1 << bit) != 0;
+            let mut options = OpenOptions::new();
+            options
+                .read(flag(0))
+                .write(flag(1))
+                .append(flag(2))
+                .truncate(flag(3))
+                .create(flag(4))
+                .create_new(flag(5));
+            let name = match i % 3 {
+                0 => format!("{i}-{}", "x".repeat(200)),
+                _ => format!("{i}"),
+            };
+            let (path, plain_path) = (Path::new("/").join(&name), plain_dir.join(&name));
+            if flag(6) {
+                fs.mknod("/", OsStr::new(&name), Permissions::from_mode(0o644))
+                    .unwrap();
+                let mut write_options = OpenOptions::new();
+                write_options.write(true);
+                fs.open_file(&path, write_options, false)
+                    .unwrap()
+                    .write_all(b"existing")
+                    .unwrap();
+                fs::write(&plain_path, b"existing").unwrap();
+            }
+
+            let plain = fs::OpenOptions::from(&options).open(&plain_path);
+            let encrypted = fs.open_file(&path, options.clone(), false);
+            let context = format!("{options:?}, exists: {}", flag(6));
+            match (plain, encrypted) {
+                (Ok(mut plain), Ok(mut encrypted)) => {
+                    if options.read {
+                        let (mut expected, mut actual) = (Vec::new(), Vec::new());
+                        plain.read_to_end(&mut expected).unwrap();
+                        encrypted.read_to_end(&mut actual).unwrap();
+                        assert_eq!(actual, expected, "{context}");
+                        plain.seek(SeekFrom::Start(0)).unwrap();
+                        encrypted.seek(SeekFrom::Start(0)).unwrap();
+                    }
+                    if options.write || options.append {
+                        plain.write_all(b"new").unwrap();
+                        encrypted.write_all(b"new").unwrap();
+                    }
+                    assert_eq!(read(&path), fs::read(&plain_path).unwrap(), "{context}");
+                }
+                (Err(plain), Err(encrypted)) => {
+                    assert_eq!(kind(&encrypted), Some(plain.kind()), "{context}");
+                    assert_eq!(
+                        fs.dir_entry(&path).is_ok(),
+                        plain_path.exists(),
+                        "{context}"
+                    );
+                }
+                (plain, encrypted) => {
+                    panic!("{context}: {plain:?} but {:?}", encrypted.map(drop));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn header_cache_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let observer = Arc::new(CountingObserver::new());
+        let fs = EncryptedFileSystem::new(&vault)
+            .observer(observer.clone())
+            .header_cache_capacity(1);
+        let mut files = fs
+            .walk("/")
+            .map(Result::unwrap)
+            .filter(|entry| entry.entry.kind == FileKind::File)
+            .map(|entry| entry.path);
+        let (path, other_path) = (files.next().unwrap(), files.next().unwrap());
+
+        let mut read_options = OpenOptions::new();
+        read_options.read(true);
+        let read = |path: &Path| {
+            let mut contents = Vec::new();
+            fs.open_file(path, read_options.clone(), false)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            contents
+        };
+
+        // Only the first of many opens decrypts the header
+        let contents = read(&path);
+        for _ in 0..1000 {
+            fs.open_file(&path, read_options.clone(), false).unwrap();
+        }
+        assert_eq!(observer.cache(CacheKind::FileHeader), (1000, 1));
+        assert_eq!(read(&path), contents);
+
+        // Writing to the file changes its modification time, so the header is read again
+        let mut write_options = read_options.clone();
+        write_options.write(true);
+        let mut file = fs.open_file(&path, write_options, false).unwrap();
+        file.write_all_at(b"changed", 0).unwrap();
+        let times = FileTimes::new().set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        fs.set_times(&path, times).unwrap();
+        assert_eq!(&read(&path)[..7], b"changed");
+        assert_eq!(observer.cache(CacheKind::FileHeader), (1002, 2));
+
+        // Opening another file drops the header of the first one
+        read(&other_path);
+        read(&path);
+        assert_eq!(observer.cache(CacheKind::FileHeader), (1002, 4));
+
+        // Without a cache, nothing is counted
+        let fs = fs.header_cache_capacity(0);
+        fs.open_file(&path, read_options.clone(), false).unwrap();
+        assert_eq!(observer.cache(CacheKind::FileHeader), (1002, 4));
+    }
+
+    #[test]
+    fn throttle_test() {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+        let vault = Vault::open(dir.path().join("vault.cryptomator"), "password").unwrap();
+        let throttle = ThrottleHandle::new(Throttle {
+            read_bytes_per_sec: Some(1024 * 1024),
+            write_bytes_per_sec: Some(1024 * 1024),
+        });
+        let fs = EncryptedFileSystem::new(&vault).throttle(throttle.clone());
+        fs.mknod("/", OsStr::new("throttled"), Permissions::from_mode(0o644))
+            .unwrap();
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+
+        // Both directions are held to the limit, apart from a burst of a tenth of a second after
+        // being idle
+        let contents = vec![7; 256 * 1024];
+        let mut file = fs.open_file("/throttled", options.clone(), false).unwrap();
+        let start = Instant::now();
+        file.write_all(&contents).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(125));
+        let mut file = fs.open_file("/throttled", options, false).unwrap();
+        let start = Instant::now();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(125));
+        assert_eq!(read, contents);
+        assert_eq!(throttle.get().read_bytes_per_sec, Some(1024 * 1024));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn dir_entry_serde_test() {
+        let mut metadata = Metadata::new(false, 0o640);
+        metadata.len = 1234;
+        let entry = DirEntry::new(FileKind::File, 1000, metadata);
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(serde_json::from_str::<DirEntry>(&json).unwrap(), entry);
+
+        // Missing timestamps are filled in, and stay missing in the metadata
+        let mut metadata = Metadata::new(true, 0o755);
+        metadata.accessed = None;
+        let entry = DirEntry::new(FileKind::Directory, 0, metadata);
+        let json = serde_json::to_string(&entry).unwrap();
+        let entry = serde_json::from_str::<DirEntry>(&json).unwrap();
+        assert_eq!(entry.accessed, entry.modified);
+        assert_eq!(entry.metadata.accessed, None);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), b"contents").unwrap();
+        let metadata = fs::metadata(file.path()).unwrap();
+        let entry = DirEntry::from_metadata(FileKind::File, 0, metadata.clone());
+        assert_eq!(entry.metadata.len, 8);
+        assert_eq!(entry.metadata.ino, metadata.ino());
+        assert_eq!(entry.modified, metadata.modified().unwrap());
     }
 }
@@ -0,0 +1,323 @@
+use std::{
+    fs::Permissions,
+    io::{self, Read, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{bail, eyre};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tar::{Archive, Builder, EntryType, Header};
+
+use super::{check_path, EncryptedFileSystem, FileKind, ImportReport};
+use crate::{
+    storage::{FileTimes, OpenOptions},
+    Result, Vault,
+};
+
+/// Options for [`Vault::export_tar`].
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// Glob patterns for the entries to export, relative to the exported directory. A directory
+    /// that matches is exported with everything in it. Everything is exported if this is empty.
+    pub include: Vec<String>,
+    /// Glob patterns for entries to leave out, along with everything in them. These take
+    /// precedence over [`ExportOptions::include`].
+    pub exclude: Vec<String>,
+    /// List entries that can't be decrypted in the report instead of failing the export. A file
+    /// that fails partway through is kept in the archive with the rest of its contents zeroed.
+    pub best_effort: bool,
+}
+
+/// A summary of a [`Vault::export_tar`] run.
+#[derive(Debug, Clone, Default)]
+pub struct ExportReport {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// The total size of the exported file contents.
+    pub bytes: u64,
+    /// Cleartext paths of entries that could not be exported, along with the reason. Only used
+    /// with [`ExportOptions::best_effort`]; errors while reading a directory are listed under the
+    /// exported directory.
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    fn new(options: &ExportOptions) -> Result<Self> {
+        let build = |patterns: &[String]| -> Result<GlobSet> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern)?);
+            }
+            Ok(builder.build()?)
+        };
+
+        Ok(Self {
+            include: match options.include.is_empty() {
+                true => None,
+                false => Some(build(&options.include)?),
+            },
+            exclude: build(&options.exclude)?,
+        })
+    }
+
+    // Whether the relative path `path` or any of its parents match.
+    fn matches(set: &GlobSet, path: &Path) -> bool {
+        path.ancestors()
+            .take_while(|path| !path.as_os_str().is_empty())
+            .any(|path| set.is_match(path))
+    }
+
+    fn allows(&self, path: &Path) -> bool {
+        !Self::matches(&self.exclude, path)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| Self::matches(include, path))
+    }
+}
+
+// Feeds file contents to the archive. The size in the tar header is written before the contents,
+// so in best-effort mode a read error is kept for the report and the rest of the file is zeroed.
+struct ContentsReader<R> {
+    inner: R,
+    remaining: u64,
+    best_effort: bool,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> Read for ContentsReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+
+        if self.error.is_none() {
+            let result = match self.inner.read(&mut buf[..len]) {
+                Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                result => result,
+            };
+            match result {
+                Ok(n) => {
+                    self.remaining -= n as u64;
+                    return Ok(n);
+                }
+                Err(err) if !self.best_effort => return Err(err),
+                Err(err) => self.error = Some(err),
+            }
+        }
+
+        buf[..len].fill(0);
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+impl Vault {
+    /// Write the contents of the cleartext directory `subpath` to a tar archive, with paths
+    /// relative to `subpath`. File contents are decrypted as they are written, one chunk at a
+    /// time, and permissions, modification times, and symlinks are kept.
+    pub fn export_tar(
+        &self,
+        writer: impl Write,
+        subpath: impl AsRef<Path>,
+        options: &ExportOptions,
+    ) -> Result<ExportReport> {
+        let subpath = subpath.as_ref();
+        check_path(subpath)?;
+        let fs = EncryptedFileSystem::new(self);
+        let filter = Filter::new(options)?;
+        let mut builder = Builder::new(writer);
+        let mut report = ExportReport::default();
+
+        // The root directory has no entry of its own
+        if subpath.parent().is_some() && fs.dir_entry(subpath)?.kind != FileKind::Directory {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory).into());
+        }
+
+        for entry in fs.walk(subpath) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) if options.best_effort => {
+                    report.errors.push((subpath.to_path_buf(), err.to_string()));
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            // Ok to unwrap, the walk only yields paths below its root
+            let archive_path = entry.path.strip_prefix(subpath).unwrap();
+            if !filter.allows(archive_path) {
+                continue;
+            }
+
+            let mut header = Header::new_gnu();
+            header.set_mode(entry.entry.metadata.mode() & 0o7777);
+            let mtime = entry.entry.modified.duration_since(UNIX_EPOCH);
+            header.set_mtime(mtime.map_or(0, |since| since.as_secs()));
+            let result = match entry.entry.kind {
+                FileKind::Directory => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_size(0);
+                    builder
+                        .append_data(&mut header, archive_path, io::empty())
+                        .map(|_| report.directories += 1)
+                        .map_err(Into::into)
+                }
+                FileKind::File => {
+                    let mut read_options = OpenOptions::new();
+                    read_options.read(true);
+                    fs.open_ciphertext(&entry.ciphertext_path, read_options)
+                        .and_then(|file| {
+                            header.set_entry_type(EntryType::Regular);
+                            header.set_size(entry.entry.size);
+                            let mut contents = ContentsReader {
+                                inner: file,
+                                remaining: entry.entry.size,
+                                best_effort: options.best_effort,
+                                error: None,
+                            };
+                            builder.append_data(&mut header, archive_path, &mut contents)?;
+                            report.files += 1;
+                            report.bytes += entry.entry.size;
+                            match contents.error {
+                                Some(err) => Err(err.into()),
+                                None => Ok(()),
+                            }
+                        })
+                }
+                FileKind::Symlink => {
+                    fs.link_target(&entry.path)
+                        .map_err(Into::into)
+                        .and_then(|target| {
+                            header.set_entry_type(EntryType::Symlink);
+                            header.set_size(0);
+                            builder.append_link(&mut header, archive_path, target)?;
+                            report.symlinks += 1;
+                            Ok(())
+                        })
+                }
+            };
+
+            match result {
+                Ok(()) => {}
+                Err(err) if options.best_effort => {
+                    report.errors.push((entry.path, err.to_string()));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        builder.into_inner()?.flush()?;
+        Ok(report)
+    }
+
+    /// Encrypt the entries of a tar archive into the cleartext directory `dest`, which must
+    /// exist. Missing parent directories are created, and existing directories are merged with
+    /// the archive, but existing files and symlinks are never replaced.
+    pub fn import_tar(&self, reader: impl Read, dest: impl AsRef<Path>) -> Result<ImportReport> {
+        let dest = dest.as_ref();
+        check_path(dest)?;
+        let fs = EncryptedFileSystem::new(self);
+        let mut archive = Archive::new(reader);
+        let mut report = ImportReport::default();
+        let mut dirs = Vec::new();
+
+        // The root directory has no entry of its own
+        if dest.parent().is_some() && fs.dir_entry(dest)?.kind != FileKind::Directory {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory).into());
+        }
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let archive_path = entry.path()?.into_owned();
+            let mut relative_path = PathBuf::new();
+            for component in archive_path.components() {
+                match component {
+                    Component::Normal(name) => relative_path.push(name),
+                    Component::CurDir => {}
+                    _ => bail!("unsafe path in archive: {}", archive_path.display()),
+                }
+            }
+            let (Some(parent), Some(name)) = (relative_path.parent(), relative_path.file_name())
+            else {
+                continue;
+            };
+
+            // Archives don't have to list every directory before its contents
+            let mut parent_path = dest.to_path_buf();
+            for component in parent.components() {
+                parent_path.push(component);
+                if fs.dir_entry(&parent_path).is_err() {
+                    fs.mkdir(
+                        // Ok to unwrap, this is always below dest
+                        parent_path.parent().unwrap(),
+                        component.as_os_str(),
+                        Permissions::from_mode(0o755),
+                    )?;
+                }
+            }
+
+            let path = parent_path.join(name);
+            let mtime = UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+            let times = FileTimes::new().set_accessed(mtime).set_modified(mtime);
+            let permissions = Permissions::from_mode(entry.header().mode()? & 0o7777);
+            match entry.header().entry_type() {
+                EntryType::Directory => {
+                    match fs.dir_entry(&path) {
+                        Ok(existing) if existing.kind == FileKind::Directory => {}
+                        Ok(_) => return Err(io::Error::from(io::ErrorKind::AlreadyExists).into()),
+                        // Permissions are set at the end, in case they don't allow adding entries
+                        Err(_) => {
+                            fs.mkdir(&parent_path, name, Permissions::from_mode(0o700))?;
+                        }
+                    }
+                    dirs.push((path, permissions, times));
+                    report.directories += 1;
+                }
+                EntryType::Regular | EntryType::Continuous => {
+                    // Permissions are set after writing, in case the file is read-only
+                    fs.mknod(&parent_path, name, Permissions::from_mode(0o600))?;
+                    let mut write_options = OpenOptions::new();
+                    write_options.read(true).write(true);
+                    let mut file = fs.open_file(&path, write_options, false)?;
+                    report.bytes += io::copy(&mut entry, &mut file)?;
+                    file.flush()?;
+                    drop(file);
+
+                    fs.set_permissions(&path, permissions)?;
+                    fs.set_times(&path, times)?;
+                    report.files += 1;
+                }
+                EntryType::Symlink => {
+                    let target = entry.link_name()?.ok_or_else(|| {
+                        eyre!("missing symlink target: {}", archive_path.display())
+                    })?;
+                    fs.symlink(&parent_path, name, target)?;
+                    fs.set_permissions(&path, permissions)?;
+                    fs.set_times(&path, times)?;
+                    report.symlinks += 1;
+                }
+                _ => report.skipped.push(archive_path),
+            }
+        }
+
+        // Deepest directories first, so adding entries doesn't change their parents' times again
+        for (path, permissions, times) in dirs.into_iter().rev() {
+            fs.set_permissions(&path, permissions)?;
+            fs.set_times(&path, times)?;
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::Permissions,
+    future::{self, Future},
+    io::{self, Seek, SeekFrom},
+    panic,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf},
+    task::{self, JoinError, JoinHandle},
+};
+
+use super::{DirEntry, EncryptedFile, EncryptedFileSystem, EntryError, FsError};
+use crate::{storage::OpenOptions, util, Result};
+
+// The most a single read or write hands to a blocking task, so large buffers don't mean large
+// copies
+const MAX_BUF_LEN: usize = 1024 * 1024;
+
+// The unit of work between checks for cancellation, one cleartext chunk
+const STEP_LEN: usize = 32 * 1024;
+
+/// An [`EncryptedFileSystem`] for async code, which runs each operation on tokio's blocking
+/// thread pool. Operations must be awaited on a tokio runtime.
+///
+/// Like the WebDAV server, this needs a vault that lives for the rest of the program, e.g. one
+/// from [`Box::leak`].
+#[derive(Clone)]
+pub struct AsyncEncryptedFileSystem {
+    fs: EncryptedFileSystem<'static>,
+}
+
+impl AsyncEncryptedFileSystem {
+    pub fn new(fs: EncryptedFileSystem<'static>) -> Self {
+        Self { fs }
+    }
+
+    /// The wrapped file system, for anything that doesn't need to be async.
+    pub fn inner(&self) -> &EncryptedFileSystem<'static> {
+        &self.fs
+    }
+
+    // Run `f` on the blocking thread pool. Panics are passed on to the caller.
+    async fn blocking<T, E>(
+        &self,
+        f: impl FnOnce(&EncryptedFileSystem<'static>) -> Result<T, E> + Send + 'static,
+    ) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<io::Error> + Send + 'static,
+    {
+        let fs = self.fs.clone();
+        task::spawn_blocking(move || f(&fs))
+            .await
+            .unwrap_or_else(|err| Err(join_error(err).into()))
+    }
+
+    pub async fn dir_entry(&self, cleartext_path: impl Into<PathBuf>) -> Result<DirEntry, FsError> {
+        let path = cleartext_path.into();
+        self.blocking(move |fs| fs.dir_entry(path)).await
+    }
+
+    /// List a cleartext directory, failing if any entry can't be read.
+    pub async fn dir_entries(
+        &self,
+        cleartext_dir: impl Into<PathBuf>,
+    ) -> Result<BTreeMap<PathBuf, DirEntry>, FsError> {
+        let path = cleartext_dir.into();
+        self.blocking(move |fs| fs.dir_entries(path)).await
+    }
+
+    /// List a cleartext directory, skipping entries that can't be read and returning them
+    /// separately.
+    pub async fn dir_entries_lossy(
+        &self,
+        cleartext_dir: impl Into<PathBuf>,
+    ) -> Result<(BTreeMap<PathBuf, DirEntry>, Vec<EntryError>), FsError> {
+        let path = cleartext_dir.into();
+        self.blocking(move |fs| fs.dir_entries_lossy(path)).await
+    }
+
+    pub async fn link_target(
+        &self,
+        cleartext_path: impl Into<PathBuf>,
+    ) -> Result<PathBuf, FsError> {
+        let path = cleartext_path.into();
+        self.blocking(move |fs| fs.link_target(path)).await
+    }
+
+    pub async fn open_file(
+        &self,
+        cleartext_path: impl Into<PathBuf>,
+        options: OpenOptions,
+        append: bool,
+    ) -> Result<AsyncEncryptedFile> {
+        let path = cleartext_path.into();
+        let file = self
+            .blocking(move |fs| fs.open_file(path, options, append))
+            .await?;
+        Ok(AsyncEncryptedFile::new(file))
+    }
+
+    pub async fn mknod(
+        &self,
+        parent: impl Into<PathBuf>,
+        name: impl Into<OsString>,
+        permissions: Permissions,
+    ) -> Result<DirEntry> {
+        let (parent, name) = (parent.into(), name.into());
+        self.blocking(move |fs| fs.mknod(parent, &name, permissions))
+            .await
+    }
+
+    pub async fn mkdir(
+        &self,
+        parent: impl Into<PathBuf>,
+        name: impl Into<OsString>,
+        permissions: Permissions,
+    ) -> Result<DirEntry> {
+        let (parent, name) = (parent.into(), name.into());
+        self.blocking(move |fs| fs.mkdir(parent, &name, permissions))
+            .await
+    }
+
+    pub async fn symlink(
+        &self,
+        parent: impl Into<PathBuf>,
+        link_name: impl Into<OsString>,
+        target: impl Into<PathBuf>,
+    ) -> Result<DirEntry> {
+        let (parent, link_name, target) = (parent.into(), link_name.into(), target.into());
+        self.blocking(move |fs| fs.symlink(parent, &link_name, target))
+            .await
+    }
+
+    pub async fn unlink(
+        &self,
+        parent: impl Into<PathBuf>,
+        name: impl Into<OsString>,
+    ) -> Result<()> {
+        let (parent, name) = (parent.into(), name.into());
+        self.blocking(move |fs| fs.unlink(parent, &name)).await
+    }
+
+    pub async fn rmdir(&self, parent: impl Into<PathBuf>, name: impl Into<OsString>) -> Result<()> {
+        let (parent, name) = (parent.into(), name.into());
+        self.blocking(move |fs| fs.rmdir(parent, &name)).await
+    }
+
+    pub async fn rename(
+        &self,
+        old_parent: impl Into<PathBuf>,
+        old_name: impl Into<OsString>,
+        new_parent: impl Into<PathBuf>,
+        new_name: impl Into<OsString>,
+    ) -> Result<()> {
+        let (old_parent, old_name) = (old_parent.into(), old_name.into());
+        let (new_parent, new_name) = (new_parent.into(), new_name.into());
+        self.blocking(move |fs| fs.rename(old_parent, &old_name, new_parent, &new_name))
+            .await
+    }
+
+    /// Rename entries within `parent`, like [`EncryptedFileSystem::rename_batch`].
+    pub async fn rename_batch(
+        &self,
+        parent: impl Into<PathBuf>,
+        renames: Vec<(OsString, OsString)>,
+    ) -> Vec<Result<()>> {
+        let (parent, len) = (parent.into(), renames.len());
+        self.blocking(move |fs| Ok::<_, io::Error>(fs.rename_batch(parent, &renames)))
+            .await
+            .unwrap_or_else(|err| {
+                (0..len)
+                    .map(|_| Err(io::Error::new(err.kind(), err.to_string()).into()))
+                    .collect()
+            })
+    }
+}
+
+/// An [`EncryptedFile`] for async code, opened by [`AsyncEncryptedFileSystem::open_file`].
+///
+/// Reads and writes run on tokio's blocking thread pool, at a position kept by this file, so
+/// seeking past the end works like it does for a [`std::fs::File`]: reads there are empty, and
+/// writes fill the gap with zeros. Dropping the file stops an operation in flight once it's done
+/// with the chunk it's working on.
+pub struct AsyncEncryptedFile {
+    state: State,
+    // Cleartext position of the next read or write
+    pos: u64,
+    // Set from start_seek, and carried out by poll_complete
+    seek: Option<SeekFrom>,
+    cancelled: Arc<AtomicBool>,
+}
+
+type BoxedFile = Box<EncryptedFile<'static>>;
+
+enum State {
+    // None if the file was lost along with a blocking task that was cancelled
+    Idle(Option<BoxedFile>),
+    Busy(JoinHandle<(BoxedFile, Operation)>),
+}
+
+enum Operation {
+    Read(io::Result<Vec<u8>>),
+    Write(io::Result<usize>),
+    Seek(io::Result<u64>),
+    Len(io::Result<u64>),
+    Sync(io::Result<()>),
+}
+
+impl AsyncEncryptedFile {
+    fn new(file: EncryptedFile<'static>) -> Self {
+        Self {
+            state: State::Idle(Some(Box::new(file))),
+            pos: 0,
+            seek: None,
+            cancelled: Default::default(),
+        }
+    }
+
+    // Start `f` on the file in a blocking task. Only called while idle.
+    fn spawn(
+        &mut self,
+        f: impl FnOnce(&mut EncryptedFile<'static>, &AtomicBool) -> Operation + Send + 'static,
+    ) -> io::Result<()> {
+        let State::Idle(file) = &mut self.state else {
+            unreachable!("an operation is already running");
+        };
+        let mut file = file
+            .take()
+            .ok_or_else(|| io::Error::other("file was lost"))?;
+        let cancelled = self.cancelled.clone();
+        self.state = State::Busy(task::spawn_blocking(move || {
+            let op = f(&mut file, &cancelled);
+            (file, op)
+        }));
+
+        Ok(())
+    }
+
+    // Wait for the operation in flight, if any, and return what it did. Reads are left for the
+    // caller to apply, since only poll_read knows how much of the data fits.
+    fn poll_op(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Operation>>> {
+        let State::Busy(handle) = &mut self.state else {
+            return Poll::Ready(Ok(None));
+        };
+        let result = ready!(Pin::new(handle).poll(cx));
+        let (file, op) = match result {
+            Ok(done) => done,
+            Err(err) => {
+                self.state = State::Idle(None);
+                return Poll::Ready(Err(join_error(err)));
+            }
+        };
+
+        self.state = State::Idle(Some(file));
+        match op {
+            Operation::Write(Ok(n)) => self.pos += n as u64,
+            Operation::Seek(Ok(pos)) => self.pos = pos,
+            _ => {}
+        }
+        Poll::Ready(Ok(Some(op)))
+    }
+
+    // Wait for the operation in flight, if any, then run `f` and wait for it as well.
+    async fn run(
+        &mut self,
+        f: impl FnOnce(&mut EncryptedFile<'static>, &AtomicBool) -> Operation + Send + 'static,
+    ) -> io::Result<Operation> {
+        future::poll_fn(|cx| self.poll_op(cx)).await?;
+        self.spawn(f)?;
+        let op = future::poll_fn(|cx| self.poll_op(cx)).await?;
+        // Ok to unwrap, the operation was just started
+        Ok(op.unwrap())
+    }
+
+    /// Fetch the cleartext size of the file, in bytes.
+    pub async fn len(&mut self) -> io::Result<u64> {
+        match self
+            .run(|file, _| Operation::Len(io_result(file.len())))
+            .await?
+        {
+            Operation::Len(result) => result,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Like [`EncryptedFile::sync_all`].
+    pub async fn sync_all(&mut self) -> io::Result<()> {
+        match self
+            .run(|file, _| Operation::Sync(io_result(file.sync_all())))
+            .await?
+        {
+            Operation::Sync(result) => result,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Like [`EncryptedFile::sync_data`].
+    pub async fn sync_data(&mut self) -> io::Result<()> {
+        match self
+            .run(|file, _| Operation::Sync(io_result(file.sync_data())))
+            .await?
+        {
+            Operation::Sync(result) => result,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Drop for AsyncEncryptedFile {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for AsyncEncryptedFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            match ready!(this.poll_op(cx))? {
+                Some(Operation::Read(result)) => {
+                    let data = result?;
+                    let n = data.len().min(buf.remaining());
+                    buf.put_slice(&data[..n]);
+                    this.pos += n as u64;
+                    return Poll::Ready(Ok(()));
+                }
+                // Something else was in flight, such as an abandoned write
+                Some(_) => continue,
+                None => {}
+            }
+
+            let (pos, len) = (this.pos, buf.remaining().min(MAX_BUF_LEN));
+            this.spawn(move |file, cancelled| Operation::Read(read_at(file, pos, len, cancelled)))?;
+        }
+    }
+}
+
+impl AsyncWrite for AsyncEncryptedFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            match ready!(this.poll_op(cx))? {
+                Some(Operation::Write(result)) => return Poll::Ready(result),
+                Some(_) => continue,
+                None => {}
+            }
+
+            let (pos, data) = (this.pos, buf[..buf.len().min(MAX_BUF_LEN)].to_vec());
+            this.spawn(move |file, cancelled| {
+                Operation::Write(write_at(file, &data, pos, cancelled))
+            })?;
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes go straight to storage, so there's only the one in flight to wait for
+        match ready!(self.poll_op(cx))? {
+            Some(Operation::Write(Err(err))) => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for AsyncEncryptedFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        if matches!(self.state, State::Busy(_)) {
+            return Err(io::Error::other(
+                "other file operation is pending, call poll_complete before start_seek",
+            ));
+        }
+
+        self.seek = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = &mut *self;
+        loop {
+            if let Some(Operation::Seek(result)) = ready!(this.poll_op(cx))? {
+                return Poll::Ready(result);
+            }
+
+            let invalid = || io::Error::from(io::ErrorKind::InvalidInput);
+            match this.seek.take() {
+                None => return Poll::Ready(Ok(this.pos)),
+                Some(SeekFrom::Start(pos)) => this.pos = pos,
+                Some(SeekFrom::Current(offset)) => {
+                    this.pos = this.pos.checked_add_signed(offset).ok_or_else(invalid)?
+                }
+                // Only this one needs to look at the file
+                Some(SeekFrom::End(offset)) => this.spawn(move |file, _| {
+                    let pos = io_result(file.len())
+                        .and_then(|len| len.checked_add_signed(offset).ok_or_else(invalid));
+                    Operation::Seek(pos)
+                })?,
+            }
+        }
+    }
+}
+
+fn io_result<T>(result: Result<T>) -> io::Result<T> {
+    result.map_err(|report| match report.downcast::<io::Error>() {
+        Ok(err) => err,
+        Err(report) => FsError::from(report).into(),
+    })
+}
+
+// Panics are passed on, and anything else means the runtime is shutting down.
+fn join_error(err: JoinError) -> io::Error {
+    match err.try_into_panic() {
+        Ok(payload) => panic::resume_unwind(payload),
+        Err(err) => io::Error::other(err),
+    }
+}
+
+// Read up to `len` bytes at `pos`, a chunk at a time.
+fn read_at(
+    file: &mut EncryptedFile<'static>,
+    pos: u64,
+    len: usize,
+    cancelled: &AtomicBool,
+) -> io::Result<Vec<u8>> {
+    // Reads past the end are empty
+    file.seek(SeekFrom::Start(pos))?;
+
+    let mut data = vec![0; len];
+    let mut filled = 0;
+    while filled < len && !cancelled.load(Ordering::Relaxed) {
+        let end = len.min(filled + STEP_LEN);
+        match util::try_read_exact(&mut *file, &mut data[filled..end])? {
+            (true, n) => filled += n,
+            (false, n) => {
+                filled += n;
+                break;
+            }
+        }
+    }
+    data.truncate(filled);
+
+    Ok(data)
+}
+
+// Write `data` at `pos`, a chunk at a time, returning how much was written.
+fn write_at(
+    file: &mut EncryptedFile<'static>,
+    data: &[u8],
+    pos: u64,
+    cancelled: &AtomicBool,
+) -> io::Result<usize> {
+    let mut written = 0;
+    for step in data.chunks(STEP_LEN) {
+        if cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+        file.write_all_at(step, pos + written as u64)?;
+        written += step.len();
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, os::unix::fs::PermissionsExt};
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        fs::FileKind,
+        testing::{self, VaultShape},
+        Vault,
+    };
+
+    fn leaked_vault(shape: &VaultShape) -> &'static Vault {
+        let dir = Box::leak(Box::new(tempfile::tempdir().unwrap()));
+        Box::leak(Box::new(testing::create_vault(dir.path(), shape).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn async_fs_test() {
+        let shape = VaultShape {
+            files_per_dir: 3,
+            file_len: 100_000,
+            ..Default::default()
+        };
+        let fs = AsyncEncryptedFileSystem::new(EncryptedFileSystem::new(leaked_vault(&shape)));
+
+        let entries = fs.dir_entries("/").await.unwrap();
+        assert_eq!(entries.len(), 3);
+        let path = shape.file_path("/", 1);
+        assert_eq!(fs.dir_entry(&path).await.unwrap().size, 100_000);
+        assert!(matches!(
+            fs.dir_entry("/missing").await,
+            Err(FsError::NotFound)
+        ));
+
+        fs.mkdir("/", "dir", Permissions::from_mode(0o755))
+            .await
+            .unwrap();
+        fs.symlink("/dir", "link", "target").await.unwrap();
+        assert_eq!(
+            fs.link_target("/dir/link").await.unwrap(),
+            PathBuf::from("target")
+        );
+        fs.mknod("/dir", "new", Permissions::from_mode(0o644))
+            .await
+            .unwrap();
+        fs.rename("/dir", "new", "/", "moved").await.unwrap();
+        assert_eq!(fs.dir_entry("/moved").await.unwrap().kind, FileKind::File);
+        let renames = vec![("moved".into(), "renamed".into())];
+        let results = fs.rename_batch("/", renames).await;
+        assert!(results.into_iter().all(|result| result.is_ok()));
+        assert_eq!(fs.dir_entry("/renamed").await.unwrap().kind, FileKind::File);
+        fs.unlink("/dir", "link").await.unwrap();
+        fs.rmdir("/", "dir").await.unwrap();
+        assert!(fs.unlink("/", OsStr::new("..")).await.is_err());
+
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut file = fs.open_file(&path, options, false).await.unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents, shape.file_contents(1));
+        assert_eq!(file.seek(SeekFrom::End(-10)).await.unwrap(), 99_990);
+        let mut end = [0; 10];
+        file.read_exact(&mut end).await.unwrap();
+        assert_eq!(end, contents[99_990..]);
+        assert_eq!(file.len().await.unwrap(), 100_000);
+    }
+
+    #[tokio::test]
+    async fn async_file_test() {
+        let shape = VaultShape {
+            files_per_dir: 0,
+            ..Default::default()
+        };
+        let fs = AsyncEncryptedFileSystem::new(EncryptedFileSystem::new(leaked_vault(&shape)));
+        fs.mknod("/", "file", Permissions::from_mode(0o644))
+            .await
+            .unwrap();
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let mut file = fs.open_file("/file", options, false).await.unwrap();
+        file.write_all(&[1; 50_000]).await.unwrap();
+        file.flush().await.unwrap();
+
+        // Seeking past the end reads nothing, and writing there leaves zeros behind
+        file.seek(SeekFrom::Start(60_000)).await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(file.read(&mut buf).await.unwrap(), 0);
+        file.write_all(b"end").await.unwrap();
+        file.sync_all().await.unwrap();
+        assert_eq!(file.len().await.unwrap(), 60_003);
+
+        file.rewind().await.unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).await.unwrap();
+        let mut expected = vec![1; 50_000];
+        expected.resize(60_000, 0);
+        expected.extend_from_slice(b"end");
+        assert!(contents == expected);
+        assert!(file.seek(SeekFrom::Current(-100_000)).await.is_err());
+
+        // A cancelled write stops between chunks
+        file.cancelled.store(true, Ordering::Relaxed);
+        assert_eq!(file.write(&[2; 100_000]).await.unwrap(), 0);
+        file.cancelled.store(false, Ordering::Relaxed);
+        assert_eq!(file.len().await.unwrap(), 60_003);
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    is_shortened, read_dir_id, DirEntry, EncryptedFileSystem, FileKind, WhileFrozen, WriteGuard,
+};
+use crate::{
+    crypto::{DirId, FileCryptor},
+    Result,
+};
+
+impl<'v> EncryptedFileSystem<'v> {
+    // Start repairing a conflict, unless conflicts aren't being repaired. Conflicts are left alone
+    // while the file system is frozen, rather than holding up listing the directory.
+    fn repairing(&self) -> Option<WriteGuard> {
+        match self.repair_conflicts && !self.read_only {
+            true => self.freeze.begin_write(WhileFrozen::Fail).ok(),
+            false => None,
+        }
+    }
+
+    /// Rename a sync conflict copy at `ciphertext_path` in the directory `cleartext_dir` to a
+    /// valid name, returning its new cleartext path. `entries` are the entries already listed.
+    /// Conflict copies are skipped if they can't be renamed, since they couldn't be opened by any
+    /// name they're listed under, as are conflict copies of directory entries that point to the
+    /// same directory as the original.
+    pub(super) fn resolve_conflict<T>(
+        &self,
+        cleartext_dir: &Path,
+        dir_id: &DirId,
+        ciphertext_path: &Path,
+        original_name: &str,
+        entries: &BTreeMap<PathBuf, T>,
+    ) -> Result<Option<(PathBuf, DirEntry)>> {
+        // Ok to unwrap, ciphertext paths are always inside a hashed directory
+        let hashed_dir_path = ciphertext_path.parent().unwrap();
+
+        // Shortened conflict copies still have the full name of the original in name.c9s
+        let original_full_name = match is_shortened(ciphertext_path) {
+            true => self
+                .storage()
+                .read_to_string(&ciphertext_path.join("name.c9s"))?,
+            false => original_name.to_string(),
+        };
+        let original_cleartext_name = self
+            .vault
+            .cryptor()
+            .decrypt_filename(&original_full_name, dir_id)?;
+        let entry = self.ciphertext_dir_entry(ciphertext_path)?;
+        let Some(_write) = self.repairing() else {
+            return Ok(None);
+        };
+
+        if entry.kind == FileKind::Directory {
+            let original_path = hashed_dir_path.join(original_name);
+            let same_dir = match (
+                read_dir_id(self.storage(), ciphertext_path),
+                read_dir_id(self.storage(), &original_path),
+            ) {
+                (Ok(dir_id), Ok(original_dir_id)) => dir_id == original_dir_id,
+                _ => false,
+            };
+            if same_dir {
+                self.storage().remove_dir_all(ciphertext_path)?;
+                self.sync_dir(hashed_dir_path)?;
+                return Ok(None);
+            }
+        }
+
+        // Ok to unwrap, there are only so many entries in a directory
+        let cleartext_path = (1..)
+            .map(|n| cleartext_dir.join(conflict_name(&original_cleartext_name, n)))
+            .find(|path| !entries.contains_key(path))
+            .unwrap();
+
+        let new_ciphertext_path = self
+            .translator
+            .get_ciphertext_path(&cleartext_path, dir_id)?;
+        // Ok to unwrap, the path was just built from a file name
+        let full_name = self
+            .translator
+            .get_full_ciphertext_name(cleartext_path.file_name().unwrap(), dir_id)?;
+        self.move_entry(
+            ciphertext_path,
+            &new_ciphertext_path,
+            &full_name,
+            entry.kind,
+        )?;
+        let entry = self.ciphertext_dir_entry(new_ciphertext_path)?;
+
+        Ok(Some((cleartext_path, entry)))
+    }
+}
+
+// Name the nth conflict copy of a cleartext name, keeping its extension at the end.
+fn conflict_name(name: &str, n: usize) -> OsString {
+    let name = Path::new(name);
+    // Ok to unwrap, decrypted names are never empty
+    let mut conflict_name = name.file_stem().unwrap().to_os_string();
+    conflict_name.push(format!(" (Conflict {n})"));
+    if let Some(extension) = name.extension() {
+        conflict_name.push(".");
+        conflict_name.push(extension);
+    }
+
+    conflict_name
+}
@@ -0,0 +1,193 @@
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt::{self, Display},
+    io,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Report;
+
+use super::{EncryptedFileSystem, FsError};
+
+thread_local! {
+    // The ciphertext path the current operation on this thread resolved to, if any
+    static CIPHERTEXT_PATH: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// What an [`EncryptedFileSystem`] operation was doing when it failed. It's attached to reports
+/// with [`wrap_err`](color_eyre::eyre::WrapErr::wrap_err), so it can be found with
+/// [`Report::downcast_ref`], and to [`FsError`]s that don't already say what went wrong, where
+/// [`FsError::context`] finds it. Either way, the original error stays in the source chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// What was being done, such as "open" or "remove directory".
+    pub operation: &'static str,
+    /// The cleartext path, unless paths are redacted, which they are unless
+    /// [`trace_paths`](EncryptedFileSystem::trace_paths) is set.
+    pub cleartext_path: Option<PathBuf>,
+    /// The ciphertext path, if the operation got as far as finding it.
+    pub ciphertext_path: Option<PathBuf>,
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cleartext_path {
+            Some(path) => write!(f, "failed to {} {}", self.operation, path.display())?,
+            None => write!(f, "failed to {} <redacted>", self.operation)?,
+        }
+        if let Some(path) = &self.ciphertext_path {
+            write!(f, " (ciphertext path {})", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+// An error from an operation, with the context it happened in.
+#[derive(Debug, thiserror::Error)]
+#[error("{context}")]
+struct ContextError {
+    context: ErrorContext,
+    #[source]
+    source: Box<dyn Error + Send + Sync>,
+}
+
+/// Errors that can carry an [`ErrorContext`].
+pub(super) trait WithContext {
+    fn with_context(self, context: ErrorContext) -> Self;
+}
+
+impl WithContext for Report {
+    fn with_context(self, context: ErrorContext) -> Self {
+        self.wrap_err(context)
+    }
+}
+
+impl WithContext for FsError {
+    // The other variants already say what went wrong, and are matched on
+    fn with_context(self, context: ErrorContext) -> Self {
+        match self {
+            Self::Io(err) => {
+                let kind = err.kind();
+                let source = err.into();
+                Self::Io(io::Error::new(kind, ContextError { context, source }))
+            }
+            Self::Other(source) => Self::Other(Box::new(ContextError { context, source })),
+            err => err,
+        }
+    }
+}
+
+impl FsError {
+    /// What the operation that failed was doing, if it was attached to this error.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        let err: &(dyn Error + 'static) = match self {
+            Self::Io(err) => err.get_ref()?,
+            Self::Other(err) => &**err,
+            _ => return None,
+        };
+        err.downcast_ref::<ContextError>().map(|err| &err.context)
+    }
+}
+
+// Note the ciphertext path the current operation resolved to, for its error context.
+pub(super) fn record(ciphertext_path: &Path) {
+    CIPHERTEXT_PATH.with_borrow_mut(|path| *path = Some(ciphertext_path.to_path_buf()));
+}
+
+impl EncryptedFileSystem<'_> {
+    // Run the operation `f` on `cleartext_path`, attaching what it was doing to its error. Nested
+    // operations each attach their own context.
+    pub(super) fn with_context<T, E: WithContext>(
+        &self,
+        operation: &'static str,
+        cleartext_path: &Path,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let outer = CIPHERTEXT_PATH.take();
+        let result = f();
+        let ciphertext_path = CIPHERTEXT_PATH.replace(outer);
+
+        result.map_err(|err| {
+            err.with_context(ErrorContext {
+                operation,
+                cleartext_path: self.trace_paths.then(|| cleartext_path.to_path_buf()),
+                ciphertext_path,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::{storage::OpenOptions, KdfParams, Vault, VaultCreateOptions};
+
+    #[test]
+    fn context_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault).trace_paths(true);
+        fs.mknod("/", OsStr::new("file"), Permissions::from_mode(0o644))
+            .unwrap();
+        let mut options = OpenOptions::new();
+        options.read(true);
+
+        // Opening a missing file says which file, both in cleartext and ciphertext
+        let Err(err) = fs.open_file("/missing", options.clone(), false) else {
+            panic!("opened a missing file");
+        };
+        let message = format!("{err:#}");
+        assert!(message.starts_with("failed to open /missing (ciphertext path "));
+        assert!(message.contains(".c9r"), "{message}");
+        let context = err.downcast_ref::<ErrorContext>().unwrap();
+        assert_eq!(context.operation, "open");
+        assert_eq!(
+            context.cleartext_path.as_deref(),
+            Some(Path::new("/missing"))
+        );
+        assert!(context
+            .ciphertext_path
+            .as_ref()
+            .unwrap()
+            .starts_with(dir.path()));
+        let source = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+
+        // Unless paths are traced, the cleartext path is left out
+        let redacted = fs.clone().trace_paths(false);
+        let Err(err) = redacted.open_file("/missing", options, false) else {
+            panic!("opened a missing file");
+        };
+        let message = format!("{err:#}");
+        assert!(message.starts_with("failed to open <redacted> (ciphertext path "));
+        assert!(!message.contains("missing"), "{message}");
+
+        // I/O errors keep their kind
+        let err = fs.link_target("/file").unwrap_err();
+        let FsError::Io(io_err) = &err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_eq!(io_err.kind(), io::ErrorKind::InvalidData);
+        let context = err.context().unwrap();
+        assert_eq!(context.operation, "read the target of");
+        assert!(context
+            .ciphertext_path
+            .as_ref()
+            .unwrap()
+            .ends_with("symlink.c9r"));
+        assert!(err
+            .to_string()
+            .starts_with("failed to read the target of /file"));
+        assert_eq!(err.source().unwrap().to_string(), "not a link");
+
+        // Errors that already say what went wrong are left alone
+        assert!(matches!(fs.dir_entry("/missing"), Err(FsError::NotFound)));
+    }
+}
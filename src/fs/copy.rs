@@ -0,0 +1,389 @@
+use std::{
+    ffi::OsString,
+    fs::Permissions,
+    io,
+    ops::ControlFlow,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::bail;
+use uuid::Uuid;
+
+use super::{check_path, DirEntry, EncryptedFileSystem, FileKind, WalkOrder};
+use crate::{storage::OpenOptions, Result};
+
+/// A summary of an [`EncryptedFileSystem::copy_dir_recursive`] run.
+#[derive(Debug, Clone, Default)]
+pub struct CopyReport {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Cleartext source paths of entries that could not be copied, along with the reason. Errors
+    /// while reading a source directory are listed under the path being copied.
+    pub errors: Vec<(PathBuf, String)>,
+    /// Whether the run was stopped early by the progress callback.
+    pub interrupted: bool,
+}
+
+impl<'v> EncryptedFileSystem<'v> {
+    /// Copy the file or symlink at the cleartext path `src` to `dst`, keeping its permissions and
+    /// timestamps. The copy is encrypted with a new file header.
+    ///
+    /// An existing file or symlink at `dst` is only replaced if `overwrite` is set, and
+    /// directories can't be copied or replaced. The copy is written under a temporary name first,
+    /// so an interrupted copy never leaves a partially written file at `dst`.
+    pub fn copy_file(
+        &self,
+        src: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+        overwrite: bool,
+    ) -> Result<DirEntry> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        check_path(src)?;
+        check_path(dst)?;
+        let (Some(dst_parent), Some(dst_name)) = (dst.parent(), dst.file_name()) else {
+            bail!("invalid destination path: {}", dst.display());
+        };
+
+        let src_entry = self.dir_entry(src)?;
+        if src_entry.kind == FileKind::Directory {
+            return Err(io::Error::from(io::ErrorKind::IsADirectory).into());
+        }
+
+        let existing = self.dir_entry(dst).ok();
+        match existing.as_ref().map(|entry| entry.kind) {
+            Some(FileKind::Directory) => {
+                return Err(io::Error::from(io::ErrorKind::IsADirectory).into())
+            }
+            Some(_) if !overwrite => {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists).into())
+            }
+            _ => {}
+        }
+
+        let tmp_name = OsString::from(format!(".copy-{}.tmp", Uuid::new_v4().simple()));
+        if let Err(err) = self.copy_to(src, &src_entry, dst_parent, &tmp_name) {
+            let _ = self.unlink(dst_parent, &tmp_name);
+            return Err(err);
+        }
+
+        // Files replace each other in a single rename, but symlinks are directories underneath,
+        // so any other combination has to remove the existing entry first
+        if let Some(existing) = existing {
+            if existing.kind != FileKind::File || src_entry.kind != FileKind::File {
+                self.unlink(dst_parent, dst_name)?;
+            }
+        }
+        self.rename(dst_parent, &tmp_name, dst_parent, dst_name)?;
+
+        Ok(self.dir_entry(dst)?)
+    }
+
+    /// Copy the directory `src` and everything in it to the new directory `dst`, keeping
+    /// permissions and timestamps. Copied directories get new directory IDs.
+    ///
+    /// `progress` is called with each cleartext source path before it is copied, and can stop
+    /// the copy early by returning [`ControlFlow::Break`]. Entries that can't be copied are listed
+    /// in the report rather than aborting the copy.
+    pub fn copy_dir_recursive(
+        &self,
+        src: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+        mut progress: impl FnMut(&Path) -> ControlFlow<()>,
+    ) -> Result<CopyReport> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        check_path(src)?;
+        check_path(dst)?;
+        if dst.starts_with(src) {
+            bail!("can't copy {} into itself", src.display());
+        }
+        let (Some(dst_parent), Some(dst_name)) = (dst.parent(), dst.file_name()) else {
+            bail!("invalid destination path: {}", dst.display());
+        };
+
+        let src_entry = self.dir_entry(src)?;
+        if src_entry.kind != FileKind::Directory {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory).into());
+        }
+        if self.dir_entry(dst).is_ok() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+
+        // Permissions are copied after the contents, in case they don't allow adding entries
+        self.mkdir(dst_parent, dst_name, Permissions::from_mode(0o700))?;
+        let mut dirs = vec![(dst.to_path_buf(), src_entry.metadata)];
+        let mut report = CopyReport::default();
+
+        for entry in self.walk(src) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    report.errors.push((src.to_path_buf(), err.to_string()));
+                    continue;
+                }
+            };
+            if progress(&entry.path).is_break() {
+                report.interrupted = true;
+                break;
+            }
+
+            // Ok to unwrap, the walk only yields paths below its root
+            let target = dst.join(entry.path.strip_prefix(src).unwrap());
+            let result = match entry.entry.kind {
+                FileKind::Directory => {
+                    // Ok to unwrap, the target is always below dst
+                    let (parent, name) = (target.parent().unwrap(), target.file_name().unwrap());
+                    self.mkdir(parent, name, Permissions::from_mode(0o700))
+                        .map(|_| {
+                            dirs.push((target, entry.entry.metadata));
+                            report.directories += 1;
+                        })
+                }
+                FileKind::File => self
+                    .copy_file(&entry.path, &target, false)
+                    .map(|_| report.files += 1),
+                FileKind::Symlink => self
+                    .copy_file(&entry.path, &target, false)
+                    .map(|_| report.symlinks += 1),
+            };
+
+            if let Err(err) = result {
+                report.errors.push((entry.path, err.to_string()));
+            }
+        }
+
+        // Deepest directories first, so adding entries doesn't change their parents' times again
+        for (path, metadata) in dirs.iter().rev() {
+            if let Err(err) = self.copy_metadata(path, metadata) {
+                report.errors.push((path.clone(), err.to_string()));
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Move the directory `src` to `dst`. This is a rename where possible, falling back to a copy
+    /// followed by removing `src` if the rename fails because the two are on different devices.
+    pub fn move_dir(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        check_path(src)?;
+        check_path(dst)?;
+        let (Some(src_parent), Some(src_name)) = (src.parent(), src.file_name()) else {
+            bail!("invalid source path: {}", src.display());
+        };
+        let (Some(dst_parent), Some(dst_name)) = (dst.parent(), dst.file_name()) else {
+            bail!("invalid destination path: {}", dst.display());
+        };
+
+        if self.dir_entry(src)?.kind != FileKind::Directory {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory).into());
+        }
+
+        match self.rename(src_parent, src_name, dst_parent, dst_name) {
+            Err(err)
+                if err
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|err| err.kind() == io::ErrorKind::CrossesDevices) =>
+            {
+                self.move_dir_by_copy(src, dst)
+            }
+            result => result,
+        }
+    }
+
+    fn move_dir_by_copy(&self, src: &Path, dst: &Path) -> Result<()> {
+        let report = self.copy_dir_recursive(src, dst, |_| ControlFlow::Continue(()))?;
+        if let Some((path, err)) = report.errors.first() {
+            bail!(
+                "failed to copy {} entries, starting with {}: {err}",
+                report.errors.len(),
+                path.display()
+            );
+        }
+
+        // Collect the entries first, so the walk doesn't see its own removals
+        let entries = self
+            .walk(src)
+            .order(WalkOrder::PostOrder)
+            .collect::<Result<Vec<_>>>()?;
+        for entry in entries
+            .into_iter()
+            .map(|entry| entry.path)
+            .chain([src.into()])
+        {
+            // Ok to unwrap, none of these is the root directory
+            let (parent, name) = (entry.parent().unwrap(), entry.file_name().unwrap());
+            if self.dir_entry(&entry)?.kind == FileKind::Directory {
+                self.rmdir(parent, name)?;
+            } else {
+                self.unlink(parent, name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write a copy of `src` into a new entry `name` in `parent`.
+    fn copy_to(
+        &self,
+        src: &Path,
+        src_entry: &DirEntry,
+        parent: &Path,
+        name: &OsString,
+    ) -> Result<()> {
+        match src_entry.kind {
+            FileKind::File => {
+                // Permissions are copied after writing, in case the file is read-only
+                self.mknod(parent, name, Permissions::from_mode(0o600))?;
+
+                let mut read_options = OpenOptions::new();
+                read_options.read(true);
+                let mut src_file = self.open_file(src, read_options, false)?;
+                let mut write_options = OpenOptions::new();
+                write_options.read(true).write(true);
+                let mut dst_file = self.open_file(parent.join(name), write_options, false)?;
+                io::copy(&mut src_file, &mut dst_file)?;
+                dst_file.sync_all()?;
+            }
+            FileKind::Symlink => {
+                let target = self.link_target(src)?;
+                self.symlink(parent, name, target)?;
+            }
+            FileKind::Directory => unreachable!(),
+        }
+
+        self.copy_metadata(parent.join(name), &src_entry.metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, io::Read};
+
+    use super::*;
+    use crate::{KdfParams, Vault, VaultCreateOptions};
+
+    #[test]
+    fn copy_file_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let read = |path: &str| {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            let mut contents = Vec::new();
+            fs.open_file(path, options, false)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            contents
+        };
+
+        let ciphertext = |path: &str| {
+            let ciphertext_path = fs.ciphertext_path(path).unwrap();
+            if ciphertext_path.is_dir() {
+                std::fs::read(ciphertext_path.join("contents.c9r")).unwrap()
+            } else {
+                std::fs::read(ciphertext_path).unwrap()
+            }
+        };
+
+        // More than one chunk, so the copy has to re-encrypt each of them
+        let contents = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        fs.mknod("/", OsStr::new("src"), Permissions::from_mode(0o640))
+            .unwrap();
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        io::Write::write_all(
+            &mut fs.open_file("/src", options, false).unwrap(),
+            &contents,
+        )
+        .unwrap();
+        fs.mkdir("/", OsStr::new("dir"), Permissions::from_mode(0o755))
+            .unwrap();
+        fs.symlink("/", OsStr::new("link"), "src").unwrap();
+
+        let long_name = "x".repeat(200);
+        for dst in ["/dir/copy".to_string(), format!("/dir/{long_name}")] {
+            let entry = fs.copy_file("/src", &dst, false).unwrap();
+            assert_eq!(entry.kind, FileKind::File);
+            assert_eq!(entry.size, contents.len() as u64);
+            assert_eq!(read(&dst), contents);
+
+            let src_entry = fs.dir_entry("/src").unwrap();
+            assert_eq!(entry.metadata.permissions().mode() & 0o777, 0o640);
+            assert_eq!(
+                entry.metadata.modified().unwrap(),
+                src_entry.metadata.modified().unwrap()
+            );
+            assert_ne!(ciphertext("/src"), ciphertext(&dst));
+        }
+
+        let err = fs.copy_file("/link", "/dir/copy", false).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        let err = fs.copy_file("/dir", "/dir2", false).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::IsADirectory
+        );
+        let err = fs.copy_file("/src", "/dir", true).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::IsADirectory
+        );
+
+        // Symlinks are recreated, and can replace files
+        let entry = fs.copy_file("/link", "/dir/copy", true).unwrap();
+        assert_eq!(entry.kind, FileKind::Symlink);
+        assert_eq!(fs.link_target("/dir/copy").unwrap(), PathBuf::from("src"));
+        fs.copy_file("/src", "/dir/copy", true).unwrap();
+        assert_eq!(read("/dir/copy"), contents);
+
+        // No temporary files are left behind
+        let names = fs
+            .dir_entries("/dir")
+            .unwrap()
+            .into_keys()
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 2, "{names:?}");
+    }
+
+    #[test]
+    fn move_dir_by_copy_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let permissions = Permissions::from_mode(0o755);
+        fs.mkdir("/", OsStr::new("a"), permissions.clone()).unwrap();
+        fs.mkdir("/a", OsStr::new("b"), permissions.clone())
+            .unwrap();
+        fs.mknod("/a/b", OsStr::new("file"), permissions.clone())
+            .unwrap();
+        fs.symlink("/a", OsStr::new("link"), "b/file").unwrap();
+        fs.mkdir("/", OsStr::new("c"), permissions).unwrap();
+
+        fs.move_dir_by_copy(Path::new("/a"), Path::new("/c/a"))
+            .unwrap();
+        let paths = fs
+            .walk("/")
+            .map(|entry| entry.unwrap().path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths.len(), 5, "{paths:?}");
+        assert!(paths.contains(&PathBuf::from("/c/a/b/file")));
+        assert!(paths.contains(&PathBuf::from("/c/a/link")));
+        assert!(fs.dir_entry("/a").is_err());
+        assert!(fs.find_orphans().unwrap().is_empty());
+    }
+}
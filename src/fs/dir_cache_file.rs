@@ -0,0 +1,166 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use color_eyre::eyre::{bail, ensure, eyre};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{crypto::DirId, storage::Metadata, util, Result, Vault};
+
+// Identifies the file and its format, and is authenticated along with the contents
+const MAGIC: &[u8; 8] = b"C9RSDC\x00\x01";
+const NONCE_LEN: usize = 12;
+// Domain separation for the key the file is encrypted with, which is derived from the master key
+const KEY_CONTEXT: &[u8] = b"cryptomator-rs directory cache";
+
+/// What a dir.c9r looked like when its directory ID was read, to tell whether it has been
+/// replaced since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct DirFileStamp {
+    modified: SystemTime,
+    len: u64,
+    ino: u64,
+}
+
+impl DirFileStamp {
+    /// The stamp of a dir.c9r, if it's a file and the storage keeps modification times.
+    pub fn new(metadata: &Metadata) -> Option<Self> {
+        Some(Self {
+            modified: metadata.modified?,
+            len: metadata.len,
+            ino: metadata.ino,
+        })
+        .filter(|_| metadata.is_file())
+    }
+}
+
+/// A cached directory as stored in the file. The hashed directory path is relative to the vault.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct PersistedDir {
+    pub cleartext_path: PathBuf,
+    pub dir_id: String,
+    pub hashed_dir_path: PathBuf,
+    pub stamp: DirFileStamp,
+}
+
+impl PersistedDir {
+    pub fn dir_id(&self) -> Result<DirId> {
+        DirId::parse(&self.dir_id)
+    }
+}
+
+fn cipher(vault: &Vault) -> Result<Aes256Gcm> {
+    let key = util::hmac(KEY_CONTEXT, &*vault.master_key()?);
+    // Ok to unwrap, HMAC-SHA256 gives a 32 byte key
+    Ok(Aes256Gcm::new_from_slice(&key).unwrap())
+}
+
+/// Read the cached directories in `path`, which must have been written for the same vault.
+pub(super) fn load(vault: &Vault, path: &Path) -> Result<Vec<PersistedDir>> {
+    let contents = fs::read(path)?;
+    let Some(encrypted) = contents.strip_prefix(MAGIC) else {
+        bail!("not a directory cache file");
+    };
+    ensure!(
+        encrypted.len() >= NONCE_LEN,
+        "truncated directory cache file"
+    );
+
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: MAGIC,
+    };
+    let plaintext = cipher(vault)?
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| eyre!("failed to decrypt the directory cache file"))?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+/// Replace the file at `path` with the cached directories `dirs`, encrypted for `vault`.
+pub(super) fn save(vault: &Vault, path: &Path, dirs: &[PersistedDir]) -> Result<()> {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.try_fill_bytes(&mut nonce)?;
+    let payload = Payload {
+        msg: &serde_json::to_vec(dirs)?,
+        aad: MAGIC,
+    };
+    let ciphertext = cipher(vault)?
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| eyre!("failed to encrypt the directory cache file"))?;
+
+    // Write to a temporary file first, so a crash never leaves a partial cache behind
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{file_name}.{}.tmp", Uuid::new_v4().simple()));
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&nonce)?;
+        file.write_all(&ciphertext)?;
+        file.sync_all()?;
+        Ok(fs::rename(&temp_path, path)?)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KdfParams, VaultCreateOptions};
+
+    #[test]
+    fn dir_cache_file_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path().join("vault"), "password", options.clone()).unwrap();
+        let other_vault = Vault::create(dir.path().join("other"), "password", options).unwrap();
+
+        let dirs = vec![PersistedDir {
+            cleartext_path: PathBuf::from("/secret/dir"),
+            dir_id: DirId::new().as_str().to_string(),
+            hashed_dir_path: PathBuf::from("d/AB/CDEFGHIJKLMNOPQRSTUVWXYZ234567"),
+            stamp: DirFileStamp {
+                modified: SystemTime::now(),
+                len: 36,
+                ino: 1,
+            },
+        }];
+        let path = dir.path().join("dirs.cache");
+        save(&vault, &path, &dirs).unwrap();
+        assert_eq!(load(&vault, &path).unwrap(), dirs);
+
+        // Nothing is readable without the right key, and any change is noticed
+        let contents = fs::read(&path).unwrap();
+        assert!(!contents.windows(6).any(|window| window == b"secret"));
+        assert!(load(&other_vault, &path).is_err());
+        for i in [0, MAGIC.len(), contents.len() - 1] {
+            let mut corrupt = contents.clone();
+            corrupt[i] ^= 1;
+            fs::write(&path, corrupt).unwrap();
+            assert!(load(&vault, &path).is_err());
+        }
+        fs::write(&path, &contents[..MAGIC.len() + 4]).unwrap();
+        assert!(load(&vault, &path).is_err());
+    }
+}
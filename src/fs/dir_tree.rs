@@ -0,0 +1,485 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+/// Inode numbers handed out to file system frontends.
+pub type Inode = u64;
+
+/// The inode of the root directory, which matches the one FUSE expects.
+pub const ROOT_INODE: Inode = 1;
+
+#[derive(Debug, Default)]
+struct Node {
+    name: OsString,
+    // Only the root directory and removed entries have no parent
+    parent: Option<Inode>,
+    children: HashMap<OsString, Inode>,
+    // How many references the kernel holds, i.e. lookups that haven't been forgotten yet
+    lookups: u64,
+    // When the entry was last used, for frontends that can't tell when it's been forgotten
+    #[cfg_attr(not(feature = "nfs"), allow(dead_code))]
+    last_used: u64,
+}
+
+/// Cleartext paths that have been looked up, mapped to stable inode numbers. Shared by the
+/// frontends that refer to entries by inode rather than by path.
+///
+/// Entries are kept as a tree, where each node knows its parent, and its children by name. An
+/// entry can be found from its parent and name in constant time, and the path of an inode is
+/// built by walking up to the root directory.
+#[derive(Debug)]
+pub struct DirTree {
+    nodes: HashMap<Inode, Node>,
+    next_inode: Inode,
+    // Counts uses of entries, to order them by when they were last used
+    clock: u64,
+}
+
+impl DirTree {
+    pub fn new() -> Self {
+        Self {
+            nodes: HashMap::from([(ROOT_INODE, Default::default())]),
+            next_inode: ROOT_INODE + 1,
+            clock: 0,
+        }
+    }
+
+    /// The path of `inode` relative to the root directory, or `None` if the inode is unknown or
+    /// its entry was removed.
+    pub fn get_path(&self, inode: Inode) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut inode = inode;
+        while inode != ROOT_INODE {
+            let node = self.nodes.get(&inode)?;
+            names.push(&node.name);
+            inode = node.parent?;
+        }
+
+        Some(names.into_iter().rev().collect())
+    }
+
+    /// The inode of the entry `name` in the directory `parent`, if it's known.
+    pub fn lookup(&self, parent: Inode, name: impl AsRef<OsStr>) -> Option<Inode> {
+        self.nodes
+            .get(&parent)?
+            .children
+            .get(name.as_ref())
+            .copied()
+    }
+
+    /// The inode of the entry `name` in the directory `parent`, which is allocated if it's not
+    /// known yet. Returns `None` if `parent` is unknown.
+    pub fn insert(&mut self, parent: Inode, name: impl AsRef<OsStr>) -> Option<Inode> {
+        let name = name.as_ref();
+        let parent_node = self.nodes.get_mut(&parent)?;
+        if let Some(&inode) = parent_node.children.get(name) {
+            self.touch(inode);
+            return Some(inode);
+        }
+
+        self.clock += 1;
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        parent_node.children.insert(name.to_owned(), inode);
+        self.nodes.insert(
+            inode,
+            Node {
+                name: name.to_owned(),
+                parent: Some(parent),
+                last_used: self.clock,
+                ..Default::default()
+            },
+        );
+
+        Some(inode)
+    }
+
+    /// The inode of `path`, relative to the root directory, allocating inodes for it and its
+    /// ancestors as needed.
+    pub fn insert_path(&mut self, path: impl AsRef<Path>) -> Inode {
+        path.as_ref()
+            .components()
+            .fold(ROOT_INODE, |inode, component| {
+                // Ok to unwrap, every inode on the way was just found or allocated
+                self.insert(inode, component).unwrap()
+            })
+    }
+
+    /// Move the entry `old_name` in `old_parent` to `new_name` in `new_parent`, keeping its
+    /// inode. Whatever was at the destination is removed.
+    pub fn rename(
+        &mut self,
+        old_parent: Inode,
+        old_name: impl AsRef<OsStr>,
+        new_parent: Inode,
+        new_name: impl AsRef<OsStr>,
+    ) {
+        let (old_name, new_name) = (old_name.as_ref(), new_name.as_ref());
+        let Some(inode) = self.lookup(old_parent, old_name) else {
+            return;
+        };
+        // A directory can't be moved into itself, which would leave the tree with a cycle
+        if self.is_ancestor(inode, new_parent) {
+            return;
+        }
+
+        // Ok to unwrap, the entry was just found there
+        self.nodes
+            .get_mut(&old_parent)
+            .unwrap()
+            .children
+            .remove(old_name);
+        self.remove(new_parent, new_name);
+        match self.nodes.get_mut(&new_parent) {
+            Some(parent_node) => {
+                parent_node.children.insert(new_name.to_owned(), inode);
+                // Ok to unwrap, children are always in the tree
+                let node = self.nodes.get_mut(&inode).unwrap();
+                node.name = new_name.to_owned();
+                node.parent = Some(new_parent);
+            }
+            None => self.remove_subtree(inode),
+        }
+    }
+
+    /// Remove the entry `name` in `parent`, and everything below it.
+    pub fn remove(&mut self, parent: Inode, name: impl AsRef<OsStr>) {
+        let removed = self
+            .nodes
+            .get_mut(&parent)
+            .and_then(|node| node.children.remove(name.as_ref()));
+        if let Some(inode) = removed {
+            self.remove_subtree(inode);
+        }
+    }
+
+    /// Note that the kernel was told about `inode`, and holds a reference to it until it's
+    /// forgotten.
+    pub fn remember(&mut self, inode: Inode) {
+        if let Some(node) = self.nodes.get_mut(&inode) {
+            node.lookups += 1;
+        }
+    }
+
+    /// Note that the kernel dropped `count` references to `inode`. Once there are none left, the
+    /// inode is forgotten, unless entries below it are still known.
+    pub fn forget(&mut self, inode: Inode, count: u64) {
+        let Some(node) = self.nodes.get_mut(&inode) else {
+            return;
+        };
+        node.lookups = node.lookups.saturating_sub(count);
+        if inode == ROOT_INODE || node.lookups > 0 || !node.children.is_empty() {
+            return;
+        }
+
+        // Ok to unwrap, the node was just found
+        let node = self.nodes.remove(&inode).unwrap();
+        if let Some(parent_node) = node.parent.and_then(|parent| self.nodes.get_mut(&parent)) {
+            parent_node.children.remove(&node.name);
+        }
+    }
+
+    /// Note that `inode` was used, so [`DirTree::evict`] keeps it over entries that weren't.
+    pub fn touch(&mut self, inode: Inode) {
+        self.clock += 1;
+        if let Some(node) = self.nodes.get_mut(&inode) {
+            node.last_used = self.clock;
+        }
+    }
+
+    /// Once more than `max_len` entries are known, forget the least recently used ones until
+    /// three quarters of that are left, so this doesn't have to be done again for every new
+    /// entry. Entries the kernel holds references to are kept, as are directories while entries
+    /// below them are known.
+    #[cfg_attr(not(feature = "nfs"), allow(dead_code))]
+    pub fn evict(&mut self, max_len: usize) {
+        if self.nodes.len() <= max_len {
+            return;
+        }
+
+        // Only entries without children can go, so their parents become candidates as they do
+        let evictable = |inode: Inode, node: &Node| {
+            inode != ROOT_INODE && node.lookups == 0 && node.children.is_empty()
+        };
+        let mut candidates: BinaryHeap<_> = self
+            .nodes
+            .iter()
+            .filter(|(&inode, node)| evictable(inode, node))
+            .map(|(&inode, node)| Reverse((node.last_used, inode)))
+            .collect();
+        let target_len = max_len - max_len / 4;
+        while self.nodes.len() > target_len {
+            let Some(Reverse((_, inode))) = candidates.pop() else {
+                break;
+            };
+            // Ok to unwrap, candidates are only removed once
+            let node = self.nodes.remove(&inode).unwrap();
+            let Some(parent) = node.parent else {
+                continue;
+            };
+            if let Some(parent_node) = self.nodes.get_mut(&parent) {
+                parent_node.children.remove(&node.name);
+                if evictable(parent, parent_node) {
+                    candidates.push(Reverse((parent_node.last_used, parent)));
+                }
+            }
+        }
+    }
+
+    // Whether `ancestor` is `inode` or one of the directories above it.
+    fn is_ancestor(&self, ancestor: Inode, mut inode: Inode) -> bool {
+        loop {
+            if inode == ancestor {
+                return true;
+            }
+            match self.nodes.get(&inode).and_then(|node| node.parent) {
+                Some(parent) => inode = parent,
+                None => return false,
+            }
+        }
+    }
+
+    // Drop a node that was taken out of its parent, and everything below it. Inodes the kernel
+    // still refers to are kept without a path until they're forgotten.
+    fn remove_subtree(&mut self, inode: Inode) {
+        let mut removed = vec![inode];
+        while let Some(inode) = removed.pop() {
+            let Some(node) = self.nodes.get_mut(&inode) else {
+                continue;
+            };
+            node.parent = None;
+            removed.extend(node.children.drain().map(|(_, child)| child));
+            if node.lookups == 0 {
+                self.nodes.remove(&inode);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn dir_tree_test() {
+        let mut tree = DirTree::new();
+        assert_eq!(tree.get_path(ROOT_INODE), Some(PathBuf::new()));
+
+        let file = tree.insert_path("a/b/file");
+        assert_eq!(tree.insert_path("a/b/file"), file);
+        assert_eq!(tree.get_path(file), Some(PathBuf::from("a/b/file")));
+        let a = tree.insert_path("a");
+        let b = tree.insert_path("a/b");
+        assert_eq!(tree.lookup(b, "file"), Some(file));
+        assert_eq!(tree.insert(b, "file"), Some(file));
+        assert_eq!(tree.insert(1000, "file"), None);
+
+        // Renamed entries keep their inode
+        tree.rename(b, "file", a, "moved");
+        assert_eq!(tree.get_path(file), Some(PathBuf::from("a/moved")));
+        assert_eq!(tree.insert_path("a/moved"), file);
+        assert_eq!(tree.lookup(b, "file"), None);
+
+        // Directories can't be moved into themselves
+        tree.rename(ROOT_INODE, "a", b, "a");
+        assert_eq!(tree.get_path(b), Some(PathBuf::from("a/b")));
+
+        // Renaming over an entry replaces it
+        let other = tree.insert_path("other");
+        tree.rename(ROOT_INODE, "a", ROOT_INODE, "other");
+        assert_eq!(tree.get_path(other), None);
+        assert_eq!(tree.get_path(file), Some(PathBuf::from("other/moved")));
+
+        tree.remove(a, "moved");
+        assert_eq!(tree.get_path(file), None);
+        assert_ne!(tree.insert_path("other/moved"), file);
+    }
+
+    #[test]
+    fn forget_test() {
+        let mut tree = DirTree::new();
+        let file = tree.insert_path("a/file");
+        let a = tree.insert_path("a");
+        tree.remember(file);
+        tree.remember(file);
+        tree.remember(a);
+
+        // Directories are kept while anything below them is known
+        tree.forget(a, 1);
+        assert_eq!(tree.get_path(a), Some(PathBuf::from("a")));
+        tree.forget(file, 1);
+        assert_eq!(tree.get_path(file), Some(PathBuf::from("a/file")));
+        tree.forget(file, 1);
+        assert_eq!(tree.get_path(file), None);
+        assert_eq!(tree.lookup(a, "file"), None);
+        tree.forget(a, 1);
+        assert_eq!(tree.get_path(a), None);
+        assert_ne!(tree.insert_path("a"), a);
+
+        // Removed entries keep their inode until they're forgotten, but have no path
+        let file = tree.insert_path("a/file");
+        let other = tree.insert_path("a/other");
+        tree.remember(file);
+        tree.remove(ROOT_INODE, "a");
+        assert_eq!(tree.get_path(file), None);
+        assert!(tree.nodes.contains_key(&file));
+        assert!(!tree.nodes.contains_key(&other));
+        tree.forget(file, 1);
+        assert!(!tree.nodes.contains_key(&file));
+        assert_eq!(tree.nodes.len(), 1);
+        tree.forget(ROOT_INODE, 1);
+        assert_eq!(tree.get_path(ROOT_INODE), Some(PathBuf::new()));
+    }
+
+    #[test]
+    fn evict_test() {
+        let mut tree = DirTree::new();
+        let old = tree.insert_path("dir/old");
+        let remembered = tree.insert_path("remembered");
+        tree.remember(remembered);
+        let used = tree.insert_path("used");
+        let new = tree.insert_path("dir/new");
+        tree.touch(used);
+
+        // Nothing happens until there are too many entries
+        tree.evict(6);
+        assert_eq!(tree.nodes.len(), 6);
+
+        // The least recently used entries go first, and directories only once they're empty
+        tree.evict(5);
+        assert_eq!(tree.nodes.len(), 4);
+        assert_eq!(tree.get_path(old), None);
+        assert_eq!(tree.get_path(new), None);
+        assert!(tree.lookup(ROOT_INODE, "dir").is_some());
+        tree.evict(3);
+        assert_eq!(tree.lookup(ROOT_INODE, "dir"), None);
+        assert_eq!(tree.get_path(used), Some(PathBuf::from("used")));
+
+        // Entries the kernel refers to and the root directory are never evicted
+        tree.evict(0);
+        assert_eq!(tree.get_path(remembered), Some(PathBuf::from("remembered")));
+        assert_eq!(tree.get_path(ROOT_INODE), Some(PathBuf::new()));
+        assert_eq!(tree.nodes.len(), 2);
+    }
+
+    const NAMES: [&str; 3] = ["a", "b", "c"];
+
+    #[derive(Debug, Clone)]
+    enum TreeOp {
+        Insert(Vec<usize>),
+        // Directories and names are picked by index from what's known at the time
+        Rename(usize, usize, usize, usize),
+        Remove(usize, usize),
+    }
+
+    fn tree_op() -> impl Strategy<Value = TreeOp> {
+        let name = 0..NAMES.len();
+        prop_oneof![
+            prop::collection::vec(name.clone(), 1..4).prop_map(TreeOp::Insert),
+            (any::<usize>(), name.clone(), any::<usize>(), name.clone())
+                .prop_map(|(a, b, c, d)| TreeOp::Rename(a, b, c, d)),
+            (any::<usize>(), name).prop_map(|(a, b)| TreeOp::Remove(a, b)),
+        ]
+    }
+
+    // A naive DirTree that maps every known path to its inode.
+    struct Model {
+        paths: BTreeMap<PathBuf, Inode>,
+        next_inode: Inode,
+    }
+
+    impl Model {
+        fn insert_path(&mut self, path: &Path) {
+            // Inodes are allocated from the top down
+            let mut ancestors: Vec<_> = path.ancestors().collect();
+            ancestors.reverse();
+            for ancestor in ancestors {
+                if !self.paths.contains_key(ancestor) {
+                    self.paths.insert(ancestor.to_path_buf(), self.next_inode);
+                    self.next_inode += 1;
+                }
+            }
+        }
+
+        fn take_subtree(&mut self, path: &Path) -> Vec<(PathBuf, Inode)> {
+            let subtree: Vec<_> = self
+                .paths
+                .keys()
+                .filter(|p| p.starts_with(path))
+                .cloned()
+                .collect();
+            subtree
+                .into_iter()
+                .map(|p| {
+                    let inode = self.paths.remove(&p).unwrap();
+                    (p, inode)
+                })
+                .collect()
+        }
+
+        fn rename(&mut self, old_path: &Path, new_path: &Path) {
+            if !self.paths.contains_key(old_path) || new_path.starts_with(old_path) {
+                return;
+            }
+            let moved = self.take_subtree(old_path);
+            self.take_subtree(new_path);
+            for (path, inode) in moved {
+                let path = new_path.join(path.strip_prefix(old_path).unwrap());
+                self.paths.insert(path, inode);
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn dir_tree_model_test(ops in prop::collection::vec(tree_op(), 1..50)) {
+            let mut tree = DirTree::new();
+            let mut model = Model {
+                paths: BTreeMap::from([(PathBuf::new(), ROOT_INODE)]),
+                next_inode: ROOT_INODE + 1,
+            };
+
+            for op in ops {
+                let known: Vec<_> = model.paths.clone().into_iter().collect();
+                let pick = |n: usize| known[n % known.len()].clone();
+                match op {
+                    TreeOp::Insert(names) => {
+                        let path: PathBuf = names.iter().map(|&n| NAMES[n]).collect();
+                        model.insert_path(&path);
+                        prop_assert_eq!(tree.insert_path(&path), model.paths[&path]);
+                    }
+                    TreeOp::Rename(old_parent, old_name, new_parent, new_name) => {
+                        let (old_parent, old_parent_inode) = pick(old_parent);
+                        let (new_parent, new_parent_inode) = pick(new_parent);
+                        let (old_name, new_name) = (NAMES[old_name], NAMES[new_name]);
+                        model.rename(&old_parent.join(old_name), &new_parent.join(new_name));
+                        tree.rename(old_parent_inode, old_name, new_parent_inode, new_name);
+                    }
+                    TreeOp::Remove(parent, name) => {
+                        let (parent, parent_inode) = pick(parent);
+                        model.take_subtree(&parent.join(NAMES[name]));
+                        tree.remove(parent_inode, NAMES[name]);
+                    }
+                }
+
+                for inode in ROOT_INODE..model.next_inode {
+                    let path = model.paths.iter().find(|(_, &i)| i == inode).map(|(p, _)| p.clone());
+                    prop_assert_eq!(tree.get_path(inode), path);
+                }
+                for (path, &inode) in &model.paths {
+                    if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+                        prop_assert_eq!(tree.lookup(model.paths[parent], name), Some(inode));
+                    }
+                }
+                prop_assert_eq!(tree.nodes.len(), model.paths.len());
+            }
+        }
+    }
+}
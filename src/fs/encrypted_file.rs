@@ -1,206 +1,523 @@
 use std::{
-    cmp::Ordering,
     fmt::Debug,
-    fs::{File, Metadata, OpenOptions},
     io::{self, Read, Seek, SeekFrom, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use fd_lock::RwLock;
-
+use super::{
+    fingerprint::{DigestAlgorithm, Hasher},
+    freeze::{Freeze, WriteGuard},
+    header_cache::{FileIdentity, HeaderCache},
+    CacheKind, FsError, Observer, WhileFrozen,
+};
 use crate::{
     crypto::{Cryptor, FileCryptor, FileHeader},
-    util, Result,
+    storage::{
+        Metadata, OpenOptions, StdStorage, StorageFile, ThrottleHandle, ThrottledFile, VaultStorage,
+    },
+    util::{self, ChunkBufPool, PositionError},
+    Result, VaultLocked,
 };
 
-// TODO: Arithmetic for converting between cleartext/ciphertext byte positions may need to change
-// in the future if we add new cryptor types that change the length of encrypted/decrypted data.
+// TODO: Arithmetic for converting between cleartext/ciphertext byte positions (see the position
+// helpers in util) may need to change in the future if we add new cryptor types that change the
+// length of encrypted/decrypted data.
 pub struct EncryptedFile<'k> {
     cryptor: Cryptor<'k>,
-    file: RwLock<File>,
+    file: Arc<dyn StorageFile>,
+    // Path of the underlying file, for reporting lock contention
+    path: PathBuf,
+    // Current byte position in the underlying file
+    pos: u64,
+    // Cleartext position that was seeked to past the end of the file, if any. The underlying
+    // position stays at the end until a write fills the gap, like a sparse file.
+    past_end: Option<u64>,
     header: FileHeader,
+    // Number of encrypted header bytes that are not stored at the start of the underlying file;
+    // either zero, or the full encrypted header length for detached files.
+    header_offset: u64,
     append: bool,
+    observer: Option<Arc<dyn Observer>>,
+    // The freeze state of the file system the file was opened through, if any
+    freeze: Option<(Arc<Freeze>, WhileFrozen)>,
+}
+
+/// An advisory lock on a ciphertext file, released when dropped.
+struct FileLock(Arc<dyn StorageFile>);
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// Sequential access to a storage file, starting at a position that is updated as it goes.
+struct Cursor<'a> {
+    file: &'a dyn StorageFile,
+    pos: &'a mut u64,
+}
+
+impl Read for Cursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, *self.pos)?;
+        *self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Cursor<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write_at(buf, *self.pos)?;
+        *self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl<'k> EncryptedFile<'k> {
     /// Open an existing encrypted file at the provided path, using the provided options.
     pub fn open(
-        cryptor: Cryptor<'k>,
+        cryptor: impl Into<Cryptor<'k>>,
+        path: impl AsRef<Path> + Debug,
+        options: OpenOptions,
+    ) -> Result<Self> {
+        Self::open_in(&StdStorage, cryptor, path, options)
+    }
+
+    /// Like [`EncryptedFile::open`], but for a file in the given storage.
+    pub fn open_in(
+        storage: &dyn VaultStorage,
+        cryptor: impl Into<Cryptor<'k>>,
         path: impl AsRef<Path> + Debug,
         options: OpenOptions,
     ) -> Result<Self> {
-        let file = RwLock::new(options.open(path)?);
+        let cryptor = cryptor.into();
+        let file: Arc<dyn StorageFile> = storage.open(path.as_ref(), &options)?.into();
+        let header = Self::read_header(&file, path.as_ref(), cryptor)?;
+
+        Ok(Self::with_header(cryptor, file, path.as_ref(), header))
+    }
+
+    /// Like [`EncryptedFile::open_in`], but take the decrypted header from `cache` if the file
+    /// hasn't changed since it was added, and add it otherwise.
+    pub(super) fn open_cached_in(
+        storage: &dyn VaultStorage,
+        cryptor: Cryptor<'k>,
+        path: &Path,
+        options: OpenOptions,
+        cache: &HeaderCache,
+        observer: Option<&dyn Observer>,
+    ) -> Result<Self> {
+        let file: Arc<dyn StorageFile> = storage.open(path, &options)?.into();
+        // The identity comes from the open file, so it can't be swapped for another in between
+        let identity = FileIdentity::new(&file.metadata()?);
+        let cached = identity.and_then(|identity| cache.get(path, &identity));
+        if let Some(observer) = observer {
+            match cached {
+                Some(_) => observer.on_cache_hit(CacheKind::FileHeader),
+                None => observer.on_cache_miss(CacheKind::FileHeader),
+            }
+        }
 
-        // Read and decrypt the file header - error if header is missing/invalid
-        let guard = file.try_read()?;
+        let header = match cached {
+            Some(header) => header,
+            None => {
+                let header = Self::read_header(&file, path, cryptor)?;
+                if let Some(identity) = identity {
+                    cache.insert(path, identity, header.clone());
+                }
+                header
+            }
+        };
+
+        Ok(Self::with_header(cryptor, file, path, header))
+    }
+
+    // Read and decrypt the header at the start of a file, failing if it's missing or invalid.
+    fn read_header(
+        file: &Arc<dyn StorageFile>,
+        path: &Path,
+        cryptor: Cryptor<'k>,
+    ) -> Result<FileHeader> {
+        let mut pos = 0;
+        let _lock = Self::lock(file, path, false)?;
         let mut encrypted_header = vec![0; cryptor.encrypted_header_len()];
-        (&*guard).read_exact(&mut encrypted_header)?;
-        let header = cryptor.decrypt_header(encrypted_header)?;
-        drop(guard);
+        Cursor {
+            file: &**file,
+            pos: &mut pos,
+        }
+        .read_exact(&mut encrypted_header)?;
+        // A header that was tampered with is as invalid as a chunk that was
+        cryptor
+            .decrypt_header(&encrypted_header)
+            .map_err(|err| crypto_error(err).into())
+    }
+
+    // An open file with a header at its start, positioned right after the header.
+    fn with_header(
+        cryptor: Cryptor<'k>,
+        file: Arc<dyn StorageFile>,
+        path: &Path,
+        header: FileHeader,
+    ) -> Self {
+        Self {
+            cryptor,
+            file,
+            path: path.to_path_buf(),
+            pos: cryptor.encrypted_header_len() as u64,
+            past_end: None,
+            header,
+            header_offset: 0,
+            append: false,
+            observer: None,
+            freeze: None,
+        }
+    }
+
+    /// Open a ciphertext file that does not begin with an encrypted header, using an
+    /// already-decrypted header that was stored elsewhere.
+    pub fn open_detached(
+        cryptor: impl Into<Cryptor<'k>>,
+        header: FileHeader,
+        path: impl AsRef<Path> + Debug,
+        options: OpenOptions,
+    ) -> Result<Self> {
+        Self::open_detached_in(&StdStorage, cryptor, header, path, options)
+    }
+
+    /// Like [`EncryptedFile::open_detached`], but for a file in the given storage.
+    pub fn open_detached_in(
+        storage: &dyn VaultStorage,
+        cryptor: impl Into<Cryptor<'k>>,
+        header: FileHeader,
+        path: impl AsRef<Path> + Debug,
+        options: OpenOptions,
+    ) -> Result<Self> {
+        let cryptor = cryptor.into();
+        let file = storage.open(path.as_ref(), &options)?.into();
+        let header_offset = cryptor.encrypted_header_len() as u64;
 
         Ok(Self {
             cryptor,
             file,
+            path: path.as_ref().to_path_buf(),
+            pos: 0,
+            past_end: None,
             header,
+            header_offset,
             append: false,
+            observer: None,
+            freeze: None,
         })
     }
 
-    /// Create a new encrypted file in read-write mode; error if the file exists.
-    pub fn create_new(cryptor: Cryptor<'k>, path: impl AsRef<Path> + Debug) -> Result<Self> {
-        let mut file = RwLock::new(File::create_new(&path)?);
+    /// Create a new encrypted file in read-write mode; error if the file exists. The header is
+    /// synced before this returns, but anything written afterwards is only durable once
+    /// [`EncryptedFile::sync_data`] or [`EncryptedFile::sync_all`] is called.
+    pub fn create_new(
+        cryptor: impl Into<Cryptor<'k>>,
+        path: impl AsRef<Path> + Debug,
+    ) -> Result<Self> {
+        Self::create_new_in(&StdStorage, cryptor, path)
+    }
+
+    /// Like [`EncryptedFile::create_new`], but for a file in the given storage.
+    pub fn create_new_in(
+        storage: &dyn VaultStorage,
+        cryptor: impl Into<Cryptor<'k>>,
+        path: impl AsRef<Path> + Debug,
+    ) -> Result<Self> {
+        let cryptor = cryptor.into();
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        let file: Arc<dyn StorageFile> = storage.open(path.as_ref(), &options)?.into();
 
         // Write a header in the new file
-        let guard = file.try_write()?;
+        let mut pos = 0;
+        let lock = Self::lock(&file, path.as_ref(), true)?;
         let header = cryptor.new_header()?;
         let header_bytes = cryptor.encrypt_header(&header)?;
-        (&*guard).write_all(&header_bytes)?;
-        guard.sync_all()?;
-        drop(guard);
+        Cursor {
+            file: &*file,
+            pos: &mut pos,
+        }
+        .write_all(&header_bytes)?;
+        file.sync_all()?;
+        drop(lock);
 
-        // Now that the file is created, open it normally for reading/writing
-        let mut options = OpenOptions::new();
-        options.read(true).write(true);
-        Self::open(cryptor, path, options)
+        Ok(Self {
+            cryptor,
+            file,
+            path: path.as_ref().to_path_buf(),
+            pos,
+            past_end: None,
+            header,
+            header_offset: 0,
+            append: false,
+            observer: None,
+            freeze: None,
+        })
     }
 
     pub(crate) fn set_append(&mut self, append: bool) {
         self.append = append;
     }
 
-    // Fetch the current byte position in the underlying ciphertext file.
-    fn ciphertext_pos(mut file: &File) -> io::Result<u64> {
-        file.stream_position()
+    pub(crate) fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) {
+        self.observer = observer;
     }
 
-    /// Fetch the size of the underlying ciphertext file, in bytes.
-    fn ciphertext_len(file: &File) -> io::Result<u64> {
-        Ok(file.metadata()?.len())
+    pub(super) fn set_freeze(&mut self, freeze: Arc<Freeze>, while_frozen: WhileFrozen) {
+        self.freeze = Some((freeze, while_frozen));
     }
 
-    // Fetch the current cleartext byte position in the file.
-    fn cleartext_pos(cryptor: Cryptor<'k>, file: &File) -> io::Result<u64> {
-        Ok(util::get_cleartext_size(
-            cryptor,
-            Self::ciphertext_pos(file)?,
-        ))
+    // Remove all of the contents but keep the header, leaving the file positioned at the start.
+    pub(super) fn truncate(&mut self) -> io::Result<()> {
+        let _lock = Self::lock(&self.file, &self.path, true)?;
+        let start = self.cryptor.encrypted_header_len() as u64 - self.header_offset;
+        self.file.set_len(start)?;
+        self.pos = start;
+        self.past_end = None;
+        Ok(())
     }
 
-    /// Fetch the cleartext size of the file, in bytes.
-    fn cleartext_len(cryptor: Cryptor<'k>, file: &File) -> io::Result<u64> {
-        Ok(util::get_cleartext_size(
-            cryptor,
-            Self::ciphertext_len(file)?,
-        ))
+    // Start a change, which keeps the file system the file was opened through from being frozen
+    // until the guard is dropped.
+    fn begin_write(&self) -> io::Result<Option<WriteGuard>> {
+        let Some((freeze, while_frozen)) = &self.freeze else {
+            return Ok(None);
+        };
+        Ok(Some(freeze.begin_write(*while_frozen)?))
     }
 
-    /// Seek without needing &mut self.
-    fn seek_inner(cryptor: Cryptor<'k>, mut file: &File, pos: SeekFrom) -> io::Result<u64> {
-        match pos {
-            SeekFrom::Start(n) => {
-                if n == Self::cleartext_pos(cryptor, file)? {
-                    return Ok(n);
-                }
+    /// Read and write the underlying file no faster than `throttle` allows.
+    pub fn set_throttle(&mut self, throttle: Arc<ThrottleHandle>) {
+        self.file = Arc::new(ThrottledFile::new(self.file.clone(), throttle));
+    }
 
-                let chunk_number = n / (cryptor.max_chunk_len() as u64);
-                let chunk_offset = n % (cryptor.max_chunk_len() as u64);
-                let mut desired_pos = (cryptor.encrypted_header_len() as u64)
-                    + chunk_number * (cryptor.max_encrypted_chunk_len() as u64);
+    /// The decrypted header of this file.
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
 
-                // Skip chunk header if desired position is partway through a chunk
-                if chunk_offset > 0 {
-                    desired_pos += chunk_offset
-                        + (cryptor.max_encrypted_chunk_len() - cryptor.max_chunk_len()) as u64;
-                }
+    /// Encrypt the header of this file, producing the same bytes that are stored at the start of
+    /// a regular (non-detached) ciphertext file.
+    pub fn encrypted_header(&self) -> Result<Vec<u8>> {
+        self.cryptor.encrypt_header(&self.header)
+    }
 
-                // Cap the seek to the end of the ciphertext file
-                let new_ciphertext_pos = desired_pos.min(Self::ciphertext_len(file)?);
-                file.seek(SeekFrom::Start(new_ciphertext_pos))?;
-                Self::cleartext_pos(cryptor, file)
-            }
-            SeekFrom::End(n) => {
-                let cleartext_size = Self::cleartext_len(cryptor, file)?;
-                Self::seek_inner(
-                    cryptor,
-                    file,
-                    SeekFrom::Start(
-                        // Don't permit seeking past the beginning or end
-                        cleartext_size.saturating_sub(-n.max(0) as u64),
-                    ),
-                )
+    /// Hash the cleartext contents of the whole file with `algorithm`, one chunk at a time, so the
+    /// file is never held in memory. Reading starts at the beginning of the file and leaves it
+    /// positioned at the end.
+    pub fn cleartext_digest(&mut self, algorithm: DigestAlgorithm) -> Result<[u8; 32]> {
+        let mut hasher = Hasher::new(algorithm);
+        let mut chunk = vec![0; self.cryptor.max_chunk_len()];
+        self.seek(SeekFrom::Start(0))?;
+        loop {
+            match self.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => hasher.update(&chunk[..n]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
             }
-            SeekFrom::Current(n) => {
-                let cleartext_pos = Self::cleartext_pos(cryptor, file)?;
-                let new_cleartext_pos = match n.cmp(&0) {
-                    Ordering::Less => cleartext_pos.saturating_sub(-n as u64),
-                    Ordering::Equal => return Ok(cleartext_pos),
-                    Ordering::Greater => cleartext_pos
-                        .saturating_add(n as u64)
-                        .min(Self::cleartext_len(cryptor, file)?),
-                };
-
-                Self::seek_inner(cryptor, file, SeekFrom::Start(new_cleartext_pos))
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    // Lock the underlying file at `path` for the duration of an operation, so other handles that
+    // lock it don't see it half-written. If another handle holds the lock, the error wraps
+    // FsError::Locked.
+    fn lock(file: &Arc<dyn StorageFile>, path: &Path, exclusive: bool) -> io::Result<FileLock> {
+        let result = match exclusive {
+            true => file.try_lock_exclusive(),
+            false => file.try_lock_shared(),
+        };
+        match result {
+            Ok(()) => Ok(FileLock(file.clone())),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Err(FsError::Locked {
+                path: path.to_path_buf(),
             }
+            .into()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn encrypt_chunk(&self, chunk: &[u8], chunk_number: u64) -> io::Result<Vec<u8>> {
+        let encrypted = self
+            .cryptor
+            .encrypt_chunk(chunk, &self.header, chunk_number)
+            .map_err(crypto_error)?;
+        if let Some(observer) = &self.observer {
+            observer.on_chunk_encrypt(chunk.len());
+        }
+
+        Ok(encrypted)
+    }
+
+    fn decrypt_chunk(&self, chunk: &[u8], chunk_number: u64) -> io::Result<Vec<u8>> {
+        // Only the last chunk can be this short, if the file was cut off
+        if chunk.len() <= self.cryptor.chunk_overhead() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("chunk {chunk_number} is truncated"),
+            ));
+        }
+
+        let decrypted = self
+            .cryptor
+            .decrypt_chunk(chunk, &self.header, chunk_number)
+            .map_err(crypto_error)?;
+        if let Some(observer) = &self.observer {
+            observer.on_chunk_decrypt(decrypted.len());
+        }
+
+        Ok(decrypted)
+    }
+
+    fn cursor(&mut self) -> Cursor<'_> {
+        Cursor {
+            file: &*self.file,
+            pos: &mut self.pos,
+        }
+    }
+
+    // Fetch the current byte position in the ciphertext, including the header.
+    fn ciphertext_pos(&self) -> u64 {
+        self.pos + self.header_offset
+    }
+
+    /// Fetch the size of the ciphertext, including the header, in bytes.
+    fn ciphertext_len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len() + self.header_offset)
+    }
+
+    // Fetch the current cleartext byte position in the file, which may be past the end.
+    fn cleartext_pos(&self) -> io::Result<u64> {
+        if let Some(pos) = self.past_end {
+            return Ok(pos);
+        }
+
+        Ok(util::ciphertext_to_cleartext_pos(
+            self.cryptor,
+            self.ciphertext_pos(),
+        )?)
+    }
+
+    /// Fetch the cleartext size of the file, in bytes. Fails if the ciphertext is truncated or
+    /// ends in a chunk that's too short to hold anything.
+    fn cleartext_len(&self) -> io::Result<u64> {
+        util::cleartext_size(self.cryptor, self.ciphertext_len()?)
+            .map_err(|err| io::Error::new(io::ErrorKind::UnexpectedEof, err))
+    }
+
+    /// Seek without taking a lock. Like [`std::fs::File`], seeking past the end is allowed, but
+    /// seeking before the start is an error.
+    fn seek_inner(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let n = match pos {
+            SeekFrom::Start(n) => n,
+            SeekFrom::End(n) => self
+                .cleartext_len()?
+                .checked_add_signed(n)
+                .ok_or_else(invalid_seek)?,
+            SeekFrom::Current(0) => return self.cleartext_pos(),
+            SeekFrom::Current(n) => self
+                .cleartext_pos()?
+                .checked_add_signed(n)
+                .ok_or_else(invalid_seek)?,
+        };
+        if self.past_end.is_none() && n == self.cleartext_pos()? {
+            return Ok(n);
         }
+
+        let desired_pos = match util::cleartext_to_ciphertext_pos(self.cryptor, n) {
+            Ok(pos) => pos,
+            // Positions too large to encrypt are past the end of any file
+            Err(PositionError::Overflow(_)) => u64::MAX,
+            Err(err) => return Err(err.into()),
+        };
+
+        // Positions past the end are only remembered; the gap is filled by the next write
+        let ciphertext_len = self.ciphertext_len()?;
+        if desired_pos > ciphertext_len {
+            self.pos = ciphertext_len - self.header_offset;
+            self.past_end = Some(n);
+        } else {
+            self.pos = desired_pos - self.header_offset;
+            self.past_end = None;
+        }
+
+        Ok(n)
     }
 
     /// Fetch the cleartext size of the file, in bytes.
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> Result<u64> {
-        Ok(Self::cleartext_len(self.cryptor, &*self.file.try_read()?)?)
+        let _lock = Self::lock(&self.file, &self.path, false)?;
+        Ok(self.cleartext_len()?)
     }
 
     /// Fetch the metadata of the underlying ciphertext file.
     pub fn metadata(&self) -> Result<Metadata> {
-        Ok(self.file.try_read()?.metadata()?)
+        let _lock = Self::lock(&self.file, &self.path, false)?;
+        Ok(self.file.metadata()?)
     }
 
-    /// Sync ciphertext file content and metadata to disk.
+    /// Make everything written so far durable, including metadata of the ciphertext file like
+    /// its timestamps and permissions, like `fsync`.
     pub fn sync_all(&mut self) -> Result<()> {
-        Ok(self.file.try_write()?.sync_all()?)
+        let _lock = Self::lock(&self.file, &self.path, true)?;
+        Ok(self.file.sync_all()?)
     }
 
-    /// Sync ciphertext file content to disk, but maybe not metadata.
+    /// Make everything written so far durable, like `fdatasync`. This includes the length of the
+    /// ciphertext file, which the cleartext length is derived from, but not necessarily other
+    /// metadata like timestamps.
     pub fn sync_data(&mut self) -> Result<()> {
-        Ok(self.file.try_write()?.sync_data()?)
+        let _lock = Self::lock(&self.file, &self.path, true)?;
+        Ok(self.file.sync_data()?)
     }
 }
 
 impl<'k> Read for EncryptedFile<'k> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let guard = self.file.try_read()?;
+        let _lock = Self::lock(&self.file, &self.path, false)?;
 
-        if buf.is_empty() || Self::ciphertext_pos(&guard)? == Self::ciphertext_len(&guard)? {
+        // The file may have grown since seeking past the end
+        if let Some(pos) = self.past_end {
+            self.seek_inner(SeekFrom::Start(pos))?;
+        }
+        if buf.is_empty()
+            || self.past_end.is_some()
+            || self.ciphertext_pos() == self.ciphertext_len()?
+        {
             return Ok(0);
         }
 
-        let max_chunk_len = self.cryptor.max_chunk_len();
-        let current_pos = Self::cleartext_pos(self.cryptor, &guard)? as usize;
-        let chunk_number = current_pos / max_chunk_len;
-        let chunk_offset = current_pos % max_chunk_len;
-        let chunk_start = chunk_number * max_chunk_len;
+        let current_pos = self.cleartext_pos()?;
+        let chunk_number = util::chunk_of(self.cryptor, current_pos);
+        let chunk_offset = util::offset_in_chunk(self.cryptor, current_pos);
+        let chunk_start = current_pos - chunk_offset as u64;
 
         // Ensure we're positioned at a chunk boundary
         if chunk_offset > 0 {
-            Self::seek_inner(self.cryptor, &guard, SeekFrom::Start(chunk_start as u64))?;
+            self.seek_inner(SeekFrom::Start(chunk_start))?;
         }
 
-        let mut ciphertext_chunk = vec![0; self.cryptor.max_encrypted_chunk_len()];
-        if let (false, n) = util::try_read_exact(&*guard, &mut ciphertext_chunk)? {
+        let mut ciphertext_chunk =
+            ChunkBufPool::global().take(self.cryptor.max_encrypted_chunk_len());
+        if let (false, n) = util::try_read_exact(self.cursor(), &mut ciphertext_chunk)? {
             ciphertext_chunk.truncate(n)
         }
 
-        let chunk = self
-            .cryptor
-            .decrypt_chunk(ciphertext_chunk, &self.header, chunk_number)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let chunk = self.decrypt_chunk(&ciphertext_chunk, chunk_number)?;
 
         let bytes_read = (&chunk[chunk_offset..]).read(buf)?;
-        Self::seek_inner(
-            self.cryptor,
-            &guard,
-            SeekFrom::Start((current_pos + bytes_read) as u64),
-        )?;
+        self.seek_inner(SeekFrom::Start(current_pos + bytes_read as u64))?;
 
         Ok(bytes_read)
     }
@@ -208,15 +525,70 @@ impl<'k> Read for EncryptedFile<'k> {
 
 impl<'k> Seek for EncryptedFile<'k> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        let guard = self.file.try_read()?;
-        Self::seek_inner(self.cryptor, &guard, pos)
+        let _lock = Self::lock(&self.file, &self.path, false)?;
+        self.seek_inner(pos)
     }
 }
 
 impl<'k> Write for EncryptedFile<'k> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let guard = self.file.try_write()?;
+        let _write = self.begin_write()?;
+        let _lock = Self::lock(&self.file, &self.path, true)?;
+        self.write_inner(buf)
+    }
 
+    fn flush(&mut self) -> io::Result<()> {
+        // Writes go straight to the underlying file, so this only checks that it isn't locked
+        let _lock = Self::lock(&self.file, &self.path, true)?;
+        Ok(())
+    }
+}
+
+impl EncryptedFile<'_> {
+    /// Write all of `buf` at the cleartext position `offset`, leaving the file positioned after
+    /// it. If `offset` is past the end of the file, the gap is filled with zeros first. In append
+    /// mode, `buf` is appended regardless of `offset`, like `pwrite` on Linux.
+    pub fn write_all_at(&mut self, mut buf: &[u8], offset: u64) -> io::Result<()> {
+        let _write = self.begin_write()?;
+        let _lock = Self::lock(&self.file, &self.path, true)?;
+
+        if !self.append {
+            self.fill_gap(offset)?;
+            self.seek_inner(SeekFrom::Start(offset))?;
+        }
+        while !buf.is_empty() {
+            match self.write_inner(buf)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+
+        Ok(())
+    }
+
+    // Extend the file with zeros up to the cleartext position `offset`, if it's past the end.
+    // Chunks are written in order, growing the last one before appending new ones, so every
+    // chunk before `offset` exists and decrypts.
+    fn fill_gap(&mut self, offset: u64) -> io::Result<()> {
+        let len = self.cleartext_len()?;
+        if offset <= len {
+            return Ok(());
+        }
+
+        self.seek_inner(SeekFrom::Start(len))?;
+        let zeros = vec![0; self.cryptor.max_chunk_len()];
+        let mut remaining = offset - len;
+        while remaining > 0 {
+            // Ok to truncate, this is at most the max chunk length
+            let n = remaining.min(zeros.len() as u64) as usize;
+            remaining -= self.write_inner(&zeros[..n])? as u64;
+        }
+
+        Ok(())
+    }
+
+    // Write into at most one chunk at the current position, without taking a lock.
+    fn write_inner(&mut self, buf: &[u8]) -> io::Result<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
@@ -225,40 +597,40 @@ impl<'k> Write for EncryptedFile<'k> {
         if self.append {
             // If we're in append mode, we can skip to the end of the file while we hold the
             // exclusive lock, which should be safe
-            (&*guard).seek(SeekFrom::End(0))?;
+            self.pos = self.file.metadata()?.len();
+            self.past_end = None;
+        } else if let Some(pos) = self.past_end {
+            self.fill_gap(pos)?;
+            self.seek_inner(SeekFrom::Start(pos))?;
         }
 
         let max_chunk_len = self.cryptor.max_chunk_len();
-        let current_pos = Self::cleartext_pos(self.cryptor, &guard)? as usize;
-        let chunk_number = current_pos / max_chunk_len;
-        let chunk_offset = current_pos % max_chunk_len;
-        let chunk_start = chunk_number * max_chunk_len;
+        let current_pos = self.cleartext_pos()?;
+        let chunk_number = util::chunk_of(self.cryptor, current_pos);
+        let chunk_offset = util::offset_in_chunk(self.cryptor, current_pos);
+        let chunk_start = current_pos - chunk_offset as u64;
 
         // Ensure we're positioned at a chunk boundary
         if chunk_offset > 0 {
-            Self::seek_inner(self.cryptor, &guard, SeekFrom::Start(chunk_start as u64))?;
+            self.seek_inner(SeekFrom::Start(chunk_start))?;
         }
 
         let bytes_written;
-        let mut ciphertext_chunk = vec![0; self.cryptor.max_encrypted_chunk_len()];
-        let replacement_chunk = match util::try_read_exact(&*guard, &mut ciphertext_chunk)? {
+        let mut ciphertext_chunk =
+            ChunkBufPool::global().take(self.cryptor.max_encrypted_chunk_len());
+        let replacement_chunk = match util::try_read_exact(self.cursor(), &mut ciphertext_chunk)? {
             // At EOF - replacement chunk is either a max-size chunk or the entire buffer,
             // whichever is smaller
             (false, 0) => {
                 let chunk = &buf[..buf.len().min(max_chunk_len)];
                 bytes_written = chunk.len();
-                self.cryptor
-                    .encrypt_chunk(chunk, &self.header, chunk_number)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                self.encrypt_chunk(chunk, chunk_number)?
             }
             // Within last chunk - replacement chunk is the last chunk overwritten with data from
             // buffer, up to one max-size chunk
             (false, n) => {
                 ciphertext_chunk.truncate(n);
-                let mut chunk = self
-                    .cryptor
-                    .decrypt_chunk(ciphertext_chunk, &self.header, chunk_number)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let mut chunk = self.decrypt_chunk(&ciphertext_chunk, chunk_number)?;
 
                 let old_len = chunk.len();
                 chunk.resize(max_chunk_len, 0);
@@ -268,9 +640,7 @@ impl<'k> Write for EncryptedFile<'k> {
                 // Otherwise, truncate to the original chunk size.
                 chunk.truncate(old_len.max(chunk_offset + bytes_written));
 
-                self.cryptor
-                    .encrypt_chunk(chunk, &self.header, chunk_number)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                self.encrypt_chunk(&chunk, chunk_number)?
             }
             // Got a whole chunk
             _ => {
@@ -278,36 +648,227 @@ impl<'k> Write for EncryptedFile<'k> {
                 if chunk_offset == 0 && buf.len() >= max_chunk_len {
                     let chunk = &buf[..max_chunk_len];
                     bytes_written = chunk.len();
-                    self.cryptor
-                        .encrypt_chunk(chunk, &self.header, chunk_number)
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                    self.encrypt_chunk(chunk, chunk_number)?
                 // Otherwise, write data from buffer into the existing chunk
                 } else {
-                    let mut chunk = self
-                        .cryptor
-                        .decrypt_chunk(ciphertext_chunk, &self.header, chunk_number)
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    let mut chunk = self.decrypt_chunk(&ciphertext_chunk, chunk_number)?;
                     bytes_written = (&mut chunk[chunk_offset..]).write(buf)?;
 
-                    self.cryptor
-                        .encrypt_chunk(chunk, &self.header, chunk_number)
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                    self.encrypt_chunk(&chunk, chunk_number)?
                 }
             }
         };
 
-        Self::seek_inner(self.cryptor, &guard, SeekFrom::Start(chunk_start as u64))?;
-        (&*guard).write_all(&replacement_chunk)?;
-        Self::seek_inner(
-            self.cryptor,
-            &guard,
-            SeekFrom::Start((current_pos + bytes_written) as u64),
-        )?;
+        self.seek_inner(SeekFrom::Start(chunk_start))?;
+        self.cursor().write_all(&replacement_chunk)?;
+        self.seek_inner(SeekFrom::Start(current_pos + bytes_written as u64))?;
 
         Ok(bytes_written)
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.file.try_write()?.flush()
+// An error from the cryptor, which means the ciphertext is invalid unless the vault was locked.
+fn crypto_error(err: color_eyre::Report) -> io::Error {
+    match err.is::<VaultLocked>() {
+        true => FsError::VaultLocked.into(),
+        false => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+fn invalid_seek() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        crypto::{siv_ctrmac, siv_gcm},
+        storage::MemoryStorage,
+        MasterKey,
+    };
+
+    #[derive(Debug, Clone)]
+    enum FileOp {
+        Seek(u64),
+        Write(usize, u8),
+        WriteAt(u64, usize, u8),
+        Read(usize),
+    }
+
+    // Positions and lengths span a few chunks of `chunk_len` bytes, so writes start, end, and
+    // extend the file inside and across chunk boundaries
+    fn file_ops(chunk_len: usize) -> impl Strategy<Value = Vec<FileOp>> {
+        let (max_pos, max_len) = (chunk_len as u64 * 5 / 2, chunk_len * 5 / 8);
+        let op = prop_oneof![
+            (0..max_pos).prop_map(FileOp::Seek),
+            (0..max_len, any::<u8>()).prop_map(|(len, seed)| FileOp::Write(len, seed)),
+            (0..max_pos, 0..max_len, any::<u8>())
+                .prop_map(|(offset, len, seed)| FileOp::WriteAt(offset, len, seed)),
+            (0..max_len).prop_map(FileOp::Read),
+        ];
+        prop::collection::vec(op, 1..16)
+    }
+
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    // Write `buf` into the cleartext model at `pos`, filling any gap with zeros.
+    fn model_write(model: &mut Vec<u8>, pos: usize, buf: &[u8]) {
+        if model.len() < pos + buf.len() {
+            model.resize(pos + buf.len(), 0);
+        }
+        model[pos..pos + buf.len()].copy_from_slice(buf);
+    }
+
+    // Apply `ops` to a new file encrypted with each of the cryptors, checking it against a plain
+    // buffer after every step.
+    fn check_against_model(
+        cryptors: [Cryptor; 2],
+        ops: &[FileOp],
+    ) -> std::result::Result<(), TestCaseError> {
+        for cryptor in cryptors {
+            let storage = MemoryStorage::new();
+            let mut file = EncryptedFile::create_new_in(&storage, cryptor, "/file").unwrap();
+            let (mut model, mut pos) = (Vec::new(), 0);
+
+            for op in ops.iter().cloned() {
+                match op {
+                    FileOp::Seek(n) => {
+                        prop_assert_eq!(file.seek(SeekFrom::Start(n)).unwrap(), n);
+                        pos = n as usize;
+                    }
+                    FileOp::Write(len, seed) => {
+                        let buf = data(len, seed);
+                        file.write_all(&buf).unwrap();
+                        model_write(&mut model, pos, &buf);
+                        pos += len;
+                    }
+                    FileOp::WriteAt(offset, len, seed) => {
+                        let buf = data(len, seed);
+                        file.write_all_at(&buf, offset).unwrap();
+                        model_write(&mut model, offset as usize, &buf);
+                        pos = offset as usize + len;
+                    }
+                    FileOp::Read(len) => {
+                        let mut buf = Vec::new();
+                        (&mut file).take(len as u64).read_to_end(&mut buf).unwrap();
+                        let start = pos.min(model.len());
+                        let end = (pos + len).min(model.len());
+                        prop_assert_eq!(&buf, &model[start..end]);
+                        pos = pos.max(end);
+                    }
+                }
+                prop_assert_eq!(file.stream_position().unwrap(), pos as u64);
+                prop_assert_eq!(file.len().unwrap(), model.len() as u64);
+            }
+
+            let mut contents = Vec::new();
+            file.rewind().unwrap();
+            file.read_to_end(&mut contents).unwrap();
+            prop_assert!(contents == model, "contents differ from the model");
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn seek_write_read_model_test(ops in file_ops(32 * 1024)) {
+            let key = MasterKey::new([1; 32], [2; 32]).unwrap();
+            let cryptors = [
+                Cryptor::SivCtrMac(siv_ctrmac::Cryptor::new(&key)),
+                Cryptor::SivGcm(siv_gcm::Cryptor::new(&key)),
+            ];
+            check_against_model(cryptors, &ops)?;
+        }
+    }
+
+    // Fewer cases for the experimental chunk lengths, to keep the run time down
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(8))]
+
+        #[test]
+        fn small_chunk_model_test(ops in file_ops(8 * 1024)) {
+            let key = MasterKey::new([1; 32], [2; 32]).unwrap();
+            let cryptors = [
+                Cryptor::SivCtrMac(siv_ctrmac::Cryptor::with_chunk_len(&key, 8 * 1024)),
+                Cryptor::SivGcm(siv_gcm::Cryptor::with_chunk_len(&key, 8 * 1024)),
+            ];
+            check_against_model(cryptors, &ops)?;
+        }
+
+        #[test]
+        fn large_chunk_model_test(ops in file_ops(128 * 1024)) {
+            let key = MasterKey::new([1; 32], [2; 32]).unwrap();
+            let cryptors = [
+                Cryptor::SivCtrMac(siv_ctrmac::Cryptor::with_chunk_len(&key, 128 * 1024)),
+                Cryptor::SivGcm(siv_gcm::Cryptor::with_chunk_len(&key, 128 * 1024)),
+            ];
+            check_against_model(cryptors, &ops)?;
+        }
+    }
+
+    #[test]
+    fn error_kind_test() {
+        let key = MasterKey::new([1; 32], [2; 32]).unwrap();
+        let cryptor = Cryptor::SivGcm(siv_gcm::Cryptor::new(&key));
+        let storage = MemoryStorage::new();
+        EncryptedFile::create_new_in(&storage, cryptor, "/file")
+            .unwrap()
+            .write_all(&[7; 100])
+            .unwrap();
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let ciphertext = storage.open(Path::new("/file"), &options).unwrap();
+        let open = || EncryptedFile::open_in(&storage, cryptor, "/file", options.clone());
+        let open_kind = || match open() {
+            Ok(_) => panic!("opened a damaged file"),
+            Err(err) => err.downcast_ref::<io::Error>().unwrap().kind(),
+        };
+        let read_kind = || {
+            let err = open().unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+            err.kind()
+        };
+        let header_len = cryptor.encrypted_header_len() as u64;
+
+        // Chunks that don't authenticate are invalid, and so are headers
+        ciphertext.write_at(&[0], header_len + 20).unwrap();
+        assert_eq!(read_kind(), io::ErrorKind::InvalidData);
+        ciphertext.write_at(&[0], 0).unwrap();
+        assert_eq!(open_kind(), io::ErrorKind::InvalidData);
+
+        // Files that were cut off end early
+        EncryptedFile::create_new_in(&storage, cryptor, "/short")
+            .unwrap()
+            .write_all(&[7; 100])
+            .unwrap();
+        let short = storage.open(Path::new("/short"), &options).unwrap();
+        short.set_len(header_len + 10).unwrap();
+        let mut file =
+            EncryptedFile::open_in(&storage, cryptor, "/short", options.clone()).unwrap();
+        assert_eq!(
+            file.read_to_end(&mut Vec::new()).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            file.seek(SeekFrom::End(0)).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        short.set_len(10).unwrap();
+        let Err(err) = EncryptedFile::open_in(&storage, cryptor, "/short", options.clone()) else {
+            panic!("opened a file without a whole header");
+        };
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
     }
 }
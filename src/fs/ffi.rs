@@ -0,0 +1,354 @@
+//! A C API for unlocking vaults and working with their contents, enabled with the `ffi` feature.
+//! The matching header is `include/cryptomator.h`, generated from this module with cbindgen.
+//!
+//! Only an rlib is built by default, so crates that depend on this one don't get a shared library
+//! they have no use for. Build one to link C code against with:
+//!
+//! ```sh
+//! cargo rustc --lib --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! Functions that fail return `NULL` or `-1`, and [`cm_last_error`] describes what went wrong.
+//! Panics are caught and reported the same way, so they never unwind into C code.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString, OsStr},
+    fs::Permissions,
+    io::{self, Seek, SeekFrom},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    ptr, slice, vec,
+};
+
+use color_eyre::eyre::{bail, eyre};
+
+use super::{EncryptedFile, EncryptedFileSystem, FileKind, FsError};
+use crate::{storage::OpenOptions, util, Passphrase, Result, Vault};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// An unlocked vault.
+pub struct CmVault {
+    // Borrows from `vault`, so it must be dropped first
+    fs: EncryptedFileSystem<'static>,
+    vault: *mut Vault,
+}
+
+/// An open cleartext file.
+pub struct CmFile {
+    file: EncryptedFile<'static>,
+}
+
+/// An iterator over the entries of a cleartext directory.
+pub struct CmDirIter {
+    entries: vec::IntoIter<(CString, FileKind)>,
+    // Keeps the last returned name alive until the next call
+    current: Option<CString>,
+}
+
+/// The kind of a directory entry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmFileKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl From<FileKind> for CmFileKind {
+    fn from(kind: FileKind) -> Self {
+        match kind {
+            FileKind::File => Self::File,
+            FileKind::Directory => Self::Directory,
+            FileKind::Symlink => Self::Symlink,
+        }
+    }
+}
+
+fn set_last_error(message: String) {
+    // Interior NUL bytes would cut the message short, so they're replaced
+    let message = CString::new(message.replace('\0', "\\0")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+// Run `f`, turning errors and panics into `default` and recording them for `cm_last_error`.
+fn boundary<T>(default: T, f: impl FnOnce() -> Result<T>) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(err)) => {
+            set_last_error(format!("{err:#}"));
+            default
+        }
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            set_last_error(format!("panic: {message}"));
+            default
+        }
+    }
+}
+
+fn non_null<T>(ptr: *const T, name: &str) -> Result<()> {
+    if ptr.is_null() {
+        bail!("{name} is null");
+    }
+    Ok(())
+}
+
+/// # Safety
+///
+/// `ptr` must be null or a valid NUL-terminated string that outlives `'a`.
+unsafe fn c_path<'a>(ptr: *const c_char, name: &str) -> Result<&'a Path> {
+    non_null(ptr, name)?;
+    Ok(Path::new(OsStr::from_bytes(CStr::from_ptr(ptr).to_bytes())))
+}
+
+/// Unlock the vault with the config file `config_path` using `password`. Returns `NULL` on
+/// failure. The vault must be freed with [`cm_vault_free`].
+///
+/// # Safety
+///
+/// `config_path` and `password` must be null or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn cm_vault_open(
+    config_path: *const c_char,
+    password: *const c_char,
+) -> *mut CmVault {
+    boundary(ptr::null_mut(), || {
+        let config_path = c_path(config_path, "config_path")?;
+        non_null(password, "password")?;
+        let password = Passphrase::from(CStr::from_ptr(password).to_bytes().to_vec());
+
+        let vault = Box::into_raw(Box::new(Vault::open(config_path, password)?));
+        // The vault is only freed by cm_vault_free, after the file system is dropped
+        let fs = EncryptedFileSystem::new(&*vault);
+        Ok(Box::into_raw(Box::new(CmVault { fs, vault })))
+    })
+}
+
+/// Lock and free a vault, wiping its keys from memory. Does nothing if `vault` is `NULL`.
+///
+/// # Safety
+///
+/// `vault` must be null or returned by [`cm_vault_open`], and must not be used afterwards. Files
+/// opened from it must be closed first.
+#[no_mangle]
+pub unsafe extern "C" fn cm_vault_free(vault: *mut CmVault) {
+    boundary((), || {
+        if !vault.is_null() {
+            let CmVault { fs, vault } = *Box::from_raw(vault);
+            drop(fs);
+            drop(Box::from_raw(vault));
+        }
+        Ok(())
+    })
+}
+
+/// Open the cleartext file `cleartext_path`, an absolute path within the vault. `flags` are
+/// `open(2)` flags: the access mode, plus `O_CREAT`, `O_EXCL`, `O_TRUNC`, and `O_APPEND`. Returns
+/// `NULL` on failure. The file must be closed with [`cm_file_close`].
+///
+/// # Safety
+///
+/// `vault` must be null or a live vault, which must outlive the file. `cleartext_path` must be
+/// null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cm_file_open(
+    vault: *mut CmVault,
+    cleartext_path: *const c_char,
+    flags: c_int,
+) -> *mut CmFile {
+    boundary(ptr::null_mut(), || {
+        non_null(vault, "vault")?;
+        let fs = &(*vault).fs;
+        let path = c_path(cleartext_path, "cleartext_path")?;
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(io::Error::from(io::ErrorKind::IsADirectory).into());
+        };
+
+        let write = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        let create = flags & libc::O_CREAT != 0;
+        match fs.dir_entry(path) {
+            Ok(entry) if entry.kind != FileKind::File => {
+                return Err(io::Error::from(io::ErrorKind::IsADirectory).into());
+            }
+            Ok(_) if create && flags & libc::O_EXCL != 0 => {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+            }
+            // Files can't be truncated in place, so they're replaced with an empty one
+            Ok(entry) if write && flags & libc::O_TRUNC != 0 => {
+                fs.unlink(parent, name)?;
+                fs.mknod(parent, name, entry.metadata.permissions())?;
+            }
+            Ok(_) => {}
+            Err(FsError::NotFound) if create => {
+                fs.mknod(parent, name, Permissions::from_mode(0o644))?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(write);
+        let file = fs.open_file(path, options, flags & libc::O_APPEND != 0)?;
+        Ok(Box::into_raw(Box::new(CmFile { file })))
+    })
+}
+
+/// Read up to `len` bytes at `offset` into `buf`. Returns the number of bytes read, which is only
+/// less than `len` at the end of the file, or -1 on failure.
+///
+/// # Safety
+///
+/// `file` must be null or a live file that isn't used by another thread during the call. `buf`
+/// must be null or valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cm_file_read_at(
+    file: *mut CmFile,
+    buf: *mut u8,
+    len: usize,
+    offset: u64,
+) -> i64 {
+    boundary(-1, || {
+        non_null(file, "file")?;
+        non_null(buf, "buf")?;
+        let file = &mut (*file).file;
+        let buf = slice::from_raw_parts_mut(buf, len);
+
+        file.seek(SeekFrom::Start(offset))?;
+        let (_, n) = util::try_read_exact(file, buf)?;
+        Ok(i64::try_from(n)?)
+    })
+}
+
+/// Write `len` bytes from `buf` at `offset`, or at the end of the file if it was opened with
+/// `O_APPEND`. A gap left by writing past the end of the file reads as zeros. Returns the number
+/// of bytes written, which is always `len`, or -1 on failure.
+///
+/// # Safety
+///
+/// `file` must be null or a live file that isn't used by another thread during the call. `buf`
+/// must be null or valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cm_file_write_at(
+    file: *mut CmFile,
+    buf: *const u8,
+    len: usize,
+    offset: u64,
+) -> i64 {
+    boundary(-1, || {
+        non_null(file, "file")?;
+        non_null(buf, "buf")?;
+        let file = &mut (*file).file;
+        let buf = slice::from_raw_parts(buf, len);
+
+        file.write_all_at(buf, offset)?;
+        Ok(i64::try_from(len)?)
+    })
+}
+
+/// Close a file. Does nothing if `file` is `NULL`.
+///
+/// # Safety
+///
+/// `file` must be null or returned by [`cm_file_open`], and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cm_file_close(file: *mut CmFile) {
+    boundary((), || {
+        if !file.is_null() {
+            drop(Box::from_raw(file));
+        }
+        Ok(())
+    })
+}
+
+/// List the cleartext directory `cleartext_path`, an absolute path within the vault. Returns
+/// `NULL` on failure. The iterator must be freed with [`cm_dir_free`].
+///
+/// # Safety
+///
+/// `vault` must be null or a live vault. `cleartext_path` must be null or a valid NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn cm_dir_list(
+    vault: *mut CmVault,
+    cleartext_path: *const c_char,
+) -> *mut CmDirIter {
+    boundary(ptr::null_mut(), || {
+        non_null(vault, "vault")?;
+        let path = c_path(cleartext_path, "cleartext_path")?;
+        let entries = (*vault)
+            .fs
+            .dir_entries(path)?
+            .into_iter()
+            .map(|(path, entry)| {
+                // Ok to unwrap, listed entries always have a name
+                let name = path.file_name().unwrap().as_bytes().to_vec();
+                let name = CString::new(name).map_err(|_| eyre!("entry name contains NUL"))?;
+                Ok((name, entry.kind))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Box::into_raw(Box::new(CmDirIter {
+            entries: entries.into_iter(),
+            current: None,
+        })))
+    })
+}
+
+/// Return the name of the next entry, storing its kind in `kind` unless that is `NULL`. Returns
+/// `NULL` once there are no more entries. The name is valid until the next call with the same
+/// iterator.
+///
+/// # Safety
+///
+/// `iter` must be null or a live iterator. `kind` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn cm_dir_next(iter: *mut CmDirIter, kind: *mut CmFileKind) -> *const c_char {
+    boundary(ptr::null(), || {
+        non_null(iter, "iter")?;
+        let iter = &mut *iter;
+        iter.current = None;
+        let Some((name, entry_kind)) = iter.entries.next() else {
+            return Ok(ptr::null());
+        };
+
+        if !kind.is_null() {
+            *kind = entry_kind.into();
+        }
+        Ok(iter.current.insert(name).as_ptr())
+    })
+}
+
+/// Free a directory iterator. Does nothing if `iter` is `NULL`.
+///
+/// # Safety
+///
+/// `iter` must be null or returned by [`cm_dir_list`], and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cm_dir_free(iter: *mut CmDirIter) {
+    boundary((), || {
+        if !iter.is_null() {
+            drop(Box::from_raw(iter));
+        }
+        Ok(())
+    })
+}
+
+/// Describe the last error on the calling thread, or return `NULL` if nothing has failed yet.
+/// The message is valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn cm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
@@ -0,0 +1,159 @@
+use std::{io, path::Path, time::SystemTime};
+
+use color_eyre::eyre::bail;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{check_path, EncryptedFileSystem, FileKind};
+use crate::{storage::OpenOptions, Result};
+
+/// A compact identity of a file's ciphertext, from [`EncryptedFileSystem::file_fingerprint`].
+///
+/// The encrypted header is chosen at random when a file is created, so an unchanged fingerprint
+/// means the file hasn't been replaced, and its length and modification time catch changes in
+/// place. The reverse doesn't hold: the fingerprint changes whenever the file is rewritten, even
+/// with identical contents, so use [`EncryptedFile::cleartext_digest`] to compare contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// SHA-256 of the encrypted file header.
+    pub header_digest: [u8; 32],
+    /// The length of the ciphertext, including the header.
+    pub len: u64,
+    /// The modification time of the ciphertext, if the storage keeps one.
+    pub modified: Option<SystemTime>,
+}
+
+/// A hash function for [`EncryptedFile::cleartext_digest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+// The running state of a DigestAlgorithm.
+pub(super) enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            DigestAlgorithm::Blake3 => Self::Blake3(Default::default()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            Self::Sha256(hasher) => hasher.finalize().into(),
+            Self::Blake3(hasher) => hasher.finalize().into(),
+        }
+    }
+}
+
+impl EncryptedFileSystem<'_> {
+    /// Fetch the [`Fingerprint`] of a file, to tell whether it changed since an earlier call
+    /// without decrypting its contents. Only the file header is read.
+    pub fn file_fingerprint(&self, cleartext_path: impl AsRef<Path>) -> Result<Fingerprint> {
+        let cleartext_path = cleartext_path.as_ref();
+        check_path(cleartext_path)?;
+        match self.dir_entry(cleartext_path)?.kind {
+            FileKind::File => {}
+            FileKind::Directory => return Err(io::Error::from(io::ErrorKind::IsADirectory).into()),
+            FileKind::Symlink => bail!("not a file: {}", cleartext_path.display()),
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let file = self.open_file(cleartext_path, options, false)?;
+        let metadata = file.metadata()?;
+
+        Ok(Fingerprint {
+            header_digest: Sha256::digest(file.encrypted_header()?).into(),
+            len: metadata.len,
+            modified: metadata.modified,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt, time::Duration};
+
+    use super::*;
+    use crate::{fs::FsError, storage::FileTimes, KdfParams, Vault, VaultCreateOptions};
+
+    #[test]
+    fn fingerprint_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let write = |name: &str, contents: &[u8]| {
+            fs.mknod("/", name.as_ref(), Permissions::from_mode(0o644))
+                .unwrap();
+            let mut file = fs
+                .open_file(format!("/{name}"), options.clone(), false)
+                .unwrap();
+            file.write_all_at(contents, 0).unwrap();
+        };
+        let contents = vec![7; 100_000];
+        write("a", &contents);
+        write("b", &contents);
+
+        // Reading leaves the fingerprint alone, but changing the ciphertext changes it
+        let fingerprint = fs.file_fingerprint("/a").unwrap();
+        let mut file = fs.open_file("/a", options.clone(), false).unwrap();
+        let digest = file.cleartext_digest(DigestAlgorithm::Sha256).unwrap();
+        assert_eq!(digest, <[u8; 32]>::from(Sha256::digest(&contents)));
+        assert_eq!(
+            file.cleartext_digest(DigestAlgorithm::Blake3).unwrap(),
+            *blake3::hash(&contents).as_bytes()
+        );
+        assert_eq!(fs.file_fingerprint("/a").unwrap(), fingerprint);
+        let json = serde_json::to_string(&fingerprint).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Fingerprint>(&json).unwrap(),
+            fingerprint
+        );
+
+        let times = FileTimes::new().set_modified(SystemTime::now() + Duration::from_secs(60));
+        fs.set_times("/a", times).unwrap();
+        assert_ne!(fs.file_fingerprint("/a").unwrap(), fingerprint);
+
+        // Files with the same contents have different fingerprints, but the same digest
+        let other = fs.file_fingerprint("/b").unwrap();
+        assert_ne!(other.header_digest, fingerprint.header_digest);
+        assert_eq!(other.len, fingerprint.len);
+        let mut file = fs.open_file("/b", options, false).unwrap();
+        assert_eq!(
+            file.cleartext_digest(DigestAlgorithm::Sha256).unwrap(),
+            digest
+        );
+
+        fs.mkdir("/", "dir".as_ref(), Permissions::from_mode(0o755))
+            .unwrap();
+        let err = fs.file_fingerprint("/dir").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::IsADirectory)
+        );
+        let err = fs.file_fingerprint("/missing").unwrap_err();
+        assert!(matches!(FsError::from(err), FsError::NotFound));
+    }
+}
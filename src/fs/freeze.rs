@@ -0,0 +1,229 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use super::{EncryptedFileSystem, FsError};
+
+/// What a change to an [`EncryptedFileSystem`] does while the file system is
+/// [frozen](EncryptedFileSystem::freeze).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhileFrozen {
+    /// Wait until the file system is thawed.
+    #[default]
+    Block,
+    /// Fail with [`FsError::Frozen`], which FUSE reports as `EAGAIN`.
+    Fail,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Number of freeze guards alive
+    freezes: usize,
+    // Number of changes in progress
+    writers: usize,
+}
+
+/// The freeze state shared by an [`EncryptedFileSystem`], its clones, and the files it opens.
+#[derive(Debug, Default)]
+pub(super) struct Freeze {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Freeze {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.changed
+            .wait(state)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Start a change, which keeps the file system from being frozen until the guard is dropped.
+    pub fn begin_write(self: &Arc<Self>, while_frozen: WhileFrozen) -> Result<WriteGuard, FsError> {
+        let mut state = self.state();
+        while state.freezes > 0 {
+            match while_frozen {
+                WhileFrozen::Block => state = self.wait(state),
+                WhileFrozen::Fail => return Err(FsError::Frozen),
+            }
+        }
+        state.writers += 1;
+
+        Ok(WriteGuard(self.clone()))
+    }
+
+    // Block new changes, then wait for the ones in progress to finish.
+    fn freeze(self: &Arc<Self>) -> FreezeGuard {
+        let mut state = self.state();
+        state.freezes += 1;
+        while state.writers > 0 {
+            state = self.wait(state);
+        }
+
+        FreezeGuard(self.clone())
+    }
+}
+
+/// A change in progress, which the file system can't be frozen during.
+#[derive(Debug)]
+pub(super) struct WriteGuard(Arc<Freeze>);
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        self.0.state().writers -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+/// Keeps an [`EncryptedFileSystem`] frozen until it's dropped. See
+/// [`EncryptedFileSystem::freeze`].
+#[derive(Debug)]
+#[must_use = "the file system is thawed as soon as the guard is dropped"]
+pub struct FreezeGuard(Arc<Freeze>);
+
+impl Drop for FreezeGuard {
+    fn drop(&mut self) {
+        self.0.state().freezes -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+impl EncryptedFileSystem<'_> {
+    /// Stop the vault from changing, so a copy of its ciphertext taken while the returned guard
+    /// is held is consistent, e.g. for a backup of a mounted vault. This waits for changes in
+    /// progress to finish; changes made afterwards, by this file system, its clones, or the files
+    /// they opened, block or fail as set with [`while_frozen`](Self::while_frozen) until the guard
+    /// is dropped. Writes go straight to the storage, so there's nothing to flush.
+    ///
+    /// Other processes using the vault aren't stopped. Making a change on the thread that holds
+    /// the guard blocks forever, unless changes are set to fail.
+    pub fn freeze(&self) -> FreezeGuard {
+        self.freeze.freeze()
+    }
+
+    pub(super) fn begin_write(&self) -> Result<WriteGuard, FsError> {
+        self.freeze.begin_write(self.while_frozen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        ffi::OsStr,
+        fs::{self, Permissions},
+        io::Read,
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{storage::OpenOptions, KdfParams, Vault, VaultCreateOptions};
+
+    // The contents of every file below `dir`.
+    fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => files.extend(snapshot(&path)),
+                false => {
+                    let contents = fs::read(&path).unwrap();
+                    files.insert(path, contents);
+                }
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn freeze_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let permissions = Permissions::from_mode(0o644);
+        fs.mknod("/", OsStr::new("file"), permissions.clone())
+            .unwrap();
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let failing = fs.clone().while_frozen(WhileFrozen::Fail);
+        let mut failing_file = failing.open_file("/file", options.clone(), false).unwrap();
+
+        let stop = AtomicBool::new(false);
+        let writes = AtomicUsize::new(0);
+        let wait_for_writes = |count: usize| {
+            let start = Instant::now();
+            while writes.load(Ordering::SeqCst) < count {
+                assert!(start.elapsed() < Duration::from_secs(30), "writer is stuck");
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        thread::scope(|scope| {
+            // Keep writing to the file and creating new ones until told to stop
+            scope.spawn(|| {
+                let mut file = fs.open_file("/file", options.clone(), false).unwrap();
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    file.write_all_at(&[i as u8; 1000], i as u64 * 1000)
+                        .unwrap();
+                    if i % 10 == 0 {
+                        let name = format!("new {i}");
+                        fs.mknod("/", OsStr::new(&name), permissions.clone())
+                            .unwrap();
+                    }
+                    i += 1;
+                    writes.store(i, Ordering::SeqCst);
+                }
+            });
+
+            // Nothing in the vault changes while it's frozen, but it can still be read
+            wait_for_writes(20);
+            let guard = fs.freeze();
+            let frozen = snapshot(dir.path());
+            thread::sleep(Duration::from_millis(200));
+            assert_eq!(snapshot(dir.path()), frozen);
+            fs.open_file("/file", OpenOptions::new().read(true).clone(), false)
+                .unwrap()
+                .read_to_end(&mut Vec::new())
+                .unwrap();
+
+            // Unless set to block, changes fail instead
+            let err = failing.mknod("/", OsStr::new("failed"), permissions.clone());
+            assert!(matches!(FsError::from(err.unwrap_err()), FsError::Frozen));
+            let err = failing_file.write_all_at(b"failed", 0).unwrap_err();
+            assert!(matches!(FsError::from(err), FsError::Frozen));
+            let Err(err) = failing.open_file("/file", options.clone(), false) else {
+                panic!("opened a frozen file for writing");
+            };
+            assert!(matches!(FsError::from(err), FsError::Frozen));
+            assert_eq!(snapshot(dir.path()), frozen);
+
+            // Writers carry on once it's thawed
+            let count = writes.load(Ordering::SeqCst);
+            drop(guard);
+            wait_for_writes(count + 20);
+            stop.store(true, Ordering::SeqCst);
+        });
+
+        // Every write made it in one piece
+        let mut contents = Vec::new();
+        fs.open_file("/file", options, false)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        let count = writes.load(Ordering::SeqCst);
+        assert_eq!(contents.len(), count * 1000);
+        for (i, chunk) in contents.chunks(1000).enumerate() {
+            assert_eq!(chunk, [i as u8; 1000]);
+        }
+        failing_file.write_all_at(b"thawed", 0).unwrap();
+    }
+}
@@ -1,30 +1,44 @@
 use std::{
-    collections::BTreeMap,
-    fs::{FileTimes, OpenOptions, Permissions},
-    io::{Seek, SeekFrom, Write},
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    ffi::OsString,
+    fs::Permissions,
+    io::{self, Seek, SeekFrom, Write},
     os::unix::{
         ffi::OsStrExt,
         fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
     },
-    path::PathBuf,
-    sync::atomic::{AtomicU64, Ordering},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use fuser::{FileAttr, FileType, Filesystem, FUSE_ROOT_ID};
+use zeroize::Zeroize;
 
 use crate::{
-    fs::{DirEntry, EncryptedFile, EncryptedFileSystem, FileKind},
-    util,
+    fs::{
+        dir_tree::{DirTree, Inode, ROOT_INODE},
+        DirEntry, EncryptedFile, EncryptedFileSystem, FileKind, FsError, Observer, WhileFrozen,
+    },
+    storage::{FileTimes, OpenOptions, ThrottleHandle},
+    util::{self, ChunkBufPool},
 };
 
-mod dir_tree;
-
-use dir_tree::DirTree;
-
 const TTL: Duration = Duration::from_secs(1);
 
-type Inode = u64;
+// What asking for an extended attribute that isn't there fails with. Vaults can't store them, so
+// that's every one.
+#[cfg(target_os = "macos")]
+const ENOATTR: libc::c_int = libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+const ENOATTR: libc::c_int = libc::ENODATA;
+
+// The shared inode tree must agree with FUSE about the root directory
+const _: () = assert!(ROOT_INODE == FUSE_ROOT_ID);
 
 impl From<FileKind> for FileType {
     fn from(kind: FileKind) -> Self {
@@ -36,6 +50,18 @@ impl From<FileKind> for FileType {
     }
 }
 
+// A snapshot of a directory's entries taken by opendir, which readdir offsets index into. Only
+// names and kinds are kept, since readdir doesn't need anything else.
+struct OpenDir {
+    path: PathBuf,
+    entries: Vec<(OsString, FileKind)>,
+    // The full entries found by the listing, which the lookups that usually follow readdir are
+    // answered from instead of going back to storage. Each one is only served once, and not after
+    // the TTL, or once something in the directory changes.
+    listed: HashMap<OsString, DirEntry>,
+    listed_at: Instant,
+}
+
 #[derive(Debug)]
 struct Attributes {
     inode: Inode,
@@ -49,14 +75,17 @@ impl From<Attributes> for FileAttr {
             size: value.entry.size,
             // TOD: Cryptomator sets this to 0, should we do the same?
             blocks: value.entry.metadata.blocks(),
-            atime: value.entry.metadata.accessed().unwrap_or(UNIX_EPOCH),
-            mtime: value.entry.metadata.modified().unwrap_or(UNIX_EPOCH),
-            // TODO: Is created() the right one to use here? Looks like Cryptomator does this also
-            ctime: value.entry.metadata.created().unwrap_or(UNIX_EPOCH),
-            crtime: value.entry.metadata.created().unwrap_or(UNIX_EPOCH),
+            atime: value.entry.accessed,
+            mtime: value.entry.modified,
+            ctime: value.entry.changed,
+            crtime: value.entry.created,
             kind: value.entry.kind.into(),
             perm: value.entry.metadata.permissions().mode() as u16,
-            nlink: value.entry.metadata.nlink() as u32,
+            // Ciphertext link counts mean nothing in the cleartext: a directory is a ciphertext
+            // directory holding a dir.c9r file, and storage-side dedupe may hard link ciphertext
+            // files. Counting subdirectories would take a listing per lookup, so directories
+            // report 1 as well, which tools like find take to mean the count is unknown.
+            nlink: 1,
             uid: value.entry.metadata.uid(),
             gid: value.entry.metadata.gid(),
             rdev: value.entry.metadata.rdev() as u32,
@@ -66,15 +95,217 @@ impl From<Attributes> for FileAttr {
     }
 }
 
+/// How a [`FuseFileSystem`] reports the owners and permissions of entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PermissionsPolicy {
+    /// Report the owner and permissions of the ciphertext files and directories.
+    #[default]
+    Passthrough,
+    /// Report the owner and permissions of the ciphertext, without the permission bits set in
+    /// `umask`.
+    MaskedPassthrough { umask: u16 },
+    /// Report the same owner and permissions for every file and directory, like the `uid=`,
+    /// `gid=`, and `umask=` options of many network file systems. Symlinks are always reported
+    /// as `0777`. Changing permissions does nothing if `ignore_chmod` is set, and fails with
+    /// `EPERM` otherwise.
+    Static {
+        file_mode: u16,
+        dir_mode: u16,
+        uid: u32,
+        gid: u32,
+        ignore_chmod: bool,
+    },
+}
+
+impl PermissionsPolicy {
+    fn apply(self, attr: &mut FileAttr) {
+        match self {
+            Self::Passthrough => {}
+            Self::MaskedPassthrough { umask } => attr.perm &= !(umask & 0o7777),
+            Self::Static {
+                file_mode,
+                dir_mode,
+                uid,
+                gid,
+                ..
+            } => {
+                attr.perm = match attr.kind {
+                    FileType::Directory => dir_mode,
+                    FileType::Symlink => 0o777,
+                    _ => file_mode,
+                } & 0o7777;
+                attr.uid = uid;
+                attr.gid = gid;
+            }
+        }
+    }
+}
+
+// Whether the user `uid` in the group `gid` may access an entry with the attributes `attr` as
+// asked by `mask`, like access(2). Supplementary groups aren't known, so they aren't checked.
+fn access_allowed(attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> bool {
+    let perm = i32::from(attr.perm);
+    let wanted = mask & (libc::R_OK | libc::W_OK | libc::X_OK);
+    if uid == 0 {
+        // Root may read and write anything, but only execute what someone can
+        return wanted & libc::X_OK == 0 || attr.kind == FileType::Directory || perm & 0o111 != 0;
+    }
+
+    let allowed = if uid == attr.uid {
+        perm >> 6
+    } else if gid == attr.gid {
+        perm >> 3
+    } else {
+        perm
+    } & 0o7;
+    wanted & !allowed == 0
+}
+
+// Replies that can report an error.
+trait ReplyError {
+    fn error(self, errno: libc::c_int);
+}
+
+macro_rules! impl_reply_error {
+    ($($reply:ty),*) => {
+        $(impl ReplyError for $reply {
+            fn error(self, errno: libc::c_int) {
+                <$reply>::error(self, errno)
+            }
+        })*
+    };
+}
+
+impl_reply_error!(
+    fuser::ReplyEntry,
+    fuser::ReplyAttr,
+    fuser::ReplyData,
+    fuser::ReplyEmpty,
+    fuser::ReplyOpen,
+    fuser::ReplyWrite,
+    fuser::ReplyDirectory,
+    fuser::ReplyCreate,
+    fuser::ReplyXattr
+);
+
+#[cfg(target_os = "macos")]
+impl_reply_error!(fuser::ReplyXTimes);
+
+// Whether `name` is one of the files Finder leaves everywhere: `.DS_Store` for folder view
+// settings, and `._` AppleDouble files for extended attributes and resource forks.
+fn is_apple_double(name: &std::ffi::OsStr) -> bool {
+    name == ".DS_Store" || name.as_bytes().starts_with(b"._")
+}
+
+// The errno for a failed operation, logging anything unexpected.
+fn errno(err: &FsError) -> libc::c_int {
+    match err {
+        FsError::NotFound => libc::ENOENT,
+        FsError::NotADirectory => libc::ENOTDIR,
+        FsError::InvalidPath => libc::EINVAL,
+        FsError::ReadOnly => libc::EROFS,
+        FsError::Frozen => libc::EAGAIN,
+        FsError::VaultLocked => libc::EACCES,
+        FsError::Io(err) if err.kind() == io::ErrorKind::AlreadyExists => libc::EEXIST,
+        FsError::Locked { path } => {
+            tracing::warn!(path = %path.display(), "file is locked by another process");
+            libc::EWOULDBLOCK
+        }
+        err => {
+            tracing::error!("{}", error_chain(err));
+            libc::EIO
+        }
+    }
+}
+
+// An error followed by its sources, which say what the operation was doing and where it failed.
+fn error_chain(err: &FsError) -> String {
+    let mut chain = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        chain.push_str(": ");
+        chain.push_str(&err.to_string());
+        source = err.source();
+    }
+    chain
+}
+
+// The options to open a file with for the kernel open flags `flags`. O_NOFOLLOW and O_DIRECTORY
+// are about the cleartext entry, so they're checked against it instead.
+fn open_options(flags: i32) -> OpenOptions {
+    // We'll support opening files in either read mode or read-write mode
+    let write = flags & libc::O_ACCMODE != libc::O_RDONLY;
+    let mut options = OpenOptions::new();
+    options
+        .read(true)
+        .write(write)
+        .truncate(write && flags & libc::O_TRUNC != 0)
+        .custom_flags(flags & (libc::O_SYNC | libc::O_DSYNC));
+    options
+}
+
+// The start of a request for `len` bytes at `offset`, if the whole range fits in a file offset.
+fn request_offset(offset: i64, len: u64) -> Option<u64> {
+    let start = u64::try_from(offset).ok()?;
+    start
+        .checked_add(len)
+        .filter(|&end| end <= i64::MAX as u64)?;
+    Some(start)
+}
+
+/// A FUSE request being handled, which is reported to the observer when dropped.
+struct Op {
+    name: &'static str,
+    // Only set if there's an observer, so nothing is timed otherwise
+    observer: Option<(Arc<dyn Observer>, Instant)>,
+    errno: Option<libc::c_int>,
+}
+
+impl Op {
+    fn new(name: &'static str, observer: Option<&Arc<dyn Observer>>) -> Self {
+        Self {
+            name,
+            observer: observer.map(|observer| (observer.clone(), Instant::now())),
+            errno: None,
+        }
+    }
+
+    // Reply with an error, and report the request as failed.
+    fn error(&mut self, reply: impl ReplyError, errno: libc::c_int) {
+        self.errno = Some(errno);
+        reply.error(errno);
+    }
+}
+
+impl Drop for Op {
+    fn drop(&mut self) {
+        if let Some((observer, start)) = &self.observer {
+            let result = self.errno.map_or(Ok(()), Err);
+            observer.on_fuse_op(self.name, start.elapsed(), result);
+        }
+    }
+}
+
 pub struct FuseFileSystem<'v> {
     fs: EncryptedFileSystem<'v>,
     tree: DirTree,
-    open_dirs: BTreeMap<u64, BTreeMap<PathBuf, DirEntry>>,
+    open_dirs: BTreeMap<u64, OpenDir>,
     open_files: BTreeMap<u64, EncryptedFile<'v>>,
     next_handle: AtomicU64,
+    emulate_hard_links: bool,
+    ignore_unsupported_times: bool,
+    permissions: PermissionsPolicy,
+    hide_apple_double: bool,
+    lock_on_unmount: bool,
+    max_read: u32,
+    // Reused by every read request, and wiped after each one
+    read_buf: Vec<u8>,
 }
 
 impl<'v> FuseFileSystem<'v> {
+    /// The default for [`FuseFileSystem::max_read`].
+    pub const DEFAULT_MAX_READ: u32 = 1024 * 1024;
+
     pub fn new(fs: EncryptedFileSystem<'v>) -> Self {
         Self {
             fs,
@@ -82,6 +313,394 @@ impl<'v> FuseFileSystem<'v> {
             open_dirs: Default::default(),
             open_files: Default::default(),
             next_handle: AtomicU64::new(0),
+            emulate_hard_links: false,
+            ignore_unsupported_times: false,
+            permissions: PermissionsPolicy::Passthrough,
+            hide_apple_double: false,
+            lock_on_unmount: false,
+            max_read: Self::DEFAULT_MAX_READ,
+            read_buf: Vec::new(),
+        }
+    }
+
+    /// Set the most data a single read request can return, so a huge requested size can't make
+    /// us allocate a buffer that big. Larger requests get a short read, so this shouldn't be
+    /// lower than the `max_read` mount option.
+    pub fn max_read(mut self, max_read: u32) -> Self {
+        self.max_read = max_read;
+        self
+    }
+
+    /// Make hard links by copying the file instead, for tools that only need the contents to
+    /// show up under the new name. Off by default, in which case `link` fails with `EPERM`, since
+    /// the vault format has no way to store hard links.
+    ///
+    /// A copy is a separate file: later changes to one name aren't seen through the other, and
+    /// both keep reporting a link count of 1.
+    pub fn emulate_hard_links(mut self, emulate: bool) -> Self {
+        self.emulate_hard_links = emulate;
+        self
+    }
+
+    /// Make setting a creation time succeed without doing anything where the storage can't change
+    /// it, which with [`StdStorage`](crate::storage::StdStorage) is everywhere but macOS. Off by
+    /// default, in which case `setattr` fails with `ENOTSUP` and changes nothing.
+    pub fn ignore_unsupported_times(mut self, ignore: bool) -> Self {
+        self.ignore_unsupported_times = ignore;
+        self
+    }
+
+    /// Set how the owners and permissions of entries are reported, which is
+    /// [`PermissionsPolicy::Passthrough`] by default.
+    pub fn permissions(mut self, policy: PermissionsPolicy) -> Self {
+        self.permissions = policy;
+        self
+    }
+
+    /// Hide `.DS_Store` and `._` AppleDouble files, like the `noappledouble` option of macFUSE,
+    /// so Finder doesn't fill the vault with them. Existing ones are left out of listings and
+    /// lookups, and creating new ones fails with `EACCES`. Off by default.
+    pub fn hide_apple_double(mut self, hide: bool) -> Self {
+        self.hide_apple_double = hide;
+        self
+    }
+
+    /// [Lock](crate::Vault::lock) the vault once the file system is unmounted, so its master key
+    /// doesn't stay in memory for as long as the vault does. Off by default.
+    pub fn lock_on_unmount(mut self, lock: bool) -> Self {
+        self.lock_on_unmount = lock;
+        self
+    }
+
+    /// Read and write the contents of files no faster than `throttle` allows, like
+    /// [`EncryptedFileSystem::throttle`]. The limits can be changed through the handle while
+    /// mounted.
+    pub fn throttle(mut self, throttle: Arc<ThrottleHandle>) -> Self {
+        self.fs = self.fs.throttle(throttle);
+        self
+    }
+
+    /// Set what changes do while the file system is [frozen](EncryptedFileSystem::freeze), like
+    /// [`EncryptedFileSystem::while_frozen`]. Blocking holds up every request behind the change
+    /// until the file system is thawed, while failing makes it return `EAGAIN`.
+    pub fn while_frozen(mut self, while_frozen: WhileFrozen) -> Self {
+        self.fs = self.fs.while_frozen(while_frozen);
+        self
+    }
+
+    fn op(&self, name: &'static str) -> Op {
+        Op::new(name, self.fs.observer.as_ref())
+    }
+
+    // The attributes to report for `entry`, which has the inode `inode`.
+    fn attr(&self, inode: Inode, entry: DirEntry) -> FileAttr {
+        let mut attr = FileAttr::from(Attributes { inode, entry });
+        self.permissions.apply(&mut attr);
+        attr
+    }
+
+    // The backup and creation times of the entry with the inode `ino`, which macOS asks for
+    // separately from the other attributes, or an errno if they can't be read.
+    #[cfg(target_os = "macos")]
+    fn xtimes(&self, ino: u64) -> Result<(SystemTime, SystemTime), libc::c_int> {
+        // Backup times aren't stored in vaults
+        let attr = self.inode_attr(ino)?;
+        Ok((SystemTime::UNIX_EPOCH, attr.crtime))
+    }
+
+    // Check that an entry may be created with the name `name`, or return an errno if not.
+    fn check_new_name(&self, name: &std::ffi::OsStr) -> Result<(), libc::c_int> {
+        match self.hide_apple_double && is_apple_double(name) {
+            true => Err(libc::EACCES),
+            false => Ok(()),
+        }
+    }
+
+    // The attributes of the entry with the inode `ino`, or an errno if they can't be read.
+    fn inode_attr(&self, ino: u64) -> Result<FileAttr, libc::c_int> {
+        let Some(path) = self.tree.get_path(ino) else {
+            tracing::warn!(ino, "inode not found");
+            return Err(libc::ENOENT);
+        };
+
+        match self.fs.dir_entry(path) {
+            Ok(entry) => Ok(self.attr(ino, entry)),
+            Err(err) => Err(errno(&err)),
+        }
+    }
+
+    // Change the creation time of `path` to `crtime`, if the storage can.
+    fn set_created(&self, path: &Path, crtime: SystemTime) -> Result<(), libc::c_int> {
+        let Err(err) = self
+            .fs
+            .set_times(path, FileTimes::new().set_created(crtime))
+        else {
+            return Ok(());
+        };
+
+        match err.downcast_ref::<io::Error>().map(io::Error::kind) {
+            Some(io::ErrorKind::Unsupported) if self.ignore_unsupported_times => {
+                tracing::debug!("ignoring creation time, which the storage can't change");
+                Ok(())
+            }
+            Some(io::ErrorKind::Unsupported) => Err(libc::ENOTSUP),
+            _ => {
+                tracing::error!("{err:?}");
+                Err(libc::EIO)
+            }
+        }
+    }
+
+    // Change the permissions of `path` to `mode`, unless the policy says otherwise.
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<(), libc::c_int> {
+        match self.permissions {
+            PermissionsPolicy::Static { ignore_chmod, .. } => match ignore_chmod {
+                true => Ok(()),
+                false => Err(libc::EPERM),
+            },
+            _ => self
+                .fs
+                .set_permissions(path, Permissions::from_mode(mode))
+                .map_err(|err| {
+                    tracing::error!("{err:?}");
+                    libc::EIO
+                }),
+        }
+    }
+
+    // Open the file with the inode `ino` using the kernel open flags `flags`, returning a new
+    // file handle, or an errno if that isn't possible.
+    fn open_handle(&mut self, ino: u64, flags: i32) -> Result<u64, libc::c_int> {
+        let Some(path) = self.tree.get_path(ino) else {
+            tracing::warn!(ino, "inode not found");
+            return Err(libc::ENOENT);
+        };
+        // Opening may truncate the file
+        self.forget_listed(&path);
+
+        // These flags are about the cleartext entry, so check them against it
+        match self.fs.dir_entry(&path).map_err(|err| errno(&err))?.kind {
+            FileKind::Symlink if flags & libc::O_NOFOLLOW != 0 => return Err(libc::ELOOP),
+            FileKind::File if flags & libc::O_DIRECTORY != 0 => return Err(libc::ENOTDIR),
+            FileKind::Directory => return Err(libc::EISDIR),
+            _ => {}
+        }
+
+        // Append mode is technically supported, but kind of through a hack
+        let file = self
+            .fs
+            .open_file(path, open_options(flags), flags & libc::O_APPEND > 0)
+            .map_err(|err| errno(&err.into()))?;
+        let fh = self.next_handle.fetch_add(1, Ordering::SeqCst);
+        self.open_files.insert(fh, file);
+
+        Ok(fh)
+    }
+
+    // Read up to `size` bytes at `offset` from the file handle `fh`, or an errno if that isn't
+    // possible.
+    fn read_data(&mut self, fh: u64, offset: i64, size: u32) -> Result<&[u8], libc::c_int> {
+        let Some(file) = self.open_files.get_mut(&fh) else {
+            tracing::warn!(fh, "file handle not found");
+            return Err(libc::ENOENT);
+        };
+        let size = size.min(self.max_read);
+        let offset = request_offset(offset, size.into()).ok_or(libc::EINVAL)?;
+
+        match file.seek(SeekFrom::Start(offset)) {
+            // There's nothing to read short of the offset
+            Ok(pos) if pos != offset => return Ok(&[]),
+            Ok(_) => {}
+            Err(err) => return Err(errno(&err.into())),
+        }
+        // Ok to convert, this is at most the max read size
+        self.read_buf.resize(size as usize, 0);
+        match util::try_read_exact(file, &mut self.read_buf) {
+            Ok((_, n)) => self.read_buf.truncate(n),
+            Err(err) => return Err(errno(&err.into())),
+        }
+
+        Ok(&self.read_buf)
+    }
+
+    // Write all of `data` at `offset` to the file handle `fh`, returning how much was written, or
+    // an errno if that isn't possible.
+    fn write_data(&mut self, fh: u64, offset: i64, data: &[u8]) -> Result<u32, libc::c_int> {
+        // Which directory the file is in isn't known here, so every listing goes
+        self.forget_listed(Path::new(""));
+        let Some(file) = self.open_files.get_mut(&fh) else {
+            tracing::warn!(fh, "file handle not found");
+            return Err(libc::ENOENT);
+        };
+        let len = u32::try_from(data.len()).map_err(|_| libc::EINVAL)?;
+        let offset = request_offset(offset, len.into()).ok_or(libc::EINVAL)?;
+
+        // Writing past the end of the file fills the gap with zeros
+        match file.write_all_at(data, offset) {
+            Ok(()) => Ok(len),
+            Err(err) => Err(errno(&err.into())),
+        }
+    }
+
+    // Take a snapshot of the directory `ino`, returning a handle to list it with.
+    fn open_dir(&mut self, ino: u64) -> Result<u64, libc::c_int> {
+        let Some(path) = self.tree.get_path(ino) else {
+            tracing::warn!(ino, "inode not found");
+            return Err(libc::ENOENT);
+        };
+        let mut read_dir = self.fs.read_dir(&path).map_err(|err| errno(&err))?;
+
+        let mut entries = Vec::new();
+        let mut listed = HashMap::new();
+        while let Some(result) = read_dir.next_listed() {
+            match result {
+                Ok((entry_path, entry)) => {
+                    // Ok to unwrap, these are all entries in a directory
+                    let name = entry_path.file_name().unwrap().to_os_string();
+                    if self.hide_apple_double && is_apple_double(&name) {
+                        continue;
+                    }
+                    entries.push((name.clone(), entry.kind));
+                    listed.insert(name, entry);
+                }
+                // Entries that can't be read are hidden, rather than failing the whole listing
+                Err((_, err)) => {
+                    tracing::warn!(path = %self.fs.traced(&path), "skipping entry: {err}");
+                }
+            }
+        }
+
+        let fh = self.next_handle.fetch_add(1, Ordering::SeqCst);
+        entries.shrink_to_fit();
+        self.open_dirs.insert(
+            fh,
+            OpenDir {
+                path,
+                entries,
+                listed,
+                listed_at: Instant::now(),
+            },
+        );
+        Ok(fh)
+    }
+
+    // Take the entry `name` in the directory `dir` from the listing of an open handle, if one
+    // listed it recently.
+    fn take_listed(&mut self, dir: &Path, name: &std::ffi::OsStr) -> Option<DirEntry> {
+        self.open_dirs
+            .values_mut()
+            .filter(|open_dir| open_dir.path == dir && open_dir.listed_at.elapsed() < TTL)
+            .find_map(|open_dir| open_dir.listed.remove(name))
+    }
+
+    // Drop what open handles listed in the directory that `path` is in, and in `path` itself and
+    // any directories below it, since it changed. The root directory's empty path drops
+    // everything.
+    fn forget_listed(&mut self, path: &Path) {
+        for open_dir in self.open_dirs.values_mut() {
+            if open_dir.path.starts_with(path) || path.parent() == Some(&open_dir.path) {
+                open_dir.listed.clear();
+            }
+        }
+    }
+
+    // Look up the entry `name` in the directory `parent`, returning its inode, or an errno if
+    // that isn't possible.
+    fn lookup_entry(
+        &mut self,
+        parent: u64,
+        name: &std::ffi::OsStr,
+    ) -> Result<(Inode, DirEntry), libc::c_int> {
+        let Some(parent_path) = self.tree.get_path(parent) else {
+            tracing::warn!(parent, "parent inode not found");
+            return Err(libc::ENOENT);
+        };
+        if self.hide_apple_double && is_apple_double(name) {
+            return Err(libc::ENOENT);
+        }
+
+        let entry = match self.take_listed(&parent_path, name) {
+            Some(entry) => entry,
+            None => self
+                .fs
+                .dir_entry(parent_path.join(name))
+                .map_err(|err| errno(&err))?,
+        };
+        Ok((self.tree.insert_path(parent_path.join(name)), entry))
+    }
+
+    // Pass the entries of the open directory `fh`, starting at index `offset`, to `add` until it
+    // returns true because the reply is full. Each entry comes with its inode and the offset of
+    // the entry after it. Offsets always index the snapshot taken by opendir, so listing from 0
+    // again serves the same entries. Entries created in the meantime are never listed, and ones
+    // removed in the meantime still are, which POSIX allows either way.
+    fn list_open_dir(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut add: impl FnMut(Inode, i64, FileKind, &std::ffi::OsStr) -> bool,
+    ) -> Result<(), libc::c_int> {
+        let Some(open_dir) = self.open_dirs.get(&fh) else {
+            tracing::warn!(fh, "dir handle not found");
+            return Err(libc::ENOENT);
+        };
+        let offset = usize::try_from(offset).map_err(|_| libc::EINVAL)?;
+
+        for (i, (name, kind)) in open_dir.entries.iter().enumerate().skip(offset) {
+            let inode = match self.tree.insert(ino, name) {
+                Some(inode) => inode,
+                None => self.tree.insert_path(open_dir.path.join(name)),
+            };
+
+            // i + 1 means the index of the next entry
+            if add(inode, (i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Make a hard link to `ino` at `name` in `new_parent`, or an errno if that isn't possible.
+    fn link_entry(
+        &mut self,
+        ino: u64,
+        new_parent: u64,
+        name: &std::ffi::OsStr,
+    ) -> Result<(Inode, DirEntry), libc::c_int> {
+        if !self.emulate_hard_links {
+            return Err(libc::EPERM);
+        }
+        self.check_new_name(name)?;
+        let Some(path) = self.tree.get_path(ino) else {
+            tracing::warn!(ino, "inode not found");
+            return Err(libc::ENOENT);
+        };
+        if path.parent().is_none() {
+            return Err(libc::EPERM);
+        }
+        let Some(new_parent) = self.tree.get_path(new_parent) else {
+            tracing::warn!(new_parent, "new parent inode not found");
+            return Err(libc::ENOENT);
+        };
+
+        let new_path = new_parent.join(name);
+        self.forget_listed(&new_path);
+        match self.fs.copy_file(&path, &new_path, false) {
+            Ok(entry) => Ok((self.tree.insert_path(new_path), entry)),
+            Err(err) => Err(match err.downcast_ref::<io::Error>().map(io::Error::kind) {
+                // Directories can't be hard linked either
+                Some(io::ErrorKind::IsADirectory) => libc::EPERM,
+                Some(io::ErrorKind::AlreadyExists) => libc::EEXIST,
+                _ => match err.downcast_ref::<FsError>() {
+                    Some(err) => errno(err),
+                    None => {
+                        tracing::error!("{err:?}");
+                        libc::EIO
+                    }
+                },
+            }),
         }
     }
 }
@@ -96,72 +715,85 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         Ok(())
     }
 
+    fn destroy(&mut self) {
+        // Release any open handles, so nothing is left borrowing the vault after unmounting
+        self.open_files.clear();
+        self.open_dirs.clear();
+        if let Err(err) = self.fs.save_dir_cache() {
+            tracing::warn!("failed to save the directory cache: {err}");
+        }
+        if self.lock_on_unmount {
+            self.fs.lock_vault();
+        }
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "lookup", request_id = req.unique(), parent, name = %self.fs.traced(name))
+    )]
     fn lookup(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEntry,
     ) {
-        if let Some(parent_path) = self.tree.get_path(parent) {
-            let target_path = parent_path.join(name);
-
-            if let Ok(entry) = self.fs.dir_entry(&target_path) {
-                let inode = self.tree.insert_path(target_path);
-                reply.entry(&TTL, &FileAttr::from(Attributes { inode, entry }), 0);
-            } else {
-                // TODO: This will ignore other errors and just assume the path is not found
-                // Maybe we want to distinguish these cases
-                tracing::warn!(?target_path, "path not found");
-                reply.error(libc::ENOENT);
+        let mut op = self.op("lookup");
+        match self.lookup_entry(parent, name) {
+            Ok((inode, entry)) => {
+                self.tree.remember(inode);
+                reply.entry(&TTL, &self.attr(inode, entry), 0);
             }
-        } else {
-            tracing::warn!(parent, "parent inode not found");
-            reply.error(libc::ENOENT);
+            Err(errno) => op.error(reply, errno),
         }
     }
 
-    fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
-        if let Some(path) = self.tree.get_path(ino) {
-            if path.parent().is_none() {
-                let metadata = match self.fs.root_dir().metadata() {
-                    Ok(metadata) => metadata,
-                    Err(err) => {
-                        tracing::error!("{err:?}");
-                        return reply.error(libc::EIO);
-                    }
-                };
-                return reply.attr(
-                    &TTL,
-                    &FileAttr::from(Attributes {
-                        inode: FUSE_ROOT_ID,
-                        entry: DirEntry {
-                            kind: FileKind::Directory,
-                            size: metadata.size(),
-                            metadata,
-                        },
-                    }),
-                );
-            }
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "forget", request_id = req.unique(), ino, nlookup)
+    )]
+    fn forget(&mut self, req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        let _op = self.op("forget");
+        self.tree.forget(ino, nlookup);
+    }
 
-            match self.fs.dir_entry(path) {
-                Ok(entry) => {
-                    reply.attr(&TTL, &FileAttr::from(Attributes { inode: ino, entry }));
-                }
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
-                }
-            }
-        } else {
-            tracing::warn!(ino, "inode not found");
-            reply.error(libc::ENOENT);
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "getattr", request_id = req.unique(), ino)
+    )]
+    fn getattr(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        let mut op = self.op("getattr");
+        match self.inode_attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => op.error(reply, errno),
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "access", request_id = req.unique(), ino, mask)
+    )]
+    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let mut op = self.op("access");
+        match self.inode_attr(ino) {
+            Ok(attr) if access_allowed(&attr, req.uid(), req.gid(), mask) => reply.ok(),
+            Ok(_) => op.error(reply, libc::EACCES),
+            Err(errno) => op.error(reply, errno),
+        }
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "setattr", request_id = req.unique(), ino)
+    )]
     fn setattr(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         ino: u64,
         mode: Option<u32>,
         _uid: Option<u32>,
@@ -170,25 +802,34 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         _size: Option<u64>,
         atime: Option<fuser::TimeOrNow>,
         mtime: Option<fuser::TimeOrNow>,
-        // TODO: Support ctime and other timestamps?
+        // The change time can't be set anywhere, and the backup and change times of macOS aren't
+        // stored in vaults
         _ctime: Option<std::time::SystemTime>,
         _fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
+        crtime: Option<std::time::SystemTime>,
         _chgtime: Option<std::time::SystemTime>,
         _bkuptime: Option<std::time::SystemTime>,
         _flags: Option<u32>,
         reply: fuser::ReplyAttr,
     ) {
+        let mut op = self.op("setattr");
         if let Some(path) = self.tree.get_path(ino) {
             if path.parent().is_none() {
                 // TODO: Should we change root dir metadata?
-                return reply.error(libc::ENOTSUP);
+                return op.error(reply, libc::ENOTSUP);
             }
+            self.forget_listed(&path);
 
             if let Some(mode) = mode {
-                if let Err(err) = self.fs.set_permissions(&path, Permissions::from_mode(mode)) {
-                    tracing::error!("{err:?}");
-                    return reply.error(libc::EIO);
+                if let Err(errno) = self.set_mode(&path, mode) {
+                    return op.error(reply, errno);
+                }
+            }
+
+            // This goes first, so nothing has changed if it isn't supported
+            if let Some(crtime) = crtime {
+                if let Err(errno) = self.set_created(&path, crtime) {
+                    return op.error(reply, errno);
                 }
             }
 
@@ -208,42 +849,47 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
 
             if let Err(err) = self.fs.set_times(&path, times) {
                 tracing::error!("{err:?}");
-                return reply.error(libc::EIO);
+                return op.error(reply, libc::EIO);
             }
 
             match self.fs.dir_entry(path) {
                 Ok(entry) => {
-                    reply.attr(&TTL, &FileAttr::from(Attributes { inode: ino, entry }));
-                }
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
+                    reply.attr(&TTL, &self.attr(ino, entry));
                 }
+                Err(err) => op.error(reply, errno(&err)),
             }
         } else {
             tracing::warn!(ino, "inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
-    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "readlink", request_id = req.unique(), ino)
+    )]
+    fn readlink(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        let mut op = self.op("readlink");
         if let Some(path) = self.tree.get_path(ino) {
             match self.fs.link_target(path) {
                 Ok(target) => reply.data(target.as_os_str().as_bytes()),
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
-                }
+                Err(err) => op.error(reply, errno(&err)),
             }
         } else {
             tracing::warn!(ino, "inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "mknod", request_id = req.unique(), parent, name = %self.fs.traced(name))
+    )]
     fn mknod(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         mode: u32,
@@ -251,26 +897,37 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         _rdev: u32,
         reply: fuser::ReplyEntry,
     ) {
+        let mut op = self.op("mknod");
+        if let Err(errno) = self.check_new_name(name) {
+            return op.error(reply, errno);
+        }
         if let Some(parent) = self.tree.get_path(parent) {
+            self.forget_listed(&parent.join(name));
             match self.fs.mknod(&parent, name, Permissions::from_mode(mode)) {
                 Ok(entry) => {
                     let inode = self.tree.insert_path(parent.join(name));
-                    reply.entry(&TTL, &FileAttr::from(Attributes { inode, entry }), 0);
+                    self.tree.remember(inode);
+                    reply.entry(&TTL, &self.attr(inode, entry), 0);
                 }
                 Err(err) => {
                     tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
+                    op.error(reply, libc::EIO);
                 }
             }
         } else {
             tracing::warn!(parent, "parent inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "mkdir", request_id = req.unique(), parent, name = %self.fs.traced(name))
+    )]
     fn mkdir(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         mode: u32,
@@ -278,106 +935,169 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         _umask: u32,
         reply: fuser::ReplyEntry,
     ) {
+        let mut op = self.op("mkdir");
+        if let Err(errno) = self.check_new_name(name) {
+            return op.error(reply, errno);
+        }
         if let Some(parent) = self.tree.get_path(parent) {
+            self.forget_listed(&parent.join(name));
             match self.fs.mkdir(&parent, name, Permissions::from_mode(mode)) {
                 Ok(entry) => {
                     let inode = self.tree.insert_path(parent.join(name));
-                    reply.entry(&TTL, &FileAttr::from(Attributes { inode, entry }), 0);
+                    self.tree.remember(inode);
+                    reply.entry(&TTL, &self.attr(inode, entry), 0);
                 }
                 Err(err) => {
                     tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
+                    op.error(reply, libc::EIO);
                 }
             }
         } else {
             tracing::warn!(parent, "parent inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "unlink", request_id = req.unique(), parent, name = %self.fs.traced(name))
+    )]
     fn unlink(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEmpty,
     ) {
+        let mut op = self.op("unlink");
         if let Some(parent_path) = self.tree.get_path(parent) {
+            self.forget_listed(&parent_path.join(name));
             if let Err(err) = self.fs.unlink(parent_path, name) {
                 tracing::error!("{err:?}");
-                reply.error(libc::EIO);
+                op.error(reply, libc::EIO);
             } else {
                 self.tree.remove(parent, name);
                 reply.ok();
             }
         } else {
             tracing::warn!(parent, "parent inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "rmdir", request_id = req.unique(), parent, name = %self.fs.traced(name))
+    )]
     fn rmdir(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEmpty,
     ) {
+        let mut op = self.op("rmdir");
         if let Some(parent_path) = self.tree.get_path(parent) {
-            match self.fs.dir_entries(parent_path.join(name)) {
-                Ok(entries) => {
-                    if !entries.is_empty() {
+            self.forget_listed(&parent_path.join(name));
+            match self.fs.dir_entries_lossy(parent_path.join(name)) {
+                Ok((entries, errors)) => {
+                    if !entries.is_empty() || !errors.is_empty() {
                         tracing::warn!("directory not empty");
-                        return reply.error(libc::ENOTEMPTY);
+                        return op.error(reply, libc::ENOTEMPTY);
                     }
 
                     if let Err(err) = self.fs.rmdir(parent_path, name) {
                         tracing::error!("{err:?}");
-                        reply.error(libc::EIO);
+                        op.error(reply, libc::EIO);
                     } else {
                         self.tree.remove(parent, name);
                         reply.ok()
                     }
                 }
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
-                }
+                Err(err) => op.error(reply, errno(&err)),
             }
         } else {
             tracing::warn!(parent, "parent inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "symlink", request_id = req.unique(), parent, name = %self.fs.traced(link_name))
+    )]
     fn symlink(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         link_name: &std::ffi::OsStr,
         target: &std::path::Path,
         reply: fuser::ReplyEntry,
     ) {
+        let mut op = self.op("symlink");
+        if let Err(errno) = self.check_new_name(link_name) {
+            return op.error(reply, errno);
+        }
         if let Some(parent) = self.tree.get_path(parent) {
+            self.forget_listed(&parent.join(link_name));
             match self.fs.symlink(&parent, link_name, target) {
                 Ok(entry) => {
                     let inode = self.tree.insert_path(parent.join(link_name));
-                    reply.entry(&TTL, &FileAttr::from(Attributes { inode, entry }), 0)
+                    self.tree.remember(inode);
+                    reply.entry(&TTL, &self.attr(inode, entry), 0)
                 }
                 Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
+                    let errno = match err.downcast_ref::<io::Error>().map(io::Error::kind) {
+                        Some(io::ErrorKind::InvalidInput) => libc::EINVAL,
+                        Some(io::ErrorKind::InvalidFilename) => libc::ENAMETOOLONG,
+                        _ => {
+                            tracing::error!("{err:?}");
+                            libc::EIO
+                        }
+                    };
+                    op.error(reply, errno);
                 }
             }
         } else {
             tracing::warn!(parent, "parent inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
+        }
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "link", request_id = req.unique(), ino, newparent, newname = %self.fs.traced(newname))
+    )]
+    fn link(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        let mut op = self.op("link");
+        match self.link_entry(ino, newparent, newname) {
+            Ok((inode, entry)) => {
+                self.tree.remember(inode);
+                reply.entry(&TTL, &self.attr(inode, entry), 0)
+            }
+            Err(errno) => op.error(reply, errno),
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "rename", request_id = req.unique(), parent, name = %self.fs.traced(name), newparent, newname = %self.fs.traced(newname))
+    )]
     fn rename(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         newparent: u64,
@@ -385,54 +1105,52 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         _flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        let mut op = self.op("rename");
+        if let Err(errno) = self.check_new_name(newname) {
+            return op.error(reply, errno);
+        }
         if let Some(old_parent) = self.tree.get_path(parent) {
             if let Some(new_parent) = self.tree.get_path(newparent) {
+                self.forget_listed(&old_parent.join(name));
+                self.forget_listed(&new_parent.join(newname));
                 if let Err(err) = self.fs.rename(old_parent, name, new_parent, newname) {
                     tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
+                    op.error(reply, libc::EIO);
                 } else {
                     self.tree.rename(parent, name, newparent, newname);
                     reply.ok()
                 }
             } else {
                 tracing::warn!(newparent, "new parent inode not found");
-                reply.error(libc::ENOENT);
+                op.error(reply, libc::ENOENT);
             }
         } else {
             tracing::warn!(parent, "old parent inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
-    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-        if let Some(path) = self.tree.get_path(ino) {
-            // We'll support opening files in either read mode or read-write mode
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(flags & libc::O_WRONLY > 0 || flags & libc::O_RDWR > 0);
-            options.custom_flags(flags);
-
-            // Append mode is technically supported, but kind of through a hack
-            match self.fs.open_file(path, options, flags & libc::O_APPEND > 0) {
-                Ok(file) => {
-                    let fh = self.next_handle.fetch_add(1, Ordering::SeqCst);
-                    self.open_files.insert(fh, file);
-                    reply.opened(fh, flags as u32)
-                }
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
-                }
-            }
-        } else {
-            tracing::warn!(ino, "inode not found");
-            reply.error(libc::ENOENT);
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "open", request_id = req.unique(), ino, flags)
+    )]
+    fn open(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let mut op = self.op("open");
+        match self.open_handle(ino, flags) {
+            Ok(fh) => reply.opened(fh, flags as u32),
+            Err(errno) => op.error(reply, errno),
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "read", request_id = req.unique(), ino = _ino, fh, offset, size)
+    )]
     fn read(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         _ino: u64,
         fh: u64,
         offset: i64,
@@ -441,36 +1159,22 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         _lock_owner: Option<u64>,
         reply: fuser::ReplyData,
     ) {
-        if let Some(file) = self.open_files.get_mut(&fh) {
-            debug_assert!(offset >= 0);
-            match file.seek(SeekFrom::Start(offset as u64)) {
-                Ok(pos) => {
-                    debug_assert_eq!(pos, offset as u64);
-                    let mut buf = vec![0_u8; size as usize];
-                    match util::try_read_exact(file, &mut buf) {
-                        Ok((false, n)) => buf.truncate(n),
-                        Ok(_) => {}
-                        Err(err) => {
-                            tracing::error!("{err:?}");
-                            return reply.error(libc::EIO);
-                        }
-                    }
-                    reply.data(&buf);
-                }
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
-                }
-            }
-        } else {
-            tracing::warn!(fh, "file handle not found");
-            reply.error(libc::ENOENT);
+        let mut op = self.op("read");
+        match self.read_data(fh, offset, size) {
+            Ok(data) => reply.data(data),
+            Err(errno) => op.error(reply, errno),
         }
+        self.read_buf.zeroize();
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "write", request_id = req.unique(), ino = _ino, fh, offset, len = data.len())
+    )]
     fn write(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         _ino: u64,
         fh: u64,
         offset: i64,
@@ -480,55 +1184,47 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         _lock_owner: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
-        if let Some(file) = self.open_files.get_mut(&fh) {
-            debug_assert!(offset >= 0);
-            match file.seek(SeekFrom::Start(offset as u64)) {
-                Ok(pos) => {
-                    debug_assert_eq!(pos, offset as u64);
-                    match file.write_all(data) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            tracing::error!("{err:?}");
-                            return reply.error(libc::EIO);
-                        }
-                    }
-                    reply.written(data.len() as u32);
-                }
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
-                }
-            }
-        } else {
-            tracing::warn!(fh, "file handle not found");
-            reply.error(libc::ENOENT);
+        let mut op = self.op("write");
+        match self.write_data(fh, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(errno) => op.error(reply, errno),
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "flush", request_id = req.unique(), ino = _ino, fh)
+    )]
     fn flush(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         _ino: u64,
         fh: u64,
         _lock_owner: u64,
         reply: fuser::ReplyEmpty,
     ) {
+        let mut op = self.op("flush");
         if let Some(file) = self.open_files.get_mut(&fh) {
             if let Err(err) = file.flush() {
-                tracing::error!("{err:?}");
-                reply.error(libc::EIO);
+                op.error(reply, errno(&err.into()));
             } else {
                 reply.ok();
             }
         } else {
             tracing::warn!(fh, "file handle not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "release", request_id = req.unique(), ino = _ino, fh)
+    )]
     fn release(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         _ino: u64,
         fh: u64,
         _flags: i32,
@@ -536,18 +1232,29 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        let _op = self.op("release");
         self.open_files.remove(&fh);
+        // Nothing is being read or written, so there's no need to hold on to spare buffers
+        if self.open_files.is_empty() {
+            ChunkBufPool::global().shrink(0);
+        }
         reply.ok();
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "fsync", request_id = req.unique(), ino = _ino, fh)
+    )]
     fn fsync(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         _ino: u64,
         fh: u64,
         datasync: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        let mut op = self.op("fsync");
         if let Some(file) = self.open_files.get_mut(&fh) {
             let result = if datasync {
                 file.sync_data()
@@ -556,93 +1263,86 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
             };
 
             if let Err(err) = result {
-                tracing::error!("{err:?}");
-                reply.error(libc::EIO);
+                op.error(reply, errno(&err.into()));
             } else {
                 reply.ok();
             }
         } else {
             tracing::warn!(fh, "file handle not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
         }
     }
 
-    fn opendir(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        flags: i32,
-        reply: fuser::ReplyOpen,
-    ) {
-        if let Some(path) = self.tree.get_path(ino) {
-            match self.fs.dir_entries(path) {
-                Ok(entries) => {
-                    let handle = self.next_handle.fetch_add(1, Ordering::SeqCst);
-                    self.open_dirs.insert(handle, entries);
-                    reply.opened(handle, flags as u32);
-                }
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
-                }
-            }
-        } else {
-            tracing::warn!(ino, "inode not found");
-            reply.error(libc::ENOENT);
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "opendir", request_id = req.unique(), ino)
+    )]
+    fn opendir(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let mut op = self.op("opendir");
+        match self.open_dir(ino) {
+            Ok(fh) => reply.opened(fh, flags as u32),
+            Err(errno) => op.error(reply, errno),
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "readdir", request_id = req.unique(), ino, fh, offset)
+    )]
     fn readdir(
         &mut self,
-        _req: &fuser::Request<'_>,
-        _ino: u64,
+        req: &fuser::Request<'_>,
+        ino: u64,
         fh: u64,
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
-        if let Some(entries) = self.open_dirs.get(&fh) {
-            for (i, (path, dir_entry)) in entries.iter().enumerate().skip(offset as usize) {
-                let name = path.file_name().unwrap().to_os_string();
-                let inode = self.tree.insert_path(path);
-
-                // i + 1 means the index of the next entry
-                if reply.add(inode, (i + 1) as i64, dir_entry.kind.into(), name) {
-                    break;
-                }
-            }
-
-            reply.ok();
-        } else {
-            tracing::warn!(fh, "dir handle not found");
-            reply.error(libc::ENOENT);
+        let mut op = self.op("readdir");
+        let result = self.list_open_dir(ino, fh, offset, |inode, next_offset, kind, name| {
+            reply.add(inode, next_offset, kind.into(), name)
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => op.error(reply, errno),
         }
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "releasedir", request_id = req.unique(), ino = _ino, fh)
+    )]
     fn releasedir(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         _ino: u64,
         fh: u64,
         _flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
+        let mut op = self.op("releasedir");
         if self.open_dirs.remove(&fh).is_some() {
             reply.ok()
         } else {
             tracing::warn!(fh, "dir handle not found");
-            reply.error(libc::ENOENT)
+            op.error(reply, libc::ENOENT)
         }
     }
 
     // TODO: Check mode/umask are being used correctly here and elsewhere
-    // TODO: echo "a" > new_file will cause a crash (subtract with overflow), maybe enforce
-    //       invariants a bit better
     // TODO: Read up on these, and other calls for more info
     //   - https://www.gnu.org/software/libc/manual/html_node/Opening-and-Closing-Files.html
     //   - https://www.man7.org/linux/man-pages/man2/open.2.html
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "create", request_id = req.unique(), parent, name = %self.fs.traced(name))
+    )]
     fn create(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         mode: u32,
@@ -650,49 +1350,912 @@ impl<'v> Filesystem for FuseFileSystem<'v> {
         flags: i32,
         reply: fuser::ReplyCreate,
     ) {
+        let mut op = self.op("create");
+        if let Err(errno) = self.check_new_name(name) {
+            return op.error(reply, errno);
+        }
         if let Some(parent) = self.tree.get_path(parent) {
-            match self
-                .fs
-                .mknod(&parent, name, Permissions::from_mode(mode & !umask))
-            {
-                Ok(entry) => {
-                    let inode = self.tree.insert_path(parent.join(name));
+            let path = parent.join(name);
+            self.forget_listed(&path);
+            // The kernel checks the access mode of the descriptor, and files can only be created
+            // for writing
+            let mut options = open_options(flags);
+            options
+                .write(true)
+                .create(true)
+                .create_new(flags & libc::O_EXCL != 0)
+                .mode(mode & !umask);
 
-                    // We'll support opening files in either read mode or read-write mode
-                    let mut options = OpenOptions::new();
-                    options.read(true);
-                    options.write(flags & libc::O_WRONLY > 0 || flags & libc::O_RDWR > 0);
-
-                    // Append mode is technically supported, but kind of through a hack
-                    match self
-                        .fs
-                        .open_file(parent.join(name), options, flags & libc::O_APPEND > 0)
-                    {
-                        Ok(file) => {
-                            let fh = self.next_handle.fetch_add(1, Ordering::SeqCst);
-                            self.open_files.insert(fh, file);
-                            reply.created(
-                                &TTL,
-                                &FileAttr::from(Attributes { inode, entry }),
-                                0,
-                                fh,
-                                flags as u32,
-                            );
-                        }
-                        Err(err) => {
-                            tracing::error!("{err:?}");
-                            reply.error(libc::EIO);
-                        }
-                    }
-                }
-                Err(err) => {
-                    tracing::error!("{err:?}");
-                    reply.error(libc::EIO);
+            // Append mode is technically supported, but kind of through a hack
+            let result = self
+                .fs
+                .open_file(&path, options, flags & libc::O_APPEND > 0)
+                .map_err(|err| errno(&err.into()))
+                .and_then(|file| Ok((file, self.fs.dir_entry(&path).map_err(|err| errno(&err))?)));
+            match result {
+                Ok((file, entry)) => {
+                    let inode = self.tree.insert_path(path);
+                    let fh = self.next_handle.fetch_add(1, Ordering::SeqCst);
+                    self.open_files.insert(fh, file);
+                    self.tree.remember(inode);
+                    reply.created(&TTL, &self.attr(inode, entry), 0, fh, flags as u32);
                 }
+                Err(errno) => op.error(reply, errno),
             }
         } else {
             tracing::warn!(parent, "parent inode not found");
-            reply.error(libc::ENOENT);
+            op.error(reply, libc::ENOENT);
+        }
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "getxattr", request_id = req.unique(), ino, name = %self.fs.traced(name))
+    )]
+    fn getxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        _size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let mut op = self.op("getxattr");
+        match self.inode_attr(ino) {
+            Ok(_) => op.error(reply, ENOATTR),
+            Err(errno) => op.error(reply, errno),
+        }
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "listxattr", request_id = req.unique(), ino, size)
+    )]
+    fn listxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let mut op = self.op("listxattr");
+        match self.inode_attr(ino) {
+            // A size of 0 asks how big the list is
+            Ok(_) if size == 0 => reply.size(0),
+            Ok(_) => reply.data(&[]),
+            Err(errno) => op.error(reply, errno),
+        }
+    }
+
+    // Failing makes macOS store extended attributes in AppleDouble files instead, unless they're
+    // hidden
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "setxattr", request_id = req.unique(), ino, name = %self.fs.traced(name))
+    )]
+    fn setxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let mut op = self.op("setxattr");
+        match self.inode_attr(ino) {
+            Ok(_) => op.error(reply, libc::ENOTSUP),
+            Err(errno) => op.error(reply, errno),
+        }
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "removexattr", request_id = req.unique(), ino, name = %self.fs.traced(name))
+    )]
+    fn removexattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let mut op = self.op("removexattr");
+        match self.inode_attr(ino) {
+            Ok(_) => op.error(reply, ENOATTR),
+            Err(errno) => op.error(reply, errno),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(op = "getxtimes", request_id = req.unique(), ino)
+    )]
+    fn getxtimes(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyXTimes) {
+        let mut op = self.op("getxtimes");
+        match self.xtimes(ino) {
+            Ok((bkuptime, crtime)) => reply.xtimes(bkuptime, crtime),
+            Err(errno) => op.error(reply, errno),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, ffi::OsStr, io::Read, path::Path, time::UNIX_EPOCH};
+
+    use super::*;
+    use crate::{
+        fs::CountingObserver,
+        storage::{MemoryStorage, Metadata, StorageFile, VaultStorage},
+        testing::{self, VaultShape},
+    };
+
+    #[test]
+    fn op_test() {
+        struct Reply<'a>(&'a Cell<Option<libc::c_int>>);
+
+        impl ReplyError for Reply<'_> {
+            fn error(self, errno: libc::c_int) {
+                self.0.set(Some(errno));
+            }
+        }
+
+        let counting = Arc::new(CountingObserver::new());
+        let observer: Arc<dyn Observer> = counting.clone();
+        drop(Op::new("lookup", Some(&observer)));
+        let replied = Cell::new(None);
+        Op::new("lookup", Some(&observer)).error(Reply(&replied), libc::ENOENT);
+        assert_eq!(replied.get(), Some(libc::ENOENT));
+        assert_eq!(counting.fuse_op_count("lookup"), (2, 1));
+        assert_eq!(counting.fuse_op_count("read"), (0, 0));
+
+        // Without an observer, nothing is timed or reported
+        let op = Op::new("read", None);
+        assert!(op.observer.is_none());
+        drop(op);
+        assert_eq!(counting.fuse_op_count("read"), (0, 0));
+    }
+
+    #[test]
+    fn errno_test() {
+        assert_eq!(errno(&FsError::NotFound), libc::ENOENT);
+        assert_eq!(errno(&FsError::NotADirectory), libc::ENOTDIR);
+        assert_eq!(errno(&FsError::InvalidPath), libc::EINVAL);
+        assert_eq!(errno(&FsError::ReadOnly), libc::EROFS);
+        assert_eq!(errno(&FsError::Frozen), libc::EAGAIN);
+        assert_eq!(errno(&FsError::VaultLocked), libc::EACCES);
+        let err = io::Error::from(io::ErrorKind::AlreadyExists);
+        assert_eq!(errno(&FsError::Io(err)), libc::EEXIST);
+        let err = FsError::Locked {
+            path: "file.c9r".into(),
+        };
+        assert_eq!(errno(&err), libc::EWOULDBLOCK);
+        let err = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(errno(&FsError::Io(err)), libc::EIO);
+        let err = FsError::NameDecryption {
+            ciphertext_name: "name.c9r".into(),
+            source: "invalid name".into(),
+        };
+        assert_eq!(errno(&err), libc::EIO);
+        assert_eq!(
+            error_chain(&err),
+            "failed to decrypt the name of name.c9r: invalid name"
+        );
+    }
+
+    #[test]
+    fn link_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 2,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        // Paths in the tree are relative to the root
+        let file = fuse.tree.insert_path(shape.file_path("", 0));
+        let name = OsStr::new("link");
+        assert!(matches!(
+            fuse.link_entry(file, ROOT_INODE, name),
+            Err(libc::EPERM)
+        ));
+        assert!(matches!(fuse.fs.dir_entry("/link"), Err(FsError::NotFound)));
+
+        // With emulation, the link is a copy that changes independently of the original
+        let mut fuse = fuse.emulate_hard_links(true);
+        let (inode, entry) = fuse.link_entry(file, ROOT_INODE, name).unwrap();
+        assert_eq!(fuse.tree.get_path(inode).unwrap(), Path::new("link"));
+        assert_eq!(entry.size, shape.file_len as u64);
+        assert_eq!(entry.metadata.nlink(), 1);
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let mut copy = fuse.fs.open_file("/link", options, false).unwrap();
+        copy.write_all(b"changed").unwrap();
+        drop(copy);
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        fuse.fs
+            .open_file(shape.file_path("/", 0), options, false)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, shape.file_contents(0));
+
+        // Existing names aren't replaced, and directories can't be linked
+        let other = shape.file_path("/", 1);
+        let other_name = other.file_name().unwrap();
+        assert!(matches!(
+            fuse.link_entry(file, ROOT_INODE, other_name),
+            Err(libc::EEXIST)
+        ));
+        assert!(matches!(
+            fuse.link_entry(ROOT_INODE, ROOT_INODE, OsStr::new("root")),
+            Err(libc::EPERM)
+        ));
+        assert!(matches!(
+            fuse.link_entry(1000, ROOT_INODE, OsStr::new("missing")),
+            Err(libc::ENOENT)
+        ));
+    }
+
+    #[test]
+    fn nlink_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            dirs_per_dir: 2,
+            depth: 1,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path().join("vault"), &shape).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        fs.symlink("/", OsStr::new("link"), Path::new("target"))
+            .unwrap();
+
+        // A hard link to the ciphertext file, like one left by a dedupe tool
+        let file = shape.file_path("/", 0);
+        std::fs::hard_link(
+            fs.ciphertext_path(&file).unwrap(),
+            dir.path().join("dedupe"),
+        )
+        .unwrap();
+
+        for (path, kind) in [
+            (file, FileType::RegularFile),
+            (shape.dir_path("/", 1), FileType::Directory),
+            (PathBuf::from("/link"), FileType::Symlink),
+        ] {
+            let entry = fs.dir_entry(&path).unwrap();
+            assert!(entry.metadata.nlink() > 1 || kind != FileType::RegularFile);
+            let attr = FileAttr::from(Attributes { inode: 2, entry });
+            assert_eq!((attr.kind, attr.nlink), (kind, 1), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn permissions_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            dirs_per_dir: 1,
+            depth: 1,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        fs.symlink("/", OsStr::new("link"), Path::new("target"))
+            .unwrap();
+        let file_path = shape.file_path("", 0);
+        fs.set_permissions(
+            Path::new("/").join(&file_path),
+            Permissions::from_mode(0o750),
+        )
+        .unwrap();
+
+        let policy = PermissionsPolicy::Static {
+            file_mode: 0o640,
+            dir_mode: 0o750,
+            uid: 1234,
+            gid: 5678,
+            ignore_chmod: false,
+        };
+        let mut fuse = FuseFileSystem::new(fs).permissions(policy);
+        let file = fuse.tree.insert_path(&file_path);
+        let subdir = fuse.tree.insert_path(shape.dir_path("", 0));
+        let link = fuse.tree.insert_path("link");
+
+        // Every entry is reported with the same owner and mode for its kind
+        for (ino, perm) in [
+            (ROOT_INODE, 0o750),
+            (file, 0o640),
+            (subdir, 0o750),
+            (link, 0o777),
+        ] {
+            let attr = fuse.inode_attr(ino).unwrap();
+            assert_eq!((attr.perm, attr.uid, attr.gid), (perm, 1234, 5678), "{ino}");
+        }
+        assert_eq!(fuse.inode_attr(1000).unwrap_err(), libc::ENOENT);
+
+        // Access is checked against the reported owner and mode
+        let attr = fuse.inode_attr(file).unwrap();
+        assert!(access_allowed(&attr, 1234, 1, libc::R_OK | libc::W_OK));
+        assert!(!access_allowed(&attr, 1234, 1, libc::X_OK));
+        assert!(access_allowed(&attr, 1, 5678, libc::R_OK));
+        assert!(!access_allowed(&attr, 1, 5678, libc::W_OK));
+        assert!(!access_allowed(&attr, 1, 1, libc::R_OK));
+        assert!(access_allowed(&attr, 1, 1, libc::F_OK));
+        assert!(access_allowed(&attr, 0, 1, libc::R_OK | libc::W_OK));
+        assert!(!access_allowed(&attr, 0, 1, libc::X_OK));
+        let attr = fuse.inode_attr(subdir).unwrap();
+        assert!(access_allowed(&attr, 1234, 1, libc::X_OK));
+
+        // Changing permissions fails, or is ignored, without touching the ciphertext
+        let path = Path::new("/").join(&file_path);
+        assert_eq!(fuse.set_mode(&path, 0o600), Err(libc::EPERM));
+        let mut fuse = fuse.permissions(PermissionsPolicy::Static {
+            file_mode: 0o640,
+            dir_mode: 0o750,
+            uid: 1234,
+            gid: 5678,
+            ignore_chmod: true,
+        });
+        assert_eq!(fuse.set_mode(&path, 0o600), Ok(()));
+        let mode = fuse
+            .fs
+            .dir_entry(&path)
+            .unwrap()
+            .metadata
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o750);
+
+        // Masked permissions keep the ciphertext owner, and chmod works as usual
+        fuse = fuse.permissions(PermissionsPolicy::MaskedPassthrough { umask: 0o027 });
+        let attr = fuse.inode_attr(file).unwrap();
+        assert_eq!(attr.perm & 0o777, 0o750);
+        assert_eq!(attr.uid, fuse.fs.dir_entry(&path).unwrap().metadata.uid());
+        fuse.set_mode(&path, 0o666).unwrap();
+        assert_eq!(fuse.inode_attr(file).unwrap().perm & 0o777, 0o640);
+        fuse = fuse.permissions(PermissionsPolicy::Passthrough);
+        assert_eq!(fuse.inode_attr(file).unwrap().perm & 0o777, 0o666);
+    }
+
+    #[test]
+    fn open_flags_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            dirs_per_dir: 1,
+            depth: 1,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        fs.symlink("/", OsStr::new("link"), shape.file_path("", 0))
+            .unwrap();
+        let mut fuse = FuseFileSystem::new(fs);
+        let file = fuse.tree.insert_path(shape.file_path("", 0));
+        let subdir = fuse.tree.insert_path(shape.dir_path("", 0));
+        let link = fuse.tree.insert_path("link");
+
+        let fh = fuse
+            .open_handle(file, libc::O_RDWR | libc::O_NOFOLLOW)
+            .unwrap();
+        assert!(fuse.open_files.contains_key(&fh));
+        assert_eq!(fuse.open_handle(link, libc::O_NOFOLLOW), Err(libc::ELOOP));
+        assert_eq!(
+            fuse.open_handle(file, libc::O_DIRECTORY),
+            Err(libc::ENOTDIR)
+        );
+        assert_eq!(fuse.open_handle(subdir, libc::O_RDONLY), Err(libc::EISDIR));
+        assert_eq!(fuse.open_handle(1000, libc::O_RDONLY), Err(libc::ENOENT));
+
+        // Truncating empties the file but keeps its header, so it's only done when writing
+        let fh = fuse
+            .open_handle(file, libc::O_RDONLY | libc::O_TRUNC)
+            .unwrap();
+        let contents = shape.file_contents(0);
+        assert_eq!(fuse.read_data(fh, 0, 4096).unwrap(), contents);
+        let fh = fuse
+            .open_handle(file, libc::O_RDWR | libc::O_TRUNC)
+            .unwrap();
+        assert!(fuse.read_data(fh, 0, 4096).unwrap().is_empty());
+        assert_eq!(fuse.write_data(fh, 0, b"new").unwrap(), 3);
+        assert_eq!(fuse.read_data(fh, 0, 4096).unwrap(), b"new");
+        let options = open_options(libc::O_WRONLY | libc::O_TRUNC | libc::O_SYNC);
+        assert!(options.write && options.truncate);
+        assert_eq!(options.custom_flags, libc::O_SYNC);
+        assert!(!open_options(libc::O_RDONLY).write);
+    }
+
+    #[test]
+    fn set_created_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        let path = Path::new("/").join(shape.file_path("", 0));
+        let crtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert_eq!(
+            fuse.set_created(Path::new("/missing"), crtime),
+            Err(libc::EIO)
+        );
+
+        #[cfg(target_os = "macos")]
+        {
+            assert_eq!(fuse.set_created(&path, crtime), Ok(()));
+            assert_eq!(fuse.fs.dir_entry(&path).unwrap().created, crtime);
+        }
+
+        // Other platforms can't change birth times, unless asked to pretend they did
+        #[cfg(not(target_os = "macos"))]
+        {
+            let created = fuse.fs.dir_entry(&path).unwrap().created;
+            assert_eq!(fuse.set_created(&path, crtime), Err(libc::ENOTSUP));
+            let fuse = fuse.ignore_unsupported_times(true);
+            assert_eq!(fuse.set_created(&path, crtime), Ok(()));
+            assert_eq!(fuse.fs.dir_entry(&path).unwrap().created, created);
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn xtimes_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        let path = shape.file_path("", 0);
+        let file = fuse.tree.insert_path(&path);
+        let crtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fuse.set_created(&path, crtime).unwrap();
+
+        // Finder reads the creation time from here, and there's never a backup time
+        assert_eq!(fuse.xtimes(file), Ok((UNIX_EPOCH, crtime)));
+        assert_eq!(fuse.xtimes(1000), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn apple_double_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 2,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        for name in [".DS_Store", "._file"] {
+            fs.mknod("/", OsStr::new(name), Permissions::from_mode(0o644))
+                .unwrap();
+        }
+        let listed = |fuse: &mut FuseFileSystem| {
+            let fh = fuse.open_dir(ROOT_INODE).unwrap();
+            fuse.open_dirs[&fh].entries.len()
+        };
+
+        assert!(is_apple_double(OsStr::new(".DS_Store")));
+        assert!(is_apple_double(OsStr::new("._file")));
+        assert!(!is_apple_double(OsStr::new(".DS_Store.txt")));
+        assert!(!is_apple_double(OsStr::new("file._")));
+
+        // They're ordinary files by default
+        let mut fuse = FuseFileSystem::new(fs.clone()).emulate_hard_links(true);
+        assert_eq!(listed(&mut fuse), shape.files_per_dir + 2);
+        fuse.lookup_entry(ROOT_INODE, OsStr::new(".DS_Store"))
+            .unwrap();
+        assert_eq!(fuse.check_new_name(OsStr::new("._new")), Ok(()));
+
+        // Hidden, existing ones can't be found, and new ones can't be made
+        let mut fuse = fuse.hide_apple_double(true);
+        assert_eq!(listed(&mut fuse), shape.files_per_dir);
+        for name in [".DS_Store", "._file"] {
+            assert!(matches!(
+                fuse.lookup_entry(ROOT_INODE, OsStr::new(name)),
+                Err(libc::ENOENT)
+            ));
+        }
+        assert_eq!(fuse.check_new_name(OsStr::new("._new")), Err(libc::EACCES));
+        let file = fuse.tree.insert_path(shape.file_path("", 0));
+        assert!(matches!(
+            fuse.link_entry(file, ROOT_INODE, OsStr::new("._link")),
+            Err(libc::EACCES)
+        ));
+        assert!(matches!(
+            fuse.fs.dir_entry("/._link"),
+            Err(FsError::NotFound)
+        ));
+        fuse.lookup_entry(ROOT_INODE, &shape.file_path("", 1).into_os_string())
+            .unwrap();
+    }
+
+    #[test]
+    fn read_data_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            file_len: 3 * 1024 * 1024,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let file = fuse
+            .fs
+            .open_file(shape.file_path("/", 0), options, false)
+            .unwrap();
+        fuse.open_files.insert(1, file);
+        let contents = shape.file_contents(0);
+
+        // Huge requests are capped, rather than allocated in full
+        let max_read = FuseFileSystem::DEFAULT_MAX_READ as usize;
+        let data = fuse.read_data(1, 100, u32::MAX).unwrap();
+        assert_eq!(data, &contents[100..100 + max_read]);
+        let mut fuse = fuse.max_read(2 * max_read as u32);
+        let data = fuse.read_data(1, 0, u32::MAX).unwrap();
+        assert_eq!(data, &contents[..2 * max_read]);
+
+        // Reads near the end are short, and past it are empty
+        let len = contents.len() as i64;
+        assert_eq!(
+            fuse.read_data(1, len - 10, 4096).unwrap(),
+            &contents[len as usize - 10..]
+        );
+        assert!(fuse.read_data(1, len, 4096).unwrap().is_empty());
+        assert!(fuse.read_data(1, len + 100_000, 4096).unwrap().is_empty());
+        assert_eq!(fuse.read_data(1, 0, 4).unwrap(), &contents[..4]);
+
+        // Offsets that are negative or overflow are rejected
+        assert_eq!(fuse.read_data(1, -1, 4096), Err(libc::EINVAL));
+        assert_eq!(fuse.read_data(1, i64::MAX, 4096), Err(libc::EINVAL));
+        assert_eq!(fuse.read_data(2, 0, 4096), Err(libc::ENOENT));
+        assert_eq!(
+            request_offset(i64::MAX - 10, 10),
+            Some(i64::MAX as u64 - 10)
+        );
+        assert_eq!(request_offset(i64::MAX, u64::MAX), None);
+    }
+
+    #[test]
+    fn lock_on_unmount_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+
+        // The vault stays unlocked by default
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        fuser::Filesystem::destroy(&mut fuse);
+        assert!(!vault.is_locked());
+
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault)).lock_on_unmount(true);
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let file = fuse
+            .fs
+            .open_file(shape.file_path("/", 0), options, false)
+            .unwrap();
+        fuse.open_files.insert(1, file);
+        fuser::Filesystem::destroy(&mut fuse);
+        assert!(vault.is_locked());
+        assert!(fuse.open_files.is_empty());
+        assert!(matches!(
+            fuse.fs.dir_entry(shape.file_path("/", 0)),
+            Err(FsError::VaultLocked)
+        ));
+    }
+
+    #[test]
+    fn write_data_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            file_len: 1000,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let file = fuse
+            .fs
+            .open_file(shape.file_path("/", 0), options, false)
+            .unwrap();
+        fuse.open_files.insert(1, file);
+
+        // Writes past the end leave zeros behind, which read back like any other data
+        assert_eq!(fuse.write_data(1, 100_000, b"end"), Ok(3));
+        assert_eq!(fuse.read_data(1, 99_998, 4096).unwrap(), b"\0\0end");
+        assert_eq!(fuse.read_data(1, 1000, 4).unwrap(), [0; 4]);
+        assert_eq!(fuse.write_data(1, 0, b"start"), Ok(5));
+        let mut expected = b"start".to_vec();
+        expected.extend_from_slice(&shape.file_contents(0)[5..]);
+        expected.resize(100_000, 0);
+        expected.extend_from_slice(b"end");
+        let mut contents = Vec::new();
+        let file = fuse.open_files.get_mut(&1).unwrap();
+        file.rewind().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert!(contents == expected);
+
+        assert_eq!(fuse.write_data(1, -1, b"data"), Err(libc::EINVAL));
+        assert_eq!(fuse.write_data(1, i64::MAX, b"data"), Err(libc::EINVAL));
+        assert_eq!(fuse.write_data(2, 0, b"data"), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn list_open_dir_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 10,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        let mut names: Vec<_> = (0..shape.files_per_dir)
+            .map(|n| shape.file_path("", n).into_os_string())
+            .collect();
+        names.sort();
+
+        // List up to `len` entries from `offset`, with their inodes and next offsets
+        let page = |fuse: &mut FuseFileSystem, fh, offset, len| {
+            let mut page = Vec::new();
+            fuse.list_open_dir(ROOT_INODE, fh, offset, |inode, next_offset, _, name| {
+                page.push((inode, name.to_os_string(), next_offset));
+                page.len() == len
+            })
+            .map(|_| page)
+        };
+
+        // Files created while the directory is listed don't shift the offsets of later pages
+        let fh = fuse.open_dir(ROOT_INODE).unwrap();
+        let mut listed = Vec::new();
+        let mut offset = 0;
+        for n in 0.. {
+            let entries = page(&mut fuse, fh, offset, 3).unwrap();
+            let Some((_, _, next_offset)) = entries.last() else {
+                break;
+            };
+            offset = *next_offset;
+            listed.extend(entries);
+            for name in [format!("a-{n}"), format!("file-{n}"), format!("z-{n}")] {
+                fuse.fs
+                    .mknod("/", OsStr::new(&name), Permissions::from_mode(0o644))
+                    .unwrap();
+            }
+        }
+        let listed_names: Vec<_> = listed.iter().map(|(_, name, _)| name.clone()).collect();
+        assert_eq!(listed_names, names);
+        for (inode, name, _) in &listed {
+            assert_eq!(fuse.tree.get_path(*inode).unwrap(), Path::new(name));
+        }
+
+        // Listing from the start again serves the same snapshot
+        assert_eq!(page(&mut fuse, fh, 0, 3).unwrap(), listed[..3]);
+        assert!(page(&mut fuse, fh, 100, 3).unwrap().is_empty());
+        assert_eq!(page(&mut fuse, fh, -1, 3), Err(libc::EINVAL));
+
+        // A new handle sees the new files
+        let new_fh = fuse.open_dir(ROOT_INODE).unwrap();
+        assert_eq!(page(&mut fuse, new_fh, 0, 100).unwrap().len(), 22);
+        assert_eq!(page(&mut fuse, new_fh + 1, 0, 3), Err(libc::ENOENT));
+        assert_eq!(fuse.open_dir(1000), Err(libc::ENOENT));
+    }
+
+    // Storage that counts how often metadata is read, which looking up an entry always does.
+    #[derive(Debug, Default)]
+    struct StatCounter {
+        inner: MemoryStorage,
+        stats: Arc<AtomicU64>,
+    }
+
+    impl VaultStorage for StatCounter {
+        fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn StorageFile>> {
+            self.inner.open(path, options)
+        }
+
+        fn create_dir(&self, path: &Path) -> io::Result<()> {
+            self.inner.create_dir(path)
+        }
+
+        fn list(&self, path: &Path) -> io::Result<Vec<OsString>> {
+            self.inner.list(path)
         }
+
+        fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+            self.stats.fetch_add(1, Ordering::SeqCst);
+            self.inner.metadata(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.inner.remove_file(path)
+        }
+
+        fn remove_dir(&self, path: &Path) -> io::Result<()> {
+            self.inner.remove_dir(path)
+        }
+
+        fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+            self.inner.set_permissions(path, permissions)
+        }
+
+        fn set_times(&self, path: &Path, times: FileTimes) -> io::Result<()> {
+            self.inner.set_times(path, times)
+        }
+    }
+
+    #[test]
+    fn lookup_listed_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 5,
+            dirs_per_dir: 1,
+            depth: 1,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let storage = StatCounter::default();
+        storage.inner.load(vault.path().join("d")).unwrap();
+        let stats = storage.stats.clone();
+        let vault = vault.with_storage(storage);
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        let subdir = shape.dir_path("", 0);
+        let subdir_inode = fuse.tree.insert_path(&subdir);
+
+        // Like `ls -l`: open the directory, list it, then look up every entry, returning how many
+        // times the lookups read metadata
+        let ls = |fuse: &mut FuseFileSystem, ino: Inode| -> u64 {
+            let fh = fuse.open_dir(ino).unwrap();
+            let mut names = Vec::new();
+            fuse.list_open_dir(ino, fh, 0, |_, _, _, name| {
+                names.push(name.to_os_string());
+                false
+            })
+            .unwrap();
+            let before = stats.load(Ordering::SeqCst);
+            let entries: Vec<_> = names
+                .iter()
+                .map(|name| fuse.lookup_entry(ino, name).unwrap())
+                .collect();
+            let lookup_stats = stats.load(Ordering::SeqCst) - before;
+            fuse.open_dirs.remove(&fh);
+
+            let dir = fuse.tree.get_path(ino).unwrap();
+            for (name, (inode, entry)) in names.iter().zip(entries) {
+                assert_eq!(fuse.tree.get_path(inode).unwrap(), dir.join(name));
+                let expected = fuse.fs.dir_entry(dir.join(name)).unwrap();
+                assert_eq!((entry.kind, entry.size), (expected.kind, expected.size));
+            }
+            lookup_stats
+        };
+
+        // Entries that were just listed are looked up without reading their metadata again
+        assert_eq!(ls(&mut fuse, ROOT_INODE), 0);
+        assert_eq!(ls(&mut fuse, subdir_inode), 0);
+
+        // Each listed entry is only served once
+        let fh = fuse.open_dir(ROOT_INODE).unwrap();
+        let name = shape.file_path("", 0).into_os_string();
+        fuse.lookup_entry(ROOT_INODE, &name).unwrap();
+        let before = stats.load(Ordering::SeqCst);
+        fuse.lookup_entry(ROOT_INODE, &name).unwrap();
+        assert!(stats.load(Ordering::SeqCst) > before);
+        fuse.open_dirs.remove(&fh);
+
+        // A change drops what was listed in the directory it's in and below it, but nothing else
+        let listed = |fuse: &FuseFileSystem, fh| fuse.open_dirs[&fh].listed.len();
+        let root_fh = fuse.open_dir(ROOT_INODE).unwrap();
+        let subdir_fh = fuse.open_dir(subdir_inode).unwrap();
+        fuse.forget_listed(&shape.file_path(&subdir, 0));
+        assert_eq!(listed(&fuse, subdir_fh), 0);
+        assert_eq!(listed(&fuse, root_fh), shape.files_per_dir + 1);
+        fuse.open_dirs.remove(&subdir_fh);
+        let subdir_fh = fuse.open_dir(subdir_inode).unwrap();
+        fuse.forget_listed(&subdir);
+        assert_eq!(listed(&fuse, subdir_fh), 0);
+        assert_eq!(listed(&fuse, root_fh), 0);
+        fuse.open_dirs.clear();
+
+        // Opening a file may truncate it, and which directory a written file is in isn't known,
+        // so writing drops everything
+        let subdir_fh = fuse.open_dir(subdir_inode).unwrap();
+        let file = fuse.tree.insert_path(shape.file_path(&subdir, 0));
+        let file_fh = fuse.open_handle(file, libc::O_RDWR).unwrap();
+        assert_eq!(listed(&fuse, subdir_fh), 0);
+        let root_fh = fuse.open_dir(ROOT_INODE).unwrap();
+        fuse.write_data(file_fh, 0, b"data").unwrap();
+        assert_eq!(listed(&fuse, root_fh), 0);
+        fuse.open_dirs.clear();
+
+        // Listings older than the TTL aren't used either
+        let fh = fuse.open_dir(ROOT_INODE).unwrap();
+        fuse.open_dirs.get_mut(&fh).unwrap().listed_at -= TTL;
+        let before = stats.load(Ordering::SeqCst);
+        fuse.lookup_entry(ROOT_INODE, &name).unwrap();
+        assert!(stats.load(Ordering::SeqCst) > before);
+    }
+
+    #[test]
+    fn open_dir_unlink_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 10,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let mut fuse = FuseFileSystem::new(EncryptedFileSystem::new(&vault));
+        let retained = |fuse: &FuseFileSystem| -> usize {
+            fuse.open_dirs
+                .values()
+                .map(|open_dir| open_dir.entries.len())
+                .sum()
+        };
+
+        // Handles only keep one name and kind per entry
+        let fh = fuse.open_dir(ROOT_INODE).unwrap();
+        assert_eq!(retained(&fuse), shape.files_per_dir);
+        let other_fh = fuse.open_dir(ROOT_INODE).unwrap();
+        assert_eq!(retained(&fuse), 2 * shape.files_per_dir);
+        fuse.open_dirs.remove(&other_fh);
+        assert_eq!(retained(&fuse), shape.files_per_dir);
+
+        // Entries unlinked after the directory was opened are still listed, at the same offsets
+        let mut listed = Vec::new();
+        fuse.list_open_dir(ROOT_INODE, fh, 0, |_, next_offset, kind, name| {
+            listed.push((name.to_os_string(), kind, next_offset));
+            listed.len() == 3
+        })
+        .unwrap();
+        for (name, _, _) in &listed {
+            fuse.fs.unlink("/", name).unwrap();
+        }
+        let mut rest = Vec::new();
+        fuse.list_open_dir(ROOT_INODE, fh, 3, |_, next_offset, kind, name| {
+            rest.push((name.to_os_string(), kind, next_offset));
+            false
+        })
+        .unwrap();
+        assert_eq!(rest.len(), shape.files_per_dir - 3);
+        assert_eq!(rest[0].2, 4);
+        assert!(rest.iter().all(|(_, kind, _)| *kind == FileKind::File));
+        let mut all = Vec::new();
+        fuse.list_open_dir(ROOT_INODE, fh, 0, |_, _, _, name| {
+            all.push(name.to_os_string());
+            false
+        })
+        .unwrap();
+        assert_eq!(all.len(), shape.files_per_dir);
+        assert!(matches!(
+            fuse.fs.dir_entry(Path::new("/").join(&listed[0].0)),
+            Err(FsError::NotFound)
+        ));
+
+        // A new handle leaves them out
+        let new_fh = fuse.open_dir(ROOT_INODE).unwrap();
+        assert_eq!(
+            fuse.open_dirs[&new_fh].entries.len(),
+            shape.files_per_dir - 3
+        );
     }
 }
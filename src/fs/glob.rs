@@ -0,0 +1,320 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Report;
+use globset::{GlobBuilder, GlobMatcher};
+
+use super::{check_path, EncryptedFileSystem, FileKind, FsError};
+use crate::Result;
+
+// How one component of a pattern is matched.
+enum Step {
+    // Looked up directly, without listing the directory
+    Literal(OsString),
+    // Matched against the names in the directory
+    Name(GlobMatcher),
+    // The rest of the pattern starts with `**` or can't be split, so the whole subtree is walked
+    Subtree,
+}
+
+// Work left to do, in the order it's popped off the stack.
+enum Pending {
+    Match(PathBuf),
+    Error(Report),
+    // A directory to apply the step with this index to
+    Dir(PathBuf, usize),
+}
+
+struct Compiled {
+    matcher: GlobMatcher,
+    steps: Vec<Step>,
+}
+
+/// An iterator over the cleartext paths below a directory that match a glob pattern, created by
+/// [`EncryptedFileSystem::glob`].
+///
+/// Patterns use the syntax of the globset crate, relative to the root, except that `*` and `?`
+/// don't match `/`. Only the directories the pattern can reach are read: literal components are
+/// looked up directly, components with wildcards list a single directory, and `**` walks the
+/// subtree below it. Matches are sorted by path within each directory.
+///
+/// Errors are yielded as items, and the search continues afterwards.
+pub struct Glob<'v> {
+    fs: EncryptedFileSystem<'v>,
+    root: PathBuf,
+    pattern: String,
+    case_insensitive: bool,
+    max_depth: Option<usize>,
+    compiled: Option<Compiled>,
+    stack: Vec<Pending>,
+    started: bool,
+}
+
+impl Glob<'_> {
+    /// Match names regardless of case. Literal components can't be looked up directly then, so
+    /// their directories are listed instead.
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Only let `**` descend `max_depth` levels into the directory it starts in.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    fn compile(&self, pattern: &str) -> Result<GlobMatcher> {
+        Ok(GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .case_insensitive(self.case_insensitive)
+            .build()?
+            .compile_matcher())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        check_path(&self.root)?;
+        let pattern = self.pattern.trim_start_matches('/');
+        let matcher = self.compile(pattern)?;
+
+        let mut steps = Vec::new();
+        for component in split(pattern) {
+            if component == "**" || component.contains('/') {
+                steps.push(Step::Subtree);
+                break;
+            } else if component.is_empty() || component == "." {
+                continue;
+            } else if !self.case_insensitive && !component.contains(is_special) {
+                steps.push(Step::Literal(component.into()));
+            } else {
+                steps.push(Step::Name(self.compile(component)?));
+            }
+        }
+
+        // An empty pattern matches nothing
+        if !steps.is_empty() {
+            self.stack.push(Pending::Dir(self.root.clone(), 0));
+        }
+        self.compiled = Some(Compiled { matcher, steps });
+        Ok(())
+    }
+
+    // Apply the step with index `step` to the directory `dir`, adding what it finds to the stack.
+    fn expand(&mut self, dir: PathBuf, step: usize) {
+        // Ok to unwrap, this is only called after compiling the pattern
+        let compiled = self.compiled.as_ref().unwrap();
+        let is_last = step + 1 == compiled.steps.len();
+        let next = |path: PathBuf, kind: FileKind| match is_last {
+            true => Some(Pending::Match(path)),
+            false => (kind == FileKind::Directory).then_some(Pending::Dir(path, step + 1)),
+        };
+        let mut found = Vec::new();
+
+        match &compiled.steps[step] {
+            Step::Literal(name) => {
+                let path = dir.join(name);
+                match self.fs.dir_entry(&path) {
+                    Ok(entry) => found.extend(next(path, entry.kind)),
+                    Err(FsError::NotFound | FsError::NotADirectory) => {}
+                    Err(err) => found.push(Pending::Error(err.into())),
+                }
+            }
+            Step::Name(matcher) => match self.fs.read_dir(&dir) {
+                Ok(entries) => {
+                    for result in entries {
+                        match result {
+                            Ok((path, entry)) => {
+                                if path.file_name().is_some_and(|name| matcher.is_match(name)) {
+                                    found.extend(next(path, entry.kind));
+                                }
+                            }
+                            Err(err) => found.push(Pending::Error(err.into())),
+                        }
+                    }
+                }
+                Err(err) => found.push(Pending::Error(err.into())),
+            },
+            Step::Subtree => {
+                let mut walk = self.fs.walk(&dir);
+                if let Some(max_depth) = self.max_depth {
+                    walk = walk.max_depth(max_depth);
+                }
+
+                // The walk isn't ordered, so its matches are sorted before they're yielded
+                let mut matches = Vec::new();
+                for result in walk {
+                    match result {
+                        Ok(entry) => {
+                            let relative =
+                                entry.path.strip_prefix(&self.root).unwrap_or(&entry.path);
+                            if compiled.matcher.is_match(relative) {
+                                matches.push(entry.path);
+                            }
+                        }
+                        Err(err) => found.push(Pending::Error(err)),
+                    }
+                }
+                matches.sort();
+                found.extend(matches.into_iter().map(Pending::Match));
+            }
+        }
+
+        self.stack.extend(found.into_iter().rev());
+    }
+}
+
+impl Iterator for Glob<'_> {
+    type Item = Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            if let Err(err) = self.start() {
+                return Some(Err(err));
+            }
+        }
+
+        loop {
+            match self.stack.pop()? {
+                Pending::Match(path) => return Some(Ok(path)),
+                Pending::Error(err) => return Some(Err(err)),
+                Pending::Dir(dir, step) => self.expand(dir, step),
+            }
+        }
+    }
+}
+
+// Whether a character has a special meaning in a glob pattern.
+fn is_special(c: char) -> bool {
+    matches!(c, '*' | '?' | '[' | ']' | '{' | '}' | '\\')
+}
+
+// Split a pattern at the slashes outside of character classes and alternations, so a component
+// only contains a slash if it can match more than one level.
+fn split(pattern: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    let (mut start, mut depth, mut escaped) = (0, 0_usize, false);
+    for (i, c) in pattern.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth = depth.saturating_sub(1),
+            '/' if depth == 0 => {
+                components.push(&pattern[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    components.push(&pattern[start..]);
+    components
+}
+
+impl<'v> EncryptedFileSystem<'v> {
+    /// Find the paths below the cleartext directory `root` that match the glob `pattern`, without
+    /// walking more of the vault than needed. See [`Glob`] for details.
+    pub fn glob(&self, pattern: impl Into<String>, root: impl AsRef<Path>) -> Glob<'v> {
+        Glob {
+            fs: self.clone(),
+            root: root.as_ref().to_path_buf(),
+            pattern: pattern.into(),
+            case_insensitive: false,
+            max_depth: None,
+            compiled: None,
+            stack: Vec::new(),
+            started: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::{KdfParams, Vault, VaultCreateOptions};
+
+    #[test]
+    fn glob_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        for path in [
+            "/photos/2023/summer/beach.jpg",
+            "/photos/2023/summer/notes.txt",
+            "/photos/2023/winter/deep/snow.JPG",
+            "/photos/2023/cover.jpg",
+            "/photos/2024/cover.jpg",
+            "/other/2023/cover.jpg",
+            "/readme.txt",
+        ] {
+            let path = Path::new(path);
+            let mut parent = PathBuf::from("/");
+            for name in path.parent().unwrap().iter().skip(1) {
+                let _ = fs.mkdir(&parent, name, Permissions::from_mode(0o755));
+                parent.push(name);
+            }
+            fs.mknod(
+                &parent,
+                path.file_name().unwrap(),
+                Permissions::from_mode(0o644),
+            )
+            .unwrap();
+        }
+        let glob = |glob: Glob| -> Vec<_> { glob.map(Result::unwrap).collect() };
+        let paths = |paths: &[&str]| -> Vec<_> { paths.iter().map(PathBuf::from).collect() };
+
+        // Nothing below /other is read, so breaking it doesn't matter
+        let (_, other_dir) = fs
+            .translator
+            .get_dir_id_and_path(Path::new("/other/2023"))
+            .unwrap();
+        std::fs::remove_dir_all(other_dir).unwrap();
+
+        assert_eq!(
+            glob(fs.glob("photos/2023/**/*.jpg", "/")),
+            paths(&["/photos/2023/cover.jpg", "/photos/2023/summer/beach.jpg"])
+        );
+        assert_eq!(
+            glob(fs.glob("/photos/*/cover.jpg", "/")),
+            paths(&["/photos/2023/cover.jpg", "/photos/2024/cover.jpg"])
+        );
+        assert_eq!(glob(fs.glob("*.txt", "/")), paths(&["/readme.txt"]));
+        assert_eq!(
+            glob(fs.glob("2023/{summer,winter}", "/photos")),
+            paths(&["/photos/2023/summer", "/photos/2023/winter"])
+        );
+        assert!(glob(fs.glob("photos/2025/**", "/")).is_empty());
+        assert!(glob(fs.glob("readme.txt/x", "/")).is_empty());
+
+        assert_eq!(
+            glob(fs.glob("PHOTOS/2023/**/*.jpg", "/").case_insensitive(true)),
+            paths(&[
+                "/photos/2023/cover.jpg",
+                "/photos/2023/summer/beach.jpg",
+                "/photos/2023/winter/deep/snow.JPG",
+            ])
+        );
+        assert_eq!(
+            glob(
+                fs.glob("photos/2023/**/*.jpg", "/")
+                    .case_insensitive(true)
+                    .max_depth(2)
+            ),
+            paths(&["/photos/2023/cover.jpg", "/photos/2023/summer/beach.jpg"])
+        );
+
+        // Walking everything does reach the broken directory
+        assert!(fs.glob("**/*.jpg", "/").any(|result| result.is_err()));
+        let mut invalid = fs.glob("photos/[", "/");
+        assert!(invalid.next().unwrap().is_err());
+        assert!(invalid.next().is_none());
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::SystemTime,
+};
+
+use crate::{crypto::FileHeader, storage::Metadata};
+
+/// Which ciphertext file a header was read from. A file that's replaced or written to gets a new
+/// identity, so its cached header isn't used anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct FileIdentity {
+    dev: u64,
+    ino: u64,
+    modified: SystemTime,
+}
+
+impl FileIdentity {
+    /// The identity of a ciphertext file, if the storage keeps modification times.
+    pub fn new(metadata: &Metadata) -> Option<Self> {
+        Some(Self {
+            dev: metadata.dev,
+            ino: metadata.ino,
+            modified: metadata.modified?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct CachedHeader {
+    identity: FileIdentity,
+    // Zeroized when the entry is dropped
+    header: FileHeader,
+    last_used: u64,
+}
+
+/// Decrypted file headers by ciphertext path, so opening the same file again doesn't decrypt its
+/// header again. Holds up to `capacity` headers, dropping the least recently used one first.
+#[derive(Debug, Default)]
+pub(super) struct HeaderCache {
+    capacity: usize,
+    entries: Mutex<HashMap<PathBuf, CachedHeader>>,
+    // Incremented on every lookup, to find the least recently used entry
+    clock: AtomicU64,
+}
+
+impl HeaderCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// The cached header of the file at `ciphertext_path`, if it still has the same identity.
+    pub fn get(&self, ciphertext_path: &Path, identity: &FileIdentity) -> Option<FileHeader> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = entries.get_mut(ciphertext_path)?;
+        if entry.identity != *identity {
+            entries.remove(ciphertext_path);
+            return None;
+        }
+
+        entry.last_used = now;
+        Some(entry.header.clone())
+    }
+
+    pub fn insert(&self, ciphertext_path: &Path, identity: FileIdentity, header: FileHeader) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= self.capacity && !entries.contains_key(ciphertext_path) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        let last_used = self.clock.load(Ordering::Relaxed);
+        entries.insert(
+            ciphertext_path.to_path_buf(),
+            CachedHeader {
+                identity,
+                header,
+                last_used,
+            },
+        );
+    }
+
+    /// Drop every cached header, zeroizing their content keys.
+    pub fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+}
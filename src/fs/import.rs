@@ -0,0 +1,272 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, Permissions},
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use color_eyre::eyre::bail;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use super::{check_path, Durability, EncryptedFileSystem, FileKind};
+use crate::{crypto::FileCryptor, storage::OpenOptions, util, Result};
+
+/// Options for [`EncryptedFileSystem::import_dir`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportOptions {
+    /// How many files to encrypt at the same time. Zero uses one thread per CPU.
+    pub threads: usize,
+}
+
+/// A summary of a [`Vault::import_tar`](crate::Vault::import_tar) or
+/// [`EncryptedFileSystem::import_dir`] run.
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// The total size of the imported file contents.
+    pub bytes: u64,
+    /// How many files and symlinks were already in the vault with the same size, modification
+    /// time, or target, and were left alone.
+    pub unchanged: u64,
+    /// Source paths of entries that were skipped, because they aren't files, directories, or
+    /// symlinks.
+    pub skipped: Vec<PathBuf>,
+    /// Source paths of entries that could not be imported, along with the reason.
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl ImportReport {
+    fn merge(&mut self, other: ImportReport) {
+        self.files += other.files;
+        self.directories += other.directories;
+        self.symlinks += other.symlinks;
+        self.bytes += other.bytes;
+        self.unchanged += other.unchanged;
+        self.skipped.extend(other.skipped);
+        self.errors.extend(other.errors);
+    }
+}
+
+impl<'v> EncryptedFileSystem<'v> {
+    /// Encrypt the contents of the directory `src` on the local file system into the cleartext
+    /// directory `dst`, which is created if needed. Permissions, timestamps, and symlinks are
+    /// kept, and files are encrypted on multiple threads.
+    ///
+    /// An interrupted import can be resumed by running it again: files that are already in the
+    /// vault with the same size and modification time are skipped, and other existing files are
+    /// replaced. Entries that can't be imported are listed in the report rather than aborting the
+    /// import.
+    pub fn import_dir(
+        &self,
+        src: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+        options: ImportOptions,
+    ) -> Result<ImportReport> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        check_path(dst)?;
+        let src_metadata = fs::metadata(src)?;
+        if !src_metadata.is_dir() {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory).into());
+        }
+        self.import_dir_entry(dst)?;
+
+        // The root directory's own metadata can't be changed
+        let mut dirs = Vec::new();
+        if dst.parent().is_some() {
+            dirs.push((dst.to_path_buf(), src_metadata));
+        }
+        let mut files = Vec::new();
+        let mut report = ImportReport::default();
+
+        for entry in WalkDir::new(src).min_depth(1) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    let path = err.path().unwrap_or(src).to_path_buf();
+                    report.errors.push((path, err.to_string()));
+                    continue;
+                }
+            };
+
+            // Ok to unwrap, the walk only yields paths below its root
+            let target = dst.join(entry.path().strip_prefix(src).unwrap());
+            let file_type = entry.file_type();
+            let result: Result<()> = entry.metadata().map_err(Into::into).and_then(|metadata| {
+                if file_type.is_dir() {
+                    self.import_dir_entry(&target)?;
+                    dirs.push((target, metadata));
+                    report.directories += 1;
+                } else if file_type.is_file() {
+                    files.push((entry.path().to_path_buf(), target, metadata));
+                } else if file_type.is_symlink() {
+                    match self.import_symlink(entry.path(), &target, &metadata)? {
+                        true => report.symlinks += 1,
+                        false => report.unchanged += 1,
+                    }
+                } else {
+                    report.skipped.push(entry.path().to_path_buf());
+                }
+                Ok(())
+            });
+
+            if let Err(err) = result {
+                report.errors.push((entry.into_path(), err.to_string()));
+            }
+        }
+
+        let threads = match options.threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads,
+        };
+        let next = AtomicUsize::new(0);
+        let partials: Vec<ImportReport> = thread::scope(|s| {
+            let handles: Vec<_> = (0..threads.min(files.len()))
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut partial = ImportReport::default();
+                        while let Some((src, target, metadata)) =
+                            files.get(next.fetch_add(1, Ordering::Relaxed))
+                        {
+                            match self.import_file(src, target, metadata) {
+                                Ok(true) => {
+                                    partial.files += 1;
+                                    partial.bytes += metadata.len();
+                                }
+                                Ok(false) => partial.unchanged += 1,
+                                Err(err) => partial.errors.push((src.clone(), err.to_string())),
+                            }
+                        }
+                        partial
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|err| std::panic::resume_unwind(err))
+                })
+                .collect()
+        });
+        for partial in partials {
+            report.merge(partial);
+        }
+
+        // Deepest directories first, so adding entries doesn't change their parents' times again
+        for (path, metadata) in dirs.into_iter().rev() {
+            if let Err(err) = self.copy_metadata(&path, &metadata.into()) {
+                report.errors.push((path, err.to_string()));
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Create the directory `dst` unless it already exists.
+    fn import_dir_entry(&self, dst: &Path) -> Result<()> {
+        let (Some(parent), Some(name)) = (dst.parent(), dst.file_name()) else {
+            // The root directory always exists
+            return Ok(());
+        };
+
+        match self.dir_entry(dst) {
+            Ok(entry) if entry.kind == FileKind::Directory => Ok(()),
+            Ok(_) => Err(io::Error::from(io::ErrorKind::AlreadyExists).into()),
+            // Permissions are copied after the contents, in case they don't allow adding entries
+            Err(_) => self
+                .mkdir(parent, name, Permissions::from_mode(0o700))
+                .map(|_| ()),
+        }
+    }
+
+    // Recreate the symlink `src` at `dst`, returning false if it's already there.
+    fn import_symlink(&self, src: &Path, dst: &Path, metadata: &fs::Metadata) -> Result<bool> {
+        // Ok to unwrap, dst is always below the import destination
+        let (parent, name) = (dst.parent().unwrap(), dst.file_name().unwrap());
+        let target = fs::read_link(src)?;
+        if self.dir_entry(dst).is_ok() {
+            if self
+                .link_target(dst)
+                .is_ok_and(|existing| existing == target)
+            {
+                return Ok(false);
+            }
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+
+        self.symlink(parent, name, target)?;
+        self.copy_metadata(dst, &metadata.clone().into())?;
+        Ok(true)
+    }
+
+    // Encrypt the file `src` into `dst`, returning false if it's already there.
+    fn import_file(&self, src: &Path, dst: &Path, metadata: &fs::Metadata) -> Result<bool> {
+        // Ok to unwrap, dst is always below the import destination
+        let (parent, name) = (dst.parent().unwrap(), dst.file_name().unwrap());
+        match self.dir_entry(dst) {
+            Ok(existing) if existing.kind == FileKind::File => {
+                if existing.size == metadata.len()
+                    && existing.metadata.modified().ok() == metadata.modified().ok()
+                {
+                    return Ok(false);
+                }
+            }
+            Ok(_) => return Err(io::Error::from(io::ErrorKind::AlreadyExists).into()),
+            Err(_) => {}
+        }
+
+        // Written under a temporary name first, so an interrupted import never leaves a partially
+        // written file that looks complete
+        let tmp_name = OsString::from(format!(".import-{}.tmp", Uuid::new_v4().simple()));
+        if let Err(err) = self.encrypt_file(src, parent, &tmp_name, metadata) {
+            let _ = self.unlink(parent, &tmp_name);
+            return Err(err);
+        }
+        self.rename(parent, &tmp_name, parent, name)?;
+
+        Ok(true)
+    }
+
+    fn encrypt_file(
+        &self,
+        src: &Path,
+        parent: &Path,
+        name: &OsString,
+        metadata: &fs::Metadata,
+    ) -> Result<()> {
+        // Permissions are copied after writing, in case the file is read-only
+        self.mknod(parent, name, Permissions::from_mode(0o600))?;
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let mut dst_file = self.open_file(parent.join(name), options, false)?;
+        let mut src_file = File::open(src)?;
+
+        // Writing whole chunks at a time means each chunk is only encrypted once
+        let mut chunk = vec![0; self.vault.cryptor().max_chunk_len()];
+        loop {
+            let (full, len) = util::try_read_exact(&mut src_file, &mut chunk)?;
+            dst_file.write_all(&chunk[..len])?;
+            if !full {
+                break;
+            }
+        }
+
+        if dst_file.len()? != metadata.len() {
+            bail!("{} changed during the import", src.display());
+        }
+        if self.durability == Durability::Safe {
+            dst_file.sync_all()?;
+        }
+        drop(dst_file);
+
+        self.copy_metadata(parent.join(name), &metadata.clone().into())
+    }
+}
@@ -0,0 +1,1873 @@
+//! An NFSv3 server (RFC 1813), for mounting a vault where FUSE isn't available, like in
+//! containers without `/dev/fuse`.
+//!
+//! The NFS and MOUNT programs are both served over TCP on the same port, so the client never needs
+//! a portmapper. File handles are the inode numbers handed out by [`DirTree`]. NFS clients never
+//! say when they're done with a handle, so the least recently used ones are forgotten once there
+//! are too many, see [`NfsVaultServer::max_handles`].
+
+mod xdr;
+
+use std::{
+    collections::BTreeSet,
+    ffi::OsStr,
+    fs::Permissions,
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{Ipv4Addr, TcpListener},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{OpenOptionsExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use xdr::{XdrReader, XdrWriter};
+use zeroize::Zeroizing;
+
+use super::{
+    dir_tree::{DirTree, Inode, ROOT_INODE},
+    DirEntry, EncryptedFileSystem, FileKind, FsError,
+};
+use crate::{
+    storage::{FileTimes, OpenOptions},
+    util, Result,
+};
+use color_eyre::eyre::bail;
+
+const NFS_PROGRAM: u32 = 100003;
+const MOUNT_PROGRAM: u32 = 100005;
+// Both programs are only served in version 3
+const VERSION: u32 = 3;
+
+const RPC_VERSION: u32 = 2;
+const CALL: u32 = 0;
+const REPLY: u32 = 1;
+const MSG_ACCEPTED: u32 = 0;
+const MSG_DENIED: u32 = 1;
+const RPC_MISMATCH: u32 = 0;
+const SUCCESS: u32 = 0;
+const AUTH_NONE: u32 = 0;
+const AUTH_SYS: u32 = 1;
+const AUTH_ERROR: u32 = 1;
+const AUTH_TOOWEAK: u32 = 5;
+const MAX_AUTH_LEN: usize = 400;
+
+// The most data a single READ or WRITE moves, which is also what clients are told to use
+const MAX_IO: u32 = 1024 * 1024;
+// Room for the RPC header and the other arguments of a WRITE
+const MAX_RECORD_LEN: usize = MAX_IO as usize + 4096;
+// Room for the status, attributes, and verifier around the entries of a READDIR reply
+const READDIR_OVERHEAD: usize = 256;
+const MAX_HANDLE_LEN: usize = 64;
+const MAX_NAME_LEN: usize = 255;
+const MAX_PATH_LEN: usize = 4096;
+
+const NFS3_OK: u32 = 0;
+const NFS3ERR_NOENT: u32 = 2;
+const NFS3ERR_IO: u32 = 5;
+const NFS3ERR_ACCES: u32 = 13;
+const NFS3ERR_EXIST: u32 = 17;
+const NFS3ERR_NOTDIR: u32 = 20;
+const NFS3ERR_ISDIR: u32 = 21;
+const NFS3ERR_INVAL: u32 = 22;
+const NFS3ERR_FBIG: u32 = 27;
+const NFS3ERR_NOSPC: u32 = 28;
+const NFS3ERR_ROFS: u32 = 30;
+const NFS3ERR_NAMETOOLONG: u32 = 63;
+const NFS3ERR_NOTEMPTY: u32 = 66;
+const NFS3ERR_STALE: u32 = 70;
+const NFS3ERR_BADHANDLE: u32 = 10001;
+const NFS3ERR_NOT_SYNC: u32 = 10002;
+const NFS3ERR_NOTSUPP: u32 = 10004;
+const NFS3ERR_TOOSMALL: u32 = 10005;
+const NFS3ERR_JUKEBOX: u32 = 10008;
+
+const NF3REG: u32 = 1;
+const NF3DIR: u32 = 2;
+const NF3LNK: u32 = 5;
+
+const ACCESS3_READ: u32 = 0x01;
+const ACCESS3_LOOKUP: u32 = 0x02;
+const ACCESS3_MODIFY: u32 = 0x04;
+const ACCESS3_EXTEND: u32 = 0x08;
+const ACCESS3_DELETE: u32 = 0x10;
+const ACCESS3_EXECUTE: u32 = 0x20;
+
+const UNSTABLE: u32 = 0;
+const FILE_SYNC: u32 = 2;
+
+const UNCHECKED: u32 = 0;
+const GUARDED: u32 = 1;
+const EXCLUSIVE: u32 = 2;
+
+const DONT_CHANGE: u32 = 0;
+const SET_TO_SERVER_TIME: u32 = 1;
+const SET_TO_CLIENT_TIME: u32 = 2;
+
+const FSF3_SYMLINK: u32 = 0x02;
+const FSF3_HOMOGENEOUS: u32 = 0x08;
+const FSF3_CANSETTIME: u32 = 0x10;
+
+const MNT3_OK: u32 = 0;
+const MNT3ERR_NOENT: u32 = 2;
+const MAX_MOUNT_PATH_LEN: usize = 1024;
+
+type NfsResult<T> = std::result::Result<T, u32>;
+
+// Why a call wasn't run, as its accept_stat, or its auth_stat if it was denied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejected {
+    ProgUnavail = 1,
+    ProgMismatch = 2,
+    ProcUnavail = 3,
+    GarbageArgs = 4,
+    TooWeak = AUTH_TOOWEAK as isize,
+}
+
+impl From<io::Error> for Rejected {
+    fn from(_: io::Error) -> Self {
+        Self::GarbageArgs
+    }
+}
+
+// Who a call is from, according to the client.
+struct Credentials {
+    uid: u32,
+    gid: u32,
+    gids: Vec<u32>,
+}
+
+impl Credentials {
+    // Calls without AUTH_SYS credentials are treated as coming from nobody
+    const NOBODY: u32 = 65534;
+
+    fn read(args: &mut XdrReader) -> io::Result<Self> {
+        let flavor = args.u32()?;
+        let body = args.opaque(MAX_AUTH_LEN)?;
+        if flavor != AUTH_SYS {
+            return Ok(Self {
+                uid: Self::NOBODY,
+                gid: Self::NOBODY,
+                gids: Vec::new(),
+            });
+        }
+
+        let mut body = XdrReader::new(body);
+        // The stamp and machine name
+        body.u32()?;
+        body.opaque(255)?;
+        let (uid, gid) = (body.u32()?, body.u32()?);
+        let count = body.u32()?;
+        if count > 16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many groups in credentials",
+            ));
+        }
+        let gids = (0..count).map(|_| body.u32()).collect::<io::Result<_>>()?;
+
+        Ok(Self { uid, gid, gids })
+    }
+
+    fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.gids.contains(&gid)
+    }
+}
+
+// Attributes to set, from a sattr3. Owners aren't stored in vaults, so they're ignored like they
+// are through FUSE.
+#[derive(Debug, Default)]
+struct SetAttrs {
+    mode: Option<u32>,
+    size: Option<u64>,
+    accessed: Option<SystemTime>,
+    modified: Option<SystemTime>,
+}
+
+impl SetAttrs {
+    fn read(args: &mut XdrReader) -> io::Result<Self> {
+        let mode = args.bool()?.then(|| args.u32()).transpose()?;
+        // The uid and gid
+        for _ in 0..2 {
+            if args.bool()? {
+                args.u32()?;
+            }
+        }
+        let size = args.bool()?.then(|| args.u64()).transpose()?;
+        let accessed = read_set_time(args)?;
+        let modified = read_set_time(args)?;
+
+        Ok(Self {
+            mode,
+            size,
+            accessed,
+            modified,
+        })
+    }
+}
+
+fn read_set_time(args: &mut XdrReader) -> io::Result<Option<SystemTime>> {
+    match args.u32()? {
+        DONT_CHANGE => Ok(None),
+        SET_TO_SERVER_TIME => Ok(Some(SystemTime::now())),
+        SET_TO_CLIENT_TIME => Ok(Some(read_time(args)?)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid time to set",
+        )),
+    }
+}
+
+fn read_time(args: &mut XdrReader) -> io::Result<SystemTime> {
+    let (seconds, nanos) = (args.u32()?, args.u32()?);
+    Ok(UNIX_EPOCH + Duration::new(seconds.into(), nanos))
+}
+
+// Names longer than a path are garbage, while shorter ones the file system can't store are
+// refused by it.
+fn read_name<'a>(args: &mut XdrReader<'a>) -> io::Result<&'a OsStr> {
+    Ok(OsStr::from_bytes(args.opaque(MAX_PATH_LEN)?))
+}
+
+// A time as an nfstime3, whose seconds run out in 2106. Times before the epoch are sent as the
+// epoch.
+fn nfs_time(time: SystemTime) -> (u32, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs() as u32, since_epoch.subsec_nanos())
+}
+
+fn write_time(res: &mut XdrWriter, time: SystemTime) {
+    let (seconds, nanos) = nfs_time(time);
+    res.u32(seconds).u32(nanos);
+}
+
+// Encode `entry`, which has the inode `inode`, as an fattr3.
+fn write_fattr(res: &mut XdrWriter, inode: Inode, entry: &DirEntry) {
+    let file_type = match entry.kind {
+        FileKind::File => NF3REG,
+        FileKind::Directory => NF3DIR,
+        FileKind::Symlink => NF3LNK,
+    };
+    let metadata = &entry.metadata;
+    res.u32(file_type)
+        .u32(metadata.mode & 0o7777)
+        // Ciphertext link counts mean nothing in the cleartext, like through FUSE
+        .u32(1)
+        .u32(metadata.uid)
+        .u32(metadata.gid)
+        .u64(entry.size)
+        .u64(metadata.blocks.saturating_mul(512))
+        // The device numbers and the file system ID
+        .u32(0)
+        .u32(0)
+        .u64(0)
+        .u64(inode);
+    write_time(res, entry.accessed);
+    write_time(res, entry.modified);
+    write_time(res, entry.changed);
+}
+
+// The NFS status for a failed operation, logging anything unexpected.
+fn status(err: impl Into<FsError>) -> u32 {
+    let err = err.into();
+    match err.kind() {
+        io::ErrorKind::NotFound => NFS3ERR_NOENT,
+        io::ErrorKind::PermissionDenied => NFS3ERR_ACCES,
+        io::ErrorKind::AlreadyExists => NFS3ERR_EXIST,
+        io::ErrorKind::NotADirectory => NFS3ERR_NOTDIR,
+        io::ErrorKind::IsADirectory => NFS3ERR_ISDIR,
+        io::ErrorKind::DirectoryNotEmpty => NFS3ERR_NOTEMPTY,
+        io::ErrorKind::InvalidInput => NFS3ERR_INVAL,
+        io::ErrorKind::InvalidFilename => NFS3ERR_NAMETOOLONG,
+        io::ErrorKind::ReadOnlyFilesystem => NFS3ERR_ROFS,
+        io::ErrorKind::StorageFull => NFS3ERR_NOSPC,
+        io::ErrorKind::FileTooLarge => NFS3ERR_FBIG,
+        io::ErrorKind::Unsupported => NFS3ERR_NOTSUPP,
+        // The client tries again later, e.g. once a locked file is free or the file system thaws
+        io::ErrorKind::WouldBlock => NFS3ERR_JUKEBOX,
+        _ => {
+            tracing::error!("{err:?}");
+            NFS3ERR_IO
+        }
+    }
+}
+
+// The status of a finished operation.
+fn status_of<T>(result: &NfsResult<T>) -> u32 {
+    match result {
+        Ok(_) => NFS3_OK,
+        Err(status) => *status,
+    }
+}
+
+/// Which users an [`NfsVaultServer`] answers, going by the AUTH_SYS credentials of their calls.
+///
+/// Clients are trusted to send the right credentials, like with any NFSv3 server without
+/// Kerberos. This keeps other users of a machine whose NFS client is trusted out of the vault,
+/// but anyone who can reach the server directly can claim to be an allowed user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NfsAuth {
+    /// Answer calls from any user. Calls without AUTH_SYS credentials are from `nobody`.
+    AnyUser,
+    /// Only answer calls from these user IDs, besides the NULL procedure that clients use to
+    /// check the server is there.
+    Users(BTreeSet<u32>),
+}
+
+impl NfsAuth {
+    // Only the user running the server, since it's their vault.
+    fn current_user() -> Self {
+        // SAFETY: getuid always succeeds
+        Self::Users(BTreeSet::from([unsafe { libc::getuid() }]))
+    }
+
+    fn allows(&self, credentials: &Credentials) -> bool {
+        match self {
+            Self::AnyUser => true,
+            Self::Users(uids) => uids.contains(&credentials.uid),
+        }
+    }
+}
+
+// How many connections are being served, so accepting more can wait for one to close.
+#[derive(Debug, Default)]
+struct Connections {
+    count: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn acquire(&self, max: usize) {
+        let count = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut count = self
+            .closed
+            .wait_while(count, |count| *count >= max)
+            .unwrap_or_else(PoisonError::into_inner);
+        *count += 1;
+    }
+
+    fn release(&self) {
+        *self.count.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.closed.notify_one();
+    }
+}
+
+/// Serves the contents of a vault to NFSv3 clients over TCP, so it can be mounted without FUSE,
+/// e.g. with `mount -t nfs -o vers=3,tcp,port=<port>,mountport=<port>,nolock localhost:/ <dir>`.
+///
+/// By default, only calls from the user running the server are answered, see
+/// [`NfsVaultServer::auth`].
+pub struct NfsVaultServer<'v> {
+    fs: EncryptedFileSystem<'v>,
+    auth: NfsAuth,
+    tree: Mutex<DirTree>,
+    max_handles: usize,
+    max_connections: usize,
+    allow_remote: bool,
+    // Part of every file handle, so handles from an earlier run are refused as stale rather than
+    // pointing at whatever got their inode this time. Also the write verifier, which tells
+    // clients to resend unstable writes the server may have lost by restarting.
+    generation: u64,
+}
+
+impl<'v> NfsVaultServer<'v> {
+    pub fn new(fs: EncryptedFileSystem<'v>) -> Self {
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        Self {
+            fs,
+            auth: NfsAuth::current_user(),
+            tree: Mutex::new(DirTree::new()),
+            max_handles: 100_000,
+            max_connections: 16,
+            allow_remote: false,
+            generation,
+        }
+    }
+
+    /// Which users to answer. Calls from anyone else are denied with `AUTH_TOOWEAK`.
+    pub fn auth(mut self, auth: NfsAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// How many file handles to keep, 100,000 by default. Once there are more, the least recently
+    /// used ones are refused as stale. Clients look entries up again by name when that happens,
+    /// but reading or writing a file they already have open fails.
+    pub fn max_handles(mut self, max_handles: usize) -> Self {
+        self.max_handles = max_handles;
+        self
+    }
+
+    /// How many connections to serve at once, 16 by default. Further ones wait to be accepted
+    /// until another is closed.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Whether [`run`](Self::run) may serve a listener that isn't bound to a loopback address.
+    /// Anyone who can connect can claim to be an allowed user, so this should only be set when
+    /// every host that can reach the listener is trusted.
+    pub fn allow_remote(mut self, allow_remote: bool) -> Self {
+        self.allow_remote = allow_remote;
+        self
+    }
+
+    /// Serve on `127.0.0.1:port`, blocking for as long as the process runs.
+    pub fn serve(&self, port: u16) -> Result<()> {
+        self.run(TcpListener::bind((Ipv4Addr::LOCALHOST, port))?)
+    }
+
+    /// Serve connections from `listener`, each on its own thread, blocking for as long as the
+    /// process runs. Failing to accept a connection is logged and retried. The listener has to be bound to a loopback address unless
+    /// [`allow_remote`](Self::allow_remote) is set.
+    pub fn run(&self, listener: TcpListener) -> Result<()> {
+        let addr = listener.local_addr()?;
+        if !addr.ip().is_loopback() && !self.allow_remote {
+            bail!("refusing to serve NFS on non-loopback address {addr}");
+        }
+
+        tracing::info!(?addr, "serving NFS");
+        let connections = Connections::default();
+        thread::scope(|s| -> Result<()> {
+            loop {
+                connections.acquire(self.max_connections);
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        connections.release();
+                        // Running out of file descriptors doesn't last, so wait for some to be freed
+                        tracing::warn!("failed to accept NFS connection: {err}");
+                        thread::sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                };
+
+                let connections = &connections;
+                s.spawn(move || {
+                    if let Err(err) = self.serve_connection(&stream) {
+                        tracing::warn!("NFS connection failed: {err}");
+                    }
+                    connections.release();
+                });
+            }
+        })
+    }
+
+    // Answer the calls in the RPC records sent over `stream`, until it's closed.
+    fn serve_connection(&self, mut stream: impl Read + Write) -> io::Result<()> {
+        while let Some(record) = xdr::read_record(&mut stream, MAX_RECORD_LEN)? {
+            if let Some(reply) = self.call(&record) {
+                xdr::write_record(&mut stream, &reply)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The reply to the RPC message `record`, or `None` if it isn't a call.
+    fn call(&self, record: &[u8]) -> Option<Vec<u8>> {
+        let mut args = XdrReader::new(record);
+        let xid = args.u32().ok()?;
+        if args.u32().ok()? != CALL {
+            return None;
+        }
+
+        let mut reply = XdrWriter::new();
+        reply.u32(xid).u32(REPLY);
+        if args.u32().ok()? != RPC_VERSION {
+            reply
+                .u32(MSG_DENIED)
+                .u32(RPC_MISMATCH)
+                .u32(RPC_VERSION)
+                .u32(RPC_VERSION);
+            return Some(reply.into_inner());
+        }
+
+        let mut res = XdrWriter::new();
+        let result = self.dispatch(&mut args, &mut res);
+        if result == Err(Rejected::TooWeak) {
+            reply.u32(MSG_DENIED).u32(AUTH_ERROR).u32(AUTH_TOOWEAK);
+            return Some(reply.into_inner());
+        }
+
+        // Replies are never authenticated
+        reply.u32(MSG_ACCEPTED).u32(AUTH_NONE).opaque(&[]);
+        match result {
+            Ok(()) => reply.u32(SUCCESS).append(res),
+            Err(Rejected::ProgMismatch) => reply
+                .u32(Rejected::ProgMismatch as u32)
+                .u32(VERSION)
+                .u32(VERSION),
+            Err(rejected) => reply.u32(rejected as u32),
+        };
+
+        Some(reply.into_inner())
+    }
+
+    fn dispatch(&self, args: &mut XdrReader, res: &mut XdrWriter) -> Result<(), Rejected> {
+        let (program, version, procedure) = (args.u32()?, args.u32()?, args.u32()?);
+        let credentials = Credentials::read(args)?;
+        // The verifier, which neither AUTH_NONE nor AUTH_SYS use
+        args.u32()?;
+        args.opaque(MAX_AUTH_LEN)?;
+
+        if program != NFS_PROGRAM && program != MOUNT_PROGRAM {
+            return Err(Rejected::ProgUnavail);
+        }
+        if version != VERSION {
+            return Err(Rejected::ProgMismatch);
+        }
+        if procedure != 0 && !self.auth.allows(&credentials) {
+            return Err(Rejected::TooWeak);
+        }
+
+        let _span = tracing::debug_span!("nfs", program, procedure).entered();
+        match program {
+            NFS_PROGRAM => self.nfs(procedure, &credentials, args, res),
+            _ => self.mount(procedure, args, res),
+        }
+    }
+
+    fn mount(
+        &self,
+        procedure: u32,
+        args: &mut XdrReader,
+        res: &mut XdrWriter,
+    ) -> Result<(), Rejected> {
+        match procedure {
+            // NULL
+            0 => {}
+            // MNT, where only the root of the vault is exported
+            1 => match args.opaque(MAX_MOUNT_PATH_LEN)? {
+                b"/" => {
+                    res.u32(MNT3_OK)
+                        .opaque(&self.file_handle(ROOT_INODE))
+                        .u32(2)
+                        .u32(AUTH_SYS)
+                        .u32(AUTH_NONE);
+                }
+                _ => {
+                    res.u32(MNT3ERR_NOENT);
+                }
+            },
+            // DUMP, which has nothing to list since clients aren't kept track of
+            2 => {
+                res.bool(false);
+            }
+            // UMNT
+            3 => {
+                args.opaque(MAX_MOUNT_PATH_LEN)?;
+            }
+            // UMNTALL
+            4 => {}
+            // EXPORT, with the root exported to everyone
+            5 => {
+                res.bool(true).opaque(b"/").bool(false).bool(false);
+            }
+            _ => return Err(Rejected::ProcUnavail),
+        }
+
+        Ok(())
+    }
+
+    fn nfs(
+        &self,
+        procedure: u32,
+        credentials: &Credentials,
+        args: &mut XdrReader,
+        res: &mut XdrWriter,
+    ) -> Result<(), Rejected> {
+        match procedure {
+            0 => {}
+            1 => self.getattr(args, res)?,
+            2 => self.setattr(args, res)?,
+            3 => self.lookup(args, res)?,
+            4 => self.access(credentials, args, res)?,
+            5 => self.readlink(args, res)?,
+            6 => self.read(args, res)?,
+            7 => self.write(args, res)?,
+            8 => self.create(args, res)?,
+            9 => self.mkdir(args, res)?,
+            10 => self.symlink(args, res)?,
+            // MKNOD and LINK, since vaults can't store special files or hard links
+            11 | 15 => {
+                res.u32(NFS3ERR_NOTSUPP);
+                if procedure == 15 {
+                    res.bool(false);
+                }
+                self.wcc_data(res, None);
+            }
+            12 => self.remove(args, res)?,
+            13 => self.rmdir(args, res)?,
+            14 => self.rename(args, res)?,
+            16 => self.read_dir(args, res, false)?,
+            17 => self.read_dir(args, res, true)?,
+            18 => self.fsstat(args, res)?,
+            19 => self.fsinfo(args, res)?,
+            20 => self.pathconf(args, res)?,
+            21 => self.commit(args, res)?,
+            _ => return Err(Rejected::ProcUnavail),
+        }
+
+        Ok(())
+    }
+
+    fn tree(&self) -> MutexGuard<'_, DirTree> {
+        self.tree.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn file_handle(&self, inode: Inode) -> [u8; 16] {
+        let mut handle = [0; 16];
+        handle[..8].copy_from_slice(&self.generation.to_be_bytes());
+        handle[8..].copy_from_slice(&inode.to_be_bytes());
+        handle
+    }
+
+    // The inode and path of the file handle `handle`.
+    fn resolve(&self, handle: &[u8]) -> NfsResult<(Inode, PathBuf)> {
+        if handle.len() != 16 {
+            return Err(NFS3ERR_BADHANDLE);
+        }
+        let (generation, inode) = handle.split_at(8);
+        if generation != self.generation.to_be_bytes() {
+            return Err(NFS3ERR_STALE);
+        }
+
+        // Ok to unwrap, this is the second half of the handle
+        let inode = Inode::from_be_bytes(inode.try_into().unwrap());
+        let mut tree = self.tree();
+        let path = tree.get_path(inode).ok_or(NFS3ERR_STALE)?;
+        tree.touch(inode);
+        Ok((inode, path))
+    }
+
+    fn read_handle(&self, args: &mut XdrReader) -> io::Result<NfsResult<(Inode, PathBuf)>> {
+        Ok(self.resolve(args.opaque(MAX_HANDLE_LEN)?))
+    }
+
+    // The inode of `name` in the directory `dir`, allocating one if it's new.
+    fn insert(&self, dir: Inode, name: &OsStr, path: &Path) -> Inode {
+        let mut tree = self.tree();
+        let inode = match tree.insert(dir, name) {
+            Some(inode) => inode,
+            None => tree.insert_path(path),
+        };
+        // The entry was just used, so it's kept along with its parents
+        tree.evict(self.max_handles);
+        inode
+    }
+
+    fn entry(&self, path: &Path) -> NfsResult<DirEntry> {
+        self.fs.dir_entry(path).map_err(status)
+    }
+
+    // The entry at `path`, which has to be a file to read or write.
+    fn file_entry(&self, path: &Path) -> NfsResult<DirEntry> {
+        let entry = self.entry(path)?;
+        match entry.kind {
+            FileKind::File => Ok(entry),
+            FileKind::Directory => Err(NFS3ERR_ISDIR),
+            FileKind::Symlink => Err(NFS3ERR_INVAL),
+        }
+    }
+
+    // Attributes that are only sent if they can be read.
+    fn post_op_attr(&self, res: &mut XdrWriter, file: Option<&(Inode, PathBuf)>) {
+        match file.map(|(inode, path)| (inode, self.fs.dir_entry(path))) {
+            Some((&inode, Ok(entry))) => {
+                res.bool(true);
+                write_fattr(res, inode, &entry);
+            }
+            _ => {
+                res.bool(false);
+            }
+        }
+    }
+
+    // The attributes of a changed file or directory. Those from before the change aren't sent,
+    // which only costs the client a cache flush.
+    fn wcc_data(&self, res: &mut XdrWriter, file: Option<&(Inode, PathBuf)>) {
+        res.bool(false);
+        self.post_op_attr(res, file);
+    }
+
+    fn getattr(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        match file.and_then(|(inode, path)| Ok((inode, self.entry(&path)?))) {
+            Ok((inode, entry)) => {
+                res.u32(NFS3_OK);
+                write_fattr(res, inode, &entry);
+            }
+            Err(status) => {
+                res.u32(status);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn setattr(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        let attrs = SetAttrs::read(args)?;
+        let guard = args.bool()?.then(|| read_time(args)).transpose()?;
+
+        let result = file.clone().and_then(|(_, path)| {
+            // The change is only made if the client saw the latest attributes
+            if let Some(guard) = guard {
+                if nfs_time(self.entry(&path)?.changed) != nfs_time(guard) {
+                    return Err(NFS3ERR_NOT_SYNC);
+                }
+            }
+            self.set_attrs(&path, &attrs)
+        });
+        res.u32(status_of(&result));
+        self.wcc_data(res, file.as_ref().ok());
+
+        Ok(())
+    }
+
+    fn set_attrs(&self, path: &Path, attrs: &SetAttrs) -> NfsResult<()> {
+        if let Some(mode) = attrs.mode {
+            let permissions = Permissions::from_mode(mode & 0o7777);
+            self.fs.set_permissions(path, permissions).map_err(status)?;
+        }
+        if let Some(size) = attrs.size {
+            self.set_size(path, size)?;
+        }
+
+        let mut times = FileTimes::new();
+        if let Some(accessed) = attrs.accessed {
+            times = times.set_accessed(accessed);
+        }
+        if let Some(modified) = attrs.modified {
+            times = times.set_modified(modified);
+        }
+        if times != FileTimes::new() {
+            self.fs.set_times(path, times).map_err(status)?;
+        }
+
+        Ok(())
+    }
+
+    // Files can be emptied or grown, but shrinking them to any other size isn't supported yet,
+    // like through FUSE.
+    fn set_size(&self, path: &Path, size: u64) -> NfsResult<()> {
+        self.file_entry(path)?;
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).truncate(size == 0);
+        let mut file = self.fs.open_file(path, options, false).map_err(status)?;
+
+        let len = file.len().map_err(status)?;
+        if size > len {
+            file.write_all_at(&[0], size - 1).map_err(status)?;
+        } else if size < len {
+            return Err(NFS3ERR_NOTSUPP);
+        }
+
+        Ok(())
+    }
+
+    fn lookup(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let dir = self.read_handle(args)?;
+        let name = read_name(args)?;
+
+        let result = dir
+            .clone()
+            .and_then(|(inode, path)| self.lookup_entry(inode, &path, name));
+        match result {
+            Ok((inode, entry)) => {
+                res.u32(NFS3_OK).opaque(&self.file_handle(inode)).bool(true);
+                write_fattr(res, inode, &entry);
+            }
+            Err(status) => {
+                res.u32(status);
+            }
+        }
+        self.post_op_attr(res, dir.as_ref().ok());
+
+        Ok(())
+    }
+
+    // The inode and entry of `name` in the directory `dir`, which is at `dir_path`.
+    fn lookup_entry(
+        &self,
+        dir: Inode,
+        dir_path: &Path,
+        name: &OsStr,
+    ) -> NfsResult<(Inode, DirEntry)> {
+        let (inode, path) = match name.as_bytes() {
+            b"." | b".." => {
+                if self.entry(dir_path)?.kind != FileKind::Directory {
+                    return Err(NFS3ERR_NOTDIR);
+                }
+                match (name.as_bytes(), dir_path.parent()) {
+                    (b"..", Some(parent)) => (self.tree().insert_path(parent), parent.to_owned()),
+                    (b"..", None) => (ROOT_INODE, PathBuf::new()),
+                    _ => (dir, dir_path.to_owned()),
+                }
+            }
+            _ => {
+                let path = dir_path.join(name);
+                let entry = self.entry(&path)?;
+                return Ok((self.insert(dir, name, &path), entry));
+            }
+        };
+
+        Ok((inode, self.entry(&path)?))
+    }
+
+    fn access(
+        &self,
+        credentials: &Credentials,
+        args: &mut XdrReader,
+        res: &mut XdrWriter,
+    ) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        let requested = args.u32()?;
+
+        match file.and_then(|(inode, path)| Ok((inode, self.entry(&path)?))) {
+            Ok((inode, entry)) => {
+                res.u32(NFS3_OK).bool(true);
+                write_fattr(res, inode, &entry);
+                res.u32(requested & self.granted(credentials, &entry));
+            }
+            Err(status) => {
+                res.u32(status).bool(false);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The ACCESS bits that `credentials` are granted for `entry`, going by its permission bits
+    // like access(2).
+    fn granted(&self, credentials: &Credentials, entry: &DirEntry) -> u32 {
+        let metadata = &entry.metadata;
+        let perm = if credentials.uid == 0 {
+            // Root may read and write anything, but only execute what someone can
+            let can_execute = entry.kind == FileKind::Directory || metadata.mode & 0o111 != 0;
+            0o6 | u32::from(can_execute)
+        } else if credentials.uid == metadata.uid {
+            metadata.mode >> 6
+        } else if credentials.in_group(metadata.gid) {
+            metadata.mode >> 3
+        } else {
+            metadata.mode
+        };
+
+        let mut granted = 0;
+        if perm & 0o4 != 0 {
+            granted |= ACCESS3_READ;
+        }
+        if perm & 0o2 != 0 && !self.fs.read_only {
+            granted |= ACCESS3_MODIFY | ACCESS3_EXTEND | ACCESS3_DELETE;
+        }
+        if perm & 0o1 != 0 {
+            granted |= ACCESS3_LOOKUP | ACCESS3_EXECUTE;
+        }
+        granted
+    }
+
+    fn readlink(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+
+        let result = file.clone().and_then(|(_, path)| {
+            if self.entry(&path)?.kind != FileKind::Symlink {
+                return Err(NFS3ERR_INVAL);
+            }
+            self.fs.link_target(&path).map_err(status)
+        });
+        res.u32(status_of(&result));
+        self.post_op_attr(res, file.as_ref().ok());
+        if let Ok(target) = result {
+            res.opaque(target.as_os_str().as_bytes());
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        let (offset, count) = (args.u64()?, args.u32()?);
+
+        let result = file
+            .clone()
+            .and_then(|(_, path)| self.read_file(&path, offset, count));
+        match result {
+            Ok((data, eof)) => {
+                res.u32(NFS3_OK);
+                self.post_op_attr(res, file.as_ref().ok());
+                // Ok to convert, this is at most the max read size
+                res.u32(data.len() as u32).bool(eof).opaque(&data);
+            }
+            Err(status) => {
+                res.u32(status);
+                self.post_op_attr(res, file.as_ref().ok());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Read at most `count` bytes at `offset` from the file at `path`, along with whether that's
+    // the end of it.
+    fn read_file(
+        &self,
+        path: &Path,
+        offset: u64,
+        count: u32,
+    ) -> NfsResult<(Zeroizing<Vec<u8>>, bool)> {
+        self.file_entry(path)?;
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut file = self.fs.open_file(path, options, false).map_err(status)?;
+
+        let len = file.len().map_err(status)?;
+        if offset >= len {
+            return Ok((Zeroizing::new(Vec::new()), true));
+        }
+        // Ok to convert, this is at most the max read size
+        let size = (len - offset).min(count.min(MAX_IO).into()) as usize;
+        file.seek(SeekFrom::Start(offset)).map_err(status)?;
+        let mut data = Zeroizing::new(vec![0; size]);
+        let (_, n) = util::try_read_exact(&mut file, &mut data).map_err(status)?;
+        data.truncate(n);
+
+        Ok((data, offset + n as u64 >= len))
+    }
+
+    fn write(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        let offset = args.u64()?;
+        // The count is the length of the data, which comes with its own
+        args.u32()?;
+        let stable = args.u32()? != UNSTABLE;
+        let data = args.opaque(MAX_IO as usize)?;
+
+        let result = file
+            .clone()
+            .and_then(|(_, path)| self.write_file(&path, offset, data, stable));
+        res.u32(status_of(&result));
+        self.wcc_data(res, file.as_ref().ok());
+        if let Ok(committed) = result {
+            // Ok to convert, this is at most the max write size
+            res.u32(data.len() as u32)
+                .u32(committed)
+                .fixed(&self.generation.to_be_bytes());
+        }
+
+        Ok(())
+    }
+
+    // Write `data` at `offset` to the file at `path`, returning how durable the write is.
+    fn write_file(&self, path: &Path, offset: u64, data: &[u8], stable: bool) -> NfsResult<u32> {
+        self.file_entry(path)?;
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let mut file = self.fs.open_file(path, options, false).map_err(status)?;
+        file.write_all_at(data, offset).map_err(status)?;
+
+        // Unstable writes are left to COMMIT, like the kernel page cache leaves them to fsync
+        if stable {
+            file.sync_data().map_err(status)?;
+            Ok(FILE_SYNC)
+        } else {
+            Ok(UNSTABLE)
+        }
+    }
+
+    // Reply to CREATE, MKDIR, or SYMLINK with the new entry.
+    fn created(
+        &self,
+        res: &mut XdrWriter,
+        result: NfsResult<(Inode, DirEntry)>,
+        dir: Option<&(Inode, PathBuf)>,
+    ) {
+        match result {
+            Ok((inode, entry)) => {
+                res.u32(NFS3_OK)
+                    .bool(true)
+                    .opaque(&self.file_handle(inode))
+                    .bool(true);
+                write_fattr(res, inode, &entry);
+            }
+            Err(status) => {
+                res.u32(status);
+            }
+        }
+        self.wcc_data(res, dir);
+    }
+
+    fn create(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let dir = self.read_handle(args)?;
+        let name = read_name(args)?;
+        let how = args.u32()?;
+        let attrs = match how {
+            UNCHECKED | GUARDED => SetAttrs::read(args)?,
+            EXCLUSIVE => {
+                // The verifier, which isn't stored, so a retried exclusive create fails with
+                // NFS3ERR_EXIST even if the first one worked
+                args.fixed(8)?;
+                SetAttrs::default()
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid create mode",
+                ))
+            }
+        };
+
+        let result = dir.clone().and_then(|(inode, dir_path)| {
+            let path = dir_path.join(name);
+            let mut options = OpenOptions::new();
+            options
+                .write(true)
+                .create(true)
+                .create_new(how != UNCHECKED)
+                .mode(attrs.mode.unwrap_or(0o644) & 0o7777);
+            drop(self.fs.open_file(&path, options, false).map_err(status)?);
+            // An existing file is changed as asked, like by SETATTR
+            self.set_attrs(&path, &attrs)?;
+
+            Ok((self.insert(inode, name, &path), self.entry(&path)?))
+        });
+        self.created(res, result, dir.as_ref().ok());
+
+        Ok(())
+    }
+
+    fn mkdir(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let dir = self.read_handle(args)?;
+        let name = read_name(args)?;
+        let attrs = SetAttrs::read(args)?;
+
+        let result = dir.clone().and_then(|(inode, dir_path)| {
+            let permissions = Permissions::from_mode(attrs.mode.unwrap_or(0o755) & 0o7777);
+            self.fs
+                .mkdir(&dir_path, name, permissions)
+                .map_err(status)?;
+            let path = dir_path.join(name);
+            self.set_attrs(&path, &attrs)?;
+
+            Ok((self.insert(inode, name, &path), self.entry(&path)?))
+        });
+        self.created(res, result, dir.as_ref().ok());
+
+        Ok(())
+    }
+
+    fn symlink(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let dir = self.read_handle(args)?;
+        let name = read_name(args)?;
+        // Symlinks have no attributes of their own to set
+        SetAttrs::read(args)?;
+        let target = Path::new(OsStr::from_bytes(args.opaque(MAX_PATH_LEN)?));
+
+        let result = dir.clone().and_then(|(inode, dir_path)| {
+            let entry = self.fs.symlink(&dir_path, name, target).map_err(status)?;
+            Ok((self.insert(inode, name, &dir_path.join(name)), entry))
+        });
+        self.created(res, result, dir.as_ref().ok());
+
+        Ok(())
+    }
+
+    fn remove(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let dir = self.read_handle(args)?;
+        let name = read_name(args)?;
+
+        let result = dir.clone().and_then(|(inode, dir_path)| {
+            if self.entry(&dir_path.join(name))?.kind == FileKind::Directory {
+                return Err(NFS3ERR_ISDIR);
+            }
+            self.fs.unlink(&dir_path, name).map_err(status)?;
+            self.tree().remove(inode, name);
+            Ok(())
+        });
+        res.u32(status_of(&result));
+        self.wcc_data(res, dir.as_ref().ok());
+
+        Ok(())
+    }
+
+    fn rmdir(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let dir = self.read_handle(args)?;
+        let name = read_name(args)?;
+
+        let result = dir.clone().and_then(|(inode, dir_path)| {
+            if self.entry(&dir_path.join(name))?.kind != FileKind::Directory {
+                return Err(NFS3ERR_NOTDIR);
+            }
+            self.fs.rmdir(&dir_path, name).map_err(status)?;
+            self.tree().remove(inode, name);
+            Ok(())
+        });
+        res.u32(status_of(&result));
+        self.wcc_data(res, dir.as_ref().ok());
+
+        Ok(())
+    }
+
+    fn rename(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let from_dir = self.read_handle(args)?;
+        let from_name = read_name(args)?;
+        let to_dir = self.read_handle(args)?;
+        let to_name = read_name(args)?;
+
+        let result = from_dir.clone().and_then(|(from_inode, from_path)| {
+            let (to_inode, to_path) = to_dir.clone()?;
+            self.fs
+                .rename(&from_path, from_name, &to_path, to_name)
+                .map_err(status)?;
+            self.tree().rename(from_inode, from_name, to_inode, to_name);
+            Ok(())
+        });
+        res.u32(status_of(&result));
+        self.wcc_data(res, from_dir.as_ref().ok());
+        self.wcc_data(res, to_dir.as_ref().ok());
+
+        Ok(())
+    }
+
+    // READDIR, or READDIRPLUS if `plus` is set, which sends the attributes and handles of the
+    // entries along with their names.
+    fn read_dir(&self, args: &mut XdrReader, res: &mut XdrWriter, plus: bool) -> io::Result<()> {
+        let dir = self.read_handle(args)?;
+        let cookie = args.u64()?;
+        // The cookie verifier, which is always zero since cookies are indexes into the sorted
+        // listing
+        args.fixed(8)?;
+        let (dir_count, max_count) = match plus {
+            true => (args.u32()?, args.u32()?),
+            false => {
+                let count = args.u32()?;
+                (count, count)
+            }
+        };
+
+        let result = dir.clone().and_then(|(inode, path)| {
+            self.list_entries(inode, &path, cookie, dir_count, max_count, plus)
+        });
+        match result {
+            Ok(list) => {
+                res.u32(NFS3_OK);
+                self.post_op_attr(res, dir.as_ref().ok());
+                res.fixed(&[0; 8]).append(list);
+            }
+            Err(status) => {
+                res.u32(status);
+                self.post_op_attr(res, dir.as_ref().ok());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Encode the entries of the directory `dir` at `path` after `cookie`, as many as fit in
+    // `dir_count` bytes of names and `max_count` bytes in total, followed by whether that's all
+    // of them.
+    fn list_entries(
+        &self,
+        dir: Inode,
+        path: &Path,
+        cookie: u64,
+        dir_count: u32,
+        max_count: u32,
+        plus: bool,
+    ) -> NfsResult<XdrWriter> {
+        // Entries that can't be read are left out, like through FUSE
+        let (entries, _) = self.fs.dir_entries_lossy(path).map_err(status)?;
+        let max_len = (max_count as usize).saturating_sub(READDIR_OVERHEAD);
+        let skip = usize::try_from(cookie).unwrap_or(usize::MAX);
+
+        let mut list = XdrWriter::new();
+        let (mut names_len, mut eof) = (0, true);
+        for (i, (entry_path, entry)) in entries.iter().enumerate().skip(skip) {
+            let Some(name) = entry_path.file_name() else {
+                continue;
+            };
+            let inode = self.insert(dir, name, entry_path);
+
+            // i + 1 is the cookie of the next entry
+            let mut encoded = XdrWriter::new();
+            encoded
+                .bool(true)
+                .u64(inode)
+                .opaque(name.as_bytes())
+                .u64(i as u64 + 1);
+            let name_len = encoded.len();
+            if plus {
+                encoded.bool(true);
+                write_fattr(&mut encoded, inode, entry);
+                encoded.bool(true).opaque(&self.file_handle(inode));
+            }
+
+            if names_len + name_len > dir_count as usize || list.len() + encoded.len() > max_len {
+                eof = false;
+                break;
+            }
+            names_len += name_len;
+            list.append(encoded);
+        }
+
+        if list.len() == 0 && !eof {
+            return Err(NFS3ERR_TOOSMALL);
+        }
+        list.bool(false).bool(eof);
+        Ok(list)
+    }
+
+    fn fsstat(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        res.u32(status_of(&file));
+        self.post_op_attr(res, file.as_ref().ok());
+        if file.is_ok() {
+            // Storage can't tell how much space it has, so none is reported, like a full disk
+            // that writes still go to
+            for _ in 0..6 {
+                res.u64(0);
+            }
+            // How long the numbers stay the same
+            res.u32(0);
+        }
+
+        Ok(())
+    }
+
+    fn fsinfo(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        res.u32(status_of(&file));
+        self.post_op_attr(res, file.as_ref().ok());
+        if file.is_ok() {
+            // Sizes of reads and writes: the most, the preferred, and what they're best a
+            // multiple of, which is the size of a cleartext chunk
+            res.u32(MAX_IO).u32(MAX_IO).u32(32 * 1024);
+            res.u32(MAX_IO).u32(MAX_IO).u32(32 * 1024);
+            // The preferred READDIR size, and the largest file size
+            res.u32(64 * 1024).u64(i64::MAX as u64);
+            // Times are kept to the nanosecond
+            res.u32(0).u32(1);
+            res.u32(FSF3_SYMLINK | FSF3_HOMOGENEOUS | FSF3_CANSETTIME);
+        }
+
+        Ok(())
+    }
+
+    fn pathconf(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        res.u32(status_of(&file));
+        self.post_op_attr(res, file.as_ref().ok());
+        if file.is_ok() {
+            // The link and name limits, then that long names are refused rather than cut short,
+            // only root may change owners, and names are case-sensitive and kept as given
+            res.u32(1)
+                .u32(MAX_NAME_LEN as u32)
+                .bool(true)
+                .bool(true)
+                .bool(false)
+                .bool(true);
+        }
+
+        Ok(())
+    }
+
+    fn commit(&self, args: &mut XdrReader, res: &mut XdrWriter) -> io::Result<()> {
+        let file = self.read_handle(args)?;
+        // The range to commit, though the whole file is synced
+        args.u64()?;
+        args.u32()?;
+
+        let result = file.clone().and_then(|(_, path)| {
+            self.file_entry(&path)?;
+            let mut options = OpenOptions::new();
+            options.read(true);
+            let mut file = self.fs.open_file(&path, options, false).map_err(status)?;
+            file.sync_all().map_err(status)
+        });
+        res.u32(status_of(&result));
+        self.wcc_data(res, file.as_ref().ok());
+        if result.is_ok() {
+            res.fixed(&self.generation.to_be_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpStream, os::unix::net::UnixStream};
+
+    use super::*;
+    use crate::testing::{self, VaultShape};
+
+    // Where the results start in a reply to an accepted call: the xid, message type, reply
+    // status, empty verifier, and accept_stat.
+    const RESULTS_OFFSET: usize = 24;
+
+    fn call_record(program: u32, version: u32, procedure: u32, uid: u32, args: &[u8]) -> Vec<u8> {
+        let mut credentials = XdrWriter::new();
+        credentials
+            .u32(0)
+            .opaque(b"client")
+            .u32(uid)
+            .u32(uid)
+            .u32(0);
+        let mut call = XdrWriter::new();
+        call.u32(7)
+            .u32(CALL)
+            .u32(RPC_VERSION)
+            .u32(program)
+            .u32(version)
+            .u32(procedure)
+            .u32(AUTH_SYS)
+            .opaque(&credentials.into_inner())
+            .u32(AUTH_NONE)
+            .opaque(&[])
+            .fixed(args);
+        call.into_inner()
+    }
+
+    // Sends calls straight to a server, as the user `uid`.
+    struct Client<'s, 'v> {
+        server: &'s NfsVaultServer<'v>,
+        uid: u32,
+    }
+
+    impl Client<'_, '_> {
+        // The results of a call, or its accept_stat if it wasn't run.
+        fn call(&self, program: u32, procedure: u32, args: XdrWriter) -> Result<Vec<u8>, u32> {
+            let record = call_record(program, VERSION, procedure, self.uid, &args.into_inner());
+            let reply = self.server.call(&record).unwrap();
+            let mut header = XdrReader::new(&reply);
+            assert_eq!(header.u32().unwrap(), 7);
+            assert_eq!(header.u32().unwrap(), REPLY);
+            assert_eq!(header.u32().unwrap(), MSG_ACCEPTED);
+            header.u32().unwrap();
+            header.opaque(0).unwrap();
+            match header.u32().unwrap() {
+                SUCCESS => Ok(reply[RESULTS_OFFSET..].to_vec()),
+                stat => Err(stat),
+            }
+        }
+
+        fn nfs(&self, procedure: u32, args: XdrWriter) -> Vec<u8> {
+            self.call(NFS_PROGRAM, procedure, args).unwrap()
+        }
+
+        fn mount_root(&self) -> Vec<u8> {
+            let mut args = XdrWriter::new();
+            args.opaque(b"/");
+            let res = self.call(MOUNT_PROGRAM, 1, args).unwrap();
+            let mut res = XdrReader::new(&res);
+            assert_eq!(res.u32().unwrap(), MNT3_OK);
+            res.opaque(MAX_HANDLE_LEN).unwrap().to_vec()
+        }
+
+        // The status of a call taking a handle and a name, like LOOKUP and REMOVE, and the
+        // handle in the results if it worked.
+        fn dir_op(&self, procedure: u32, dir: &[u8], name: &str) -> (u32, Vec<u8>) {
+            let mut args = XdrWriter::new();
+            args.opaque(dir).opaque(name.as_bytes());
+            let res = self.nfs(procedure, args);
+            let mut res = XdrReader::new(&res);
+            let status = res.u32().unwrap();
+            match (status, procedure) {
+                (NFS3_OK, 3) => (status, res.opaque(MAX_HANDLE_LEN).unwrap().to_vec()),
+                _ => (status, Vec::new()),
+            }
+        }
+
+        // The status of creating `name` in `dir` with the arguments `args` after the name, and
+        // the new handle if it worked.
+        fn create(
+            &self,
+            procedure: u32,
+            dir: &[u8],
+            name: &str,
+            args: XdrWriter,
+        ) -> (u32, Vec<u8>) {
+            let mut all_args = XdrWriter::new();
+            all_args.opaque(dir).opaque(name.as_bytes()).append(args);
+            let res = self.nfs(procedure, all_args);
+            let mut res = XdrReader::new(&res);
+            match res.u32().unwrap() {
+                NFS3_OK => {
+                    assert!(res.bool().unwrap());
+                    (NFS3_OK, res.opaque(MAX_HANDLE_LEN).unwrap().to_vec())
+                }
+                status => (status, Vec::new()),
+            }
+        }
+
+        // The status of GETATTR, with the type, mode, size, and fileid if it worked.
+        fn getattr(&self, file: &[u8]) -> (u32, Option<(u32, u32, u64, u64)>) {
+            let mut args = XdrWriter::new();
+            args.opaque(file);
+            let res = self.nfs(1, args);
+            let mut res = XdrReader::new(&res);
+            match res.u32().unwrap() {
+                NFS3_OK => {
+                    let (file_type, mode) = (res.u32().unwrap(), res.u32().unwrap());
+                    // The link count and owners
+                    res.fixed(12).unwrap();
+                    let size = res.u64().unwrap();
+                    // The space used, device numbers, and file system ID
+                    res.fixed(24).unwrap();
+                    (NFS3_OK, Some((file_type, mode, size, res.u64().unwrap())))
+                }
+                status => (status, None),
+            }
+        }
+
+        fn setattr(&self, file: &[u8], mode: Option<u32>, size: Option<u64>) -> u32 {
+            let mut args = XdrWriter::new();
+            args.opaque(file);
+            write_sattr(&mut args, mode, size);
+            args.bool(false);
+            XdrReader::new(&self.nfs(2, args)).u32().unwrap()
+        }
+
+        fn write(&self, file: &[u8], offset: u64, data: &[u8]) -> u32 {
+            let mut args = XdrWriter::new();
+            args.opaque(file)
+                .u64(offset)
+                .u32(data.len() as u32)
+                .u32(FILE_SYNC)
+                .opaque(data);
+            let res = self.nfs(7, args);
+            let mut res = XdrReader::new(&res);
+            let status = res.u32().unwrap();
+            if status == NFS3_OK {
+                skip_wcc_data(&mut res);
+                assert_eq!(res.u32().unwrap(), data.len() as u32);
+                assert_eq!(res.u32().unwrap(), FILE_SYNC);
+            }
+            status
+        }
+
+        fn read(&self, file: &[u8], offset: u64, count: u32) -> (Vec<u8>, bool) {
+            let mut args = XdrWriter::new();
+            args.opaque(file).u64(offset).u32(count);
+            let res = self.nfs(6, args);
+            let mut res = XdrReader::new(&res);
+            assert_eq!(res.u32().unwrap(), NFS3_OK);
+            skip_post_op_attr(&mut res);
+            res.u32().unwrap();
+            let eof = res.bool().unwrap();
+            (res.opaque(MAX_IO as usize).unwrap().to_vec(), eof)
+        }
+
+        // The status of READDIRPLUS, with the names listed, the last cookie, and whether that's
+        // all of them.
+        fn read_dir(
+            &self,
+            dir: &[u8],
+            cookie: u64,
+            dir_count: u32,
+            max_count: u32,
+        ) -> (u32, Vec<String>, u64, bool) {
+            let mut args = XdrWriter::new();
+            args.opaque(dir)
+                .u64(cookie)
+                .fixed(&[0; 8])
+                .u32(dir_count)
+                .u32(max_count);
+            let res = self.nfs(17, args);
+            let mut res = XdrReader::new(&res);
+            let status = res.u32().unwrap();
+            skip_post_op_attr(&mut res);
+            if status != NFS3_OK {
+                return (status, Vec::new(), 0, false);
+            }
+
+            res.fixed(8).unwrap();
+            let (mut names, mut last_cookie) = (Vec::new(), cookie);
+            while res.bool().unwrap() {
+                res.u64().unwrap();
+                let name = res.opaque(MAX_PATH_LEN).unwrap();
+                names.push(String::from_utf8(name.to_vec()).unwrap());
+                last_cookie = res.u64().unwrap();
+                skip_post_op_attr(&mut res);
+                assert!(res.bool().unwrap());
+                res.opaque(MAX_HANDLE_LEN).unwrap();
+            }
+            (status, names, last_cookie, res.bool().unwrap())
+        }
+    }
+
+    fn write_sattr(args: &mut XdrWriter, mode: Option<u32>, size: Option<u64>) {
+        args.bool(mode.is_some());
+        if let Some(mode) = mode {
+            args.u32(mode);
+        }
+        // Owners aren't changed
+        args.bool(false).bool(false).bool(size.is_some());
+        if let Some(size) = size {
+            args.u64(size);
+        }
+        args.u32(DONT_CHANGE).u32(DONT_CHANGE);
+    }
+
+    // The tests mostly call as root, whoever runs them
+    fn root_only() -> NfsAuth {
+        NfsAuth::Users(BTreeSet::from([0]))
+    }
+
+    fn skip_post_op_attr(res: &mut XdrReader) {
+        if res.bool().unwrap() {
+            // An fattr3 is 84 bytes long
+            res.fixed(84).unwrap();
+        }
+    }
+
+    fn skip_wcc_data(res: &mut XdrReader) {
+        if res.bool().unwrap() {
+            res.fixed(24).unwrap();
+        }
+        skip_post_op_attr(res);
+    }
+
+    #[test]
+    fn nfs_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 1,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let server = NfsVaultServer::new(EncryptedFileSystem::new(&vault)).auth(root_only());
+        let client = Client {
+            server: &server,
+            uid: 0,
+        };
+
+        // Only the root is exported
+        let root = client.mount_root();
+        let mut args = XdrWriter::new();
+        args.opaque(b"/elsewhere");
+        let res = client.call(MOUNT_PROGRAM, 1, args).unwrap();
+        assert_eq!(XdrReader::new(&res).u32().unwrap(), MNT3ERR_NOENT);
+        assert_eq!(client.getattr(&root).1.unwrap().0, NF3DIR);
+
+        // Existing files are found, and missing ones aren't
+        let file_name = shape
+            .file_path("", 0)
+            .into_os_string()
+            .into_string()
+            .unwrap();
+        let (status, file) = client.dir_op(3, &root, &file_name);
+        assert_eq!(status, NFS3_OK);
+        let (data, eof) = client.read(&file, 0, 4096);
+        assert_eq!(data, shape.file_contents(0));
+        assert!(eof);
+        assert_eq!(client.dir_op(3, &root, "missing").0, NFS3ERR_NOENT);
+
+        // Create, write, read, and resize a file
+        let guarded = || {
+            let mut how = XdrWriter::new();
+            how.u32(GUARDED);
+            write_sattr(&mut how, Some(0o600), None);
+            how
+        };
+        let (status, new_file) = client.create(8, &root, "new.txt", guarded());
+        assert_eq!(status, NFS3_OK);
+        assert_eq!(
+            client.create(8, &root, "new.txt", guarded()).0,
+            NFS3ERR_EXIST
+        );
+        assert_eq!(client.write(&new_file, 0, b"hello world"), NFS3_OK);
+        assert_eq!(client.read(&new_file, 6, 100), (b"world".to_vec(), true));
+        assert_eq!(client.read(&new_file, 0, 5), (b"hello".to_vec(), false));
+        let (file_type, mode, size, _) = client.getattr(&new_file).1.unwrap();
+        assert_eq!((file_type, mode, size), (NF3REG, 0o600, 11));
+        assert_eq!(client.setattr(&new_file, Some(0o640), Some(0)), NFS3_OK);
+        assert_eq!(client.setattr(&new_file, None, Some(100)), NFS3_OK);
+        assert_eq!(client.read(&new_file, 0, 4096), (vec![0; 100], true));
+        assert_eq!(client.setattr(&new_file, None, Some(50)), NFS3ERR_NOTSUPP);
+        let (_, mode, size, _) = client.getattr(&new_file).1.unwrap();
+        assert_eq!((mode, size), (0o640, 100));
+
+        // Make a directory and move the file into it, which keeps its handle
+        let mut attrs = XdrWriter::new();
+        write_sattr(&mut attrs, Some(0o755), None);
+        let (status, new_dir) = client.create(9, &root, "dir", attrs);
+        assert_eq!(status, NFS3_OK);
+        let mut args = XdrWriter::new();
+        args.opaque(&root)
+            .opaque(b"new.txt")
+            .opaque(&new_dir)
+            .opaque(b"moved.txt");
+        assert_eq!(
+            XdrReader::new(&client.nfs(14, args)).u32().unwrap(),
+            NFS3_OK
+        );
+        assert_eq!(client.read(&new_file, 0, 4096).0.len(), 100);
+        assert_eq!(client.dir_op(3, &new_dir, "moved.txt").1, new_file);
+        assert_eq!(client.dir_op(3, &new_dir, "..").1, root);
+        assert_eq!(client.dir_op(3, &new_file, "..").0, NFS3ERR_NOTDIR);
+
+        // Symlinks point wherever they were made to
+        let mut target = XdrWriter::new();
+        write_sattr(&mut target, None, None);
+        target.opaque(b"dir/moved.txt");
+        let (status, link) = client.create(10, &root, "link", target);
+        assert_eq!(status, NFS3_OK);
+        let mut args = XdrWriter::new();
+        args.opaque(&link);
+        let res = client.nfs(5, args);
+        let mut res = XdrReader::new(&res);
+        assert_eq!(res.u32().unwrap(), NFS3_OK);
+        skip_post_op_attr(&mut res);
+        assert_eq!(res.opaque(MAX_PATH_LEN).unwrap(), b"dir/moved.txt");
+
+        // Listings are split by size and picked up again from the cookie
+        let (status, names, _, eof) = client.read_dir(&root, 0, 4096, 65536);
+        assert_eq!(status, NFS3_OK);
+        assert_eq!(names, ["dir", file_name.as_str(), "link"]);
+        assert!(eof);
+        let (status, first, cookie, eof) = client.read_dir(&root, 0, 4096, 400);
+        assert_eq!((status, first.len(), eof), (NFS3_OK, 1, false));
+        let (_, rest, _, eof) = client.read_dir(&root, cookie, 4096, 65536);
+        assert_eq!([first, rest].concat(), names);
+        assert!(eof);
+        assert_eq!(client.read_dir(&root, 0, 4096, 300).0, NFS3ERR_TOOSMALL);
+
+        // Directories are only removed with RMDIR, once they're empty
+        assert_eq!(client.dir_op(13, &root, "dir").0, NFS3ERR_NOTEMPTY);
+        assert_eq!(client.dir_op(12, &root, "dir").0, NFS3ERR_ISDIR);
+        assert_eq!(client.dir_op(13, &root, "link").0, NFS3ERR_NOTDIR);
+        assert_eq!(client.dir_op(12, &new_dir, "moved.txt").0, NFS3_OK);
+        assert_eq!(client.dir_op(13, &root, "dir").0, NFS3_OK);
+        assert_eq!(client.getattr(&new_file).0, NFS3ERR_STALE);
+        assert_eq!(client.getattr(&new_dir).0, NFS3ERR_STALE);
+
+        // Handles have to be from this server
+        assert_eq!(client.getattr(&root[..8]).0, NFS3ERR_BADHANDLE);
+        let mut other_handle = root.clone();
+        other_handle[0] ^= 1;
+        assert_eq!(client.getattr(&other_handle).0, NFS3ERR_STALE);
+    }
+
+    #[test]
+    fn max_handles_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 8,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let server = NfsVaultServer::new(EncryptedFileSystem::new(&vault))
+            .auth(root_only())
+            .max_handles(4);
+        let client = Client {
+            server: &server,
+            uid: 0,
+        };
+        let root = client.mount_root();
+        let names: Vec<_> = (0..shape.files_per_dir)
+            .map(|i| shape.file_path("", i).to_string_lossy().into_owned())
+            .collect();
+        let handles: Vec<_> = names
+            .iter()
+            .map(|name| client.dir_op(3, &root, name).1)
+            .collect();
+
+        // The least recently used handles are refused, until their entries are looked up again
+        assert_eq!(client.getattr(&handles[0]).0, NFS3ERR_STALE);
+        assert_eq!(client.getattr(&handles[7]).0, NFS3_OK);
+        assert_eq!(client.getattr(&root).0, NFS3_OK);
+        let (status, handle) = client.dir_op(3, &root, &names[0]);
+        assert_eq!(status, NFS3_OK);
+        assert_ne!(handle, handles[0]);
+        assert_eq!(client.getattr(&handle).0, NFS3_OK);
+    }
+
+    #[test]
+    fn run_test() {
+        let dir = tempfile::tempdir().unwrap();
+        // The server runs until the test exits, so the vault has to outlive it
+        let vault = Box::leak(Box::new(
+            testing::create_vault(dir.path(), &VaultShape::default()).unwrap(),
+        ));
+
+        // Listeners anyone could reach aren't served unless asked to
+        let server = NfsVaultServer::new(EncryptedFileSystem::new(vault));
+        assert!(server.run(TcpListener::bind("0.0.0.0:0").unwrap()).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            NfsVaultServer::new(EncryptedFileSystem::new(vault))
+                .auth(NfsAuth::AnyUser)
+                .max_connections(1)
+                .run(listener)
+                .unwrap()
+        });
+        let null = call_record(NFS_PROGRAM, VERSION, 0, 0, &[]);
+        let ping = |mut stream: &TcpStream| {
+            xdr::write_record(&mut stream, &null)?;
+            xdr::read_record(&mut stream, MAX_RECORD_LEN)
+        };
+
+        // Another connection waits until the first one is closed
+        let first = TcpStream::connect(addr).unwrap();
+        assert!(ping(&first).unwrap().is_some());
+        let second = TcpStream::connect(addr).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(ping(&second).is_err());
+        drop(first);
+        second.set_read_timeout(None).unwrap();
+        assert!(xdr::read_record(&mut &second, MAX_RECORD_LEN)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn access_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = testing::create_vault(dir.path(), &VaultShape::default()).unwrap();
+        let server = NfsVaultServer::new(EncryptedFileSystem::new(&vault)).auth(NfsAuth::AnyUser);
+        let root_client = Client {
+            server: &server,
+            uid: 0,
+        };
+        let root = root_client.mount_root();
+        let (_, file) = root_client.dir_op(
+            3,
+            &root,
+            &VaultShape::default().file_path("", 0).to_string_lossy(),
+        );
+
+        let access = |client: &Client, file: &[u8], requested: u32| {
+            let mut args = XdrWriter::new();
+            args.opaque(file).u32(requested);
+            let res = client.nfs(4, args);
+            let mut res = XdrReader::new(&res);
+            assert_eq!(res.u32().unwrap(), NFS3_OK);
+            skip_post_op_attr(&mut res);
+            res.u32().unwrap()
+        };
+        let all = ACCESS3_READ | ACCESS3_MODIFY | ACCESS3_EXTEND | ACCESS3_EXECUTE;
+
+        // Root may do anything but execute files that nobody can
+        assert_eq!(root_client.setattr(&file, Some(0o604), None), NFS3_OK);
+        assert_eq!(access(&root_client, &file, all), all & !ACCESS3_EXECUTE);
+        // Anyone else gets the permission bits for others
+        let other = Client {
+            server: &server,
+            uid: 54321,
+        };
+        assert_eq!(access(&other, &file, all), ACCESS3_READ);
+        assert_eq!(root_client.setattr(&file, Some(0o600), None), NFS3_OK);
+        assert_eq!(access(&other, &file, all), 0);
+
+        // Nothing can be changed through a read-only server
+        let server =
+            NfsVaultServer::new(EncryptedFileSystem::new(&vault).read_only(true)).auth(root_only());
+        let client = Client {
+            server: &server,
+            uid: 0,
+        };
+        let root = client.mount_root();
+        assert_eq!(access(&client, &root, all), ACCESS3_READ | ACCESS3_EXECUTE);
+        let mut how = XdrWriter::new();
+        how.u32(UNCHECKED);
+        write_sattr(&mut how, None, None);
+        assert_eq!(client.create(8, &root, "new.txt", how).0, NFS3ERR_ROFS);
+    }
+
+    #[test]
+    fn rpc_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = testing::create_vault(dir.path(), &VaultShape::default()).unwrap();
+        let server = NfsVaultServer::new(EncryptedFileSystem::new(&vault)).auth(root_only());
+        let client = Client {
+            server: &server,
+            uid: 0,
+        };
+
+        // Calls that can't be run are rejected, as are calls for other RPC versions
+        let call = |program, procedure| client.call(program, procedure, XdrWriter::new());
+        assert_eq!(call(NFS_PROGRAM, 0), Ok(Vec::new()));
+        assert_eq!(call(NFS_PROGRAM, 22), Err(Rejected::ProcUnavail as u32));
+        assert_eq!(call(100000, 0), Err(Rejected::ProgUnavail as u32));
+        assert_eq!(call(NFS_PROGRAM, 1), Err(Rejected::GarbageArgs as u32));
+        let record = call_record(NFS_PROGRAM, 2, 0, 0, &[]);
+        let reply = server.call(&record).unwrap();
+        assert_eq!(
+            &reply[8..RESULTS_OFFSET],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]
+        );
+        let mut record = call_record(NFS_PROGRAM, VERSION, 0, 0, &[]);
+        record[11] = 3;
+        let reply = server.call(&record).unwrap();
+        assert_eq!(
+            &reply[8..16],
+            [0, 0, 0, MSG_DENIED as u8, 0, 0, 0, RPC_MISMATCH as u8]
+        );
+
+        // Other users can only check that the server is there
+        let reply = server
+            .call(&call_record(
+                MOUNT_PROGRAM,
+                VERSION,
+                1,
+                1000,
+                b"\0\0\0\x01/\0\0\0",
+            ))
+            .unwrap();
+        assert_eq!(
+            &reply[8..],
+            [
+                0,
+                0,
+                0,
+                MSG_DENIED as u8,
+                0,
+                0,
+                0,
+                AUTH_ERROR as u8,
+                0,
+                0,
+                0,
+                AUTH_TOOWEAK as u8
+            ]
+        );
+        let other = Client {
+            server: &server,
+            uid: 1000,
+        };
+        assert_eq!(other.call(NFS_PROGRAM, 0, XdrWriter::new()), Ok(Vec::new()));
+        // Only the user running the server is allowed by default
+        let default = NfsVaultServer::new(EncryptedFileSystem::new(&vault));
+        assert_eq!(default.auth, NfsAuth::current_user());
+
+        // Calls are answered over a stream until it's closed
+        let (mut stream, server_stream) = UnixStream::pair().unwrap();
+        thread::scope(|s| {
+            let handle = s.spawn(|| server.serve_connection(server_stream));
+            for _ in 0..2 {
+                xdr::write_record(&mut stream, &call_record(MOUNT_PROGRAM, VERSION, 0, 0, &[]))
+                    .unwrap();
+                let reply = xdr::read_record(&mut stream, MAX_RECORD_LEN)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(reply.len(), RESULTS_OFFSET);
+            }
+            drop(stream);
+            handle.join().unwrap().unwrap();
+        });
+    }
+}
@@ -0,0 +1,226 @@
+//! Just enough XDR (RFC 4506) and ONC RPC record marking (RFC 5531) for NFSv3 over TCP.
+
+use std::io::{self, Read, Write};
+
+// The last fragment of a record has the top bit of its length set
+const LAST_FRAGMENT: u32 = 1 << 31;
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated XDR data")
+}
+
+// Values are padded to a multiple of 4 bytes.
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// Decodes XDR values from the front of a buffer.
+#[derive(Clone)]
+pub(super) struct XdrReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> XdrReader<'a> {
+    pub(super) fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(truncated());
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    pub(super) fn u32(&mut self) -> io::Result<u32> {
+        // Ok to unwrap, we just took 4 bytes
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(super) fn u64(&mut self) -> io::Result<u64> {
+        // Ok to unwrap, we just took 8 bytes
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(super) fn bool(&mut self) -> io::Result<bool> {
+        match self.u32()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid XDR boolean",
+            )),
+        }
+    }
+
+    /// Fixed-length opaque data of `len` bytes.
+    pub(super) fn fixed(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let data = self.take(len)?;
+        self.take(padding(len))?;
+        Ok(data)
+    }
+
+    /// Variable-length opaque data or a string of at most `max_len` bytes.
+    pub(super) fn opaque(&mut self, max_len: usize) -> io::Result<&'a [u8]> {
+        // Ok to convert, usize is at least 32 bits on every supported platform
+        let len = self.u32()? as usize;
+        if len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "XDR data too long",
+            ));
+        }
+        self.fixed(len)
+    }
+}
+
+/// Encodes XDR values into a growing buffer.
+#[derive(Default)]
+pub(super) struct XdrWriter {
+    buf: Vec<u8>,
+}
+
+impl XdrWriter {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub(super) fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub(super) fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(super) fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(super) fn bool(&mut self, value: bool) -> &mut Self {
+        self.u32(value.into())
+    }
+
+    /// Fixed-length opaque data, whose length the reader already knows.
+    pub(super) fn fixed(&mut self, data: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(data);
+        self.buf.resize(self.buf.len() + padding(data.len()), 0);
+        self
+    }
+
+    /// Variable-length opaque data or a string. Callers keep these well under 4 GiB.
+    pub(super) fn opaque(&mut self, data: &[u8]) -> &mut Self {
+        self.u32(data.len() as u32).fixed(data)
+    }
+
+    /// Everything encoded by `other`.
+    pub(super) fn append(&mut self, other: XdrWriter) -> &mut Self {
+        self.buf.extend_from_slice(&other.buf);
+        self
+    }
+}
+
+/// Read a whole RPC record, made of one or more fragments of at most `max_len` bytes in total.
+/// Returns `None` if the connection was closed between records.
+pub(super) fn read_record(reader: &mut impl Read, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut record = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        match reader.read_exact(&mut header) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && record.is_empty() => {
+                return Ok(None)
+            }
+            result => result?,
+        }
+
+        let header = u32::from_be_bytes(header);
+        // Ok to convert, usize is at least 32 bits on every supported platform
+        let len = (header & !LAST_FRAGMENT) as usize;
+        if record.len() + len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "RPC record too long",
+            ));
+        }
+        let start = record.len();
+        record.resize(start + len, 0);
+        reader.read_exact(&mut record[start..])?;
+
+        if header & LAST_FRAGMENT != 0 {
+            return Ok(Some(record));
+        }
+    }
+}
+
+/// Write `record` as a single fragment.
+pub(super) fn write_record(writer: &mut impl Write, record: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(record.len())
+        .ok()
+        .filter(|len| len & LAST_FRAGMENT == 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "RPC record too long"))?;
+    // The header goes out with the record, so it isn't held back waiting for an ACK
+    let mut fragment = Vec::with_capacity(4 + record.len());
+    fragment.extend_from_slice(&(len | LAST_FRAGMENT).to_be_bytes());
+    fragment.extend_from_slice(record);
+    writer.write_all(&fragment)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xdr_test() {
+        let mut writer = XdrWriter::new();
+        writer
+            .u32(7)
+            .u64(u64::MAX - 1)
+            .bool(true)
+            .opaque(b"hello")
+            .fixed(&[1, 2, 3, 4]);
+        let buf = writer.into_inner();
+        // Strings are padded to 4 bytes
+        assert_eq!(buf.len(), 4 + 8 + 4 + 4 + 8 + 4);
+
+        let mut reader = XdrReader::new(&buf);
+        assert_eq!(reader.u32().unwrap(), 7);
+        assert_eq!(reader.u64().unwrap(), u64::MAX - 1);
+        assert!(reader.bool().unwrap());
+        assert!(reader.clone().opaque(4).is_err());
+        assert_eq!(reader.opaque(5).unwrap(), b"hello");
+        assert_eq!(reader.fixed(4).unwrap(), [1, 2, 3, 4]);
+        assert!(reader.u32().is_err());
+    }
+
+    #[test]
+    fn record_test() {
+        let mut stream = Vec::new();
+        write_record(&mut stream, b"first").unwrap();
+        // A record split into two fragments
+        stream.extend_from_slice(&3u32.to_be_bytes());
+        stream.extend_from_slice(b"sec");
+        stream.extend_from_slice(&(3 | LAST_FRAGMENT).to_be_bytes());
+        stream.extend_from_slice(b"ond");
+
+        let mut reader = stream.as_slice();
+        assert_eq!(read_record(&mut reader, 10).unwrap().unwrap(), b"first");
+        assert_eq!(read_record(&mut reader, 10).unwrap().unwrap(), b"second");
+        assert_eq!(read_record(&mut reader, 10).unwrap(), None);
+
+        let mut reader = stream.as_slice();
+        assert!(read_record(&mut reader, 4).is_err());
+        // A connection closed in the middle of a record is an error
+        let mut reader = &stream[..stream.len() - 1];
+        read_record(&mut reader, 10).unwrap();
+        assert!(read_record(&mut reader, 10).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Display},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        PoisonError, RwLock,
+    },
+    time::Duration,
+};
+
+/// Receives events from an [`EncryptedFileSystem`](super::EncryptedFileSystem) and the FUSE file
+/// system built on it, such as to collect metrics. Every method does nothing by default.
+///
+/// Methods are called on hot paths, from any thread, so they should return quickly.
+pub trait Observer: Debug + Send + Sync {
+    /// A FUSE request named `op` was handled, either successfully or with an `errno` value.
+    fn on_fuse_op(&self, op: &'static str, duration: Duration, result: Result<(), i32>) {
+        let _ = (op, duration, result);
+    }
+
+    /// A chunk with `bytes` bytes of cleartext was encrypted.
+    fn on_chunk_encrypt(&self, bytes: usize) {
+        let _ = bytes;
+    }
+
+    /// A chunk was decrypted into `bytes` bytes of cleartext.
+    fn on_chunk_decrypt(&self, bytes: usize) {
+        let _ = bytes;
+    }
+
+    /// A lookup was answered from a cache.
+    fn on_cache_hit(&self, kind: CacheKind) {
+        let _ = kind;
+    }
+
+    /// A lookup missed a cache, and had to go to storage.
+    fn on_cache_miss(&self, kind: CacheKind) {
+        let _ = kind;
+    }
+}
+
+/// The caches reported to [`Observer::on_cache_hit`] and [`Observer::on_cache_miss`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CacheKind {
+    /// Directory IDs of cleartext directories.
+    DirId,
+    /// Cleartext directories of hashed ciphertext directories.
+    DirPath,
+    /// Decrypted headers of ciphertext files.
+    FileHeader,
+}
+
+impl CacheKind {
+    const ALL: [CacheKind; 3] = [CacheKind::DirId, CacheKind::DirPath, CacheKind::FileHeader];
+
+    fn name(self) -> &'static str {
+        match self {
+            CacheKind::DirId => "dir_id",
+            CacheKind::DirPath => "dir_path",
+            CacheKind::FileHeader => "file_header",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct OpCounters {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// An [`Observer`] that adds everything up, and displays the totals in the Prometheus text
+/// format.
+#[derive(Debug, Default)]
+pub struct CountingObserver {
+    // Only locked for writing to add an operation the first time it's seen
+    fuse_ops: RwLock<BTreeMap<&'static str, OpCounters>>,
+    chunks_encrypted: AtomicU64,
+    bytes_encrypted: AtomicU64,
+    chunks_decrypted: AtomicU64,
+    bytes_decrypted: AtomicU64,
+    cache_hits: [AtomicU64; 3],
+    cache_misses: [AtomicU64; 3],
+}
+
+impl CountingObserver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many times the FUSE operation `op` was called, and how many of those calls failed.
+    pub fn fuse_op_count(&self, op: &str) -> (u64, u64) {
+        let fuse_ops = self.fuse_ops.read().unwrap_or_else(PoisonError::into_inner);
+        fuse_ops.get(op).map_or((0, 0), |counters| {
+            (
+                counters.calls.load(Ordering::Relaxed),
+                counters.errors.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// The number of chunks encrypted, and their total cleartext size.
+    pub fn encrypted(&self) -> (u64, u64) {
+        (
+            self.chunks_encrypted.load(Ordering::Relaxed),
+            self.bytes_encrypted.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The number of chunks decrypted, and their total cleartext size.
+    pub fn decrypted(&self) -> (u64, u64) {
+        (
+            self.chunks_decrypted.load(Ordering::Relaxed),
+            self.bytes_decrypted.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The number of hits and misses of a cache.
+    pub fn cache(&self, kind: CacheKind) -> (u64, u64) {
+        (
+            self.cache_hits[kind as usize].load(Ordering::Relaxed),
+            self.cache_misses[kind as usize].load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl Observer for CountingObserver {
+    fn on_fuse_op(&self, op: &'static str, duration: Duration, result: Result<(), i32>) {
+        let add = |counters: &OpCounters| {
+            counters.calls.fetch_add(1, Ordering::Relaxed);
+            if result.is_err() {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            let nanos = duration.as_nanos().try_into().unwrap_or(u64::MAX);
+            counters.nanos.fetch_add(nanos, Ordering::Relaxed);
+        };
+
+        let fuse_ops = self.fuse_ops.read().unwrap_or_else(PoisonError::into_inner);
+        match fuse_ops.get(op) {
+            Some(counters) => add(counters),
+            None => {
+                drop(fuse_ops);
+                let mut fuse_ops = self
+                    .fuse_ops
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner);
+                add(fuse_ops.entry(op).or_default());
+            }
+        }
+    }
+
+    fn on_chunk_encrypt(&self, bytes: usize) {
+        self.chunks_encrypted.fetch_add(1, Ordering::Relaxed);
+        self.bytes_encrypted
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn on_chunk_decrypt(&self, bytes: usize) {
+        self.chunks_decrypted.fetch_add(1, Ordering::Relaxed);
+        self.bytes_decrypted
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn on_cache_hit(&self, kind: CacheKind) {
+        self.cache_hits[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_cache_miss(&self, kind: CacheKind) {
+        self.cache_misses[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Display for CountingObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fuse_ops = self.fuse_ops.read().unwrap_or_else(PoisonError::into_inner);
+        writeln!(f, "# TYPE cryptomator_fuse_ops_total counter")?;
+        for (op, counters) in fuse_ops.iter() {
+            let calls = counters.calls.load(Ordering::Relaxed);
+            writeln!(f, "cryptomator_fuse_ops_total{{op=\"{op}\"}} {calls}")?;
+        }
+        writeln!(f, "# TYPE cryptomator_fuse_op_errors_total counter")?;
+        for (op, counters) in fuse_ops.iter() {
+            let errors = counters.errors.load(Ordering::Relaxed);
+            writeln!(
+                f,
+                "cryptomator_fuse_op_errors_total{{op=\"{op}\"}} {errors}"
+            )?;
+        }
+        writeln!(f, "# TYPE cryptomator_fuse_op_seconds_total counter")?;
+        for (op, counters) in fuse_ops.iter() {
+            let seconds = Duration::from_nanos(counters.nanos.load(Ordering::Relaxed));
+            let seconds = seconds.as_secs_f64();
+            writeln!(
+                f,
+                "cryptomator_fuse_op_seconds_total{{op=\"{op}\"}} {seconds}"
+            )?;
+        }
+        drop(fuse_ops);
+
+        let (chunks, bytes) = self.encrypted();
+        writeln!(f, "# TYPE cryptomator_chunks_encrypted_total counter")?;
+        writeln!(f, "cryptomator_chunks_encrypted_total {chunks}")?;
+        writeln!(f, "# TYPE cryptomator_bytes_encrypted_total counter")?;
+        writeln!(f, "cryptomator_bytes_encrypted_total {bytes}")?;
+        let (chunks, bytes) = self.decrypted();
+        writeln!(f, "# TYPE cryptomator_chunks_decrypted_total counter")?;
+        writeln!(f, "cryptomator_chunks_decrypted_total {chunks}")?;
+        writeln!(f, "# TYPE cryptomator_bytes_decrypted_total counter")?;
+        writeln!(f, "cryptomator_bytes_decrypted_total {bytes}")?;
+
+        writeln!(f, "# TYPE cryptomator_cache_hits_total counter")?;
+        for kind in CacheKind::ALL {
+            let (hits, _) = self.cache(kind);
+            writeln!(
+                f,
+                "cryptomator_cache_hits_total{{cache=\"{}\"}} {hits}",
+                kind.name()
+            )?;
+        }
+        writeln!(f, "# TYPE cryptomator_cache_misses_total counter")?;
+        for kind in CacheKind::ALL {
+            let (_, misses) = self.cache(kind);
+            writeln!(
+                f,
+                "cryptomator_cache_misses_total{{cache=\"{}\"}} {misses}",
+                kind.name()
+            )?;
+        }
+
+        Ok(())
+    }
+}
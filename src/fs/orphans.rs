@@ -0,0 +1,194 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{bail, eyre, WrapErr};
+
+use super::{check_path, read_dir_id, EncryptedFileSystem, FileKind};
+use crate::{
+    crypto::{DirId, FileCryptor},
+    Result,
+};
+
+/// A hashed directory under `d` that isn't referenced by any directory entry, found by
+/// [`EncryptedFileSystem::find_orphans`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanDir {
+    pub hashed_dir_path: PathBuf,
+    /// The directory ID recovered from the directory's dirid.c9r, if it could be read and
+    /// matches the location of the directory. Orphans are only adoptable if this is known.
+    pub dir_id: Option<DirId>,
+}
+
+impl<'v> EncryptedFileSystem<'v> {
+    /// Find the hashed directories that can't be reached from the root directory, e.g. because
+    /// their dir.c9r was lost in a sync conflict. Directories inside an orphan are also orphans,
+    /// and become reachable again once it is adopted.
+    ///
+    /// Fails if any directory or dir.c9r in the vault can't be read, since it may hold the only
+    /// reference to an apparent orphan.
+    pub fn find_orphans(&self) -> Result<Vec<OrphanDir>> {
+        let referenced = self.referenced_dirs()?;
+        let mut orphans = Vec::new();
+
+        let storage = self.storage();
+        let data_dir = self.vault.path().join("d");
+        for prefix in storage.list(&data_dir)? {
+            let prefix_path = data_dir.join(&prefix);
+            if !storage.is_dir(&prefix_path) {
+                continue;
+            }
+
+            for dir in storage.list(&prefix_path)? {
+                let hashed_dir = Path::new(&prefix).join(&dir);
+                let dir_path = prefix_path.join(&dir);
+                if !storage.is_dir(&dir_path) || referenced.contains(&hashed_dir) {
+                    continue;
+                }
+
+                orphans.push(OrphanDir {
+                    hashed_dir_path: dir_path,
+                    dir_id: self.recover_dir_id(&hashed_dir).ok(),
+                });
+            }
+        }
+
+        orphans.sort_by(|a, b| a.hashed_dir_path.cmp(&b.hashed_dir_path));
+        Ok(orphans)
+    }
+
+    /// Link an orphaned directory back into the vault as the new directory `target`, keeping its
+    /// contents.
+    pub fn adopt_orphan(&self, orphan: &OrphanDir, target: impl AsRef<Path>) -> Result<()> {
+        let target = target.as_ref();
+        check_path(target)?;
+        let Some(dir_id) = &orphan.dir_id else {
+            bail!(
+                "directory ID of {} is unknown",
+                orphan.hashed_dir_path.display()
+            );
+        };
+        let (Some(parent), Some(name)) = (target.parent(), target.file_name()) else {
+            bail!("invalid target path: {}", target.display());
+        };
+
+        if parent.parent().is_some() && self.dir_entry(parent)?.kind != FileKind::Directory {
+            bail!("not a directory: {}", parent.display());
+        }
+        let _write = self.check_writable()?;
+        let parent_dir_id = self.translator.get_dir_id(parent)?;
+        let _guard = self.dir_locks.lock(&[&parent_dir_id]);
+        if self.dir_entry(target).is_ok() {
+            bail!("{} already exists", target.display());
+        }
+        self.check_orphan(orphan)?;
+
+        self.create_dir_entry(parent, name, dir_id)?;
+        Ok(())
+    }
+
+    /// Delete an orphaned directory and everything in it. Directories inside it become orphans
+    /// of their own.
+    pub fn purge_orphan(&self, orphan: &OrphanDir) -> Result<()> {
+        let _write = self.check_writable()?;
+        self.check_orphan(orphan)?;
+        self.storage().remove_dir_all(&orphan.hashed_dir_path)?;
+
+        // Ok to unwrap, check_orphan makes sure this is a hashed directory under d
+        let prefix_dir = orphan.hashed_dir_path.parent().unwrap();
+        let _ = self.storage().remove_dir(prefix_dir);
+
+        Ok(())
+    }
+
+    // Find the hashed directories reachable from the root directory, relative to d. Unlike the
+    // cleartext directory index, this doesn't need names to be decrypted, and anything that can't
+    // be read is an error rather than skipped.
+    fn referenced_dirs(&self) -> Result<HashSet<PathBuf>> {
+        let cryptor = self.vault.cryptor();
+        let storage = self.storage();
+        let data_dir = self.vault.path().join("d");
+        let root = cryptor.hash_dir_id(&DirId::root())?;
+        let mut referenced = HashSet::from([root.clone()]);
+        let mut queue = vec![root];
+
+        while let Some(hashed_dir) = queue.pop() {
+            let dir_path = data_dir.join(&hashed_dir);
+            let entries = match storage.list(&dir_path) {
+                Ok(entries) => entries,
+                // A missing directory can't reference any others
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err)
+                        .wrap_err_with(|| format!("failed to list {}", dir_path.display()))
+                }
+            };
+
+            for name in entries {
+                let entry_path = dir_path.join(name);
+                let metadata = storage
+                    .metadata(&entry_path)
+                    .wrap_err_with(|| format!("failed to read {}", entry_path.display()))?;
+                // Only directory entries hold a dir.c9r, whether they're shortened or not
+                if !metadata.is_dir() {
+                    continue;
+                }
+
+                let dir_id = match read_dir_id(storage, &entry_path) {
+                    Ok(dir_id) => dir_id,
+                    Err(err)
+                        if err
+                            .downcast_ref::<io::Error>()
+                            .is_some_and(|err| err.kind() == io::ErrorKind::NotFound) =>
+                    {
+                        continue
+                    }
+                    Err(err) => return Err(err),
+                };
+                let hashed_dir = cryptor.hash_dir_id(&dir_id)?;
+                if referenced.insert(hashed_dir.clone()) {
+                    queue.push(hashed_dir);
+                }
+            }
+        }
+
+        Ok(referenced)
+    }
+
+    // Read a hashed directory's dirid.c9r, checking that it belongs there.
+    fn recover_dir_id(&self, hashed_dir: &Path) -> Result<DirId> {
+        let dir_id = self.read_dir_id_backup(&self.vault.path().join("d").join(hashed_dir))?;
+        if self.vault.cryptor().hash_dir_id(&dir_id)? != hashed_dir {
+            bail!("dirid.c9r does not match the location of the directory");
+        }
+
+        Ok(dir_id)
+    }
+
+    // Make sure an orphan is still unreferenced, since the vault may have changed since it was
+    // found.
+    fn check_orphan(&self, orphan: &OrphanDir) -> Result<()> {
+        let hashed_dir = orphan
+            .hashed_dir_path
+            .strip_prefix(self.vault.path().join("d"))
+            .ok()
+            .filter(|hashed_dir| hashed_dir.components().count() == 2)
+            .ok_or_else(|| {
+                eyre!(
+                    "not a hashed directory: {}",
+                    orphan.hashed_dir_path.display()
+                )
+            })?;
+
+        if self.referenced_dirs()?.contains(hashed_dir) {
+            bail!(
+                "{} is no longer an orphan",
+                orphan.hashed_dir_path.display()
+            );
+        }
+
+        Ok(())
+    }
+}
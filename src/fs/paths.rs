@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use color_eyre::eyre::{bail, eyre, WrapErr};
+
+use super::{
+    check_path, read_dir_id, writes, CacheKind, EncryptedFile, EncryptedFileSystem, FileKind,
+};
+use crate::{
+    crypto::{DirId, FileCryptor},
+    storage::OpenOptions,
+    Result,
+};
+
+impl<'v> EncryptedFileSystem<'v> {
+    /// Translate a cleartext path into the ciphertext path of the corresponding entry, which is
+    /// either a `.c9r` file or directory, or a shortened `.c9s` directory. The root directory
+    /// translates to its hashed directory under `d`.
+    pub fn ciphertext_path(&self, cleartext_path: impl AsRef<Path>) -> Result<PathBuf> {
+        let cleartext_path = cleartext_path.as_ref();
+        check_path(cleartext_path)?;
+        match cleartext_path.parent() {
+            Some(parent) => {
+                let parent_dir_id = self.translator.get_dir_id(parent)?;
+                self.translator
+                    .get_ciphertext_path(cleartext_path, &parent_dir_id)
+            }
+            None => self.hashed_dir_path(&DirId::root()),
+        }
+    }
+
+    /// Translate a ciphertext path into the corresponding cleartext path. The ciphertext path may
+    /// be absolute, or relative to the vault, and may point to a hashed directory, an entry in
+    /// one, or a file inside an entry such as `dir.c9r` or `contents.c9r`.
+    ///
+    /// The first call walks the whole vault to find the location of each directory, and the
+    /// results are reused by later calls until they are found to be out of date.
+    pub fn cleartext_path(&self, ciphertext_path: impl AsRef<Path>) -> Result<PathBuf> {
+        let ciphertext_path = ciphertext_path.as_ref();
+        let data_dir = self.vault.path().join("d");
+        let components = data_path_components(&data_dir, ciphertext_path)?;
+        let hashed_dir = Path::new(components[0]).join(components[1]);
+
+        let cleartext_dir = self.cleartext_dir(&hashed_dir)?;
+        let Some(&entry_name) = components.get(2) else {
+            return Ok(cleartext_dir);
+        };
+        if entry_name == "dirid.c9r" {
+            return Ok(cleartext_dir);
+        }
+
+        let dir_id = self.translator.get_dir_id(&cleartext_dir)?;
+        let entry_path = data_dir.join(&hashed_dir).join(entry_name);
+        let name = self.translator.get_cleartext_name(entry_path, &dir_id)?;
+        Ok(cleartext_dir.join(name))
+    }
+
+    /// Open the encrypted file at a ciphertext path without resolving its cleartext path. The
+    /// path may be absolute, or relative to the vault, and may point to a file entry, a shortened
+    /// entry, the `contents.c9r` or `symlink.c9r` inside one, or a `dirid.c9r` backup.
+    ///
+    /// Only the file header is read, so a damaged file fails with an error naming it, and damaged
+    /// chunks are only noticed once they're read.
+    pub fn open_ciphertext(
+        &self,
+        ciphertext_path: impl AsRef<Path>,
+        options: OpenOptions,
+    ) -> Result<EncryptedFile<'v>> {
+        let ciphertext_path = ciphertext_path.as_ref();
+        let _write = match writes(&options) {
+            true => Some(self.check_writable()?),
+            false => None,
+        };
+        let data_dir = self.vault.path().join("d");
+        let components = data_path_components(&data_dir, ciphertext_path)?;
+
+        let entry_path = data_dir.join(components.iter().collect::<PathBuf>());
+        let file_path = match components.as_slice() {
+            [_, _, _] if self.storage().is_dir(&entry_path) => [
+                entry_path.join("contents.c9r"),
+                entry_path.join("symlink.c9r"),
+            ]
+            .into_iter()
+            .find(|path| self.storage().is_file(path))
+            .ok_or_else(|| eyre!("not an encrypted file: {}", ciphertext_path.display()))?,
+            [_, _, _] => entry_path,
+            [_, _, _, name] if *name == "contents.c9r" || *name == "symlink.c9r" => entry_path,
+            _ => bail!("not an encrypted file: {}", ciphertext_path.display()),
+        };
+
+        let mut file =
+            EncryptedFile::open_in(self.storage(), self.vault.cryptor(), &file_path, options)
+                .wrap_err_with(|| format!("failed to open {}", file_path.display()))?;
+        file.set_observer(self.observer.clone());
+        file.set_freeze(self.freeze.clone(), self.while_frozen);
+
+        Ok(file)
+    }
+
+    /// Decrypt the name of a single ciphertext entry in the directory with ID `parent_dir_id`.
+    /// Shortened `.c9s` names can't be decrypted from the name alone, so pass the full ciphertext
+    /// name from their `name.c9s` instead.
+    pub fn decrypt_ciphertext_name(
+        &self,
+        ciphertext_file_name: impl AsRef<OsStr>,
+        parent_dir_id: &DirId,
+    ) -> Result<String> {
+        let ciphertext_file_name = ciphertext_file_name.as_ref();
+        let Some(name) = ciphertext_file_name.to_str() else {
+            bail!(
+                "invalid ciphertext name: {}",
+                ciphertext_file_name.display()
+            );
+        };
+        if name.ends_with(".c9s") {
+            bail!("shortened name {name} must be read from its name.c9s");
+        }
+
+        self.vault.cryptor().decrypt_filename(name, parent_dir_id)
+    }
+
+    pub(super) fn hashed_dir_path(&self, dir_id: &DirId) -> Result<PathBuf> {
+        Ok(self
+            .vault
+            .path()
+            .join("d")
+            .join(self.vault.cryptor().hash_dir_id(dir_id)?))
+    }
+
+    // Find the cleartext directory for a hashed directory path, relative to `d`.
+    fn cleartext_dir(&self, hashed_dir: &Path) -> Result<PathBuf> {
+        let mut dir_paths = self.dir_paths.lock().unwrap();
+
+        // Directories may have moved since the index was built, so check that the cached path
+        // still leads to the same place
+        if let Some(cleartext_dir) = dir_paths.get(hashed_dir) {
+            self.observe(|observer| observer.on_cache_hit(CacheKind::DirPath));
+            let current = self
+                .translator
+                .get_dir_id_uncached(cleartext_dir)
+                .and_then(|dir_id| self.vault.cryptor().hash_dir_id(&dir_id));
+            if current.is_ok_and(|current| current == hashed_dir) {
+                return Ok(cleartext_dir.clone());
+            }
+        }
+
+        self.observe(|observer| observer.on_cache_miss(CacheKind::DirPath));
+
+        // Whatever moved the directory may also have invalidated cached directory IDs
+        self.translator.invalidate("/");
+        *dir_paths = self.index_dirs()?;
+        if let Some(cleartext_dir) = dir_paths.get(hashed_dir) {
+            return Ok(cleartext_dir.clone());
+        }
+
+        // The directory isn't reachable from the root, but its dirid.c9r can at least say which
+        // directory it is
+        match self.read_dir_id_backup(&self.vault.path().join("d").join(hashed_dir)) {
+            Ok(dir_id) => bail!("directory {dir_id} is not reachable from the root directory"),
+            Err(_) => bail!("unknown directory: {}", hashed_dir.display()),
+        }
+    }
+
+    // Walk the vault to map every hashed directory to its cleartext path.
+    pub(super) fn index_dirs(&self) -> Result<HashMap<PathBuf, PathBuf>> {
+        let cryptor = self.vault.cryptor();
+        let mut dir_paths = HashMap::from([(cryptor.hash_dir_id(&DirId::root())?, "/".into())]);
+
+        // Entries that can't be read are skipped, since they can't be translated anyway
+        for entry in self.walk("/").filter_map(Result::ok) {
+            if entry.entry.kind != FileKind::Directory {
+                continue;
+            }
+
+            if let Ok(dir_id) = read_dir_id(self.storage(), &entry.ciphertext_path) {
+                dir_paths.insert(cryptor.hash_dir_id(&dir_id)?, entry.path);
+            }
+        }
+
+        Ok(dir_paths)
+    }
+
+    pub(super) fn read_dir_id_backup(&self, hashed_dir_path: &Path) -> Result<DirId> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut dir_id = String::new();
+        EncryptedFile::open_in(
+            self.storage(),
+            self.vault.cryptor(),
+            hashed_dir_path.join("dirid.c9r"),
+            options,
+        )?
+        .read_to_string(&mut dir_id)?;
+
+        DirId::parse(&dir_id)
+    }
+}
+
+// Split a ciphertext path into its components below `d`, which start with the two components of
+// a hashed directory.
+fn data_path_components<'a>(data_dir: &Path, ciphertext_path: &'a Path) -> Result<Vec<&'a OsStr>> {
+    let relative_path = if ciphertext_path.is_absolute() {
+        ciphertext_path.strip_prefix(data_dir).ok()
+    } else {
+        ciphertext_path.strip_prefix("d").ok()
+    }
+    .ok_or_else(|| eyre!("not a path in the vault: {}", ciphertext_path.display()))?;
+
+    let components = relative_path
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => Ok(name),
+            _ => Err(eyre!(
+                "invalid ciphertext path: {}",
+                ciphertext_path.display()
+            )),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if components.len() < 2 {
+        bail!("not a path in the vault: {}", ciphertext_path.display());
+    }
+
+    Ok(components)
+}
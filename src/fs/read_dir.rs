@@ -0,0 +1,266 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    vec,
+};
+
+use tracing::{field, instrument};
+
+use super::{
+    check_path, is_temp_name, record_ciphertext_path, sync_conflict_original, DirEntry,
+    EncryptedFileSystem, EntryError, EntryErrorReason, FsError,
+};
+use crate::{crypto::DirId, VaultLocked};
+
+/// An iterator over the entries of a cleartext directory, created by
+/// [`EncryptedFileSystem::read_dir`].
+///
+/// Entries that can't be listed come first, ordered by ciphertext name, followed by the rest
+/// ordered by cleartext name. Names are all decrypted up front, but nothing else is read for an
+/// entry until it's yielded, so skipping entries with [`Iterator::skip`] or [`Iterator::nth`] is
+/// cheap. Entries removed in the meantime are left out.
+pub struct ReadDir<'v> {
+    fs: EncryptedFileSystem<'v>,
+    entries: vec::IntoIter<Listed>,
+}
+
+// A ciphertext entry whose name has been read, but not necessarily anything else.
+struct Listed {
+    ciphertext_path: PathBuf,
+    state: ListedState,
+}
+
+enum ListedState {
+    // Looked up once it's yielded
+    Unread(PathBuf),
+    // Sync conflict copies are looked up while they're resolved
+    Read(PathBuf, DirEntry),
+    Failed(FsError, EntryErrorReason),
+}
+
+type ListedResult = Result<(PathBuf, DirEntry), (FsError, EntryError)>;
+
+impl ReadDir<'_> {
+    // Look up the next entry, describing it for a lossy listing if that fails.
+    pub(super) fn next_listed(&mut self) -> Option<ListedResult> {
+        loop {
+            let Listed {
+                ciphertext_path,
+                state,
+            } = self.entries.next()?;
+            let (err, reason) = match state {
+                ListedState::Unread(cleartext_path) => {
+                    match self.fs.ciphertext_dir_entry(&ciphertext_path) {
+                        Ok(entry) => return Some(Ok((cleartext_path, entry))),
+                        Err(FsError::NotFound) => continue,
+                        Err(err) => {
+                            let reason = EntryErrorReason::Invalid(err.to_string());
+                            (err, reason)
+                        }
+                    }
+                }
+                ListedState::Read(cleartext_path, entry) => {
+                    return Some(Ok((cleartext_path, entry)))
+                }
+                ListedState::Failed(err, reason) => (err, reason),
+            };
+
+            let error = EntryError {
+                // Ok to unwrap, this came from a directory listing
+                ciphertext_name: ciphertext_path.file_name().unwrap().to_os_string(),
+                reason,
+            };
+            return Some(Err((err, error)));
+        }
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = Result<(PathBuf, DirEntry), FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_listed()
+            .map(|result| result.map_err(|(err, _)| err))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        if n > 0 {
+            self.entries.nth(n - 1);
+        }
+        self.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.entries.len()))
+    }
+}
+
+impl<'v> EncryptedFileSystem<'v> {
+    /// Iterate over the cleartext directory `cleartext_dir` in a fixed order, only reading each
+    /// entry as it's yielded. See [`ReadDir`] for details.
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %self.traced(cleartext_dir.as_ref()), ciphertext_path = field::Empty)
+    )]
+    pub fn read_dir(&self, cleartext_dir: impl AsRef<Path>) -> Result<ReadDir<'v>, FsError> {
+        let cleartext_dir = cleartext_dir.as_ref();
+        self.with_context("list", cleartext_dir, || {
+            check_path(cleartext_dir)?;
+            let (dir_id, hashed_dir_path) = self.translator.get_dir_id_and_path(cleartext_dir)?;
+            record_ciphertext_path(&hashed_dir_path);
+            // Entries that are being created or removed may be incomplete
+            let _guard = self.dir_locks.lock(&[&dir_id]);
+
+            self.read_dir_locked(cleartext_dir, &dir_id, &hashed_dir_path)
+        })
+    }
+
+    /// Like [`EncryptedFileSystem::read_dir`], for callers that already hold the lock of the
+    /// directory with ID `dir_id`.
+    pub(super) fn read_dir_locked(
+        &self,
+        cleartext_dir: &Path,
+        dir_id: &DirId,
+        hashed_dir_path: &Path,
+    ) -> Result<ReadDir<'v>, FsError> {
+        let mut entries = BTreeMap::new();
+        let mut failed = Vec::new();
+        let mut conflicts = Vec::new();
+        for file_name in self.storage().list(hashed_dir_path)? {
+            if file_name == "dirid.c9r" || is_temp_name(&file_name) {
+                continue;
+            }
+            let ciphertext_path = hashed_dir_path.join(&file_name);
+
+            // Conflict copies are named after the other entries, so they come last. Shortened
+            // ones would otherwise be listed under the original name from their name.c9s.
+            if let Some(original_name) = file_name.to_str().and_then(sync_conflict_original) {
+                conflicts.push((ciphertext_path, original_name));
+                continue;
+            }
+
+            // Look up the entry by the ciphertext path we already have, rather than encrypting
+            // and possibly shortening the name again
+            match self.translator.get_cleartext_name(&ciphertext_path, dir_id) {
+                Ok(name) => {
+                    let cleartext_path = cleartext_dir.join(name);
+                    let state = ListedState::Unread(cleartext_path.clone());
+                    entries.insert(
+                        cleartext_path,
+                        Listed {
+                            ciphertext_path,
+                            state,
+                        },
+                    );
+                }
+                // Nothing in the directory can be read, so it's not a problem with this entry
+                Err(err) if err.is::<VaultLocked>() => return Err(FsError::VaultLocked),
+                Err(err) => {
+                    let reason = EntryErrorReason::Invalid(err.to_string());
+                    let err = FsError::NameDecryption {
+                        ciphertext_name: file_name,
+                        source: err.into(),
+                    };
+                    failed.push(Listed {
+                        ciphertext_path,
+                        state: ListedState::Failed(err, reason),
+                    });
+                }
+            }
+        }
+
+        for (ciphertext_path, original_name) in conflicts {
+            let result = self.resolve_conflict(
+                cleartext_dir,
+                dir_id,
+                &ciphertext_path,
+                &original_name,
+                &entries,
+            );
+            let state = match result {
+                Ok(Some((cleartext_path, entry))) => {
+                    let state = ListedState::Read(cleartext_path.clone(), entry);
+                    entries.insert(
+                        cleartext_path,
+                        Listed {
+                            ciphertext_path,
+                            state,
+                        },
+                    );
+                    continue;
+                }
+                Ok(None) => continue,
+                Err(err) => ListedState::Failed(
+                    err.into(),
+                    EntryErrorReason::SyncConflict { original_name },
+                ),
+            };
+            failed.push(Listed {
+                ciphertext_path,
+                state,
+            });
+        }
+
+        failed.sort_by(|a, b| a.ciphertext_path.cmp(&b.ciphertext_path));
+        failed.extend(entries.into_values());
+        Ok(ReadDir {
+            fs: self.clone(),
+            entries: failed.into_iter(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs, fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::testing::{self, VaultShape};
+
+    #[test]
+    fn read_dir_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 20,
+            ..Default::default()
+        };
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let mut paths = (0..shape.files_per_dir)
+            .map(|n| shape.file_path("/", n))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let listed = fs
+            .read_dir("/")
+            .unwrap()
+            .map(|result| result.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(listed, paths);
+        assert_eq!(
+            fs.dir_entries("/").unwrap().into_keys().collect::<Vec<_>>(),
+            paths
+        );
+
+        // A page in the middle
+        let page = fs
+            .read_dir("/")
+            .unwrap()
+            .skip(5)
+            .take(3)
+            .map(|result| result.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(page, paths[5..8]);
+
+        // An entry that is neither a file, directory, nor symlink only fails once it's reached
+        fs.mkdir("/", OsStr::new("broken"), Permissions::from_mode(0o755))
+            .unwrap();
+        fs::remove_file(fs.ciphertext_path("/broken").unwrap().join("dir.c9r")).unwrap();
+        let mut read_dir = fs.read_dir("/").unwrap();
+        assert!(matches!(read_dir.next(), Some(Err(FsError::Other(_)))));
+        let mut read_dir = fs.read_dir("/").unwrap();
+        assert_eq!(read_dir.nth(1).unwrap().unwrap().0, paths[0]);
+        assert_eq!(read_dir.count(), paths.len() - 1);
+    }
+}
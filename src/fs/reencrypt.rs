@@ -0,0 +1,178 @@
+use std::{
+    fs::Permissions,
+    io,
+    ops::ControlFlow,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::bail;
+
+use super::{DirEntry, EncryptedFileSystem, FileKind};
+use crate::{
+    storage::OpenOptions, vault::CONFIG_FILE_NAME, Passphrase, Result, Vault, VaultCreateOptions,
+};
+
+/// A summary of a [`Vault::reencrypt`] run.
+#[derive(Debug, Clone, Default)]
+pub struct ReencryptReport {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Entries that were already present in the destination vault from a previous run.
+    pub skipped: u64,
+    /// Cleartext paths of entries that could not be copied, along with the reason.
+    pub errors: Vec<(PathBuf, String)>,
+    /// Whether the run was stopped early by the progress callback.
+    pub interrupted: bool,
+}
+
+struct Reencryptor<'a, P> {
+    src: EncryptedFileSystem<'a>,
+    dst: EncryptedFileSystem<'a>,
+    progress: P,
+    report: ReencryptReport,
+}
+
+impl<'a, P: FnMut(&Path) -> ControlFlow<()>> Reencryptor<'a, P> {
+    fn error(&mut self, cleartext_path: &Path, err: impl ToString) {
+        self.report
+            .errors
+            .push((cleartext_path.to_path_buf(), err.to_string()));
+    }
+
+    fn copy_dir(&mut self, cleartext_dir: &Path) -> ControlFlow<()> {
+        let entries = match self.src.dir_entries(cleartext_dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                self.error(cleartext_dir, err);
+                return ControlFlow::Continue(());
+            }
+        };
+
+        for (cleartext_path, entry) in entries {
+            if (self.progress)(&cleartext_path).is_break() {
+                self.report.interrupted = true;
+                return ControlFlow::Break(());
+            }
+
+            if let Err(err) = self.copy_entry(&cleartext_path, &entry) {
+                self.error(&cleartext_path, err);
+                continue;
+            }
+
+            if entry.kind == FileKind::Directory {
+                self.copy_dir(&cleartext_path)?;
+
+                // Adding entries changes the directory's times, and read-only permissions would
+                // have prevented adding them, so both are copied last
+                if let Err(err) = self.copy_metadata(&cleartext_path, &entry) {
+                    self.error(&cleartext_path, err);
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    fn copy_entry(&mut self, cleartext_path: &Path, entry: &DirEntry) -> Result<()> {
+        // Ok to unwrap, the root directory is never copied
+        let parent = cleartext_path.parent().unwrap();
+        let name = cleartext_path.file_name().unwrap();
+
+        if let Ok(existing) = self.dst.dir_entry(cleartext_path) {
+            if existing.kind != entry.kind {
+                bail!("destination already contains a different type of entry");
+            }
+
+            // A file with the wrong size was only partially written by a previous run
+            if existing.kind != FileKind::File || existing.size == entry.size {
+                self.report.skipped += 1;
+                return Ok(());
+            }
+
+            self.dst.unlink(parent, name)?;
+        }
+
+        match entry.kind {
+            FileKind::File => {
+                // Permissions are copied after writing, in case the file is read-only
+                self.dst
+                    .mknod(parent, name, Permissions::from_mode(0o600))?;
+
+                let mut read_options = OpenOptions::new();
+                read_options.read(true);
+                let mut src_file = self.src.open_file(cleartext_path, read_options, false)?;
+                let mut write_options = OpenOptions::new();
+                write_options.read(true).write(true);
+                let mut dst_file = self.dst.open_file(cleartext_path, write_options, false)?;
+                io::copy(&mut src_file, &mut dst_file)?;
+                dst_file.sync_all()?;
+                drop(dst_file);
+
+                self.copy_metadata(cleartext_path, entry)?;
+                self.report.files += 1;
+            }
+            FileKind::Directory => {
+                self.dst
+                    .mkdir(parent, name, Permissions::from_mode(0o700))?;
+                self.report.directories += 1;
+            }
+            FileKind::Symlink => {
+                let target = self.src.link_target(cleartext_path)?;
+                self.dst.symlink(parent, name, target)?;
+                self.copy_metadata(cleartext_path, entry)?;
+                self.report.symlinks += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn copy_metadata(&self, cleartext_path: &Path, entry: &DirEntry) -> Result<()> {
+        self.dst.copy_metadata(cleartext_path, &entry.metadata)
+    }
+}
+
+impl Vault {
+    /// Copy the contents of this vault into a new vault at `dst_path`, which is encrypted with a
+    /// new master key and protected by `new_password`. Timestamps and permissions are preserved.
+    ///
+    /// `progress` is called with each cleartext path before it is copied, and can stop the run
+    /// early by returning [`ControlFlow::Break`]. Running this again with the same destination
+    /// resumes the copy, skipping entries that are already present. Entries that can't be copied
+    /// are listed in the report rather than aborting the run.
+    pub fn reencrypt(
+        &self,
+        dst_path: impl AsRef<Path>,
+        new_password: impl Into<Passphrase>,
+        progress: impl FnMut(&Path) -> ControlFlow<()>,
+    ) -> Result<ReencryptReport> {
+        let dst_path = dst_path.as_ref();
+        let dst_vault = if dst_path.join(CONFIG_FILE_NAME).try_exists()? {
+            Vault::open(dst_path.join(CONFIG_FILE_NAME), new_password)?
+        } else {
+            let claims = &self.config().claims;
+            let options = VaultCreateOptions {
+                cipher_combo: claims.cipher_combo,
+                shortening_threshold: claims.shortening_threshold,
+                ..Default::default()
+            };
+            Vault::create(dst_path, new_password, options)?
+        };
+
+        if *dst_vault.master_key()? == *self.master_key()? {
+            bail!("destination vault uses the same master key");
+        }
+
+        let mut reencryptor = Reencryptor {
+            src: EncryptedFileSystem::new(self),
+            dst: EncryptedFileSystem::new(&dst_vault),
+            progress,
+            report: Default::default(),
+        };
+        let _ = reencryptor.copy_dir(Path::new("/"));
+
+        Ok(reencryptor.report)
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use super::{EncryptedFileSystem, FileKind, WalkEntry};
+use crate::{Result, Vault};
+
+/// Options for [`Vault::stats_with_progress`].
+#[derive(Debug, Clone, Copy)]
+pub struct StatsOptions {
+    /// How many files to include in [`VaultStats::largest_files`].
+    pub largest_files: usize,
+    /// Scan directories on multiple threads.
+    pub parallel: bool,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        Self {
+            largest_files: 10,
+            parallel: false,
+        }
+    }
+}
+
+/// Counts and sizes of the entries in a vault. Only file contents are counted towards the byte
+/// totals, so comparing them shows the overhead of encryption.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VaultStats {
+    pub files: u64,
+    /// The number of directories, not including the root directory.
+    pub directories: u64,
+    pub symlinks: u64,
+    pub cleartext_bytes: u64,
+    pub ciphertext_bytes: u64,
+    /// The cleartext path with the most components, if the vault isn't empty.
+    pub deepest_path: Option<PathBuf>,
+    /// The largest files in the vault as (cleartext path, cleartext size) pairs, largest first.
+    pub largest_files: Vec<(PathBuf, u64)>,
+    /// The number of entries that could not be decrypted or read.
+    pub errors: u64,
+}
+
+// Stats for part of a vault, which can be merged with others
+#[derive(Default)]
+struct PartialStats {
+    stats: VaultStats,
+    max_depth: usize,
+    largest_files: BinaryHeap<Reverse<(u64, PathBuf)>>,
+}
+
+impl PartialStats {
+    fn add_path(&mut self, cleartext_path: &Path) {
+        let depth = cleartext_path.components().count() - 1;
+        if depth > self.max_depth {
+            self.max_depth = depth;
+            self.stats.deepest_path = Some(cleartext_path.to_path_buf());
+        }
+    }
+
+    fn add_file(&mut self, cleartext_path: PathBuf, size: u64, limit: usize) {
+        self.largest_files.push(Reverse((size, cleartext_path)));
+        if self.largest_files.len() > limit {
+            self.largest_files.pop();
+        }
+    }
+
+    fn merge(&mut self, other: PartialStats, limit: usize) {
+        self.stats.files += other.stats.files;
+        self.stats.directories += other.stats.directories;
+        self.stats.symlinks += other.stats.symlinks;
+        self.stats.cleartext_bytes += other.stats.cleartext_bytes;
+        self.stats.ciphertext_bytes += other.stats.ciphertext_bytes;
+        self.stats.errors += other.stats.errors;
+
+        if other.max_depth > self.max_depth {
+            self.max_depth = other.max_depth;
+            self.stats.deepest_path = other.stats.deepest_path;
+        }
+
+        for Reverse((size, path)) in other.largest_files {
+            self.add_file(path, size, limit);
+        }
+    }
+
+    fn finish(mut self) -> VaultStats {
+        self.stats.largest_files = self
+            .largest_files
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, path))| (path, size))
+            .collect();
+        self.stats
+    }
+}
+
+// Tally the entries of a single directory, returning the subdirectories to scan next.
+fn scan_dir(
+    fs: &EncryptedFileSystem,
+    cleartext_dir: &Path,
+    options: &StatsOptions,
+    progress: &(impl Fn(&Path) + Sync),
+    partial: &mut PartialStats,
+) -> Vec<PathBuf> {
+    let mut subdirs = Vec::new();
+    // Unlike dir_entries, a bad entry shouldn't prevent counting the rest of the directory
+    for entry in fs.walk(cleartext_dir).max_depth(1) {
+        let WalkEntry {
+            path: cleartext_path,
+            entry: dir_entry,
+            ..
+        } = match entry {
+            Ok(entry) => entry,
+            Err(_) => {
+                partial.stats.errors += 1;
+                continue;
+            }
+        };
+
+        progress(&cleartext_path);
+        partial.add_path(&cleartext_path);
+        match dir_entry.kind {
+            FileKind::File => {
+                partial.stats.files += 1;
+                partial.stats.cleartext_bytes += dir_entry.size;
+                partial.stats.ciphertext_bytes += dir_entry.metadata.len();
+                partial.add_file(cleartext_path, dir_entry.size, options.largest_files);
+            }
+            FileKind::Directory => {
+                partial.stats.directories += 1;
+                subdirs.push(cleartext_path);
+            }
+            FileKind::Symlink => partial.stats.symlinks += 1,
+        }
+    }
+
+    subdirs
+}
+
+impl Vault {
+    /// Count the entries in this vault and their sizes. File contents are not decrypted.
+    pub fn stats(&self) -> Result<VaultStats> {
+        self.stats_with_progress(Default::default(), |_| {})
+    }
+
+    /// Same as [`Vault::stats`], but with custom options. `progress` is called with each cleartext
+    /// path as it is scanned, possibly from multiple threads.
+    pub fn stats_with_progress(
+        &self,
+        options: StatsOptions,
+        progress: impl Fn(&Path) + Sync,
+    ) -> Result<VaultStats> {
+        let fs = EncryptedFileSystem::new(self);
+        let mut stats = PartialStats::default();
+        let mut level = vec![PathBuf::from("/")];
+
+        // Scan one level of the directory tree at a time, splitting each level between threads
+        while !level.is_empty() {
+            let threads = match options.parallel {
+                true => thread::available_parallelism().map_or(1, |n| n.get()),
+                false => 1,
+            };
+
+            if threads == 1 || level.len() == 1 {
+                let mut next_level = Vec::new();
+                for dir in &level {
+                    next_level.extend(scan_dir(&fs, dir, &options, &progress, &mut stats));
+                }
+                level = next_level;
+                continue;
+            }
+
+            let chunk_len = level.len().div_ceil(threads);
+            let (fs, options, progress) = (&fs, &options, &progress);
+            let results: Vec<_> = thread::scope(|s| {
+                let handles: Vec<_> = level
+                    .chunks(chunk_len)
+                    .map(|dirs| {
+                        s.spawn(move || {
+                            let mut partial = PartialStats::default();
+                            let mut next_level = Vec::new();
+                            for dir in dirs {
+                                next_level.extend(scan_dir(
+                                    fs,
+                                    dir,
+                                    options,
+                                    progress,
+                                    &mut partial,
+                                ));
+                            }
+                            (partial, next_level)
+                        })
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle
+                            .join()
+                            .unwrap_or_else(|err| std::panic::resume_unwind(err))
+                    })
+                    .collect()
+            });
+
+            level = Vec::new();
+            for (partial, next_level) in results {
+                stats.merge(partial, options.largest_files);
+                level.extend(next_level);
+            }
+        }
+
+        Ok(stats.finish())
+    }
+}
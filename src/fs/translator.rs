@@ -1,23 +1,162 @@
 use std::{
+    collections::HashMap,
     ffi::OsStr,
-    fs,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
 };
 
 use base64ct::{Base64Url, Encoding};
-use color_eyre::eyre::bail;
+use color_eyre::eyre::{bail, WrapErr};
 use sha1::{Digest, Sha1};
 
-use crate::{crypto::FileCryptor, Result, Vault};
+use super::{
+    dir_cache_file::{DirFileStamp, PersistedDir},
+    CacheKind, FsError, Observer,
+};
+use crate::{
+    crypto::{DirId, FileCryptor},
+    storage::Metadata,
+    Result, Vault,
+};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Translator<'v> {
     vault: &'v Vault,
+    dirs: Arc<DirCache>,
+    observer: Option<Arc<dyn Observer>>,
+}
+
+/// Cleartext directory paths mapped to their directory IDs and hashed directory paths, so
+/// resolving a path doesn't need to read every dir.c9r along the way.
+#[derive(Debug, Default)]
+struct DirCache {
+    capacity: usize,
+    entries: RwLock<HashMap<PathBuf, CachedDir>>,
+    // Loaded from a persistent cache file, and only trusted once their dir.c9r is found unchanged
+    persisted: RwLock<HashMap<PathBuf, PersistedDir>>,
+    // Incremented on every lookup, to find the least recently used entry
+    clock: AtomicU64,
+    #[cfg(test)]
+    dir_id_reads: std::sync::atomic::AtomicUsize,
+}
+
+#[derive(Debug)]
+struct CachedDir {
+    dir_id: DirId,
+    hashed_dir_path: PathBuf,
+    // The dir.c9r the ID was read from, which the root directory doesn't have
+    stamp: Option<DirFileStamp>,
+    // Updated by lookups, which only hold a read lock
+    last_used: AtomicU64,
+}
+
+impl DirCache {
+    fn get(&self, cleartext_path: &Path) -> Option<(DirId, PathBuf)> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        let entries = self.entries.read().unwrap();
+        let entry = entries.get(cleartext_path)?;
+        entry.last_used.store(now, Ordering::Relaxed);
+        Some((entry.dir_id.clone(), entry.hashed_dir_path.clone()))
+    }
+
+    fn insert(
+        &self,
+        cleartext_path: &Path,
+        dir_id: DirId,
+        hashed_dir_path: PathBuf,
+        stamp: Option<DirFileStamp>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(cleartext_path) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        let last_used = AtomicU64::new(self.clock.load(Ordering::Relaxed));
+        entries.insert(
+            cleartext_path.to_path_buf(),
+            CachedDir {
+                dir_id,
+                hashed_dir_path,
+                stamp,
+                last_used,
+            },
+        );
+    }
 }
 
 impl<'v> Translator<'v> {
-    pub fn new(vault: &'v Vault) -> Self {
-        Self { vault }
+    pub fn new(vault: &'v Vault, cache_capacity: usize) -> Self {
+        Self {
+            vault,
+            dirs: Arc::new(DirCache {
+                capacity: cache_capacity,
+                ..Default::default()
+            }),
+            observer: None,
+        }
+    }
+
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) {
+        self.observer = observer;
+    }
+
+    /// Forget the cached directory IDs of `cleartext_path` and everything below it.
+    pub fn invalidate(&self, cleartext_path: impl AsRef<Path>) {
+        let cleartext_path = cleartext_path.as_ref();
+        self.dirs
+            .entries
+            .write()
+            .unwrap()
+            .retain(|path, _| !path.starts_with(cleartext_path));
+        self.dirs
+            .persisted
+            .write()
+            .unwrap()
+            .retain(|path, _| !path.starts_with(cleartext_path));
+    }
+
+    /// Add directories from a persistent cache file, which are checked as they're looked up.
+    pub fn load_persisted(&self, dirs: Vec<PersistedDir>) {
+        let mut persisted = self.dirs.persisted.write().unwrap();
+        for dir in dirs {
+            persisted.insert(dir.cleartext_path.clone(), dir);
+        }
+    }
+
+    /// The cached directories to write to a persistent cache file, including those loaded from
+    /// one that haven't been looked up since.
+    pub fn persisted(&self) -> Vec<PersistedDir> {
+        let mut dirs = self.dirs.persisted.read().unwrap().clone();
+        for (path, entry) in self.dirs.entries.read().unwrap().iter() {
+            let (Some(stamp), Ok(hashed_dir_path)) = (
+                entry.stamp,
+                entry.hashed_dir_path.strip_prefix(self.vault.path()),
+            ) else {
+                continue;
+            };
+            let dir = PersistedDir {
+                cleartext_path: path.clone(),
+                dir_id: entry.dir_id.as_str().to_string(),
+                hashed_dir_path: hashed_dir_path.to_path_buf(),
+                stamp,
+            };
+            dirs.insert(path.clone(), dir);
+        }
+
+        dirs.into_values().collect()
     }
 
     /// Translates a cleartext name to its full, unshortened ciphertext name, including .c9r
@@ -25,9 +164,11 @@ impl<'v> Translator<'v> {
     pub fn get_full_ciphertext_name(
         &self,
         cleartext_name: impl AsRef<OsStr>,
-        dir_id: impl AsRef<str>,
+        dir_id: &DirId,
     ) -> Result<String> {
-        Ok(self.vault.cryptor().encrypt_name(cleartext_name, dir_id)? + ".c9r")
+        self.vault
+            .cryptor()
+            .encrypt_filename(cleartext_name.as_ref(), dir_id)
     }
 
     /// Translates a cleartext path to a ciphertext path, which may be shortened depending on vault
@@ -35,10 +176,12 @@ impl<'v> Translator<'v> {
     pub fn get_ciphertext_path(
         &self,
         cleartext_path: impl AsRef<Path>,
-        dir_id: impl AsRef<str>,
+        dir_id: &DirId,
     ) -> Result<PathBuf> {
-        let cleartext_name = cleartext_path.as_ref().file_name().unwrap();
-        let ciphertext_name = self.get_full_ciphertext_name(cleartext_name, &dir_id)?;
+        let Some(cleartext_name) = cleartext_path.as_ref().file_name() else {
+            return Err(FsError::InvalidPath.into());
+        };
+        let ciphertext_name = self.get_full_ciphertext_name(cleartext_name, dir_id)?;
         let hashed_dir_id = self.vault.cryptor().hash_dir_id(dir_id)?;
         let path = self.vault.path().join("d").join(hashed_dir_id);
         let final_name =
@@ -54,53 +197,280 @@ impl<'v> Translator<'v> {
 
     /// Translates a cleartext directory path to its directory ID, or translates a cleartext file
     /// path to its containing directory's ID.
-    pub fn get_dir_id(&self, cleartext_path: impl AsRef<Path>) -> Result<String> {
-        let parent_dir_id = match cleartext_path.as_ref().parent() {
-            Some(parent) => self.get_dir_id(parent)?,
-            None => return Ok(String::new()),
+    pub fn get_dir_id(&self, cleartext_path: impl AsRef<Path>) -> Result<DirId> {
+        Ok(self.get_dir(cleartext_path.as_ref(), true, false)?.0)
+    }
+
+    /// Like [`Translator::get_dir_id`], but also gives the path of the hashed directory. Fails
+    /// with [`FsError::NotADirectory`] if `cleartext_dir` isn't a directory.
+    pub fn get_dir_id_and_path(&self, cleartext_dir: impl AsRef<Path>) -> Result<(DirId, PathBuf)> {
+        self.get_dir(cleartext_dir.as_ref(), true, true)
+    }
+
+    /// Like [`Translator::get_dir_id`], but reads every dir.c9r along the way rather than trusting
+    /// the cache.
+    pub fn get_dir_id_uncached(&self, cleartext_path: impl AsRef<Path>) -> Result<DirId> {
+        Ok(self.get_dir(cleartext_path.as_ref(), false, false)?.0)
+    }
+
+    // The last component of `cleartext_path` may be a file, in which case its parent is
+    // returned, but the components before it must be directories.
+    fn get_dir(
+        &self,
+        cleartext_path: &Path,
+        use_cache: bool,
+        must_be_dir: bool,
+    ) -> Result<(DirId, PathBuf)> {
+        if use_cache {
+            let cached = self.dirs.get(cleartext_path);
+            if let Some(observer) = &self.observer {
+                match cached {
+                    Some(_) => observer.on_cache_hit(CacheKind::DirId),
+                    None => observer.on_cache_miss(CacheKind::DirId),
+                }
+            }
+            if let Some(dir) = cached {
+                return Ok(dir);
+            }
+        }
+
+        let (dir_id, hashed_dir_path, stamp) = match cleartext_path.parent() {
+            Some(parent) => {
+                let parent = self.get_dir(parent, use_cache, true)?;
+                let ciphertext_path = self.get_ciphertext_path(cleartext_path, &parent.0)?;
+                let storage = self.vault.storage();
+                let dir_file = storage
+                    .metadata(&ciphertext_path.join("dir.c9r"))
+                    .ok()
+                    .filter(Metadata::is_file);
+                let Some(dir_file) = dir_file else {
+                    if !must_be_dir {
+                        return Ok(parent);
+                    }
+                    match storage.exists(&ciphertext_path) {
+                        true => return Err(FsError::NotADirectory.into()),
+                        false => return Err(FsError::NotFound.into()),
+                    }
+                };
+
+                let stamp = DirFileStamp::new(&dir_file);
+                let persisted = use_cache
+                    .then(|| self.persisted_dir(cleartext_path, stamp))
+                    .flatten();
+                match persisted {
+                    Some((dir_id, hashed_dir_path)) => (dir_id, hashed_dir_path, stamp),
+                    None => {
+                        #[cfg(test)]
+                        self.dirs.dir_id_reads.fetch_add(1, Ordering::Relaxed);
+                        let dir_id = super::read_dir_id(storage, &ciphertext_path)?;
+                        let hashed_dir_path = self.hashed_dir_path(&dir_id)?;
+                        (dir_id, hashed_dir_path, stamp)
+                    }
+                }
+            }
+            None => (DirId::root(), self.hashed_dir_path(&DirId::root())?, None),
         };
 
-        let ciphertext_path = self.get_ciphertext_path(cleartext_path, &parent_dir_id)?;
+        self.dirs.insert(
+            cleartext_path,
+            dir_id.clone(),
+            hashed_dir_path.clone(),
+            stamp,
+        );
+        Ok((dir_id, hashed_dir_path))
+    }
 
-        if ciphertext_path.join("dir.c9r").is_file() {
-            Ok(fs::read_to_string(ciphertext_path.join("dir.c9r"))?)
-        } else {
-            Ok(parent_dir_id)
+    // A directory from the persistent cache, if its dir.c9r hasn't changed since the ID was read
+    // from it and its hashed directory still exists. Otherwise it's forgotten, so the directory
+    // is resolved again.
+    fn persisted_dir(
+        &self,
+        cleartext_path: &Path,
+        stamp: Option<DirFileStamp>,
+    ) -> Option<(DirId, PathBuf)> {
+        let persisted = self.dirs.persisted.read().unwrap();
+        let dir = persisted.get(cleartext_path)?;
+        let hashed_dir_path = self.vault.path().join(&dir.hashed_dir_path);
+        match dir.dir_id() {
+            Ok(dir_id)
+                if Some(dir.stamp) == stamp && self.vault.storage().is_dir(&hashed_dir_path) =>
+            {
+                Some((dir_id, hashed_dir_path))
+            }
+            _ => {
+                drop(persisted);
+                self.dirs.persisted.write().unwrap().remove(cleartext_path);
+                None
+            }
         }
     }
 
+    fn hashed_dir_path(&self, dir_id: &DirId) -> Result<PathBuf> {
+        Ok(self
+            .vault
+            .path()
+            .join("d")
+            .join(self.vault.cryptor().hash_dir_id(dir_id)?))
+    }
+
     /// Translates a ciphertext path (either full-length or shortened) into the decrypted filename
     /// of the corresponding cleartext file.
-    // TODO: Refactor this if possible
     pub fn get_cleartext_name(
         &self,
         ciphertext_path: impl AsRef<Path>,
-        dir_id: impl AsRef<str>,
+        dir_id: &DirId,
     ) -> Result<String> {
-        match ciphertext_path.as_ref().extension() {
-            Some(extension) => {
-                if extension == "c9s" {
-                    let mut ciphertext_name = PathBuf::from(fs::read_to_string(
-                        ciphertext_path.as_ref().join("name.c9s"),
-                    )?);
-
-                    // Remove .c9r from name
-                    ciphertext_name.set_extension("");
-
-                    Ok(self
-                        .vault
-                        .cryptor()
-                        .decrypt_name(ciphertext_name.to_string_lossy(), dir_id)?)
-                } else {
-                    let stem = ciphertext_path.as_ref().file_stem().unwrap_or_default();
-
-                    Ok(self
-                        .vault
-                        .cryptor()
-                        .decrypt_name(stem.to_string_lossy(), dir_id)?)
-                }
-            }
+        let ciphertext_path = ciphertext_path.as_ref();
+        let ciphertext_name = match ciphertext_path.extension() {
+            // Shortened names store the full ciphertext name, including .c9r, in name.c9s
+            Some(extension) if extension == "c9s" => self
+                .vault
+                .storage()
+                .read_to_string(&ciphertext_path.join("name.c9s"))
+                .wrap_err_with(|| format!("missing name.c9s in {}", ciphertext_path.display()))?,
+            Some(_) => ciphertext_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
             None => bail!("ciphertext path missing extension"),
+        };
+
+        self.vault
+            .cryptor()
+            .decrypt_filename(&ciphertext_name, dir_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::PermissionsExt,
+    };
+
+    use super::*;
+    use crate::{fs::EncryptedFileSystem, storage::OpenOptions, KdfParams, VaultCreateOptions};
+
+    #[test]
+    fn dir_cache_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let permissions = Permissions::from_mode(0o755);
+
+        let depth = 5;
+        let mut deep_dir = PathBuf::from("/");
+        let fs = EncryptedFileSystem::new(&vault);
+        for i in 0..depth {
+            fs.mkdir(&deep_dir, OsStr::new(&format!("d{i}")), permissions.clone())
+                .unwrap();
+            deep_dir.push(format!("d{i}"));
+        }
+        fs.mknod(&deep_dir, OsStr::new("file"), permissions)
+            .unwrap();
+        let deep_file = deep_dir.join("file");
+
+        let reads =
+            |fs: &EncryptedFileSystem| fs.translator.dirs.dir_id_reads.load(Ordering::Relaxed);
+        let ops = 100;
+        for (capacity, expected_reads) in [(0, ops * 3 * depth), (1024, depth)] {
+            let fs = EncryptedFileSystem::new(&vault).dir_cache_capacity(capacity);
+            for _ in 0..ops {
+                fs.dir_entry(&deep_file).unwrap();
+                assert_eq!(fs.dir_entries(&deep_dir).unwrap().len(), 1);
+                let mut options = OpenOptions::new();
+                options.read(true);
+                fs.open_file(&deep_file, options, false).unwrap();
+            }
+            assert_eq!(reads(&fs), expected_reads);
         }
+
+        // Moving a directory forgets everything below it
+        let fs = EncryptedFileSystem::new(&vault);
+        fs.dir_entry(&deep_file).unwrap();
+        fs.rename("/d0", OsStr::new("d1"), "/d0", OsStr::new("moved"))
+            .unwrap();
+        assert!(fs.dir_entry(&deep_file).is_err());
+        fs.dir_entry("/d0/moved/d2/d3/d4/file").unwrap();
+        assert_eq!(reads(&fs), depth + depth - 1);
+
+        fs.invalidate("/");
+        fs.dir_entry("/d0/moved/d2/d3/d4/file").unwrap();
+        assert_eq!(reads(&fs), 3 * depth - 1);
+    }
+
+    #[test]
+    fn persistent_dir_cache_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path().join("vault"), "password", options).unwrap();
+        let permissions = Permissions::from_mode(0o755);
+        let cache_file = dir.path().join("dirs.cache");
+        let reads =
+            |fs: &EncryptedFileSystem| fs.translator.dirs.dir_id_reads.load(Ordering::Relaxed);
+
+        let fs = EncryptedFileSystem::new(&vault);
+        for (parent, name) in [("/", "a"), ("/a", "b"), ("/a/b", "c"), ("/", "d")] {
+            fs.mkdir(parent, OsStr::new(name), permissions.clone())
+                .unwrap();
+        }
+        fs.mknod("/a/b/c", OsStr::new("file"), permissions.clone())
+            .unwrap();
+        let fs = EncryptedFileSystem::new(&vault).dir_cache_file(&cache_file);
+        fs.dir_entry("/a/b/c/file").unwrap();
+        fs.dir_entry("/d").unwrap();
+        assert_eq!(reads(&fs), 3);
+        fs.save_dir_cache().unwrap();
+
+        // Remounting trusts the cache for unchanged directories, even with no cache in memory
+        for capacity in [1024, 0] {
+            let fs = EncryptedFileSystem::new(&vault)
+                .dir_cache_file(&cache_file)
+                .dir_cache_capacity(capacity);
+            fs.dir_entry("/a/b/c/file").unwrap();
+            assert_eq!(fs.dir_entries("/a/b/c").unwrap().len(), 1);
+            assert_eq!(reads(&fs), 0);
+        }
+
+        // Directories moved or replaced by something else are read again under their new paths,
+        // and their old paths are gone
+        let other = EncryptedFileSystem::new(&vault);
+        other
+            .rename("/", OsStr::new("a"), "/d", OsStr::new("a"))
+            .unwrap();
+        let fs = EncryptedFileSystem::new(&vault).dir_cache_file(&cache_file);
+        assert!(matches!(
+            fs.dir_entry("/a/b/c/file"),
+            Err(FsError::NotFound)
+        ));
+        fs.dir_entry("/d/a/b/c/file").unwrap();
+        assert_eq!(reads(&fs), 4);
+        fs.save_dir_cache().unwrap();
+
+        other.unlink("/d/a/b/c", OsStr::new("file")).unwrap();
+        other.rmdir("/d/a/b", OsStr::new("c")).unwrap();
+        other
+            .mkdir("/d/a/b", OsStr::new("c"), permissions.clone())
+            .unwrap();
+        let fs = EncryptedFileSystem::new(&vault).dir_cache_file(&cache_file);
+        assert!(fs.dir_entries("/d/a/b/c").unwrap().is_empty());
+        assert_eq!(reads(&fs), 1);
+
+        // A cache file that can't be read is ignored, and replaced when saving
+        fs::write(&cache_file, b"garbage").unwrap();
+        let fs = EncryptedFileSystem::new(&vault).dir_cache_file(&cache_file);
+        fs.dir_entry("/d/a/b/c").unwrap();
+        assert_eq!(reads(&fs), 3);
+        fs.save_dir_cache().unwrap();
+        let fs = EncryptedFileSystem::new(&vault).dir_cache_file(&cache_file);
+        fs.dir_entry("/d/a/b/c").unwrap();
+        assert_eq!(reads(&fs), 0);
     }
 }
@@ -0,0 +1,198 @@
+use std::{
+    path::{Path, PathBuf},
+    thread,
+};
+
+use super::{check_path, EncryptedFileSystem, FileKind, Walk, WalkEntry};
+use crate::Result;
+
+/// Options for [`EncryptedFileSystem::disk_usage`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DuOptions {
+    /// Walk the top-level directories on multiple threads.
+    pub parallel: bool,
+    /// Count the targets of symlinks instead of the symlinks themselves. A target reachable
+    /// through several links is counted once for each of them.
+    pub follow_symlinks: bool,
+}
+
+/// Entry counts and sizes of a cleartext directory tree, computed by
+/// [`EncryptedFileSystem::disk_usage`]. Only file contents are counted towards the byte totals.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DuReport {
+    pub files: u64,
+    /// The number of directories, not including the directory being measured.
+    pub directories: u64,
+    pub symlinks: u64,
+    pub cleartext_bytes: u64,
+    pub ciphertext_bytes: u64,
+    /// Ciphertext paths of entries or directories that could not be read, along with the reason.
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl DuReport {
+    fn add(&mut self, entry: &WalkEntry) {
+        match entry.entry.kind {
+            FileKind::File => {
+                self.files += 1;
+                self.cleartext_bytes += entry.entry.size;
+                self.ciphertext_bytes += entry.entry.metadata.len();
+            }
+            FileKind::Directory => self.directories += 1,
+            FileKind::Symlink => self.symlinks += 1,
+        }
+    }
+
+    // Count everything yielded by a walk, returning the directories that weren't descended into.
+    fn add_walk(&mut self, mut walk: Walk) -> Result<Vec<PathBuf>> {
+        let mut subdirs = Vec::new();
+        while let Some(entry) = walk.next_with_path() {
+            match entry {
+                Ok(entry) => {
+                    self.add(&entry);
+                    if entry.entry.kind == FileKind::Directory {
+                        subdirs.push(entry.path);
+                    }
+                }
+                Err((err, Some(ciphertext_path))) => {
+                    self.errors.push((ciphertext_path, err.to_string()))
+                }
+                Err((err, None)) => return Err(err),
+            }
+        }
+
+        Ok(subdirs)
+    }
+
+    fn merge(&mut self, other: DuReport) {
+        self.files += other.files;
+        self.directories += other.directories;
+        self.symlinks += other.symlinks;
+        self.cleartext_bytes += other.cleartext_bytes;
+        self.ciphertext_bytes += other.ciphertext_bytes;
+        self.errors.extend(other.errors);
+    }
+}
+
+impl<'v> EncryptedFileSystem<'v> {
+    /// Count the entries below the cleartext directory `path` and add up their sizes. Sizes are
+    /// worked out from the ciphertext metadata, so nothing is decrypted except names.
+    ///
+    /// Entries that can't be read are listed in [`DuReport::errors`] rather than failing the
+    /// whole count.
+    pub fn disk_usage(&self, path: impl AsRef<Path>, options: DuOptions) -> Result<DuReport> {
+        let path = path.as_ref();
+        check_path(path)?;
+        let mut report = DuReport::default();
+
+        let walk = |root: &Path| self.walk(root).follow_symlinks(options.follow_symlinks);
+        if !options.parallel {
+            report.add_walk(walk(path))?;
+            return Ok(report);
+        }
+
+        // Count the top level first, then split its directories between threads
+        let subdirs = report.add_walk(walk(path).max_depth(1))?;
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_len = subdirs.len().div_ceil(threads).max(1);
+        let results: Vec<Result<DuReport>> = thread::scope(|s| {
+            let handles: Vec<_> = subdirs
+                .chunks(chunk_len)
+                .map(|dirs| {
+                    s.spawn(move || {
+                        let mut partial = DuReport::default();
+                        for dir in dirs {
+                            partial.add_walk(walk(dir))?;
+                        }
+                        Ok(partial)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|err| std::panic::resume_unwind(err))
+                })
+                .collect()
+        });
+
+        for partial in results {
+            report.merge(partial?);
+        }
+        report.errors.sort();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::{KdfParams, Vault, VaultCreateOptions};
+
+    #[test]
+    fn disk_usage_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        let permissions = Permissions::from_mode(0o755);
+
+        for i in 0..4 {
+            let dir = format!("dir{i}");
+            fs.mkdir("/", OsStr::new(&dir), permissions.clone())
+                .unwrap();
+            fs.mkdir(format!("/{dir}"), OsStr::new("nested"), permissions.clone())
+                .unwrap();
+            fs.mknod(
+                format!("/{dir}/nested"),
+                OsStr::new("file"),
+                permissions.clone(),
+            )
+            .unwrap();
+        }
+        fs.symlink("/", OsStr::new("link"), "dir0").unwrap();
+
+        let mut usage = DuReport::default();
+        for parallel in [false, true] {
+            let options = DuOptions {
+                parallel,
+                ..Default::default()
+            };
+            usage = fs.disk_usage("/", options).unwrap();
+            assert_eq!((usage.files, usage.directories, usage.symlinks), (4, 8, 1));
+            assert_eq!(usage.cleartext_bytes, 0);
+            assert!(usage.errors.is_empty());
+        }
+
+        // Following the link counts the linked directory twice
+        let options = DuOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let followed = fs.disk_usage("/", options).unwrap();
+        assert_eq!(
+            (followed.files, followed.directories, followed.symlinks),
+            (5, 10, 0)
+        );
+        assert_eq!(followed.ciphertext_bytes, usage.ciphertext_bytes / 4 * 5);
+
+        // Broken entries are counted as errors, listed by ciphertext path
+        let broken = fs.ciphertext_path("/dir1/nested").unwrap();
+        std::fs::remove_file(broken.join("dir.c9r")).unwrap();
+        fs.invalidate("/");
+        let usage = fs.disk_usage("/", Default::default()).unwrap();
+        assert_eq!((usage.files, usage.directories), (3, 7));
+        assert_eq!(usage.errors.len(), 1);
+        assert_eq!(usage.errors[0].0, broken);
+
+        assert!(fs.disk_usage("/missing", Default::default()).is_err());
+    }
+}
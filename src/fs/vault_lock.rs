@@ -0,0 +1,155 @@
+use std::{
+    io,
+    path::Path,
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::FsError;
+use crate::storage::{OpenOptions, StorageFile, VaultStorage};
+
+/// The name of the lock file, next to vault.cryptomator.
+pub(super) const LOCK_FILE_NAME: &str = ".cryptomator-rs.lock";
+
+/// An advisory lock on a whole vault, released when dropped. The lock file also records which
+/// process took the lock and when, so others can say who is using the vault.
+#[derive(Debug)]
+pub(super) struct VaultLock {
+    file: Box<dyn StorageFile>,
+}
+
+impl VaultLock {
+    /// Lock the vault at `vault_path`, exclusively to change it or shared to only read it.
+    pub fn acquire(
+        storage: &dyn VaultStorage,
+        vault_path: &Path,
+        exclusive: bool,
+    ) -> Result<Self, FsError> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        let file = storage.open(&vault_path.join(LOCK_FILE_NAME), &options)?;
+
+        let result = match exclusive {
+            true => file.try_lock_exclusive(),
+            false => file.try_lock_shared(),
+        };
+        match result {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                // If the recorded process is gone, the lock is held by one the record doesn't name,
+                // such as a child that inherited it
+                let (pid, since) = read_holder(&*file)
+                    .filter(|&(pid, _)| is_running(pid))
+                    .unzip();
+                return Err(FsError::VaultInUse { pid, since });
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        // This replaces the record of any earlier holder, including one that died, since its
+        // lock went away with it
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let record = format!("{} {since}\n", process::id());
+        file.set_len(0)?;
+        file.write_at(record.as_bytes(), 0)?;
+
+        Ok(Self { file })
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+// Read the process ID and time recorded by the holder of the lock, if the record is intact.
+fn read_holder(file: &dyn StorageFile) -> Option<(u32, SystemTime)> {
+    let mut buf = [0; 64];
+    let n = file.read_at(&mut buf, 0).ok()?;
+    let record = std::str::from_utf8(&buf[..n]).ok()?;
+    let (pid, since) = record.trim_end().split_once(' ')?;
+    let since = UNIX_EPOCH + Duration::from_secs(since.parse().ok()?);
+    Some((pid.parse().ok()?, since))
+}
+
+// Whether a process with ID `pid` is running on this machine.
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+
+    // SAFETY: signal 0 only checks that the process exists and may be signalled
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use super::*;
+    use crate::{fs::EncryptedFileSystem, KdfParams, Vault, VaultCreateOptions};
+
+    #[test]
+    fn vault_lock_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let lock = |read_only, force| {
+            EncryptedFileSystem::new(&vault)
+                .read_only(read_only)
+                .acquire_lock(force)
+        };
+        let assert_in_use =
+            |result: Result<EncryptedFileSystem, FsError>, expected_pid| match result
+                .map(|_| ())
+                .unwrap_err()
+            {
+                FsError::VaultInUse { pid, since } => {
+                    assert_eq!(pid, expected_pid);
+                    assert_eq!(since.is_some(), expected_pid.is_some());
+                }
+                err => panic!("unexpected error: {err:?}"),
+            };
+
+        // Clones share the lock, which lasts until the last of them is dropped
+        let fs = lock(false, false).unwrap();
+        let clone = fs.clone();
+        drop(fs);
+        assert_in_use(lock(false, false), Some(process::id()));
+        assert_in_use(lock(true, false), Some(process::id()));
+        let forced = lock(false, true).unwrap();
+        drop(clone);
+        drop(forced);
+
+        // Any number of read-only handles can share the vault, but keep others from changing it
+        let readers = [lock(true, false).unwrap(), lock(true, false).unwrap()];
+        assert_in_use(lock(false, false), Some(process::id()));
+        drop(readers);
+        let fs = lock(false, false).unwrap();
+
+        // A record left behind by a process that's gone doesn't name anyone
+        let mut child = Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        let path = dir.path().join(LOCK_FILE_NAME);
+        fs::write(&path, format!("{dead_pid} 1000\n")).unwrap();
+        assert_in_use(lock(false, false), None);
+
+        // Once its lock is gone, the stale record is taken over
+        drop(fs);
+        let _fs = lock(false, false).unwrap();
+        let record = fs::read_to_string(&path).unwrap();
+        assert!(record.starts_with(&format!("{} ", process::id())));
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+    vec,
+};
+
+use color_eyre::{eyre::bail, Report};
+
+use super::{check_path, is_temp_name, read_dir_id, DirEntry, EncryptedFileSystem, FileKind};
+use crate::{
+    crypto::{DirId, FileCryptor},
+    Result,
+};
+
+/// The order in which [`Walk`] yields directories relative to their contents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Yield each directory before its contents.
+    #[default]
+    PreOrder,
+    /// Yield each directory after its contents.
+    PostOrder,
+}
+
+/// An entry yielded by [`Walk`].
+#[derive(Debug)]
+pub struct WalkEntry {
+    pub path: PathBuf,
+    pub entry: DirEntry,
+    /// The number of directories between the walk root and this entry, starting at 1 for direct
+    /// children of the root.
+    pub depth: usize,
+    /// The full-length or shortened ciphertext path of this entry. For a followed symlink, this
+    /// is the ciphertext path of its target.
+    pub ciphertext_path: PathBuf,
+}
+
+struct Frame {
+    // Cleartext path of the directory, which may go through followed symlinks
+    cleartext_dir: PathBuf,
+    dir_id: DirId,
+    hashed_dir_path: PathBuf,
+    // Opened on first use, so errors are reported in order
+    entries: Option<vec::IntoIter<OsString>>,
+    opened: bool,
+    // Depth of the entries in this directory
+    depth: usize,
+    // The entry for this directory, yielded after its contents in post-order
+    post_order_entry: Option<WalkEntry>,
+}
+
+/// A recursive iterator over the contents of a cleartext directory, created by
+/// [`EncryptedFileSystem::walk`]. Directories are read one at a time as the walk descends.
+///
+/// Errors for individual entries or directories are yielded as items, and the walk continues
+/// afterwards.
+pub struct Walk<'v> {
+    fs: EncryptedFileSystem<'v>,
+    root: PathBuf,
+    max_depth: Option<usize>,
+    order: WalkOrder,
+    follow_symlinks: bool,
+    stack: Vec<Frame>,
+    started: bool,
+}
+
+impl<'v> Walk<'v> {
+    /// Only descend `max_depth` levels below the root. A depth of 1 yields only the root's
+    /// direct children.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn order(mut self, order: WalkOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Yield the targets of symlinks instead of the symlinks themselves, descending into linked
+    /// directories. A symlink to one of its own ancestors is reported as an error. If the walk
+    /// root is a symlink to a directory, the directory is walked.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    fn frame(&self, cleartext_dir: PathBuf, dir_id: DirId, depth: usize) -> Result<Frame> {
+        let hashed_dir_id = self.fs.vault.cryptor().hash_dir_id(&dir_id)?;
+        Ok(Frame {
+            cleartext_dir,
+            dir_id,
+            hashed_dir_path: self.fs.vault.path().join("d").join(hashed_dir_id),
+            entries: None,
+            opened: false,
+            depth,
+            post_order_entry: None,
+        })
+    }
+
+    fn start(&mut self) -> Result<()> {
+        check_path(&self.root)?;
+        let dir_id = match self.root.parent() {
+            Some(parent) => {
+                let parent_dir_id = self.fs.translator.get_dir_id(parent)?;
+                let mut ciphertext_path = self
+                    .fs
+                    .translator
+                    .get_ciphertext_path(&self.root, &parent_dir_id)?;
+                let mut entry = self.fs.ciphertext_dir_entry(&ciphertext_path)?;
+                if entry.kind == FileKind::Symlink && self.follow_symlinks {
+                    (entry, ciphertext_path) =
+                        self.resolve_symlink(&self.root, &ciphertext_path)?;
+                }
+                if entry.kind != FileKind::Directory {
+                    bail!("not a directory: {}", self.root.display());
+                }
+
+                read_dir_id(self.fs.storage(), &ciphertext_path)?
+            }
+            None => DirId::root(),
+        };
+        let frame = self.frame(self.root.clone(), dir_id, 1)?;
+        self.stack.push(frame);
+        Ok(())
+    }
+
+    // Resolve an entry in the current directory, returning it if it should be yielded now.
+    fn visit(&mut self, ciphertext_path: PathBuf) -> Result<Option<WalkEntry>> {
+        // Ok to unwrap, this is only called while a directory is being read
+        let frame = self.stack.last().unwrap();
+        let depth = frame.depth;
+        let name = self
+            .fs
+            .translator
+            .get_cleartext_name(&ciphertext_path, &frame.dir_id)?;
+        let path = frame.cleartext_dir.join(name);
+
+        let mut entry = self.fs.ciphertext_dir_entry(&ciphertext_path)?;
+        let mut ciphertext_path = ciphertext_path;
+        if entry.kind == FileKind::Symlink && self.follow_symlinks {
+            (entry, ciphertext_path) = self.resolve_symlink(&path, &ciphertext_path)?;
+        }
+
+        let walk_entry = WalkEntry {
+            path,
+            entry,
+            depth,
+            ciphertext_path,
+        };
+
+        let descend = walk_entry.entry.kind == FileKind::Directory
+            && self.max_depth.is_none_or(|max| depth < max);
+        if !descend {
+            return Ok(Some(walk_entry));
+        }
+
+        let dir_id = read_dir_id(self.fs.storage(), &walk_entry.ciphertext_path)?;
+        if self.stack.iter().any(|frame| frame.dir_id == dir_id) {
+            bail!("symlink loop at {}", walk_entry.path.display());
+        }
+
+        let mut frame = self.frame(walk_entry.path.clone(), dir_id, depth + 1)?;
+        let result = match self.order {
+            WalkOrder::PreOrder => Some(walk_entry),
+            WalkOrder::PostOrder => {
+                frame.post_order_entry = Some(walk_entry);
+                None
+            }
+        };
+        self.stack.push(frame);
+
+        Ok(result)
+    }
+
+    // Find the entry and ciphertext path of a symlink's target.
+    fn resolve_symlink(
+        &self,
+        cleartext_path: &Path,
+        ciphertext_path: &Path,
+    ) -> Result<(DirEntry, PathBuf)> {
+        let target = self.fs.read_symlink(&ciphertext_path.join("symlink.c9r"))?;
+
+        // Ok to unwrap, the walk never yields the root directory
+        let mut resolved = cleartext_path.parent().unwrap().to_path_buf();
+        for component in target.components() {
+            match component {
+                Component::RootDir => resolved = PathBuf::from("/"),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !resolved.pop() {
+                        bail!("symlink target outside of vault: {}", target.display());
+                    }
+                }
+                Component::Normal(name) => resolved.push(name),
+                Component::Prefix(_) => bail!("unsupported symlink target: {}", target.display()),
+            }
+        }
+
+        // The root directory is an ancestor of every entry
+        let Some(parent) = resolved.parent() else {
+            bail!("symlink loop at {}", cleartext_path.display());
+        };
+        let parent_dir_id = self.fs.translator.get_dir_id(parent)?;
+        let target_ciphertext_path = self
+            .fs
+            .translator
+            .get_ciphertext_path(&resolved, &parent_dir_id)?;
+        let entry = self.fs.ciphertext_dir_entry(&target_ciphertext_path)?;
+        if entry.kind == FileKind::Symlink {
+            bail!("chained symlinks are not supported: {}", resolved.display());
+        }
+
+        Ok((entry, target_ciphertext_path))
+    }
+}
+
+impl Walk<'_> {
+    // Like Iterator::next, but errors also give the ciphertext path that caused them, if any.
+    pub(super) fn next_with_path(
+        &mut self,
+    ) -> Option<Result<WalkEntry, (Report, Option<PathBuf>)>> {
+        if !self.started {
+            self.started = true;
+            if let Err(err) = self.start() {
+                return Some(Err((err, None)));
+            }
+        }
+
+        loop {
+            let frame = self.stack.last_mut()?;
+            if !frame.opened {
+                frame.opened = true;
+                match self.fs.storage().list(&frame.hashed_dir_path) {
+                    Ok(entries) => frame.entries = Some(entries.into_iter()),
+                    Err(err) => {
+                        return Some(Err((err.into(), Some(frame.hashed_dir_path.clone()))))
+                    }
+                }
+            }
+
+            let ciphertext_path = match frame.entries.as_mut().and_then(Iterator::next) {
+                Some(name) if name == "dirid.c9r" || is_temp_name(&name) => continue,
+                Some(name) => frame.hashed_dir_path.join(name),
+                None => {
+                    // Ok to unwrap, we just looked at the last frame
+                    match self.stack.pop().unwrap().post_order_entry {
+                        Some(entry) => return Some(Ok(entry)),
+                        None => continue,
+                    }
+                }
+            };
+
+            match self.visit(ciphertext_path.clone()) {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => continue,
+                Err(err) => return Some(Err((err, Some(ciphertext_path)))),
+            }
+        }
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_path()
+            .map(|result| result.map_err(|(err, _)| err))
+    }
+}
+
+impl<'v> EncryptedFileSystem<'v> {
+    /// Recursively iterate over the contents of the cleartext directory `root`, not including
+    /// `root` itself.
+    pub fn walk(&self, root: impl AsRef<Path>) -> Walk<'v> {
+        Walk {
+            fs: self.clone(),
+            root: root.as_ref().to_path_buf(),
+            max_depth: None,
+            order: Default::default(),
+            follow_symlinks: false,
+            stack: Vec::new(),
+            started: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::{KdfParams, Vault, VaultCreateOptions};
+
+    #[test]
+    fn symlink_loop_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path(), "password", options).unwrap();
+        let fs = EncryptedFileSystem::new(&vault);
+        fs.mkdir("/", OsStr::new("a"), Permissions::from_mode(0o755))
+            .unwrap();
+        fs.mknod("/a", OsStr::new("file"), Permissions::from_mode(0o644))
+            .unwrap();
+        fs.symlink("/a", OsStr::new("loop"), "..").unwrap();
+        fs.symlink("/", OsStr::new("link"), "a").unwrap();
+
+        let walk: Vec<_> = fs.walk("/").follow_symlinks(true).collect();
+        let errors: Vec<_> = walk
+            .iter()
+            .filter_map(|entry| entry.as_ref().err())
+            .collect();
+        // The link from the root doesn't loop, but the one inside /a does, wherever it's reached
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors
+            .iter()
+            .all(|err| err.to_string().contains("symlink loop")));
+
+        let paths: Vec<_> = walk
+            .into_iter()
+            .filter_map(Result::ok)
+            .map(|e| e.path)
+            .collect();
+        assert!(paths.contains(&PathBuf::from("/link/file")));
+        assert!(paths.contains(&PathBuf::from("/a/file")));
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    convert::Infallible,
+    ffi::OsStr,
+    fmt::{self, Debug},
+    fs::Permissions,
+    io::{self, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use base64ct::{Base64, Encoding};
+use bytes::{Buf, Bytes};
+use color_eyre::Report;
+use dav_server::{
+    body::Body,
+    davpath::DavPath,
+    fakels::FakeLs,
+    fs::{
+        DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, FsStream,
+        OpenOptions as DavOpenOptions, ReadDirMeta,
+    },
+    DavHandler, DavMethodSet,
+};
+use futures_util::stream;
+use hyper::{
+    body::Incoming, header, server::conn::http1, service::service_fn, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+use crate::{
+    fs::{self, DirEntry, EncryptedFile, EncryptedFileSystem, FileKind},
+    storage::OpenOptions,
+    util, Result,
+};
+
+/// Serves the contents of a vault to WebDAV clients over HTTP.
+///
+/// The server needs a vault that lives for the rest of the program, e.g. one from [`Box::leak`].
+/// It only allows reading unless [`WebDavServer::writable`] is set. Symlinks are not shown.
+pub struct WebDavServer {
+    fs: EncryptedFileSystem<'static>,
+    bind_addr: SocketAddr,
+    writable: bool,
+    // Expected "username:password", if basic authentication is required
+    credentials: Option<Arc<str>>,
+}
+
+impl WebDavServer {
+    pub fn new(fs: EncryptedFileSystem<'static>, bind_addr: impl Into<SocketAddr>) -> Self {
+        Self {
+            fs,
+            bind_addr: bind_addr.into(),
+            writable: false,
+            credentials: None,
+        }
+    }
+
+    /// Allow clients to create, change, move, and delete entries.
+    pub fn writable(mut self, writable: bool) -> Self {
+        self.writable = writable;
+        self
+    }
+
+    /// Require clients to log in with HTTP basic authentication. Credentials are sent in the
+    /// clear, so this should only be used on a trusted network or behind a TLS proxy.
+    pub fn basic_auth(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some(format!("{username}:{password}").into());
+        self
+    }
+
+    /// Serve requests on a new runtime, blocking the current thread.
+    pub fn serve(self) -> Result<()> {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(self.run())
+    }
+
+    /// Serve requests on the current runtime, which must be a multi-threaded tokio runtime.
+    pub async fn run(self) -> Result<()> {
+        let methods = match self.writable {
+            true => DavMethodSet::WEBDAV_RW,
+            false => DavMethodSet::WEBDAV_RO,
+        };
+        let handler = DavHandler::builder()
+            .filesystem(Box::new(DavVault {
+                fs: self.fs,
+                writable: self.writable,
+            }))
+            .locksystem(FakeLs::new())
+            .methods(methods)
+            .build_handler();
+        let listener = TcpListener::bind(self.bind_addr).await?;
+        tracing::info!(addr = ?listener.local_addr()?, "serving WebDAV");
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let (handler, credentials) = (handler.clone(), self.credentials.clone());
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    handle(request, handler.clone(), credentials.clone())
+                });
+                let connection =
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+                if let Err(err) = connection.await {
+                    tracing::warn!("{err}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    handler: DavHandler,
+    credentials: Option<Arc<str>>,
+) -> Result<Response<Body>, Infallible> {
+    if let Some(credentials) = credentials {
+        let authorized = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok()?.strip_prefix("Basic "))
+            .and_then(|value| Base64::decode_vec(value.trim()).ok())
+            .is_some_and(|given| constant_time_eq(&given, credentials.as_bytes()));
+
+        if !authorized {
+            // Ok to unwrap, the response is always valid
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, "Basic realm=\"vault\"")
+                .body(Body::from("unauthorized"))
+                .unwrap());
+        }
+    }
+
+    Ok(handler.handle(request).await)
+}
+
+// Compare credentials without giving away how much of them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[derive(Clone)]
+struct DavVault {
+    fs: EncryptedFileSystem<'static>,
+    writable: bool,
+}
+
+impl DavVault {
+    fn entry(&self, cleartext_path: &Path) -> FsResult<DirEntry> {
+        self.fs.dir_entry(cleartext_path).map_err(entry_error)
+    }
+
+    // Split a path into its parent directory and name, for an operation that changes the vault.
+    fn parent_and_name<'a>(&self, cleartext_path: &'a Path) -> FsResult<(&'a Path, &'a OsStr)> {
+        if !self.writable {
+            return Err(FsError::Forbidden);
+        }
+
+        let (Some(parent), Some(name)) = (cleartext_path.parent(), cleartext_path.file_name())
+        else {
+            return Err(FsError::Forbidden);
+        };
+        if self.entry(parent)?.kind != FileKind::Directory {
+            return Err(FsError::NotFound);
+        }
+
+        Ok((parent, name))
+    }
+
+    fn open_file(&self, cleartext_path: &Path, options: DavOpenOptions) -> FsResult<DavVaultFile> {
+        let write = options.write || options.append || options.truncate || options.create;
+        let existing = match self.entry(cleartext_path) {
+            Ok(entry) if entry.kind != FileKind::File => return Err(FsError::Forbidden),
+            Ok(_) if options.create_new => return Err(FsError::Exists),
+            Ok(entry) => Some(entry),
+            Err(FsError::NotFound) if options.create || options.create_new => None,
+            Err(err) => return Err(err),
+        };
+
+        if write || options.create_new {
+            let (parent, name) = self.parent_and_name(cleartext_path)?;
+            match existing {
+                // Files can't be truncated in place, so they're replaced with an empty one
+                Some(entry) if options.truncate => {
+                    self.fs.unlink(parent, name).map_err(fs_error)?;
+                    self.fs
+                        .mknod(parent, name, entry.metadata.permissions())
+                        .map_err(fs_error)?;
+                }
+                Some(_) => {}
+                None => {
+                    self.fs
+                        .mknod(parent, name, Permissions::from_mode(0o644))
+                        .map_err(fs_error)?;
+                }
+            }
+        }
+
+        let mut file_options = OpenOptions::new();
+        file_options.read(true).write(write || options.create_new);
+        let file = self
+            .fs
+            .open_file(cleartext_path, file_options, options.append)
+            .map_err(fs_error)?;
+
+        Ok(DavVaultFile(file))
+    }
+}
+
+// File system calls block, so the runtime is told to move other tasks off this thread first.
+fn blocking<'a, T: Send + 'a>(f: impl FnOnce() -> FsResult<T> + Send + 'a) -> FsFuture<'a, T> {
+    Box::pin(async move { tokio::task::block_in_place(f) })
+}
+
+fn fs_error(err: Report) -> FsError {
+    entry_error(err.into())
+}
+
+fn entry_error(err: fs::FsError) -> FsError {
+    match err {
+        fs::FsError::NotFound | fs::FsError::NotADirectory => FsError::NotFound,
+        fs::FsError::InvalidPath | fs::FsError::ReadOnly | fs::FsError::VaultLocked => {
+            FsError::Forbidden
+        }
+        fs::FsError::Io(err) => io_error(err),
+        err => {
+            tracing::debug!("{err:?}");
+            FsError::GeneralFailure
+        }
+    }
+}
+
+fn io_error(err: io::Error) -> FsError {
+    match err.kind() {
+        io::ErrorKind::NotFound => FsError::NotFound,
+        io::ErrorKind::AlreadyExists => FsError::Exists,
+        io::ErrorKind::PermissionDenied | io::ErrorKind::DirectoryNotEmpty => FsError::Forbidden,
+        io::ErrorKind::StorageFull => FsError::InsufficientStorage,
+        _ => {
+            tracing::debug!("{err:?}");
+            FsError::GeneralFailure
+        }
+    }
+}
+
+impl DavFileSystem for DavVault {
+    fn open<'a>(
+        &'a self,
+        path: &'a DavPath,
+        options: DavOpenOptions,
+    ) -> FsFuture<'a, Box<dyn DavFile>> {
+        blocking(move || Ok(Box::new(self.open_file(&path.as_pathbuf(), options)?) as _))
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
+        blocking(move || {
+            let cleartext_dir = path.as_pathbuf();
+            if self.entry(&cleartext_dir)?.kind != FileKind::Directory {
+                return Err(FsError::Forbidden);
+            }
+
+            let (entries, errors) = self
+                .fs
+                .dir_entries_lossy(&cleartext_dir)
+                .map_err(entry_error)?;
+            for err in errors {
+                tracing::warn!(
+                    path = %self.fs.traced(&cleartext_dir),
+                    "skipping entry: {err}"
+                );
+            }
+
+            let entries: Vec<FsResult<Box<dyn DavDirEntry>>> = entries
+                .into_iter()
+                .map(|(path, entry)| {
+                    // Ok to unwrap, entries always have a name
+                    let name = path.file_name().unwrap().as_bytes().to_vec();
+                    Ok(Box::new(DavVaultEntry {
+                        name,
+                        meta: Meta::from(&entry),
+                    }) as _)
+                })
+                .collect();
+            Ok(Box::pin(stream::iter(entries)) as _)
+        })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        blocking(move || Ok(Box::new(Meta::from(&self.entry(&path.as_pathbuf())?)) as _))
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        blocking(move || {
+            let path = path.as_pathbuf();
+            let (parent, name) = self.parent_and_name(&path)?;
+            self.fs
+                .mkdir(parent, name, Permissions::from_mode(0o755))
+                .map_err(fs_error)?;
+            Ok(())
+        })
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        blocking(move || {
+            let path = path.as_pathbuf();
+            let (parent, name) = self.parent_and_name(&path)?;
+            self.fs.rmdir(parent, name).map_err(fs_error)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        blocking(move || {
+            let path = path.as_pathbuf();
+            let (parent, name) = self.parent_and_name(&path)?;
+            if self.entry(&path)?.kind == FileKind::Directory {
+                return Err(FsError::Forbidden);
+            }
+            self.fs.unlink(parent, name).map_err(fs_error)
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        blocking(move || {
+            let (from, to) = (from.as_pathbuf(), to.as_pathbuf());
+            let (from_parent, from_name) = self.parent_and_name(&from)?;
+            let (to_parent, to_name) = self.parent_and_name(&to)?;
+            self.entry(&from)?;
+            self.fs
+                .rename(from_parent, from_name, to_parent, to_name)
+                .map_err(fs_error)
+        })
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        blocking(move || {
+            let (from, to) = (from.as_pathbuf(), to.as_pathbuf());
+            self.parent_and_name(&to)?;
+            self.fs.copy_file(from, to, true).map_err(fs_error)?;
+            Ok(())
+        })
+    }
+}
+
+struct DavVaultFile(EncryptedFile<'static>);
+
+impl Debug for DavVaultFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DavVaultFile").finish_non_exhaustive()
+    }
+}
+
+impl DavFile for DavVaultFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        blocking(move || {
+            let metadata = self.0.metadata().map_err(fs_error)?;
+            Ok(Box::new(Meta {
+                kind: FileKind::File,
+                len: self.0.len().map_err(fs_error)?,
+                modified: metadata.modified().map_err(io_error)?,
+                accessed: metadata.accessed().map_err(io_error)?,
+            }) as _)
+        })
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        blocking(move || {
+            while buf.has_remaining() {
+                let chunk = buf.chunk();
+                self.0.write_all(chunk).map_err(io_error)?;
+                let len = chunk.len();
+                buf.advance(len);
+            }
+            Ok(())
+        })
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
+        blocking(move || self.0.write_all(&buf).map_err(io_error))
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
+        blocking(move || {
+            let mut buf = vec![0; count];
+            let (_, len) = util::try_read_exact(&mut self.0, &mut buf).map_err(io_error)?;
+            buf.truncate(len);
+            Ok(buf.into())
+        })
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        blocking(move || self.0.seek(pos).map_err(io_error))
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        blocking(move || self.0.flush().map_err(io_error))
+    }
+}
+
+struct DavVaultEntry {
+    name: Vec<u8>,
+    meta: Meta,
+}
+
+impl DavDirEntry for DavVaultEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.clone()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let meta = self.meta.clone();
+        Box::pin(async move { Ok(Box::new(meta) as _) })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Meta {
+    kind: FileKind,
+    len: u64,
+    modified: SystemTime,
+    accessed: SystemTime,
+}
+
+impl From<&DirEntry> for Meta {
+    fn from(entry: &DirEntry) -> Self {
+        Self {
+            kind: entry.kind,
+            // Directory sizes are meaningless to clients
+            len: match entry.kind {
+                FileKind::Directory => 0,
+                _ => entry.size,
+            },
+            modified: entry.modified,
+            accessed: entry.accessed,
+        }
+    }
+}
+
+impl DavMetaData for Meta {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        Ok(self.modified)
+    }
+
+    fn accessed(&self) -> FsResult<SystemTime> {
+        Ok(self.accessed)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.kind == FileKind::Directory
+    }
+
+    fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+}
@@ -0,0 +1,571 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    ffi::OsStr,
+    fmt::{self, Display},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use base64ct::{Base64Url, Encoding};
+use serde::{Serialize, Serializer};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    crypto::{self, Cryptor, DirId, FileCryptor},
+    fs::EncryptedFile,
+    storage::{OpenOptions, VaultStorage},
+    Result, Vault,
+};
+
+mod repair;
+
+pub use repair::{Repair, RepairAction, RepairOptions, RepairSummary};
+
+/// Options for [`Vault::check`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckOptions {
+    /// Decrypt the contents of every file to verify each chunk, rather than only file headers.
+    pub deep: bool,
+}
+
+/// The kind of an [`Issue`], serialized in `snake_case`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// A directory is referenced by its parent, but its ciphertext directory is missing.
+    MissingDirectory,
+    /// A ciphertext directory is not referenced by any parent directory.
+    OrphanedDirectory,
+    /// A directory is missing its dirid.c9r backup.
+    MissingDirIdBackup,
+    /// A directory's dirid.c9r could not be decrypted.
+    CorruptDirIdBackup,
+    /// A directory's dirid.c9r does not match the location of the directory.
+    DirIdMismatch,
+    /// A dir.c9r file does not contain a valid directory ID.
+    CorruptDirId,
+    /// An entry does not have a valid encrypted name.
+    InvalidName,
+    /// An entry's name could not be decrypted.
+    NameDecryptionFailed,
+    /// A shortened name's name.c9s does not match the name of its directory.
+    ShortenedNameMismatch,
+    /// An entry is neither a file, directory, nor symlink.
+    UnknownEntryType,
+    /// A file or symlink header could not be decrypted.
+    CorruptHeader,
+    /// A file or symlink has a chunk that could not be decrypted.
+    CorruptContent,
+    /// An I/O error occurred while checking an entry.
+    Io,
+}
+
+impl IssueKind {
+    /// How serious an issue of this kind is.
+    pub fn severity(self) -> Severity {
+        match self {
+            // Everything is still readable, the vault is just less robust against future damage
+            IssueKind::OrphanedDirectory
+            | IssueKind::MissingDirIdBackup
+            | IssueKind::CorruptDirIdBackup
+            | IssueKind::DirIdMismatch => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+/// How serious an [`Issue`] is, serialized in `snake_case`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Nothing is lost, but the vault could be cleaned up or made easier to recover.
+    Warning,
+    /// Some cleartext can't be read.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A suggested fix for an [`Issue`], serialized as an object with an `action` field naming the
+/// variant in `snake_case`, plus any fields of the variant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Fix {
+    /// Remove the entry that points to the missing directory, whose contents are lost.
+    MissingDir,
+    /// Move the orphaned directory back into the vault with
+    /// [`EncryptedFileSystem::adopt_orphan`](crate::fs::EncryptedFileSystem::adopt_orphan).
+    OrphanDir,
+    /// Rewrite the dirid.c9r backup from the directory ID in the parent's dir.c9r.
+    MissingDirid,
+    /// Restore the listed cleartext chunks from a backup, or truncate the file before the first
+    /// of them. Chunks are numbered from 0.
+    DamagedChunk { chunks: Vec<u64> },
+}
+
+/// A problem found while checking a vault.
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub severity: Severity,
+    #[serde(serialize_with = "serialize_path")]
+    pub ciphertext_path: PathBuf,
+    /// The cleartext path of the affected entry, if it could be determined.
+    #[serde(serialize_with = "serialize_opt_path")]
+    pub cleartext_path: Option<PathBuf>,
+    pub details: String,
+    /// A fix that could be applied automatically, if there is one.
+    pub fix: Option<Fix>,
+}
+
+// Paths are serialized lossily, since JSON strings can't hold arbitrary bytes
+fn serialize_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+fn serialize_opt_path<S: Serializer>(
+    path: &Option<PathBuf>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match path {
+        Some(path) => serialize_path(path, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.ciphertext_path.display())?;
+        if let Some(cleartext_path) = &self.cleartext_path {
+            write!(f, " ({})", cleartext_path.display())?;
+        }
+        if !self.details.is_empty() {
+            write!(f, ": {}", self.details)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IntegrityReport {
+    pub directories_checked: u64,
+    pub files_checked: u64,
+    pub symlinks_checked: u64,
+    pub issues: Vec<Issue>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Serialize this report as pretty-printed JSON, for other tools to consume. For example:
+    ///
+    /// ```json
+    /// {
+    ///   "directories_checked": 3,
+    ///   "files_checked": 4,
+    ///   "symlinks_checked": 2,
+    ///   "issues": [
+    ///     {
+    ///       "kind": "corrupt_content",
+    ///       "severity": "error",
+    ///       "ciphertext_path": "/vault/d/RC/WG5E.../LNyf....c9r",
+    ///       "cleartext_path": "/image.png",
+    ///       "details": "1 of 3 chunks could not be decrypted",
+    ///       "fix": { "action": "damaged_chunk", "chunks": [0] }
+    ///     }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// See [`IssueKind`], [`Severity`], and [`Fix`] for the possible values. Paths that aren't
+    /// valid UTF-8 are converted lossily, and `cleartext_path` and `fix` are `null` if unknown.
+    pub fn to_json(&self) -> String {
+        // Ok to unwrap, everything here has a JSON representation
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Describe this report in a human-readable form, with one line for each issue.
+    pub fn to_pretty_string(&self) -> String {
+        let mut pretty = format!(
+            "Checked {} directories, {} files, and {} symlinks: ",
+            self.directories_checked, self.files_checked, self.symlinks_checked
+        );
+        match self.issues.len() {
+            0 => pretty.push_str("no issues found\n"),
+            1 => pretty.push_str("1 issue found\n"),
+            n => pretty.push_str(&format!("{n} issues found\n")),
+        }
+        for issue in &self.issues {
+            pretty.push_str(&format!("{}: {issue}\n", issue.severity));
+        }
+
+        pretty
+    }
+}
+
+struct Checker<'v, P> {
+    vault: &'v Vault,
+    options: CheckOptions,
+    progress: P,
+    report: IntegrityReport,
+}
+
+impl<'v, P: FnMut(&Path)> Checker<'v, P> {
+    fn issue(
+        &mut self,
+        kind: IssueKind,
+        ciphertext_path: impl Into<PathBuf>,
+        cleartext_path: Option<&Path>,
+        details: impl Display,
+    ) {
+        let fix = match kind {
+            IssueKind::MissingDirectory => Some(Fix::MissingDir),
+            IssueKind::OrphanedDirectory => Some(Fix::OrphanDir),
+            IssueKind::MissingDirIdBackup
+            | IssueKind::CorruptDirIdBackup
+            | IssueKind::DirIdMismatch => Some(Fix::MissingDirid),
+            _ => None,
+        };
+        self.issue_with_fix(kind, ciphertext_path, cleartext_path, details, fix);
+    }
+
+    fn issue_with_fix(
+        &mut self,
+        kind: IssueKind,
+        ciphertext_path: impl Into<PathBuf>,
+        cleartext_path: Option<&Path>,
+        details: impl Display,
+        fix: Option<Fix>,
+    ) {
+        self.report.issues.push(Issue {
+            kind,
+            severity: kind.severity(),
+            ciphertext_path: ciphertext_path.into(),
+            cleartext_path: cleartext_path.map(Path::to_path_buf),
+            details: details.to_string(),
+            fix,
+        });
+    }
+
+    fn storage(&self) -> &'v dyn VaultStorage {
+        self.vault.storage()
+    }
+
+    fn hashed_dir_path(&self, dir_id: &DirId) -> Result<PathBuf> {
+        Ok(self
+            .vault
+            .path()
+            .join("d")
+            .join(self.vault.cryptor().hash_dir_id(dir_id)?))
+    }
+
+    fn run(mut self) -> Result<IntegrityReport> {
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::from([(DirId::root(), PathBuf::from("/"))]);
+
+        while let Some((dir_id, cleartext_dir)) = queue.pop_front() {
+            let hashed_dir_path = self.hashed_dir_path(&dir_id)?;
+            (self.progress)(&hashed_dir_path);
+            self.report.directories_checked += 1;
+
+            if !self.storage().is_dir(&hashed_dir_path) {
+                self.issue(
+                    IssueKind::MissingDirectory,
+                    hashed_dir_path,
+                    Some(&cleartext_dir),
+                    "",
+                );
+                continue;
+            }
+
+            visited.insert(hashed_dir_path.clone());
+            self.check_dir_id_backup(&hashed_dir_path, &dir_id, Some(&cleartext_dir));
+
+            let file_names = match self.storage().list(&hashed_dir_path) {
+                Ok(file_names) => file_names,
+                Err(err) => {
+                    self.issue(IssueKind::Io, hashed_dir_path, Some(&cleartext_dir), err);
+                    continue;
+                }
+            };
+
+            for file_name in file_names {
+                if file_name == "dirid.c9r" {
+                    continue;
+                }
+
+                let path = hashed_dir_path.join(file_name);
+                (self.progress)(&path);
+                if let Some(child) = self.check_entry(&path, &dir_id, &cleartext_dir) {
+                    queue.push_back(child);
+                }
+            }
+        }
+
+        self.check_orphans(&visited)?;
+        Ok(self.report)
+    }
+
+    fn check_dir_id_backup(
+        &mut self,
+        hashed_dir_path: &Path,
+        dir_id: &DirId,
+        cleartext_dir: Option<&Path>,
+    ) {
+        let backup_path = hashed_dir_path.join("dirid.c9r");
+        if !self.storage().is_file(&backup_path) {
+            self.issue(
+                IssueKind::MissingDirIdBackup,
+                backup_path,
+                cleartext_dir,
+                "",
+            );
+            return;
+        }
+
+        match self.decrypt_file(&backup_path) {
+            // The root directory's ID is empty, which isn't valid in a dir.c9r file
+            Ok(contents) if contents.is_empty() && dir_id.is_root() => {}
+            Ok(contents) => match std::str::from_utf8(&contents).map(DirId::parse) {
+                Ok(Ok(backup_id)) if &backup_id == dir_id => {}
+                Ok(Ok(backup_id)) => self.issue(
+                    IssueKind::DirIdMismatch,
+                    backup_path,
+                    cleartext_dir,
+                    format_args!("expected {dir_id:?}, found {backup_id:?}"),
+                ),
+                _ => self.issue(
+                    IssueKind::CorruptDirIdBackup,
+                    backup_path,
+                    cleartext_dir,
+                    "invalid directory ID",
+                ),
+            },
+            Err((_, err)) => self.issue(
+                IssueKind::CorruptDirIdBackup,
+                backup_path,
+                cleartext_dir,
+                err,
+            ),
+        }
+    }
+
+    // Check a single entry in a ciphertext directory, returning the ID and cleartext path of the
+    // corresponding directory if the entry is a valid directory.
+    fn check_entry(
+        &mut self,
+        path: &Path,
+        parent_dir_id: &DirId,
+        cleartext_dir: &Path,
+    ) -> Option<(DirId, PathBuf)> {
+        let file_name = path.file_name().unwrap_or_default();
+        if !crypto::is_encrypted_name(file_name) {
+            self.issue(IssueKind::InvalidName, path, None, "");
+            return None;
+        }
+
+        let cleartext_path = match self.decrypt_name(path, file_name, parent_dir_id) {
+            Ok(name) => cleartext_dir.join(name),
+            Err((kind, details)) => {
+                self.issue(kind, path, None, details);
+                return None;
+            }
+        };
+        let cleartext = Some(cleartext_path.as_path());
+
+        if self.storage().is_file(path) && path.extension() == Some(OsStr::new("c9r")) {
+            self.check_file(path, cleartext);
+            self.report.files_checked += 1;
+        } else if self.storage().is_file(&path.join("dir.c9r")) {
+            let dir_id = self
+                .storage()
+                .read_to_string(&path.join("dir.c9r"))
+                .map_err(|err| err.to_string())
+                .and_then(|id| DirId::parse(&id).map_err(|err| err.to_string()));
+            match dir_id {
+                Ok(dir_id) => return Some((dir_id, cleartext_path)),
+                Err(err) => self.issue(
+                    IssueKind::CorruptDirId,
+                    path.join("dir.c9r"),
+                    cleartext,
+                    err,
+                ),
+            }
+        } else if self.storage().is_file(&path.join("symlink.c9r")) {
+            self.check_file(&path.join("symlink.c9r"), cleartext);
+            self.report.symlinks_checked += 1;
+        } else if self.storage().is_file(&path.join("contents.c9r")) {
+            self.check_file(&path.join("contents.c9r"), cleartext);
+            self.report.files_checked += 1;
+        } else {
+            self.issue(IssueKind::UnknownEntryType, path, cleartext, "");
+        }
+
+        None
+    }
+
+    fn decrypt_name(
+        &self,
+        path: &Path,
+        file_name: &OsStr,
+        parent_dir_id: &DirId,
+    ) -> Result<String, (IssueKind, String)> {
+        // Ok to unwrap, we already checked that this is a valid encrypted name
+        let file_name = file_name.to_str().unwrap();
+        let ciphertext_name = match file_name.strip_suffix(".c9s") {
+            Some(hash) => {
+                let full_name = self
+                    .storage()
+                    .read_to_string(&path.join("name.c9s"))
+                    .map_err(|err| (IssueKind::Io, err.to_string()))?;
+                let expected_hash = Sha1::new().chain_update(&full_name).finalize();
+                if Base64Url::encode_string(&expected_hash) != hash {
+                    return Err((IssueKind::ShortenedNameMismatch, String::new()));
+                }
+
+                full_name
+            }
+            None => file_name.to_string(),
+        };
+
+        self.vault
+            .cryptor()
+            .decrypt_filename(&ciphertext_name, parent_dir_id)
+            .map_err(|err| (IssueKind::NameDecryptionFailed, err.to_string()))
+    }
+
+    fn check_file(&mut self, path: &Path, cleartext_path: Option<&Path>) {
+        let file = match self.open_file(path) {
+            Ok(file) => file,
+            Err((kind, err)) => return self.issue(kind, path, cleartext_path, err),
+        };
+        if !self.options.deep {
+            return;
+        }
+
+        match damaged_chunks(file, self.vault.cryptor()) {
+            Ok((_, damaged)) if damaged.is_empty() => {}
+            Ok((total, damaged)) => self.issue_with_fix(
+                IssueKind::CorruptContent,
+                path,
+                cleartext_path,
+                format_args!("{} of {total} chunks could not be decrypted", damaged.len()),
+                Some(Fix::DamagedChunk { chunks: damaged }),
+            ),
+            Err(err) => self.issue(IssueKind::Io, path, cleartext_path, err),
+        }
+    }
+
+    // Decrypt a whole file.
+    fn decrypt_file(&self, path: &Path) -> Result<Vec<u8>, (IssueKind, String)> {
+        let mut decrypted = Vec::new();
+        self.open_file(path)?
+            .read_to_end(&mut decrypted)
+            .map_err(|err| (IssueKind::CorruptContent, err.to_string()))?;
+
+        Ok(decrypted)
+    }
+
+    // Open a file for reading, decrypting its header.
+    fn open_file(&self, path: &Path) -> Result<EncryptedFile<'v>, (IssueKind, String)> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        EncryptedFile::open_in(self.storage(), self.vault.cryptor(), path, options).map_err(|err| {
+            // A header that's cut off or doesn't authenticate is corrupt, anything else is I/O
+            match err.downcast_ref::<io::Error>().map(io::Error::kind) {
+                Some(io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData) | None => {
+                    (IssueKind::CorruptHeader, err.to_string())
+                }
+                Some(_) => (IssueKind::Io, err.to_string()),
+            }
+        })
+    }
+
+    fn check_orphans(&mut self, visited: &BTreeSet<PathBuf>) -> Result<()> {
+        let data_dir = self.vault.path().join("d");
+        for prefix in self.storage().list(&data_dir)? {
+            let prefix = data_dir.join(prefix);
+            if !self.storage().is_dir(&prefix) {
+                continue;
+            }
+
+            for dir in self.storage().list(&prefix)? {
+                let dir = prefix.join(dir);
+                if visited.contains(&dir) {
+                    continue;
+                }
+
+                (self.progress)(&dir);
+                self.issue(IssueKind::OrphanedDirectory, dir, None, "");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Decrypt every chunk of a file, returning the total number of chunks and the numbers of those
+// that could not be decrypted.
+fn damaged_chunks(mut file: EncryptedFile, cryptor: Cryptor) -> io::Result<(u64, Vec<u64>)> {
+    let chunk_len = cryptor.max_chunk_len() as u64;
+    // Count chunks by the ciphertext, since the cleartext length leaves out a last chunk that's
+    // too short to hold anything
+    let ciphertext_len = file.metadata().map_err(io::Error::other)?.len();
+    let total = ciphertext_len
+        .saturating_sub(cryptor.encrypted_header_len() as u64)
+        .div_ceil(cryptor.max_encrypted_chunk_len() as u64);
+    let mut damaged = Vec::new();
+    // Ok to truncate, this is the length of a chunk
+    let mut buf = vec![0; chunk_len as usize];
+    for chunk in 0..total {
+        // Reading from the start of a chunk always decrypts the whole chunk
+        file.seek(SeekFrom::Start(chunk * chunk_len))?;
+        match file.read(&mut buf) {
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                damaged.push(chunk)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok((total, damaged))
+}
+
+impl Vault {
+    /// Check the integrity of this vault, reporting any problems found.
+    pub fn check(&self, options: CheckOptions) -> Result<IntegrityReport> {
+        self.check_with_progress(options, |_| {})
+    }
+
+    /// Same as [`Vault::check`], but calls `progress` with each ciphertext path before it is
+    /// checked.
+    pub fn check_with_progress(
+        &self,
+        options: CheckOptions,
+        progress: impl FnMut(&Path),
+    ) -> Result<IntegrityReport> {
+        Checker {
+            vault: self,
+            options,
+            progress,
+            report: Default::default(),
+        }
+        .run()
+    }
+}
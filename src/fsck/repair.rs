@@ -0,0 +1,326 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::eyre;
+
+use super::{IntegrityReport, Issue, IssueKind};
+use crate::{
+    crypto::{DirId, FileCryptor},
+    fs::{sync_conflict_original, EncryptedFile, EncryptedFileSystem},
+    storage::OpenOptions,
+    Result, Vault,
+};
+
+/// Options for [`Vault::repair`].
+#[derive(Debug, Clone, Default)]
+pub struct RepairOptions {
+    /// The kinds of issues to repair, all others are skipped. Only these kinds can be repaired:
+    ///
+    /// - [`IssueKind::MissingDirIdBackup`], [`IssueKind::CorruptDirIdBackup`], and
+    ///   [`IssueKind::DirIdMismatch`], by rewriting dirid.c9r from the ID in the parent's dir.c9r
+    /// - [`IssueKind::OrphanedDirectory`], by adopting the orphan into the root directory if its
+    ///   directory ID is known
+    /// - [`IssueKind::MissingDirectory`], by removing the entry that points to it
+    /// - [`IssueKind::InvalidName`], if the entry is a sync conflict copy, by renaming it like
+    ///   [`EncryptedFileSystem::repair_conflicts`] does
+    /// - [`IssueKind::CorruptContent`], if the last chunk is too short to hold any cleartext, by
+    ///   truncating the file before it
+    pub kinds: Vec<IssueKind>,
+    /// Only work out what would be repaired, without changing anything.
+    pub dry_run: bool,
+    /// Allow repairs that delete ciphertext, i.e. removing entries of missing directories and
+    /// truncating files. The deleted ciphertext can't be decrypted anyway, but it may still be
+    /// useful to a more thorough recovery.
+    pub allow_data_loss: bool,
+}
+
+/// What was done to repair an [`Issue`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairAction {
+    /// The dirid.c9r backup was rewritten.
+    RestoreDirIdBackup,
+    /// The orphaned directory was adopted as the cleartext directory `target`.
+    AdoptOrphan { target: PathBuf },
+    /// The entry at `ciphertext_path`, which pointed to the missing directory, was removed.
+    RemoveDirEntry { ciphertext_path: PathBuf },
+    /// The sync conflict copy was given a valid name in the cleartext directory `cleartext_dir`.
+    RenameConflict { cleartext_dir: PathBuf },
+    /// An incomplete last chunk was cut off, leaving `len` bytes of ciphertext.
+    TruncatePartialChunk { len: u64 },
+}
+
+/// An [`Issue`] that was repaired.
+#[derive(Debug, Clone)]
+pub struct Repair {
+    pub issue: Issue,
+    pub action: RepairAction,
+}
+
+/// The outcome of [`Vault::repair`].
+#[derive(Debug, Clone, Default)]
+pub struct RepairSummary {
+    /// Issues that were repaired, or would have been in a dry run.
+    pub repaired: Vec<Repair>,
+    /// Issues that were left alone, along with the reason.
+    pub skipped: Vec<(Issue, String)>,
+    /// Issues that could not be repaired, along with the reason.
+    pub errors: Vec<(Issue, String)>,
+}
+
+// What became of a single issue.
+enum Outcome {
+    Repaired(RepairAction),
+    Skipped(&'static str),
+}
+
+struct Repairer<'v, 'o> {
+    vault: &'v Vault,
+    fs: EncryptedFileSystem<'v>,
+    options: &'o RepairOptions,
+    summary: RepairSummary,
+}
+
+impl Repairer<'_, '_> {
+    fn repair(&mut self, issue: &Issue) {
+        if !self.options.kinds.contains(&issue.kind) {
+            self.summary
+                .skipped
+                .push((issue.clone(), "not selected".to_string()));
+            return;
+        }
+
+        let result = match issue.kind {
+            IssueKind::MissingDirIdBackup
+            | IssueKind::CorruptDirIdBackup
+            | IssueKind::DirIdMismatch => self.restore_dir_id_backup(issue),
+            IssueKind::OrphanedDirectory => self.adopt_orphan(issue),
+            IssueKind::MissingDirectory => self.remove_dir_entry(issue),
+            IssueKind::InvalidName => self.rename_conflict(issue),
+            IssueKind::CorruptContent => self.truncate_partial_chunk(issue),
+            _ => Ok(Outcome::Skipped("can't be repaired automatically")),
+        };
+
+        let dry_run = self.options.dry_run;
+        let path = issue.ciphertext_path.display();
+        match result {
+            Ok(Outcome::Repaired(action)) => {
+                tracing::info!(dry_run, kind = ?issue.kind, %path, ?action, "repaired issue");
+                self.summary.repaired.push(Repair {
+                    issue: issue.clone(),
+                    action,
+                });
+            }
+            Ok(Outcome::Skipped(reason)) => {
+                tracing::info!(dry_run, kind = ?issue.kind, %path, reason, "skipped issue");
+                self.summary
+                    .skipped
+                    .push((issue.clone(), reason.to_string()));
+            }
+            Err(err) => {
+                tracing::warn!(dry_run, kind = ?issue.kind, %path, "failed to repair issue: {err}");
+                self.summary.errors.push((issue.clone(), err.to_string()));
+            }
+        }
+    }
+
+    fn hashed_dir_path(&self, dir_id: &DirId) -> Result<PathBuf> {
+        Ok(self
+            .vault
+            .path()
+            .join("d")
+            .join(self.vault.cryptor().hash_dir_id(dir_id)?))
+    }
+
+    // Read the directory ID from the entry of a cleartext directory.
+    fn dir_id(&self, cleartext_dir: &Path) -> Result<DirId> {
+        if cleartext_dir.parent().is_none() {
+            return Ok(DirId::root());
+        }
+
+        let ciphertext_path = self.fs.ciphertext_path(cleartext_dir)?;
+        let dir_id = self
+            .vault
+            .storage()
+            .read_to_string(&ciphertext_path.join("dir.c9r"))?;
+        DirId::parse(&dir_id)
+    }
+
+    fn restore_dir_id_backup(&self, issue: &Issue) -> Result<Outcome> {
+        let Some(cleartext_dir) = &issue.cleartext_path else {
+            return Ok(Outcome::Skipped("the directory is unknown"));
+        };
+        let dir_id = self.dir_id(cleartext_dir)?;
+        // Ok to unwrap, dirid.c9r is always inside a hashed directory
+        let hashed_dir_path = issue.ciphertext_path.parent().unwrap();
+        if self.hashed_dir_path(&dir_id)? != hashed_dir_path {
+            return Ok(Outcome::Skipped(
+                "the directory has changed since it was checked",
+            ));
+        }
+
+        if !self.options.dry_run {
+            let storage = self.vault.storage();
+            match storage.remove_file(&issue.ciphertext_path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            let mut backup = EncryptedFile::create_new_in(
+                storage,
+                self.vault.cryptor(),
+                &issue.ciphertext_path,
+            )?;
+            backup.write_all(dir_id.as_str().as_bytes())?;
+            backup.sync_all()?;
+        }
+
+        Ok(Outcome::Repaired(RepairAction::RestoreDirIdBackup))
+    }
+
+    fn adopt_orphan(&self, issue: &Issue) -> Result<Outcome> {
+        // Adopting a directory also adopts the orphans inside it, so look for orphans again
+        let orphans = self.fs.find_orphans()?;
+        let Some(orphan) = orphans
+            .iter()
+            .find(|orphan| orphan.hashed_dir_path == issue.ciphertext_path)
+        else {
+            return Ok(Outcome::Skipped("the directory is no longer an orphan"));
+        };
+        if orphan.dir_id.is_none() {
+            return Ok(Outcome::Skipped("the directory ID is unknown"));
+        }
+
+        // Ok to unwrap, hashed directories always have a name
+        let hashed_dir = orphan.hashed_dir_path.file_name().unwrap();
+        let target = Path::new("/").join(format!("Recovered {}", hashed_dir.to_string_lossy()));
+        if !self.options.dry_run {
+            self.fs.adopt_orphan(orphan, &target)?;
+        }
+
+        Ok(Outcome::Repaired(RepairAction::AdoptOrphan { target }))
+    }
+
+    fn remove_dir_entry(&self, issue: &Issue) -> Result<Outcome> {
+        if !self.options.allow_data_loss {
+            return Ok(Outcome::Skipped(
+                "removing the entry requires allow_data_loss",
+            ));
+        }
+        let Some(cleartext_dir) = &issue.cleartext_path else {
+            return Ok(Outcome::Skipped("the directory is unknown"));
+        };
+        let storage = self.vault.storage();
+        if storage.exists(&issue.ciphertext_path)
+            || self.hashed_dir_path(&self.dir_id(cleartext_dir)?)? != issue.ciphertext_path
+        {
+            return Ok(Outcome::Skipped(
+                "the directory has changed since it was checked",
+            ));
+        }
+
+        let ciphertext_path = self.fs.ciphertext_path(cleartext_dir)?;
+        if !self.options.dry_run {
+            storage.remove_dir_all(&ciphertext_path)?;
+            self.fs.invalidate(cleartext_dir);
+        }
+
+        Ok(Outcome::Repaired(RepairAction::RemoveDirEntry {
+            ciphertext_path,
+        }))
+    }
+
+    fn rename_conflict(&self, issue: &Issue) -> Result<Outcome> {
+        let is_conflict = issue
+            .ciphertext_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(sync_conflict_original)
+            .is_some();
+        if !is_conflict {
+            return Ok(Outcome::Skipped("the entry is not a sync conflict copy"));
+        }
+
+        // Ok to unwrap, entries are always inside a hashed directory
+        let hashed_dir_path = issue.ciphertext_path.parent().unwrap();
+        let cleartext_dir = self.fs.cleartext_path(hashed_dir_path)?;
+        if !self.options.dry_run {
+            // Conflict copies are renamed as the directory is listed
+            self.fs
+                .clone()
+                .repair_conflicts(true)
+                .read_dir(&cleartext_dir)?;
+            if self.vault.storage().exists(&issue.ciphertext_path) {
+                return Err(eyre!("the sync conflict copy could not be renamed"));
+            }
+        }
+
+        Ok(Outcome::Repaired(RepairAction::RenameConflict {
+            cleartext_dir,
+        }))
+    }
+
+    fn truncate_partial_chunk(&self, issue: &Issue) -> Result<Outcome> {
+        if !self.options.allow_data_loss {
+            return Ok(Outcome::Skipped(
+                "truncating the file requires allow_data_loss",
+            ));
+        }
+
+        let cryptor = self.vault.cryptor();
+        let storage = self.vault.storage();
+        let len = storage.metadata(&issue.ciphertext_path)?.len();
+        let encrypted_chunk_len = cryptor.max_encrypted_chunk_len() as u64;
+        let partial_len =
+            len.saturating_sub(cryptor.encrypted_header_len() as u64) % encrypted_chunk_len;
+        if partial_len == 0 || partial_len > cryptor.chunk_overhead() as u64 {
+            return Ok(Outcome::Skipped(
+                "no chunk is too short to hold any cleartext",
+            ));
+        }
+
+        let len = len - partial_len;
+        if !self.options.dry_run {
+            let mut options = OpenOptions::new();
+            options.write(true);
+            let file = storage.open(&issue.ciphertext_path, &options)?;
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+
+        Ok(Outcome::Repaired(RepairAction::TruncatePartialChunk {
+            len,
+        }))
+    }
+}
+
+impl Vault {
+    /// Repair the issues in `report` that can be fixed safely, as selected by `options`. Each
+    /// issue is checked again before it's repaired, in case the vault changed since the report
+    /// was made, and each repair is logged. The vault should not be in use in the meantime.
+    ///
+    /// Orphaned directories are adopted before anything else, so their contents are kept even if
+    /// the entries of missing directories are removed.
+    pub fn repair(
+        &self,
+        report: &IntegrityReport,
+        options: &RepairOptions,
+    ) -> Result<RepairSummary> {
+        let mut repairer = Repairer {
+            vault: self,
+            fs: EncryptedFileSystem::new(self),
+            options,
+            summary: Default::default(),
+        };
+
+        let (orphans, others): (Vec<_>, Vec<_>) = report
+            .issues
+            .iter()
+            .partition(|issue| issue.kind == IssueKind::OrphanedDirectory);
+        for issue in orphans.into_iter().chain(others) {
+            repairer.repair(issue);
+        }
+
+        Ok(repairer.summary)
+    }
+}
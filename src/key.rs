@@ -1,36 +1,210 @@
-use std::{fmt::Debug, fs, path::Path};
+use std::{
+    fmt::Debug,
+    fs,
+    ops::Deref,
+    path::Path,
+    sync::{PoisonError, RwLock, RwLockReadGuard},
+    time::{Duration, Instant},
+};
 
 use aes_kw::KekAes256;
 use base64ct::{Base64, Encoding};
+use color_eyre::eyre::bail;
 use rand_core::{self, OsRng, RngCore};
 use scrypt::{
     password_hash::{Salt, SaltString},
     Params,
 };
 use serde::{Deserialize, Serialize};
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{util, Result, VaultLocked};
+
+#[cfg(feature = "hub")]
+mod hub;
+mod loader;
+mod passphrase;
 
-use crate::{util, Result};
+#[cfg(feature = "hub")]
+pub use hub::HubKeyLoader;
+pub use loader::{MasterkeyFileLoader, MasterkeyLoader, RawKeyLoader};
+pub use passphrase::Passphrase;
 
 pub const SUBKEY_LEN: usize = 32;
 
+/// The `version` field written to masterkey files, which is also used for the version MAC.
+pub const MASTERKEY_FILE_VERSION: u32 = 999;
+
+/// Scrypt parameters used to derive the key that wraps the master key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// CPU/memory cost (N), which must be a power of two.
+    pub cost: u32,
+    /// Block size (r).
+    pub block_size: u32,
+    /// Parallelism (p). The official app only supports a value of 1.
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    pub const MIN_COST: u32 = 1 << 10;
+    pub const MAX_COST: u32 = 1 << 24;
+    pub const MAX_BLOCK_SIZE: u32 = 64;
+    pub const MAX_PARALLELISM: u32 = 16;
+
+    pub fn new(cost: u32, block_size: u32, parallelism: u32) -> Result<Self> {
+        let params = Self {
+            cost,
+            block_size,
+            parallelism,
+        };
+        params.validate()?;
+        Ok(params)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.cost.is_power_of_two() || !(Self::MIN_COST..=Self::MAX_COST).contains(&self.cost) {
+            bail!(
+                "scrypt cost must be a power of two between {} and {}, got {}",
+                Self::MIN_COST,
+                Self::MAX_COST,
+                self.cost
+            );
+        }
+
+        if !(1..=Self::MAX_BLOCK_SIZE).contains(&self.block_size) {
+            bail!("invalid scrypt block size: {}", self.block_size);
+        }
+
+        if !(1..=Self::MAX_PARALLELISM).contains(&self.parallelism) {
+            bail!("invalid scrypt parallelism: {}", self.parallelism);
+        }
+
+        Ok(())
+    }
+
+    /// Pick the largest cost for which deriving a key on this machine takes no longer than
+    /// `target`, using the default block size and parallelism. The result is never lower than
+    /// [`KdfParams::MIN_COST`].
+    pub fn calibrate(target: Duration) -> Result<Self> {
+        let mut params = Self {
+            cost: Self::MIN_COST,
+            ..Default::default()
+        };
+        let salt = SaltString::encode_b64(b"calibration")?;
+
+        loop {
+            let start = Instant::now();
+            util::derive_kek(
+                &Passphrase::from("calibration"),
+                params.try_into()?,
+                salt.as_salt(),
+            )?;
+            let elapsed = start.elapsed();
+
+            // Doubling the cost roughly doubles the time taken
+            if params.cost >= Self::MAX_COST || elapsed * 2 > target {
+                return Ok(params);
+            }
+            params.cost *= 2;
+        }
+    }
+}
+
+impl Default for KdfParams {
+    /// The same parameters as the official app.
+    fn default() -> Self {
+        Self {
+            cost: 1 << 15,
+            block_size: 8,
+            parallelism: 1,
+        }
+    }
+}
+
+impl TryFrom<KdfParams> for Params {
+    type Error = color_eyre::Report;
+
+    fn try_from(params: KdfParams) -> Result<Self> {
+        params.validate()?;
+        Ok(Params::new(
+            params.cost.ilog2() as u8,
+            params.block_size,
+            params.parallelism,
+            SUBKEY_LEN,
+        )?)
+    }
+}
+
+impl From<Params> for KdfParams {
+    fn from(params: Params) -> Self {
+        Self {
+            cost: 1 << params.log_n(),
+            block_size: params.r(),
+            parallelism: params.p(),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Zeroize, ZeroizeOnDrop)]
 pub struct MasterKey([u8; SUBKEY_LEN * 2]);
 
 impl MasterKey {
-    pub fn new() -> Result<Self> {
+    /// Generate a new random master key, e.g. for a new vault.
+    pub fn random() -> Result<Self> {
         let mut key = Self([0_u8; SUBKEY_LEN * 2]);
         OsRng.try_fill_bytes(&mut key.0)?;
         Ok(key)
     }
 
+    /// Create a [`MasterKey`] from its encryption and MAC subkeys, which are zeroized afterwards.
+    /// Since arrays are copied when passed by value, the caller is responsible for zeroizing
+    /// their own copies.
+    ///
+    /// Fails if either subkey is all zeros, which is a sign that it was never filled in.
+    pub fn new(mut enc_key: [u8; SUBKEY_LEN], mut mac_key: [u8; SUBKEY_LEN]) -> Result<Self> {
+        let mut key = Self([0_u8; SUBKEY_LEN * 2]);
+        key.0[..SUBKEY_LEN].copy_from_slice(&enc_key);
+        key.0[SUBKEY_LEN..].copy_from_slice(&mac_key);
+        enc_key.zeroize();
+        mac_key.zeroize();
+        key.validate()?;
+        Ok(key)
+    }
+
+    /// Create a [`MasterKey`] from its raw bytes: the encryption subkey followed by the MAC
+    /// subkey, i.e. the unwrapped `primaryMasterKey` and `hmacMasterKey` of a masterkey file.
+    ///
+    /// Fails if either subkey is all zeros, which is a sign that it was never filled in.
+    pub fn from_raw(bytes: Zeroizing<[u8; SUBKEY_LEN * 2]>) -> Result<Self> {
+        let key = Self::from_raw_unchecked(bytes);
+        key.validate()?;
+        Ok(key)
+    }
+
+    // For bytes that are known to come from an existing master key.
+    pub(crate) fn from_raw_unchecked(bytes: Zeroizing<[u8; SUBKEY_LEN * 2]>) -> Self {
+        Self(*bytes)
+    }
+
     /// Create a [`MasterKey`] from the provided byte array.
     ///
     /// # Safety
     ///
     /// - `bytes` should contain secret, random bytes with sufficient entropy
+    #[deprecated(note = "use `MasterKey::from_raw` instead, which is safe and validates the key")]
     pub unsafe fn from_bytes(bytes: [u8; SUBKEY_LEN * 2]) -> Self {
-        MasterKey(bytes)
+        Self::from_raw_unchecked(Zeroizing::new(bytes))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.enc_key().iter().all(|&b| b == 0) {
+            bail!("encryption subkey of master key is all zeros");
+        }
+        if self.mac_key().iter().all(|&b| b == 0) {
+            bail!("MAC subkey of master key is all zeros");
+        }
+        Ok(())
     }
 
     pub(crate) fn enc_key(&self) -> &[u8; SUBKEY_LEN] {
@@ -59,6 +233,7 @@ impl MasterKey {
         key_encryption_key.wrap(self.mac_key(), &mut wrapped_mac_master_key)?;
 
         Ok(WrappedKey {
+            version: format_version,
             scrypt_salt,
             scrypt_params,
             enc_key: wrapped_enc_master_key.to_vec(),
@@ -68,10 +243,10 @@ impl MasterKey {
     }
 
     pub fn from_wrapped(wrapped_key: &WrappedKey, key_encryption_key: &KekAes256) -> Result<Self> {
-        let mut buffer = [0_u8; SUBKEY_LEN * 2];
+        let mut buffer = Zeroizing::new([0_u8; SUBKEY_LEN * 2]);
         key_encryption_key.unwrap(wrapped_key.enc_key(), &mut buffer[0..SUBKEY_LEN])?;
         key_encryption_key.unwrap(wrapped_key.mac_key(), &mut buffer[SUBKEY_LEN..])?;
-        Ok(MasterKey(buffer))
+        Ok(Self::from_raw_unchecked(buffer))
     }
 }
 
@@ -81,6 +256,77 @@ impl Debug for MasterKey {
     }
 }
 
+/// A master key shared by a [`Vault`](crate::Vault), which can be wiped while cryptors still
+/// refer to it. Anything that needs the key fails with [`VaultLocked`] from then on.
+#[derive(Debug)]
+pub(crate) struct SharedKey(RwLock<Option<MasterKey>>);
+
+impl SharedKey {
+    pub(crate) fn new(key: MasterKey) -> Self {
+        Self(RwLock::new(Some(key)))
+    }
+
+    pub(crate) fn get(&self) -> Result<KeyGuard<'_>, VaultLocked> {
+        // Wiping can't be interrupted halfway, so a panic while holding the lock doesn't matter
+        let guard = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        match *guard {
+            Some(_) => Ok(KeyGuard::Shared(guard)),
+            None => Err(VaultLocked),
+        }
+    }
+
+    /// Zeroize the key, after waiting for anything that's using it to finish.
+    pub(crate) fn wipe(&self) {
+        let mut guard = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        // Zeroized in place, since taking the key out would leave a copy behind
+        if let Some(key) = guard.as_mut() {
+            key.zeroize();
+        }
+        *guard = None;
+    }
+
+    pub(crate) fn is_wiped(&self) -> bool {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_none()
+    }
+}
+
+/// The master key a cryptor uses, either borrowed directly or shared by a vault.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum KeyRef<'k> {
+    Borrowed(&'k MasterKey),
+    Shared(&'k SharedKey),
+}
+
+impl<'k> KeyRef<'k> {
+    pub(crate) fn get(self) -> Result<KeyGuard<'k>, VaultLocked> {
+        match self {
+            Self::Borrowed(key) => Ok(KeyGuard::Borrowed(key)),
+            Self::Shared(key) => key.get(),
+        }
+    }
+}
+
+/// Access to a master key, which can't be wiped while this is held.
+pub(crate) enum KeyGuard<'k> {
+    Borrowed(&'k MasterKey),
+    Shared(RwLockReadGuard<'k, Option<MasterKey>>),
+}
+
+impl Deref for KeyGuard<'_> {
+    type Target = MasterKey;
+
+    fn deref(&self) -> &MasterKey {
+        match self {
+            Self::Borrowed(key) => key,
+            // Ok to unwrap, guards are only handed out for keys that haven't been wiped
+            Self::Shared(guard) => guard.as_ref().unwrap(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct RawWrappedKey {
@@ -88,6 +334,9 @@ struct RawWrappedKey {
     scrypt_salt: String,
     scrypt_cost_param: u32,
     scrypt_block_size: u32,
+    // Not part of the official format, only written if parallelism is not 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scrypt_parallelism: Option<u32>,
     primary_master_key: String,
     hmac_master_key: String,
     version_mac: String,
@@ -95,6 +344,7 @@ struct RawWrappedKey {
 
 #[derive(Debug)]
 pub struct WrappedKey {
+    pub(crate) version: u32,
     pub(crate) scrypt_salt: SaltString,
     pub(crate) scrypt_params: Params,
     pub(crate) enc_key: Vec<u8>,
@@ -104,17 +354,24 @@ pub struct WrappedKey {
 
 impl WrappedKey {
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let json = fs::read_to_string(path)?;
-        let raw: RawWrappedKey = serde_json::from_str(&json)?;
-        let recommended_params = Params::recommended();
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Parse the contents of a masterkey file. Nothing is unwrapped or verified yet.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let raw: RawWrappedKey = serde_json::from_str(json)?;
         let salt_no_padding = raw.scrypt_salt.replace('=', "");
+        let Some(log_n) = raw.scrypt_cost_param.checked_ilog2() else {
+            bail!("invalid scrypt cost parameter: {}", raw.scrypt_cost_param);
+        };
 
         Ok(Self {
+            version: raw.version,
             scrypt_salt: SaltString::from_b64(&salt_no_padding)?,
             scrypt_params: Params::new(
-                raw.scrypt_cost_param.ilog2() as u8,
+                log_n as u8,
                 raw.scrypt_block_size,
-                recommended_params.p(),
+                raw.scrypt_parallelism.unwrap_or(1),
                 SUBKEY_LEN,
             )?,
             enc_key: Base64::decode_vec(&raw.primary_master_key)?,
@@ -123,7 +380,34 @@ impl WrappedKey {
         })
     }
 
-    pub fn salt(&self) -> Salt {
+    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut salt_buf = [0_u8; Salt::MAX_LENGTH];
+        let salt = self.salt().decode_b64(&mut salt_buf)?;
+
+        let raw = RawWrappedKey {
+            version: self.version,
+            scrypt_salt: Base64::encode_string(salt),
+            scrypt_cost_param: 1 << self.scrypt_params.log_n(),
+            scrypt_block_size: self.scrypt_params.r(),
+            scrypt_parallelism: Some(self.scrypt_params.p()).filter(|&p| p != 1),
+            primary_master_key: Base64::encode_string(&self.enc_key),
+            hmac_master_key: Base64::encode_string(&self.mac_key),
+            version_mac: Base64::encode_string(&self.version_mac),
+        };
+
+        let mut file = fs::File::create_new(path)?;
+        serde_json::to_writer_pretty(&mut file, &raw)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// The `version` field of the masterkey file. This is the vault format for vaults older than
+    /// format 8, and 999 otherwise.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn salt(&self) -> Salt<'_> {
         self.scrypt_salt.as_salt()
     }
 
@@ -157,10 +441,10 @@ mod tests {
     fn wrap_and_unwrap_test() {
         let key_bytes = [[10; SUBKEY_LEN], [20; SUBKEY_LEN]].concat();
         let key = MasterKey(key_bytes.try_into().unwrap());
-        let password = String::from("this is a test password");
+        let password = Passphrase::from("this is a test password");
         let params = Params::new(15, 8, 1, SUBKEY_LEN).unwrap();
         let salt_string = SaltString::encode_b64(b"test salt").unwrap();
-        let kek = util::derive_kek(password, params, salt_string.as_salt()).unwrap();
+        let kek = util::derive_kek(&password, params, salt_string.as_salt()).unwrap();
         let wrapped_key = key.wrap(&kek, params, salt_string.clone(), 8).unwrap();
 
         assert_eq!(wrapped_key.scrypt_salt, salt_string);
@@ -182,4 +466,99 @@ mod tests {
 
         assert_eq!(MasterKey::from_wrapped(&wrapped_key, &kek).unwrap(), key);
     }
+
+    #[test]
+    fn master_key_constructors_test() {
+        use crate::crypto::{siv_gcm, FileCryptor};
+
+        let key = MasterKey::new([10; SUBKEY_LEN], [20; SUBKEY_LEN]).unwrap();
+        assert_eq!(key.enc_key(), &[10; SUBKEY_LEN]);
+        assert_eq!(key.mac_key(), &[20; SUBKEY_LEN]);
+        let raw_bytes = [[10; SUBKEY_LEN], [20; SUBKEY_LEN]].concat();
+        let raw = MasterKey::from_raw(Zeroizing::new(raw_bytes.try_into().unwrap())).unwrap();
+        assert_eq!(raw, key);
+
+        assert!(MasterKey::new([0; SUBKEY_LEN], [20; SUBKEY_LEN]).is_err());
+        assert!(MasterKey::new([10; SUBKEY_LEN], [0; SUBKEY_LEN]).is_err());
+        assert!(MasterKey::from_raw(Zeroizing::new([0; SUBKEY_LEN * 2])).is_err());
+        assert_ne!(MasterKey::random().unwrap(), MasterKey::random().unwrap());
+
+        // Keys built either way are interchangeable with the cryptor
+        let cryptor = siv_gcm::Cryptor::new(&key);
+        let header = cryptor.new_header().unwrap();
+        let ciphertext = cryptor.encrypt_chunk(b"some data", &header, 0).unwrap();
+        let other = siv_gcm::Cryptor::new(&raw);
+        let header = other
+            .decrypt_header(&cryptor.encrypt_header(&header).unwrap())
+            .unwrap();
+        assert_eq!(
+            other.decrypt_chunk(&ciphertext, &header, 0).unwrap(),
+            b"some data"
+        );
+        let random_key = MasterKey::random().unwrap();
+        let random_cryptor = siv_gcm::Cryptor::new(&random_key);
+        assert!(random_cryptor
+            .decrypt_header(&cryptor.encrypt_header(&header).unwrap())
+            .is_err());
+
+        // The deprecated constructor still works
+        #[allow(deprecated)]
+        // Safe, this is for test purposes only
+        let deprecated = unsafe { MasterKey::from_bytes(*key.raw_key()) };
+        assert_eq!(deprecated, key);
+    }
+
+    #[test]
+    fn kdf_params_test() {
+        assert!(KdfParams::new(1 << 15, 8, 1).is_ok());
+        assert!(KdfParams::new(1000, 8, 1).is_err());
+        assert!(KdfParams::new(1 << 8, 8, 1).is_err());
+        assert!(KdfParams::new(1 << 25, 8, 1).is_err());
+        assert!(KdfParams::new(1 << 15, 0, 1).is_err());
+        assert!(KdfParams::new(1 << 15, 8, 0).is_err());
+
+        let params = Params::try_from(KdfParams::new(1 << 12, 4, 2).unwrap()).unwrap();
+        assert_eq!((params.log_n(), params.r(), params.p()), (12, 4, 2));
+        assert_eq!(
+            KdfParams::from(params),
+            KdfParams::new(1 << 12, 4, 2).unwrap()
+        );
+
+        // Too short to ever increase the cost
+        let calibrated = KdfParams::calibrate(Duration::ZERO).unwrap();
+        assert_eq!(calibrated.cost, KdfParams::MIN_COST);
+        let calibrated = KdfParams::calibrate(Duration::from_millis(50)).unwrap();
+        assert!(calibrated.validate().is_ok());
+    }
+
+    #[test]
+    fn wrapped_key_file_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = MasterKey([5; SUBKEY_LEN * 2]);
+        let params = Params::try_from(KdfParams::new(1 << 10, 4, 2).unwrap()).unwrap();
+        let salt_string = SaltString::encode_b64(b"test salt").unwrap();
+        let kek = util::derive_kek(&"password".into(), params, salt_string.as_salt()).unwrap();
+        let wrapped_key = key.wrap(&kek, params, salt_string, 999).unwrap();
+
+        let path = dir.path().join("masterkey.cryptomator");
+        wrapped_key.to_file(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(json["scryptCostParam"], 1024);
+        assert_eq!(json["scryptBlockSize"], 4);
+        assert_eq!(json["scryptParallelism"], 2);
+
+        let read_back = WrappedKey::from_file(&path).unwrap();
+        assert_eq!(KdfParams::from(read_back.params()), KdfParams::from(params));
+        assert_eq!(read_back.salt(), wrapped_key.salt());
+        assert_eq!(MasterKey::from_wrapped(&read_back, &kek).unwrap(), key);
+
+        // Malformed files are errors, not panics
+        let mut json = json;
+        json["scryptCostParam"] = 0.into();
+        assert!(WrappedKey::from_json(&json.to_string()).is_err());
+        json["scryptCostParam"] = 1024.into();
+        json["primaryMasterKey"] = "AAAA".into();
+        let truncated = WrappedKey::from_json(&json.to_string()).unwrap();
+        assert!(MasterKey::from_wrapped(&truncated, &kek).is_err());
+    }
 }
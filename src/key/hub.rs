@@ -0,0 +1,325 @@
+use std::fmt::Debug;
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use base64ct::{Base64, Base64UrlUnpadded, Encoding};
+use color_eyre::eyre::{bail, eyre};
+use p384::{ecdh, PublicKey, SecretKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{key::SUBKEY_LEN, MasterKey, MasterkeyLoader, Result};
+
+const KEY_ID_SCHEMES: [&str; 2] = ["hub+https://", "hub+http://"];
+
+/// Loads the master key of a Cryptomator Hub vault from a JWE that was encrypted to the user's
+/// device key, using ECDH-ES key agreement on P-384 and A256GCM content encryption.
+///
+/// Hub vaults have no masterkey file. Their `kid` header is a `hub+https://` URI pointing to the
+/// vault on the Hub server, which is where the JWE is fetched from.
+pub struct HubKeyLoader {
+    device_key: SecretKey,
+    jwe: String,
+}
+
+impl HubKeyLoader {
+    /// Create a [`HubKeyLoader`] from the user's device private key and the JWE in compact
+    /// serialization, as returned by the Hub server for the vault.
+    pub fn new(device_key: SecretKey, jwe: impl Into<String>) -> Self {
+        Self {
+            device_key,
+            jwe: jwe.into(),
+        }
+    }
+
+    /// The URL of the vault on the Hub server, if `key_id` is a Hub key ID.
+    pub fn vault_url(key_id: &str) -> Option<&str> {
+        KEY_ID_SCHEMES
+            .iter()
+            .any(|scheme| key_id.starts_with(scheme))
+            .then(|| &key_id["hub+".len()..])
+    }
+
+    fn decrypt(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let parts: Vec<&str> = self.jwe.trim().split('.').collect();
+        let [header_b64, encrypted_key, iv, ciphertext, tag] = parts[..] else {
+            bail!("JWE must have 5 parts, found {}", parts.len());
+        };
+        if !encrypted_key.is_empty() {
+            bail!("JWE using ECDH-ES must not have an encrypted key");
+        }
+
+        let header: JweHeader =
+            serde_json::from_slice(&Base64UrlUnpadded::decode_vec(header_b64)?)?;
+        if header.alg != "ECDH-ES" {
+            bail!("unsupported JWE algorithm: {}", header.alg);
+        }
+        if header.enc != "A256GCM" {
+            bail!("unsupported JWE encryption: {}", header.enc);
+        }
+        let apu = Base64UrlUnpadded::decode_vec(header.apu.as_deref().unwrap_or_default())?;
+        let apv = Base64UrlUnpadded::decode_vec(header.apv.as_deref().unwrap_or_default())?;
+
+        let ephemeral_key = header.epk.public_key()?;
+        let shared_secret = ecdh::diffie_hellman(
+            self.device_key.to_nonzero_scalar(),
+            ephemeral_key.as_affine(),
+        );
+        let key = concat_kdf(
+            shared_secret.raw_secret_bytes(),
+            header.enc.as_bytes(),
+            &apu,
+            &apv,
+        );
+
+        let iv = Base64UrlUnpadded::decode_vec(iv)?;
+        if iv.len() != 12 {
+            bail!(
+                "JWE initialization vector must be 12 bytes, found {}",
+                iv.len()
+            );
+        }
+        let mut msg = Base64UrlUnpadded::decode_vec(ciphertext)?;
+        msg.extend(Base64UrlUnpadded::decode_vec(tag)?);
+        // Ok to unwrap, the key is always 32 bytes
+        let cipher = Aes256Gcm::new_from_slice(key.as_ref()).unwrap();
+        let payload = cipher
+            .decrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: &msg,
+                    aad: header_b64.as_bytes(),
+                },
+            )
+            .map_err(|_| eyre!("failed to decrypt JWE, it may be for another device"))?;
+
+        Ok(Zeroizing::new(payload))
+    }
+}
+
+impl MasterkeyLoader for HubKeyLoader {
+    fn supports(&self, key_id: &str) -> bool {
+        Self::vault_url(key_id).is_some()
+    }
+
+    fn load(&self, _key_id: &str) -> Result<MasterKey> {
+        let payload = self.decrypt()?;
+        let payload: Zeroizing<JwePayload> = Zeroizing::new(serde_json::from_slice(&payload)?);
+
+        let mut bytes = Zeroizing::new([0_u8; SUBKEY_LEN * 2]);
+        let decoded = Base64::decode(payload.key.as_bytes(), bytes.as_mut())
+            .map_err(|_| eyre!("JWE payload must contain a {}-byte key", SUBKEY_LEN * 2))?;
+        if decoded.len() != SUBKEY_LEN * 2 {
+            bail!("JWE payload must contain a {}-byte key", SUBKEY_LEN * 2);
+        }
+        MasterKey::from_raw(bytes)
+    }
+}
+
+impl Debug for HubKeyLoader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("HubKeyLoader")
+    }
+}
+
+#[derive(Deserialize)]
+struct JweHeader {
+    alg: String,
+    enc: String,
+    epk: EphemeralKey,
+    apu: Option<String>,
+    apv: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EphemeralKey {
+    kty: String,
+    crv: String,
+    x: String,
+    y: String,
+}
+
+impl EphemeralKey {
+    fn public_key(&self) -> Result<PublicKey> {
+        if self.kty != "EC" || self.crv != "P-384" {
+            bail!("JWE ephemeral key must be on P-384");
+        }
+
+        // An uncompressed SEC1 point
+        let mut point = vec![0x04];
+        point.extend(Base64UrlUnpadded::decode_vec(&self.x)?);
+        point.extend(Base64UrlUnpadded::decode_vec(&self.y)?);
+        PublicKey::from_sec1_bytes(&point).map_err(|_| eyre!("invalid JWE ephemeral key"))
+    }
+}
+
+#[derive(Deserialize, Zeroize)]
+struct JwePayload {
+    key: String,
+}
+
+// The Concat KDF from NIST SP 800-56A, as used by ECDH-ES in RFC 7518. A single round of SHA-256
+// is enough for the 256-bit key of A256GCM.
+fn concat_kdf(shared_secret: &[u8], alg_id: &[u8], apu: &[u8], apv: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(1_u32.to_be_bytes());
+    hasher.update(shared_secret);
+    for info in [alg_id, apu, apv] {
+        // Ok to truncate, these all came from a header that fits in memory
+        hasher.update((info.len() as u32).to_be_bytes());
+        hasher.update(info);
+    }
+    hasher.update(256_u32.to_be_bytes());
+
+    Zeroizing::new(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use p384::elliptic_curve::sec1::ToEncodedPoint;
+
+    use super::*;
+    use crate::{
+        fs::{EncryptedFile, EncryptedFileSystem},
+        storage::OpenOptions,
+        testing::{self, VaultShape},
+        util, Vault,
+    };
+
+    // Encrypt a master key into a JWE for the device with the public key `device_key`, like the
+    // Hub server does, but with a fixed ephemeral key and IV.
+    fn encrypt_for_device(
+        key: &MasterKey,
+        device_key: &PublicKey,
+        ephemeral_key: &SecretKey,
+        iv: [u8; 12],
+    ) -> String {
+        let point = ephemeral_key.public_key().to_encoded_point(false);
+        let header = serde_json::json!({
+            "alg": "ECDH-ES",
+            "enc": "A256GCM",
+            "epk": {
+                "kty": "EC",
+                "crv": "P-384",
+                "x": Base64UrlUnpadded::encode_string(point.x().unwrap()),
+                "y": Base64UrlUnpadded::encode_string(point.y().unwrap()),
+            },
+        });
+        let header_b64 = Base64UrlUnpadded::encode_string(header.to_string().as_bytes());
+
+        let shared_secret =
+            ecdh::diffie_hellman(ephemeral_key.to_nonzero_scalar(), device_key.as_affine());
+        let cek = concat_kdf(shared_secret.raw_secret_bytes(), b"A256GCM", &[], &[]);
+        let payload = serde_json::json!({ "key": Base64::encode_string(key.raw_key()) });
+        let mut ciphertext = Aes256Gcm::new_from_slice(cek.as_ref())
+            .unwrap()
+            .encrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: payload.to_string().as_bytes(),
+                    aad: header_b64.as_bytes(),
+                },
+            )
+            .unwrap();
+        let tag = ciphertext.split_off(ciphertext.len() - 16);
+
+        format!(
+            "{header_b64}..{}.{}.{}",
+            Base64UrlUnpadded::encode_string(&iv),
+            Base64UrlUnpadded::encode_string(&ciphertext),
+            Base64UrlUnpadded::encode_string(&tag),
+        )
+    }
+
+    #[test]
+    fn hub_key_loader_test() {
+        let device_key = SecretKey::from_slice(&[7; 48]).unwrap();
+        let ephemeral_key = SecretKey::from_slice(&[9; 48]).unwrap();
+        let key = MasterKey::new([10; SUBKEY_LEN], [20; SUBKEY_LEN]).unwrap();
+        let jwe = encrypt_for_device(&key, &device_key.public_key(), &ephemeral_key, [3; 12]);
+
+        let loader = HubKeyLoader::new(device_key.clone(), jwe.clone());
+        let key_id = "hub+https://hub.example.com/api/vaults/3e0a5ea8";
+        assert!(loader.supports(key_id));
+        assert_eq!(
+            HubKeyLoader::vault_url(key_id),
+            Some("https://hub.example.com/api/vaults/3e0a5ea8")
+        );
+        assert!(!loader.supports("masterkeyfile:masterkey.cryptomator"));
+        assert_eq!(loader.load(key_id).unwrap(), key);
+
+        // Another device can't decrypt it
+        let other_device = SecretKey::from_slice(&[8; 48]).unwrap();
+        assert!(HubKeyLoader::new(other_device, jwe.clone())
+            .load(key_id)
+            .is_err());
+
+        // Tampering with any part is detected
+        let parts: Vec<&str> = jwe.split('.').collect();
+        for i in [0, 2, 3, 4] {
+            let mut tampered = parts.clone();
+            let mut part = Base64UrlUnpadded::decode_vec(parts[i]).unwrap();
+            // Trailing whitespace keeps the header valid JSON, so only authentication catches it
+            if i == 0 {
+                part.push(b' ');
+            } else {
+                part[0] ^= 1;
+            }
+            let part = Base64UrlUnpadded::encode_string(&part);
+            tampered[i] = &part;
+            let loader = HubKeyLoader::new(device_key.clone(), tampered.join("."));
+            assert!(loader.load(key_id).is_err(), "tampered part {i}");
+        }
+        assert!(HubKeyLoader::new(device_key, "not.a.jwe")
+            .load(key_id)
+            .is_err());
+    }
+
+    #[test]
+    fn open_hub_vault_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape::default();
+        let vault = testing::create_vault(dir.path(), &shape).unwrap();
+        let config_path = dir.path().join("vault.cryptomator");
+
+        // Turn it into a Hub vault, whose key ID points to the Hub server
+        let mut header = vault.config().header.clone();
+        header.kid = Some("hub+https://hub.example.com/api/vaults/3e0a5ea8".to_string());
+        let jwt =
+            util::sign_jwt(header, &vault.config().claims, &vault.master_key().unwrap()).unwrap();
+        fs::write(&config_path, jwt).unwrap();
+        fs::remove_file(dir.path().join("masterkey.cryptomator")).unwrap();
+
+        let device_key = SecretKey::from_slice(&[7; 48]).unwrap();
+        let ephemeral_key = SecretKey::from_slice(&[9; 48]).unwrap();
+        let jwe = encrypt_for_device(
+            &vault.master_key().unwrap(),
+            &device_key.public_key(),
+            &ephemeral_key,
+            [3; 12],
+        );
+        let loader = HubKeyLoader::new(device_key, jwe);
+        let hub_vault = Vault::open_with_loader(&config_path, &loader).unwrap();
+        assert_eq!(
+            *hub_vault.master_key().unwrap(),
+            *vault.master_key().unwrap()
+        );
+
+        // Files can be read with the key from the JWE
+        let fs = EncryptedFileSystem::new(&hub_vault);
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        let ciphertext_path = fs.ciphertext_path(shape.file_path("/", 0)).unwrap();
+        EncryptedFile::open(hub_vault.cryptor(), ciphertext_path, options)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, shape.file_contents(0));
+    }
+}
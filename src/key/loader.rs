@@ -0,0 +1,146 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use color_eyre::eyre::bail;
+use jsonwebtoken::DecodingKey;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{
+    key::SUBKEY_LEN, util, vault, MasterKey, Passphrase, Result, VaultOpenError, WrappedKey,
+};
+
+const MASTERKEY_FILE_SCHEME: &str = "masterkeyfile:";
+
+/// A source of master keys for opening vaults.
+pub trait MasterkeyLoader {
+    /// Whether this loader can load the key with the provided ID, which is the `kid` header of
+    /// the vault config.
+    fn supports(&self, key_id: &str) -> bool;
+
+    /// Load the master key with the provided ID.
+    fn load(&self, key_id: &str) -> Result<MasterKey>;
+
+    /// The public key that the vault config is signed with, if it's signed using ES256 or ES384
+    /// rather than the master key. Configs of Cryptomator Hub vaults are signed this way.
+    fn config_public_key(&self, _key_id: &str) -> Result<Option<DecodingKey>> {
+        Ok(None)
+    }
+}
+
+/// Loads a master key from a password-protected masterkey file, resolving `masterkeyfile:` key
+/// IDs relative to the vault directory.
+///
+/// If the masterkey file is corrupt, any backups of it (`<file name>.<hash>.bkup`) are tried
+/// instead, most recent first.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct MasterkeyFileLoader {
+    #[zeroize(skip)]
+    vault_path: PathBuf,
+    password: Passphrase,
+    #[zeroize(skip)]
+    backup_used: Mutex<Option<PathBuf>>,
+}
+
+impl MasterkeyFileLoader {
+    pub fn new(vault_path: impl Into<PathBuf>, password: impl Into<Passphrase>) -> Self {
+        Self {
+            vault_path: vault_path.into(),
+            password: password.into(),
+            backup_used: Default::default(),
+        }
+    }
+
+    /// The backup file that the most recently loaded key came from, if the primary masterkey
+    /// file was corrupt.
+    pub fn backup_used(&self) -> Option<PathBuf> {
+        self.backup_used.lock().unwrap().clone()
+    }
+
+    fn load_file(&self, key_path: &Path) -> Result<MasterKey> {
+        let wrapped_key = WrappedKey::from_file(key_path)?;
+        let kek = util::derive_kek(&self.password, wrapped_key.params(), wrapped_key.salt())?;
+        // Key unwrapping only fails if the integrity check fails, i.e. the KEK is wrong
+        MasterKey::from_wrapped(&wrapped_key, &kek)
+            .map_err(|_| VaultOpenError::WrongPassword.into())
+    }
+}
+
+impl MasterkeyLoader for MasterkeyFileLoader {
+    fn supports(&self, key_id: &str) -> bool {
+        key_id.starts_with(MASTERKEY_FILE_SCHEME)
+    }
+
+    fn load(&self, key_id: &str) -> Result<MasterKey> {
+        let Some(file_name) = key_id.strip_prefix(MASTERKEY_FILE_SCHEME) else {
+            bail!("unsupported key URI format: {key_id}");
+        };
+
+        let key_path = self.vault_path.join(file_name);
+        if !key_path.try_exists()? {
+            return Err(VaultOpenError::MissingMasterkeyFile(key_path).into());
+        }
+
+        *self.backup_used.lock().unwrap() = None;
+        let err = match self.load_file(&key_path) {
+            Ok(key) => return Ok(key),
+            Err(err) if matches!(err.downcast_ref(), Some(VaultOpenError::WrongPassword)) => {
+                return Err(err)
+            }
+            Err(err) => err,
+        };
+
+        // The masterkey file is corrupt, so try the backups. If any of them can be parsed but the
+        // password is wrong, report that instead of the original error.
+        let mut wrong_password = false;
+        for backup_path in vault::find_backups(&key_path)? {
+            match self.load_file(&backup_path) {
+                Ok(key) => {
+                    tracing::warn!(
+                        "{} is corrupt ({err}), using backup {}",
+                        key_path.display(),
+                        backup_path.display()
+                    );
+                    *self.backup_used.lock().unwrap() = Some(backup_path);
+                    return Ok(key);
+                }
+                Err(err) => {
+                    wrong_password |=
+                        matches!(err.downcast_ref(), Some(VaultOpenError::WrongPassword))
+                }
+            }
+        }
+
+        if wrong_password {
+            Err(VaultOpenError::WrongPassword.into())
+        } else {
+            Err(err)
+        }
+    }
+}
+
+/// Provides an already-known master key, regardless of the key ID.
+#[derive(Debug)]
+pub struct RawKeyLoader(MasterKey);
+
+impl RawKeyLoader {
+    /// Create a [`RawKeyLoader`] from the raw bytes of a master key.
+    ///
+    /// # Safety
+    ///
+    /// - `bytes` should contain secret, random bytes with sufficient entropy
+    pub unsafe fn new(bytes: [u8; SUBKEY_LEN * 2]) -> Self {
+        Self(MasterKey::from_raw_unchecked(Zeroizing::new(bytes)))
+    }
+}
+
+impl MasterkeyLoader for RawKeyLoader {
+    fn supports(&self, _key_id: &str) -> bool {
+        true
+    }
+
+    fn load(&self, _key_id: &str) -> Result<MasterKey> {
+        Ok(self.0.clone())
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    fmt::Debug,
+    io::{self, Read},
+};
+
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// A vault password, which is wiped from memory when dropped.
+///
+/// Passwords can be converted from strings and byte vectors without copying, so a `String` can be
+/// passed anywhere a passphrase is expected.
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct Passphrase(Vec<u8>);
+
+impl Passphrase {
+    /// Read a passphrase from `reader` up to the first newline or EOF, e.g. from a pipe or file
+    /// descriptor. A trailing carriage return is removed.
+    pub fn read_from(mut reader: impl Read) -> io::Result<Self> {
+        let mut passphrase = Self(Vec::with_capacity(64));
+        let mut byte = Zeroizing::new([0_u8; 1]);
+
+        loop {
+            match reader.read(byte.as_mut()) {
+                Ok(0) => break,
+                Ok(_) if byte[0] == b'\n' => break,
+                Ok(_) => {
+                    let bytes = &mut passphrase.0;
+                    if bytes.len() == bytes.capacity() {
+                        // Grow the buffer ourselves, so the old one is wiped rather than freed
+                        let mut grown = Vec::with_capacity(bytes.capacity() * 2);
+                        grown.extend_from_slice(bytes);
+                        bytes.zeroize();
+                        *bytes = grown;
+                    }
+                    bytes.push(byte[0]);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        if passphrase.0.last() == Some(&b'\r') {
+            passphrase.0.pop();
+        }
+
+        Ok(passphrase)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Passphrase {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(password: String) -> Self {
+        Self(password.into_bytes())
+    }
+}
+
+impl From<&str> for Passphrase {
+    fn from(password: &str) -> Self {
+        Self(password.as_bytes().to_vec())
+    }
+}
+
+impl From<Zeroizing<String>> for Passphrase {
+    fn from(password: Zeroizing<String>) -> Self {
+        Self::from(password.as_str())
+    }
+}
+
+impl Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Passphrase")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passphrase_test() {
+        fn assert_zeroize_on_drop<T: ZeroizeOnDrop>() {}
+        assert_zeroize_on_drop::<Passphrase>();
+
+        assert_eq!(Passphrase::from("password").as_bytes(), b"password");
+        assert_eq!(
+            Passphrase::from(String::from("password")),
+            Passphrase::from(b"password".to_vec())
+        );
+
+        let long = "a".repeat(1000);
+        let input = format!("{long}\r\nignored");
+        let passphrase = Passphrase::read_from(input.as_bytes()).unwrap();
+        assert_eq!(passphrase.as_bytes(), long.as_bytes());
+        assert_eq!(
+            Passphrase::read_from(&b"password"[..]).unwrap().as_bytes(),
+            b"password"
+        );
+
+        let mut passphrase = Passphrase::from("password");
+        passphrase.zeroize();
+        assert!(passphrase.as_bytes().is_empty());
+    }
+}
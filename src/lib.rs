@@ -1,12 +1,29 @@
 pub mod crypto;
 pub mod fs;
+pub mod fsck;
 mod key;
+pub mod migration;
+mod recovery_key;
+mod single_file;
+pub mod storage;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod util;
 mod vault;
 
 pub use self::{
-    key::{MasterKey, WrappedKey},
-    vault::{CipherCombo, Vault, VaultConfig},
+    key::{
+        KdfParams, MasterKey, MasterkeyFileLoader, MasterkeyLoader, Passphrase, RawKeyLoader,
+        WrappedKey,
+    },
+    recovery_key::{RecoveryKey, WordList},
+    single_file::{decrypt_file, encrypt_file, FileCryptError, FileKey},
+    vault::{
+        CipherCombo, Vault, VaultConfig, VaultCreateOptions, VaultKind, VaultLocked, VaultOpenError,
+    },
 };
 
-pub type Result<T> = color_eyre::Result<T>;
+#[cfg(feature = "hub")]
+pub use self::key::HubKeyLoader;
+
+pub type Result<T, E = color_eyre::Report> = color_eyre::Result<T, E>;
@@ -1,11 +1,392 @@
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{Args, Parser, Subcommand};
+use color_eyre::eyre::{bail, WrapErr};
 use cryptomator::{
     fs::{fuse::FuseFileSystem, EncryptedFileSystem},
-    Result, Vault,
+    CipherCombo, FileCryptError, FileKey, Passphrase, Result, Vault, VaultCreateOptions,
+    VaultOpenError,
 };
-use fuser::MountOption;
+use fuser::{MountOption, Session};
+#[cfg(not(target_os = "macos"))]
+use libc::daemon;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use zeroize::Zeroizing;
+
+const CONFIG_FILE_NAME: &str = "vault.cryptomator";
+
+// Distinct from the exit code for other errors, so scripts can ask for the password again
+const WRONG_PASSWORD_EXIT_CODE: u8 = 3;
+
+// libc leaves it out on macOS, where it's deprecated, but it still works before any threads are
+// started
+#[cfg(target_os = "macos")]
+extern "C" {
+    fn daemon(nochdir: libc::c_int, noclose: libc::c_int) -> libc::c_int;
+}
+
+/// Create, unlock, and mount Cryptomator vaults.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Mount a vault with FUSE until it is unmounted or the process is interrupted.
+    Mount {
+        /// The vault directory, or its vault.cryptomator file.
+        vault: PathBuf,
+        mountpoint: PathBuf,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Mount options, like those of mount(8). May be repeated or comma-separated.
+        #[arg(short = 'o', value_delimiter = ',')]
+        options: Vec<String>,
+        /// Stay in the foreground instead of detaching from the terminal.
+        #[arg(short, long)]
+        foreground: bool,
+        /// Include cleartext paths and names in logs, which are redacted otherwise.
+        #[arg(long)]
+        trace_paths: bool,
+        /// Copy files when asked to hard link them, since vaults can't store hard links.
+        #[arg(long)]
+        emulate_hard_links: bool,
+        /// Hide the .DS_Store and ._ files that macOS leaves behind, and refuse to create them.
+        #[arg(long)]
+        hide_apple_double: bool,
+        /// Keep directory IDs in this encrypted file between mounts, so large vaults are faster
+        /// to use right after mounting. It should be outside the vault.
+        #[arg(long, value_name = "FILE")]
+        dir_cache_file: Option<PathBuf>,
+        /// Mount even if the vault is in use by another process, which may corrupt it.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Create a new, empty vault.
+    Create {
+        /// The directory for the vault, which is created if needed.
+        path: PathBuf,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Change the password of a vault.
+    ChangePassword {
+        /// The vault directory, or its vault.cryptomator file.
+        vault: PathBuf,
+        #[command(flatten)]
+        password: PasswordArgs,
+        /// Read the new password from the first line of this file instead of prompting for it.
+        #[arg(long, value_name = "FILE")]
+        new_password_file: Option<PathBuf>,
+    },
+    /// Print the claims of the vault config.
+    Info {
+        /// The vault directory, or its vault.cryptomator file.
+        vault: PathBuf,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Decrypt the contents of a single encrypted file, such as a .c9r file from a backup.
+    DecryptFile {
+        /// The encrypted file.
+        input: PathBuf,
+        /// Where to write the cleartext. Use `-` for standard output.
+        output: PathBuf,
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+    /// Encrypt a single file, to be decrypted with decrypt-file or put into a vault.
+    EncryptFile {
+        /// The cleartext file. Use `-` for standard input.
+        input: PathBuf,
+        /// Where to write the encrypted file.
+        output: PathBuf,
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+}
+
+#[derive(Debug, Args)]
+struct KeyArgs {
+    /// The vault directory, or its vault.cryptomator file.
+    #[arg(
+        long,
+        required_unless_present = "masterkey",
+        conflicts_with = "masterkey"
+    )]
+    vault: Option<PathBuf>,
+    /// Use this masterkey.cryptomator file instead of a vault.
+    #[arg(long, value_name = "FILE", requires = "cipher_combo")]
+    masterkey: Option<PathBuf>,
+    /// The cipher combo of the masterkey file's vault, SIV_GCM or SIV_CTRMAC.
+    #[arg(long, value_parser = parse_cipher_combo, requires = "masterkey")]
+    cipher_combo: Option<CipherCombo>,
+    #[command(flatten)]
+    password: PasswordArgs,
+}
 
-pub fn main() -> Result<()> {
+impl KeyArgs {
+    // Run `f` with the key, opening the vault or masterkey file first.
+    fn with_key<T>(&self, f: impl FnOnce(FileKey) -> Result<T, FileCryptError>) -> Result<T> {
+        let password = self.password.read("Password: ")?;
+        match (&self.vault, &self.masterkey, self.cipher_combo) {
+            (_, Some(path), Some(cipher_combo)) => Ok(f(FileKey::MasterkeyFile {
+                path,
+                password,
+                cipher_combo,
+            })?),
+            (Some(vault), _, _) => {
+                let vault = Vault::open(config_path(vault), password)?;
+                Ok(f(FileKey::Vault(&vault))?)
+            }
+            _ => bail!("either --vault or --masterkey and --cipher-combo are required"),
+        }
+    }
+}
+
+fn parse_cipher_combo(name: &str) -> Result<CipherCombo, String> {
+    match name {
+        "SIV_GCM" => Ok(CipherCombo::SivGcm),
+        "SIV_CTRMAC" => Ok(CipherCombo::SivCtrMac),
+        _ => Err(String::from("expected SIV_GCM or SIV_CTRMAC")),
+    }
+}
+
+#[derive(Debug, Args)]
+struct PasswordArgs {
+    /// Read the password from the first line of this file instead of prompting for it. Use `-`
+    /// for standard input.
+    #[arg(long, value_name = "FILE")]
+    password_file: Option<PathBuf>,
+}
+
+impl PasswordArgs {
+    fn read(&self, prompt: &str) -> Result<Passphrase> {
+        read_password(self.password_file.as_deref(), prompt)
+    }
+}
+
+fn read_password(file: Option<&Path>, prompt: &str) -> Result<Passphrase> {
+    match file {
+        Some(path) if path == Path::new("-") => Ok(Passphrase::read_from(std::io::stdin())?),
+        Some(path) => {
+            let file =
+                File::open(path).wrap_err_with(|| format!("failed to open {}", path.display()))?;
+            Ok(Passphrase::read_from(file)?)
+        }
+        None => Ok(Zeroizing::new(rpassword::prompt_password(prompt)?).into()),
+    }
+}
+
+// Read a new password, asking twice when prompting so typos don't lock anyone out.
+fn read_new_password(file: Option<&Path>) -> Result<Passphrase> {
+    let password = read_password(file, "New password: ")?;
+    if file.is_none() && read_password(None, "Repeat new password: ")? != password {
+        bail!("passwords do not match");
+    }
+    if password.as_bytes().is_empty() {
+        bail!("password must not be empty");
+    }
+
+    Ok(password)
+}
+
+fn config_path(vault: &Path) -> PathBuf {
+    match vault.is_dir() {
+        true => vault.join(CONFIG_FILE_NAME),
+        false => vault.to_path_buf(),
+    }
+}
+
+fn open(vault: &Path, password: &PasswordArgs) -> Result<Vault> {
+    let password = password.read("Password: ")?;
+    Ok(Vault::open(config_path(vault), password)?)
+}
+
+// The mount options for `options` given on the command line, after our defaults. On macOS, the
+// volume is named `volname` in Finder unless the options say otherwise.
+fn mount_options(options: &[String], volname: &str, hide_apple_double: bool) -> Vec<MountOption> {
+    let mut mount_options = vec![
+        MountOption::FSName(String::from("cryptomator")),
+        MountOption::DefaultPermissions,
+    ];
+    if cfg!(target_os = "macos") {
+        // macFUSE treats volumes as network shares unless they're marked local
+        mount_options.push(MountOption::CUSTOM(String::from("local")));
+        if !options.iter().any(|option| option.starts_with("volname=")) {
+            mount_options.push(MountOption::CUSTOM(format!("volname={volname}")));
+        }
+        // Finder doesn't even try to create AppleDouble files then
+        if hide_apple_double {
+            mount_options.push(MountOption::CUSTOM(String::from("noappledouble")));
+        }
+    }
+
+    for option in options.iter().filter(|option| !option.is_empty()) {
+        mount_options.push(match option.as_str() {
+            "ro" => MountOption::RO,
+            "rw" => MountOption::RW,
+            "allow_other" => MountOption::AllowOther,
+            "allow_root" => MountOption::AllowRoot,
+            "auto_unmount" => MountOption::AutoUnmount,
+            "dev" => MountOption::Dev,
+            "nodev" => MountOption::NoDev,
+            "suid" => MountOption::Suid,
+            "nosuid" => MountOption::NoSuid,
+            "exec" => MountOption::Exec,
+            "noexec" => MountOption::NoExec,
+            "atime" => MountOption::Atime,
+            "noatime" => MountOption::NoAtime,
+            "dirsync" => MountOption::DirSync,
+            "sync" => MountOption::Sync,
+            "async" => MountOption::Async,
+            option => match option.split_once('=') {
+                Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+                Some(("subtype", name)) => MountOption::Subtype(name.to_string()),
+                _ => MountOption::CUSTOM(option.to_string()),
+            },
+        });
+    }
+
+    mount_options
+}
+
+#[allow(clippy::too_many_arguments)]
+fn mount(
+    vault: Vault,
+    mountpoint: &Path,
+    options: &[String],
+    foreground: bool,
+    trace_paths: bool,
+    emulate_hard_links: bool,
+    hide_apple_double: bool,
+    dir_cache_file: Option<PathBuf>,
+    force: bool,
+) -> Result<()> {
+    if !foreground {
+        // Detach before mounting, so the FUSE session threads belong to the detached process.
+        // Keep the working directory, since the mountpoint may be relative.
+        // SAFETY: no other threads have been started yet
+        if unsafe { daemon(1, 0) } != 0 {
+            return Err(std::io::Error::last_os_error()).wrap_err("failed to detach");
+        }
+    }
+
+    // The last of "ro" and "rw" wins, like with mount(8)
+    let read_only = options
+        .iter()
+        .rev()
+        .find(|option| *option == "ro" || *option == "rw")
+        .is_some_and(|option| option == "ro");
+    let mut fs = EncryptedFileSystem::new(&vault)
+        .read_only(read_only)
+        .trace_paths(trace_paths)
+        .acquire_lock(force)?;
+    if let Some(path) = dir_cache_file {
+        fs = fs.dir_cache_file(path);
+    }
+    let fs = FuseFileSystem::new(fs)
+        .emulate_hard_links(emulate_hard_links)
+        .hide_apple_double(hide_apple_double)
+        .lock_on_unmount(true);
+    let volname = vault
+        .path()
+        .file_name()
+        .map_or_else(|| "cryptomator".into(), |name| name.to_string_lossy());
+    let mount_options = mount_options(options, &volname, hide_apple_double);
+    let mut session = Session::new(fs, mountpoint, &mount_options)
+        .wrap_err_with(|| format!("failed to mount at {}", mountpoint.display()))?;
+
+    // Unmounting makes the session return, so the vault is locked on the way out
+    let mut unmounter = session.unmount_callable();
+    ctrlc::set_handler(move || {
+        if let Err(err) = unmounter.unmount() {
+            tracing::error!("failed to unmount: {err}");
+        }
+    })?;
+    session.run()?;
+
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Command::Mount {
+            vault,
+            mountpoint,
+            password,
+            options,
+            foreground,
+            trace_paths,
+            emulate_hard_links,
+            hide_apple_double,
+            dir_cache_file,
+            force,
+        } => mount(
+            open(&vault, &password)?,
+            &mountpoint,
+            &options,
+            foreground,
+            trace_paths,
+            emulate_hard_links,
+            hide_apple_double,
+            dir_cache_file,
+            force,
+        ),
+        Command::Create { path, password } => {
+            let password = read_new_password(password.password_file.as_deref())?;
+            let vault = Vault::create(&path, password, VaultCreateOptions::default())?;
+            println!("Created vault at {}", vault.path().display());
+            Ok(())
+        }
+        Command::ChangePassword {
+            vault,
+            password,
+            new_password_file,
+        } => {
+            let old_password = password.read("Current password: ")?;
+            let mut vault = Vault::open(config_path(&vault), old_password.clone())?;
+            let new_password = read_new_password(new_password_file.as_deref())?;
+            vault.change_password(old_password, new_password)?;
+            println!("Changed the password of {}", vault.path().display());
+            Ok(())
+        }
+        Command::Info { vault, password } => {
+            let vault = open(&vault, &password)?;
+            let config = vault.config();
+            println!("path: {}", vault.path().display());
+            if let Some(kid) = &config.header.kid {
+                println!("key id: {kid}");
+            }
+            println!("{}", serde_json::to_string_pretty(&config.claims)?);
+            Ok(())
+        }
+        Command::DecryptFile { input, output, key } => {
+            key.with_key(|key| match output == Path::new("-") {
+                true => cryptomator::decrypt_file(key, &input, io::stdout().lock()),
+                false => cryptomator::decrypt_file(key, &input, File::create(&output)?),
+            })?;
+            Ok(())
+        }
+        Command::EncryptFile { input, output, key } => {
+            key.with_key(|key| match input == Path::new("-") {
+                true => cryptomator::encrypt_file(key, io::stdin().lock(), &output),
+                false => cryptomator::encrypt_file(key, File::open(&input)?, &output),
+            })?;
+            Ok(())
+        }
+    }
+}
+
+pub fn main() -> Result<ExitCode> {
     color_eyre::install()?;
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::from_default_env())
@@ -18,20 +399,64 @@ pub fn main() -> Result<()> {
         .with(tracing_error::ErrorLayer::default())
         .init();
 
-    let vault = Vault::open(
-        "tests/fixtures/vault_v8_siv_ctrmac/vault.cryptomator",
-        String::from("password"),
-    )?;
-
-    fuser::mount2(
-        FuseFileSystem::new(EncryptedFileSystem::new(&vault)),
-        "example",
-        &[
-            // MountOption::RO,
-            MountOption::FSName(String::from("example-fs")),
-            MountOption::DefaultPermissions,
-        ],
-    )?;
+    match run(Cli::parse()) {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        Err(err)
+            if matches!(err.downcast_ref(), Some(VaultOpenError::WrongPassword))
+                || matches!(err.downcast_ref(), Some(FileCryptError::WrongPassword)) =>
+        {
+            eprintln!("error: wrong password");
+            Ok(ExitCode::from(WRONG_PASSWORD_EXIT_CODE))
+        }
+        Err(err) => Err(err),
+    }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_options_test() {
+        let options = |options: &[&str], hide_apple_double| {
+            let options: Vec<_> = options.iter().map(|option| option.to_string()).collect();
+            mount_options(&options, "vault", hide_apple_double)
+        };
+        let custom = |option: &str| MountOption::CUSTOM(option.to_string());
+
+        let defaults = options(&[], false);
+        assert_eq!(
+            defaults[..2],
+            [
+                MountOption::FSName("cryptomator".to_string()),
+                MountOption::DefaultPermissions
+            ]
+        );
+        let given = options(&["ro", "", "fsname=vault", "custom"], false);
+        assert_eq!(
+            given[defaults.len()..],
+            [
+                MountOption::RO,
+                MountOption::FSName("vault".to_string()),
+                custom("custom")
+            ]
+        );
+
+        // macFUSE gets a local volume named after the vault, unless it's named otherwise
+        #[cfg(target_os = "macos")]
+        {
+            assert_eq!(defaults[2..], [custom("local"), custom("volname=vault")]);
+            assert_eq!(
+                options(&["volname=Other"], true)[2..],
+                [
+                    custom("local"),
+                    custom("noappledouble"),
+                    custom("volname=Other")
+                ]
+            );
+        }
+
+        #[cfg(not(target_os = "macos"))]
+        assert_eq!(options(&[], true), defaults);
+    }
 }
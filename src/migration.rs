@@ -0,0 +1,443 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use base32ct::{Base32Upper, Encoding as Base32Encoding};
+use base64ct::{Base64Url, Encoding as _};
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use jsonwebtoken::{Algorithm, Header};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use uuid::Uuid;
+
+use crate::{
+    key::MASTERKEY_FILE_VERSION,
+    util,
+    vault::{self, CONFIG_FILE_NAME, MASTERKEY_FILE_NAME},
+    CipherCombo, MasterKey, Passphrase, Result, Vault, VaultConfig, VaultOpenError, WrappedKey,
+};
+
+const JOURNAL_FILE_NAME: &str = "migration.journal";
+// Sibling directories used while swapping a migrated directory into place
+const NEW_DIR_SUFFIX: &str = ".migrating-new";
+const OLD_DIR_SUFFIX: &str = ".migrating-old";
+
+// Format 6 prefixes for directory and symlink names, and the extension for shortened names
+const V6_DIR_PREFIX: &str = "0";
+const V6_SYMLINK_PREFIX: &str = "1S";
+const V6_SHORTENED_EXTENSION: &str = ".lng";
+// Format 7 shortens any name longer than this
+const V7_SHORTENING_THRESHOLD: u32 = 220;
+
+/// The changes made (or that would be made, for a dry run) by [`Vault::migrate`].
+#[derive(Debug, Clone, Default)]
+pub struct MigrationReport {
+    pub from_format: u32,
+    pub to_format: u32,
+    pub dry_run: bool,
+    /// Ciphertext entries that were moved to a new location, as (old path, new path) pairs.
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    /// Files that were created or rewritten.
+    pub written: Vec<PathBuf>,
+    /// Files and directories that were removed.
+    pub removed: Vec<PathBuf>,
+}
+
+// Records which directories have already been migrated from format 6 to 7, so an interrupted
+// migration can be resumed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Journal {
+    completed_dirs: BTreeSet<PathBuf>,
+}
+
+impl Journal {
+    fn load(vault_path: &Path) -> Result<Self> {
+        match fs::read(vault_path.join(JOURNAL_FILE_NAME)) {
+            Ok(json) => Ok(serde_json::from_slice(&json)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, vault_path: &Path) -> Result<()> {
+        let path = vault_path.join(JOURNAL_FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.sync_all()?;
+        Ok(fs::rename(tmp_path, path)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum V6EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+struct Migrator<'a> {
+    vault_path: &'a Path,
+    master_key: MasterKey,
+    wrapped_key: WrappedKey,
+    report: MigrationReport,
+}
+
+impl<'a> Migrator<'a> {
+    fn dry_run(&self) -> bool {
+        self.report.dry_run
+    }
+
+    // Rewrite the masterkey file with a different version, keeping the same wrapped keys.
+    fn set_masterkey_version(&mut self, version: u32) -> Result<()> {
+        let key_path = self.vault_path.join(MASTERKEY_FILE_NAME);
+        self.wrapped_key.version = version;
+        self.wrapped_key.version_mac = util::hmac(&version.to_be_bytes(), &self.master_key);
+
+        if !self.dry_run() {
+            vault::replace_file(&key_path, |tmp_path| self.wrapped_key.to_file(tmp_path))?;
+        }
+        self.report.written.push(key_path);
+        Ok(())
+    }
+
+    // Format 6 -> 7: names are encoded with base64url instead of base32 and get a .c9r extension,
+    // directories and symlinks are stored as folders containing dir.c9r and symlink.c9r, and long
+    // names are shortened to .c9s folders instead of using the m/ metadata directory.
+    fn migrate_v6_to_v7(&mut self) -> Result<()> {
+        let mut journal = Journal::load(self.vault_path)?;
+
+        let data_dir = self.vault_path.join("d");
+        for prefix in sorted_entries(&data_dir)? {
+            if !prefix.is_dir() {
+                continue;
+            }
+
+            for dir in sorted_entries(&prefix)? {
+                let name = dir.file_name().unwrap_or_default().to_string_lossy();
+                // Sidecar directories are handled along with the directory they belong to
+                if name.ends_with(NEW_DIR_SUFFIX) || name.ends_with(OLD_DIR_SUFFIX) {
+                    continue;
+                }
+
+                let relative_path = dir.strip_prefix(self.vault_path)?.to_path_buf();
+                if journal.completed_dirs.contains(&relative_path) {
+                    continue;
+                }
+
+                self.migrate_v6_dir(&dir)?;
+
+                if !self.dry_run() {
+                    journal.completed_dirs.insert(relative_path);
+                    journal.save(self.vault_path)?;
+                    fs::remove_dir_all(with_suffix(&dir, OLD_DIR_SUFFIX))?;
+                }
+            }
+        }
+
+        // Finish any directories that were interrupted after being moved out of the way
+        self.recover_interrupted_swaps(&data_dir, &mut journal)?;
+
+        let metadata_dir = self.vault_path.join("m");
+        if metadata_dir.is_dir() {
+            if !self.dry_run() {
+                fs::remove_dir_all(&metadata_dir)?;
+            }
+            self.report.removed.push(metadata_dir);
+        }
+
+        self.set_masterkey_version(7)?;
+        if !self.dry_run() {
+            let journal_path = self.vault_path.join(JOURNAL_FILE_NAME);
+            if journal_path.exists() {
+                fs::remove_file(journal_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Build the migrated directory next to the original, then swap it into place.
+    fn migrate_v6_dir(&mut self, dir: &Path) -> Result<()> {
+        let new_dir = with_suffix(dir, NEW_DIR_SUFFIX);
+        let old_dir = with_suffix(dir, OLD_DIR_SUFFIX);
+
+        if !self.dry_run() {
+            // Leftovers from an interrupted attempt at this directory
+            if new_dir.exists() {
+                fs::remove_dir_all(&new_dir)?;
+            }
+            if old_dir.exists() {
+                fs::remove_dir_all(&old_dir)?;
+            }
+            fs::create_dir(&new_dir)?;
+        }
+
+        for entry in sorted_entries(dir)? {
+            let file_name = entry.file_name().unwrap_or_default().to_string_lossy();
+            let Some((kind, encrypted_name)) = self.parse_v6_name(&file_name)? else {
+                // Not something we know how to migrate, so leave it as-is
+                if !self.dry_run() {
+                    link_or_copy(&entry, &new_dir.join(entry.file_name().unwrap()))?;
+                }
+                continue;
+            };
+
+            let name = Base64Url::encode_string(&encrypted_name) + ".c9r";
+            let (node_name, shortened) = if name.len() > V7_SHORTENING_THRESHOLD as usize {
+                let hash = Sha1::new().chain_update(&name).finalize();
+                (Base64Url::encode_string(&hash) + ".c9s", true)
+            } else {
+                (name.clone(), false)
+            };
+
+            let target = match (kind, shortened) {
+                (V6EntryKind::File, false) => dir.join(&node_name),
+                (V6EntryKind::File, true) => dir.join(&node_name).join("contents.c9r"),
+                (V6EntryKind::Directory, _) => dir.join(&node_name).join("dir.c9r"),
+                (V6EntryKind::Symlink, _) => dir.join(&node_name).join("symlink.c9r"),
+            };
+            self.report.renamed.push((entry.clone(), target));
+
+            if !self.dry_run() {
+                let node_path = new_dir.join(&node_name);
+                let staged_target = match (kind, shortened) {
+                    (V6EntryKind::File, false) => node_path,
+                    (V6EntryKind::File, true) => node_path.join("contents.c9r"),
+                    (V6EntryKind::Directory, _) => node_path.join("dir.c9r"),
+                    (V6EntryKind::Symlink, _) => node_path.join("symlink.c9r"),
+                };
+                if let Some(parent) = staged_target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                link_or_copy(&entry, &staged_target)?;
+
+                if shortened {
+                    let mut name_file =
+                        fs::File::create_new(new_dir.join(&node_name).join("name.c9s"))?;
+                    name_file.write_all(name.as_bytes())?;
+                }
+            }
+        }
+
+        if !self.dry_run() {
+            fs::rename(dir, &old_dir)?;
+            fs::rename(&new_dir, dir)?;
+        }
+
+        Ok(())
+    }
+
+    // A crash between moving a directory out of the way and moving its replacement into place
+    // leaves only the sidecar directories behind.
+    fn recover_interrupted_swaps(&mut self, data_dir: &Path, journal: &mut Journal) -> Result<()> {
+        if self.dry_run() {
+            return Ok(());
+        }
+
+        for prefix in sorted_entries(data_dir)? {
+            if !prefix.is_dir() {
+                continue;
+            }
+
+            for sidecar in sorted_entries(&prefix)? {
+                let name = sidecar.file_name().unwrap_or_default().to_string_lossy();
+                let Some(dir_name) = name.strip_suffix(NEW_DIR_SUFFIX) else {
+                    if let Some(dir_name) = name.strip_suffix(OLD_DIR_SUFFIX) {
+                        // The directory itself exists if the swap finished
+                        if prefix.join(dir_name).exists() {
+                            fs::remove_dir_all(&sidecar)?;
+                        }
+                    }
+                    continue;
+                };
+
+                let dir = prefix.join(dir_name);
+                if dir.exists() {
+                    fs::remove_dir_all(&sidecar)?;
+                } else {
+                    fs::rename(&sidecar, &dir)?;
+                    journal
+                        .completed_dirs
+                        .insert(dir.strip_prefix(self.vault_path)?.to_path_buf());
+                    journal.save(self.vault_path)?;
+                    fs::remove_dir_all(with_suffix(&dir, OLD_DIR_SUFFIX))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Parse a format 6 file name into its entry kind and encrypted name, resolving long names
+    // from the metadata directory. Returns `None` for names that aren't format 6 entries.
+    fn parse_v6_name(&self, file_name: &str) -> Result<Option<(V6EntryKind, Vec<u8>)>> {
+        let full_name = match file_name.strip_suffix(V6_SHORTENED_EXTENSION) {
+            Some(_) if file_name.len() >= 4 => {
+                let metadata_path = self
+                    .vault_path
+                    .join("m")
+                    .join(&file_name[0..2])
+                    .join(&file_name[2..4])
+                    .join(file_name);
+                fs::read_to_string(&metadata_path).wrap_err_with(|| {
+                    format!("failed to read long name {}", metadata_path.display())
+                })?
+            }
+            _ => file_name.to_string(),
+        };
+
+        let (kind, encoded) = if let Some(encoded) = full_name.strip_prefix(V6_DIR_PREFIX) {
+            (V6EntryKind::Directory, encoded)
+        } else if let Some(encoded) = full_name.strip_prefix(V6_SYMLINK_PREFIX) {
+            (V6EntryKind::Symlink, encoded)
+        } else {
+            (V6EntryKind::File, full_name.as_str())
+        };
+
+        Ok(Base32Upper::decode_vec(encoded)
+            .ok()
+            .map(|encrypted_name| (kind, encrypted_name)))
+    }
+
+    // Format 7 -> 8: the vault config moves into a signed vault.cryptomator file, and the
+    // masterkey file version becomes a fixed value.
+    fn migrate_v7_to_v8(&mut self) -> Result<()> {
+        let config_path = self.vault_path.join(CONFIG_FILE_NAME);
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(format!("masterkeyfile:{MASTERKEY_FILE_NAME}"));
+        let claims = VaultConfig {
+            jti: Uuid::new_v4(),
+            format: 8,
+            cipher_combo: CipherCombo::SivCtrMac,
+            shortening_threshold: V7_SHORTENING_THRESHOLD,
+            extra: Default::default(),
+        };
+
+        // The config is written first, so that an interrupted migration is detected as format 8
+        // with an outdated masterkey file
+        if !self.dry_run() {
+            let jwt = util::sign_jwt(header, claims, &self.master_key)?;
+            let mut file = fs::File::create_new(&config_path)?;
+            file.write_all(jwt.as_bytes())?;
+            file.sync_all()?;
+        }
+        self.report.written.push(config_path);
+
+        self.set_masterkey_version(MASTERKEY_FILE_VERSION)
+    }
+}
+
+impl Vault {
+    /// Migrate a vault in an older format to `target_format`, returning a summary of the changes.
+    ///
+    /// Vaults in formats 6 and 7 can be migrated to format 7 or 8. Format 6 vaults are migrated
+    /// one directory at a time, so an interrupted migration can safely be resumed by running it
+    /// again.
+    pub fn migrate(
+        path: impl AsRef<Path>,
+        password: impl Into<Passphrase>,
+        target_format: u32,
+    ) -> Result<MigrationReport> {
+        migrate(path.as_ref(), password.into(), target_format, false)
+    }
+
+    /// Same as [`Vault::migrate`], but only report what would change, without modifying the
+    /// vault.
+    pub fn migrate_dry_run(
+        path: impl AsRef<Path>,
+        password: impl Into<Passphrase>,
+        target_format: u32,
+    ) -> Result<MigrationReport> {
+        migrate(path.as_ref(), password.into(), target_format, true)
+    }
+}
+
+fn migrate(
+    vault_path: &Path,
+    password: Passphrase,
+    target_format: u32,
+    dry_run: bool,
+) -> Result<MigrationReport> {
+    let wrapped_key = WrappedKey::from_file(vault_path.join(MASTERKEY_FILE_NAME))?;
+    let kek = util::derive_kek(&password, wrapped_key.params(), wrapped_key.salt())?;
+    let master_key = MasterKey::from_wrapped(&wrapped_key, &kek)
+        .map_err(|_| eyre!(VaultOpenError::WrongPassword))?;
+
+    if util::hmac(&wrapped_key.version().to_be_bytes(), &master_key) != wrapped_key.version_mac() {
+        bail!("masterkey file version MAC mismatch");
+    }
+
+    let from_format = if vault_path.join(CONFIG_FILE_NAME).exists() {
+        8
+    } else {
+        wrapped_key.version()
+    };
+
+    if !(6..=8).contains(&from_format) {
+        bail!("unsupported vault format: {from_format}");
+    }
+    if !(from_format..=8).contains(&target_format) {
+        bail!("cannot migrate vault from format {from_format} to {target_format}");
+    }
+
+    let mut migrator = Migrator {
+        vault_path,
+        master_key,
+        wrapped_key,
+        report: MigrationReport {
+            from_format,
+            to_format: target_format,
+            dry_run,
+            ..Default::default()
+        },
+    };
+
+    // Finish a format 7 -> 8 migration that was interrupted after writing the config
+    if from_format == 8 && migrator.wrapped_key.version() != MASTERKEY_FILE_VERSION {
+        migrator.set_masterkey_version(MASTERKEY_FILE_VERSION)?;
+    }
+
+    if from_format < 7 && target_format >= 7 {
+        migrator.migrate_v6_to_v7()?;
+    }
+    if from_format < 8 && target_format >= 8 {
+        migrator.migrate_v7_to_v8()?;
+    }
+
+    Ok(migrator.report)
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+// Hard links keep the original file intact until the swap is complete, without copying data
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if from.is_dir() {
+        fs::create_dir(to)?;
+        for entry in sorted_entries(from)? {
+            link_or_copy(&entry, &to.join(entry.file_name().unwrap()))?;
+        }
+        return Ok(());
+    }
+
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use std::{collections::HashMap, fmt::Debug};
+
+use color_eyre::eyre::{bail, eyre};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{key::SUBKEY_LEN, MasterKey, Result};
+
+const RAW_KEY_LEN: usize = SUBKEY_LEN * 2;
+// The raw key is padded with two bytes of its CRC32 checksum, giving a multiple of 3 bytes
+const PADDED_KEY_LEN: usize = RAW_KEY_LEN + 2;
+// Each group of 3 bytes is encoded as two 12-bit word indices
+const NUM_WORDS: usize = PADDED_KEY_LEN / 3 * 2;
+const WORD_LIST_LEN: usize = 1 << 12;
+
+/// A list of 4096 words used to encode a [`RecoveryKey`] in human-readable form.
+///
+/// To produce recovery keys that are compatible with the official Cryptomator app, this must be
+/// constructed from the app's `4096words_en.txt` word list.
+pub struct WordList {
+    words: Vec<String>,
+    indices: HashMap<String, u16>,
+}
+
+impl WordList {
+    /// Parse a word list containing one word per line.
+    pub fn new(text: &str) -> Result<Self> {
+        let words: Vec<String> = text
+            .lines()
+            .map(str::trim)
+            .filter(|w| !w.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+
+        if words.len() != WORD_LIST_LEN {
+            bail!(
+                "word list must contain {WORD_LIST_LEN} words, found {}",
+                words.len()
+            );
+        }
+
+        let mut indices = HashMap::with_capacity(WORD_LIST_LEN);
+        for (i, word) in words.iter().enumerate() {
+            if indices.insert(word.clone(), i as u16).is_some() {
+                bail!("duplicate word in word list: {word}");
+            }
+        }
+
+        Ok(Self { words, indices })
+    }
+}
+
+impl Debug for WordList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WordList")
+    }
+}
+
+/// The raw master key of a vault, along with a checksum, which can be used to restore access to
+/// the vault if the password is lost.
+#[derive(PartialEq, Eq, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct RecoveryKey([u8; PADDED_KEY_LEN]);
+
+impl RecoveryKey {
+    pub fn new(key: &MasterKey) -> Self {
+        let mut bytes = [0_u8; PADDED_KEY_LEN];
+        bytes[..RAW_KEY_LEN].copy_from_slice(key.raw_key());
+        // Same as the official app: the two least significant checksum bytes, in reverse order
+        let crc = crc32fast::hash(key.raw_key()).to_le_bytes();
+        bytes[RAW_KEY_LEN] = crc[0];
+        bytes[RAW_KEY_LEN + 1] = crc[1];
+        Self(bytes)
+    }
+
+    /// Parse a recovery key from its word encoding. Words may be separated by any whitespace, and
+    /// are matched case-insensitively.
+    pub fn parse(text: &str, word_list: &WordList) -> Result<Self> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.len() != NUM_WORDS {
+            bail!(
+                "recovery key must contain {NUM_WORDS} words, found {}",
+                words.len()
+            );
+        }
+
+        let mut bytes = [0_u8; PADDED_KEY_LEN];
+        for (pair, chunk) in words.chunks_exact(2).zip(bytes.chunks_exact_mut(3)) {
+            let mut indices = [0_u16; 2];
+            for (word, index) in pair.iter().zip(indices.iter_mut()) {
+                let word = Zeroizing::new(word.to_lowercase());
+                *index = *word_list
+                    .indices
+                    .get(word.as_str())
+                    .ok_or_else(|| eyre!("recovery key contains unknown word"))?;
+            }
+
+            chunk[0] = (indices[0] >> 4) as u8;
+            chunk[1] = ((indices[0] << 4) as u8) | ((indices[1] >> 8) as u8);
+            chunk[2] = indices[1] as u8;
+        }
+
+        let key = Self(bytes);
+        if key != Self::new(&key.master_key()) {
+            bail!("recovery key checksum mismatch");
+        }
+
+        Ok(key)
+    }
+
+    /// Encode this recovery key as a space-separated list of words.
+    pub fn encode(&self, word_list: &WordList) -> Zeroizing<String> {
+        let mut encoded = Zeroizing::new(String::new());
+        for chunk in self.0.chunks_exact(3) {
+            let first = ((chunk[0] as usize) << 4) | (chunk[1] as usize >> 4);
+            let second = ((chunk[1] as usize & 0x0F) << 8) | chunk[2] as usize;
+
+            for index in [first, second] {
+                if !encoded.is_empty() {
+                    encoded.push(' ');
+                }
+                encoded.push_str(&word_list.words[index]);
+            }
+        }
+
+        encoded
+    }
+
+    pub fn master_key(&self) -> MasterKey {
+        let mut bytes = Zeroizing::new([0_u8; RAW_KEY_LEN]);
+        bytes.copy_from_slice(&self.0[..RAW_KEY_LEN]);
+        // The bytes came from an existing master key
+        MasterKey::from_raw_unchecked(bytes)
+    }
+}
+
+impl Debug for RecoveryKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RecoveryKey")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_word_list() -> WordList {
+        let text: String = (0..WORD_LIST_LEN).map(|i| format!("word{i}\n")).collect();
+        WordList::new(&text).unwrap()
+    }
+
+    #[test]
+    fn encode_and_parse_test() {
+        let word_list = test_word_list();
+        let key = MasterKey::from_raw(Zeroizing::new([0xAB; RAW_KEY_LEN])).unwrap();
+        let recovery_key = RecoveryKey::new(&key);
+        let encoded = recovery_key.encode(&word_list);
+
+        // 0xABABAB -> 0xABA, 0xBAB
+        assert!(encoded.starts_with("word2746 word2987 word2746 "));
+        assert_eq!(encoded.split(' ').count(), NUM_WORDS);
+
+        let crc = crc32fast::hash(&[0xAB; RAW_KEY_LEN]);
+        let second_to_last = (0xAB << 4) | ((crc & 0xFF) >> 4);
+        let last = ((crc & 0x0F) << 8) | ((crc >> 8) & 0xFF);
+        assert!(encoded.ends_with(&format!(" word{second_to_last} word{last}")));
+
+        let parsed = RecoveryKey::parse(&encoded, &word_list).unwrap();
+        assert_eq!(parsed, recovery_key);
+        assert_eq!(parsed.master_key(), key);
+
+        // Whitespace and casing should not matter
+        let messy = format!("  {}\n", encoded.to_uppercase().replace(' ', " \t\n "));
+        assert_eq!(
+            RecoveryKey::parse(&messy, &word_list).unwrap(),
+            recovery_key
+        );
+    }
+
+    #[test]
+    fn invalid_recovery_key_test() {
+        let word_list = test_word_list();
+        let key = MasterKey::from_raw(Zeroizing::new([7; RAW_KEY_LEN])).unwrap();
+        let encoded = RecoveryKey::new(&key).encode(&word_list);
+        let words: Vec<&str> = encoded.split(' ').collect();
+
+        // Missing word
+        assert!(RecoveryKey::parse(&words[1..].join(" "), &word_list).is_err());
+
+        // Unknown word
+        let mut unknown = words.clone();
+        unknown[3] = "unknown";
+        assert!(RecoveryKey::parse(&unknown.join(" "), &word_list).is_err());
+
+        // Checksum mismatch
+        let mut swapped = words.clone();
+        swapped.swap(0, 1);
+        assert!(RecoveryKey::parse(&swapped.join(" "), &word_list).is_err());
+    }
+
+    #[test]
+    fn word_list_test() {
+        assert!(WordList::new("one\ntwo\nthree").is_err());
+
+        let mut text: String = (0..WORD_LIST_LEN - 1)
+            .map(|i| format!("word{i}\n"))
+            .collect();
+        text.push_str("word0\n");
+        assert!(WordList::new(&text).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    crypto::{siv_ctrmac, siv_gcm, Cryptor, FileCryptor},
+    util, CipherCombo, MasterKey, MasterkeyFileLoader, MasterkeyLoader, Passphrase, Vault,
+    VaultOpenError,
+};
+
+/// The key for [`decrypt_file`] and [`encrypt_file`]. A master key alone doesn't say which cipher
+/// combo its vault uses, so that has to be given along with it.
+pub enum FileKey<'a> {
+    Vault(&'a Vault),
+    MasterKey(&'a MasterKey, CipherCombo),
+    /// A masterkey.cryptomator file, unlocked with the password. Backups next to it are tried if
+    /// it's corrupt.
+    MasterkeyFile {
+        path: &'a Path,
+        password: Passphrase,
+        cipher_combo: CipherCombo,
+    },
+}
+
+impl<'a> From<&'a Vault> for FileKey<'a> {
+    fn from(vault: &'a Vault) -> Self {
+        Self::Vault(vault)
+    }
+}
+
+/// The reasons [`decrypt_file`] and [`encrypt_file`] can fail.
+#[derive(Debug, thiserror::Error)]
+pub enum FileCryptError {
+    #[error("wrong password")]
+    WrongPassword,
+    /// The file header doesn't authenticate, so the file is from a vault with a different key or
+    /// cipher combo. A damaged header looks the same.
+    #[error("the file was not encrypted with this key and cipher combo, or its header is damaged")]
+    WrongKey,
+    /// The file is too short to hold a file header.
+    #[error("the file is truncated")]
+    Truncated,
+    /// The key is right, but a chunk of the contents doesn't authenticate. Chunks are numbered
+    /// from 0, and everything before the damaged chunk has been written to the output.
+    #[error("the file is damaged at chunk {chunk}")]
+    DamagedChunk { chunk: u64 },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl From<color_eyre::Report> for FileCryptError {
+    fn from(report: color_eyre::Report) -> Self {
+        match report.downcast::<VaultOpenError>() {
+            Ok(VaultOpenError::WrongPassword) => Self::WrongPassword,
+            Ok(err) => Self::Other(err.into()),
+            Err(report) => Self::Other(report.into()),
+        }
+    }
+}
+
+impl FileKey<'_> {
+    // Run `f` with a cryptor for the key, loading it from the masterkey file first if needed.
+    fn with_cryptor<T>(
+        &self,
+        f: impl FnOnce(Cryptor) -> Result<T, FileCryptError>,
+    ) -> Result<T, FileCryptError> {
+        let (master_key, cipher_combo) = match self {
+            FileKey::Vault(vault) => return f(vault.cryptor()),
+            FileKey::MasterKey(master_key, cipher_combo) => {
+                return f(cryptor(master_key, *cipher_combo))
+            }
+            FileKey::MasterkeyFile {
+                path,
+                password,
+                cipher_combo,
+            } => {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                let file_name = path.file_name().unwrap_or(OsStr::new(""));
+                let loader = MasterkeyFileLoader::new(dir, password.clone());
+                let key_id = format!("masterkeyfile:{}", file_name.to_string_lossy());
+                (loader.load(&key_id)?, *cipher_combo)
+            }
+        };
+
+        f(cryptor(&master_key, cipher_combo))
+    }
+}
+
+fn cryptor(master_key: &MasterKey, cipher_combo: CipherCombo) -> Cryptor<'_> {
+    match cipher_combo {
+        CipherCombo::SivCtrMac => Cryptor::SivCtrMac(siv_ctrmac::Cryptor::new(master_key)),
+        CipherCombo::SivGcm => Cryptor::SivGcm(siv_gcm::Cryptor::new(master_key)),
+    }
+}
+
+/// Decrypt a single encrypted file, such as a .c9r file taken from a backup, into `output`.
+/// Returns the cleartext length.
+///
+/// Only the file contents are decrypted. Its name can't be, without the ID of the directory it
+/// was in.
+pub fn decrypt_file<'a>(
+    key: impl Into<FileKey<'a>>,
+    ciphertext_path: impl AsRef<Path>,
+    output: impl Write,
+) -> Result<u64, FileCryptError> {
+    let mut input = File::open(ciphertext_path)?;
+    let mut output = BufWriter::new(output);
+    let cleartext_len = key.into().with_cryptor(|cryptor| {
+        let mut header = vec![0; cryptor.encrypted_header_len()];
+        if !util::try_read_exact(&mut input, &mut header)?.0 {
+            return Err(FileCryptError::Truncated);
+        }
+        let header = cryptor
+            .decrypt_header(&header)
+            .map_err(|_| FileCryptError::WrongKey)?;
+
+        let mut chunk = vec![0; cryptor.max_encrypted_chunk_len()];
+        let mut cleartext_len = 0;
+        for chunk_number in 0.. {
+            let (_, len) = util::try_read_exact(&mut input, &mut chunk)?;
+            if len == 0 {
+                break;
+            }
+
+            let cleartext = cryptor
+                .decrypt_chunk(&chunk[..len], &header, chunk_number)
+                .map_err(|_| FileCryptError::DamagedChunk {
+                    chunk: chunk_number,
+                })?;
+            output.write_all(&cleartext)?;
+            cleartext_len += cleartext.len() as u64;
+        }
+
+        Ok(cleartext_len)
+    })?;
+
+    output.flush()?;
+    Ok(cleartext_len)
+}
+
+/// Encrypt everything read from `input` into a new encrypted file at `output_path`, replacing any
+/// file there. Returns the cleartext length.
+///
+/// The file can be put into the vault under an encrypted name, or decrypted with
+/// [`decrypt_file`]. If encryption fails, the partial file is removed.
+pub fn encrypt_file<'a>(
+    key: impl Into<FileKey<'a>>,
+    mut input: impl Read,
+    output_path: impl AsRef<Path>,
+) -> Result<u64, FileCryptError> {
+    let output_path = output_path.as_ref();
+    key.into().with_cryptor(|cryptor| {
+        let file = File::create(output_path)?;
+        let result = write_encrypted(cryptor, &mut input, file);
+        if result.is_err() {
+            let _ = fs::remove_file(output_path);
+        }
+        result
+    })
+}
+
+fn write_encrypted(
+    cryptor: Cryptor,
+    mut input: impl Read,
+    file: File,
+) -> Result<u64, FileCryptError> {
+    let mut output = BufWriter::new(file);
+    let header = cryptor.new_header()?;
+    output.write_all(&cryptor.encrypt_header(&header)?)?;
+
+    let mut chunk = vec![0; cryptor.max_chunk_len()];
+    let mut cleartext_len = 0;
+    for chunk_number in 0.. {
+        let (_, len) = util::try_read_exact(&mut input, &mut chunk)?;
+        if len == 0 {
+            break;
+        }
+
+        output.write_all(&cryptor.encrypt_chunk(&chunk[..len], &header, chunk_number)?)?;
+        cleartext_len += len as u64;
+    }
+
+    output.into_inner().map_err(io::Error::from)?.sync_all()?;
+    Ok(cleartext_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KdfParams, VaultCreateOptions};
+
+    #[test]
+    fn single_file_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultCreateOptions {
+            cipher_combo: CipherCombo::SivCtrMac,
+            kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+            ..Default::default()
+        };
+        let vault = Vault::create(dir.path().join("vault"), "password", options).unwrap();
+        let masterkey_path = vault.config_dir().join("masterkey.cryptomator");
+        let masterkey_file = |password: &str, cipher_combo| FileKey::MasterkeyFile {
+            path: &masterkey_path,
+            password: password.into(),
+            cipher_combo,
+        };
+
+        // Any way of giving the key reads the same file, whatever its length
+        let master_key = vault.master_key().unwrap();
+        let path = dir.path().join("file.c9r");
+        for len in [0, 1, 32 * 1024, 100_000] {
+            let contents: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(encrypt_file(&vault, &contents[..], &path).unwrap(), len);
+            for key in [
+                FileKey::Vault(&vault),
+                FileKey::MasterKey(&master_key, CipherCombo::SivCtrMac),
+                masterkey_file("password", CipherCombo::SivCtrMac),
+            ] {
+                let mut decrypted = Vec::new();
+                assert_eq!(decrypt_file(key, &path, &mut decrypted).unwrap(), len);
+                assert_eq!(decrypted, contents);
+            }
+        }
+
+        // Each way of failing says why
+        let check = |key: FileKey, expected: &str| {
+            let err = decrypt_file(key, &path, io::sink()).unwrap_err();
+            assert_eq!(format!("{err:?}"), expected);
+        };
+        check(
+            masterkey_file("wrong", CipherCombo::SivCtrMac),
+            "WrongPassword",
+        );
+        check(
+            FileKey::MasterKey(&master_key, CipherCombo::SivGcm),
+            "WrongKey",
+        );
+        let other_key = MasterKey::random().unwrap();
+        check(
+            FileKey::MasterKey(&other_key, CipherCombo::SivCtrMac),
+            "WrongKey",
+        );
+
+        let mut ciphertext = fs::read(&path).unwrap();
+        ciphertext[88 + 32 * 1024 + 48 + 10] ^= 1;
+        fs::write(&path, &ciphertext).unwrap();
+        check(FileKey::Vault(&vault), "DamagedChunk { chunk: 1 }");
+        fs::write(&path, &ciphertext[..50]).unwrap();
+        check(FileKey::Vault(&vault), "Truncated");
+
+        // A failed encryption leaves nothing behind, but a wrong password doesn't touch the file
+        let err = encrypt_file(&vault, FailingReader, &path).unwrap_err();
+        assert!(matches!(err, FileCryptError::Io(_)));
+        assert!(!path.exists());
+        fs::write(&path, b"existing").unwrap();
+        let err = encrypt_file(
+            masterkey_file("wrong", CipherCombo::SivCtrMac),
+            &[1, 2, 3][..],
+            &path,
+        )
+        .unwrap_err();
+        assert!(matches!(err, FileCryptError::WrongPassword));
+        assert_eq!(fs::read(&path).unwrap(), b"existing");
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("failed to read"))
+        }
+    }
+}
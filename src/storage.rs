@@ -0,0 +1,680 @@
+//! Backends that hold the ciphertext of a vault.
+//!
+//! Everything an [`EncryptedFileSystem`](crate::fs::EncryptedFileSystem) reads or writes goes
+//! through the [`VaultStorage`] of its vault. Paths passed to a backend are the same absolute
+//! paths the data would have on disk, starting with [`Vault::path`](crate::Vault::path).
+
+use std::{
+    ffi::OsString,
+    fmt::Debug,
+    fs::{self, File, Permissions},
+    io::{self, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+mod memory;
+mod throttle;
+
+pub use memory::MemoryStorage;
+pub use throttle::{Throttle, ThrottleHandle, ThrottledFile, ThrottledStorage};
+
+// File type bits of `st_mode`, which libc types as the narrower `mode_t` on macOS. The casts only
+// do something there.
+#[allow(clippy::unnecessary_cast)]
+const S_IFMT: u32 = libc::S_IFMT as u32;
+#[allow(clippy::unnecessary_cast)]
+const S_IFDIR: u32 = libc::S_IFDIR as u32;
+#[allow(clippy::unnecessary_cast)]
+const S_IFREG: u32 = libc::S_IFREG as u32;
+
+/// Where the ciphertext files and directories of a vault are kept.
+pub trait VaultStorage: Debug + Send + Sync {
+    /// Open a file with the given options, creating it if they allow.
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn StorageFile>>;
+
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// The names of the entries in a directory, in no particular order.
+    fn list(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Move a file or directory, replacing `to` if it's a file or an empty directory.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove an empty directory.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()>;
+
+    fn set_times(&self, path: &Path, times: FileTimes) -> io::Result<()>;
+
+    /// Make `link` another name for the file at `original`. Backends without hard links fail
+    /// with [`io::ErrorKind::Unsupported`], and callers copy the file instead.
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let _ = (original, link);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Make sure changes to the entries of a directory survive a crash.
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let _ = path;
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        match self.metadata(path) {
+            Ok(metadata) if metadata.is_dir() => return Ok(()),
+            Ok(_) => return Err(io::ErrorKind::AlreadyExists.into()),
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            Err(_) => {}
+        }
+
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        match self.create_dir(path) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Remove a directory and everything in it.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        for name in self.list(path)? {
+            let child = path.join(name);
+            if self.metadata(&child)?.is_dir() {
+                self.remove_dir_all(&child)?;
+            } else {
+                self.remove_file(&child)?;
+            }
+        }
+
+        self.remove_dir(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|metadata| metadata.is_file())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|metadata| metadata.is_dir())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        StorageCursor::new(self.open(path, &options)?).read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Create or replace a file with the given contents.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        StorageCursor::new(self.open(path, &options)?).write_all(contents)
+    }
+
+    /// Copy the contents of a file, replacing `to` if it exists.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let contents = self.read(from)?;
+        self.write(to, &contents)?;
+        Ok(contents.len() as u64)
+    }
+}
+
+/// An open file in a [`VaultStorage`]. Reads and writes are positioned, so a file doesn't keep
+/// track of a current position.
+pub trait StorageFile: Debug + Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    fn metadata(&self) -> io::Result<Metadata>;
+
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Make the contents and metadata of the file durable, like `fsync`. Backends that are
+    /// durable anyway, or never are, don't need to do anything.
+    fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Make the contents and length of the file durable, like `fdatasync`, but maybe not other
+    /// metadata. The same as [`StorageFile::sync_all`] by default.
+    fn sync_data(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    /// Take a shared advisory lock on the file, failing with [`io::ErrorKind::WouldBlock`] if
+    /// another handle holds an exclusive lock. Backends that can't lock files don't need to.
+    fn try_lock_shared(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Take an exclusive advisory lock on the file, failing with [`io::ErrorKind::WouldBlock`]
+    /// if another handle holds any lock.
+    fn try_lock_exclusive(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads and writes a [`StorageFile`] sequentially, starting at the beginning.
+pub(crate) struct StorageCursor {
+    file: Box<dyn StorageFile>,
+    pos: u64,
+}
+
+impl StorageCursor {
+    pub(crate) fn new(file: Box<dyn StorageFile>) -> Self {
+        Self { file, pos: 0 }
+    }
+}
+
+impl Read for StorageCursor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for StorageCursor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Options for [`VaultStorage::open`], which work the same way as [`std::fs::OpenOptions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub truncate: bool,
+    pub create: bool,
+    pub create_new: bool,
+    pub mode: u32,
+    pub custom_flags: i32,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            mode: 0o666,
+            custom_flags: 0,
+        }
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenOptionsExt for OpenOptions {
+    fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    fn custom_flags(&mut self, flags: i32) -> &mut Self {
+        self.custom_flags = flags;
+        self
+    }
+}
+
+impl From<&OpenOptions> for fs::OpenOptions {
+    fn from(options: &OpenOptions) -> Self {
+        let mut std_options = fs::OpenOptions::new();
+        std_options
+            .read(options.read)
+            .write(options.write)
+            .append(options.append)
+            .truncate(options.truncate)
+            .create(options.create)
+            .create_new(options.create_new)
+            .mode(options.mode)
+            .custom_flags(options.custom_flags);
+        std_options
+    }
+}
+
+/// Timestamps to change with [`VaultStorage::set_times`], which work the same way as
+/// [`std::fs::FileTimes`].
+///
+/// Like std, [`StdStorage`] can only change creation times on macOS. Elsewhere, setting one fails
+/// with [`io::ErrorKind::Unsupported`], without changing the other times.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileTimes {
+    accessed: Option<SystemTime>,
+    modified: Option<SystemTime>,
+    created: Option<SystemTime>,
+}
+
+impl FileTimes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_accessed(mut self, accessed: SystemTime) -> Self {
+        self.accessed = Some(accessed);
+        self
+    }
+
+    pub fn set_modified(mut self, modified: SystemTime) -> Self {
+        self.modified = Some(modified);
+        self
+    }
+
+    pub fn set_created(mut self, created: SystemTime) -> Self {
+        self.created = Some(created);
+        self
+    }
+
+    pub fn accessed(&self) -> Option<SystemTime> {
+        self.accessed
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    pub fn created(&self) -> Option<SystemTime> {
+        self.created
+    }
+}
+
+impl From<FileTimes> for fs::FileTimes {
+    fn from(times: FileTimes) -> Self {
+        let mut std_times = fs::FileTimes::new();
+        if let Some(accessed) = times.accessed {
+            std_times = std_times.set_accessed(accessed);
+        }
+        if let Some(modified) = times.modified {
+            std_times = std_times.set_modified(modified);
+        }
+        #[cfg(target_os = "macos")]
+        if let Some(created) = times.created {
+            use std::os::macos::fs::FileTimesExt;
+            std_times = std_times.set_created(created);
+        }
+        std_times
+    }
+}
+
+/// Metadata of a file or directory in a [`VaultStorage`]. The accessors work the same way as
+/// those of [`std::fs::Metadata`], including [`MetadataExt`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Metadata {
+    /// The file type and permission bits, like `st_mode`.
+    pub mode: u32,
+    pub len: u64,
+    pub accessed: Option<SystemTime>,
+    pub modified: Option<SystemTime>,
+    pub created: Option<SystemTime>,
+    /// When the metadata itself last changed, like `st_ctime`.
+    pub changed: Option<SystemTime>,
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub dev: u64,
+    pub ino: u64,
+    pub rdev: u64,
+    pub blksize: u64,
+    pub blocks: u64,
+}
+
+impl Metadata {
+    /// Metadata for a new, empty file or directory with the given permissions, owned by the
+    /// current user.
+    pub fn new(is_dir: bool, permissions: u32) -> Self {
+        let file_type = match is_dir {
+            true => S_IFDIR,
+            false => S_IFREG,
+        };
+        let now = SystemTime::now();
+        // SAFETY: these calls can't fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        Self {
+            mode: file_type | (permissions & 0o7777),
+            len: 0,
+            accessed: Some(now),
+            modified: Some(now),
+            created: Some(now),
+            changed: Some(now),
+            nlink: 1,
+            uid,
+            gid,
+            dev: 0,
+            ino: 0,
+            rdev: 0,
+            blksize: 4096,
+            blocks: 0,
+        }
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        Permissions::from_mode(self.mode)
+    }
+
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        self.accessed.ok_or_else(unsupported_time)
+    }
+
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        self.modified.ok_or_else(unsupported_time)
+    }
+
+    pub fn created(&self) -> io::Result<SystemTime> {
+        self.created.ok_or_else(unsupported_time)
+    }
+}
+
+fn unsupported_time() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "timestamp not available")
+}
+
+// Split a timestamp into seconds and nanoseconds since the epoch, like the fields of `stat`.
+// Missing timestamps and ones before the epoch are reported as the epoch.
+fn split_time(time: Option<SystemTime>) -> (i64, i64) {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |since| {
+            (since.as_secs() as i64, since.subsec_nanos() as i64)
+        })
+}
+
+impl MetadataExt for Metadata {
+    fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    fn nlink(&self) -> u64 {
+        self.nlink
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    fn rdev(&self) -> u64 {
+        self.rdev
+    }
+
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn atime(&self) -> i64 {
+        split_time(self.accessed).0
+    }
+
+    fn atime_nsec(&self) -> i64 {
+        split_time(self.accessed).1
+    }
+
+    fn mtime(&self) -> i64 {
+        split_time(self.modified).0
+    }
+
+    fn mtime_nsec(&self) -> i64 {
+        split_time(self.modified).1
+    }
+
+    fn ctime(&self) -> i64 {
+        split_time(self.changed).0
+    }
+
+    fn ctime_nsec(&self) -> i64 {
+        split_time(self.changed).1
+    }
+
+    fn blksize(&self) -> u64 {
+        self.blksize
+    }
+
+    fn blocks(&self) -> u64 {
+        self.blocks
+    }
+}
+
+impl From<fs::Metadata> for Metadata {
+    fn from(metadata: fs::Metadata) -> Self {
+        Self {
+            mode: metadata.mode(),
+            len: metadata.len(),
+            accessed: metadata.accessed().ok(),
+            modified: metadata.modified().ok(),
+            created: metadata.created().ok(),
+            changed: u64::try_from(metadata.ctime())
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::new(secs, metadata.ctime_nsec() as u32)),
+            nlink: metadata.nlink(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            rdev: metadata.rdev(),
+            blksize: metadata.blksize(),
+            blocks: metadata.blocks(),
+        }
+    }
+}
+
+/// Storage in a directory on a local file system, using [`std::fs`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdStorage;
+
+impl VaultStorage for StdStorage {
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(StdFile(
+            fs::OpenOptions::from(options).open(path)?,
+        )))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        Ok(fs::metadata(path)?.into())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+        fs::set_permissions(path, permissions)
+    }
+
+    fn set_times(&self, path: &Path, times: FileTimes) -> io::Result<()> {
+        if cfg!(not(target_os = "macos")) && times.created.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "creation times can't be changed on this platform",
+            ));
+        }
+
+        File::open(path)?.set_times(times.into())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        fs::copy(from, to)
+    }
+}
+
+#[derive(Debug)]
+struct StdFile(File);
+
+impl StdFile {
+    fn flock(&self, operation: libc::c_int) -> io::Result<()> {
+        // SAFETY: the descriptor stays open for as long as self is borrowed
+        match unsafe { libc::flock(self.0.as_raw_fd(), operation | libc::LOCK_NB) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl StorageFile for StdFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.0.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.0.write_at(buf, offset)
+    }
+
+    fn metadata(&self) -> io::Result<Metadata> {
+        Ok(self.0.metadata()?.into())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+
+    fn try_lock_shared(&self) -> io::Result<()> {
+        self.flock(libc::LOCK_SH)
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<()> {
+        self.flock(libc::LOCK_EX)
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.flock(libc::LOCK_UN)
+    }
+}
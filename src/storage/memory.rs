@@ -0,0 +1,503 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{self, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::SystemTime,
+};
+
+use super::{FileTimes, Metadata, OpenOptions, StorageFile, VaultStorage, S_IFMT};
+
+// Permission bits cleared from new files and directories, like a typical umask
+const UMASK: u32 = 0o022;
+
+/// Storage that keeps everything in memory, which is useful for tests that shouldn't touch the
+/// disk. Clones share the same contents.
+///
+/// Files and directories behave like they do on a local file system, including hard links,
+/// permissions, and timestamps, but permissions aren't enforced.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    nodes: Arc<Mutex<BTreeMap<PathBuf, Arc<Mutex<Node>>>>>,
+    next_ino: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct Node {
+    metadata: Metadata,
+    data: Vec<u8>,
+}
+
+type Nodes = BTreeMap<PathBuf, Arc<Mutex<Node>>>;
+
+// Nodes only hold plain data, so a panic while one is locked can't leave it inconsistent
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl MemoryStorage {
+    /// Create empty storage, containing only the root directory `/`.
+    pub fn new() -> Self {
+        let storage = Self {
+            nodes: Default::default(),
+            next_ino: Arc::new(AtomicU64::new(1)),
+        };
+        let root = storage.node(true, 0o755);
+        lock(&storage.nodes).insert(PathBuf::from("/"), root);
+        storage
+    }
+
+    /// Copy the directory at `path` on disk into this storage, at the same absolute path. File
+    /// contents, permissions, and timestamps are copied; anything other than regular files and
+    /// directories is skipped.
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref().canonicalize()?;
+        self.create_dir_all(&path)?;
+        self.load_dir(&path)
+    }
+
+    fn load_dir(&self, dir: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                self.create_dir(&path)?;
+                self.load_dir(&path)?;
+            } else if metadata.is_file() {
+                self.write(&path, &fs::read(&path)?)?;
+                self.copy_attributes(&path, &metadata)?;
+            }
+        }
+
+        // This comes last, since adding entries changes the modification time of the directory
+        self.copy_attributes(dir, &fs::metadata(dir)?)
+    }
+
+    fn copy_attributes(&self, path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+        self.set_permissions(path, metadata.permissions())?;
+        let mut times = FileTimes::new();
+        if let Ok(accessed) = metadata.accessed() {
+            times = times.set_accessed(accessed);
+        }
+        if let Ok(modified) = metadata.modified() {
+            times = times.set_modified(modified);
+        }
+        self.set_times(path, times)
+    }
+
+    fn node(&self, is_dir: bool, permissions: u32) -> Arc<Mutex<Node>> {
+        let mut metadata = Metadata::new(is_dir, permissions & !UMASK);
+        metadata.ino = self.next_ino.fetch_add(1, Ordering::Relaxed);
+        Arc::new(Mutex::new(Node {
+            metadata,
+            data: Vec::new(),
+        }))
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn not_found() -> io::Error {
+    io::ErrorKind::NotFound.into()
+}
+
+fn get<'a>(nodes: &'a Nodes, path: &Path) -> io::Result<&'a Arc<Mutex<Node>>> {
+    nodes.get(path).ok_or_else(not_found)
+}
+
+fn is_dir(node: &Arc<Mutex<Node>>) -> bool {
+    lock(node).metadata.is_dir()
+}
+
+// The direct children of a directory, which come right after it in path order.
+fn children<'a>(nodes: &'a Nodes, path: &'a Path) -> impl Iterator<Item = &'a PathBuf> {
+    nodes
+        .range(path.to_path_buf()..)
+        .skip(1)
+        .map(|(child, _)| child)
+        .take_while(move |child| child.starts_with(path))
+        .filter(move |child| child.parent() == Some(path))
+}
+
+// Make sure a new entry at `path` has a directory to go in.
+fn check_parent(nodes: &Nodes, path: &Path) -> io::Result<()> {
+    let parent = path.parent().ok_or(io::ErrorKind::InvalidInput)?;
+    match is_dir(get(nodes, parent)?) {
+        true => Ok(()),
+        false => Err(io::ErrorKind::NotADirectory.into()),
+    }
+}
+
+// Record a change to the entries of the directory containing `path`.
+fn touch_parent(nodes: &Nodes, path: &Path) {
+    if let Some(parent) = path.parent().and_then(|parent| nodes.get(parent)) {
+        let now = SystemTime::now();
+        let mut parent = lock(parent);
+        parent.metadata.modified = Some(now);
+        parent.metadata.changed = Some(now);
+    }
+}
+
+impl VaultStorage for MemoryStorage {
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn StorageFile>> {
+        let writable = options.write || options.append;
+        let mut nodes = lock(&self.nodes);
+        let node = match nodes.get(path) {
+            Some(_) if options.create_new => return Err(io::ErrorKind::AlreadyExists.into()),
+            Some(node) if is_dir(node) => return Err(io::ErrorKind::IsADirectory.into()),
+            Some(node) => {
+                if options.truncate && writable {
+                    let mut node = lock(node);
+                    node.data.clear();
+                    let now = Some(SystemTime::now());
+                    (node.metadata.modified, node.metadata.changed) = (now, now);
+                }
+                node.clone()
+            }
+            None if (options.create || options.create_new) && writable => {
+                check_parent(&nodes, path)?;
+                let node = self.node(false, options.mode);
+                nodes.insert(path.to_path_buf(), node.clone());
+                touch_parent(&nodes, path);
+                node
+            }
+            None => return Err(not_found()),
+        };
+
+        Ok(Box::new(MemoryFile {
+            node,
+            read: options.read,
+            write: writable,
+            append: options.append,
+        }))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = lock(&self.nodes);
+        if nodes.contains_key(path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        check_parent(&nodes, path)?;
+
+        let node = self.node(true, 0o777);
+        nodes.insert(path.to_path_buf(), node);
+        touch_parent(&nodes, path);
+        Ok(())
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let nodes = lock(&self.nodes);
+        if !is_dir(get(&nodes, path)?) {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+
+        // Ok to unwrap, children always have a name
+        Ok(children(&nodes, path)
+            .map(|child| child.file_name().unwrap().to_os_string())
+            .collect())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let nodes = lock(&self.nodes);
+        let node = lock(get(&nodes, path)?);
+        let mut metadata = node.metadata.clone();
+        if metadata.is_dir() {
+            let subdirs = children(&nodes, path)
+                .filter(|child| is_dir(&nodes[*child]))
+                .count();
+            metadata.nlink = 2 + subdirs as u64;
+        } else {
+            metadata.len = node.data.len() as u64;
+            metadata.blocks = metadata.len.div_ceil(512);
+        }
+
+        Ok(metadata)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut nodes = lock(&self.nodes);
+        let from_is_dir = is_dir(get(&nodes, from)?);
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(from) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        check_parent(&nodes, to)?;
+
+        if let Some(existing) = nodes.get(to) {
+            match (from_is_dir, is_dir(existing)) {
+                (true, true) if children(&nodes, to).next().is_some() => {
+                    return Err(io::ErrorKind::DirectoryNotEmpty.into())
+                }
+                (true, false) => return Err(io::ErrorKind::NotADirectory.into()),
+                (false, true) => return Err(io::ErrorKind::IsADirectory.into()),
+                (true, true) => {}
+                (false, false) => lock(existing).metadata.nlink -= 1,
+            }
+        }
+
+        // Move the entry along with everything below it
+        let moved: Vec<PathBuf> = nodes
+            .range(from.to_path_buf()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        for old_path in moved {
+            // Ok to unwrap, these paths were just found in the map
+            let node = nodes.remove(&old_path).unwrap();
+            let new_path = to.join(old_path.strip_prefix(from).unwrap());
+            nodes.insert(new_path.components().collect(), node);
+        }
+
+        lock(&nodes[to]).metadata.changed = Some(SystemTime::now());
+        touch_parent(&nodes, from);
+        touch_parent(&nodes, to);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = lock(&self.nodes);
+        if is_dir(get(&nodes, path)?) {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+
+        // Ok to unwrap, we just checked that the node exists
+        let node = nodes.remove(path).unwrap();
+        lock(&node).metadata.nlink -= 1;
+        touch_parent(&nodes, path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = lock(&self.nodes);
+        if !is_dir(get(&nodes, path)?) {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        if children(&nodes, path).next().is_some() {
+            return Err(io::ErrorKind::DirectoryNotEmpty.into());
+        }
+
+        nodes.remove(path);
+        touch_parent(&nodes, path);
+        Ok(())
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+        let nodes = lock(&self.nodes);
+        let mut node = lock(get(&nodes, path)?);
+        node.metadata.mode = (node.metadata.mode & S_IFMT) | (permissions.mode() & 0o7777);
+        node.metadata.changed = Some(SystemTime::now());
+        Ok(())
+    }
+
+    fn set_times(&self, path: &Path, times: FileTimes) -> io::Result<()> {
+        let nodes = lock(&self.nodes);
+        let mut node = lock(get(&nodes, path)?);
+        if let Some(accessed) = times.accessed() {
+            node.metadata.accessed = Some(accessed);
+        }
+        if let Some(modified) = times.modified() {
+            node.metadata.modified = Some(modified);
+        }
+        if let Some(created) = times.created() {
+            node.metadata.created = Some(created);
+        }
+        node.metadata.changed = Some(SystemTime::now());
+        Ok(())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let mut nodes = lock(&self.nodes);
+        let node = get(&nodes, original)?.clone();
+        if is_dir(&node) {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        if nodes.contains_key(link) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        check_parent(&nodes, link)?;
+
+        {
+            let mut node = lock(&node);
+            node.metadata.nlink += 1;
+            node.metadata.changed = Some(SystemTime::now());
+        }
+        nodes.insert(link.to_path_buf(), node);
+        touch_parent(&nodes, link);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct MemoryFile {
+    node: Arc<Mutex<Node>>,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+fn bad_descriptor() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+impl StorageFile for MemoryFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if !self.read {
+            return Err(bad_descriptor());
+        }
+
+        let node = lock(&self.node);
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(node.data.len());
+        let len = buf.len().min(node.data.len() - start);
+        buf[..len].copy_from_slice(&node.data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        if !self.write {
+            return Err(bad_descriptor());
+        }
+
+        let mut node = lock(&self.node);
+        // Like pwrite on Linux, appending ignores the offset
+        let start = match self.append {
+            true => node.data.len(),
+            false => usize::try_from(offset).map_err(|_| io::ErrorKind::FileTooLarge)?,
+        };
+        let end = start
+            .checked_add(buf.len())
+            .ok_or(io::ErrorKind::FileTooLarge)?;
+        if node.data.len() < end {
+            node.data.resize(end, 0);
+        }
+        node.data[start..end].copy_from_slice(buf);
+
+        let now = Some(SystemTime::now());
+        (node.metadata.modified, node.metadata.changed) = (now, now);
+        Ok(buf.len())
+    }
+
+    fn metadata(&self) -> io::Result<Metadata> {
+        let node = lock(&self.node);
+        let mut metadata = node.metadata.clone();
+        metadata.len = node.data.len() as u64;
+        metadata.blocks = metadata.len.div_ceil(512);
+        Ok(metadata)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        if !self.write {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        let mut node = lock(&self.node);
+        let len = usize::try_from(len).map_err(|_| io::ErrorKind::FileTooLarge)?;
+        node.data.resize(len, 0);
+        let now = Some(SystemTime::now());
+        (node.metadata.modified, node.metadata.changed) = (now, now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::storage::StorageCursor;
+
+    #[test]
+    fn memory_storage_test() {
+        let storage = MemoryStorage::new();
+        let path = |p: &str| PathBuf::from(p);
+        storage.create_dir_all(&path("/a/b")).unwrap();
+        storage.write(&path("/a/b/file"), b"hello").unwrap();
+        assert_eq!(storage.read(&path("/a/b/file")).unwrap(), b"hello");
+        assert_eq!(storage.metadata(&path("/a/b/file")).unwrap().len(), 5);
+        assert_eq!(storage.metadata(&path("/a")).unwrap().nlink, 3);
+        assert_eq!(
+            storage
+                .create_dir(&path("/missing/dir"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // Open handles keep working across renames, and see writes made through other handles
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        let file = storage.open(&path("/a/b/file"), &options).unwrap();
+        storage.rename(&path("/a"), &path("/moved")).unwrap();
+        assert!(!storage.exists(&path("/a/b/file")));
+        storage.write(&path("/moved/b/file"), b"bye").unwrap();
+        let mut contents = String::new();
+        StorageCursor::new(file)
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "bye");
+
+        // Hard links share contents and count towards nlink
+        storage
+            .hard_link(&path("/moved/b/file"), &path("/moved/link"))
+            .unwrap();
+        let mut link = StorageCursor::new(storage.open(&path("/moved/link"), &options).unwrap());
+        link.write_all(b"BYE").unwrap();
+        assert_eq!(storage.read(&path("/moved/b/file")).unwrap(), b"BYE");
+        assert_eq!(storage.metadata(&path("/moved/link")).unwrap().nlink, 2);
+        let created = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        storage
+            .set_times(&path("/moved/link"), FileTimes::new().set_created(created))
+            .unwrap();
+        let metadata = storage.metadata(&path("/moved/b/file")).unwrap();
+        assert_eq!(metadata.created, Some(created));
+        storage.remove_file(&path("/moved/b/file")).unwrap();
+        assert_eq!(storage.metadata(&path("/moved/link")).unwrap().nlink, 1);
+
+        assert_eq!(
+            storage.remove_dir(&path("/moved")).unwrap_err().kind(),
+            io::ErrorKind::DirectoryNotEmpty
+        );
+        storage.remove_dir_all(&path("/moved")).unwrap();
+        assert_eq!(storage.list(&path("/")).unwrap(), Vec::<OsString>::new());
+    }
+
+    #[test]
+    fn load_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file"), b"on disk").unwrap();
+        fs::set_permissions(root.join("sub/file"), Permissions::from_mode(0o600)).unwrap();
+
+        let storage = MemoryStorage::new();
+        storage.load(dir.path()).unwrap();
+        assert_eq!(storage.read(&root.join("sub/file")).unwrap(), b"on disk");
+        for path in [root.clone(), root.join("sub"), root.join("sub/file")] {
+            let on_disk = fs::metadata(&path).unwrap();
+            let in_memory = storage.metadata(&path).unwrap();
+            assert_eq!(in_memory.permissions(), on_disk.permissions());
+            assert_eq!(in_memory.modified().unwrap(), on_disk.modified().unwrap());
+        }
+
+        // Changes stay in memory
+        storage.remove_dir_all(&root.join("sub")).unwrap();
+        assert!(root.join("sub/file").exists());
+    }
+}
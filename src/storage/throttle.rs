@@ -0,0 +1,312 @@
+use std::{
+    ffi::OsString,
+    fs::Permissions,
+    io,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{FileTimes, Metadata, OpenOptions, StorageFile, VaultStorage};
+
+// How much of a second's worth of bytes may be transferred at once after being idle
+const BURST: f64 = 0.1;
+
+/// Limits on how fast file contents are read from and written to storage, in bytes per second.
+/// `None` means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Throttle {
+    pub read_bytes_per_sec: Option<u64>,
+    pub write_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    // Goes negative when more was taken than there was, which later callers wait off
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            tokens: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    // Take `len` tokens, returning how long to wait before the bytes may be transferred.
+    fn take(&mut self, len: usize) -> Duration {
+        let Some(rate) = self.rate.filter(|&rate| rate > 0) else {
+            return Duration::ZERO;
+        };
+
+        let now = Instant::now();
+        let refilled = now.duration_since(self.updated).as_secs_f64() * rate as f64;
+        self.tokens = (self.tokens + refilled).min(rate as f64 * BURST) - len as f64;
+        self.updated = now;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Token buckets that enforce a [`Throttle`], shared by everything it applies to. The limits can
+/// be changed at any time with [`ThrottleHandle::set`], and apply to all of them together.
+#[derive(Debug)]
+pub struct ThrottleHandle {
+    read: Mutex<Bucket>,
+    write: Mutex<Bucket>,
+}
+
+impl ThrottleHandle {
+    pub fn new(throttle: Throttle) -> Arc<Self> {
+        Arc::new(Self {
+            read: Mutex::new(Bucket::new(throttle.read_bytes_per_sec)),
+            write: Mutex::new(Bucket::new(throttle.write_bytes_per_sec)),
+        })
+    }
+
+    /// Replace the limits. Transfers that are already waiting finish waiting first.
+    pub fn set(&self, throttle: Throttle) {
+        for (bucket, rate) in [
+            (&self.read, throttle.read_bytes_per_sec),
+            (&self.write, throttle.write_bytes_per_sec),
+        ] {
+            let mut bucket = bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            if bucket.rate != rate {
+                *bucket = Bucket::new(rate);
+            }
+        }
+    }
+
+    pub fn get(&self) -> Throttle {
+        let rate =
+            |bucket: &Mutex<Bucket>| bucket.lock().unwrap_or_else(PoisonError::into_inner).rate;
+        Throttle {
+            read_bytes_per_sec: rate(&self.read),
+            write_bytes_per_sec: rate(&self.write),
+        }
+    }
+
+    // Wait until `len` bytes may be transferred. The bucket is unlocked while waiting, so the
+    // limits can be changed and other transfers can queue up behind this one.
+    fn wait(bucket: &Mutex<Bucket>, len: usize) {
+        let delay = bucket
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take(len);
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Wait until `len` bytes may be read.
+    pub fn wait_read(&self, len: usize) {
+        Self::wait(&self.read, len);
+    }
+
+    /// Wait until `len` bytes may be written.
+    pub fn wait_write(&self, len: usize) {
+        Self::wait(&self.write, len);
+    }
+}
+
+/// A [`StorageFile`] whose reads and writes are limited by a [`ThrottleHandle`].
+#[derive(Debug)]
+pub struct ThrottledFile {
+    inner: Arc<dyn StorageFile>,
+    throttle: Arc<ThrottleHandle>,
+}
+
+impl ThrottledFile {
+    pub fn new(inner: impl Into<Arc<dyn StorageFile>>, throttle: Arc<ThrottleHandle>) -> Self {
+        Self {
+            inner: inner.into(),
+            throttle,
+        }
+    }
+}
+
+impl StorageFile for ThrottledFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        // Only what was actually read counts, which isn't known until afterwards
+        let n = self.inner.read_at(buf, offset)?;
+        self.throttle.wait_read(n);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.throttle.wait_write(buf.len());
+        self.inner.write_at(buf, offset)
+    }
+
+    fn metadata(&self) -> io::Result<Metadata> {
+        self.inner.metadata()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.inner.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+
+    fn try_lock_shared(&self) -> io::Result<()> {
+        self.inner.try_lock_shared()
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<()> {
+        self.inner.try_lock_exclusive()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.inner.unlock()
+    }
+}
+
+/// Storage whose file contents are read and written no faster than a [`ThrottleHandle`] allows,
+/// e.g. for a vault on a metered network mount. Only file contents count, not metadata or
+/// directory listings.
+#[derive(Debug)]
+pub struct ThrottledStorage<S> {
+    inner: S,
+    throttle: Arc<ThrottleHandle>,
+}
+
+impl<S: VaultStorage> ThrottledStorage<S> {
+    pub fn new(inner: S, throttle: Arc<ThrottleHandle>) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+// Copying isn't forwarded, so the default implementation reads and writes through open files
+impl<S: VaultStorage> VaultStorage for ThrottledStorage<S> {
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn StorageFile>> {
+        let file = self.inner.open(path, options)?;
+        Ok(Box::new(ThrottledFile::new(file, self.throttle.clone())))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        self.inner.list(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+        self.inner.set_permissions(path, permissions)
+    }
+
+    fn set_times(&self, path: &Path, times: FileTimes) -> io::Result<()> {
+        self.inner.set_times(path, times)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        self.inner.hard_link(original, link)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.sync_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir_all(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    // Transfer `total` bytes in 16 KiB pieces, returning the throughput in bytes per second.
+    fn throughput(total: usize, mut transfer: impl FnMut(&mut [u8], u64)) -> f64 {
+        let mut buf = vec![7; 16 * 1024];
+        let start = Instant::now();
+        for offset in (0..total).step_by(buf.len()) {
+            transfer(&mut buf, offset as u64);
+        }
+        total as f64 / start.elapsed().as_secs_f64()
+    }
+
+    #[test]
+    fn throttle_test() {
+        let rate = 4 * 1024 * 1024;
+        let throttle = ThrottleHandle::new(Throttle {
+            read_bytes_per_sec: Some(rate),
+            write_bytes_per_sec: Some(rate / 2),
+        });
+        let storage = ThrottledStorage::new(MemoryStorage::new(), throttle.clone());
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        let file = storage.open(Path::new("/file"), &options).unwrap();
+        let within = |throughput: f64, rate: u64| {
+            let ratio = throughput / rate as f64;
+            assert!((0.8..=1.2).contains(&ratio), "{throughput} vs {rate}");
+        };
+
+        // Sustained throughput stays near each limit
+        let write = |buf: &mut [u8], offset| {
+            file.write_at(buf, offset).unwrap();
+        };
+        let read = |buf: &mut [u8], _| {
+            file.read_at(buf, 0).unwrap();
+        };
+        within(throughput(rate as usize / 2, write), rate / 2);
+        within(throughput(rate as usize, read), rate);
+
+        // Limits can be changed while in use, and lifted
+        throttle.set(Throttle {
+            read_bytes_per_sec: Some(rate * 2),
+            ..throttle.get()
+        });
+        within(throughput(rate as usize * 2, read), rate * 2);
+        throttle.set(Throttle::default());
+        assert!(throughput(rate as usize * 2, read) > rate as f64 * 20.0);
+
+        // A transfer that's waiting doesn't hold up anything else
+        throttle.set(Throttle {
+            write_bytes_per_sec: Some(1024 * 1024),
+            ..Default::default()
+        });
+        thread::scope(|scope| {
+            scope.spawn(|| file.write_at(&vec![0; 1024 * 1024], 0).unwrap());
+            thread::sleep(Duration::from_millis(100));
+            let start = Instant::now();
+            throttle.set(Throttle::default());
+            file.read_at(&mut [0; 1024], 0).unwrap();
+            assert!(start.elapsed() < Duration::from_millis(100));
+        });
+    }
+}
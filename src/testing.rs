@@ -0,0 +1,169 @@
+//! Helpers for building vaults of a given shape, shared by tests and benchmarks. Enabled with the
+//! `testing` feature.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::eyre;
+use uuid::Uuid;
+
+use crate::{
+    fs::{EncryptedFileSystem, ImportOptions},
+    CipherCombo, KdfParams, Result, Vault, VaultCreateOptions,
+};
+
+#[cfg(feature = "test-vectors")]
+pub mod vectors;
+
+/// The password of vaults built by [`create_vault`].
+pub const PASSWORD: &str = "password";
+
+/// The layout of a vault built by [`create_vault`]. Every directory gets the same number of
+/// files and subdirectories, down to `depth` levels below the root.
+#[derive(Debug, Clone)]
+pub struct VaultShape {
+    pub cipher_combo: CipherCombo,
+    pub files_per_dir: usize,
+    pub dirs_per_dir: usize,
+    pub depth: usize,
+    pub file_len: usize,
+    /// The length of the generated cleartext names. Long enough names are shortened in the
+    /// ciphertext, which is slower to list.
+    pub name_len: usize,
+}
+
+impl Default for VaultShape {
+    fn default() -> Self {
+        Self {
+            cipher_combo: CipherCombo::SivGcm,
+            files_per_dir: 10,
+            dirs_per_dir: 0,
+            depth: 0,
+            file_len: 1024,
+            name_len: 16,
+        }
+    }
+}
+
+impl VaultShape {
+    /// The cleartext path of the `n`th file in the cleartext directory `dir`.
+    pub fn file_path(&self, dir: impl AsRef<Path>, n: usize) -> PathBuf {
+        dir.as_ref().join(name("file", n, self.name_len))
+    }
+
+    /// The cleartext path of the `n`th subdirectory of the cleartext directory `dir`.
+    pub fn dir_path(&self, dir: impl AsRef<Path>, n: usize) -> PathBuf {
+        dir.as_ref().join(name("dir", n, self.name_len))
+    }
+
+    /// The cleartext contents of the `n`th file in every directory.
+    pub fn file_contents(&self, n: usize) -> Vec<u8> {
+        (0..self.file_len).map(|i| (n + i) as u8).collect()
+    }
+
+    fn write_plaintext(&self, dir: &Path, depth: usize) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        for n in 0..self.files_per_dir {
+            fs::write(self.file_path(dir, n), self.file_contents(n))?;
+        }
+        if depth < self.depth {
+            for n in 0..self.dirs_per_dir {
+                self.write_plaintext(&self.dir_path(dir, n), depth + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Pad `prefix-n` with `x` up to `len` characters.
+fn name(prefix: &str, n: usize, len: usize) -> String {
+    format!("{prefix}-{n:x<0$}", len.saturating_sub(prefix.len() + 1))
+}
+
+/// Create a vault at `path` with the password [`PASSWORD`], filled with directories and files
+/// according to `shape`. Key derivation uses the cheapest allowed parameters, so this is only
+/// meant for vaults that are thrown away.
+pub fn create_vault(path: impl AsRef<Path>, shape: &VaultShape) -> Result<Vault> {
+    let options = VaultCreateOptions {
+        cipher_combo: shape.cipher_combo,
+        kdf_params: KdfParams::new(KdfParams::MIN_COST, 8, 1)?,
+        ..Default::default()
+    };
+    let vault = Vault::create(path, PASSWORD, options)?;
+
+    // The contents are written to a plaintext directory first, then imported all at once
+    let plaintext_dir = std::env::temp_dir().join(format!("cryptomator-{}", Uuid::new_v4()));
+    let result = shape.write_plaintext(&plaintext_dir, 0).and_then(|_| {
+        let report = EncryptedFileSystem::new(&vault).import_dir(
+            &plaintext_dir,
+            "/",
+            ImportOptions::default(),
+        )?;
+        match report.errors.first() {
+            Some((path, err)) => Err(eyre!("{}: {err}", path.display())),
+            None => Ok(()),
+        }
+    });
+    fs::remove_dir_all(&plaintext_dir)?;
+    result?;
+
+    Ok(vault)
+}
+
+/// Recursively copy the directory `src` to `dst`, such as a fixture vault that a test is about to
+/// modify.
+pub fn copy_dir(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    fs::create_dir_all(&dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dst = dst.as_ref().join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(entry.path(), dst)?;
+        } else {
+            fs::copy(entry.path(), dst)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::storage::OpenOptions;
+
+    #[test]
+    fn create_vault_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let shape = VaultShape {
+            files_per_dir: 3,
+            dirs_per_dir: 2,
+            depth: 2,
+            file_len: 40_000,
+            name_len: 200,
+            ..Default::default()
+        };
+        let vault = create_vault(dir.path().join("vault"), &shape).unwrap();
+
+        // 7 directories with 3 files each, and the 6 directories below the root
+        let fs = EncryptedFileSystem::new(&vault);
+        assert_eq!(fs.walk("/").count(), 27);
+        assert_eq!(name("file", 3, 10), "file-3xxxx");
+        assert_eq!(name("file", 3, 0), "file-3");
+
+        let path = shape.file_path(shape.dir_path(shape.dir_path("/", 1), 0), 2);
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        fs.open_ciphertext(fs.ciphertext_path(&path).unwrap(), options)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, shape.file_contents(2));
+    }
+}
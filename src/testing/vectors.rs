@@ -0,0 +1,471 @@
+//! Known-answer test vectors for the cryptors, to check compatibility with other implementations
+//! of the vault format.
+//!
+//! A set of vectors is a JSON file per cipher combo, holding a master key and the expected
+//! ciphertext of names, directory IDs, file headers, and file chunks under that key. Binary values
+//! are standard base64. [`TestVectors::check`] asserts that our cryptors produce and accept every
+//! ciphertext. [`TestVectors::from_vault`] reads vectors from a vault written by another
+//! implementation, such as the official desktop app, and [`TestVectors::generate`] writes our own
+//! vectors for others to check against.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{bail, ensure, WrapErr};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{siv_ctrmac, siv_gcm, Cryptor, DirId, FileCryptor, FileHeader},
+    key::SUBKEY_LEN,
+    CipherCombo, MasterKey, Result, Vault,
+};
+
+/// Test vectors for one cipher combo and master key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestVectors {
+    pub cipher_combo: CipherCombo,
+    /// The raw master key: the encryption subkey followed by the MAC subkey.
+    #[serde(with = "base64")]
+    pub master_key: Vec<u8>,
+    pub names: Vec<NameVector>,
+    pub dir_ids: Vec<DirIdVector>,
+    pub headers: Vec<HeaderVector>,
+    pub chunks: Vec<ChunkVector>,
+}
+
+/// A cleartext name in the directory with ID `parent_dir_id`, and its encrypted name without the
+/// .c9r extension.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NameVector {
+    pub parent_dir_id: String,
+    pub cleartext: String,
+    pub ciphertext: String,
+}
+
+/// A directory ID, empty for the root, and the path of its directory below d/.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirIdVector {
+    pub dir_id: String,
+    pub hashed_path: String,
+}
+
+/// A file header and its encrypted form.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeaderVector {
+    #[serde(with = "base64")]
+    pub nonce: Vec<u8>,
+    /// The reserved bytes followed by the content key.
+    #[serde(with = "base64")]
+    pub payload: Vec<u8>,
+    #[serde(with = "base64")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// A cleartext chunk of the file with the given header, and its encryption with `nonce`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkVector {
+    #[serde(with = "base64")]
+    pub header_nonce: Vec<u8>,
+    #[serde(with = "base64")]
+    pub header_payload: Vec<u8>,
+    pub chunk_number: u64,
+    #[serde(with = "base64")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "base64")]
+    pub cleartext: Vec<u8>,
+    #[serde(with = "base64")]
+    pub ciphertext: Vec<u8>,
+}
+
+// Names that tend to trip up encodings: non-ASCII, spaces, and long enough to be shortened
+const NAMES: &[&str] = &[
+    "file.txt",
+    "a",
+    "with spaces and (brackets)",
+    "ünïcødé 文件 🔒",
+    "name that is long enough to be shortened to a .c9s directory when it is encrypted, since the \
+     encrypted name gets longer than the limit of the vault format",
+];
+
+// Chunk numbers past 2^32 catch chunk numbers that are truncated, as on 32-bit targets
+const CHUNK_NUMBERS: &[u64] = &[0, 1, (1 << 32) + 2];
+
+impl TestVectors {
+    /// Generate vectors for `cipher_combo` under `master_key`, with random directory IDs, headers,
+    /// and nonces.
+    pub fn generate(cipher_combo: CipherCombo, master_key: &MasterKey) -> Result<Self> {
+        let cryptor = cryptor(cipher_combo, master_key);
+        let dir_ids = [DirId::root(), DirId::new()];
+
+        let mut names = Vec::new();
+        for dir_id in &dir_ids {
+            for name in NAMES {
+                names.push(NameVector {
+                    parent_dir_id: dir_id.as_str().to_string(),
+                    cleartext: name.to_string(),
+                    ciphertext: cryptor.encrypt_name(OsStr::new(name), dir_id.as_str())?,
+                });
+            }
+        }
+
+        let header = cryptor.new_header()?;
+        let headers = vec![HeaderVector {
+            nonce: header.nonce().to_vec(),
+            payload: header.payload().to_vec(),
+            ciphertext: cryptor.encrypt_header(&header)?,
+        }];
+
+        // A full chunk as well as short ones, as found at the end of a file. Chunk nonces are as
+        // long as header nonces in both cipher combos.
+        let mut chunks = Vec::new();
+        for (i, &chunk_number) in CHUNK_NUMBERS.iter().enumerate() {
+            let len = if i == 1 { cryptor.max_chunk_len() } else { 100 };
+            let cleartext: Vec<u8> = (0..len).map(|n| (n * 7 + i) as u8).collect();
+            let mut nonce = vec![0; header.nonce().len()];
+            OsRng.try_fill_bytes(&mut nonce)?;
+            chunks.push(ChunkVector {
+                header_nonce: header.nonce().to_vec(),
+                header_payload: header.payload().to_vec(),
+                chunk_number,
+                ciphertext: cryptor.encrypt_chunk_with_nonce(
+                    &nonce,
+                    &cleartext,
+                    &header,
+                    chunk_number,
+                )?,
+                nonce,
+                cleartext,
+            });
+        }
+
+        Ok(Self {
+            cipher_combo,
+            master_key: master_key.raw_key().to_vec(),
+            names,
+            dir_ids: dir_ids
+                .iter()
+                .map(|dir_id| {
+                    Ok(DirIdVector {
+                        dir_id: dir_id.as_str().to_string(),
+                        hashed_path: cryptor.hash_dir_id(dir_id)?.to_string_lossy().into(),
+                    })
+                })
+                .collect::<Result<_>>()?,
+            headers,
+            chunks,
+        })
+    }
+
+    /// Read vectors from the encrypted data of `vault`, taking every ciphertext as is: the names
+    /// and directories, and the header along with the first and last chunks of every file. The
+    /// chunk nonces are the ones stored with the chunks.
+    pub fn from_vault(vault: &Vault) -> Result<Self> {
+        let cryptor = vault.cryptor();
+        let mut vectors = Self {
+            cipher_combo: vault.config().claims.cipher_combo,
+            master_key: vault.master_key()?.raw_key().to_vec(),
+            names: Vec::new(),
+            dir_ids: Vec::new(),
+            headers: Vec::new(),
+            chunks: Vec::new(),
+        };
+
+        let mut dir_ids = vec![DirId::root()];
+        while let Some(dir_id) = dir_ids.pop() {
+            let hashed_path = cryptor.hash_dir_id(&dir_id)?;
+            let hashed_dir_path = vault.path().join("d").join(&hashed_path);
+            vectors.dir_ids.push(DirIdVector {
+                dir_id: dir_id.as_str().to_string(),
+                hashed_path: hashed_path.to_string_lossy().into(),
+            });
+
+            let mut file_names = vault.storage().list(&hashed_dir_path)?;
+            file_names.sort();
+            for file_name in file_names {
+                let path = hashed_dir_path.join(&file_name);
+                // The backup of the directory ID is encrypted like file contents
+                if file_name == "dirid.c9r" {
+                    vectors.add_file(&cryptor, vault.storage().read(&path)?)?;
+                    continue;
+                }
+
+                let full_name = match file_name.to_str() {
+                    Some(name) if name.ends_with(".c9s") => {
+                        vault.storage().read_to_string(&path.join("name.c9s"))?
+                    }
+                    Some(name) if name.ends_with(".c9r") => name.to_string(),
+                    _ => bail!("unexpected ciphertext entry: {}", path.display()),
+                };
+                // Ok to unwrap, shortened names are always of .c9r names
+                let ciphertext = full_name.strip_suffix(".c9r").unwrap().to_string();
+                vectors.names.push(NameVector {
+                    parent_dir_id: dir_id.as_str().to_string(),
+                    cleartext: cryptor.decrypt_name(&ciphertext, dir_id.as_str())?,
+                    ciphertext,
+                });
+
+                if vault.storage().is_file(&path) {
+                    vectors.add_file(&cryptor, vault.storage().read(&path)?)?;
+                } else if vault.storage().exists(&path.join("dir.c9r")) {
+                    let dir_id = vault.storage().read_to_string(&path.join("dir.c9r"))?;
+                    dir_ids.push(DirId::parse(&dir_id)?);
+                } else {
+                    // Symlink targets are encrypted like file contents
+                    for contents in ["contents.c9r", "symlink.c9r"] {
+                        if vault.storage().exists(&path.join(contents)) {
+                            let contents = vault.storage().read(&path.join(contents))?;
+                            vectors.add_file(&cryptor, contents)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(vectors)
+    }
+
+    // Add the header and the first and last chunks of an encrypted file.
+    fn add_file(&mut self, cryptor: &Cryptor, contents: Vec<u8>) -> Result<()> {
+        ensure!(
+            contents.len() >= cryptor.encrypted_header_len(),
+            "encrypted file is too short for its header"
+        );
+        let (encrypted_header, encrypted_chunks) =
+            contents.split_at(cryptor.encrypted_header_len());
+        let header = cryptor.decrypt_header(encrypted_header)?;
+        self.headers.push(HeaderVector {
+            nonce: header.nonce().to_vec(),
+            payload: header.payload().to_vec(),
+            ciphertext: encrypted_header.to_vec(),
+        });
+
+        let encrypted_chunks: Vec<&[u8]> = encrypted_chunks
+            .chunks(cryptor.max_encrypted_chunk_len())
+            .collect();
+        let last = encrypted_chunks.len().saturating_sub(1);
+        for (chunk_number, encrypted_chunk) in encrypted_chunks.into_iter().enumerate() {
+            if chunk_number != 0 && chunk_number != last {
+                continue;
+            }
+            // Chunk nonces are as long as header nonces in both cipher combos
+            ensure!(
+                encrypted_chunk.len() >= header.nonce().len(),
+                "encrypted chunk is too short for its nonce"
+            );
+            let chunk_number = chunk_number as u64;
+            self.chunks.push(ChunkVector {
+                header_nonce: header.nonce().to_vec(),
+                header_payload: header.payload().to_vec(),
+                chunk_number,
+                nonce: encrypted_chunk[..header.nonce().len()].to_vec(),
+                cleartext: cryptor.decrypt_chunk(encrypted_chunk, &header, chunk_number)?,
+                ciphertext: encrypted_chunk.to_vec(),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&contents).wrap_err_with(|| format!("parsing {}", path.display()))
+    }
+
+    /// Load every .json file in `dir`, sorted by path.
+    pub fn load_dir(dir: impl AsRef<Path>) -> Result<Vec<(PathBuf, Self)>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new("json")) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        paths
+            .into_iter()
+            .map(|path| Ok((path.clone(), Self::load(path)?)))
+            .collect()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut json = serde_json::to_vec_pretty(self)?;
+        json.push(b'\n');
+        Ok(fs::write(path, json)?)
+    }
+
+    /// Check that our cryptor for the cipher combo turns every cleartext into the expected
+    /// ciphertext and back, and rejects chunks under the wrong chunk number. Fails on the first
+    /// vector that doesn't match.
+    pub fn check(&self) -> Result<()> {
+        let Ok(raw_key) = <[u8; SUBKEY_LEN * 2]>::try_from(self.master_key.as_slice()) else {
+            bail!("invalid master key length: {}", self.master_key.len());
+        };
+        let master_key = MasterKey::from_raw(Zeroizing::new(raw_key))?;
+        let cryptor = cryptor(self.cipher_combo, &master_key);
+
+        for (i, vector) in self.names.iter().enumerate() {
+            let ciphertext =
+                cryptor.encrypt_name(OsStr::new(&vector.cleartext), &vector.parent_dir_id)?;
+            ensure!(
+                ciphertext == vector.ciphertext,
+                "name {i}: encrypted {:?} to {ciphertext}, expected {}",
+                vector.cleartext,
+                vector.ciphertext
+            );
+            let cleartext = cryptor.decrypt_name(&vector.ciphertext, &vector.parent_dir_id)?;
+            ensure!(
+                cleartext == vector.cleartext,
+                "name {i}: decrypted to {cleartext:?}"
+            );
+        }
+
+        for (i, vector) in self.dir_ids.iter().enumerate() {
+            let dir_id = match vector.dir_id.as_str() {
+                "" => DirId::root(),
+                dir_id => DirId::parse(dir_id)?,
+            };
+            let hashed_path = cryptor.hash_dir_id(&dir_id)?;
+            ensure!(
+                hashed_path == Path::new(&vector.hashed_path),
+                "directory ID {i}: hashed to {}, expected {}",
+                hashed_path.display(),
+                vector.hashed_path
+            );
+        }
+
+        for (i, vector) in self.headers.iter().enumerate() {
+            let header = header(&cryptor, &vector.nonce, &vector.payload)
+                .wrap_err_with(|| format!("header {i}"))?;
+            let ciphertext = cryptor.encrypt_header(&header)?;
+            ensure!(
+                ciphertext == vector.ciphertext,
+                "header {i}: encrypted to something other than expected"
+            );
+            let decrypted = cryptor
+                .decrypt_header(&vector.ciphertext)
+                .wrap_err_with(|| format!("header {i}"))?;
+            ensure!(
+                decrypted == header,
+                "header {i}: decrypted to a different header"
+            );
+        }
+
+        for (i, vector) in self.chunks.iter().enumerate() {
+            let header = header(&cryptor, &vector.header_nonce, &vector.header_payload)
+                .wrap_err_with(|| format!("chunk {i}"))?;
+            let ciphertext = cryptor
+                .encrypt_chunk_with_nonce(
+                    &vector.nonce,
+                    &vector.cleartext,
+                    &header,
+                    vector.chunk_number,
+                )
+                .wrap_err_with(|| format!("chunk {i}"))?;
+            ensure!(
+                ciphertext == vector.ciphertext,
+                "chunk {i}: encrypted to something other than expected"
+            );
+            let cleartext = cryptor
+                .decrypt_chunk(&vector.ciphertext, &header, vector.chunk_number)
+                .wrap_err_with(|| format!("chunk {i}"))?;
+            ensure!(
+                cleartext == vector.cleartext,
+                "chunk {i}: decrypted to something other than expected"
+            );
+            ensure!(
+                cryptor
+                    .decrypt_chunk(&vector.ciphertext, &header, vector.chunk_number ^ 1)
+                    .is_err(),
+                "chunk {i}: decrypted with the wrong chunk number"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn cryptor(cipher_combo: CipherCombo, master_key: &MasterKey) -> Cryptor<'_> {
+    match cipher_combo {
+        CipherCombo::SivCtrMac => Cryptor::SivCtrMac(siv_ctrmac::Cryptor::new(master_key)),
+        CipherCombo::SivGcm => Cryptor::SivGcm(siv_gcm::Cryptor::new(master_key)),
+    }
+}
+
+// A header from a vector, checking the lengths first since the cryptors expect them to be right.
+fn header(cryptor: &Cryptor, nonce: &[u8], payload: &[u8]) -> Result<FileHeader> {
+    let template = cryptor.new_header()?;
+    ensure!(
+        nonce.len() == template.nonce().len(),
+        "invalid header nonce length: {}",
+        nonce.len()
+    );
+    ensure!(
+        payload.len() == template.payload().len(),
+        "invalid header payload length: {}",
+        payload.len()
+    );
+
+    Ok(FileHeader::from_parts(nonce.to_vec(), payload.to_vec()))
+}
+
+// Binary values as base64 strings
+mod base64 {
+    use base64ct::{Base64, Encoding};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&Base64::encode_string(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        Base64::decode_vec(&string).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let master_key = MasterKey::random().unwrap();
+        for cipher_combo in [CipherCombo::SivCtrMac, CipherCombo::SivGcm] {
+            let vectors = TestVectors::generate(cipher_combo, &master_key).unwrap();
+            vectors.check().unwrap();
+
+            let path = dir.path().join(format!("{cipher_combo:?}.json"));
+            vectors.save(&path).unwrap();
+            assert_eq!(TestVectors::load(&path).unwrap(), vectors);
+        }
+        assert_eq!(TestVectors::load_dir(dir.path()).unwrap().len(), 2);
+
+        // Any change to a ciphertext is noticed
+        let vectors = TestVectors::generate(CipherCombo::SivGcm, &master_key).unwrap();
+        let mut wrong = vectors.clone();
+        wrong.names[0].ciphertext = wrong.names[1].ciphertext.clone();
+        assert!(wrong.check().is_err());
+        let mut wrong = vectors.clone();
+        wrong.headers[0].ciphertext[0] ^= 1;
+        assert!(wrong.check().is_err());
+        let mut wrong = vectors.clone();
+        wrong.chunks[2].ciphertext[20] ^= 1;
+        assert!(wrong.check().is_err());
+        let mut wrong = vectors;
+        wrong.chunks[0].nonce.pop();
+        assert!(wrong.check().is_err());
+    }
+}
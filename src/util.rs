@@ -1,32 +1,40 @@
-use std::io::{self, Read};
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    io::{self, Read},
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
 
 use aes_kw::{Kek, KekAes256};
 use hmac::{Hmac, Mac};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, TokenData, Validation};
-use scrypt::{
-    password_hash::{PasswordHasher, Salt},
-    Params, Scrypt,
+use jsonwebtoken::{
+    errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
 };
+use scrypt::{password_hash::Salt, Params};
 use serde::{de::DeserializeOwned, Serialize};
 use sha2::Sha256;
-use zeroize::Zeroize;
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::{
     crypto::{Cryptor, FileCryptor},
     key::SUBKEY_LEN,
-    MasterKey, Result,
+    MasterKey, Passphrase, Result,
 };
 
-pub fn derive_kek(mut password: String, params: Params, salt: Salt) -> Result<KekAes256> {
-    let password_hash =
-        Scrypt.hash_password_customized(password.as_bytes(), None, None, params, salt)?;
-
-    password.zeroize();
-    debug_assert_eq!(password_hash.hash.unwrap().len(), SUBKEY_LEN);
+pub fn derive_kek(password: &Passphrase, params: Params, salt: Salt) -> Result<KekAes256> {
+    let mut salt_buffer = [0_u8; Salt::MAX_LENGTH];
+    let salt = salt.decode_b64(&mut salt_buffer)?;
 
-    let mut kek_bytes = [0_u8; SUBKEY_LEN];
-    kek_bytes.copy_from_slice(password_hash.hash.unwrap().as_bytes());
-    Ok(Kek::from(kek_bytes))
+    // Derive straight into a buffer that gets wiped, rather than going through a PasswordHash
+    let mut kek_bytes = Zeroizing::new([0_u8; SUBKEY_LEN]);
+    scrypt::scrypt(password.as_bytes(), salt, &params, kek_bytes.as_mut())?;
+    Ok(Kek::from(*kek_bytes))
 }
 
 pub fn hmac(data: &[u8], key: &MasterKey) -> Vec<u8> {
@@ -39,7 +47,90 @@ pub fn hmac(data: &[u8], key: &MasterKey) -> Vec<u8> {
         .to_vec()
 }
 
+/// The reasons a JWT can fail verification.
+#[derive(Debug, thiserror::Error)]
+pub enum JwtError {
+    #[error("JWT signature does not match the key")]
+    SignatureMismatch,
+    #[error("unsupported JWT algorithm: {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+    #[error("no key to verify JWT signatures using {0:?}")]
+    MissingKey(Algorithm),
+    #[error("malformed JWT")]
+    Malformed(#[source] jsonwebtoken::errors::Error),
+}
+
+impl From<jsonwebtoken::errors::Error> for JwtError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            ErrorKind::InvalidSignature => Self::SignatureMismatch,
+            _ => Self::Malformed(err),
+        }
+    }
+}
+
+/// The keys that JWT signatures can be verified with. Which one is used depends on the `alg`
+/// header of the token: HS256, HS384, and HS512 use the master key, while ES256 and ES384 use an
+/// EC public key, like the configs of Cryptomator Hub vaults.
+#[derive(Clone, Copy, Default)]
+pub struct JwtVerifier<'k> {
+    master_key: Option<&'k MasterKey>,
+    public_key: Option<&'k DecodingKey>,
+}
+
+impl<'k> JwtVerifier<'k> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn master_key(mut self, master_key: &'k MasterKey) -> Self {
+        self.master_key = Some(master_key);
+        self
+    }
+
+    /// Verify ES256 and ES384 signatures with `public_key`, e.g. from
+    /// [`DecodingKey::from_jwk`].
+    pub fn public_key(mut self, public_key: &'k DecodingKey) -> Self {
+        self.public_key = Some(public_key);
+        self
+    }
+
+    fn decoding_key(&self, alg: Algorithm) -> Result<Cow<'k, DecodingKey>, JwtError> {
+        let key = match alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => self
+                .master_key
+                .map(|key| Cow::Owned(DecodingKey::from_secret(key.raw_key()))),
+            Algorithm::ES256 | Algorithm::ES384 => self.public_key.map(Cow::Borrowed),
+            _ => return Err(JwtError::UnsupportedAlgorithm(alg)),
+        };
+        key.ok_or(JwtError::MissingKey(alg))
+    }
+}
+
+impl<'k> From<&'k MasterKey> for JwtVerifier<'k> {
+    fn from(master_key: &'k MasterKey) -> Self {
+        Self::new().master_key(master_key)
+    }
+}
+
+impl Debug for JwtVerifier<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtVerifier")
+            .field("master_key", &self.master_key.is_some())
+            .field("public_key", &self.public_key.is_some())
+            .finish()
+    }
+}
+
+/// Sign a JWT with the master key. Only the HMAC algorithms are supported.
 pub fn sign_jwt(header: Header, claims: impl Serialize, key: &MasterKey) -> Result<String> {
+    if !matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Err(JwtError::UnsupportedAlgorithm(header.alg).into());
+    }
+
     Ok(jsonwebtoken::encode(
         &header,
         &claims,
@@ -47,56 +138,327 @@ pub fn sign_jwt(header: Header, claims: impl Serialize, key: &MasterKey) -> Resu
     )?)
 }
 
-pub fn verify_jwt<T: DeserializeOwned>(
+/// Verify a JWT with the key that matches its `alg` header. The algorithm must also be one of
+/// those allowed by `validation`.
+pub fn verify_jwt<'k, T: DeserializeOwned>(
     token: String,
-    validation: Validation,
-    key: &MasterKey,
-) -> Result<TokenData<T>> {
-    Ok(jsonwebtoken::decode(
-        &token,
-        &DecodingKey::from_secret(key.raw_key()),
-        &validation,
-    )?)
+    mut validation: Validation,
+    verifier: impl Into<JwtVerifier<'k>>,
+) -> Result<TokenData<T>, JwtError> {
+    let header = jsonwebtoken::decode_header(&token).map_err(JwtError::Malformed)?;
+    if !validation.algorithms.contains(&header.alg) {
+        return Err(JwtError::UnsupportedAlgorithm(header.alg));
+    }
+    let key = verifier.into().decoding_key(header.alg)?;
+    // Allowed algorithms must all use the same kind of key
+    validation.algorithms = vec![header.alg];
+
+    Ok(jsonwebtoken::decode(&token, &key, &validation)?)
+}
+
+/// How [`try_read_exact_with`] retries reads that fail with [`io::ErrorKind::WouldBlock`], which
+/// non-blocking storage backends may return when no data is available yet. Interrupted reads are
+/// always retried right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadRetry {
+    /// How many times in a row a read may fail with `WouldBlock` before giving up.
+    pub would_block_retries: u32,
+    /// How long to wait before the first retry, which doubles with each retry after that.
+    pub backoff: Duration,
+}
+
+impl ReadRetry {
+    /// Never retry reads that would block.
+    pub const NONE: Self = Self {
+        would_block_retries: 0,
+        backoff: Duration::ZERO,
+    };
+}
+
+impl Default for ReadRetry {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// A read that failed, along with how much of the buffer was filled beforehand.
+#[derive(Debug, thiserror::Error)]
+#[error("read failed after {bytes_read} bytes: {error}")]
+pub struct PartialReadError {
+    pub bytes_read: usize,
+    pub error: io::Error,
+}
+
+impl From<PartialReadError> for io::Error {
+    /// Errors that happened before anything was read are passed through as they are, otherwise
+    /// they're wrapped so the number of bytes read isn't lost.
+    fn from(err: PartialReadError) -> Self {
+        match err.bytes_read {
+            0 => err.error,
+            _ => io::Error::new(err.error.kind(), err),
+        }
+    }
 }
 
 /// A modified version of read_exact that ignores an unexpected EOF, returning whether the whole
 /// buffer could be filled and the number of bytes read.
-pub fn try_read_exact(mut this: impl Read, mut buf: &mut [u8]) -> io::Result<(bool, usize)> {
+///
+/// Interrupted reads are retried. If a read fails after part of the buffer was filled, the error
+/// wraps a [`PartialReadError`] with the number of bytes read.
+pub fn try_read_exact(this: impl Read, buf: &mut [u8]) -> io::Result<(bool, usize)> {
+    Ok(try_read_exact_with(this, buf, ReadRetry::NONE)?)
+}
+
+/// Like [`try_read_exact`], but also retrying reads that would block as configured by `retry`.
+pub fn try_read_exact_with(
+    mut this: impl Read,
+    mut buf: &mut [u8],
+    retry: ReadRetry,
+) -> Result<(bool, usize), PartialReadError> {
     let mut bytes_read: usize = 0;
+    let mut retries = 0;
+    let mut backoff = retry.backoff;
     while !buf.is_empty() {
         match this.read(buf) {
             Ok(0) => break,
             Ok(n) => {
                 buf = &mut buf[n..];
                 bytes_read += n;
+                retries = 0;
+                backoff = retry.backoff;
             }
             Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
-            Err(e) => return Err(e),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock && retries < retry.would_block_retries =>
+            {
+                retries += 1;
+                thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+            Err(error) => return Err(PartialReadError { bytes_read, error }),
         }
     }
     Ok((buf.is_empty(), bytes_read))
 }
 
+/// A pool of reusable buffers for whole ciphertext chunks, which saves allocating (and freeing) a
+/// large buffer for every chunk that is read or written. Buffers are wiped before they're reused.
+#[derive(Debug)]
+pub struct ChunkBufPool {
+    capacity: usize,
+    max_idle: AtomicUsize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+impl ChunkBufPool {
+    /// The capacity of buffers in the global pool, enough for a ciphertext chunk of any cipher.
+    pub const GLOBAL_CAPACITY: usize = 33 * 1024;
+
+    /// Create a pool of buffers with the given capacity, keeping at most `max_idle` of them
+    /// around while they're not in use.
+    pub const fn new(capacity: usize, max_idle: usize) -> Self {
+        Self {
+            capacity,
+            max_idle: AtomicUsize::new(max_idle),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The pool shared by file reads and writes.
+    pub fn global() -> &'static Self {
+        static GLOBAL: ChunkBufPool = ChunkBufPool::new(ChunkBufPool::GLOBAL_CAPACITY, 64);
+        &GLOBAL
+    }
+
+    /// Take a zeroed buffer of length `len`. Buffers longer than the pool's capacity are
+    /// allocated as usual and not kept afterwards.
+    pub fn take(&self, len: usize) -> PooledBuf<'_> {
+        let mut buf = match len <= self.capacity {
+            true => self
+                .idle
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop()
+                .unwrap_or_else(|| Vec::with_capacity(self.capacity)),
+            false => Vec::new(),
+        };
+        buf.resize(len, 0);
+
+        PooledBuf { buf, pool: self }
+    }
+
+    /// Change how many unused buffers are kept, dropping any extra ones right away.
+    pub fn set_max_idle(&self, max_idle: usize) {
+        self.max_idle.store(max_idle, Ordering::Relaxed);
+        self.shrink(max_idle);
+    }
+
+    /// Drop unused buffers until at most `keep` are left, to give their memory back.
+    pub fn shrink(&self, keep: usize) {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        idle.truncate(keep);
+        idle.shrink_to(keep);
+    }
+
+    /// The number of unused buffers in the pool.
+    pub fn idle(&self) -> usize {
+        self.idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    fn recycle(&self, mut buf: Vec<u8>) {
+        buf.zeroize();
+        // Buffers that were grown past the pool's capacity don't fit its accounting
+        if buf.capacity() != self.capacity {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < self.max_idle.load(Ordering::Relaxed) {
+            idle.push(buf);
+        }
+    }
+}
+
+/// A buffer taken from a [`ChunkBufPool`], which is wiped and returned to the pool when dropped.
+#[derive(Debug)]
+pub struct PooledBuf<'p> {
+    buf: Vec<u8>,
+    pool: &'p ChunkBufPool,
+}
+
+impl Deref for PooledBuf<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf<'_> {
+    fn drop(&mut self) {
+        self.pool.recycle(std::mem::take(&mut self.buf));
+    }
+}
+
+/// A position or size that can't be converted between cleartext and ciphertext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum PositionError {
+    #[error("ciphertext position {0} is inside the file header")]
+    InsideHeader(u64),
+    #[error("ciphertext position {0} is inside the overhead of a chunk")]
+    InsideChunkOverhead(u64),
+    #[error("cleartext position {0} is too large to encrypt")]
+    Overflow(u64),
+}
+
+impl From<PositionError> for io::Error {
+    fn from(err: PositionError) -> Self {
+        match err {
+            PositionError::Overflow(_) => io::Error::new(io::ErrorKind::InvalidInput, err),
+            _ => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// The number of the chunk that holds the cleartext position `pos`.
+pub fn chunk_of(cryptor: Cryptor<'_>, pos: u64) -> u64 {
+    pos / cryptor.max_chunk_len() as u64
+}
+
+/// The offset of the cleartext position `pos` in its chunk.
+pub fn offset_in_chunk(cryptor: Cryptor<'_>, pos: u64) -> usize {
+    // Ok to truncate, this is always less than the max chunk length
+    (pos % cryptor.max_chunk_len() as u64) as usize
+}
+
+/// Map a cleartext position to a position in the ciphertext, including the header. Positions
+/// partway through a chunk are placed past the overhead of that chunk, so every position maps
+/// back with [`ciphertext_to_cleartext_pos`].
+pub fn cleartext_to_ciphertext_pos(cryptor: Cryptor<'_>, pos: u64) -> Result<u64, PositionError> {
+    let offset = match offset_in_chunk(cryptor, pos) {
+        0 => 0,
+        offset => offset as u64 + cryptor.chunk_overhead() as u64,
+    };
+
+    chunk_of(cryptor, pos)
+        .checked_mul(cryptor.max_encrypted_chunk_len() as u64)
+        .and_then(|chunk_start| chunk_start.checked_add(cryptor.encrypted_header_len() as u64))
+        .and_then(|chunk_start| chunk_start.checked_add(offset))
+        .ok_or(PositionError::Overflow(pos))
+}
+
+/// Map a position in the ciphertext, including the header, to a cleartext position. This also
+/// maps ciphertext sizes to cleartext sizes.
+///
+/// Positions in the header or the overhead of a chunk have no cleartext position. As a size, this
+/// means the file is truncated or ends in a chunk too short to hold any cleartext.
+pub fn ciphertext_to_cleartext_pos(cryptor: Cryptor<'_>, pos: u64) -> Result<u64, PositionError> {
+    let chunks_pos = pos
+        .checked_sub(cryptor.encrypted_header_len() as u64)
+        .ok_or(PositionError::InsideHeader(pos))?;
+    let encrypted_chunk_len = cryptor.max_encrypted_chunk_len() as u64;
+    let offset = match chunks_pos % encrypted_chunk_len {
+        0 => 0,
+        offset if offset <= cryptor.chunk_overhead() as u64 => {
+            return Err(PositionError::InsideChunkOverhead(pos))
+        }
+        offset => offset - cryptor.chunk_overhead() as u64,
+    };
+
+    // Can't overflow, there's always less cleartext than ciphertext
+    Ok(chunks_pos / encrypted_chunk_len * cryptor.max_chunk_len() as u64 + offset)
+}
+
+/// The ciphertext size of a file with `cleartext_len` bytes of cleartext, including the header.
+/// Sizes too large to encrypt saturate at `u64::MAX`.
+pub fn ciphertext_size(cryptor: Cryptor<'_>, cleartext_len: u64) -> u64 {
+    // The end of the cleartext maps to the end of the ciphertext
+    cleartext_to_ciphertext_pos(cryptor, cleartext_len).unwrap_or(u64::MAX)
+}
+
+/// The cleartext size of a file with `ciphertext_len` bytes of ciphertext, including the header.
+/// Fails if no file has that size, because the header is incomplete or the last chunk is too
+/// short to hold any cleartext.
+pub fn cleartext_size(cryptor: Cryptor<'_>, ciphertext_len: u64) -> Result<u64, PositionError> {
+    ciphertext_to_cleartext_pos(cryptor, ciphertext_len)
+}
+
+/// The cleartext size of a file with the given ciphertext size. Unlike [`cleartext_size`], sizes
+/// that no valid file has are accepted: an incomplete header counts as an empty file, and a last
+/// chunk too short to hold any cleartext is ignored.
 pub fn get_cleartext_size(cryptor: Cryptor<'_>, ciphertext_size: u64) -> u64 {
-    let max_enc_chunk_len = cryptor.max_encrypted_chunk_len() as u64;
-    let max_chunk_len = cryptor.max_chunk_len() as u64;
-    let enc_chunks_len = ciphertext_size - cryptor.encrypted_header_len() as u64;
-    let num_full_chunks = enc_chunks_len / max_enc_chunk_len;
-    // Length of last partial cleartext chunk, or zero if there is no partial chunk
-    let remainder = (enc_chunks_len % max_enc_chunk_len).max(max_enc_chunk_len - max_chunk_len)
-        - (max_enc_chunk_len - max_chunk_len);
+    let encrypted_chunk_len = cryptor.max_encrypted_chunk_len() as u64;
+    let chunks_len = ciphertext_size.saturating_sub(cryptor.encrypted_header_len() as u64);
+    let remainder =
+        (chunks_len % encrypted_chunk_len).saturating_sub(cryptor.chunk_overhead() as u64);
 
-    num_full_chunks * max_chunk_len + remainder
+    chunks_len / encrypted_chunk_len * cryptor.max_chunk_len() as u64 + remainder
 }
 
 #[cfg(test)]
 mod tests {
-    use base64ct::{Base64, Encoding};
-    use jsonwebtoken::Algorithm;
-    use scrypt::password_hash::SaltString;
+    use std::{fs, path::Path};
+
+    use base64ct::{Base64, Base64UrlUnpadded, Encoding};
+    use jsonwebtoken::jwk::Jwk;
+    use proptest::prelude::*;
+    use scrypt::{
+        password_hash::{PasswordHasher, SaltString},
+        Scrypt,
+    };
     use serde::Deserialize;
 
     use super::*;
+    use crate::crypto::{siv_ctrmac, siv_gcm};
 
     #[test]
     #[ignore]
@@ -117,10 +479,10 @@ mod tests {
     #[test]
     #[ignore]
     fn kek_derivation_test() {
-        let password = String::from("this is a test password");
+        let password = Passphrase::from("this is a test password");
         let salt_string = SaltString::encode_b64(b"examplesalt").unwrap();
         let params = Params::new(15, 8, 1, SUBKEY_LEN).unwrap();
-        let kek = derive_kek(password, params, salt_string.as_salt()).unwrap();
+        let kek = derive_kek(&password, params, salt_string.as_salt()).unwrap();
         let wrapped_data = kek.wrap_vec(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
 
         assert_eq!(
@@ -131,14 +493,145 @@ mod tests {
 
     #[test]
     fn hmac_test() {
-        // Safe, this is for test purposes only
-        let key = unsafe { MasterKey::from_bytes([15_u8; SUBKEY_LEN * 2]) };
+        let key = MasterKey::from_raw(Zeroizing::new([15_u8; SUBKEY_LEN * 2])).unwrap();
         assert_eq!(
             Base64::encode_string(&hmac(b"here is some data", &key)),
             "CWTyTEOJ2pDGgMpGjHgQV8T+EjEJYliXRQL2XzgT1W0="
         );
     }
 
+    // Reads at most `step` bytes at a time, failing once with `fault` when reaching `fail_at`.
+    struct FaultyReader {
+        data: Vec<u8>,
+        pos: usize,
+        step: usize,
+        fail_at: usize,
+        fault: Option<io::ErrorKind>,
+        faults: u32,
+    }
+
+    impl Read for FaultyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.fail_at && self.faults > 0 {
+                if let Some(kind) = self.fault {
+                    self.faults -= 1;
+                    return Err(kind.into());
+                }
+            }
+            // Stop short of the fault, so it's hit at exactly that position
+            let mut end = (self.pos + self.step.min(buf.len())).min(self.data.len());
+            if self.pos < self.fail_at && self.faults > 0 {
+                end = end.min(self.fail_at);
+            }
+            let n = end - self.pos;
+            buf[..n].copy_from_slice(&self.data[self.pos..end]);
+            self.pos = end;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn try_read_exact_test() {
+        let data: Vec<u8> = (0..10).collect();
+        let reader = |step, fail_at, fault, faults| FaultyReader {
+            data: data.clone(),
+            pos: 0,
+            step,
+            fail_at,
+            fault,
+            faults,
+        };
+
+        for step in 1..=4 {
+            for fail_at in 0..=data.len() {
+                // Interrupted and short reads are retried, wherever they happen
+                let mut buf = [0; 10];
+                let interrupted = reader(step, fail_at, Some(io::ErrorKind::Interrupted), 3);
+                assert_eq!(try_read_exact(interrupted, &mut buf).unwrap(), (true, 10));
+                assert_eq!(buf[..], data[..]);
+
+                let mut buf = [0; 12];
+                let short = reader(step, fail_at, None, 0);
+                assert_eq!(try_read_exact(short, &mut buf).unwrap(), (false, 10));
+                assert_eq!(buf[..10], data[..]);
+
+                // Other errors report how much was read before them
+                if fail_at == data.len() {
+                    continue;
+                }
+                let mut buf = [0; 10];
+                let broken = reader(step, fail_at, Some(io::ErrorKind::ConnectionReset), 1);
+                let err = try_read_exact_with(broken, &mut buf, ReadRetry::NONE).unwrap_err();
+                assert_eq!(err.bytes_read, fail_at);
+                assert_eq!(err.error.kind(), io::ErrorKind::ConnectionReset);
+
+                let broken = reader(step, fail_at, Some(io::ErrorKind::ConnectionReset), 1);
+                let err = try_read_exact(broken, &mut buf).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+                let partial = err
+                    .get_ref()
+                    .and_then(|err| err.downcast_ref::<PartialReadError>());
+                assert_eq!(
+                    partial.map(|err| err.bytes_read),
+                    Some(fail_at).filter(|&n| n > 0)
+                );
+            }
+        }
+
+        // Reads that would block are only retried if configured to
+        let retry = ReadRetry {
+            would_block_retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let mut buf = [0; 10];
+        let blocked = reader(3, 4, Some(io::ErrorKind::WouldBlock), 2);
+        assert_eq!(
+            try_read_exact_with(blocked, &mut buf, retry).unwrap(),
+            (true, 10)
+        );
+        let blocked = reader(3, 4, Some(io::ErrorKind::WouldBlock), 2);
+        let err = try_read_exact_with(blocked, &mut buf, ReadRetry::NONE).unwrap_err();
+        assert_eq!(
+            (err.bytes_read, err.error.kind()),
+            (4, io::ErrorKind::WouldBlock)
+        );
+        let blocked = reader(3, 4, Some(io::ErrorKind::WouldBlock), 3);
+        let err = try_read_exact_with(blocked, &mut buf, retry).unwrap_err();
+        assert_eq!(
+            (err.bytes_read, err.error.kind()),
+            (4, io::ErrorKind::WouldBlock)
+        );
+    }
+
+    #[test]
+    fn chunk_buf_pool_test() {
+        let pool = ChunkBufPool::new(64, 2);
+        let mut first = pool.take(64);
+        first.fill(0xff);
+        let second = pool.take(10);
+        let third = pool.take(10);
+        drop((first, second, third));
+        // Only two buffers are kept
+        assert_eq!(pool.idle(), 2);
+
+        // Reused buffers are wiped and have the requested length
+        let buf = pool.take(32);
+        assert_eq!(*buf, vec![0; 32]);
+        assert_eq!(pool.idle(), 1);
+        drop(buf);
+
+        // Buffers that are too big aren't kept
+        drop(pool.take(65));
+        assert_eq!(pool.idle(), 2);
+
+        pool.shrink(1);
+        assert_eq!(pool.idle(), 1);
+        pool.set_max_idle(0);
+        assert_eq!(pool.idle(), 0);
+        drop(pool.take(10));
+        assert_eq!(pool.idle(), 0);
+    }
+
     #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
     struct ExampleClaims {
         one: u32,
@@ -149,8 +642,7 @@ mod tests {
     #[test]
     fn sign_and_verify_jwt_test() {
         let key_bytes = [[30; SUBKEY_LEN], [40; SUBKEY_LEN]].concat();
-        // Safe, this is for test purposes only
-        let key = unsafe { MasterKey::from_bytes(key_bytes.try_into().unwrap()) };
+        let key = MasterKey::from_raw(Zeroizing::new(key_bytes.try_into().unwrap())).unwrap();
 
         let header = Header::new(Algorithm::HS256);
         let claims = ExampleClaims {
@@ -173,4 +665,229 @@ mod tests {
         assert_eq!(verified.header, header);
         assert_eq!(verified.claims, claims);
     }
+
+    #[test]
+    fn verify_ec_jwt_test() {
+        let fixture = |name| {
+            fs::read_to_string(Path::new("tests/fixtures/vault_v8_ec_signed").join(name)).unwrap()
+        };
+        let public_key = |name| {
+            let jwk: Jwk = serde_json::from_str(&fixture(name)).unwrap();
+            DecodingKey::from_jwk(&jwk).unwrap()
+        };
+        let es256_key = public_key("public_key.json");
+        let es384_key = public_key("public_key_es384.json");
+        let master_key = MasterKey::new([1; SUBKEY_LEN], [2; SUBKEY_LEN]).unwrap();
+        let verify_with = |token: &str, verifier: JwtVerifier<'_>, algorithms: &[Algorithm]| {
+            let mut validation = Validation::new(Algorithm::ES256);
+            validation.algorithms = algorithms.to_vec();
+            validation.validate_exp = false;
+            validation.required_spec_claims.clear();
+            verify_jwt::<serde_json::Value>(token.to_string(), validation, verifier)
+        };
+        let verify = |token: &str, verifier| {
+            verify_with(
+                token,
+                verifier,
+                &[Algorithm::HS256, Algorithm::ES256, Algorithm::ES384],
+            )
+        };
+
+        let es256 = fixture("vault.cryptomator");
+        let es384 = fixture("vault_es384.cryptomator");
+        let verifier = JwtVerifier::from(&master_key).public_key(&es256_key);
+        let verified = verify(&es256, verifier).unwrap();
+        assert_eq!(verified.header.alg, Algorithm::ES256);
+        assert_eq!(verified.claims["format"], 8);
+        let verified = verify(&es384, JwtVerifier::new().public_key(&es384_key)).unwrap();
+        assert_eq!(verified.header.alg, Algorithm::ES384);
+
+        // The wrong public key, or a tampered signature
+        assert!(matches!(
+            verify(&es256, JwtVerifier::new().public_key(&es384_key)),
+            Err(JwtError::SignatureMismatch)
+        ));
+        let tampered = format!("{}AAAA", &es256[..es256.len() - 4]);
+        assert!(matches!(
+            verify(&tampered, verifier),
+            Err(JwtError::SignatureMismatch)
+        ));
+
+        // No key for the algorithm
+        assert!(matches!(
+            verify(&es256, JwtVerifier::from(&master_key)),
+            Err(JwtError::MissingKey(Algorithm::ES256))
+        ));
+        let hs256 = sign_jwt(Header::new(Algorithm::HS256), "claims", &master_key).unwrap();
+        assert!(matches!(
+            verify(&hs256, JwtVerifier::new().public_key(&es256_key)),
+            Err(JwtError::MissingKey(Algorithm::HS256))
+        ));
+
+        // Algorithms that aren't supported, or not allowed by the validation
+        let (_, rest) = es256.split_once('.').unwrap();
+        let rs256 = format!(
+            "{}.{rest}",
+            Base64UrlUnpadded::encode_string(br#"{"typ":"JWT","alg":"RS256"}"#)
+        );
+        assert!(matches!(
+            verify_with(&rs256, verifier, &[Algorithm::RS256]),
+            Err(JwtError::UnsupportedAlgorithm(Algorithm::RS256))
+        ));
+        assert!(matches!(
+            verify_with(&es256, verifier, &[Algorithm::HS256]),
+            Err(JwtError::UnsupportedAlgorithm(Algorithm::ES256))
+        ));
+
+        // Tokens that can't be decoded at all
+        assert!(matches!(
+            verify("not a token", verifier),
+            Err(JwtError::Malformed(_))
+        ));
+        let (unsigned, _) = es256.rsplit_once('.').unwrap();
+        assert!(matches!(
+            verify(unsigned, verifier),
+            Err(JwtError::Malformed(_))
+        ));
+
+        // Signing is only done with the master key
+        let err = sign_jwt(Header::new(Algorithm::ES256), "claims", &master_key).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(JwtError::UnsupportedAlgorithm(Algorithm::ES256))
+        ));
+    }
+
+    fn cryptors(key: &MasterKey) -> [Cryptor<'_>; 2] {
+        [
+            Cryptor::SivCtrMac(siv_ctrmac::Cryptor::new(key)),
+            Cryptor::SivGcm(siv_gcm::Cryptor::new(key)),
+        ]
+    }
+
+    #[test]
+    fn position_test() {
+        let key = MasterKey::new([1; SUBKEY_LEN], [2; SUBKEY_LEN]).unwrap();
+        let cryptor = Cryptor::SivGcm(siv_gcm::Cryptor::new(&key));
+        // 68 byte header, then chunks of 32 KiB with 28 bytes of overhead
+        for (cleartext, ciphertext) in [
+            (0, 68),
+            (1, 68 + 29),
+            (32 * 1024, 68 + 32 * 1024 + 28),
+            (32 * 1024 + 1, 68 + 32 * 1024 + 28 + 29),
+        ] {
+            assert_eq!(
+                cleartext_to_ciphertext_pos(cryptor, cleartext),
+                Ok(ciphertext)
+            );
+            assert_eq!(
+                ciphertext_to_cleartext_pos(cryptor, ciphertext),
+                Ok(cleartext)
+            );
+        }
+
+        assert_eq!(
+            ciphertext_to_cleartext_pos(cryptor, 67),
+            Err(PositionError::InsideHeader(67))
+        );
+        assert_eq!(
+            ciphertext_to_cleartext_pos(cryptor, 68 + 28),
+            Err(PositionError::InsideChunkOverhead(68 + 28))
+        );
+        assert_eq!(
+            cleartext_to_ciphertext_pos(cryptor, u64::MAX),
+            Err(PositionError::Overflow(u64::MAX))
+        );
+        assert_eq!(get_cleartext_size(cryptor, 0), 0);
+        assert_eq!(get_cleartext_size(cryptor, 68 + 28), 0);
+        assert_eq!(
+            (
+                chunk_of(cryptor, 32 * 1024 + 5),
+                offset_in_chunk(cryptor, 32 * 1024 + 5)
+            ),
+            (1, 5)
+        );
+    }
+
+    #[test]
+    fn size_test() {
+        const CHUNK: u64 = 32 * 1024;
+
+        let key = MasterKey::new([1; SUBKEY_LEN], [2; SUBKEY_LEN]).unwrap();
+        // The header and chunk overhead of SIV-CTRMAC, then SIV-GCM
+        for (cryptor, (header, overhead)) in cryptors(&key).into_iter().zip([(88, 48), (68, 28)]) {
+            assert_eq!(cryptor.encrypted_header_len(), header as usize);
+            assert_eq!(cryptor.chunk_overhead(), overhead as usize);
+            let chunk = CHUNK + overhead;
+            for (cleartext, ciphertext) in [
+                (0, header),
+                (1, header + overhead + 1),
+                (CHUNK - 1, header + chunk - 1),
+                (CHUNK, header + chunk),
+                (CHUNK + 1, header + chunk + overhead + 1),
+                (3 * CHUNK, header + 3 * chunk),
+                (3 * CHUNK + 100, header + 3 * chunk + overhead + 100),
+            ] {
+                assert_eq!(ciphertext_size(cryptor, cleartext), ciphertext);
+                assert_eq!(cleartext_size(cryptor, ciphertext), Ok(cleartext));
+                assert_eq!(get_cleartext_size(cryptor, ciphertext), cleartext);
+            }
+
+            // Truncated headers, and chunks with nothing but overhead, are no valid file's size
+            for ciphertext in [
+                0,
+                header - 1,
+                header + 1,
+                header + overhead,
+                header + chunk + overhead,
+            ] {
+                assert!(cleartext_size(cryptor, ciphertext).is_err(), "{ciphertext}");
+            }
+            assert_eq!(ciphertext_size(cryptor, u64::MAX), u64::MAX);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn cleartext_pos_roundtrip_test(pos in 0..u64::MAX / 2) {
+            let key = MasterKey::new([1; SUBKEY_LEN], [2; SUBKEY_LEN]).unwrap();
+            for cryptor in cryptors(&key) {
+                let ciphertext_pos = cleartext_to_ciphertext_pos(cryptor, pos).unwrap();
+                prop_assert!(ciphertext_pos > pos);
+                prop_assert_eq!(ciphertext_to_cleartext_pos(cryptor, ciphertext_pos), Ok(pos));
+                prop_assert_eq!(get_cleartext_size(cryptor, ciphertext_pos), pos);
+                prop_assert!(cleartext_to_ciphertext_pos(cryptor, pos + 1).unwrap() > ciphertext_pos);
+                prop_assert_eq!(
+                    chunk_of(cryptor, pos) * cryptor.max_chunk_len() as u64
+                        + offset_in_chunk(cryptor, pos) as u64,
+                    pos
+                );
+            }
+        }
+
+        #[test]
+        fn ciphertext_pos_test(pos: u64) {
+            let key = MasterKey::new([1; SUBKEY_LEN], [2; SUBKEY_LEN]).unwrap();
+            for cryptor in cryptors(&key) {
+                let header_len = cryptor.encrypted_header_len() as u64;
+                let encrypted_chunk_len = cryptor.max_encrypted_chunk_len() as u64;
+                let overhead = encrypted_chunk_len - cryptor.max_chunk_len() as u64;
+                let offset = pos.saturating_sub(header_len) % encrypted_chunk_len;
+
+                // Valid positions map back exactly, and invalid ones are rejected
+                match ciphertext_to_cleartext_pos(cryptor, pos) {
+                    Ok(cleartext_pos) => {
+                        prop_assert!(offset == 0 || offset > overhead);
+                        prop_assert_eq!(cleartext_to_ciphertext_pos(cryptor, cleartext_pos), Ok(pos));
+                    }
+                    Err(PositionError::InsideHeader(_)) => prop_assert!(pos < header_len),
+                    Err(PositionError::InsideChunkOverhead(_)) => {
+                        prop_assert!(pos >= header_len && (1..=overhead).contains(&offset));
+                    }
+                    Err(err) => prop_assert!(false, "unexpected error: {err}"),
+                }
+                prop_assert!(get_cleartext_size(cryptor, pos) <= pos);
+            }
+        }
+    }
 }
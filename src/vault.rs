@@ -1,18 +1,33 @@
 use std::{
+    error::Error,
     fs,
+    io::{self, Write},
+    ops::Deref,
     path::{Path, PathBuf},
 };
 
-use color_eyre::eyre::{bail, OptionExt};
-use jsonwebtoken::{TokenData, Validation};
+use color_eyre::eyre::{bail, OptionExt, WrapErr};
+use jsonwebtoken::{Algorithm, Header, TokenData, Validation};
+use rand_core::{OsRng, RngCore};
+use scrypt::{password_hash::SaltString, Params};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 use crate::{
-    crypto::{siv_ctrmac, siv_gcm, Cryptor},
-    util, MasterKey, Result, WrappedKey,
+    crypto::{siv_ctrmac, siv_gcm, Cryptor, DirId, FileCryptor},
+    fs::EncryptedFile,
+    key::{KdfParams, SharedKey, MASTERKEY_FILE_VERSION},
+    recovery_key::RecoveryKey,
+    storage::{MemoryStorage, StdStorage, VaultStorage},
+    util::{self, JwtError, JwtVerifier},
+    MasterKey, MasterkeyFileLoader, MasterkeyLoader, Passphrase, Result, WrappedKey,
 };
 
+pub(crate) const CONFIG_FILE_NAME: &str = "vault.cryptomator";
+pub(crate) const MASTERKEY_FILE_NAME: &str = "masterkey.cryptomator";
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CipherCombo {
     /// AES-SIV for file name encryption, AES-CTR + HMAC for content encryption.
@@ -23,90 +38,669 @@ pub enum CipherCombo {
     SivGcm,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+// Fields are in the same order as written by the official app, so re-signed configs are identical
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VaultConfig {
     pub jti: Uuid,
     pub format: u32,
+    pub cipher_combo: CipherCombo,
     pub shortening_threshold: u32,
+    /// Any other claims, which are preserved when re-signing the config.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl VaultConfig {
+    /// Verify the vault config JWT `jwt` with the master key and decode it, the same way as when
+    /// opening a vault. Configs signed with a public key, like those of Hub vaults, need
+    /// [`Vault::open_with_loader`] instead.
+    pub fn decode(jwt: &str, master_key: &MasterKey) -> Result<TokenData<Self>, VaultOpenError> {
+        verify_config(jwt.to_string(), master_key)
+    }
+}
+
+/// The reasons a vault can fail to open.
+#[derive(Debug, thiserror::Error)]
+pub enum VaultOpenError {
+    #[error("wrong password")]
+    WrongPassword,
+    #[error("unsupported vault format: {0}")]
+    UnsupportedFormat(u32),
+    #[error("unsupported cipher combo: {0}")]
+    UnsupportedCipherCombo(String),
+    #[error("vault config signature does not match the master key")]
+    InvalidConfigSignature,
+    #[error("invalid vault config")]
+    InvalidConfig(#[source] Box<dyn Error + Send + Sync>),
+    #[error("unsupported key URI format: {0}")]
+    UnsupportedKeyId(String),
+    #[error("missing masterkey file: {}", .0.display())]
+    MissingMasterkeyFile(PathBuf),
+    #[error("missing data directory `d` in {}", .0.display())]
+    MissingDataDir(PathBuf),
+    /// There's no vault at the path, see [`VaultKind::NotAVault`].
+    #[error(
+        "{} is not a vault, since it has no vault.cryptomator or masterkey.cryptomator",
+        .0.display()
+    )]
+    NotAVault(PathBuf),
+    /// The path is the directory containing `vaults` rather than a vault itself.
+    #[error(
+        "{} is not a vault, but contains {}; open that instead",
+        .path.display(),
+        .vaults.iter().map(|vault| vault.display().to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    VaultParentDirectory { path: PathBuf, vaults: Vec<PathBuf> },
+    /// The vault has an older format without a vault.cryptomator, and needs to be migrated.
+    #[error(
+        "{} is a format {format} vault, which has to be migrated to format 8 first",
+        .path.display()
+    )]
+    LegacyFormat { path: PathBuf, format: u32 },
+    #[error("failed to load master key")]
+    KeyLoad(#[source] Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The vault was [locked](Vault::lock), so its master key is gone and nothing can be encrypted or
+/// decrypted anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("the vault is locked")]
+pub struct VaultLocked;
+
+/// What a directory looks like to [`Vault::detect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VaultKind {
+    /// A vault with a vault.cryptomator config, as introduced by format 8, and the masterkey file
+    /// it refers to if any. A format 8 masterkey file without a config is also taken as one.
+    Format8,
+    /// A vault with a version 7 masterkey.cryptomator and no config.
+    Format7,
+    /// A vault with a masterkey.cryptomator of version 6 or older and no config.
+    Format6,
+    /// A vault config that refers to a missing masterkey file, or just a `d` directory.
+    MissingMasterkey,
+    /// Nothing that belongs to a vault.
+    NotAVault,
+    /// Not a vault, but a directory containing the vaults `vaults`, which is easy to pick by
+    /// mistake.
+    PossiblyVaultParentDirectory { vaults: Vec<PathBuf> },
+}
+
+/// Options used when creating a new vault with [`Vault::create`].
+#[derive(Debug, Clone)]
+pub struct VaultCreateOptions {
     pub cipher_combo: CipherCombo,
+    pub shortening_threshold: u32,
+    /// Parameters for deriving the key that wraps the master key.
+    pub kdf_params: KdfParams,
+    /// Salt for deriving the key that wraps the master key. A random salt is generated if `None`.
+    pub scrypt_salt: Option<SaltString>,
+}
+
+impl Default for VaultCreateOptions {
+    fn default() -> Self {
+        Self {
+            cipher_combo: CipherCombo::SivGcm,
+            shortening_threshold: 220,
+            kdf_params: Default::default(),
+            scrypt_salt: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Vault {
     path: PathBuf,
+    config_dir: PathBuf,
     config: TokenData<VaultConfig>,
-    master_key: MasterKey,
+    master_key: SharedKey,
+    masterkey_backup: Option<PathBuf>,
+    storage: Box<dyn VaultStorage>,
 }
 
 impl Vault {
-    // TODO: New vaults require the following:
-    // - name
-    // - location
-    // - password -> master key
-    //
-    // Optional: generate a recovery key
-    //
-    // pub fn create() -> Result<Self, VaultCreateError> {}
+    // Creation procedure is as follows:
+    // 1. Generate a random master key and wrap it with a KEK derived from the password
+    // 2. Write the wrapped master key, then sign and write the config JWT
+    // 3. Create the data directory, along with the root directory and its dirid.c9r
+    pub fn create(
+        path: impl AsRef<Path>,
+        password: impl Into<Passphrase>,
+        options: VaultCreateOptions,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        let path = path.canonicalize()?;
+        if path.join(CONFIG_FILE_NAME).try_exists()? {
+            bail!("vault already exists at {}", path.display());
+        }
+
+        let salt = match options.scrypt_salt {
+            Some(salt) => salt,
+            None => random_salt()?,
+        };
+
+        let master_key = MasterKey::random()?;
+        let params = Params::try_from(options.kdf_params)?;
+        let kek = util::derive_kek(&password.into(), params, salt.as_salt())?;
+        let wrapped_key = master_key.wrap(&kek, params, salt, MASTERKEY_FILE_VERSION)?;
+        wrapped_key.to_file(path.join(MASTERKEY_FILE_NAME))?;
+        backup_file(&path.join(MASTERKEY_FILE_NAME))?;
+
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(format!("masterkeyfile:{MASTERKEY_FILE_NAME}"));
+        let claims = VaultConfig {
+            jti: Uuid::new_v4(),
+            format: 8,
+            cipher_combo: options.cipher_combo,
+            shortening_threshold: options.shortening_threshold,
+            extra: Default::default(),
+        };
+        let jwt = util::sign_jwt(header.clone(), &claims, &master_key)?;
+        fs::File::create_new(path.join(CONFIG_FILE_NAME))?.write_all(jwt.as_bytes())?;
+
+        let vault = Self {
+            config_dir: path.clone(),
+            path,
+            config: TokenData { header, claims },
+            master_key: SharedKey::new(master_key),
+            masterkey_backup: None,
+            storage: Box::new(StdStorage),
+        };
+
+        // The root directory ID is empty, so its dirid.c9r only contains a header
+        let root_dir = vault
+            .path
+            .join("d")
+            .join(vault.cryptor().hash_dir_id(&DirId::root())?);
+        vault.storage.create_dir_all(&root_dir)?;
+        EncryptedFile::create_new_in(&*vault.storage, vault.cryptor(), root_dir.join("dirid.c9r"))?;
+
+        Ok(vault)
+    }
+
+    /// Open a vault using its password, loading the master key from the masterkey file next to
+    /// the vault config. `config_path` may also be the vault directory. If there's no config
+    /// there, the error says what was found instead, as [`Vault::detect`] sees it.
+    pub fn open(
+        config_path: impl AsRef<Path>,
+        password: impl Into<Passphrase>,
+    ) -> Result<Self, VaultOpenError> {
+        let mut config_path = config_path.as_ref().to_path_buf();
+        if !config_path.is_file() {
+            let dir = vault_dir(&config_path).to_path_buf();
+            let kind = Self::detect(&dir).map_err(|err| match err.downcast::<io::Error>() {
+                Ok(err) => VaultOpenError::Io(err),
+                Err(err) => VaultOpenError::InvalidConfig(err.into()),
+            })?;
+            match kind {
+                VaultKind::Format8 => config_path = dir.join(CONFIG_FILE_NAME),
+                VaultKind::Format7 => return Err(legacy_format(dir, 7)),
+                VaultKind::Format6 => return Err(legacy_format(dir, 6)),
+                VaultKind::MissingMasterkey => {
+                    return Err(VaultOpenError::MissingMasterkeyFile(
+                        dir.join(MASTERKEY_FILE_NAME),
+                    ))
+                }
+                VaultKind::NotAVault => return Err(VaultOpenError::NotAVault(dir)),
+                VaultKind::PossiblyVaultParentDirectory { vaults } => {
+                    return Err(VaultOpenError::VaultParentDirectory { path: dir, vaults })
+                }
+            }
+        }
+
+        // Ok to unwrap, a file always has a parent
+        let config_dir = config_path.parent().unwrap();
+        let loader = MasterkeyFileLoader::new(config_dir, password);
+        let mut vault = Self::open_with_loader(&config_path, &loader)?;
+        vault.masterkey_backup = loader.backup_used();
+        Ok(vault)
+    }
+
+    /// Work out whether `path`, a directory or a vault.cryptomator file in one, is a vault and of
+    /// what kind, by looking for the files a vault has. Nothing is decrypted, so a directory that
+    /// looks like a vault may still fail to open.
+    pub fn detect(path: impl AsRef<Path>) -> Result<VaultKind> {
+        let dir = vault_dir(path.as_ref());
+        if !fs::metadata(dir)?.is_dir() {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory).into());
+        }
+
+        let config_path = dir.join(CONFIG_FILE_NAME);
+        if config_path.is_file() {
+            // Keys that aren't in a masterkey file, like those from Hub, can't be missing here
+            let masterkey_path = fs::read_to_string(&config_path)
+                .ok()
+                .and_then(|jwt| jsonwebtoken::decode_header(&jwt).ok())
+                .and_then(|header| master_key_path(dir, &header).ok());
+            return Ok(match masterkey_path {
+                Some(path) if !path.is_file() => VaultKind::MissingMasterkey,
+                _ => VaultKind::Format8,
+            });
+        }
+
+        // Older formats have no config, only a masterkey file with the format as its version
+        let masterkey_path = dir.join(MASTERKEY_FILE_NAME);
+        if masterkey_path.is_file() {
+            let version = fs::read(&masterkey_path)
+                .ok()
+                .and_then(|json| serde_json::from_slice::<Value>(&json).ok())
+                .and_then(|json| json.get("version")?.as_u64());
+            return Ok(match version {
+                // The config is missing, which opening the vault reports
+                Some(version) if version == MASTERKEY_FILE_VERSION as u64 => VaultKind::Format8,
+                Some(7) => VaultKind::Format7,
+                Some(..=6) => VaultKind::Format6,
+                _ => VaultKind::NotAVault,
+            });
+        }
+        if dir.join("d").is_dir() {
+            return Ok(VaultKind::MissingMasterkey);
+        }
+
+        let mut vaults = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.join(CONFIG_FILE_NAME).is_file() || path.join(MASTERKEY_FILE_NAME).is_file() {
+                vaults.push(path);
+            }
+        }
+        vaults.sort();
+        Ok(match vaults.is_empty() {
+            true => VaultKind::NotAVault,
+            false => VaultKind::PossiblyVaultParentDirectory { vaults },
+        })
+    }
 
     // Unlock procedure is as follows:
-    // 1. Decode the config JWT header to get the master key URI
-    // 2. Load the wrapped master key and grab the scrypt parameters
-    // 3. Derive a KEK with the password and scrypt parameters
-    // 4. Use the KEK to unwrap the master key and decode/verify the config JWT
-    pub fn open(config_path: impl AsRef<Path>, password: String) -> Result<Self> {
+    // 1. Decode the config JWT header to get the master key ID
+    // 2. Check that the loader supports the key ID, and load the master key
+    // 3. Use the master key to decode/verify the config JWT
+    pub fn open_with_loader(
+        config_path: impl AsRef<Path>,
+        loader: &dyn MasterkeyLoader,
+    ) -> Result<Self, VaultOpenError> {
         let jwt = fs::read_to_string(&config_path)?;
-        let header = jsonwebtoken::decode_header(&jwt)?;
-        let master_key_uri = header.kid.ok_or_eyre("JWT header is missing `kid` claim")?;
-
-        if master_key_uri.starts_with("masterkeyfile:") {
-            // TODO: Handle case with no parent?
-            let config_dir = config_path.as_ref().parent().unwrap();
-            let key_path = config_dir.join(master_key_uri.split_once("masterkeyfile:").unwrap().1);
-            let wrapped_key = WrappedKey::from_file(key_path)?;
-            let kek = util::derive_kek(password, wrapped_key.params(), wrapped_key.salt())?;
-            let master_key = MasterKey::from_wrapped(&wrapped_key, &kek)?;
-
-            let mut validation = Validation::new(header.alg);
-            validation.validate_exp = false;
-            validation.required_spec_claims.clear();
-
-            let config: TokenData<VaultConfig> = util::verify_jwt(jwt, validation, &master_key)?;
-
-            // TODO: Only version 8 is supported for now
-            match config.claims.format {
-                8 => {}
-                other => bail!("unsupported vault format: {other}"),
-            }
+        let header = jsonwebtoken::decode_header(&jwt)
+            .map_err(|err| VaultOpenError::InvalidConfig(err.into()))?;
+        let key_id = header.kid.ok_or_else(|| {
+            VaultOpenError::InvalidConfig("JWT header is missing `kid` claim".into())
+        })?;
+        if !loader.supports(&key_id) {
+            return Err(VaultOpenError::UnsupportedKeyId(key_id));
+        }
 
-            Ok(Self {
-                path: config_dir.canonicalize()?,
-                config,
-                master_key,
-            })
-        } else {
-            bail!("unsupported key URI format: {master_key_uri}");
+        // Loaders may report a specific failure reason, otherwise wrap whatever error occurred
+        let load_err = |err: color_eyre::Report| {
+            err.downcast::<VaultOpenError>()
+                .unwrap_or_else(|err| VaultOpenError::KeyLoad(err.into()))
+        };
+        let master_key = loader.load(&key_id).map_err(load_err)?;
+        let public_key = loader.config_public_key(&key_id).map_err(load_err)?;
+        let mut verifier = JwtVerifier::from(&master_key);
+        if let Some(public_key) = &public_key {
+            verifier = verifier.public_key(public_key);
         }
+        let config = verify_config(jwt, verifier)?;
+
+        // TODO: Handle case with no parent?
+        let config_dir = config_path.as_ref().parent().unwrap().canonicalize()?;
+        Ok(Self {
+            path: config_dir.clone(),
+            config_dir,
+            config,
+            master_key: SharedKey::new(master_key),
+            masterkey_backup: None,
+            storage: Box::new(StdStorage),
+        })
+    }
+
+    /// Open a vault whose encrypted data is stored separately from its config, e.g. to keep the
+    /// config and masterkey file in a more protected location. `data_root` is the directory that
+    /// contains the vault's `d` directory.
+    pub fn open_split(
+        config_path: impl AsRef<Path>,
+        loader: &dyn MasterkeyLoader,
+        data_root: impl AsRef<Path>,
+    ) -> Result<Self, VaultOpenError> {
+        let data_root = data_root.as_ref();
+        if !data_root.join("d").is_dir() {
+            return Err(VaultOpenError::MissingDataDir(data_root.to_path_buf()));
+        }
+
+        let mut vault = Self::open_with_loader(config_path, loader)?;
+        vault.path = data_root.canonicalize()?;
+        Ok(vault)
+    }
+
+    /// Change the password used to wrap the master key. The master key itself is unchanged, so
+    /// the vault config and all encrypted data remain valid.
+    pub fn change_password(
+        &mut self,
+        old_password: impl Into<Passphrase>,
+        new_password: impl Into<Passphrase>,
+    ) -> Result<()> {
+        self.change_password_inner(old_password.into(), new_password.into(), None)
+    }
+
+    /// Same as [`Vault::change_password`], but also change the KDF parameters used to derive the
+    /// key that wraps the master key.
+    pub fn change_password_with_kdf(
+        &mut self,
+        old_password: impl Into<Passphrase>,
+        new_password: impl Into<Passphrase>,
+        kdf_params: KdfParams,
+    ) -> Result<()> {
+        self.change_password_inner(old_password.into(), new_password.into(), Some(kdf_params))
+    }
+
+    fn change_password_inner(
+        &mut self,
+        old_password: Passphrase,
+        new_password: Passphrase,
+        kdf_params: Option<KdfParams>,
+    ) -> Result<()> {
+        let new_params = match kdf_params {
+            Some(params) => Some(Params::try_from(params)?),
+            None => None,
+        };
+
+        let key_path = master_key_path(&self.config_dir, &self.config.header)?;
+        let master_key = self.master_key()?;
+        let wrapped_key = WrappedKey::from_file(&key_path)?;
+        let old_kek = util::derive_kek(&old_password, wrapped_key.params(), wrapped_key.salt())?;
+        match MasterKey::from_wrapped(&wrapped_key, &old_kek) {
+            Ok(key) if key == *master_key => {}
+            _ => bail!("incorrect password"),
+        }
+
+        let params = new_params.unwrap_or(wrapped_key.params());
+        let salt = random_salt()?;
+        let new_kek = util::derive_kek(&new_password, params, salt.as_salt())?;
+        let new_wrapped_key = master_key.wrap(&new_kek, params, salt, MASTERKEY_FILE_VERSION)?;
+
+        replace_file(&key_path, |tmp_path| new_wrapped_key.to_file(tmp_path))?;
+        backup_file(&key_path)
+    }
+
+    /// Sign the current vault config with the master key and write it back to disk, keeping a
+    /// backup of the old config. All claims are preserved, including unrecognized ones.
+    pub fn resign_config(&self) -> Result<()> {
+        let jwt = util::sign_jwt(
+            self.config.header.clone(),
+            &self.config.claims,
+            &*self.master_key()?,
+        )?;
+
+        replace_file(&self.config_dir.join(CONFIG_FILE_NAME), |tmp_path| {
+            let mut file = fs::File::create_new(tmp_path)?;
+            file.write_all(jwt.as_bytes())?;
+            Ok(file.sync_all()?)
+        })
     }
 
+    /// Export the recovery key for this vault, which can be used to reset the password with
+    /// [`Vault::reset_password_with_recovery_key`].
+    pub fn recovery_key(&self) -> Result<RecoveryKey, VaultLocked> {
+        Ok(RecoveryKey::new(&*self.master_key()?))
+    }
+
+    /// Restore access to a vault using its recovery key, writing a new masterkey file that is
+    /// protected by the provided password. The old masterkey file is backed up if present.
+    pub fn reset_password_with_recovery_key(
+        config_path: impl AsRef<Path>,
+        recovery_key: &RecoveryKey,
+        new_password: impl Into<Passphrase>,
+    ) -> Result<Self> {
+        let jwt = fs::read_to_string(&config_path)?;
+        let master_key = recovery_key.master_key();
+        // The config signature tells us whether this is the right key for the vault
+        let config = verify_config(jwt, &master_key)
+            .wrap_err("recovery key does not match the vault config")?;
+
+        // TODO: Handle case with no parent?
+        let config_dir = config_path.as_ref().parent().unwrap().canonicalize()?;
+        let key_path = master_key_path(&config_dir, &config.header)?;
+
+        let params = Params::try_from(KdfParams::default())?;
+        let salt = random_salt()?;
+        let kek = util::derive_kek(&new_password.into(), params, salt.as_salt())?;
+        let wrapped_key = master_key.wrap(&kek, params, salt, MASTERKEY_FILE_VERSION)?;
+        replace_file(&key_path, |tmp_path| wrapped_key.to_file(tmp_path))?;
+        backup_file(&key_path)?;
+
+        Ok(Self {
+            path: config_dir.clone(),
+            config_dir,
+            config,
+            master_key: SharedKey::new(master_key),
+            masterkey_backup: None,
+            storage: Box::new(StdStorage),
+        })
+    }
+
+    /// If the vault was opened using a backup of its masterkey file because the primary file was
+    /// corrupt, this is the path of that backup.
+    pub fn masterkey_backup(&self) -> Option<&Path> {
+        self.masterkey_backup.as_deref()
+    }
+
+    /// The directory containing the encrypted data of the vault. This is the same as
+    /// [`Vault::config_dir`] unless the vault was opened with [`Vault::open_split`].
     pub fn path(&self) -> &Path {
         &self.path
     }
 
+    /// Where the encrypted data of the vault is kept. This is the local file system unless it was
+    /// changed with [`Vault::with_storage`].
+    pub fn storage(&self) -> &dyn VaultStorage {
+        &*self.storage
+    }
+
+    /// Keep the encrypted data of the vault in other storage, at the same paths under
+    /// [`Vault::path`]. The vault config and masterkey file are still read from disk.
+    pub fn with_storage(mut self, storage: impl VaultStorage + 'static) -> Self {
+        self.storage = Box::new(storage);
+        self
+    }
+
+    /// Copy the encrypted data of the vault from disk into a new [`MemoryStorage`] and keep it
+    /// there, so later changes through this vault never reach the disk.
+    pub fn into_memory(self) -> Result<Self> {
+        let storage = MemoryStorage::new();
+        storage.load(self.path.join("d"))?;
+        Ok(self.with_storage(storage))
+    }
+
+    /// The directory containing the vault config.
+    pub fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
     pub fn config(&self) -> &TokenData<VaultConfig> {
         &self.config
     }
 
-    pub fn master_key(&self) -> &MasterKey {
-        &self.master_key
+    /// The master key of the vault. It can't be wiped by [`Vault::lock`] while this is held, so
+    /// it shouldn't be kept around.
+    pub fn master_key(&self) -> Result<impl Deref<Target = MasterKey> + '_, VaultLocked> {
+        self.master_key.get()
     }
 
-    pub fn cryptor(&self) -> Cryptor {
+    /// A cryptor for the vault's cipher combo. Once the vault is locked, anything it does that
+    /// needs the master key fails with [`VaultLocked`].
+    pub fn cryptor(&self) -> Cryptor<'_> {
         match self.config().claims.cipher_combo {
             CipherCombo::SivCtrMac => {
-                Cryptor::SivCtrMac(siv_ctrmac::Cryptor::new(self.master_key()))
+                Cryptor::SivCtrMac(siv_ctrmac::Cryptor::shared(&self.master_key))
             }
-            CipherCombo::SivGcm => Cryptor::SivGcm(siv_gcm::Cryptor::new(self.master_key())),
+            CipherCombo::SivGcm => Cryptor::SivGcm(siv_gcm::Cryptor::shared(&self.master_key)),
+        }
+    }
+
+    /// Lock the vault, zeroizing the master key. This waits for anything that's encrypting or
+    /// decrypting to finish, and from then on, everything that needs the key fails with
+    /// [`VaultLocked`], including cryptors, file systems, and files that were opened before. The
+    /// vault can't be unlocked again, so it has to be reopened instead.
+    ///
+    /// Dropping the vault also zeroizes the key, but locking it works while it's still borrowed,
+    /// e.g. by a [`FuseFileSystem`](crate::fs::fuse::FuseFileSystem) that's being unmounted.
+    pub fn lock(&self) {
+        self.master_key.wipe();
+    }
+
+    /// Whether the vault has been [locked](Vault::lock).
+    pub fn is_locked(&self) -> bool {
+        self.master_key.is_wiped()
+    }
+}
+
+// The directory of a vault, given either the directory or its config, which may be missing.
+fn vault_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if path.is_file() || path.ends_with(CONFIG_FILE_NAME) => {
+            match parent.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => parent,
+            }
+        }
+        _ => path,
+    }
+}
+
+fn legacy_format(path: PathBuf, format: u32) -> VaultOpenError {
+    VaultOpenError::LegacyFormat { path, format }
+}
+
+fn master_key_path(vault_path: &Path, header: &Header) -> Result<PathBuf> {
+    let Some(master_key_uri) = header.kid.as_deref() else {
+        bail!("JWT header is missing `kid` claim");
+    };
+
+    match master_key_uri.split_once("masterkeyfile:") {
+        Some(("", file_name)) => Ok(vault_path.join(file_name)),
+        _ => bail!("unsupported key URI format: {master_key_uri}"),
+    }
+}
+
+fn verify_config<'k>(
+    jwt: String,
+    verifier: impl Into<JwtVerifier<'k>>,
+) -> Result<TokenData<VaultConfig>, VaultOpenError> {
+    // The key is picked based on the algorithm, so any of these can be allowed
+    let mut validation = Validation::default();
+    validation.algorithms = vec![
+        Algorithm::HS256,
+        Algorithm::HS384,
+        Algorithm::HS512,
+        Algorithm::ES256,
+        Algorithm::ES384,
+    ];
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+
+    // Verify the signature before looking at any claims, but check the format and cipher combo
+    // before deserializing so that unsupported vaults can be reported as such
+    let raw: TokenData<serde_json::Value> =
+        util::verify_jwt(jwt, validation, verifier).map_err(|err| match err {
+            JwtError::SignatureMismatch => VaultOpenError::InvalidConfigSignature,
+            _ => VaultOpenError::InvalidConfig(err.into()),
+        })?;
+
+    // TODO: Only version 8 is supported for now
+    match raw.claims.get("format").and_then(|f| f.as_u64()) {
+        Some(8) => {}
+        Some(other) => return Err(VaultOpenError::UnsupportedFormat(other as u32)),
+        None => return Err(VaultOpenError::InvalidConfig("missing vault format".into())),
+    }
+
+    if let Some(combo) = raw.claims.get("cipherCombo").and_then(|c| c.as_str()) {
+        if serde_json::from_value::<CipherCombo>(combo.into()).is_err() {
+            return Err(VaultOpenError::UnsupportedCipherCombo(combo.to_string()));
         }
     }
+
+    Ok(TokenData {
+        header: raw.header,
+        claims: serde_json::from_value(raw.claims)
+            .map_err(|err| VaultOpenError::InvalidConfig(err.into()))?,
+    })
+}
+
+/// Replace a file with new contents, keeping a backup of the old file if present.
+pub(crate) fn replace_file(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    // Write to a temporary file first, so the old file is only replaced once the new one is
+    // complete
+    let file_name = path
+        .file_name()
+        .ok_or_eyre("no file name")?
+        .to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{file_name}.{}.tmp", Uuid::new_v4()));
+    write(&tmp_path)?;
+
+    let mut result = Ok(());
+    if path.try_exists()? {
+        result = backup_file(path);
+    }
+    if result.is_ok() {
+        result = fs::rename(&tmp_path, path).map_err(Into::into);
+    }
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+fn random_salt() -> Result<SaltString> {
+    let mut salt_bytes = [0_u8; 8];
+    OsRng.try_fill_bytes(&mut salt_bytes)?;
+    Ok(SaltString::encode_b64(&salt_bytes)?)
+}
+
+/// Copy a file to a backup next to it, named the same way as the official Cryptomator app does:
+/// `<file name>.<first 4 bytes of SHA-256 digest, in hex>.bkup`.
+pub(crate) fn backup_file(path: &Path) -> Result<()> {
+    let contents = fs::read(path)?;
+    let digest = Sha256::digest(&contents);
+    let id: String = digest[..4].iter().map(|b| format!("{b:02X}")).collect();
+
+    let mut file_name = path.file_name().ok_or_eyre("no file name")?.to_os_string();
+    file_name.push(format!(".{id}.bkup"));
+    fs::write(path.with_file_name(file_name), contents)?;
+
+    Ok(())
+}
+
+/// Find the backups of a file created by [`backup_file`], most recently modified first.
+pub(crate) fn find_backups(path: &Path) -> Result<Vec<PathBuf>> {
+    let file_name = path
+        .file_name()
+        .ok_or_eyre("no file name")?
+        .to_string_lossy();
+    let Some(dir) = path.parent() else {
+        return Ok(Vec::new());
+    };
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_backup = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(file_name.as_ref()))
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|rest| rest.strip_suffix(".bkup"))
+            .is_some_and(|id| id.len() == 8 && id.bytes().all(|b| b.is_ascii_hexdigit()));
+
+        if is_backup {
+            backups.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+
+    backups.sort_by(|a, b| b.cmp(a));
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
 }
@@ -0,0 +1,69 @@
+// Kept in its own test binary, since the allocator counts allocations from every thread
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::{Read, Seek, SeekFrom, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use cryptomator::{crypto::siv_gcm, fs::EncryptedFile, util::ChunkBufPool, MasterKey};
+
+// Allocations at least this big are counted, which covers chunk buffers
+const LARGE: usize = 32 * 1024;
+
+static LARGE_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= LARGE {
+            LARGE_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_large_allocations(f: impl FnOnce()) -> usize {
+    let before = LARGE_ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    LARGE_ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+#[test]
+pub fn pooled_chunk_buffers() {
+    let key = MasterKey::random().unwrap();
+    let cryptor = siv_gcm::Cryptor::new(&key);
+    let dir = tempfile::tempdir().unwrap();
+    let mut file = EncryptedFile::create_new(cryptor, dir.path().join("file")).unwrap();
+    file.write_all(&vec![7; 16 * 32 * 1024]).unwrap();
+
+    let mut read_and_overwrite = || {
+        let mut chunk = [0; 4096];
+        for n in 0..16 {
+            file.seek(SeekFrom::Start(n * 32 * 1024)).unwrap();
+            file.read_exact(&mut chunk).unwrap();
+            file.seek(SeekFrom::Start(n * 32 * 1024)).unwrap();
+            file.write_all(&chunk).unwrap();
+        }
+    };
+
+    let pool = ChunkBufPool::global();
+    pool.set_max_idle(0);
+    let unpooled = count_large_allocations(&mut read_and_overwrite);
+
+    pool.set_max_idle(64);
+    read_and_overwrite();
+    let pooled = count_large_allocations(&mut read_and_overwrite);
+
+    // Each read and write used to allocate a ciphertext buffer of its own
+    assert!(pooled + 32 <= unpooled, "{pooled} vs {unpooled}");
+    assert!(pool.idle() > 0);
+}
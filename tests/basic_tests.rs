@@ -1,16 +1,37 @@
 use std::{
-    fs::{self, File},
-    io::{Read, Seek, Write},
-    path::PathBuf,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::{self, Permissions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::ControlFlow,
+    os::unix::{fs::PermissionsExt, net::UnixListener},
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
 };
 
-use base64ct::{Base64, Encoding};
+use base64ct::{Base64, Base64UrlUnpadded, Encoding};
 use cryptomator::{
-    crypto::FileCryptor, fs::EncryptedFile, util, CipherCombo, MasterKey, Vault, VaultConfig,
+    crypto::{siv_gcm, DirId, FileCryptor},
+    fs::{
+        EncryptedFile, EncryptedFileSystem, EntryErrorReason, ExportOptions, FileKind, FsError,
+        ImportOptions, OrphanDir, StatsOptions, Walk, WalkOrder,
+    },
+    fsck::{CheckOptions, IntegrityReport, IssueKind, RepairAction, RepairOptions},
+    storage::{FileTimes, MemoryStorage, Metadata, OpenOptions, StorageFile, VaultStorage},
+    testing::copy_dir,
+    util::{self, JwtError},
+    CipherCombo, KdfParams, MasterKey, MasterkeyFileLoader, MasterkeyLoader, Passphrase,
+    RawKeyLoader, RecoveryKey, Vault, VaultConfig, VaultCreateOptions, VaultKind, VaultLocked,
+    VaultOpenError, WordList, WrappedKey,
 };
-use jsonwebtoken::{TokenData, Validation};
+use jsonwebtoken::{jwk::Jwk, Algorithm, DecodingKey, TokenData, Validation};
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 #[test]
 pub fn siv_ctrmac_basic() {
@@ -31,26 +52,26 @@ pub fn siv_ctrmac_basic() {
         VaultConfig {
             jti: Uuid::from_str("3c34938f-8acb-4c41-9a48-7a8f3c42835a").unwrap(),
             format: 8,
+            cipher_combo: CipherCombo::SivCtrMac,
             shortening_threshold: 220,
-            cipher_combo: CipherCombo::SivCtrMac
+            extra: Default::default(),
         }
     );
 
     // Check key import
-    let key = vault.master_key();
+    let key = &*vault.master_key().unwrap();
 
-    assert_eq!(*key, unsafe {
-        MasterKey::from_bytes(
-            Base64::decode_vec("6RqWrWltqvYqQAowjweyJs8Hq/45NL3t/yIB/gVcubF8id+XIsrTnr7qfnd2YKLP/otupwsBCC+jaoIiduSxlw==")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-        )
-    });
+    let expected: [u8; 64] = Base64::decode_vec(
+        "6RqWrWltqvYqQAowjweyJs8Hq/45NL3t/yIB/gVcubF8id+XIsrTnr7qfnd2YKLP/otupwsBCC+jaoIiduSxlw==",
+    )
+    .unwrap()
+    .try_into()
+    .unwrap();
+    assert_eq!(*key, MasterKey::from_raw(Zeroizing::new(expected)).unwrap());
 
     // Check JWT signing/verifying
     let config_jwt =
-        util::sign_jwt(vault.config().header.clone(), vault.config().claims, key).unwrap();
+        util::sign_jwt(vault.config().header.clone(), &vault.config().claims, key).unwrap();
 
     let mut validation = Validation::new(vault.config().header.alg);
     validation.validate_exp = false;
@@ -71,7 +92,9 @@ pub fn siv_ctrmac_basic() {
         "test_file.txt"
     );
     assert_eq!(
-        cryptor.encrypt_name("test_file.txt", "").unwrap(),
+        cryptor
+            .encrypt_name(OsStr::new("test_file.txt"), "")
+            .unwrap(),
         "TKDIJ1vsa0Tp5ZCcUudycUuYTcz17tdgI489pGU="
     );
 
@@ -86,7 +109,10 @@ pub fn siv_ctrmac_basic() {
     );
     assert_eq!(
         cryptor
-            .encrypt_name("test_file_2.txt", "68fdafca-2315-4840-87bc-19c48baf897f")
+            .encrypt_name(
+                OsStr::new("test_file_2.txt"),
+                "68fdafca-2315-4840-87bc-19c48baf897f"
+            )
             .unwrap(),
         "3ZnmWpMsMPllwZCto1Gb0R7JvkiWcuV1Kmk6aczQPQ==",
     );
@@ -128,7 +154,7 @@ pub fn siv_ctrmac_basic() {
     assert_eq!(&ciphertext[88..], b"");
 
     assert_eq!(
-        cryptor.hash_dir_id("").unwrap(),
+        cryptor.hash_dir_id(&DirId::root()).unwrap(),
         PathBuf::from("B3").join("EO5WWODTDD254SS2TQWVAQKJAWPBKK")
     );
 
@@ -153,7 +179,7 @@ pub fn siv_ctrmac_basic() {
 
     assert_eq!(
         cryptor
-            .hash_dir_id("68fdafca-2315-4840-87bc-19c48baf897f")
+            .hash_dir_id(&DirId::parse("68fdafca-2315-4840-87bc-19c48baf897f").unwrap())
             .unwrap(),
         PathBuf::from("QQ").join("I7Q3TUGAZFNCXWWEXUSOJS7PQ4K4HE")
     );
@@ -162,7 +188,7 @@ pub fn siv_ctrmac_basic() {
     let mut file = EncryptedFile::open(
         cryptor,
         "tests/fixtures/vault_v8_siv_ctrmac/d/B3/EO5WWODTDD254SS2TQWVAQKJAWPBKK/TKDIJ1vsa0Tp5ZCcUudycUuYTcz17tdgI489pGU=.c9r",
-        File::options().read(true).clone()
+        OpenOptions::new().read(true).clone()
     )
     .unwrap();
 
@@ -174,7 +200,7 @@ pub fn siv_ctrmac_basic() {
     let mut file = EncryptedFile::open(
         cryptor,
         "tests/fixtures/vault_v8_siv_ctrmac/d/B3/EO5WWODTDD254SS2TQWVAQKJAWPBKK/elqiMLEIVhXP94ydJeId4vavM_9rPv380wdMYzwg.c9r",
-        File::options().read(true).clone()
+        OpenOptions::new().read(true).clone()
     )
     .unwrap();
 
@@ -247,26 +273,26 @@ pub fn siv_gcm_basic() {
         VaultConfig {
             jti: Uuid::from_str("46cd26de-d575-4563-ad30-432123f85f36").unwrap(),
             format: 8,
+            cipher_combo: CipherCombo::SivGcm,
             shortening_threshold: 220,
-            cipher_combo: CipherCombo::SivGcm
+            extra: Default::default(),
         }
     );
 
     // Check key import
-    let key = vault.master_key();
+    let key = &*vault.master_key().unwrap();
 
-    assert_eq!(*key, unsafe {
-        MasterKey::from_bytes(
-            Base64::decode_vec("sXs8e6rKQX3iySTUkOd6V0FqaM3nqN/x8ULcUYdtBXQBSSDBbf8FEBAkUuGhpqot8leMQTfevZKICb7t8voIOQ==")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-        )
-    });
+    let expected: [u8; 64] = Base64::decode_vec(
+        "sXs8e6rKQX3iySTUkOd6V0FqaM3nqN/x8ULcUYdtBXQBSSDBbf8FEBAkUuGhpqot8leMQTfevZKICb7t8voIOQ==",
+    )
+    .unwrap()
+    .try_into()
+    .unwrap();
+    assert_eq!(*key, MasterKey::from_raw(Zeroizing::new(expected)).unwrap());
 
     // Check JWT signing/verifying
     let config_jwt =
-        util::sign_jwt(vault.config().header.clone(), vault.config().claims, key).unwrap();
+        util::sign_jwt(vault.config().header.clone(), &vault.config().claims, key).unwrap();
 
     let mut validation = Validation::new(vault.config().header.alg);
     validation.validate_exp = false;
@@ -287,7 +313,9 @@ pub fn siv_gcm_basic() {
         "test_file.txt"
     );
     assert_eq!(
-        cryptor.encrypt_name("test_file.txt", "").unwrap(),
+        cryptor
+            .encrypt_name(OsStr::new("test_file.txt"), "")
+            .unwrap(),
         "AlBBrYyQQqFiMXocarsNhcWd2oQ0yyRu86LZdZw="
     );
 
@@ -302,7 +330,10 @@ pub fn siv_gcm_basic() {
     );
     assert_eq!(
         cryptor
-            .encrypt_name("test_file_2.txt", "1a3534ba-34fb-4ba6-ad67-1e37627d40be")
+            .encrypt_name(
+                OsStr::new("test_file_2.txt"),
+                "1a3534ba-34fb-4ba6-ad67-1e37627d40be"
+            )
             .unwrap(),
         "j2O1bILonFELjBCQTaqZEBgfUh1_uHvXjOdMdc2ZEg=="
     );
@@ -344,7 +375,7 @@ pub fn siv_gcm_basic() {
     assert_eq!(&ciphertext[68..], b"");
 
     assert_eq!(
-        cryptor.hash_dir_id("").unwrap(),
+        cryptor.hash_dir_id(&DirId::root()).unwrap(),
         PathBuf::from("RC").join("WG5EI3VR4DOIGAFUPFXLALP5SBGCL5")
     );
 
@@ -370,7 +401,7 @@ pub fn siv_gcm_basic() {
 
     assert_eq!(
         cryptor
-            .hash_dir_id("1a3534ba-34fb-4ba6-ad67-1e37627d40be")
+            .hash_dir_id(&DirId::parse("1a3534ba-34fb-4ba6-ad67-1e37627d40be").unwrap())
             .unwrap(),
         PathBuf::from("RT").join("C3KT7DD5C3X6QE32X4IL6PM6WHHNB5")
     );
@@ -379,7 +410,7 @@ pub fn siv_gcm_basic() {
     let mut file = EncryptedFile::open(
         cryptor,
         "tests/fixtures/vault_v8_siv_gcm/d/RC/WG5EI3VR4DOIGAFUPFXLALP5SBGCL5/AlBBrYyQQqFiMXocarsNhcWd2oQ0yyRu86LZdZw=.c9r",
-        File::options().read(true).clone()
+        OpenOptions::new().read(true).clone()
     )
     .unwrap();
 
@@ -391,7 +422,7 @@ pub fn siv_gcm_basic() {
     let mut file = EncryptedFile::open(
         cryptor,
         "tests/fixtures/vault_v8_siv_gcm/d/RC/WG5EI3VR4DOIGAFUPFXLALP5SBGCL5/LNyfONa3J2M1pirw-S-YBasDwUyV7RyhSwz7oMlP.c9r",
-        File::options().read(true).clone()
+        OpenOptions::new().read(true).clone()
     )
     .unwrap();
 
@@ -444,3 +475,2186 @@ pub fn siv_gcm_basic() {
 
     fs::remove_file("tests/test_larger_siv_gcm.jpg").unwrap();
 }
+
+#[test]
+pub fn detached_header() {
+    for (vault_dir, file_path) in [
+        (
+            "tests/fixtures/vault_v8_siv_ctrmac",
+            "d/B3/EO5WWODTDD254SS2TQWVAQKJAWPBKK/elqiMLEIVhXP94ydJeId4vavM_9rPv380wdMYzwg.c9r",
+        ),
+        (
+            "tests/fixtures/vault_v8_siv_gcm",
+            "d/RC/WG5EI3VR4DOIGAFUPFXLALP5SBGCL5/LNyfONa3J2M1pirw-S-YBasDwUyV7RyhSwz7oMlP.c9r",
+        ),
+    ] {
+        let vault = Vault::open(
+            PathBuf::from(vault_dir).join("vault.cryptomator"),
+            String::from("password"),
+        )
+        .unwrap();
+        let cryptor = vault.cryptor();
+        let ciphertext = fs::read(PathBuf::from(vault_dir).join(file_path)).unwrap();
+
+        // Split the ciphertext into a standalone header and a body file
+        let (encrypted_header, body) = ciphertext.split_at(cryptor.encrypted_header_len());
+        let header = cryptor.decrypt_header(encrypted_header).unwrap();
+        let body_path = PathBuf::from(format!(
+            "tests/test_detached_{}.c9r",
+            vault_dir.rsplit('_').next().unwrap()
+        ));
+        fs::write(&body_path, body).unwrap();
+
+        // Read the body using the detached header
+        let mut file = EncryptedFile::open_detached(
+            cryptor,
+            header,
+            &body_path,
+            OpenOptions::new().read(true).clone(),
+        )
+        .unwrap();
+
+        let mut decrypted = Vec::new();
+        file.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(
+            decrypted,
+            fs::read("tests/fixtures/test_image.jpg").unwrap()
+        );
+        assert_eq!(file.len().unwrap(), decrypted.len() as u64);
+
+        // Re-emitting the header and reassembling gives back the original file
+        let mut reassembled = file.encrypted_header().unwrap();
+        reassembled.extend(fs::read(&body_path).unwrap());
+        assert_eq!(reassembled, ciphertext);
+
+        fs::remove_file(&body_path).unwrap();
+    }
+}
+
+#[test]
+pub fn create_vault() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaultCreateOptions {
+        cipher_combo: CipherCombo::SivCtrMac,
+        shortening_threshold: 180,
+        // Cheap parameters, to keep the test fast
+        kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+        scrypt_salt: None,
+    };
+
+    let created = Vault::create(dir.path(), String::from("password"), options.clone()).unwrap();
+    assert_eq!(created.path(), dir.path().canonicalize().unwrap());
+    assert_eq!(created.config().claims.format, 8);
+    assert_eq!(created.config().claims.shortening_threshold, 180);
+    assert_eq!(created.config().claims.cipher_combo, CipherCombo::SivCtrMac);
+
+    // The root directory and its backup dir ID should exist
+    let root_dir = dir
+        .path()
+        .join("d")
+        .join(created.cryptor().hash_dir_id(&DirId::root()).unwrap());
+    assert_eq!(
+        fs::metadata(root_dir.join("dirid.c9r")).unwrap().len(),
+        created.cryptor().encrypted_header_len() as u64
+    );
+
+    // Creating over an existing vault should fail
+    assert!(Vault::create(dir.path(), String::from("password"), options).is_err());
+
+    // Re-opening the vault should give back the same key and config
+    let opened = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    assert_eq!(
+        *opened.master_key().unwrap(),
+        *created.master_key().unwrap()
+    );
+    assert_eq!(opened.config().header, created.config().header);
+    assert_eq!(opened.config().claims, created.config().claims);
+    assert!(Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("wrong password"),
+    )
+    .is_err());
+}
+
+#[test]
+pub fn change_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaultCreateOptions {
+        kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+        ..Default::default()
+    };
+    let mut vault = Vault::create(dir.path(), String::from("old password"), options).unwrap();
+    let config_path = dir.path().join("vault.cryptomator");
+    let key_path = dir.path().join("masterkey.cryptomator");
+    let old_config = fs::read(&config_path).unwrap();
+    let old_key_file = fs::read(&key_path).unwrap();
+
+    // The wrong old password should be rejected without touching anything
+    assert!(vault
+        .change_password(String::from("wrong"), String::from("new password"))
+        .is_err());
+    assert_eq!(fs::read(&key_path).unwrap(), old_key_file);
+
+    vault
+        .change_password(String::from("old password"), String::from("new password"))
+        .unwrap();
+
+    // The config is signed with the raw master key, which does not change
+    assert_eq!(fs::read(&config_path).unwrap(), old_config);
+    assert_ne!(fs::read(&key_path).unwrap(), old_key_file);
+
+    // Both the old and new key files are backed up, and no temporary files are left behind
+    let mut backups: Vec<_> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name != "d" && !name.ends_with(".cryptomator"))
+        .map(|name| {
+            assert!(name.starts_with("masterkey.cryptomator.") && name.ends_with(".bkup"));
+            fs::read(dir.path().join(name)).unwrap()
+        })
+        .collect();
+    backups.sort();
+    let mut expected = vec![old_key_file, fs::read(&key_path).unwrap()];
+    expected.sort();
+    assert_eq!(backups, expected);
+
+    assert!(Vault::open(&config_path, String::from("old password")).is_err());
+    let reopened = Vault::open(&config_path, String::from("new password")).unwrap();
+    assert_eq!(
+        *reopened.master_key().unwrap(),
+        *vault.master_key().unwrap()
+    );
+}
+
+#[test]
+pub fn recovery_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaultCreateOptions {
+        kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+        ..Default::default()
+    };
+    let vault = Vault::create(dir.path(), String::from("forgotten"), options).unwrap();
+    let config_path = dir.path().join("vault.cryptomator");
+
+    // Stand-in for the official word list, which is not bundled with this crate
+    let word_list =
+        WordList::new(&(0..4096).map(|i| format!("w{i}\n")).collect::<String>()).unwrap();
+    let encoded = vault.recovery_key().unwrap().encode(&word_list);
+    let recovery_key = RecoveryKey::parse(&encoded, &word_list).unwrap();
+
+    // A recovery key for a different vault should be rejected
+    let other_dir = tempfile::tempdir().unwrap();
+    fs::copy(&config_path, other_dir.path().join("vault.cryptomator")).unwrap();
+    let other_key = RecoveryKey::new(&MasterKey::random().unwrap());
+    assert!(Vault::reset_password_with_recovery_key(
+        other_dir.path().join("vault.cryptomator"),
+        &other_key,
+        String::from("new password"),
+    )
+    .is_err());
+    assert!(!other_dir.path().join("masterkey.cryptomator").exists());
+
+    let reset = Vault::reset_password_with_recovery_key(
+        &config_path,
+        &recovery_key,
+        String::from("new password"),
+    )
+    .unwrap();
+    assert_eq!(*reset.master_key().unwrap(), *vault.master_key().unwrap());
+    assert_eq!(reset.config().claims, vault.config().claims);
+
+    assert!(Vault::open(&config_path, String::from("forgotten")).is_err());
+    let reopened = Vault::open(&config_path, String::from("new password")).unwrap();
+    assert_eq!(
+        *reopened.master_key().unwrap(),
+        *vault.master_key().unwrap()
+    );
+}
+
+#[test]
+pub fn lock_vault() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+    let cryptor = vault.cryptor();
+    let mut options = OpenOptions::new();
+    options.read(true);
+    let ciphertext_path = fs.ciphertext_path("/test_file.txt").unwrap();
+    let mut file = fs
+        .open_ciphertext(&ciphertext_path, options.clone())
+        .unwrap();
+    assert!(fs.dir_entries("/").is_ok());
+    assert!(!vault.is_locked());
+
+    // Everything that was made before locking stops working too
+    fs.lock_vault();
+    assert!(vault.is_locked());
+    assert_eq!(vault.master_key().err(), Some(VaultLocked));
+    assert_eq!(vault.recovery_key().err(), Some(VaultLocked));
+    assert!(cryptor
+        .hash_dir_id(&DirId::root())
+        .unwrap_err()
+        .is::<VaultLocked>());
+    assert!(matches!(fs.dir_entries("/"), Err(FsError::VaultLocked)));
+    assert!(matches!(
+        FsError::from(fs.ciphertext_path("/test_dir").unwrap_err()),
+        FsError::VaultLocked
+    ));
+    let err = file.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(matches!(FsError::from(err), FsError::VaultLocked));
+    let Err(err) = fs.open_ciphertext(&ciphertext_path, options) else {
+        panic!("opened a file in a locked vault");
+    };
+    assert!(matches!(FsError::from(err), FsError::VaultLocked));
+
+    // Locking again does nothing
+    vault.lock();
+    assert!(vault.is_locked());
+}
+
+#[test]
+pub fn raw_key_loader() {
+    let key_bytes: [u8; 64] = Base64::decode_vec(
+        "sXs8e6rKQX3iySTUkOd6V0FqaM3nqN/x8ULcUYdtBXQBSSDBbf8FEBAkUuGhpqot8leMQTfevZKICb7t8voIOQ==",
+    )
+    .unwrap()
+    .try_into()
+    .unwrap();
+    let config_path = "tests/fixtures/vault_v8_siv_gcm/vault.cryptomator";
+
+    // Safe, this is for test purposes only
+    let loader = unsafe { RawKeyLoader::new(key_bytes) };
+    let vault = Vault::open_with_loader(config_path, &loader).unwrap();
+    assert_eq!(*vault.master_key().unwrap(), loader.load("").unwrap());
+    assert_eq!(vault.config().claims.cipher_combo, CipherCombo::SivGcm);
+    assert_eq!(
+        vault.config().header.kid.as_deref(),
+        Some("masterkeyfile:masterkey.cryptomator")
+    );
+
+    // The wrong key fails to verify the config
+    let mut wrong_bytes = key_bytes;
+    wrong_bytes[0] ^= 1;
+    let wrong_loader = unsafe { RawKeyLoader::new(wrong_bytes) };
+    assert!(Vault::open_with_loader(config_path, &wrong_loader).is_err());
+
+    // Loaders that don't support the key ID are rejected
+    struct NoKeys;
+    impl MasterkeyLoader for NoKeys {
+        fn supports(&self, key_id: &str) -> bool {
+            key_id.starts_with("hub+https:")
+        }
+
+        fn load(&self, _key_id: &str) -> cryptomator::Result<MasterKey> {
+            unreachable!()
+        }
+    }
+    assert!(Vault::open_with_loader(config_path, &NoKeys).is_err());
+}
+
+#[test]
+pub fn ec_signed_config() {
+    let fixture_dir = Path::new("tests/fixtures/vault_v8_ec_signed");
+    let config_path = fixture_dir.join("vault.cryptomator");
+    let public_key = |name| {
+        let jwk: Jwk =
+            serde_json::from_str(&fs::read_to_string(fixture_dir.join(name)).unwrap()).unwrap();
+        DecodingKey::from_jwk(&jwk).unwrap()
+    };
+
+    // Like a Cryptomator Hub vault, where the config is signed with an EC key
+    struct HubLoader(Option<DecodingKey>);
+    impl MasterkeyLoader for HubLoader {
+        fn supports(&self, key_id: &str) -> bool {
+            key_id.starts_with("hub+https:")
+        }
+
+        fn load(&self, _key_id: &str) -> cryptomator::Result<MasterKey> {
+            MasterKey::new([1; 32], [2; 32])
+        }
+
+        fn config_public_key(&self, _key_id: &str) -> cryptomator::Result<Option<DecodingKey>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    let loader = HubLoader(Some(public_key("public_key.json")));
+    let vault = Vault::open_with_loader(&config_path, &loader).unwrap();
+    assert_eq!(vault.config().header.alg, Algorithm::ES256);
+    assert_eq!(vault.config().claims.cipher_combo, CipherCombo::SivGcm);
+    assert_eq!(vault.config().claims.shortening_threshold, 220);
+
+    let wrong_loader = HubLoader(Some(public_key("public_key_es384.json")));
+    assert!(matches!(
+        Vault::open_with_loader(&config_path, &wrong_loader),
+        Err(VaultOpenError::InvalidConfigSignature)
+    ));
+
+    // Without a public key, there's nothing to verify the signature with
+    let Err(VaultOpenError::InvalidConfig(err)) =
+        Vault::open_with_loader(&config_path, &HubLoader(None))
+    else {
+        panic!("expected an invalid config");
+    };
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(JwtError::MissingKey(Algorithm::ES256))
+    ));
+}
+
+#[test]
+pub fn vault_open_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaultCreateOptions {
+        kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+        ..Default::default()
+    };
+    let vault = Vault::create(dir.path(), String::from("password"), options).unwrap();
+    let config_path = dir.path().join("vault.cryptomator");
+    let original_config = fs::read_to_string(&config_path).unwrap();
+    let open = || Vault::open(&config_path, String::from("password"));
+
+    assert!(matches!(
+        Vault::open(&config_path, String::from("wrong")),
+        Err(VaultOpenError::WrongPassword)
+    ));
+
+    // Re-sign the config with doctored claims
+    let resign = |key: &str, value: serde_json::Value| {
+        let mut claims = serde_json::to_value(&vault.config().claims).unwrap();
+        claims[key] = value;
+        let jwt = util::sign_jwt(
+            vault.config().header.clone(),
+            claims,
+            &vault.master_key().unwrap(),
+        )
+        .unwrap();
+        fs::write(&config_path, jwt).unwrap();
+    };
+
+    resign("format", 9.into());
+    assert!(matches!(open(), Err(VaultOpenError::UnsupportedFormat(9))));
+
+    resign("cipherCombo", "SIV_CBC".into());
+    assert!(matches!(
+        open(),
+        Err(VaultOpenError::UnsupportedCipherCombo(combo)) if combo == "SIV_CBC"
+    ));
+
+    // Flip a bit in the signature
+    let (payload, signature) = original_config.rsplit_once('.').unwrap();
+    let mut signature = Base64UrlUnpadded::decode_vec(signature).unwrap();
+    signature[0] ^= 1;
+    fs::write(
+        &config_path,
+        format!("{payload}.{}", Base64UrlUnpadded::encode_string(&signature)),
+    )
+    .unwrap();
+    assert!(matches!(
+        open(),
+        Err(VaultOpenError::InvalidConfigSignature)
+    ));
+
+    fs::write(&config_path, &original_config).unwrap();
+    assert!(open().is_ok());
+
+    fs::remove_file(dir.path().join("masterkey.cryptomator")).unwrap();
+    assert!(matches!(
+        open(),
+        Err(VaultOpenError::MissingMasterkeyFile(path)) if path.ends_with("masterkey.cryptomator")
+    ));
+
+    // With only the data directory left, the masterkey file is what's reported missing
+    fs::remove_file(&config_path).unwrap();
+    assert!(matches!(
+        open(),
+        Err(VaultOpenError::MissingMasterkeyFile(path)) if path.ends_with("masterkey.cryptomator")
+    ));
+}
+
+#[test]
+pub fn vault_detect() {
+    let dir = tempfile::tempdir().unwrap();
+    let fixtures = Path::new("tests/fixtures");
+    let parent = dir.path().join("vaults");
+    let names = ["vault_v6", "vault_v7", "vault_v8_siv_gcm"];
+    for name in names {
+        copy_dir(fixtures.join(name), parent.join(name)).unwrap();
+    }
+    let (v6, v7, v8) = (
+        parent.join("vault_v6"),
+        parent.join("vault_v7"),
+        parent.join("vault_v8_siv_gcm"),
+    );
+    let detect = |path: &Path| Vault::detect(path).unwrap();
+
+    assert_eq!(detect(&v6), VaultKind::Format6);
+    assert_eq!(detect(&v7), VaultKind::Format7);
+    assert_eq!(detect(&v8), VaultKind::Format8);
+    assert_eq!(detect(&v8.join("vault.cryptomator")), VaultKind::Format8);
+    // Keys from Hub aren't expected to be in a masterkey file
+    assert_eq!(
+        detect(&fixtures.join("vault_v8_ec_signed")),
+        VaultKind::Format8
+    );
+    Vault::open(&v8, "password").unwrap();
+    assert!(matches!(
+        Vault::open(&v7, "password"),
+        Err(VaultOpenError::LegacyFormat { format: 7, .. })
+    ));
+    assert!(matches!(
+        Vault::open(v6.join("vault.cryptomator"), "password"),
+        Err(VaultOpenError::LegacyFormat { format: 6, path }) if path == v6
+    ));
+
+    // Picking the directory that contains the vault is an easy mistake to make
+    let vaults = names.map(|name| parent.join(name)).to_vec();
+    assert_eq!(
+        detect(&parent),
+        VaultKind::PossiblyVaultParentDirectory {
+            vaults: vaults.clone()
+        }
+    );
+    let err = Vault::open(&parent, "password").unwrap_err();
+    assert!(err.to_string().contains("vault_v8_siv_gcm"));
+    assert!(matches!(
+        err,
+        VaultOpenError::VaultParentDirectory { vaults: found, .. } if found == vaults
+    ));
+
+    fs::remove_file(v8.join("masterkey.cryptomator")).unwrap();
+    assert_eq!(detect(&v8), VaultKind::MissingMasterkey);
+    fs::remove_file(v8.join("vault.cryptomator")).unwrap();
+    assert_eq!(detect(&v8), VaultKind::MissingMasterkey);
+    assert!(matches!(
+        Vault::open(&v8, "password"),
+        Err(VaultOpenError::MissingMasterkeyFile(path)) if path.ends_with("masterkey.cryptomator")
+    ));
+
+    let random = dir.path().join("random");
+    fs::create_dir(&random).unwrap();
+    fs::write(random.join("notes.txt"), "not a vault").unwrap();
+    fs::create_dir(random.join("photos")).unwrap();
+    assert_eq!(detect(&random), VaultKind::NotAVault);
+    let err = Vault::open(random.join("vault.cryptomator"), "password").unwrap_err();
+    assert!(err.to_string().contains("vault.cryptomator"));
+    assert!(matches!(err, VaultOpenError::NotAVault(path) if path == random));
+    assert!(Vault::detect(dir.path().join("missing")).is_err());
+}
+
+#[test]
+pub fn resign_config_preserves_claims() {
+    let key_bytes: [u8; 64] = Base64::decode_vec(
+        "sXs8e6rKQX3iySTUkOd6V0FqaM3nqN/x8ULcUYdtBXQBSSDBbf8FEBAkUuGhpqot8leMQTfevZKICb7t8voIOQ==",
+    )
+    .unwrap()
+    .try_into()
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("vault.cryptomator");
+    let original =
+        fs::read_to_string("tests/fixtures/vault_v8_extra_claims/vault.cryptomator").unwrap();
+    fs::write(&config_path, &original).unwrap();
+
+    // Safe, this is for test purposes only
+    let loader = unsafe { RawKeyLoader::new(key_bytes) };
+    let vault = Vault::open_with_loader(&config_path, &loader).unwrap();
+    assert_eq!(vault.config().claims.extra["vendor"], "example");
+    assert_eq!(
+        vault.config().claims.extra["features"],
+        serde_json::json!({ "sharing": true, "tags": ["a", "b"] })
+    );
+
+    vault.resign_config().unwrap();
+
+    // The claims are re-encoded byte-for-byte, and the new config can still be opened
+    let resigned = fs::read_to_string(&config_path).unwrap();
+    assert_eq!(
+        resigned.split('.').nth(1).unwrap(),
+        original.split('.').nth(1).unwrap()
+    );
+    let reopened = Vault::open_with_loader(&config_path, &loader).unwrap();
+    assert_eq!(reopened.config().claims, vault.config().claims);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+pub fn integrity_check() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    let deep = CheckOptions { deep: true };
+
+    let mut progress_calls = 0;
+    let report = vault
+        .check_with_progress(deep, |_| progress_calls += 1)
+        .unwrap();
+    assert!(report.is_ok(), "{:?}", report.issues);
+    assert_eq!(report.directories_checked, 3);
+    assert_eq!(report.files_checked, 4);
+    assert_eq!(report.symlinks_checked, 2);
+    assert_eq!(progress_calls, 3 + 8);
+
+    // Corrupt the vault in various ways
+    let d = dir.path().join("d");
+    let image =
+        d.join("RC/WG5EI3VR4DOIGAFUPFXLALP5SBGCL5/LNyfONa3J2M1pirw-S-YBasDwUyV7RyhSwz7oMlP.c9r");
+    let mut bytes = fs::read(&image).unwrap();
+    bytes[1000] ^= 1;
+    // Somewhere in the fourth chunk, after the header and three encrypted chunks
+    bytes[68 + 3 * (32 * 1024 + 28) + 100] ^= 1;
+    fs::write(&image, bytes).unwrap();
+
+    let truncated =
+        d.join("RC/WG5EI3VR4DOIGAFUPFXLALP5SBGCL5/AlBBrYyQQqFiMXocarsNhcWd2oQ0yyRu86LZdZw=.c9r");
+    fs::write(&truncated, b"too short").unwrap();
+
+    fs::write(d.join("RC/WG5EI3VR4DOIGAFUPFXLALP5SBGCL5/notes.txt"), b"hi").unwrap();
+    fs::remove_file(d.join("4O/XVJ6TTE3ZDMSW4MFMJJBQTIJCDR3BY/dirid.c9r")).unwrap();
+    fs::write(
+        d.join("RT/C3KT7DD5C3X6QE32X4IL6PM6WHHNB5/xxnLPC-aOBj_nn5vdWzSIhuWris=.c9s/name.c9s"),
+        "AAAAAAAAAAAAAAAAAAAAAAAA.c9r",
+    )
+    .unwrap();
+    fs::create_dir_all(d.join("ZZ/ORPHANEDDIRECTORYABCDEFGHIJKLM")).unwrap();
+
+    let kinds = |report: IntegrityReport| {
+        let mut kinds: Vec<_> = report
+            .issues
+            .iter()
+            .map(|i| format!("{:?}", i.kind))
+            .collect();
+        kinds.sort();
+        kinds
+    };
+
+    assert_eq!(
+        kinds(vault.check(deep).unwrap()),
+        [
+            "CorruptContent",
+            "CorruptHeader",
+            "InvalidName",
+            "MissingDirIdBackup",
+            "OrphanedDirectory",
+            "ShortenedNameMismatch"
+        ]
+    );
+
+    // The JSON report has a severity and suggested fix for each issue
+    let report = vault.check(deep).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(json["files_checked"], 4);
+    let issues = json["issues"].as_array().unwrap();
+    assert_eq!(issues.len(), 6);
+    let issue = |kind: &str| {
+        issues
+            .iter()
+            .find(|issue| issue["kind"] == kind)
+            .unwrap_or_else(|| panic!("no {kind} issue"))
+    };
+    let content_issue = issue("corrupt_content");
+    assert_eq!(content_issue["severity"], "error");
+    assert_eq!(content_issue["ciphertext_path"], image.to_str().unwrap());
+    assert!(content_issue["cleartext_path"].is_string());
+    assert_eq!(
+        content_issue["fix"],
+        serde_json::json!({ "action": "damaged_chunk", "chunks": [0, 3] })
+    );
+    let orphan_issue = issue("orphaned_directory");
+    assert_eq!(orphan_issue["severity"], "warning");
+    assert_eq!(orphan_issue["cleartext_path"], serde_json::Value::Null);
+    assert_eq!(orphan_issue["fix"]["action"], "orphan_dir");
+    assert_eq!(
+        issue("missing_dir_id_backup")["fix"]["action"],
+        "missing_dirid"
+    );
+    assert_eq!(issue("invalid_name")["fix"], serde_json::Value::Null);
+
+    let pretty = report.to_pretty_string();
+    assert!(pretty.starts_with("Checked 3 directories, 4 files, and 1 symlinks: 6 issues found\n"));
+    assert_eq!(pretty.lines().count(), 7);
+    assert!(pretty.contains("error: CorruptContent: "));
+
+    // Only a deep check looks at file contents
+    let report = vault.check(CheckOptions::default()).unwrap();
+    let header_issue = report
+        .issues
+        .iter()
+        .find(|i| i.kind == IssueKind::CorruptHeader)
+        .unwrap();
+    assert_eq!(header_issue.ciphertext_path, truncated);
+    assert!(header_issue.cleartext_path.is_some());
+    assert!(!report
+        .issues
+        .iter()
+        .any(|i| i.kind == IssueKind::CorruptContent));
+    assert_eq!(report.issues.len(), 5);
+
+    // The vault is checked through its storage, so a copy in memory finds the same issues
+    let expected = kinds(vault.check(deep).unwrap());
+    let vault = vault.into_memory().unwrap();
+    fs::remove_dir_all(&d).unwrap();
+    assert_eq!(kinds(vault.check(deep).unwrap()), expected);
+}
+
+#[test]
+pub fn vault_repair() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path().join("vault")).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault/vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+    let storage = vault.storage();
+    let d = vault.path().join("d");
+    let read = |path: &Path| {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        fs.open_ciphertext(fs.ciphertext_path(path).unwrap(), options)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    };
+    // An orphan that can be adopted, since its dirid.c9r is intact
+    let plaintext = dir.path().join("plaintext");
+    fs::create_dir_all(plaintext.join("lost")).unwrap();
+    fs::write(plaintext.join("lost/found.txt"), b"found").unwrap();
+    fs::write(plaintext.join("full.bin"), [7; 32 * 1024]).unwrap();
+    fs.import_dir(&plaintext, "/", ImportOptions::default())
+        .unwrap();
+    storage
+        .remove_dir_all(&fs.ciphertext_path("/lost").unwrap())
+        .unwrap();
+
+    let root_dir = d.join("RC/WG5EI3VR4DOIGAFUPFXLALP5SBGCL5");
+    storage.remove_dir_all(&d.join("4O")).unwrap();
+    storage.remove_file(&root_dir.join("dirid.c9r")).unwrap();
+    storage
+        .write(
+            &d.join("RT/C3KT7DD5C3X6QE32X4IL6PM6WHHNB5/dirid.c9r"),
+            b"junk",
+        )
+        .unwrap();
+    storage.write(&root_dir.join("notes.txt"), b"hi").unwrap();
+    let file = fs.ciphertext_path("/test_file.txt").unwrap();
+    let conflict = file.with_file_name(format!(
+        "{} (1).c9r",
+        file.file_stem().unwrap().to_str().unwrap()
+    ));
+    storage
+        .write(&conflict, &storage.read(&file).unwrap())
+        .unwrap();
+    // A chunk that was only partly written after the last full one
+    let full_path = fs.ciphertext_path("/full.bin").unwrap();
+    let mut ciphertext = storage.read(&full_path).unwrap();
+    let full_len = ciphertext.len() as u64;
+    ciphertext.extend([0; 10]);
+    storage.write(&full_path, &ciphertext).unwrap();
+
+    let deep = CheckOptions { deep: true };
+    let report = vault.check(deep).unwrap();
+    let mut kinds: Vec<_> = report.issues.iter().map(|issue| issue.kind).collect();
+    kinds.sort_by_key(|kind| format!("{kind:?}"));
+    assert_eq!(
+        kinds,
+        [
+            IssueKind::CorruptContent,
+            IssueKind::CorruptDirIdBackup,
+            IssueKind::InvalidName,
+            IssueKind::InvalidName,
+            IssueKind::MissingDirIdBackup,
+            IssueKind::MissingDirectory,
+            IssueKind::OrphanedDirectory,
+        ]
+    );
+    let all_kinds = RepairOptions {
+        kinds,
+        dry_run: true,
+        allow_data_loss: true,
+    };
+
+    // A dry run doesn't change anything
+    let summary = vault.repair(&report, &all_kinds).unwrap();
+    assert_eq!(summary.repaired.len(), 6);
+    assert_eq!(summary.skipped.len(), 1);
+    assert_eq!(
+        summary.skipped[0].0.ciphertext_path,
+        root_dir.join("notes.txt")
+    );
+    assert!(summary.errors.is_empty());
+    assert_eq!(vault.check(deep).unwrap().issues.len(), 7);
+
+    // Nor does repairing nothing
+    let summary = vault.repair(&report, &RepairOptions::default()).unwrap();
+    assert_eq!(summary.skipped.len(), 7);
+    assert_eq!(vault.check(deep).unwrap().issues.len(), 7);
+
+    // Without allow_data_loss, the missing directory and damaged file are left alone
+    let options = RepairOptions {
+        dry_run: false,
+        allow_data_loss: false,
+        ..all_kinds.clone()
+    };
+    let summary = vault.repair(&report, &options).unwrap();
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    let mut actions: Vec<_> = summary
+        .repaired
+        .iter()
+        .map(|repair| repair.action.clone())
+        .collect();
+    actions.sort_by_key(|action| format!("{action:?}"));
+    let orphan_name = match &actions[0] {
+        RepairAction::AdoptOrphan { target } => target.clone(),
+        action => panic!("unexpected action: {action:?}"),
+    };
+    assert_eq!(
+        actions[1..],
+        [
+            RepairAction::RenameConflict {
+                cleartext_dir: PathBuf::from("/")
+            },
+            RepairAction::RestoreDirIdBackup,
+            RepairAction::RestoreDirIdBackup,
+        ]
+    );
+    assert_eq!(read(&orphan_name.join("found.txt")), b"found");
+    assert_eq!(
+        read(Path::new("/test_file (Conflict 1).txt")),
+        read(Path::new("/test_file.txt"))
+    );
+
+    let report = vault.check(deep).unwrap();
+    let mut kinds: Vec<_> = report.issues.iter().map(|issue| issue.kind).collect();
+    kinds.sort_by_key(|kind| format!("{kind:?}"));
+    assert_eq!(
+        kinds,
+        [
+            IssueKind::CorruptContent,
+            IssueKind::InvalidName,
+            IssueKind::MissingDirectory,
+        ]
+    );
+
+    let options = RepairOptions {
+        allow_data_loss: true,
+        ..options
+    };
+    let summary = vault.repair(&report, &options).unwrap();
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(summary.repaired.len(), 2);
+    assert!(summary
+        .repaired
+        .iter()
+        .any(|repair| repair.action == RepairAction::TruncatePartialChunk { len: full_len }));
+    assert_eq!(read(Path::new("/full.bin")), [7; 32 * 1024]);
+    let report = vault.check(deep).unwrap();
+    assert_eq!(report.issues.len(), 1);
+    assert_eq!(report.issues[0].ciphertext_path, root_dir.join("notes.txt"));
+}
+
+#[test]
+pub fn masterkey_backup_fallback() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaultCreateOptions {
+        kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+        ..Default::default()
+    };
+    let vault = Vault::create(dir.path(), String::from("password"), options).unwrap();
+    let config_path = dir.path().join("vault.cryptomator");
+    let key_path = dir.path().join("masterkey.cryptomator");
+
+    // A backup is written on creation, but only used if the primary file is corrupt
+    let opened = Vault::open(&config_path, String::from("password")).unwrap();
+    assert_eq!(opened.masterkey_backup(), None);
+
+    fs::write(&key_path, "{ corrupt").unwrap();
+    let opened = Vault::open(&config_path, String::from("password")).unwrap();
+    assert_eq!(*opened.master_key().unwrap(), *vault.master_key().unwrap());
+    let backup = opened.masterkey_backup().unwrap();
+    assert!(backup
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("masterkey.cryptomator."));
+
+    // Backups are no help with the wrong password
+    assert!(matches!(
+        Vault::open(&config_path, String::from("wrong")),
+        Err(VaultOpenError::WrongPassword)
+    ));
+
+    fs::remove_file(backup).unwrap();
+    assert!(matches!(
+        Vault::open(&config_path, String::from("password")),
+        Err(VaultOpenError::KeyLoad(_))
+    ));
+}
+
+#[test]
+pub fn custom_kdf_params() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaultCreateOptions {
+        kdf_params: KdfParams::new(2048, 4, 1).unwrap(),
+        ..Default::default()
+    };
+    let mut vault = Vault::create(dir.path(), String::from("password"), options).unwrap();
+    let key_path = dir.path().join("masterkey.cryptomator");
+    let read_params = || KdfParams::from(WrappedKey::from_file(&key_path).unwrap().params());
+    assert_eq!(read_params(), KdfParams::new(2048, 4, 1).unwrap());
+
+    // Invalid parameters are rejected up front
+    let invalid = KdfParams {
+        cost: 3000,
+        ..Default::default()
+    };
+    assert!(vault
+        .change_password_with_kdf(String::from("password"), String::from("new"), invalid)
+        .is_err());
+
+    vault
+        .change_password_with_kdf(
+            String::from("password"),
+            String::from("new"),
+            KdfParams::new(1024, 8, 1).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(read_params(), KdfParams::new(1024, 8, 1).unwrap());
+
+    // Changing only the password keeps the current parameters
+    vault
+        .change_password(String::from("new"), String::from("newer"))
+        .unwrap();
+    assert_eq!(read_params(), KdfParams::new(1024, 8, 1).unwrap());
+    Vault::open(dir.path().join("vault.cryptomator"), String::from("newer")).unwrap();
+}
+
+fn check_migrated_vault(vault_path: &Path) {
+    let vault = Vault::open(
+        vault_path.join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    assert_eq!(vault.config().claims.format, 8);
+    assert_eq!(vault.config().claims.cipher_combo, CipherCombo::SivCtrMac);
+    assert_eq!(
+        WrappedKey::from_file(vault_path.join("masterkey.cryptomator"))
+            .unwrap()
+            .version(),
+        999
+    );
+
+    let cryptor = vault.cryptor();
+    let root_dir = vault_path
+        .join("d")
+        .join(cryptor.hash_dir_id(&DirId::root()).unwrap());
+    let file_name = cryptor
+        .encrypt_filename(OsStr::new("test_file.txt"), &DirId::root())
+        .unwrap();
+    let mut options = OpenOptions::new();
+    options.read(true);
+    let mut file = EncryptedFile::open(cryptor, root_dir.join(file_name), options).unwrap();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "Hello, world!\n");
+
+    // Format 8 doesn't require dirid.c9r backups, and migration doesn't create them
+    let report = vault.check(CheckOptions { deep: true }).unwrap();
+    assert_eq!(report.directories_checked, 2);
+    assert_eq!(report.files_checked, 3);
+    assert_eq!(report.symlinks_checked, 1);
+    assert_eq!(report.issues.len(), 2);
+    assert!(report
+        .issues
+        .iter()
+        .all(|issue| issue.kind == IssueKind::MissingDirIdBackup));
+}
+
+#[test]
+pub fn migrate_vault() {
+    for format in [6, 7] {
+        let dir = tempfile::tempdir().unwrap();
+        copy_dir(format!("tests/fixtures/vault_v{format}"), dir.path()).unwrap();
+
+        assert!(Vault::migrate(dir.path(), String::from("wrong"), 8).is_err());
+        assert!(Vault::migrate(dir.path(), String::from("password"), 5).is_err());
+
+        // A dry run reports the changes without making them
+        let report = Vault::migrate_dry_run(dir.path(), String::from("password"), 8).unwrap();
+        assert_eq!((report.from_format, report.to_format), (format, 8));
+        assert!(report.dry_run);
+        assert_eq!(report.renamed.len(), if format == 6 { 5 } else { 0 });
+        assert!(report
+            .written
+            .contains(&dir.path().join("vault.cryptomator")));
+        assert!(!dir.path().join("vault.cryptomator").exists());
+
+        if format == 6 {
+            // Leftovers from an interrupted migration are cleaned up
+            let root_dir = fs::read_dir(dir.path().join("d/AT")).unwrap().next();
+            let root_dir = root_dir.unwrap().unwrap().path();
+            let mut stale = root_dir.into_os_string();
+            stale.push(".migrating-new");
+            fs::create_dir(&stale).unwrap();
+            fs::write(Path::new(&stale).join("junk"), "junk").unwrap();
+        }
+
+        let report = Vault::migrate(dir.path(), String::from("password"), 8).unwrap();
+        assert!(!report.dry_run);
+        assert!(!dir.path().join("m").exists());
+        assert!(!dir.path().join("migration.journal").exists());
+        check_migrated_vault(dir.path());
+
+        // Migrating again is a no-op
+        let report = Vault::migrate(dir.path(), String::from("password"), 8).unwrap();
+        assert_eq!(report.from_format, 8);
+        assert!(report.written.is_empty());
+    }
+}
+
+#[test]
+pub fn reencrypt_vault() {
+    let vault = Vault::open(
+        "tests/fixtures/vault_v8_siv_ctrmac/vault.cryptomator",
+        String::from("password"),
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    // Stop partway through, then resume
+    let mut seen = 0;
+    let report = vault
+        .reencrypt(dir.path(), String::from("new"), |_| {
+            seen += 1;
+            if seen > 3 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+    assert!(report.interrupted);
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+    let copied = report.files + report.directories + report.symlinks;
+    let report = vault
+        .reencrypt(dir.path(), String::from("new"), |_| {
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert!(!report.interrupted);
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.skipped, copied);
+    assert_eq!(
+        report.files + report.directories + report.symlinks + report.skipped,
+        8
+    );
+
+    let new_vault = Vault::open(dir.path().join("vault.cryptomator"), String::from("new")).unwrap();
+    assert_ne!(
+        *new_vault.master_key().unwrap(),
+        *vault.master_key().unwrap()
+    );
+    assert_eq!(
+        new_vault.config().claims.cipher_combo,
+        CipherCombo::SivCtrMac
+    );
+
+    let file_path = |vault: &Vault| {
+        let cryptor = vault.cryptor();
+        vault
+            .path()
+            .join("d")
+            .join(cryptor.hash_dir_id(&DirId::root()).unwrap())
+            .join(
+                cryptor
+                    .encrypt_filename(OsStr::new("test_file.txt"), &DirId::root())
+                    .unwrap(),
+            )
+    };
+    let read = |vault: &Vault| {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        EncryptedFile::open(vault.cryptor(), file_path(vault), options)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    };
+    assert!(!read(&vault).is_empty());
+    assert_eq!(read(&new_vault), read(&vault));
+    assert_eq!(
+        fs::metadata(file_path(&new_vault))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        fs::metadata(file_path(&vault)).unwrap().modified().unwrap()
+    );
+
+    let report = new_vault.check(CheckOptions { deep: true }).unwrap();
+    assert!(report.is_ok(), "{:?}", report.issues);
+}
+
+#[test]
+pub fn vault_stats() {
+    let vault = Vault::open(
+        "tests/fixtures/vault_v8_siv_gcm/vault.cryptomator",
+        String::from("password"),
+    )
+    .unwrap();
+
+    let stats = vault.stats().unwrap();
+    assert_eq!(stats.files, 4);
+    assert_eq!(stats.directories, 2);
+    assert_eq!(stats.symlinks, 2);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.cleartext_bytes, 484818 + 47 + 41 + 29);
+    assert!(stats.ciphertext_bytes > stats.cleartext_bytes);
+    assert_eq!(stats.deepest_path.unwrap().components().count(), 3);
+    assert_eq!(
+        stats.largest_files[..2],
+        [
+            (PathBuf::from("/test_image.jpg"), 484818),
+            (PathBuf::from("/test_dir/test_file_2.txt"), 47)
+        ]
+    );
+
+    let progress_calls = AtomicUsize::new(0);
+    let options = StatsOptions {
+        largest_files: 1,
+        parallel: true,
+    };
+    let parallel_stats = vault
+        .stats_with_progress(options, |_| {
+            progress_calls.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+    assert_eq!(progress_calls.into_inner(), 8);
+    assert_eq!(parallel_stats.largest_files, stats.largest_files[..1]);
+    assert_eq!(parallel_stats.cleartext_bytes, stats.cleartext_bytes);
+
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    check_stats_errors(&vault);
+}
+
+fn check_stats_errors(vault: &Vault) {
+    // A single bad entry is counted as an error without hiding the rest of the directory
+    let root_dir = vault
+        .path()
+        .join("d")
+        .join(vault.cryptor().hash_dir_id(&DirId::root()).unwrap());
+    vault
+        .storage()
+        .write(&root_dir.join("bogus.c9r"), b"")
+        .unwrap();
+    let stats = vault.stats().unwrap();
+    assert_eq!(stats.errors, 1);
+    assert_eq!(stats.files, 4);
+}
+
+#[test]
+pub fn open_split_vault() {
+    let config_dir = tempfile::tempdir().unwrap();
+    let data_root = tempfile::tempdir().unwrap();
+    let fixture = Path::new("tests/fixtures/vault_v8_siv_gcm");
+    for file_name in ["vault.cryptomator", "masterkey.cryptomator"] {
+        fs::copy(fixture.join(file_name), config_dir.path().join(file_name)).unwrap();
+    }
+    copy_dir(fixture.join("d"), data_root.path().join("d")).unwrap();
+
+    let config_path = config_dir.path().join("vault.cryptomator");
+    let loader = MasterkeyFileLoader::new(config_dir.path(), String::from("password"));
+    assert!(matches!(
+        Vault::open_split(&config_path, &loader, config_dir.path()),
+        Err(VaultOpenError::MissingDataDir(_))
+    ));
+
+    let vault = Vault::open_split(&config_path, &loader, data_root.path()).unwrap();
+    assert_eq!(vault.path(), data_root.path().canonicalize().unwrap());
+    assert_eq!(
+        vault.config_dir(),
+        config_dir.path().canonicalize().unwrap()
+    );
+
+    let stats = vault.stats().unwrap();
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.files, 4);
+}
+
+#[test]
+pub fn open_with_passphrase() {
+    let config_path = "tests/fixtures/vault_v8_siv_gcm/vault.cryptomator";
+    let passphrase = Passphrase::read_from(&b"password\n"[..]).unwrap();
+    let vault = Vault::open(config_path, passphrase).unwrap();
+    let from_string = Vault::open(config_path, String::from("password")).unwrap();
+    assert_eq!(
+        *vault.master_key().unwrap(),
+        *from_string.master_key().unwrap()
+    );
+
+    let wrong = Zeroizing::new(String::from("wrong"));
+    assert!(matches!(
+        Vault::open(config_path, wrong),
+        Err(VaultOpenError::WrongPassword)
+    ));
+}
+
+#[test]
+pub fn walk_vault() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    check_walk(&vault);
+}
+
+fn check_walk(vault: &Vault) {
+    let fs = EncryptedFileSystem::new(vault);
+    let paths = |walk: Walk| -> Vec<PathBuf> { walk.map(|entry| entry.unwrap().path).collect() };
+
+    let pre_order = paths(fs.walk("/"));
+    assert_eq!(pre_order.len(), 8);
+    let position = |paths: &[PathBuf], path: &str| paths.iter().position(|p| p == Path::new(path));
+    let test_dir = position(&pre_order, "/test_dir").unwrap();
+    let test_file_2 = position(&pre_order, "/test_dir/test_file_2.txt").unwrap();
+    assert!(test_dir < test_file_2);
+
+    let post_order = paths(fs.walk("/").order(WalkOrder::PostOrder));
+    assert_eq!(post_order.len(), 8);
+    let test_dir = position(&post_order, "/test_dir").unwrap();
+    let test_file_2 = position(&post_order, "/test_dir/test_file_2.txt").unwrap();
+    assert!(test_dir > test_file_2);
+
+    assert_eq!(paths(fs.walk("/").max_depth(1)).len(), 4);
+    let walk = fs.walk("/test_dir").collect::<Vec<_>>();
+    assert_eq!(walk.len(), 4);
+    assert!(walk.iter().all(|entry| entry.as_ref().unwrap().depth == 1));
+    assert!(fs.walk("/test_file.txt").next().unwrap().is_err());
+
+    // Symlinks are reported as-is unless followed
+    let link = fs
+        .walk("/")
+        .map(Result::unwrap)
+        .find(|entry| entry.path == Path::new("/test_link"))
+        .unwrap();
+    assert_eq!(link.entry.kind, FileKind::Symlink);
+    let followed = fs
+        .walk("/")
+        .follow_symlinks(true)
+        .map(Result::unwrap)
+        .find(|entry| entry.path == Path::new("/test_link"))
+        .unwrap();
+    assert_eq!(followed.entry.kind, FileKind::File);
+    assert_eq!(followed.entry.size, 47);
+
+    // A bad entry is yielded as an error without ending the walk
+    let root_dir = vault
+        .path()
+        .join("d")
+        .join(vault.cryptor().hash_dir_id(&DirId::root()).unwrap());
+    vault
+        .storage()
+        .write(&root_dir.join("bogus.c9r"), b"")
+        .unwrap();
+    let walk = fs.walk("/").collect::<Vec<_>>();
+    assert_eq!(walk.len(), 9);
+    assert_eq!(walk.iter().filter(|entry| entry.is_err()).count(), 1);
+}
+
+#[test]
+pub fn path_translation() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+    let vault_path = vault.path();
+
+    let file = fs.ciphertext_path("/test_dir/test_file_2.txt").unwrap();
+    assert_eq!(
+        file,
+        vault_path.join(
+            "d/RT/C3KT7DD5C3X6QE32X4IL6PM6WHHNB5/j2O1bILonFELjBCQTaqZEBgfUh1_uHvXjOdMdc2ZEg==.c9r"
+        )
+    );
+    assert_eq!(
+        fs.cleartext_path(&file).unwrap(),
+        Path::new("/test_dir/test_file_2.txt")
+    );
+    assert_eq!(
+        fs.cleartext_path(file.strip_prefix(vault_path).unwrap())
+            .unwrap(),
+        Path::new("/test_dir/test_file_2.txt")
+    );
+
+    // Directories, symlinks, and shortened names
+    for cleartext in [
+        "/test_dir",
+        "/test_link",
+        "/test_dir/test_name_too_long_name_too_long_name_too_long_name_too_long_name_too_long_name_too_long_name_too_long_name_too_long_name_too_long_name_too_long.txt",
+    ] {
+        let ciphertext = fs.ciphertext_path(cleartext).unwrap();
+        assert_eq!(fs.cleartext_path(&ciphertext).unwrap(), Path::new(cleartext));
+    }
+    let test_dir = fs.ciphertext_path("/test_dir").unwrap();
+    assert_eq!(
+        fs.cleartext_path(test_dir.join("dir.c9r")).unwrap(),
+        Path::new("/test_dir")
+    );
+
+    let root = fs.ciphertext_path("/").unwrap();
+    assert_eq!(fs.cleartext_path(&root).unwrap(), Path::new("/"));
+    assert_eq!(
+        fs.cleartext_path("d/RT/C3KT7DD5C3X6QE32X4IL6PM6WHHNB5/dirid.c9r")
+            .unwrap(),
+        Path::new("/test_dir")
+    );
+
+    // Moving a directory is picked up on the next lookup
+    let renamed = test_dir.with_file_name(
+        vault
+            .cryptor()
+            .encrypt_filename(OsStr::new("renamed"), &DirId::root())
+            .unwrap(),
+    );
+    fs::rename(&test_dir, renamed).unwrap();
+    assert_eq!(
+        fs.cleartext_path("d/RT/C3KT7DD5C3X6QE32X4IL6PM6WHHNB5")
+            .unwrap(),
+        Path::new("/renamed")
+    );
+
+    assert!(fs.cleartext_path("/elsewhere/d/AB/CD").is_err());
+    assert!(fs.cleartext_path("d/AB/CDEFGH").is_err());
+}
+
+#[test]
+pub fn long_names() {
+    let vault = Vault::open(
+        "tests/fixtures/vault_v8_long_names/vault.cryptomator",
+        String::from("password"),
+    )
+    .unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+    let long_file = format!("{}.txt", "long_file_name_".repeat(15));
+    let long_dir = "long_directory_name_".repeat(12);
+    let long_link = format!("{}_link", "l".repeat(225));
+
+    let entries = fs
+        .walk("/")
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.path, entry.entry)
+        })
+        .collect::<HashMap<_, _>>();
+    assert_eq!(entries.len(), 5);
+    let root = Path::new("/");
+    for (path, kind, size) in [
+        (root.join(&long_file), FileKind::File, 32),
+        (root.join(&long_dir).join(&long_file), FileKind::File, 35),
+        (root.join(&long_link), FileKind::Symlink, 229),
+        (root.join("short.txt"), FileKind::File, 12),
+    ] {
+        assert_eq!(entries[&path].kind, kind);
+        assert_eq!(entries[&path].size, size);
+    }
+    assert_eq!(entries[&root.join(&long_dir)].kind, FileKind::Directory);
+
+    // Contents are stored inside the shortened entry
+    let nested = root.join(&long_dir).join(&long_file);
+    let ciphertext_path = fs.ciphertext_path(&nested).unwrap();
+    assert_eq!(ciphertext_path.extension().unwrap(), "c9s");
+    assert_eq!(fs.cleartext_path(&ciphertext_path).unwrap(), nested);
+    let mut options = OpenOptions::new();
+    options.read(true);
+    let mut contents = String::new();
+    EncryptedFile::open(
+        vault.cryptor(),
+        ciphertext_path.join("contents.c9r"),
+        options,
+    )
+    .unwrap()
+    .read_to_string(&mut contents)
+    .unwrap();
+    assert_eq!(contents, "Nested file with a very long name.\n");
+
+    let followed = fs
+        .walk("/")
+        .follow_symlinks(true)
+        .map(Result::unwrap)
+        .find(|entry| entry.path == root.join(&long_link))
+        .unwrap();
+    assert_eq!(followed.entry.kind, FileKind::File);
+    assert_eq!(followed.entry.size, 32);
+
+    let report = vault.check(CheckOptions { deep: true }).unwrap();
+    assert!(report.is_ok(), "{:?}", report.issues);
+}
+
+#[test]
+pub fn lossy_dir_listing() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+    let entries = fs.dir_entries("/").unwrap();
+
+    let file = fs.ciphertext_path("/test_file.txt").unwrap();
+    fs::copy(&file, file.with_file_name("renamed.txt.c9r")).unwrap();
+    assert!(fs.dir_entries("/").is_err());
+    let (lossy_entries, errors) = fs.dir_entries_lossy("/").unwrap();
+    assert!(lossy_entries.keys().eq(entries.keys()));
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].ciphertext_name, "renamed.txt.c9r");
+    assert!(matches!(errors[0].reason, EntryErrorReason::Invalid(_)));
+}
+
+#[test]
+pub fn open_by_ciphertext_path() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_long_names", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+    let long_file = format!("{}.txt", "long_file_name_".repeat(15));
+    let read = |path: &Path| -> cryptomator::Result<Vec<u8>> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        fs.open_ciphertext(path, options)?
+            .read_to_end(&mut contents)?;
+        Ok(contents)
+    };
+
+    // Full-length and shortened entries, by absolute or relative path
+    let short = fs.ciphertext_path("/short.txt").unwrap();
+    assert_eq!(read(&short).unwrap().len(), 12);
+    assert_eq!(
+        read(short.strip_prefix(vault.path()).unwrap())
+            .unwrap()
+            .len(),
+        12
+    );
+    let long = fs.ciphertext_path(Path::new("/").join(&long_file)).unwrap();
+    assert_eq!(read(&long).unwrap().len(), 32);
+    assert_eq!(read(&long.join("contents.c9r")).unwrap().len(), 32);
+    let link = fs
+        .ciphertext_path(format!("/{}_link", "l".repeat(225)))
+        .unwrap();
+    assert_eq!(read(&link).unwrap(), long_file.as_bytes());
+
+    // Names can be decrypted one at a time
+    let name = short.file_name().unwrap();
+    assert_eq!(
+        fs.decrypt_ciphertext_name(name, &DirId::root()).unwrap(),
+        "short.txt"
+    );
+    let full_name = fs::read_to_string(long.join("name.c9s")).unwrap();
+    assert_eq!(
+        fs.decrypt_ciphertext_name(full_name, &DirId::root())
+            .unwrap(),
+        long_file
+    );
+    assert!(fs
+        .decrypt_ciphertext_name(long.file_name().unwrap(), &DirId::root())
+        .is_err());
+    assert!(fs.decrypt_ciphertext_name(name, &DirId::new()).is_err());
+
+    // Paths outside of the vault's files are rejected
+    assert!(read(Path::new("/elsewhere/d/AB/CD/file.c9r")).is_err());
+    assert!(read(&short.with_file_name("..").join("short.c9r")).is_err());
+    assert!(read(short.parent().unwrap()).is_err());
+    assert!(read(&long.join("name.c9s")).is_err());
+
+    // A damaged header is reported with the path of the file
+    let mut file = fs::OpenOptions::new().write(true).open(&short).unwrap();
+    file.write_all(&[0; 16]).unwrap();
+    let err = read(&short).unwrap_err();
+    assert!(err.to_string().contains(&short.display().to_string()));
+}
+
+#[test]
+pub fn orphaned_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    check_orphans(&vault);
+
+    let report = vault.check(CheckOptions::default()).unwrap();
+    assert!(report.is_ok(), "{:?}", report.issues);
+}
+
+fn check_orphans(vault: &Vault) {
+    let fs = EncryptedFileSystem::new(vault);
+    let storage = vault.storage();
+    assert!(fs.find_orphans().unwrap().is_empty());
+
+    // Losing the entry for /test_dir also orphans the directory inside it
+    storage
+        .remove_dir_all(&fs.ciphertext_path("/test_dir").unwrap())
+        .unwrap();
+    let junk = vault.path().join("d/ZZ/JUNK");
+    storage.create_dir_all(&junk).unwrap();
+    storage.write(&junk.join("junk.c9r"), b"junk").unwrap();
+
+    let orphans = fs.find_orphans().unwrap();
+    assert_eq!(orphans.len(), 3);
+    let test_dir = orphans
+        .iter()
+        .find(|orphan| {
+            orphan
+                .hashed_dir_path
+                .ends_with("RT/C3KT7DD5C3X6QE32X4IL6PM6WHHNB5")
+        })
+        .unwrap();
+    assert_eq!(
+        test_dir.dir_id,
+        Some(DirId::parse("1a3534ba-34fb-4ba6-ad67-1e37627d40be").unwrap())
+    );
+    let junk_orphan = orphans
+        .iter()
+        .find(|orphan| orphan.hashed_dir_path == junk)
+        .unwrap();
+    assert_eq!(junk_orphan.dir_id, None);
+
+    assert!(fs.adopt_orphan(junk_orphan, "/junk").is_err());
+    assert!(fs.adopt_orphan(test_dir, "/test_file.txt").is_err());
+    fs.adopt_orphan(test_dir, "/recovered").unwrap();
+    assert!(fs.adopt_orphan(test_dir, "/again").is_err());
+    assert!(fs
+        .walk("/recovered")
+        .any(|entry| entry.unwrap().path == Path::new("/recovered/test_file_2.txt")));
+
+    let orphans = fs.find_orphans().unwrap();
+    assert_eq!(&orphans, std::slice::from_ref(junk_orphan));
+    assert!(fs.purge_orphan(test_dir).is_err());
+    assert!(fs
+        .purge_orphan(&OrphanDir {
+            hashed_dir_path: vault.path().join("d"),
+            dir_id: None,
+        })
+        .is_err());
+
+    // A directory entry whose dir.c9r can't be read might be the one referencing the orphan
+    let root_dir = fs.ciphertext_path("/recovered").unwrap();
+    let unreadable = root_dir.with_file_name("unreadable.c9r");
+    storage.create_dir_all(&unreadable).unwrap();
+    storage.write(&unreadable.join("dir.c9r"), b"").unwrap();
+    assert!(fs.find_orphans().is_err());
+    assert!(fs.purge_orphan(&orphans[0]).is_err());
+    assert!(storage.exists(&junk));
+    storage.remove_dir_all(&unreadable).unwrap();
+
+    fs.purge_orphan(&orphans[0]).unwrap();
+    assert!(!storage.exists(&vault.path().join("d/ZZ")));
+    assert!(fs.find_orphans().unwrap().is_empty());
+}
+
+#[test]
+pub fn tar_export_and_import() {
+    let vault = Vault::open(
+        "tests/fixtures/vault_v8_siv_gcm/vault.cryptomator",
+        String::from("password"),
+    )
+    .unwrap()
+    .into_memory()
+    .unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+    let archive_paths = |archive: &[u8]| -> Vec<PathBuf> {
+        tar::Archive::new(archive)
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect()
+    };
+
+    let mut archive = Vec::new();
+    let report = vault
+        .export_tar(&mut archive, "/", &Default::default())
+        .unwrap();
+    assert_eq!(
+        (report.files, report.directories, report.symlinks),
+        (4, 2, 2)
+    );
+    assert_eq!(report.bytes, 484818 + 47 + 41 + 29);
+    assert_eq!(archive_paths(&archive).len(), 8);
+
+    // Importing the archive into a new vault recreates the same tree
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaultCreateOptions {
+        kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+        ..Default::default()
+    };
+    let imported = Vault::create(dir.path(), String::from("password"), options).unwrap();
+    let report = imported.import_tar(&archive[..], "/").unwrap();
+    assert_eq!(
+        (report.files, report.directories, report.symlinks),
+        (4, 2, 2)
+    );
+    assert!(report.skipped.is_empty());
+
+    let imported_fs = EncryptedFileSystem::new(&imported);
+    let read = |fs: &EncryptedFileSystem, path: &Path| {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        fs.open_ciphertext(fs.ciphertext_path(path).unwrap(), options)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    };
+    let tree = |fs: &EncryptedFileSystem| {
+        fs.walk("/")
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let metadata = &entry.entry.metadata;
+                let contents =
+                    (entry.entry.kind != FileKind::Directory).then(|| read(fs, &entry.path));
+                (
+                    entry.path,
+                    entry.entry.kind,
+                    metadata.permissions(),
+                    metadata.modified().unwrap(),
+                    contents,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut expected = tree(&fs);
+    let mut actual = tree(&imported_fs);
+    expected.sort_by(|a, b| a.0.cmp(&b.0));
+    actual.sort_by(|a, b| a.0.cmp(&b.0));
+    // Tar only keeps whole seconds
+    for entry in expected.iter_mut().chain(&mut actual) {
+        entry.3 =
+            UNIX_EPOCH + Duration::from_secs(entry.3.duration_since(UNIX_EPOCH).unwrap().as_secs());
+    }
+    assert_eq!(actual, expected);
+    assert!(imported.import_tar(&archive[..], "/").is_err());
+    assert!(imported.check(Default::default()).unwrap().is_ok());
+
+    // Filters apply to paths relative to the exported directory, and cover everything below
+    let mut archive = Vec::new();
+    let options = ExportOptions {
+        include: vec!["test_dir".into(), "*.jpg".into()],
+        exclude: vec!["**/*.txt".into()],
+        best_effort: false,
+    };
+    vault.export_tar(&mut archive, "/", &options).unwrap();
+    let paths = archive_paths(&archive);
+    assert!(paths.contains(&PathBuf::from("test_image.jpg")));
+    assert!(paths.iter().any(|path| path.starts_with("test_dir")));
+    assert!(!paths
+        .iter()
+        .any(|path| path.extension() == Some(OsStr::new("txt"))));
+    let mut archive = Vec::new();
+    vault
+        .export_tar(&mut archive, "/test_dir", &Default::default())
+        .unwrap();
+    assert!(archive_paths(&archive).contains(&PathBuf::from("test_file_2.txt")));
+
+    // A damaged chunk fails the export, unless it's only reported
+    let image = fs.ciphertext_path("/test_image.jpg").unwrap();
+    let mut ciphertext = vault.storage().read(&image).unwrap();
+    *ciphertext.last_mut().unwrap() ^= 1;
+    vault.storage().write(&image, &ciphertext).unwrap();
+    assert!(vault
+        .export_tar(&mut Vec::new(), "/", &Default::default())
+        .is_err());
+    let mut archive = Vec::new();
+    let options = ExportOptions {
+        best_effort: true,
+        ..Default::default()
+    };
+    let report = vault.export_tar(&mut archive, "/", &options).unwrap();
+    assert_eq!(report.errors.len(), 1);
+    assert_eq!(report.errors[0].0, Path::new("/test_image.jpg"));
+    assert_eq!(archive_paths(&archive).len(), 8);
+}
+
+#[test]
+pub fn import_plaintext_dir() {
+    let src = tempfile::tempdir().unwrap();
+    let src = src.path();
+    let large = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+    fs::create_dir_all(src.join("nested/deeper")).unwrap();
+    fs::write(src.join("small.txt"), "small").unwrap();
+    fs::write(src.join("nested/large.bin"), &large).unwrap();
+    fs::write(src.join("nested/deeper/empty"), "").unwrap();
+    fs::set_permissions(src.join("small.txt"), Permissions::from_mode(0o640)).unwrap();
+    std::os::unix::fs::symlink("../small.txt", src.join("nested/link")).unwrap();
+    let _socket = UnixListener::bind(src.join("socket")).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaultCreateOptions {
+        kdf_params: KdfParams::new(1024, 8, 1).unwrap(),
+        ..Default::default()
+    };
+    let vault = Vault::create(dir.path(), String::from("password"), options).unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+    let options = ImportOptions { threads: 4 };
+    let report = fs.import_dir(src, "/imported", options).unwrap();
+    assert_eq!(
+        (report.files, report.directories, report.symlinks),
+        (3, 2, 1)
+    );
+    assert_eq!(report.bytes, 5 + 100_000);
+    assert_eq!(report.skipped, [src.join("socket")]);
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+    let read = |path: &str| {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        fs.open_ciphertext(fs.ciphertext_path(path).unwrap(), options)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    };
+    assert_eq!(read("/imported/small.txt"), b"small");
+    assert_eq!(read("/imported/nested/large.bin"), large);
+    assert_eq!(read("/imported/nested/link"), b"../small.txt");
+    for entry in fs.walk("/imported") {
+        let entry = entry.unwrap();
+        let src_path = src.join(entry.path.strip_prefix("/imported").unwrap());
+        let src_metadata = fs::symlink_metadata(src_path).unwrap();
+        assert_eq!(
+            entry.entry.metadata.modified().unwrap(),
+            src_metadata.modified().unwrap(),
+            "{}",
+            entry.path.display()
+        );
+        if entry.entry.kind != FileKind::Symlink {
+            assert_eq!(
+                entry.entry.metadata.permissions(),
+                src_metadata.permissions()
+            );
+        }
+    }
+
+    // Running it again only imports what changed
+    let report = fs.import_dir(src, "/imported", options).unwrap();
+    assert_eq!((report.files, report.unchanged), (0, 4));
+    fs::write(src.join("small.txt"), "changed").unwrap();
+    let report = fs.import_dir(src, "/imported", options).unwrap();
+    assert_eq!((report.files, report.unchanged), (1, 3));
+    assert_eq!(read("/imported/small.txt"), b"changed");
+    assert_eq!(fs.walk("/").count(), 7);
+
+    assert!(vault.check(Default::default()).unwrap().is_ok());
+}
+
+#[test]
+pub fn memory_vault() {
+    let config_path = "tests/fixtures/vault_v8_siv_gcm/vault.cryptomator";
+    let open = || {
+        Vault::open(config_path, String::from("password"))
+            .unwrap()
+            .into_memory()
+            .unwrap()
+    };
+
+    // The same checks as on a copy of the fixture, without copying it
+    check_walk(&open());
+    check_stats_errors(&open());
+    check_orphans(&open());
+
+    // Files written in memory can be read back, and the fixture is left alone
+    let vault = open();
+    let fs = EncryptedFileSystem::new(&vault);
+    fs.copy_file("/test_file.txt", "/copied.txt", false)
+        .unwrap();
+    let read = |path: &str| {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = String::new();
+        fs.open_ciphertext(fs.ciphertext_path(path).unwrap(), options)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    };
+    assert_eq!(read("/copied.txt"), read("/test_file.txt"));
+    assert_eq!(vault.stats().unwrap().files, 5);
+
+    let on_disk = Vault::open(config_path, String::from("password")).unwrap();
+    let stats = on_disk.stats().unwrap();
+    assert_eq!((stats.files, stats.directories, stats.errors), (4, 2, 0));
+}
+
+#[test]
+pub fn copy_and_move_dirs() {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    let fs = EncryptedFileSystem::new(&vault);
+
+    // Relative paths, kinds, permissions, and decrypted contents of everything in a directory
+    let tree = |root: &str| {
+        let mut tree = fs
+            .walk(root)
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let inner = match entry.entry.kind {
+                    FileKind::File => "contents.c9r",
+                    FileKind::Symlink => "symlink.c9r",
+                    FileKind::Directory => "",
+                };
+                let mut ciphertext_path = entry.ciphertext_path.join(inner);
+                if !ciphertext_path.exists() {
+                    ciphertext_path = entry.ciphertext_path.clone();
+                }
+
+                let mut contents = Vec::new();
+                if entry.entry.kind != FileKind::Directory {
+                    let mut options = OpenOptions::new();
+                    options.read(true);
+                    EncryptedFile::open(vault.cryptor(), ciphertext_path, options)
+                        .unwrap()
+                        .read_to_end(&mut contents)
+                        .unwrap();
+                }
+
+                (
+                    entry.path.strip_prefix(root).unwrap().to_path_buf(),
+                    entry.entry.kind,
+                    entry.entry.metadata.permissions(),
+                    contents,
+                )
+            })
+            .collect::<Vec<_>>();
+        tree.sort_by(|a, b| a.0.cmp(&b.0));
+        tree
+    };
+
+    let mut progress_calls = 0;
+    let report = fs
+        .copy_dir_recursive("/test_dir", "/copy", |_| {
+            progress_calls += 1;
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert!(!report.interrupted);
+    assert_eq!(
+        (report.files, report.directories, report.symlinks),
+        (2, 1, 1)
+    );
+    assert_eq!(progress_calls, 4);
+    let original = tree("/test_dir");
+    assert_eq!(original.len(), 4);
+    assert_eq!(tree("/copy"), original);
+
+    // Copied directories are new directories, not links to the old ones
+    let nested = original
+        .iter()
+        .find(|entry| entry.1 == FileKind::Directory)
+        .unwrap()
+        .0
+        .clone();
+    assert_ne!(
+        fs::read(
+            fs.ciphertext_path(Path::new("/test_dir").join(&nested))
+                .unwrap()
+                .join("dir.c9r")
+        )
+        .unwrap(),
+        fs::read(
+            fs.ciphertext_path(Path::new("/copy").join(&nested))
+                .unwrap()
+                .join("dir.c9r")
+        )
+        .unwrap()
+    );
+
+    assert!(fs
+        .copy_dir_recursive("/test_dir", "/copy", |_| ControlFlow::Continue(()))
+        .is_err());
+    assert!(fs
+        .copy_dir_recursive(
+            "/test_dir",
+            "/test_dir/inner",
+            |_| ControlFlow::Continue(())
+        )
+        .is_err());
+
+    fs.move_dir("/copy", "/test_dir/moved").unwrap();
+    assert_eq!(tree("/test_dir/moved"), original);
+    assert!(fs.walk("/copy").next().unwrap().is_err());
+    assert!(fs.move_dir("/test_file.txt", "/moved").is_err());
+
+    let report = vault.check(CheckOptions::default()).unwrap();
+    assert!(report.is_ok(), "{:?}", report.issues);
+}
+
+#[cfg(feature = "webdav")]
+#[test]
+pub fn webdav_server() {
+    use cryptomator::fs::webdav::WebDavServer;
+    use reqwest::{blocking::Client, header, Method, StatusCode};
+
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir("tests/fixtures/vault_v8_siv_gcm", dir.path()).unwrap();
+    let vault = Vault::open(
+        dir.path().join("vault.cryptomator"),
+        String::from("password"),
+    )
+    .unwrap();
+    let vault: &'static Vault = Box::leak(Box::new(vault));
+
+    let serve = |writable: bool, auth: bool| {
+        // Find a free port for the server to bind to
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut server =
+            WebDavServer::new(EncryptedFileSystem::new(vault), addr).writable(writable);
+        if auth {
+            server = server.basic_auth("user", "secret");
+        }
+        std::thread::spawn(move || server.serve().unwrap());
+        for _ in 0..100 {
+            if std::net::TcpStream::connect(addr).is_ok() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        format!("http://{addr}")
+    };
+    let client = Client::new();
+    let propfind = Method::from_bytes(b"PROPFIND").unwrap();
+
+    // Read-only access
+    let url = serve(false, false);
+    let listing = client
+        .request(propfind.clone(), format!("{url}/"))
+        .header("Depth", "1")
+        .send()
+        .unwrap();
+    assert_eq!(listing.status(), StatusCode::MULTI_STATUS);
+    assert!(listing.text().unwrap().contains("test_file.txt"));
+
+    let file = client.get(format!("{url}/test_file.txt")).send().unwrap();
+    assert_eq!(file.status(), StatusCode::OK);
+    assert_eq!(
+        file.text().unwrap(),
+        "this is a test file with some text in it\n"
+    );
+    let range = client
+        .get(format!("{url}/test_file.txt"))
+        .header(header::RANGE, "bytes=10-13")
+        .send()
+        .unwrap();
+    assert_eq!(range.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(range.text().unwrap(), "test");
+
+    let put = client
+        .put(format!("{url}/new.txt"))
+        .body("nope")
+        .send()
+        .unwrap();
+    assert!(put.status().is_client_error());
+    let missing = client.get(format!("{url}/missing.txt")).send().unwrap();
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+    // Read-write access with authentication
+    let url = serve(true, true);
+    let unauthorized = client.get(format!("{url}/test_file.txt")).send().unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+    let wrong = client
+        .get(format!("{url}/test_file.txt"))
+        .basic_auth("user", Some("wrong"))
+        .send()
+        .unwrap();
+    assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+
+    let request = |method: Method, path: &str| {
+        client
+            .request(method, format!("{url}{path}"))
+            .basic_auth("user", Some("secret"))
+    };
+    let contents = "hello ".repeat(10_000);
+    let put = request(Method::PUT, "/new.txt")
+        .body(contents.clone())
+        .send()
+        .unwrap();
+    assert!(put.status().is_success(), "{}", put.status());
+    let mkcol = request(Method::from_bytes(b"MKCOL").unwrap(), "/folder")
+        .send()
+        .unwrap();
+    assert!(mkcol.status().is_success(), "{}", mkcol.status());
+    let moved = request(Method::from_bytes(b"MOVE").unwrap(), "/new.txt")
+        .header("Destination", format!("{url}/folder/moved.txt"))
+        .send()
+        .unwrap();
+    assert!(moved.status().is_success(), "{}", moved.status());
+    let copied = request(Method::from_bytes(b"COPY").unwrap(), "/folder/moved.txt")
+        .header("Destination", format!("{url}/copied.txt"))
+        .send()
+        .unwrap();
+    assert!(copied.status().is_success(), "{}", copied.status());
+
+    let fs = EncryptedFileSystem::new(vault);
+    for path in ["/folder/moved.txt", "/copied.txt"] {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut read = String::new();
+        fs.open_ciphertext(fs.ciphertext_path(path).unwrap(), options)
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, contents);
+    }
+
+    let delete = request(Method::DELETE, "/folder").send().unwrap();
+    assert!(delete.status().is_success(), "{}", delete.status());
+    let missing = request(Method::GET, "/folder/moved.txt").send().unwrap();
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+    assert!(vault.check(Default::default()).unwrap().is_ok());
+}
+
+// Storage that logs writes and syncs of its files, in order
+#[derive(Debug, Default)]
+struct SyncLog {
+    inner: MemoryStorage,
+    log: Arc<Mutex<Vec<&'static str>>>,
+}
+
+#[derive(Debug)]
+struct SyncLogFile {
+    inner: Box<dyn StorageFile>,
+    log: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl SyncLog {
+    fn take(&self) -> Vec<&'static str> {
+        std::mem::take(&mut self.log.lock().unwrap())
+    }
+}
+
+impl VaultStorage for SyncLog {
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(SyncLogFile {
+            inner: self.inner.open(path, options)?,
+            log: self.log.clone(),
+        }))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        self.inner.list(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+        self.inner.set_permissions(path, permissions)
+    }
+
+    fn set_times(&self, path: &Path, times: FileTimes) -> io::Result<()> {
+        self.inner.set_times(path, times)
+    }
+}
+
+impl StorageFile for SyncLogFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.inner.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.log.lock().unwrap().push("write");
+        self.inner.write_at(buf, offset)
+    }
+
+    fn metadata(&self) -> io::Result<Metadata> {
+        self.inner.metadata()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.log.lock().unwrap().push("set_len");
+        self.inner.set_len(len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.log.lock().unwrap().push("sync_all");
+        self.inner.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.log.lock().unwrap().push("sync_data");
+        self.inner.sync_data()
+    }
+}
+
+#[test]
+pub fn sync_order() {
+    let key = MasterKey::random().unwrap();
+    let cryptor = siv_gcm::Cryptor::new(&key);
+    let storage = SyncLog::default();
+    let path = Path::new("/file");
+
+    // The header is durable before the file is handed out
+    let mut file = EncryptedFile::create_new_in(&storage, cryptor, path).unwrap();
+    assert_eq!(storage.take(), ["write", "sync_all"]);
+
+    // Later writes are only synced when asked, at the requested level
+    file.write_all(&[1; 40_000]).unwrap();
+    file.flush().unwrap();
+    assert!(storage.take().iter().all(|&event| event == "write"));
+    file.sync_data().unwrap();
+    assert_eq!(storage.take(), ["sync_data"]);
+    file.write_all(b"more").unwrap();
+    file.sync_all().unwrap();
+    assert_eq!(storage.take().last(), Some(&"sync_all"));
+    drop(file);
+
+    let mut options = OpenOptions::new();
+    options.read(true);
+    let mut file = EncryptedFile::open_in(&storage, cryptor, path, options).unwrap();
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents.len(), 40_004);
+    assert!(storage.take().is_empty());
+}
+
+#[test]
+pub fn write_past_eof() {
+    const CHUNK: u64 = 32 * 1024;
+
+    let key = MasterKey::random().unwrap();
+    let cryptor = siv_gcm::Cryptor::new(&key);
+    let storage = MemoryStorage::new();
+    for gap in [1, CHUNK, 100 * CHUNK] {
+        let path = PathBuf::from(format!("/gap-{gap}"));
+        let mut file = EncryptedFile::create_new_in(&storage, cryptor, &path).unwrap();
+        file.write_all(&[1; 1000]).unwrap();
+        file.write_all_at(b"end", 1000 + gap).unwrap();
+        assert_eq!(file.len().unwrap(), 1000 + gap + 3);
+        drop(file);
+
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut contents = Vec::new();
+        EncryptedFile::open_in(&storage, cryptor, &path, options)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        let mut expected = vec![1; 1000];
+        expected.resize((1000 + gap) as usize, 0);
+        expected.extend_from_slice(b"end");
+        assert!(contents == expected, "gap of {gap} bytes");
+    }
+}
+
+#[test]
+pub fn seek_past_eof() {
+    let key = MasterKey::random().unwrap();
+    let cryptor = siv_gcm::Cryptor::new(&key);
+    let storage = MemoryStorage::new();
+    let path = Path::new("/image");
+    let mut file = EncryptedFile::create_new_in(&storage, cryptor, path).unwrap();
+
+    // Build the file out of order, like an image or a torrent written at offsets
+    assert_eq!(file.seek(SeekFrom::Start(100_000)).unwrap(), 100_000);
+    assert_eq!(file.len().unwrap(), 0);
+    file.write_all(b"data").unwrap();
+    assert_eq!(file.stream_position().unwrap(), 100_004);
+    file.rewind().unwrap();
+    file.write_all(b"header").unwrap();
+    assert_eq!(file.seek(SeekFrom::End(10)).unwrap(), 100_014);
+    file.write_all(b"footer").unwrap();
+    assert_eq!(file.len().unwrap(), 100_020);
+
+    // Reads past the end are empty, until something is written there
+    let mut buf = [0; 16];
+    assert_eq!(file.seek(SeekFrom::Current(50)).unwrap(), 100_070);
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+    assert_eq!(file.seek(SeekFrom::Start(u64::MAX)).unwrap(), u64::MAX);
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+    assert_eq!(file.stream_position().unwrap(), u64::MAX);
+
+    // Seeking before the start or overflowing fails, and leaves the position alone
+    assert!(file.seek(SeekFrom::Current(1)).is_err());
+    assert!(file.seek(SeekFrom::End(-100_021)).is_err());
+    file.seek(SeekFrom::Start(100_000)).unwrap();
+    assert!(file.seek(SeekFrom::Current(-100_001)).is_err());
+    file.read_exact(&mut buf[..4]).unwrap();
+    assert_eq!(&buf[..4], b"data");
+
+    file.rewind().unwrap();
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).unwrap();
+    let mut expected = b"header".to_vec();
+    expected.resize(100_000, 0);
+    expected.extend_from_slice(b"data");
+    expected.resize(100_014, 0);
+    expected.extend_from_slice(b"footer");
+    assert!(contents == expected);
+}
@@ -0,0 +1,156 @@
+#![cfg(feature = "cli")]
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+fn cryptomator(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cryptomator"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+pub fn create_info_and_change_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let vault = dir.path().join("vault");
+    let password = dir.path().join("password");
+    let new_password = dir.path().join("new_password");
+    fs::write(&password, "password\n").unwrap();
+    fs::write(&new_password, "new password\n").unwrap();
+
+    let output = cryptomator(&["create", path(&vault), "--password-file", path(&password)]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(vault.join("vault.cryptomator").is_file());
+    let output = cryptomator(&["create", path(&vault), "--password-file", path(&password)]);
+    assert!(!output.status.success());
+
+    // The vault can be given as its directory or its config file
+    let config = vault.join("vault.cryptomator");
+    for vault_arg in [path(&vault), path(&config)] {
+        let output = cryptomator(&["info", vault_arg, "--password-file", path(&password)]);
+        assert!(output.status.success(), "{output:?}");
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("\"format\": 8"), "{stdout}");
+        assert!(stdout.contains("\"cipherCombo\": \"SIV_GCM\""), "{stdout}");
+        assert!(stdout.contains("key id: masterkeyfile:masterkey.cryptomator"));
+    }
+
+    let output = cryptomator(&[
+        "change-password",
+        path(&vault),
+        "--password-file",
+        path(&password),
+        "--new-password-file",
+        path(&new_password),
+    ]);
+    assert!(output.status.success(), "{output:?}");
+
+    // A wrong password has its own exit code and message
+    let output = cryptomator(&["info", path(&vault), "--password-file", path(&password)]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "error: wrong password\n"
+    );
+    let output = cryptomator(&["info", path(&vault), "--password-file", path(&new_password)]);
+    assert!(output.status.success(), "{output:?}");
+
+    let missing = dir.path().join("missing");
+    let output = cryptomator(&["info", path(&missing), "--password-file", path(&password)]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+pub fn decrypt_and_encrypt_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let password = dir.path().join("password");
+    fs::write(&password, "password\n").unwrap();
+    let image = fs::read("tests/fixtures/test_image.jpg").unwrap();
+
+    // Fixture files decrypt with their vault, or with just the masterkey file and cipher combo
+    let image_path = dir.path().join("image.jpg");
+    let output = cryptomator(&[
+        "decrypt-file",
+        "tests/fixtures/vault_v8_siv_gcm/d/RC/WG5EI3VR4DOIGAFUPFXLALP5SBGCL5/LNyfONa3J2M1pirw-S-YBasDwUyV7RyhSwz7oMlP.c9r",
+        path(&image_path),
+        "--vault",
+        "tests/fixtures/vault_v8_siv_gcm",
+        "--password-file",
+        path(&password),
+    ]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(fs::read(&image_path).unwrap(), image);
+
+    let ctrmac_image = "tests/fixtures/vault_v8_siv_ctrmac/d/B3/EO5WWODTDD254SS2TQWVAQKJAWPBKK/elqiMLEIVhXP94ydJeId4vavM_9rPv380wdMYzwg.c9r";
+    let decrypt = |cipher_combo: &str, password: &Path| {
+        cryptomator(&[
+            "decrypt-file",
+            ctrmac_image,
+            "-",
+            "--masterkey",
+            "tests/fixtures/vault_v8_siv_ctrmac/masterkey.cryptomator",
+            "--cipher-combo",
+            cipher_combo,
+            "--password-file",
+            path(password),
+        ])
+    };
+    let output = decrypt("SIV_CTRMAC", &password);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, image);
+
+    // A wrong key is told apart from a wrong password
+    let output = decrypt("SIV_GCM", &password);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("not encrypted with this key"));
+    let wrong_password = dir.path().join("wrong_password");
+    fs::write(&wrong_password, "wrong\n").unwrap();
+    let output = decrypt("SIV_CTRMAC", &wrong_password);
+    assert_eq!(output.status.code(), Some(3));
+
+    // Encrypted files round-trip, and damage is reported by chunk
+    let encrypted = dir.path().join("image.c9r");
+    let output = cryptomator(&[
+        "encrypt-file",
+        "tests/fixtures/test_image.jpg",
+        path(&encrypted),
+        "--vault",
+        "tests/fixtures/vault_v8_siv_gcm",
+        "--password-file",
+        path(&password),
+    ]);
+    assert!(output.status.success(), "{output:?}");
+    let decrypt_encrypted = || {
+        cryptomator(&[
+            "decrypt-file",
+            path(&encrypted),
+            "-",
+            "--vault",
+            "tests/fixtures/vault_v8_siv_gcm",
+            "--password-file",
+            path(&password),
+        ])
+    };
+    let output = decrypt_encrypted();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, image);
+
+    let mut ciphertext = fs::read(&encrypted).unwrap();
+    let len = ciphertext.len();
+    ciphertext[len - 1] ^= 1;
+    fs::write(&encrypted, ciphertext).unwrap();
+    let output = decrypt_encrypted();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("damaged at chunk 14"), "{stderr}");
+}
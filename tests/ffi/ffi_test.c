@@ -0,0 +1,77 @@
+/* Exercises the C API against a new vault. Usage: ffi_test <vault config path> <password> */
+
+#include <fcntl.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "cryptomator.h"
+
+#define CHECK(cond)                                                            \
+  do {                                                                         \
+    if (!(cond)) {                                                             \
+      const char *error = cm_last_error();                                     \
+      fprintf(stderr, "%s:%d: check failed: %s (last error: %s)\n", __FILE__,  \
+              __LINE__, #cond, error ? error : "none");                        \
+      return 1;                                                                \
+    }                                                                          \
+  } while (0)
+
+int main(int argc, char **argv) {
+  CHECK(argc == 3);
+
+  /* Failures return NULL and leave a message behind */
+  CHECK(cm_vault_open(argv[1], "wrong password") == NULL);
+  CHECK(strstr(cm_last_error(), "wrong password") != NULL);
+  CHECK(cm_vault_open(NULL, argv[2]) == NULL);
+  CHECK(strcmp(cm_last_error(), "config_path is null") == 0);
+  CHECK(cm_file_open(NULL, "/file.txt", O_RDONLY) == NULL);
+  CHECK(strcmp(cm_last_error(), "vault is null") == 0);
+  CHECK(cm_file_read_at(NULL, NULL, 0, 0) == -1);
+  cm_vault_free(NULL);
+  cm_file_close(NULL);
+  cm_dir_free(NULL);
+
+  CmVault *vault = cm_vault_open(argv[1], argv[2]);
+  CHECK(vault != NULL);
+  CHECK(cm_file_open(vault, "/file.txt", O_RDONLY) == NULL);
+
+  CmFile *file = cm_file_open(vault, "/file.txt", O_RDWR | O_CREAT | O_EXCL);
+  CHECK(file != NULL);
+  const char *contents = "hello from C";
+  CHECK(cm_file_write_at(file, (const uint8_t *)contents, strlen(contents), 0) ==
+        (int64_t)strlen(contents));
+  CHECK(cm_file_write_at(file, (const uint8_t *)"!", 1, strlen(contents)) == 1);
+  cm_file_close(file);
+  CHECK(cm_file_open(vault, "/file.txt", O_RDWR | O_CREAT | O_EXCL) == NULL);
+
+  file = cm_file_open(vault, "/file.txt", O_RDONLY);
+  CHECK(file != NULL);
+  char buf[64] = {0};
+  CHECK(cm_file_read_at(file, (uint8_t *)buf, sizeof(buf), 6) == 7);
+  CHECK(strcmp(buf, "from C!") == 0);
+  CHECK(cm_file_read_at(file, (uint8_t *)buf, sizeof(buf), 100) == 0);
+  CHECK(cm_file_write_at(file, (const uint8_t *)"x", 1, 0) == -1);
+  cm_file_close(file);
+
+  file = cm_file_open(vault, "/file.txt", O_WRONLY | O_TRUNC);
+  CHECK(file != NULL);
+  cm_file_close(file);
+
+  CmDirIter *iter = cm_dir_list(vault, "/");
+  CHECK(iter != NULL);
+  CmFileKind kind;
+  const char *name = cm_dir_next(iter, &kind);
+  CHECK(name != NULL && strcmp(name, "file.txt") == 0);
+  CHECK(kind == CM_FILE_KIND_FILE);
+  CHECK(cm_dir_next(iter, NULL) == NULL);
+  cm_dir_free(iter);
+
+  file = cm_file_open(vault, "/file.txt", O_RDONLY);
+  CHECK(file != NULL);
+  CHECK(cm_file_read_at(file, (uint8_t *)buf, sizeof(buf), 0) == 0);
+  cm_file_close(file);
+
+  cm_vault_free(vault);
+
+  return 0;
+}
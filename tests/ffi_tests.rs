@@ -0,0 +1,84 @@
+#![cfg(feature = "ffi")]
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use cryptomator::{Vault, VaultCreateOptions};
+use serde_json::Value;
+
+// The crate only builds an rlib by default, so build the shared library separately and return
+// the path cargo reports for it
+fn build_library(target_dir: &Path) -> PathBuf {
+    let output = Command::new(env!("CARGO"))
+        .args([
+            "rustc",
+            "--lib",
+            "--features",
+            "ffi",
+            "--crate-type",
+            "cdylib",
+        ])
+        .arg("--message-format=json")
+        .arg("--target-dir")
+        .arg(target_dir)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|message| message["reason"] == "compiler-artifact")
+        .filter(|message| message["target"]["name"] == "cryptomator")
+        .flat_map(|message| message["filenames"].as_array().cloned().unwrap_or_default())
+        .filter_map(|file_name| file_name.as_str().map(PathBuf::from))
+        .find(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "so" || extension == "dylib")
+        })
+        .expect("cargo built no shared library")
+}
+
+#[test]
+pub fn c_program() {
+    let dir = tempfile::tempdir().unwrap();
+    let vault = Vault::create(
+        dir.path().join("vault"),
+        "password",
+        VaultCreateOptions::default(),
+    )
+    .unwrap();
+    let config_path = vault.path().join("vault.cryptomator");
+    drop(vault);
+
+    let library = build_library(&Path::new(env!("CARGO_TARGET_TMPDIR")).join("ffi"));
+    // Ok to unwrap, cargo reports the full path
+    let library_dir = library.parent().unwrap();
+    let program = dir.path().join("ffi_test");
+    let output = Command::new(env::var("CC").unwrap_or_else(|_| String::from("cc")))
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-Iinclude")
+        .arg("tests/ffi/ffi_test.c")
+        .arg("-o")
+        .arg(&program)
+        .arg("-L")
+        .arg(library_dir)
+        .arg(format!("-Wl,-rpath,{}", library_dir.display()))
+        .arg("-lcryptomator")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let output = Command::new(&program)
+        .arg(&config_path)
+        .arg("password")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+}
@@ -0,0 +1,78 @@
+//! Tests that mount a vault with FUSE. These need a FUSE-capable kernel and fusermount, so they
+//! are ignored by default. Run them with `cargo test --test fuse_tests -- --ignored`.
+
+use std::{
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    thread,
+};
+
+use cryptomator::{
+    fs::{fuse::FuseFileSystem, EncryptedFileSystem},
+    Vault,
+};
+use fuser::{MountOption, Session};
+
+// Mount `fs` at a temporary mountpoint, run `f` with the mountpoint, and unmount again, even if
+// `f` panics.
+fn with_mount(fs: FuseFileSystem, options: &[MountOption], f: impl FnOnce(&Path)) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut session = Session::new(fs, dir.path(), options).unwrap();
+    let mut unmounter = session.unmount_callable();
+    thread::scope(|s| {
+        let handle = s.spawn(move || session.run());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(dir.path())));
+        unmounter.unmount().unwrap();
+        handle.join().unwrap().unwrap();
+        if let Err(err) = result {
+            panic::resume_unwind(err);
+        }
+    });
+}
+
+#[test]
+#[ignore]
+pub fn long_names_mounted() {
+    let vault = Vault::open(
+        "tests/fixtures/vault_v8_long_names/vault.cryptomator",
+        String::from("password"),
+    )
+    .unwrap();
+    let fs = FuseFileSystem::new(EncryptedFileSystem::new(&vault).read_only(true));
+    let long_file = format!("{}.txt", "long_file_name_".repeat(15));
+    let long_dir = "long_directory_name_".repeat(12);
+    let long_link = format!("{}_link", "l".repeat(225));
+
+    with_mount(fs, &[MountOption::RO], |mountpoint| {
+        let mut names: Vec<_> = fs::read_dir(mountpoint)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        let mut expected = vec![
+            long_dir.clone(),
+            long_file.clone(),
+            long_link.clone(),
+            String::from("short.txt"),
+        ];
+        expected.sort();
+        assert_eq!(names, expected);
+
+        // Shortened files, directories and symlinks all resolve through the mount
+        assert_eq!(fs::metadata(mountpoint.join(&long_file)).unwrap().len(), 32);
+        assert!(fs::metadata(mountpoint.join(&long_dir)).unwrap().is_dir());
+        assert_eq!(
+            fs::read_to_string(mountpoint.join(&long_dir).join(&long_file)).unwrap(),
+            "Nested file with a very long name.\n"
+        );
+        assert_eq!(
+            fs::read_link(mountpoint.join(&long_link)).unwrap(),
+            Path::new(&long_file)
+        );
+        assert_eq!(
+            fs::read(mountpoint.join(&long_link)).unwrap(),
+            fs::read(mountpoint.join(&long_file)).unwrap()
+        );
+    });
+}
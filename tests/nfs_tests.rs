@@ -0,0 +1,93 @@
+//! Tests that mount a vault over NFS. These need root and an NFS client, so they are ignored by
+//! default. Run them with `cargo test --features nfs --test nfs_tests -- --ignored`.
+
+#![cfg(feature = "nfs")]
+
+use std::{fs, net::TcpListener, path::Path, process::Command, thread};
+
+use cryptomator::{
+    fs::{nfs::NfsVaultServer, EncryptedFileSystem},
+    testing::{self, VaultShape},
+};
+
+fn run(command: &mut Command) {
+    let status = command.status().unwrap();
+    assert!(status.success(), "{command:?} failed with {status}");
+}
+
+#[test]
+#[ignore]
+pub fn mounted() {
+    let dir = tempfile::tempdir().unwrap();
+    let shape = VaultShape {
+        files_per_dir: 2,
+        ..Default::default()
+    };
+    // The server runs until the test exits, so the vault has to outlive it
+    let vault = Box::leak(Box::new(
+        testing::create_vault(dir.path().join("vault"), &shape).unwrap(),
+    ));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        NfsVaultServer::new(EncryptedFileSystem::new(vault))
+            .run(listener)
+            .unwrap()
+    });
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir(&mountpoint).unwrap();
+    run(Command::new("mount")
+        .args(["-t", "nfs", "-o"])
+        .arg(format!(
+            "vers=3,tcp,port={port},mountport={port},mountproto=tcp,nolock"
+        ))
+        .arg("localhost:/")
+        .arg(&mountpoint));
+
+    let result = std::panic::catch_unwind(|| {
+        let file = mountpoint.join(shape.file_path("", 0));
+        assert_eq!(fs::read(&file).unwrap(), shape.file_contents(0));
+
+        let new_dir = mountpoint.join("dir");
+        fs::create_dir(&new_dir).unwrap();
+        fs::write(new_dir.join("new.txt"), "hello").unwrap();
+        fs::rename(new_dir.join("new.txt"), mountpoint.join("moved.txt")).unwrap();
+        std::os::unix::fs::symlink("moved.txt", mountpoint.join("link")).unwrap();
+        assert_eq!(
+            fs::read_to_string(mountpoint.join("link")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            fs::read_link(mountpoint.join("link")).unwrap(),
+            Path::new("moved.txt")
+        );
+        fs::remove_dir(&new_dir).unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(&mountpoint)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        let mut expected = vec![
+            shape
+                .file_path("", 0)
+                .into_os_string()
+                .into_string()
+                .unwrap(),
+            shape
+                .file_path("", 1)
+                .into_os_string()
+                .into_string()
+                .unwrap(),
+            String::from("link"),
+            String::from("moved.txt"),
+        ];
+        expected.sort();
+        assert_eq!(names, expected);
+    });
+    run(Command::new("umount").arg(&mountpoint));
+    if let Err(err) = result {
+        std::panic::resume_unwind(err);
+    }
+}
@@ -0,0 +1,34 @@
+#![cfg(feature = "test-vectors")]
+
+use cryptomator::{testing::vectors::TestVectors, CipherCombo, Vault};
+
+// The vectors are read from vaults written by the official desktop app, so our cryptors are
+// checked against its ciphertexts rather than their own
+#[test]
+pub fn reference_vectors() {
+    let vectors = TestVectors::load_dir("tests/fixtures/vectors").unwrap();
+    let cipher_combos: Vec<_> = vectors.iter().map(|(_, v)| v.cipher_combo).collect();
+    assert!(cipher_combos.contains(&CipherCombo::SivCtrMac));
+    assert!(cipher_combos.contains(&CipherCombo::SivGcm));
+
+    for (path, vectors) in vectors {
+        if let Err(err) = vectors.check() {
+            panic!("{}: {err:?}", path.display());
+        }
+    }
+}
+
+#[test]
+pub fn vectors_match_fixture_vaults() {
+    for name in ["siv_ctrmac", "siv_gcm"] {
+        let vault = Vault::open(
+            format!("tests/fixtures/vault_v8_{name}/vault.cryptomator"),
+            "password",
+        )
+        .unwrap();
+        let vectors = TestVectors::load(format!("tests/fixtures/vectors/{name}.json")).unwrap();
+        assert_eq!(TestVectors::from_vault(&vault).unwrap(), vectors, "{name}");
+        // A full chunk as well as a short one at the end of a file
+        assert!(vectors.chunks.iter().any(|v| v.chunk_number > 0));
+    }
+}